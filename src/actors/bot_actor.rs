@@ -0,0 +1,124 @@
+use actix::prelude::*;
+use log::warn;
+use std::{sync::Arc, time::Duration};
+
+use crate::actors::database_actor::{
+    messages::{GetBotWebhooks, GetUsersInfo},
+    DatabaseActor,
+};
+use crate::actors::websocket_actor::ChatMessage;
+use crate::bots::{self, BotRoutes};
+use crate::config::BotsConfig;
+use crate::ids::UserId;
+use crate::metrics;
+
+// Актор доставки сообщений ботам
+//
+// Держит в памяти, в каких чатах состоят боты, и перечитывает это из базы раз в
+// bots.refresh_secs, так что бот начинает получать сообщения нового чата с этой задержкой.
+// Каждое сообщение отправляется каждому боту отдельной задачей, так что медленный или
+// недоступный вебхук не задерживает остальных.
+
+/// Сколько ботов искать одним запросом к базе
+const BOT_LOOKUP_BATCH: usize = 100;
+
+pub mod messages {
+    use super::*;
+
+    /// Новое сообщение, принятое этим экземпляром
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct DeliverToBots(pub ChatMessage);
+
+    /// Перечитать ботов и их чаты
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct RefreshBots;
+}
+
+pub struct BotActor {
+    db: Addr<DatabaseActor>,
+    config: BotsConfig,
+    routes: Arc<BotRoutes>,
+}
+
+impl BotActor {
+    pub fn new(db: Addr<DatabaseActor>, config: BotsConfig) -> Self {
+        Self {
+            db,
+            config,
+            routes: Default::default(),
+        }
+    }
+}
+
+/// Загружает ботов и раскладывает их по чатам
+async fn load_routes(db: Addr<DatabaseActor>) -> Result<BotRoutes, String> {
+    let bots = db
+        .send(GetBotWebhooks)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    let mut users = vec![];
+    for batch in bots.chunks(BOT_LOOKUP_BATCH) {
+        let user_ids = batch.iter().map(|bot| UserId(bot.user_id)).collect();
+        users.extend(
+            db.send(GetUsersInfo { user_ids })
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?,
+        );
+    }
+    Ok(BotRoutes::new(bots, &users))
+}
+
+impl Actor for BotActor {
+    type Context = Context<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.notify(messages::RefreshBots);
+        ctx.run_interval(
+            Duration::from_secs(self.config.refresh_secs.max(1)),
+            |_, ctx| ctx.notify(messages::RefreshBots),
+        );
+    }
+}
+
+impl Handler<messages::RefreshBots> for BotActor {
+    type Result = ();
+    fn handle(&mut self, _msg: messages::RefreshBots, ctx: &mut Self::Context) -> Self::Result {
+        load_routes(self.db.clone())
+            .into_actor(self)
+            .map(|routes, act, _| match routes {
+                Ok(routes) => act.routes = Arc::new(routes),
+                // Старые маршруты лучше, чем никаких
+                Err(e) => warn!("Cannot load bot webhooks: {e}"),
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Handler<messages::DeliverToBots> for BotActor {
+    type Result = ();
+    fn handle(&mut self, msg: messages::DeliverToBots, _ctx: &mut Self::Context) -> Self::Result {
+        let message = Arc::new(msg.0);
+        for bot in self.routes.recipients(&message) {
+            let message = message.clone();
+            let config = self.config.clone();
+            actix::spawn(async move {
+                let result =
+                    bots::deliver(&bot, &message, &config, || chrono::Utc::now().timestamp()).await;
+                let outcome = match result {
+                    Ok(()) => "ok",
+                    Err(e) => {
+                        warn!(
+                            "Cannot deliver message {} to bot {}: {e}",
+                            message.message_id, bot.user_id
+                        );
+                        "error"
+                    }
+                };
+                metrics::BOT_DELIVERIES.with_label_values(&[outcome]).inc();
+            });
+        }
+    }
+}
@@ -1,15 +1,22 @@
 use crate::actors::database_actor;
 use crate::{
-    actors::websocket_actor::{self, ChatMessage, WebsocketActor},
-    database::DBResult,
+    actors::websocket_actor::{
+        self, messages::BrokerMessage, ChatMessage, LoginConflict, MessageTombstone,
+    },
+    clock,
+    config::{Admission, ConfigHandle},
+    database::data::{NotificationSettings, UnpinnedMessage},
+    ids::{ChatId, UserId},
+    load_shedding, metrics, text,
 };
 use actix::prelude::*;
+use log::{info, warn};
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use tokio::sync::{Mutex, Notify};
 
 use super::database_actor::DatabaseActor;
 
@@ -23,12 +30,116 @@ use super::database_actor::DatabaseActor;
 // Когда пользователь подключается к чату, брокер получает список всех чатов пользователей и
 // обновляет свою таблицу: добавляет в socket_map новый id пользователя(если его не было раньше) с
 // сокетом и обновляет subscribers, добавляя пользователя в каналы
+//
+// Когда закрывается последний сокет пользователя, брокер забывает и пользователя, и его
+// подписки: иначе таблицы растут с каждым когда-либо подключавшимся пользователем.
+// Подписки нужны только для рассылки по сокетам этого экземпляра, а при следующем
+// подключении они снова читаются из базы
+//
+// Сокеты пользователя хранятся в порядке подключения. Если политика duplicate_login не
+// пускает столько сокетов, то при подключении брокер закрывает либо самые старые сокеты,
+// либо новый: сокет получает LoginConflict и закрывается со своим кодом
+//
+// Сокет, который упал, не успев сообщить о закрытии, остался бы в socket_map навсегда.
+// Поэтому брокер раз в DEAD_SESSION_SWEEP_INTERVAL проверяет, живы ли адреса сокетов,
+// и забывает мертвые вместе с подписками пользователей, у которых живых сокетов не осталось
+//
+// Вместе с чатами при подключении читается список блокировки пользователя: сообщения и
+// упоминания от заблокированных ему не рассылаются. Изменения списка приходят от всех
+// экземпляров через Редис, а забывается он вместе с подписками
+//
+// Так же читаются настройки уведомлений пользователя в его чатах: упоминание приходит,
+// только если чат не заглушен и приоритет уведомлений его пропускает. Об изменениях
+// настроек экземпляры узнают через Редис и перечитывают их из базы
+//
+// Очередь каждого сокета ограничена (slow_consumer.mailbox_capacity). Если она переполнена,
+// брокер не кладет событие в обход ограничения, а отбрасывает его и сигналит сокету по
+// отдельному каналу: в очереди сигнал встал бы за всем, что в ней скопилось. Сокет сам
+// решает, предупредить клиента или отключить его
+//
+// Пока экземпляр был отключен от Редиса (например, при смене главного узла), он мог
+// пропустить подписки, отписки и изменения блокировок. После переподключения Редис-актор
+// дочитывает их из журнала управляющих сообщений, а брокер заново собирает снимок подписок
+// и блокировок своих пользователей из базы, так что клиентам переподключаться не нужно
 
 type AsyncMutex<T> = Arc<Mutex<T>>;
 
+/// Сигнал сокету, что брокер отбросил событие, потому что очередь сокета переполнена
+pub struct Overflow;
+
+/// Сокет, подключенный к брокеру
+#[derive(Clone)]
+pub struct SocketHandle {
+    pub addr: Recipient<BrokerMessage>,
+    /// Сигнал о переполнении, который сокет ждет мимо своей очереди. Сигналы, которые
+    /// сокет еще не забрал, сливаются в один, так что и они память не копят
+    pub overflow: Option<Arc<Notify>>,
+}
+
+impl SocketHandle {
+    fn signal_overflow(&self) {
+        if let Some(overflow) = &self.overflow {
+            overflow.notify_one();
+        }
+    }
+}
+
+impl From<Recipient<BrokerMessage>> for SocketHandle {
+    fn from(addr: Recipient<BrokerMessage>) -> Self {
+        Self {
+            addr,
+            overflow: None,
+        }
+    }
+}
+
+/// Не чаще одного события typing от пользователя в чате за это время
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Как часто брокер ищет мертвые сокеты
+pub const DEAD_SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Сколько пар (чат, пользователь) помнит ограничитель, прежде чем забыть устаревшие
+const TYPING_THROTTLE_CAPACITY: usize = 1024;
+
+/// Ограничитель частоты событий typing по парам (чат, пользователь)
+pub struct TypingThrottle {
+    window: Duration,
+    last_sent: HashMap<(ChatId, UserId), Instant>,
+}
+
+impl TypingThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Можно ли пропустить событие сейчас, и если да, то запоминает его
+    pub fn allow(&mut self, chat_id: ChatId, user_id: UserId, now: Instant) -> bool {
+        if let Some(last) = self.last_sent.get(&(chat_id, user_id)) {
+            if now.saturating_duration_since(*last) < self.window {
+                return false;
+            }
+        }
+        if self.last_sent.len() >= TYPING_THROTTLE_CAPACITY {
+            let window = self.window;
+            self.last_sent
+                .retain(|_, last| now.saturating_duration_since(*last) < window);
+        }
+        self.last_sent.insert((chat_id, user_id), now);
+        true
+    }
+}
+
 // Какие сообщения принимает
 pub mod messages {
-    use crate::actors::redis_actor::SubscriptionData;
+    use crate::actors::redis_actor::{
+        BlockChangedData, CallSignalData, ChatRenamedData, EphemeralData, MemberRemovedData,
+        NotificationsChangedData, PresenceData, ProfileUpdatedData, ReadPositionData,
+        SessionRevokedData, SubscriptionData, TypingData,
+    };
 
     use super::*;
 
@@ -36,22 +147,70 @@ pub mod messages {
     #[rtype(result = "()")]
     pub enum RedisMessage {
         NewMessage(ChatMessage),
+        MessageEdited(ChatMessage),
+        MessageDeleted(MessageTombstone),
+        MessageUnpinned(UnpinnedMessage),
+        SessionRevoked(SessionRevokedData),
+        Typing(TypingData),
+        Ephemeral(EphemeralData),
+        CallSignal(CallSignalData),
+        ReadPosition(ReadPositionData),
+        ChatRenamed(ChatRenamedData),
+        ProfileUpdated(ProfileUpdatedData),
+        MemberRemoved(MemberRemovedData),
+        Presence(PresenceData),
         NewSubscription(SubscriptionData),
         NewUnsubscription(SubscriptionData),
+        BlockChanged(BlockChangedData),
+        NotificationsChanged(NotificationsChangedData),
     }
 
     #[derive(Message)]
     #[rtype(result = "()")]
     pub enum WebsocketMessage {
-        BrokerNotifyStarted(Addr<WebsocketActor>, i64),
-        BrokerNotifyClosed(Addr<WebsocketActor>, i64),
+        BrokerNotifyStarted(SocketHandle, UserId),
+        BrokerNotifyClosed(Recipient<BrokerMessage>, UserId),
     }
+
+    /// Сколько записей сейчас держит брокер
+    #[derive(Message)]
+    #[rtype(result = "BrokerStats")]
+    pub struct GetStats;
+
+    /// Убрать сокеты, чьи акторы уже остановились, и вернуть, сколько их было
+    #[derive(Message)]
+    #[rtype(result = "usize")]
+    pub struct CollectDeadSessions;
+
+    /// Перечитать из базы чаты, списки блокировки и настройки уведомлений пользователей с сокетами на этом
+    /// экземпляре и вернуть, скольких пользователей удалось перечитать
+    #[derive(Message)]
+    #[rtype(result = "usize")]
+    pub struct ResyncSubscriptions;
+}
+
+/// Размеры таблиц брокера: при постоянном числе подключений они не должны расти
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BrokerStats {
+    /// Пользователи с сокетами на этом экземпляре
+    pub users: usize,
+    pub sockets: usize,
+    /// Чаты, у которых есть подписчики
+    pub chats: usize,
+    /// Пары (чат, пользователь)
+    pub subscriptions: usize,
 }
 
 pub struct BrokerActor {
-    subscribers: AsyncMutex<HashMap<Uuid, HashSet<i64>>>,
-    socket_map: AsyncMutex<HashMap<i64, HashSet<Addr<WebsocketActor>>>>,
+    subscribers: AsyncMutex<HashMap<ChatId, HashSet<UserId>>>,
+    socket_map: AsyncMutex<HashMap<UserId, Vec<SocketHandle>>>,
+    /// Кого заблокировали пользователи с сокетами на этом экземпляре
+    blocks: AsyncMutex<HashMap<UserId, HashSet<UserId>>>,
+    /// Настройки уведомлений пользователей с сокетами на этом экземпляре по чатам
+    notifications: AsyncMutex<HashMap<UserId, HashMap<ChatId, NotificationSettings>>>,
+    typing: AsyncMutex<TypingThrottle>,
     db: Addr<DatabaseActor>,
+    config: Option<ConfigHandle>,
 }
 
 impl BrokerActor {
@@ -62,12 +221,123 @@ impl BrokerActor {
             db,
             subscribers,
             socket_map,
+            blocks: Arc::new(Mutex::new(HashMap::new())),
+            notifications: Arc::new(Mutex::new(HashMap::new())),
+            typing: Arc::new(Mutex::new(TypingThrottle::new(TYPING_THROTTLE))),
+            config: None,
+        }
+    }
+
+    /// Включает политику одновременных входов из конфигурации
+    ///
+    /// Без конфигурации пользователь может открыть сколько угодно сокетов
+    pub fn with_config(mut self, config: ConfigHandle) -> Self {
+        self.config = Some(config);
+        self
+    }
+}
+
+impl BrokerActor {
+    /// Убирает из user_ids тех, кто заблокировал sender_id
+    async fn drop_blockers(
+        user_ids: &mut HashSet<UserId>,
+        blocks: &AsyncMutex<HashMap<UserId, HashSet<UserId>>>,
+        sender_id: UserId,
+    ) {
+        let blocks = blocks.lock().await;
+        user_ids.retain(|id| {
+            !blocks
+                .get(id)
+                .is_some_and(|blocked| blocked.contains(&sender_id))
+        });
+    }
+
+    /// Убирает из user_ids тех, кто не хочет уведомлений об упоминаниях в чате chat_id
+    async fn drop_silenced(
+        user_ids: &mut HashSet<UserId>,
+        notifications: &AsyncMutex<HashMap<UserId, HashMap<ChatId, NotificationSettings>>>,
+        chat_id: ChatId,
+    ) {
+        let notifications = notifications.lock().await;
+        user_ids.retain(|id| {
+            notifications
+                .get(id)
+                .and_then(|chats| chats.get(&chat_id))
+                .is_none_or(|settings| settings.should_notify(true))
+        });
+    }
+
+    /// Настройки уведомлений пользователя из базы, если база недоступна - пустые
+    async fn load_notifications(
+        db: &Addr<DatabaseActor>,
+        user_id: UserId,
+    ) -> HashMap<ChatId, NotificationSettings> {
+        match db
+            .send(database_actor::messages::GetAllNotificationSettings { user_id })
+            .await
+        {
+            Ok(Ok(settings)) => settings,
+            Ok(Err(e)) => {
+                warn!("Cannot read notification settings of user {user_id}: {e}");
+                HashMap::new()
+            }
+            Err(e) => {
+                metrics::MAILBOX_ERRORS
+                    .with_label_values(&["database"])
+                    .inc();
+                warn!("Cannot read notification settings of user {user_id}: {e}");
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Отправляет событие на все сокеты пользователей user_ids, подключенные к этому экземпляру
+    ///
+    /// Сокет с переполненной очередью события не получает, а получает сигнал о переполнении
+    async fn fanout(
+        user_ids: &HashSet<UserId>,
+        socket_map: &AsyncMutex<HashMap<UserId, Vec<SocketHandle>>>,
+        event: impl Fn() -> websocket_actor::messages::BrokerMessage,
+    ) {
+        for id in user_ids {
+            if let Some(sockets) = socket_map.lock().await.get(id) {
+                for socket in sockets {
+                    if let Err(SendError::Full(_)) = socket.addr.try_send(event()) {
+                        metrics::SLOW_CONSUMERS
+                            .with_label_values(&["dropped"])
+                            .inc();
+                        socket.signal_overflow();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Как fanout, но сокетам с переполненной очередью событие не доставляется: следующий
+    /// кратковременный сигнал все равно заменит пропущенный
+    async fn fanout_lossy(
+        user_ids: &HashSet<UserId>,
+        socket_map: &AsyncMutex<HashMap<UserId, Vec<SocketHandle>>>,
+        event: impl Fn() -> websocket_actor::messages::BrokerMessage,
+    ) {
+        for id in user_ids {
+            if let Some(sockets) = socket_map.lock().await.get(id) {
+                for socket in sockets {
+                    let _ = socket.addr.try_send(event());
+                }
+            }
         }
     }
 }
 
 impl Actor for BrokerActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(DEAD_SESSION_SWEEP_INTERVAL, |_, ctx| {
+            ctx.notify(messages::CollectDeadSessions);
+        });
+    }
 }
 
 impl Handler<messages::WebsocketMessage> for BrokerActor {
@@ -79,97 +349,501 @@ impl Handler<messages::WebsocketMessage> for BrokerActor {
     ) -> Self::Result {
         let subscribers = self.subscribers.clone();
         let socket_map = self.socket_map.clone();
+        let blocks = self.blocks.clone();
+        let notifications = self.notifications.clone();
         let db = self.db.clone();
+        let duplicate_login = self
+            .config
+            .as_ref()
+            .map(|config| config.current().duplicate_login.clone())
+            .unwrap_or_default();
         Box::pin(async move {
             match msg {
                 messages::WebsocketMessage::BrokerNotifyStarted(addr, id) => {
-                    socket_map
-                        .lock()
-                        .await
-                        .entry(id)
-                        .and_modify(|set| {
-                            set.insert(addr.clone());
-                        })
-                        .or_insert({
-                            let mut h = HashSet::new();
-                            h.insert(addr);
-                            h
-                        });
-                    let user_chats: DBResult<Vec<Uuid>> = db
+                    {
+                        let mut socket_map = socket_map.lock().await;
+                        let sockets = socket_map.entry(id).or_default();
+                        match duplicate_login.admit(sockets.len()) {
+                            Admission::Accept { evict } => {
+                                for old in sockets.drain(..evict) {
+                                    metrics::DUPLICATE_LOGINS
+                                        .with_label_values(&["kicked"])
+                                        .inc();
+                                    old.addr.do_send(BrokerMessage::LoginConflict(
+                                        LoginConflict::Replaced,
+                                    ));
+                                }
+                                sockets.push(addr);
+                            }
+                            // У пользователя уже есть сокеты, так что запись не пустеет
+                            Admission::Deny => {
+                                metrics::DUPLICATE_LOGINS
+                                    .with_label_values(&["denied"])
+                                    .inc();
+                                addr.addr
+                                    .do_send(BrokerMessage::LoginConflict(LoginConflict::Denied));
+                                return;
+                            }
+                        }
+                    }
+                    let user_chats = db
                         .send(database_actor::messages::GetUserChats { user_id: id })
                         .await
-                        .unwrap();
+                        .unwrap_or_else(|e| {
+                            metrics::MAILBOX_ERRORS
+                                .with_label_values(&["database"])
+                                .inc();
+                            warn!("Cannot read chats of user {id}: {e}");
+                            Ok(vec![])
+                        });
                     if let Ok(chats) = user_chats {
+                        // Пока читали чаты, сокет мог уже закрыться. Блокировки берутся в том
+                        // же порядке, что и при рассылке: сначала подписки, потом сокеты
+                        let mut subscribers = subscribers.lock().await;
+                        if !socket_map.lock().await.contains_key(&id) {
+                            return;
+                        }
                         for chat in chats {
-                            subscribers
-                                .lock()
-                                .await
-                                .entry(chat)
-                                .and_modify(|v| {
-                                    v.insert(id);
-                                })
-                                .or_insert({
-                                    let mut h = HashSet::new();
-                                    h.insert(id);
-                                    h
-                                });
+                            subscribers.entry(chat).or_default().insert(id);
+                        }
+                    }
+                    // Без ящика базы блокировок не знаем, но рассылка не должна падать
+                    let blocked = db
+                        .send(database_actor::messages::GetBlockedUsers { user_id: id })
+                        .await
+                        .unwrap_or_else(|e| {
+                            metrics::MAILBOX_ERRORS
+                                .with_label_values(&["database"])
+                                .inc();
+                            warn!("Cannot read blocked users of user {id}: {e}");
+                            Ok(HashSet::new())
+                        });
+                    if let Ok(blocked) = blocked {
+                        // Список блокировки берется после сокетов, как и при уборке
+                        let socket_map = socket_map.lock().await;
+                        if socket_map.contains_key(&id) {
+                            blocks.lock().await.insert(id, blocked);
                         }
                     }
+                    let settings = Self::load_notifications(&db, id).await;
+                    if socket_map.lock().await.contains_key(&id) {
+                        notifications.lock().await.insert(id, settings);
+                    }
                 }
                 messages::WebsocketMessage::BrokerNotifyClosed(addr, id) => {
-                    socket_map.lock().await.entry(id).and_modify(|set| {
-                        set.remove(&addr);
+                    {
+                        let mut socket_map = socket_map.lock().await;
+                        let Some(sockets) = socket_map.get_mut(&id) else {
+                            return;
+                        };
+                        sockets.retain(|socket| socket.addr != addr);
+                        if !sockets.is_empty() {
+                            return;
+                        }
+                        socket_map.remove(&id);
+                    }
+                    let mut subscribers = subscribers.lock().await;
+                    // Пользователь мог уже подключиться снова
+                    if socket_map.lock().await.contains_key(&id) {
+                        return;
+                    }
+                    subscribers.retain(|_, user_ids| {
+                        user_ids.remove(&id);
+                        !user_ids.is_empty()
                     });
+                    blocks.lock().await.remove(&id);
+                    notifications.lock().await.remove(&id);
                 }
             }
         })
     }
 }
 
+impl Handler<messages::GetStats> for BrokerActor {
+    type Result = ResponseFuture<BrokerStats>;
+    fn handle(&mut self, _msg: messages::GetStats, _ctx: &mut Self::Context) -> Self::Result {
+        let subscribers = self.subscribers.clone();
+        let socket_map = self.socket_map.clone();
+        Box::pin(async move {
+            let subscribers = subscribers.lock().await;
+            let socket_map = socket_map.lock().await;
+            BrokerStats {
+                users: socket_map.len(),
+                sockets: socket_map.values().map(Vec::len).sum(),
+                chats: subscribers.len(),
+                subscriptions: subscribers.values().map(HashSet::len).sum(),
+            }
+        })
+    }
+}
+
+impl Handler<messages::CollectDeadSessions> for BrokerActor {
+    type Result = ResponseFuture<usize>;
+    fn handle(
+        &mut self,
+        _msg: messages::CollectDeadSessions,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let subscribers = self.subscribers.clone();
+        let socket_map = self.socket_map.clone();
+        let blocks = self.blocks.clone();
+        let notifications = self.notifications.clone();
+        Box::pin(async move {
+            // Блокировки берутся в том же порядке, что и при рассылке
+            let mut subscribers = subscribers.lock().await;
+            let mut socket_map = socket_map.lock().await;
+            let mut cleaned = 0;
+            let mut gone = HashSet::new();
+            socket_map.retain(|&user_id, sockets| {
+                let before = sockets.len();
+                sockets.retain(|socket| socket.addr.connected());
+                cleaned += before - sockets.len();
+                if sockets.is_empty() {
+                    gone.insert(user_id);
+                }
+                !sockets.is_empty()
+            });
+            if !gone.is_empty() {
+                subscribers.retain(|_, user_ids| {
+                    user_ids.retain(|user_id| !gone.contains(user_id));
+                    !user_ids.is_empty()
+                });
+                blocks
+                    .lock()
+                    .await
+                    .retain(|user_id, _| !gone.contains(user_id));
+                notifications
+                    .lock()
+                    .await
+                    .retain(|user_id, _| !gone.contains(user_id));
+            }
+            metrics::DEAD_SESSIONS_CLEANED.set(cleaned as i64);
+            if cleaned > 0 {
+                info!("Removed {cleaned} dead sockets from the broker");
+            }
+            cleaned
+        })
+    }
+}
+
+impl Handler<messages::ResyncSubscriptions> for BrokerActor {
+    type Result = ResponseFuture<usize>;
+    fn handle(
+        &mut self,
+        _msg: messages::ResyncSubscriptions,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let subscribers = self.subscribers.clone();
+        let socket_map = self.socket_map.clone();
+        let blocks = self.blocks.clone();
+        let notifications = self.notifications.clone();
+        let db = self.db.clone();
+        Box::pin(async move {
+            let users: Vec<UserId> = socket_map.lock().await.keys().copied().collect();
+            let mut snapshot = HashMap::new();
+            for user_id in users {
+                let chats = db
+                    .send(database_actor::messages::GetUserChats { user_id })
+                    .await;
+                let blocked = db
+                    .send(database_actor::messages::GetBlockedUsers { user_id })
+                    .await;
+                match (chats, blocked) {
+                    (Ok(Ok(chats)), Ok(Ok(blocked))) => {
+                        let settings = Self::load_notifications(&db, user_id).await;
+                        snapshot.insert(user_id, (chats, blocked, settings));
+                    }
+                    // Прежние подписки лучше, чем никаких
+                    _ => warn!("Cannot resync subscriptions of user {user_id}"),
+                }
+            }
+            // Блокировки берутся в том же порядке, что и при рассылке
+            let mut subscribers = subscribers.lock().await;
+            let socket_map = socket_map.lock().await;
+            let mut blocks = blocks.lock().await;
+            let mut notifications = notifications.lock().await;
+            // Пользователи, которые за это время отключились, уже забыты
+            snapshot.retain(|user_id, _| socket_map.contains_key(user_id));
+            subscribers.retain(|_, user_ids| {
+                user_ids.retain(|user_id| !snapshot.contains_key(user_id));
+                !user_ids.is_empty()
+            });
+            let resynced = snapshot.len();
+            for (user_id, (chats, blocked, settings)) in snapshot {
+                for chat_id in chats {
+                    subscribers.entry(chat_id).or_default().insert(user_id);
+                }
+                blocks.insert(user_id, blocked);
+                notifications.insert(user_id, settings);
+            }
+            info!("Resynced subscriptions of {resynced} users");
+            resynced
+        })
+    }
+}
+
 impl Handler<messages::RedisMessage> for BrokerActor {
     type Result = ResponseFuture<()>;
     fn handle(&mut self, msg: messages::RedisMessage, _ctx: &mut Self::Context) -> Self::Result {
         let subscribers = self.subscribers.clone();
         let socket_map = self.socket_map.clone();
+        let blocks = self.blocks.clone();
+        let notifications = self.notifications.clone();
+        let db = self.db.clone();
+        let typing = self.typing.clone();
+        // Сообщение в очереди, пока его не разослали: по глубине очереди видно перегрузку
+        let queued = load_shedding::BROKER_QUEUE.enter();
         Box::pin(async move {
+            let _queued = queued;
             match msg {
                 messages::RedisMessage::NewMessage(new_msg) => {
-                    if let Some(user_ids) = subscribers.lock().await.get(&new_msg.chat_id) {
-                        for id in user_ids {
-                            if let Some(user_addresses) = socket_map.lock().await.get(id) {
-                                for addr in user_addresses {
-                                    addr.do_send(
-                                        websocket_actor::messages::BrokerMessage::NewMessage(
-                                            new_msg.clone(),
-                                        ),
-                                    );
-                                }
+                    // Следующие сообщения этого экземпляра должны оказаться позже увиденного
+                    clock::CLOCK.observe(new_msg.date.timestamp);
+                    let (chat_id, sender_id) = (ChatId(new_msg.chat_id), UserId(new_msg.sender_id));
+                    let mut user_ids = subscribers
+                        .lock()
+                        .await
+                        .get(&chat_id)
+                        .cloned()
+                        .unwrap_or_default();
+                    // Размер чата считаем по участникам, которых знает этот экземпляр
+                    metrics::MESSAGE_DELIVERY_LATENCY
+                        .with_label_values(&[metrics::chat_size_bucket(user_ids.len())])
+                        .observe(metrics::seconds_since(new_msg.date.timestamp));
+                    Self::drop_blockers(&mut user_ids, &blocks, sender_id).await;
+                    // Отправитель получает свое сообщение на все сокеты, даже если
+                    // этот экземпляр еще не знает о его подписке на чат
+                    user_ids.insert(sender_id);
+                    Self::fanout(&user_ids, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::NewMessage(new_msg.clone())
+                    })
+                    .await;
+                    // Упомянутым отдельное событие, чтобы клиент мог выделить упоминание,
+                    // если только они не отключили такие уведомления в этом чате
+                    let mut mentioned: HashSet<UserId> =
+                        new_msg.mentions.iter().copied().map(UserId).collect();
+                    Self::drop_blockers(&mut mentioned, &blocks, sender_id).await;
+                    Self::drop_silenced(&mut mentioned, &notifications, chat_id).await;
+                    let preview = text::preview(&new_msg.msg_text, text::PREVIEW_LENGTH);
+                    Self::fanout(&mentioned, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::Mentioned {
+                            chat_id,
+                            message_id: new_msg.message_id,
+                            sender_id,
+                            preview: preview.clone(),
+                        }
+                    })
+                    .await;
+                }
+                messages::RedisMessage::MessageEdited(edited) => {
+                    let Some(mut user_ids) = subscribers
+                        .lock()
+                        .await
+                        .get(&ChatId(edited.chat_id))
+                        .cloned()
+                    else {
+                        return;
+                    };
+                    // Новый текст не должен дойти до тех, кто заблокировал отправителя
+                    Self::drop_blockers(&mut user_ids, &blocks, UserId(edited.sender_id)).await;
+                    Self::fanout(&user_ids, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::MessageEdited(edited.clone())
+                    })
+                    .await;
+                }
+                messages::RedisMessage::MessageDeleted(tombstone) => {
+                    if let Some(user_ids) = subscribers.lock().await.get(&ChatId(tombstone.chat_id))
+                    {
+                        Self::fanout(user_ids, &socket_map, || {
+                            websocket_actor::messages::BrokerMessage::MessageDeleted(
+                                tombstone.clone(),
+                            )
+                        })
+                        .await;
+                    }
+                }
+                messages::RedisMessage::MessageUnpinned(unpinned) => {
+                    if let Some(user_ids) = subscribers.lock().await.get(&ChatId(unpinned.chat_id))
+                    {
+                        Self::fanout(user_ids, &socket_map, || {
+                            websocket_actor::messages::BrokerMessage::MessageUnpinned(
+                                unpinned.clone(),
+                            )
+                        })
+                        .await;
+                    }
+                }
+                messages::RedisMessage::ChatRenamed(data) => {
+                    if let Some(user_ids) = subscribers.lock().await.get(&data.chat_id) {
+                        Self::fanout(user_ids, &socket_map, || {
+                            websocket_actor::messages::BrokerMessage::ChatRenamed(data.clone())
+                        })
+                        .await;
+                    }
+                }
+                // Событие получают участники всех чатов пользователя, каждый по одному разу,
+                // и другие сокеты самого пользователя
+                messages::RedisMessage::ProfileUpdated(data) => {
+                    let mut user_ids = HashSet::from([data.user_id]);
+                    {
+                        let subscribers = subscribers.lock().await;
+                        for chat_id in &data.chats {
+                            if let Some(members) = subscribers.get(chat_id) {
+                                user_ids.extend(members);
                             }
                         }
                     }
+                    Self::fanout(&user_ids, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::ProfileUpdated(data.clone())
+                    })
+                    .await;
                 }
-                messages::RedisMessage::NewSubscription(sub_data) => {
-                    subscribers
+                // Исключенный больше не получает событий чата, а его сокеты узнают,
+                // что чат пропал
+                messages::RedisMessage::MemberRemoved(data) => {
+                    {
+                        let mut subscribers = subscribers.lock().await;
+                        if let Some(user_ids) = subscribers.get_mut(&data.chat_id) {
+                            user_ids.remove(&data.user_id);
+                            if user_ids.is_empty() {
+                                subscribers.remove(&data.chat_id);
+                            }
+                        }
+                    }
+                    Self::fanout(&HashSet::from([data.user_id]), &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::RemovedFromChat(data.clone())
+                    })
+                    .await;
+                }
+                messages::RedisMessage::SessionRevoked(data) => {
+                    Self::fanout(&HashSet::from([data.user_id]), &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::SessionRevoked(
+                            data.session_id.clone(),
+                        )
+                    })
+                    .await;
+                }
+                // Отметку получают все сокеты пользователя, сокет-источник пропустит ее сам
+                messages::RedisMessage::ReadPosition(data) => {
+                    Self::fanout(&HashSet::from([data.user_id]), &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::ReadPositionChanged(data.clone())
+                    })
+                    .await;
+                }
+                // Все экземпляры видят одни и те же события, так что и пропускают одни и те же
+                messages::RedisMessage::Typing(data) => {
+                    if !typing
                         .lock()
                         .await
-                        .entry(sub_data.chat_id)
-                        .and_modify(|set| {
-                            set.insert(sub_data.user_id);
-                        })
-                        .or_insert({
-                            let mut h = HashSet::new();
-                            h.insert(sub_data.user_id);
-                            h
-                        });
+                        .allow(data.chat_id, data.user_id, Instant::now())
+                    {
+                        return;
+                    }
+                    let Some(mut others) = subscribers.lock().await.get(&data.chat_id).cloned()
+                    else {
+                        return;
+                    };
+                    others.remove(&data.user_id);
+                    Self::drop_blockers(&mut others, &blocks, data.user_id).await;
+                    Self::fanout(&others, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::Typing {
+                            chat_id: data.chat_id,
+                            user_id: data.user_id,
+                        }
+                    })
+                    .await;
                 }
-                messages::RedisMessage::NewUnsubscription(sub_data) => {
-                    subscribers
+                // Сигнал получают остальные участники чата, отправителю он не нужен
+                messages::RedisMessage::Ephemeral(data) => {
+                    let Some(mut others) = subscribers.lock().await.get(&data.chat_id).cloned()
+                    else {
+                        return;
+                    };
+                    others.remove(&data.sender_id);
+                    Self::drop_blockers(&mut others, &blocks, data.sender_id).await;
+                    Self::fanout_lossy(&others, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::Ephemeral(data.clone())
+                    })
+                    .await;
+                }
+                // Кадр получают только сокеты адресата и только если он состоит в чате,
+                // иначе сигнализацией можно было бы писать кому угодно
+                messages::RedisMessage::CallSignal(data) => {
+                    let is_member = subscribers
                         .lock()
                         .await
-                        .entry(sub_data.chat_id)
-                        .and_modify(|set| {
-                            set.remove(&sub_data.user_id);
-                        });
+                        .get(&data.chat_id)
+                        .is_some_and(|user_ids| user_ids.contains(&data.to_user));
+                    if !is_member {
+                        return;
+                    }
+                    // Заблокированный не может звонить тому, кто его заблокировал
+                    let mut callee = HashSet::from([data.to_user]);
+                    Self::drop_blockers(&mut callee, &blocks, data.from_user).await;
+                    Self::fanout(&callee, &socket_map, || {
+                        websocket_actor::messages::BrokerMessage::CallSignal(data.clone())
+                    })
+                    .await;
+                }
+                // Событие получают остальные участники небольших чатов пользователя
+                messages::RedisMessage::Presence(data) => {
+                    for &chat_id in &data.chats {
+                        let Some(mut others) = subscribers.lock().await.get(&chat_id).cloned()
+                        else {
+                            continue;
+                        };
+                        others.remove(&data.user_id);
+                        Self::fanout(&others, &socket_map, || {
+                            websocket_actor::messages::BrokerMessage::Presence {
+                                chat_id,
+                                user_id: data.user_id,
+                                online: data.online,
+                            }
+                        })
+                        .await;
+                    }
+                }
+                // Подписки нужны только пользователям с сокетами на этом экземпляре
+                messages::RedisMessage::NewSubscription(sub_data) => {
+                    let mut subscribers = subscribers.lock().await;
+                    if socket_map.lock().await.contains_key(&sub_data.user_id) {
+                        subscribers
+                            .entry(sub_data.chat_id)
+                            .or_default()
+                            .insert(sub_data.user_id);
+                    }
+                }
+                // Список нужен только пользователям с сокетами на этом экземпляре,
+                // остальные прочитают его из базы при подключении
+                messages::RedisMessage::BlockChanged(data) => {
+                    let mut blocks = blocks.lock().await;
+                    let Some(blocked) = blocks.get_mut(&data.user_id) else {
+                        return;
+                    };
+                    if data.blocked {
+                        blocked.insert(data.blocked_id);
+                    } else {
+                        blocked.remove(&data.blocked_id);
+                    }
+                }
+                // Настройки перечитываются целиком: изменение могло прийти с любого экземпляра
+                messages::RedisMessage::NotificationsChanged(data) => {
+                    if !socket_map.lock().await.contains_key(&data.user_id) {
+                        return;
+                    }
+                    let settings = Self::load_notifications(&db, data.user_id).await;
+                    if socket_map.lock().await.contains_key(&data.user_id) {
+                        notifications.lock().await.insert(data.user_id, settings);
+                    }
+                }
+                messages::RedisMessage::NewUnsubscription(sub_data) => {
+                    let mut subscribers = subscribers.lock().await;
+                    if let Some(user_ids) = subscribers.get_mut(&sub_data.chat_id) {
+                        user_ids.remove(&sub_data.user_id);
+                        if user_ids.is_empty() {
+                            subscribers.remove(&sub_data.chat_id);
+                        }
+                    }
                 }
             }
         })
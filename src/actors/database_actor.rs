@@ -1,13 +1,30 @@
 use actix::prelude::*;
-use std::sync::Arc;
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
+use crate::config::{DatabaseConfig, HistoryLimits};
 use crate::database::{
-    data::{ChatInfo, ChatType, UserInfo},
+    data::{
+        Attachment, BotWebhook, ChannelListing, ChatEvent, ChatInfo, ChatType, ChatUsage,
+        DeliveryMode, Draft, NotificationSettings, PinOutcome, PinnedMessage, PostPolicy,
+        ReadPosition, UnpinnedMessage, UserInfo, UserPreferences,
+    },
     DBError, DBResult, Database, PageIndex,
 };
-use uuid::Uuid;
+use crate::ids::{ChatId, UserId};
+use crate::metrics;
+use crate::purge::{self, PurgeReport};
+use crate::repair::{self, RepairReport};
+use crate::services::{
+    Authorization, ChatService, ChatSummary, GroupChatCreation, InsertedMessage, ServiceError,
+    SharedHistory, UserService,
+};
+use crate::templates::{self, TemplateChat};
 
-use super::websocket_actor::ChatMessage;
+use super::websocket_actor::{ChatMessage, MessageTombstone};
 
 // База данных должна уметь:
 // 1) Создавать новых пользователей                 +
@@ -19,10 +36,27 @@ use super::websocket_actor::ChatMessage;
 // 7) Выдавать историю сообщений чата               +
 
 pub mod messages {
-    use crate::actors::websocket_actor::ChatMessage;
-    use crate::database::data::{ChatInfo, UserInfo};
+    use crate::actors::websocket_actor::{ChatMessage, MessageTombstone};
+    use crate::config::ChatTemplate;
+    use crate::config::HistoryLimits;
+    use crate::config::NameRules;
+    use crate::config::PurgeConfig;
+    use crate::database::data::{
+        Attachment, BotWebhook, ChannelListing, ChatEvent, ChatInfo, ChatLabels, ChatPermissions,
+        ChatRole, ChatUsage, DeliveryMode, Draft, Mute, NotificationSettings, PinOutcome,
+        PinnedMessage, ReadPosition, SecretKind, UnpinnedMessage, UserInfo, UserPreferences,
+        UserProfile,
+    };
     use crate::database::{DBResult, PageIndex};
+    use crate::ids::{ChatId, UserId};
+    use crate::purge::PurgeReport;
+    use crate::repair::RepairReport;
+    use crate::services::{
+        Authorization, ChatSummary, GroupChatCreation, InsertedMessage, ServiceError, SharedHistory,
+    };
+    use crate::templates::TemplateChat;
     use actix::Message;
+    use std::collections::{HashMap, HashSet};
     use uuid::Uuid;
 
     #[derive(Message)]
@@ -33,75 +67,578 @@ pub mod messages {
     #[rtype(result = "DBResult<()>")]
     pub struct InitDatabaseClear;
 
+    /// Проверить схему базы, в ответ приходит список расхождений
     #[derive(Message)]
-    #[rtype(result = "DBResult<()>")]
+    #[rtype(result = "DBResult<Vec<String>>")]
+    pub struct CheckSchema;
+
+    /// Сохранить новое сообщение, найдя в нем упоминания; возвращает сохраненное сообщение
+    ///
+    /// Повтор сообщения с тем же client_msg_id не сохраняется еще раз
+    #[derive(Message)]
+    #[rtype(result = "DBResult<InsertedMessage>")]
     pub struct InsertNewMessage(pub ChatMessage);
 
+    /// Проверить, что пользователь состоит в чате, иначе LogicError
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct CheckMembership {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    /// Может ли пользователь писать в чат: состоит в нем и политика публикации не против
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct CheckCanPost {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    /// Найти пользователя, а если его нет - создать с проверенным по name_rules именем
+    /// и добавить в чаты default_chats
+    #[derive(Message)]
+    #[rtype(result = "Result<Authorization, ServiceError>")]
+    pub struct AuthorizeUser {
+        pub user_id: UserId,
+        pub user_name: String,
+        pub name_rules: NameRules,
+        pub default_chats: Vec<ChatId>,
+    }
+
     #[derive(Message)]
     #[rtype(result = "DBResult<UserInfo>")]
     pub struct GetUserInfo {
-        pub user_id: i64,
+        pub user_id: UserId,
+    }
+
+    /// Переименовать пользователя в проверенное по name_rules имя
+    #[derive(Message)]
+    #[rtype(result = "Result<UserInfo, ServiceError>")]
+    pub struct RenameUser {
+        pub user_id: UserId,
+        pub new_name: String,
+        pub name_rules: NameRules,
+    }
+
+    /// Заменить поля профиля, в ответ - пользователь с новым профилем
+    #[derive(Message)]
+    #[rtype(result = "DBResult<UserInfo>")]
+    pub struct SetUserProfile {
+        pub user_id: UserId,
+        pub profile: UserProfile,
     }
 
     #[derive(Message)]
-    #[rtype(result = "DBResult<Vec<Uuid>>")]
+    #[rtype(result = "DBResult<chrono::Duration>")]
+    pub struct GetUserCreationDate {
+        pub user_id: UserId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<ChatId>>")]
     pub struct GetUserChats {
-        pub user_id: i64,
+        pub user_id: UserId,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<UserInfo>")]
     pub struct CreateNewUser {
-        pub user_id: i64,
+        pub user_id: UserId,
         pub user_name: String,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<ChatInfo>")]
     pub struct CreateNewPrivateChat {
-        pub creator_id: i64,
+        pub creator_id: UserId,
         pub chat_name: String,
-        pub invited_user_id: i64,
+        pub invited_user_id: UserId,
     }
 
     #[derive(Message)]
-    #[rtype(result = "DBResult<ChatInfo>")]
+    #[rtype(result = "DBResult<GroupChatCreation>")]
     pub struct CreateNewGroupChat {
-        pub creator_id: i64,
-        pub invited_users_id: Vec<i64>,
+        pub creator_id: UserId,
+        pub invited_users_id: Vec<UserId>,
         pub chat_name: String,
+        /// Создать чат без незарегистрированных приглашенных вместо отказа
+        pub skip_unregistered: bool,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<ChatInfo>")]
     pub struct GetChatInfo {
-        pub user_id: i64,
-        pub chat_id: Uuid,
+        pub user_id: UserId,
+        pub chat_id: ChatId,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<()>")]
     pub struct InviteUserToChat {
-        pub user_id: i64,
-        pub chat_id: Uuid,
-        pub guest_user_id: i64,
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub guest_user_id: UserId,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<()>")]
     pub struct ExitChat {
-        pub user_id: i64,
-        pub chat_id: Uuid,
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct RemoveUserFromChat {
+        pub admin_id: UserId,
+        pub target_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetPermissions {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub permissions: ChatPermissions,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetRole {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub target_id: UserId,
+        pub role: ChatRole,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct RenameChat {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub new_name: String,
     }
 
     #[derive(Message)]
     #[rtype(result = "DBResult<(Vec<ChatMessage>, PageIndex)>")]
+    /// Страница истории, page_size уменьшается по history_limits, если сообщения чата крупные
     pub struct GetChatHistory {
-        pub user_id: i64,
-        pub chat_id: Uuid,
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub page_index: Option<PageIndex>,
+        pub page_size: usize,
+        pub history_limits: HistoryLimits,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<u64>")]
+    pub struct GetMessageCount {
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatUsage>")]
+    pub struct GetChatUsage {
+        pub chat_id: ChatId,
+    }
+
+    /// Страница ленты событий чата, участие в чате не проверяется
+    #[derive(Message)]
+    #[rtype(result = "DBResult<(Vec<ChatEvent>, Option<i64>)>")]
+    pub struct GetChatEvents {
+        pub chat_id: ChatId,
+        pub since_position: Option<i64>,
+        pub page_size: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<u64>")]
+    pub struct GetMemberCount {
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<(Vec<ChatMessage>, PageIndex)>")]
+    pub struct GetThread {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub message_id: Uuid,
         pub page_index: Option<PageIndex>,
         pub page_size: usize,
     }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<UserInfo>>")]
+    pub struct GetUsersInfo {
+        pub user_ids: Vec<UserId>,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<(Vec<UserId>, Option<UserId>)>")]
+    pub struct GetChatMembersPaged {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub after_user: Option<UserId>,
+        pub page_size: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<(Vec<UserInfo>, Option<i64>)>")]
+    pub struct GetUserListPaged {
+        pub after_token: Option<i64>,
+        pub page_size: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<ChatMessage>>")]
+    /// Сообщения раньше before, limit уменьшается по history_limits, если сообщения чата крупные
+    pub struct GetChatHistoryBefore {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub before: Option<chrono::Duration>,
+        pub limit: usize,
+        pub history_limits: HistoryLimits,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Option<DeliveryMode>>")]
+    pub struct GetDeliveryMode {
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetDeliveryMode {
+        pub chat_id: ChatId,
+        pub mode: DeliveryMode,
+    }
+
+    /// Задать ограничение числа участников чата, None - общее ограничение
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetMemberLimit {
+        pub chat_id: ChatId,
+        pub max_members: Option<u32>,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetMessageTtl {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub ttl_secs: u32,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetChatLabels {
+        pub chat_id: ChatId,
+        pub labels: ChatLabels,
+    }
+
+    /// Чаты пользователя для подробного списка
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<ChatSummary>>")]
+    pub struct GetChatSummaries {
+        pub user_id: UserId,
+    }
+
+    /// Отрезок истории чата по ссылке, сообщения читаются от имени created_by
+    #[derive(Message)]
+    #[rtype(result = "DBResult<SharedHistory>")]
+    pub struct GetSharedHistory {
+        pub created_by: UserId,
+        pub chat_id: ChatId,
+        pub from: chrono::Duration,
+        pub to: chrono::Duration,
+        pub limit: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<HashMap<ChatId, i64>>")]
+    pub struct GetUnreadCounts {
+        pub user_id: UserId,
+        /// Отдавать и счетчики чатов с отключенными уведомлениями
+        pub include_muted: bool,
+    }
+
+    /// Пользователь прочитал чат до сообщения position включительно
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct MarkRead {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub position: ReadPosition,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<HashMap<ChatId, ReadPosition>>")]
+    pub struct GetReadPositions {
+        pub user_id: UserId,
+    }
+
+    /// Отправить чат в архив или, с archived: false, вернуть из архива
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetArchived {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub archived: bool,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<HashSet<ChatId>>")]
+    pub struct GetArchivedChats {
+        pub user_id: UserId,
+    }
+
+    /// Заблокировать пользователя или, с blocked: false, снять блокировку
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetBlocked {
+        pub user_id: UserId,
+        pub blocked_id: UserId,
+        pub blocked: bool,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<HashSet<UserId>>")]
+    pub struct GetBlockedUsers {
+        pub user_id: UserId,
+    }
+
+    /// Добавить пользователя в контакты, если их меньше max_contacts
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct AddContact {
+        pub user_id: UserId,
+        pub contact_id: UserId,
+        pub max_contacts: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct RemoveContact {
+        pub user_id: UserId,
+        pub contact_id: UserId,
+    }
+
+    /// Сделать пользователя ботом с вебхуком, в ответ - секрет подписи
+    #[derive(Message)]
+    #[rtype(result = "DBResult<String>")]
+    pub struct SetBotWebhook {
+        pub user_id: UserId,
+        pub callback_url: String,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct RemoveBotWebhook {
+        pub user_id: UserId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<BotWebhook>>")]
+    pub struct GetBotWebhooks;
+
+    /// Контакты с именами, по имени
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<UserInfo>>")]
+    pub struct GetContacts {
+        pub user_id: UserId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Option<Draft>>")]
+    pub struct GetDraft {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    /// Сохранить черновик, пустой текст удаляет его
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Option<Draft>>")]
+    pub struct SaveDraft {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub text: String,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetNotificationSettings {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub settings: NotificationSettings,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<HashMap<ChatId, NotificationSettings>>")]
+    pub struct GetAllNotificationSettings {
+        pub user_id: UserId,
+    }
+
+    /// Отключить уведомления чата, None - включить обратно
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct SetMute {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub mute: Option<Mute>,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<UserPreferences>")]
+    pub struct GetUserPreferences {
+        pub user_id: UserId,
+    }
+
+    /// Заменить общие настройки уведомлений, None - вернуть значения по умолчанию
+    #[derive(Message)]
+    #[rtype(result = "DBResult<UserPreferences>")]
+    pub struct SetUserPreferences {
+        pub user_id: UserId,
+        pub preferences: Option<UserPreferences>,
+    }
+
+    /// Создать групповой чат по шаблону, имя уже подставлено
+    #[derive(Message)]
+    #[rtype(result = "DBResult<TemplateChat>")]
+    pub struct CreateChatFromTemplate {
+        pub creator_id: UserId,
+        pub template: ChatTemplate,
+        pub chat_name: String,
+        pub extra_members: Vec<UserId>,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<PinnedMessage>>")]
+    pub struct GetPins {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<PinOutcome>")]
+    pub struct PinMessage {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub message_id: Uuid,
+        /// Когда закрепление снимется само, от начала эпохи
+        pub expires_at: Option<chrono::Duration>,
+        pub max_pins: usize,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<UnpinnedMessage>")]
+    pub struct UnpinMessage {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub message_id: Uuid,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct AddAttachment(pub Attachment);
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Attachment>")]
+    pub struct GetAttachment {
+        pub user_id: UserId,
+        pub attachment_id: Uuid,
+    }
+
+    /// Снять закрепления с истекшим сроком
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<UnpinnedMessage>>")]
+    pub struct ExpirePins;
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatMessage>")]
+    pub struct EditMessage {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub message_id: Uuid,
+        pub date: chrono::Duration,
+        pub msg_text: String,
+    }
+
+    /// Проверить связи пользователей и чатов, при fix = true починить их
+    #[derive(Message)]
+    #[rtype(result = "DBResult<RepairReport>")]
+    pub struct RepairMemberships {
+        pub fix: bool,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<PurgeReport>")]
+    pub struct PurgeAbandonedChats(pub PurgeConfig);
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatMessage>")]
+    pub struct ForwardMessage {
+        pub user_id: UserId,
+        pub from_chat_id: ChatId,
+        pub message_id: Uuid,
+        pub to_chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<MessageTombstone>")]
+    pub struct DeleteMessage {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub message_id: Uuid,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<String>")]
+    pub struct RotateChatSecret {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub kind: SecretKind,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<()>")]
+    pub struct RevokeChatSecret {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub kind: SecretKind,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatInfo>")]
+    pub struct JoinChatByInvite {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+        pub invite_code: String,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatInfo>")]
+    pub struct CreateNewChannel {
+        pub creator_id: UserId,
+        pub chat_name: String,
+        /// Пишут только владелец и администраторы, остальные участники читают
+        pub broadcast: bool,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<ChatInfo>")]
+    pub struct JoinPublicChannel {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "DBResult<Vec<ChannelListing>>")]
+    pub struct DiscoverChannels {
+        pub query: String,
+        pub limit: usize,
+    }
 }
 
 pub struct DatabaseActor {
@@ -114,6 +651,42 @@ impl DatabaseActor {
         let db: Arc<Box<dyn Database>> = Arc::new(Box::new(db));
         Ok(Self { db })
     }
+
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self, DBError> {
+        let db = crate::database::ScyllaDatabase::connect(config).await?;
+        metrics::watch_scylla_driver(db.client.get_metrics());
+        let db: Arc<Box<dyn Database>> = Arc::new(Box::new(db));
+        Ok(Self { db })
+    }
+
+    /// Актор над уже готовой базой, например над базой в памяти
+    pub fn from_database(db: impl Database + 'static) -> Self {
+        let db: Arc<Box<dyn Database>> = Arc::new(Box::new(db));
+        Self { db }
+    }
+}
+
+/// Размер страницы истории с учетом среднего размера сообщений чата
+///
+/// Если статистику прочитать не удалось, то отдаем столько, сколько запросили
+async fn adapt_page_size(
+    db: &Arc<Box<dyn Database>>,
+    chat_id: ChatId,
+    requested: usize,
+    limits: &HistoryLimits,
+) -> usize {
+    let average = match db.get_average_message_size(chat_id).await {
+        Ok(average) => average,
+        Err(e) => {
+            warn!("Cannot get message stats of chat {chat_id}: {e}");
+            None
+        }
+    };
+    let page_size = limits.page_size(requested, average);
+    if page_size < requested {
+        metrics::SHRUNK_HISTORY_PAGES.inc();
+    }
+    page_size
 }
 
 impl Actor for DatabaseActor {
@@ -121,37 +694,116 @@ impl Actor for DatabaseActor {
 }
 
 impl Handler<messages::InsertNewMessage> for DatabaseActor {
-    type Result = ResponseFuture<DBResult<()>>;
+    type Result = ResponseFuture<DBResult<InsertedMessage>>;
     fn handle(
         &mut self,
         msg: messages::InsertNewMessage,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
         let db = self.db.clone();
-        Box::pin(async move { db.add_new_message_to_chat(msg.0).await })
+        Box::pin(async move {
+            let received_at = msg.0.date.timestamp;
+            let result = ChatService::new(&**db).send_message(msg.0).await;
+            metrics::MESSAGE_PERSIST_LATENCY
+                .with_label_values(&[if result.is_ok() { "ok" } else { "error" }])
+                .observe(metrics::seconds_since(received_at));
+            result
+        })
     }
 }
 
-impl Handler<messages::GetUserInfo> for DatabaseActor {
-    type Result = ResponseFuture<DBResult<UserInfo>>;
-    fn handle(&mut self, msg: messages::GetUserInfo, _ctx: &mut Self::Context) -> Self::Result {
+impl Handler<messages::CheckMembership> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::CheckMembership, _ctx: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
-        Box::pin(async move { db.get_user_info(msg.user_id).await })
+        Box::pin(async move {
+            ChatService::new(&**db)
+                .ensure_member(msg.user_id, msg.chat_id)
+                .await
+        })
     }
 }
 
-impl Handler<messages::GetUserChats> for DatabaseActor {
-    type Result = ResponseFuture<DBResult<Vec<Uuid>>>;
-    fn handle(&mut self, msg: messages::GetUserChats, _ctx: &mut Self::Context) -> Self::Result {
+impl Handler<messages::CheckCanPost> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::CheckCanPost, _ctx: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
-        Box::pin(async move { db.get_user_chats(msg.user_id).await })
+        Box::pin(async move { db.check_can_post(msg.user_id, msg.chat_id).await })
     }
 }
 
-impl Handler<messages::CreateNewUser> for DatabaseActor {
-    type Result = ResponseFuture<DBResult<UserInfo>>;
-
-    fn handle(&mut self, msg: messages::CreateNewUser, _ctx: &mut Self::Context) -> Self::Result {
+impl Handler<messages::AuthorizeUser> for DatabaseActor {
+    type Result = ResponseFuture<Result<Authorization, ServiceError>>;
+    fn handle(&mut self, msg: messages::AuthorizeUser, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            UserService::new(&**db)
+                .authorize(
+                    msg.user_id,
+                    &msg.user_name,
+                    &msg.name_rules,
+                    &msg.default_chats,
+                )
+                .await
+        })
+    }
+}
+
+impl Handler<messages::GetUserCreationDate> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<chrono::Duration>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetUserCreationDate,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_user_creation_date(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::GetUserInfo> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UserInfo>>;
+    fn handle(&mut self, msg: messages::GetUserInfo, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_user_info(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::RenameUser> for DatabaseActor {
+    type Result = ResponseFuture<Result<UserInfo, ServiceError>>;
+    fn handle(&mut self, msg: messages::RenameUser, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            UserService::new(&**db)
+                .rename(msg.user_id, &msg.new_name, &msg.name_rules)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::SetUserProfile> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UserInfo>>;
+    fn handle(&mut self, msg: messages::SetUserProfile, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.set_user_profile(msg.user_id, msg.profile).await?;
+            db.get_user_info(msg.user_id).await
+        })
+    }
+}
+
+impl Handler<messages::GetUserChats> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<ChatId>>>;
+    fn handle(&mut self, msg: messages::GetUserChats, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_user_chats(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::CreateNewUser> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UserInfo>>;
+
+    fn handle(&mut self, msg: messages::CreateNewUser, _ctx: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         Box::pin(async move { db.create_new_user(msg.user_id, msg.user_name).await })
     }
@@ -178,7 +830,7 @@ impl Handler<messages::CreateNewPrivateChat> for DatabaseActor {
 }
 
 impl Handler<messages::CreateNewGroupChat> for DatabaseActor {
-    type Result = ResponseFuture<DBResult<ChatInfo>>;
+    type Result = ResponseFuture<DBResult<GroupChatCreation>>;
     fn handle(
         &mut self,
         msg: messages::CreateNewGroupChat,
@@ -186,13 +838,14 @@ impl Handler<messages::CreateNewGroupChat> for DatabaseActor {
     ) -> Self::Result {
         let db = self.db.clone();
         Box::pin(async move {
-            db.create_new_chat(
-                msg.creator_id,
-                msg.invited_users_id,
-                ChatType::Group,
-                msg.chat_name,
-            )
-            .await
+            ChatService::new(&**db)
+                .create_group_chat(
+                    msg.creator_id,
+                    msg.invited_users_id,
+                    msg.chat_name,
+                    msg.skip_unregistered,
+                )
+                .await
         })
     }
 }
@@ -228,17 +881,116 @@ impl Handler<messages::ExitChat> for DatabaseActor {
     }
 }
 
+impl Handler<messages::RemoveUserFromChat> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(
+        &mut self,
+        msg: messages::RemoveUserFromChat,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.remove_user_from_chat(msg.admin_id, msg.target_id, msg.chat_id)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::SetPermissions> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetPermissions, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.set_permissions(msg.user_id, msg.chat_id, msg.permissions)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::SetRole> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetRole, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.set_role(msg.user_id, msg.chat_id, msg.target_id, msg.role)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::RenameChat> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::RenameChat, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.rename_chat(msg.user_id, msg.chat_id, msg.new_name).await })
+    }
+}
+
 impl Handler<messages::GetChatHistory> for DatabaseActor {
     type Result = ResponseFuture<DBResult<(Vec<ChatMessage>, PageIndex)>>;
     fn handle(&mut self, msg: messages::GetChatHistory, _ctx: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         Box::pin(async move {
-            db.get_chat_history_paged(msg.user_id, msg.chat_id, msg.page_size, msg.page_index)
+            let page_size =
+                adapt_page_size(&db, msg.chat_id, msg.page_size, &msg.history_limits).await;
+            db.get_chat_history_paged(msg.user_id, msg.chat_id, page_size, msg.page_index)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::GetMessageCount> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<u64>>;
+    fn handle(&mut self, msg: messages::GetMessageCount, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_message_count(msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::GetChatUsage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatUsage>>;
+    fn handle(&mut self, msg: messages::GetChatUsage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_chat_usage(msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::GetChatEvents> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<(Vec<ChatEvent>, Option<i64>)>>;
+    fn handle(&mut self, msg: messages::GetChatEvents, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.get_chat_events(msg.chat_id, msg.since_position, msg.page_size)
                 .await
         })
     }
 }
 
+impl Handler<messages::GetMemberCount> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<u64>>;
+    fn handle(&mut self, msg: messages::GetMemberCount, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_member_count(msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::GetThread> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<(Vec<ChatMessage>, PageIndex)>>;
+    fn handle(&mut self, msg: messages::GetThread, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.get_thread_paged(
+                msg.user_id,
+                msg.chat_id,
+                msg.message_id,
+                msg.page_size,
+                msg.page_index,
+            )
+            .await
+        })
+    }
+}
+
 impl Handler<messages::InitDatabase> for DatabaseActor {
     type Result = ResponseFuture<DBResult<()>>;
     fn handle(&mut self, _msg: messages::InitDatabase, _ctx: &mut Self::Context) -> Self::Result {
@@ -247,6 +999,14 @@ impl Handler<messages::InitDatabase> for DatabaseActor {
     }
 }
 
+impl Handler<messages::CheckSchema> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<String>>>;
+    fn handle(&mut self, _msg: messages::CheckSchema, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.check_schema().await })
+    }
+}
+
 impl Handler<messages::InitDatabaseClear> for DatabaseActor {
     type Result = ResponseFuture<DBResult<()>>;
     fn handle(
@@ -258,3 +1018,601 @@ impl Handler<messages::InitDatabaseClear> for DatabaseActor {
         Box::pin(async move { db.init_db_clear().await })
     }
 }
+
+impl Handler<messages::RotateChatSecret> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<String>>;
+    fn handle(
+        &mut self,
+        msg: messages::RotateChatSecret,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.rotate_chat_secret(msg.user_id, msg.chat_id, msg.kind)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::RevokeChatSecret> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(
+        &mut self,
+        msg: messages::RevokeChatSecret,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.revoke_chat_secret(msg.user_id, msg.chat_id, msg.kind)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::JoinChatByInvite> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatInfo>>;
+    fn handle(
+        &mut self,
+        msg: messages::JoinChatByInvite,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.join_chat_by_invite(msg.user_id, msg.chat_id, msg.invite_code)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::CreateNewChannel> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatInfo>>;
+    fn handle(
+        &mut self,
+        msg: messages::CreateNewChannel,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            let mut chat = db
+                .create_new_chat(msg.creator_id, vec![], ChatType::Channel, msg.chat_name)
+                .await?;
+            if msg.broadcast {
+                db.set_post_policy(ChatId(chat.id), PostPolicy::AdminsOnly)
+                    .await?;
+                chat.post_policy = PostPolicy::AdminsOnly;
+            }
+            Ok(chat)
+        })
+    }
+}
+
+impl Handler<messages::JoinPublicChannel> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatInfo>>;
+    fn handle(
+        &mut self,
+        msg: messages::JoinPublicChannel,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.join_public_channel(msg.user_id, msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::DiscoverChannels> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<ChannelListing>>>;
+    fn handle(
+        &mut self,
+        msg: messages::DiscoverChannels,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.discover_channels(msg.query, msg.limit).await })
+    }
+}
+
+impl Handler<messages::GetUserListPaged> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<(Vec<UserInfo>, Option<i64>)>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetUserListPaged,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_user_list_paged(msg.after_token, msg.page_size).await })
+    }
+}
+
+impl Handler<messages::GetChatMembersPaged> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<(Vec<UserId>, Option<UserId>)>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetChatMembersPaged,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.get_chat_members_paged(msg.user_id, msg.chat_id, msg.after_user, msg.page_size)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::GetUsersInfo> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<UserInfo>>>;
+    fn handle(&mut self, msg: messages::GetUsersInfo, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_users_info(msg.user_ids).await })
+    }
+}
+
+impl Handler<messages::GetChatHistoryBefore> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<ChatMessage>>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetChatHistoryBefore,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            let limit = adapt_page_size(&db, msg.chat_id, msg.limit, &msg.history_limits).await;
+            db.get_chat_history_before(msg.user_id, msg.chat_id, msg.before, limit)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::EditMessage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatMessage>>;
+    fn handle(&mut self, msg: messages::EditMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.edit_message(
+                msg.user_id,
+                msg.chat_id,
+                msg.message_id,
+                msg.date,
+                msg.msg_text,
+            )
+            .await
+        })
+    }
+}
+
+impl Handler<messages::ForwardMessage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<ChatMessage>>;
+    fn handle(&mut self, msg: messages::ForwardMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.forward_message(
+                msg.user_id,
+                msg.from_chat_id,
+                msg.message_id,
+                msg.to_chat_id,
+            )
+            .await
+        })
+    }
+}
+
+impl Handler<messages::GetDeliveryMode> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Option<DeliveryMode>>>;
+    fn handle(&mut self, msg: messages::GetDeliveryMode, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_delivery_mode(msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::SetMessageTtl> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetMessageTtl, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.set_message_ttl(msg.user_id, msg.chat_id, msg.ttl_secs)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::SetChatLabels> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetChatLabels, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.set_chat_labels(msg.chat_id, msg.labels).await })
+    }
+}
+
+impl Handler<messages::SetDeliveryMode> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetDeliveryMode, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.set_delivery_mode(msg.chat_id, msg.mode).await })
+    }
+}
+
+impl Handler<messages::SetMemberLimit> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetMemberLimit, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.set_member_limit(msg.chat_id, msg.max_members).await })
+    }
+}
+
+impl Handler<messages::GetChatSummaries> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<ChatSummary>>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetChatSummaries,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { ChatService::new(&**db).chat_summaries(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::GetSharedHistory> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<SharedHistory>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetSharedHistory,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            ChatService::new(&**db)
+                .shared_history(msg.created_by, msg.chat_id, msg.from, msg.to, msg.limit)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::GetUnreadCounts> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<HashMap<ChatId, i64>>>;
+    fn handle(&mut self, msg: messages::GetUnreadCounts, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            let mut counts = db.get_unread_counts(msg.user_id).await?;
+            if !msg.include_muted {
+                let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+                let settings = db.get_all_notification_settings(msg.user_id).await?;
+                counts.retain(|chat_id, _| {
+                    !settings
+                        .get(chat_id)
+                        .is_some_and(|settings| settings.is_muted_at(now))
+                });
+            }
+            Ok(counts)
+        })
+    }
+}
+
+impl Handler<messages::MarkRead> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::MarkRead, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.save_read_position(msg.user_id, msg.chat_id, msg.position)
+                .await?;
+            db.reset_unread(msg.user_id, msg.chat_id).await
+        })
+    }
+}
+
+impl Handler<messages::GetReadPositions> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<HashMap<ChatId, ReadPosition>>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetReadPositions,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_read_positions(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::SetArchived> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetArchived, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            match msg.archived {
+                true => db.archive_chat(msg.user_id, msg.chat_id).await,
+                false => db.unarchive_chat(msg.user_id, msg.chat_id).await,
+            }
+        })
+    }
+}
+
+impl Handler<messages::GetArchivedChats> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<HashSet<ChatId>>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetArchivedChats,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_archived_chats(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::SetBlocked> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetBlocked, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            match msg.blocked {
+                true => db.block_user(msg.user_id, msg.blocked_id).await,
+                false => db.unblock_user(msg.user_id, msg.blocked_id).await,
+            }
+        })
+    }
+}
+
+impl Handler<messages::GetBlockedUsers> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<HashSet<UserId>>>;
+    fn handle(&mut self, msg: messages::GetBlockedUsers, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_blocked_users(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::AddContact> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::AddContact, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            UserService::new(&**db)
+                .add_contact(msg.user_id, msg.contact_id, msg.max_contacts)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::RemoveContact> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::RemoveContact, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.remove_contact(msg.user_id, msg.contact_id).await })
+    }
+}
+
+impl Handler<messages::SetBotWebhook> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<String>>;
+    fn handle(&mut self, msg: messages::SetBotWebhook, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.set_bot_webhook(msg.user_id, msg.callback_url).await })
+    }
+}
+
+impl Handler<messages::RemoveBotWebhook> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(
+        &mut self,
+        msg: messages::RemoveBotWebhook,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.remove_bot_webhook(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::GetBotWebhooks> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<BotWebhook>>>;
+    fn handle(&mut self, _msg: messages::GetBotWebhooks, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_bot_webhooks().await })
+    }
+}
+
+impl Handler<messages::GetContacts> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<UserInfo>>>;
+    fn handle(&mut self, msg: messages::GetContacts, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { UserService::new(&**db).contacts(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::GetDraft> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Option<Draft>>>;
+    fn handle(&mut self, msg: messages::GetDraft, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_draft(msg.user_id, msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::SaveDraft> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Option<Draft>>>;
+    fn handle(&mut self, msg: messages::SaveDraft, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.save_draft(msg.user_id, msg.chat_id, msg.text).await })
+    }
+}
+
+impl Handler<messages::SetNotificationSettings> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(
+        &mut self,
+        msg: messages::SetNotificationSettings,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.set_notification_settings(msg.user_id, msg.chat_id, msg.settings)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::GetAllNotificationSettings> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<HashMap<ChatId, NotificationSettings>>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetAllNotificationSettings,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_all_notification_settings(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::SetMute> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::SetMute, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.set_mute(msg.user_id, msg.chat_id, msg.mute).await })
+    }
+}
+
+impl Handler<messages::GetUserPreferences> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UserPreferences>>;
+    fn handle(
+        &mut self,
+        msg: messages::GetUserPreferences,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_user_preferences(msg.user_id).await })
+    }
+}
+
+impl Handler<messages::SetUserPreferences> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UserPreferences>>;
+    fn handle(
+        &mut self,
+        msg: messages::SetUserPreferences,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            match msg.preferences {
+                Some(preferences) => {
+                    db.set_user_preferences(msg.user_id, preferences.clone())
+                        .await?;
+                    Ok(preferences)
+                }
+                None => {
+                    db.delete_user_preferences(msg.user_id).await?;
+                    Ok(UserPreferences::default())
+                }
+            }
+        })
+    }
+}
+
+impl Handler<messages::CreateChatFromTemplate> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<TemplateChat>>;
+    fn handle(
+        &mut self,
+        msg: messages::CreateChatFromTemplate,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            templates::create_from_template(
+                &**db,
+                msg.creator_id,
+                &msg.template,
+                msg.chat_name,
+                msg.extra_members,
+            )
+            .await
+        })
+    }
+}
+
+impl Handler<messages::GetPins> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<PinnedMessage>>>;
+    fn handle(&mut self, msg: messages::GetPins, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_pins(msg.user_id, msg.chat_id).await })
+    }
+}
+
+impl Handler<messages::PinMessage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<PinOutcome>>;
+    fn handle(&mut self, msg: messages::PinMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.pin_message(
+                msg.user_id,
+                msg.chat_id,
+                msg.message_id,
+                msg.expires_at,
+                msg.max_pins,
+            )
+            .await
+        })
+    }
+}
+
+impl Handler<messages::UnpinMessage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<UnpinnedMessage>>;
+    fn handle(&mut self, msg: messages::UnpinMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.unpin_message(msg.user_id, msg.chat_id, msg.message_id)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::AddAttachment> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<()>>;
+    fn handle(&mut self, msg: messages::AddAttachment, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.add_attachment(msg.0).await })
+    }
+}
+
+impl Handler<messages::GetAttachment> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Attachment>>;
+    fn handle(&mut self, msg: messages::GetAttachment, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { db.get_attachment(msg.user_id, msg.attachment_id).await })
+    }
+}
+
+impl Handler<messages::ExpirePins> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<Vec<UnpinnedMessage>>>;
+    fn handle(&mut self, _msg: messages::ExpirePins, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.expire_pins(chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::DeleteMessage> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<MessageTombstone>>;
+    fn handle(&mut self, msg: messages::DeleteMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.delete_message(msg.user_id, msg.chat_id, msg.message_id)
+                .await
+        })
+    }
+}
+
+impl Handler<messages::PurgeAbandonedChats> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<PurgeReport>>;
+    fn handle(
+        &mut self,
+        msg: messages::PurgeAbandonedChats,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { purge::purge_abandoned_chats(&**db, &msg.0).await })
+    }
+}
+
+impl Handler<messages::RepairMemberships> for DatabaseActor {
+    type Result = ResponseFuture<DBResult<RepairReport>>;
+    fn handle(
+        &mut self,
+        msg: messages::RepairMemberships,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let db = self.db.clone();
+        Box::pin(async move { repair::repair(&**db, msg.fix).await })
+    }
+}
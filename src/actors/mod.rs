@@ -1,4 +1,6 @@
+pub mod bot_actor;
 pub mod broker_actor;
 pub mod database_actor;
 pub mod redis_actor;
+pub mod storage_actor;
 pub mod websocket_actor;
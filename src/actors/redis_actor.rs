@@ -1,20 +1,272 @@
-use crate::actors::websocket_actor::ChatMessage;
+use crate::{
+    actors::websocket_actor::{messages::BrokerMessage, ChatMessage, MessageTombstone},
+    calls::CallSignalKind,
+    config::{DeliveryConfig, PresenceConfig, RedisConfig},
+    database::data::{DeliveryMode, UnpinnedMessage, UserProfile},
+    ids::{ChatId, UserId},
+    metrics,
+    presence::PresenceTracker,
+    redis_topology::RedisConnector,
+    serializable_duration::SerializableDuration,
+    transport::{ControlLog, PubSubTransport, StreamTransport, Transport, MESSAGE_CHANNEL},
+};
 use actix::prelude::*;
 use futures_util::StreamExt;
-use redis::AsyncCommands;
+use log::{info, warn};
+use redis::aio::PubSub;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-use super::broker_actor::{self, BrokerActor};
+use super::{
+    bot_actor::{self, BotActor},
+    broker_actor::{self, BrokerActor},
+    database_actor::{
+        messages::{GetDeliveryMode, GetMemberCount, GetUserChats},
+        DatabaseActor,
+    },
+};
+
+// Каналы, через которые экземпляры сервиса обмениваются сообщениями
+// К именам добавляется префикс окружения из конфигурации
+const MESSAGE_EDITED_CHANNEL: &str = "message_edited";
+const MESSAGE_DELETED_CHANNEL: &str = "message_deleted";
+const MESSAGE_UNPINNED_CHANNEL: &str = "message_unpinned";
+const SUBSCRIBE_CHANNEL: &str = "subscribe";
+const UNSUBSCRIBE_CHANNEL: &str = "unsubscribe";
+const DELIVERY_MODE_CHANNEL: &str = "delivery_mode";
+const SESSION_REVOKED_CHANNEL: &str = "session_revoked";
+const TYPING_CHANNEL: &str = "typing";
+const READ_POSITION_CHANNEL: &str = "read_position";
+const CHAT_RENAMED_CHANNEL: &str = "chat_renamed";
+const MEMBER_REMOVED_CHANNEL: &str = "member_removed";
+const PRESENCE_CHANNEL: &str = "presence";
+const EPHEMERAL_CHANNEL: &str = "ephemeral";
+const CALL_SIGNAL_CHANNEL: &str = "call_signal";
+const USER_BLOCKS_CHANNEL: &str = "user_blocks";
+const PROFILE_UPDATED_CHANNEL: &str = "profile_updated";
+const NOTIFICATIONS_CHANNEL: &str = "notification_settings";
+
+/// Каналы, на которые подписан каждый экземпляр
+const CHANNELS: [&str; 18] = [
+    MESSAGE_CHANNEL,
+    MESSAGE_EDITED_CHANNEL,
+    MESSAGE_DELETED_CHANNEL,
+    MESSAGE_UNPINNED_CHANNEL,
+    SUBSCRIBE_CHANNEL,
+    UNSUBSCRIBE_CHANNEL,
+    DELIVERY_MODE_CHANNEL,
+    SESSION_REVOKED_CHANNEL,
+    TYPING_CHANNEL,
+    READ_POSITION_CHANNEL,
+    CHAT_RENAMED_CHANNEL,
+    MEMBER_REMOVED_CHANNEL,
+    PRESENCE_CHANNEL,
+    EPHEMERAL_CHANNEL,
+    CALL_SIGNAL_CHANNEL,
+    USER_BLOCKS_CHANNEL,
+    PROFILE_UPDATED_CHANNEL,
+    NOTIFICATIONS_CHANNEL,
+];
+
+/// Каналы управляющих сообщений: они меняют состояние брокера, поэтому публикуются
+/// через журнал и дочитываются после переподключения
+const CONTROL_CHANNELS: [&str; 7] = [
+    SUBSCRIBE_CHANNEL,
+    UNSUBSCRIBE_CHANNEL,
+    DELIVERY_MODE_CHANNEL,
+    SESSION_REVOKED_CHANNEL,
+    MEMBER_REMOVED_CHANNEL,
+    USER_BLOCKS_CHANNEL,
+    NOTIFICATIONS_CHANNEL,
+];
+
+/// id записи журнала, которую ControlLog добавляет к управляющему сообщению
+#[derive(Deserialize)]
+struct ControlStamp {
+    log_id: Option<String>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct SubscriptionData {
-    pub chat_id: Uuid,
-    pub user_id: i64,
+    pub chat_id: ChatId,
+    pub user_id: UserId,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct DeliveryModeData {
+    pub chat_id: ChatId,
+    pub mode: DeliveryMode,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionRevokedData {
+    pub user_id: UserId,
+    pub session_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TypingData {
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+}
+
+/// Кратковременный сигнал участникам чата (курсор, геопозиция, звонок), в базу не пишется
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EphemeralData {
+    pub chat_id: ChatId,
+    pub sender_id: UserId,
+    /// Вид сигнала, его задает и понимает клиент
+    pub kind: String,
+    pub payload: serde_json::Value,
+}
+
+/// Кадр сигнализации звонка одному участнику чата, в базу не пишется
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CallSignalData {
+    pub chat_id: ChatId,
+    pub call_id: Uuid,
+    pub from_user: UserId,
+    pub to_user: UserId,
+    pub signal: CallSignalKind,
+    /// Описание SDP или кандидат ICE, их понимают только клиенты
+    pub payload: serde_json::Value,
+}
+
+/// Пользователь дочитал чат до сообщения на одном из своих устройств
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadPositionData {
+    pub user_id: UserId,
+    pub chat_id: ChatId,
+    pub message_id: Uuid,
+    pub date: SerializableDuration,
+    /// Сокет, с которого пришла отметка, ему событие не отправляется
+    pub origin: Uuid,
+}
+
+/// Чат переименовали
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatRenamedData {
+    pub chat_id: ChatId,
+    pub name: String,
+    /// Кто переименовал
+    pub renamed_by: UserId,
+}
+
+/// Пользователь сменил имя
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileUpdatedData {
+    pub user_id: UserId,
+    pub name: String,
+    #[serde(flatten, default)]
+    pub profile: UserProfile,
+    /// Чаты пользователя, событие получают их участники
+    pub chats: Vec<ChatId>,
+}
+
+/// Участника исключили из чата
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MemberRemovedData {
+    pub chat_id: ChatId,
+    pub user_id: UserId,
+    /// Кто исключил
+    pub removed_by: UserId,
+}
+
+/// Пользователь появился в сети или вышел из нее на всех экземплярах сразу
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PresenceData {
+    pub user_id: UserId,
+    pub online: bool,
+    /// Чаты пользователя не больше presence.max_chat_size участников, только в них
+    /// рассылаются member_online и member_offline
+    pub chats: Vec<ChatId>,
+}
+
+/// Пользователь заблокировал blocked_id или, с blocked: false, снял блокировку
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockChangedData {
+    pub user_id: UserId,
+    pub blocked_id: UserId,
+    pub blocked: bool,
+}
+
+/// Пользователь поменял настройки уведомлений в одном из чатов
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationsChangedData {
+    pub user_id: UserId,
+    pub chat_id: ChatId,
+}
+
+/// Сколько режимов доставки помнит экземпляр, прежде чем выбросить устаревшие
+const DELIVERY_MODES_CAPACITY: usize = 10000;
+/// Сколько помнится режим доставки, который узнали у базы
+pub const DELIVERY_MODE_TTL: Duration = Duration::from_secs(600);
+
+/// Режимы доставки чатов, которые уже спрашивали у базы
+///
+/// Запись живет не дольше ttl, а при переполнении устаревшие записи выбрасываются,
+/// чтобы чаты, в которые давно не писали, не копились в памяти
+pub struct DeliveryModeCache {
+    ttl: Duration,
+    capacity: usize,
+    modes: HashMap<ChatId, (DeliveryMode, Instant)>,
+}
+
+impl DeliveryModeCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            modes: HashMap::new(),
+        }
+    }
+
+    /// Режим чата, если он запомнен не раньше ttl назад
+    pub fn get(&self, chat_id: ChatId, now: Instant) -> Option<DeliveryMode> {
+        self.modes
+            .get(&chat_id)
+            .filter(|(_, saved)| now.saturating_duration_since(*saved) < self.ttl)
+            .map(|(mode, _)| *mode)
+    }
+
+    pub fn insert(&mut self, chat_id: ChatId, mode: DeliveryMode, now: Instant) {
+        if self.modes.len() >= self.capacity && !self.modes.contains_key(&chat_id) {
+            let ttl = self.ttl;
+            self.modes
+                .retain(|_, (_, saved)| now.saturating_duration_since(*saved) < ttl);
+            // Все записи свежие, тогда место освобождает самая старая
+            if self.modes.len() >= self.capacity {
+                if let Some(oldest) = self
+                    .modes
+                    .iter()
+                    .min_by_key(|(_, (_, saved))| *saved)
+                    .map(|(chat_id, _)| *chat_id)
+                {
+                    self.modes.remove(&oldest);
+                }
+            }
+        }
+        self.modes.insert(chat_id, (mode, now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.modes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modes.is_empty()
+    }
+}
+
+type DeliveryModes = Arc<Mutex<DeliveryModeCache>>;
+
 // Какие сообщения принимает
 pub mod messages {
     use super::*;
@@ -30,13 +282,99 @@ pub mod messages {
     #[rtype(result = "()")]
     pub enum WebsocketMessage {
         NewMessage(ChatMessage),
+        MessageEdited(ChatMessage),
+        MessageDeleted(MessageTombstone),
+        /// С сообщения сняли закрепление
+        MessageUnpinned(UnpinnedMessage),
+        /// Пользователь печатает в чате
+        Typing(TypingData),
+        /// Кратковременный сигнал участникам чата
+        Ephemeral(EphemeralData),
+        /// Кадр сигнализации звонка одному участнику чата
+        CallSignal(CallSignalData),
+        /// Пользователь отметил чат прочитанным
+        ReadPosition(ReadPositionData),
+        /// Чат переименовали
+        ChatRenamed(ChatRenamedData),
+        /// Пользователь сменил имя
+        ProfileUpdated(ProfileUpdatedData),
+        /// Участника исключили из чата
+        MemberRemoved(MemberRemovedData),
+        /// Клиент получил все сообщения чата до delivery_id включительно
+        Ack {
+            chat_id: ChatId,
+            user_id: UserId,
+            delivery_id: String,
+        },
+        /// Дослать в сокет неподтвержденные сообщения чатов пользователя
+        Replay {
+            user_id: UserId,
+            chats: Vec<ChatId>,
+            /// Заблокированные пользователем отправители, их сообщения не досылаются
+            blocked: HashSet<UserId>,
+            socket: Recipient<BrokerMessage>,
+        },
+    }
+
+    /// Режим доставки чата поменялся, об этом надо сказать всем экземплярам сервиса
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct DeliveryModeChanged {
+        pub chat_id: ChatId,
+        pub mode: DeliveryMode,
+    }
+
+    /// Сессию отозвали, ее сокеты надо закрыть на всех экземплярах сервиса
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct SessionRevoked {
+        pub user_id: UserId,
+        pub session_id: String,
+    }
+
+    /// Список блокировки пользователя поменялся, брокерам всех экземпляров надо
+    /// перестать или снова начать доставлять ему сообщения blocked_id
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct BlockChanged {
+        pub user_id: UserId,
+        pub blocked_id: UserId,
+        pub blocked: bool,
+    }
+
+    /// Пользователь поменял настройки уведомлений в чате chat_id: брокерам надо
+    /// перечитать их, чтобы не присылать упоминания, которые он не хочет получать
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct NotificationsChanged {
+        pub user_id: UserId,
+        pub chat_id: ChatId,
+    }
+
+    /// У пользователя открылся (opened) или закрылся сокет на этом экземпляре
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct SocketPresence {
+        pub user_id: UserId,
+        pub opened: bool,
     }
 }
 
 pub struct RedisActor {
-    client: Arc<Mutex<redis::Client>>,
-    connection: Arc<Mutex<redis::aio::Connection>>,
+    connector: RedisConnector,
+    pubsub: PubSubTransport,
+    stream: StreamTransport,
+    control: ControlLog,
     broker: Addr<BrokerActor>,
+    config: RedisConfig,
+    delivery: DeliveryConfig,
+    db: Option<Addr<DatabaseActor>>,
+    modes: DeliveryModes,
+    presence: Option<PresenceTracker>,
+    presence_config: PresenceConfig,
+    /// Сколько сокетов у пользователей на этом экземпляре
+    local_sockets: Arc<Mutex<HashMap<UserId, usize>>>,
+    bots: Option<Addr<BotActor>>,
 }
 
 impl RedisActor {
@@ -45,72 +383,188 @@ impl RedisActor {
         port: u16,
         broker: Addr<BrokerActor>,
     ) -> Result<Self, Box<dyn Error>> {
-        let con_str = format!("redis://{}:{}", host, port);
-        let client = redis::Client::open(con_str)?;
-        let connection = client.get_async_connection().await?;
-        let connection = Arc::new(Mutex::new(connection));
-        let client = Arc::new(Mutex::new(client));
+        let config = RedisConfig {
+            host: host.into(),
+            port,
+            ..Default::default()
+        };
+        Self::connect(&config, broker).await
+    }
+
+    pub async fn connect(
+        config: &RedisConfig,
+        broker: Addr<BrokerActor>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let connector = RedisConnector::new(config);
+        let connection = connector.connect().await?;
+        let pubsub = PubSubTransport::new(connection, config.clone());
+        let delivery = DeliveryConfig::default();
+        let stream = StreamTransport::new(
+            pubsub.clone(),
+            delivery.stream_max_len,
+            delivery.stream_ttl_secs,
+        );
+        let control = ControlLog::new(pubsub.clone(), config.control_log_max_len);
         Ok(RedisActor {
-            connection,
-            client,
+            connector,
+            pubsub,
+            stream,
+            control,
             broker,
+            config: config.clone(),
+            delivery,
+            db: None,
+            modes: Arc::new(Mutex::new(DeliveryModeCache::new(
+                DELIVERY_MODE_TTL,
+                DELIVERY_MODES_CAPACITY,
+            ))),
+            presence: None,
+            presence_config: PresenceConfig::default(),
+            local_sockets: Default::default(),
+            bots: None,
         })
     }
+
+    /// Включает выбор режима доставки по чатам
+    ///
+    /// Без базы все чаты доставляются в режиме по умолчанию
+    pub fn with_delivery(mut self, delivery: DeliveryConfig, db: Addr<DatabaseActor>) -> Self {
+        self.stream = StreamTransport::new(
+            self.pubsub.clone(),
+            delivery.stream_max_len,
+            delivery.stream_ttl_secs,
+        );
+        self.delivery = delivery;
+        self.db = Some(db);
+        self
+    }
+
+    /// Включает учет присутствия пользователей в сети
+    ///
+    /// Без него события member_online и member_offline не рассылаются
+    pub fn with_presence(mut self, tracker: PresenceTracker, config: PresenceConfig) -> Self {
+        self.presence = Some(tracker);
+        self.presence_config = config;
+        self
+    }
+
+    /// Включает доставку сообщений ботам на их вебхуки
+    ///
+    /// Ботам уходят сообщения, которые публикует этот экземпляр, так что каждое
+    /// сообщение бот получает один раз
+    pub fn with_bots(mut self, bots: Addr<BotActor>) -> Self {
+        self.bots = Some(bots);
+        self
+    }
+
+    /// Возвращает future, которое узнает режим доставки чата
+    ///
+    /// Если база недоступна, то используется режим по умолчанию, но он не запоминается
+    fn delivery_mode(&self, chat_id: ChatId) -> impl std::future::Future<Output = DeliveryMode> {
+        let modes = self.modes.clone();
+        let db = self.db.clone();
+        let default_mode = self.delivery.default_mode;
+        async move {
+            if let Some(mode) = modes.lock().await.get(chat_id, Instant::now()) {
+                return mode;
+            }
+            let Some(db) = db else {
+                return default_mode;
+            };
+            match db.send(GetDeliveryMode { chat_id }).await {
+                Ok(Ok(mode)) => {
+                    let mode = mode.unwrap_or(default_mode);
+                    modes.lock().await.insert(chat_id, mode, Instant::now());
+                    mode
+                }
+                Ok(Err(e)) => {
+                    warn!("Cannot get delivery mode of chat {chat_id}: {e}");
+                    default_mode
+                }
+                Err(e) => {
+                    warn!("Cannot get delivery mode of chat {chat_id}: {e}");
+                    default_mode
+                }
+            }
+        }
+    }
 }
 
 impl Actor for RedisActor {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
-        let client = self.client.clone();
-
+        if let Some(tracker) = self.presence.clone() {
+            ctx.run_interval(tracker.refresh_interval(), move |act, ctx| {
+                let tracker = tracker.clone();
+                let sockets = act.local_sockets.clone();
+                ctx.spawn(
+                    async move {
+                        let users: Vec<UserId> = sockets.lock().await.keys().copied().collect();
+                        if let Err(e) = tracker.refresh(&users).await {
+                            warn!("Cannot refresh presence of {} users: {e}", users.len());
+                        }
+                    }
+                    .into_actor(act),
+                );
+            });
+        }
+        let connector = self.connector.clone();
+        let control = self.control.clone();
         let broker = self.broker.clone();
+        let config = self.config.clone();
+        let modes = self.modes.clone();
         Box::pin(async move {
-            let receiver = client.lock().await.get_async_connection().await.unwrap();
-            // Делаем ресивер из подключения
-            let mut receiver = receiver.into_pubsub();
-
-            // Подписываем ресивер на чаты, подписки и отписки
-            receiver.subscribe("chat_message").await.unwrap();
-            receiver.subscribe("subscribe").await.unwrap();
-            receiver.subscribe("unsubscribe").await.unwrap();
-
-            // Получаем поток из ресивера
-            let mut stream = receiver.on_message();
-
-            // Бесконечный цикл обработки сообщений:
-            // Если получили новое сообщение
-            while let Some(msg) = stream.next().await {
-                // Получаем название канала и текст сообщения
-                let channel: String = msg.get_channel_name().to_owned();
-                let text: String = msg.get_payload().unwrap();
-
-                // Делаем разные вещи относительно названия канала
-                match channel.as_str() {
-                    // Канал подписывания на чаты
-                    "subscribe" => {
-                        if let Ok(new_sub) = serde_json::from_str::<SubscriptionData>(&text) {
-                            broker.do_send(broker_actor::messages::RedisMessage::NewSubscription(
-                                new_sub,
-                            ));
+            // Последнее управляющее сообщение, которое экземпляр точно получил
+            let mut cursor = None;
+            let mut reconnected = false;
+            loop {
+                let mut receiver = subscribe(&connector, &config).await;
+                if reconnected {
+                    metrics::REDIS_RECONNECTS.inc();
+                    info!("Reconnected to redis");
+                    // Подписка уже есть, так что новое не потеряется, а то, что придет
+                    // и через канал, и из журнала, применяется повторно без вреда
+                    match control.since(cursor.as_deref()).await {
+                        Ok(missed) => {
+                            info!("Replaying {} control messages", missed.len());
+                            for (id, channel, text) in missed {
+                                dispatch(&channel, &text, &broker, &modes).await;
+                                cursor = Some(id);
+                            }
                         }
+                        Err(e) => warn!("Cannot replay control messages: {e}"),
                     }
-                    // Канал отписывания от чата
-                    "unsibscribe" => {
-                        if let Ok(new_unsub) = serde_json::from_str::<SubscriptionData>(&text) {
-                            broker.do_send(
-                                broker_actor::messages::RedisMessage::NewUnsubscription(new_unsub),
-                            );
-                        }
+                    broker.do_send(broker_actor::messages::ResyncSubscriptions);
+                } else {
+                    match control.last_id().await {
+                        Ok(id) => cursor = id,
+                        Err(e) => warn!("Cannot read control log position: {e}"),
                     }
-                    // Канал сообщений чатов
-                    "chat_message" => {
-                        if let Ok(new_msg) = serde_json::from_str::<ChatMessage>(&text) {
-                            broker
-                                .do_send(broker_actor::messages::RedisMessage::NewMessage(new_msg));
+                }
+
+                // Получаем поток из ресивера
+                let mut stream = receiver.on_message();
+
+                // Цикл обработки сообщений, пока подключение живо:
+                // Если получили новое сообщение
+                while let Some(msg) = stream.next().await {
+                    // Получаем название канала и текст сообщения
+                    let channel: String = msg.get_channel_name().to_owned();
+                    let Ok(text) = msg.get_payload::<String>() else {
+                        continue;
+                    };
+                    let Some(channel) = channel.strip_prefix(config.namespace.as_str()) else {
+                        continue;
+                    };
+                    if CONTROL_CHANNELS.contains(&channel) {
+                        if let Ok(ControlStamp { log_id: Some(id) }) = serde_json::from_str(&text) {
+                            cursor = Some(id);
                         }
                     }
-                    _ => {}
+                    dispatch(channel, &text, &broker, &modes).await;
                 }
+                warn!("Lost connection to redis, reconnecting");
+                reconnected = true;
             }
         })
         .into_actor(self)
@@ -118,6 +572,162 @@ impl Actor for RedisActor {
     }
 }
 
+/// Подключается к Redis и подписывается на все каналы сервиса, повторяя попытки,
+/// пока не получится
+async fn subscribe(connector: &RedisConnector, config: &RedisConfig) -> PubSub {
+    let mut attempt = 0;
+    loop {
+        let result = async {
+            // Ресивер на отдельном подключении, в Sentinel - к текущему главному узлу
+            let mut receiver = connector.pubsub().await?;
+            // Подписываем ресивер на чаты, подписки и отписки
+            for channel in CHANNELS {
+                receiver.subscribe(config.key(channel)).await?;
+            }
+            Ok::<_, redis::RedisError>(receiver)
+        }
+        .await;
+        match result {
+            Ok(receiver) => return receiver,
+            Err(e) => {
+                let delay = config.reconnect_delay(attempt);
+                warn!("Cannot subscribe to redis channels, retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                attempt = attempt.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Передает сообщение из канала channel (без префикса окружения) брокеру
+async fn dispatch(channel: &str, text: &str, broker: &Addr<BrokerActor>, modes: &DeliveryModes) {
+    // Делаем разные вещи относительно названия канала
+    match channel {
+        // Канал подписывания на чаты
+        SUBSCRIBE_CHANNEL => {
+            if let Ok(new_sub) = serde_json::from_str::<SubscriptionData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::NewSubscription(
+                    new_sub,
+                ));
+            }
+        }
+        // Канал отписывания от чата
+        UNSUBSCRIBE_CHANNEL => {
+            if let Ok(new_unsub) = serde_json::from_str::<SubscriptionData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::NewUnsubscription(
+                    new_unsub,
+                ));
+            }
+        }
+        // Канал сообщений чатов
+        MESSAGE_CHANNEL => {
+            if let Ok(new_msg) = serde_json::from_str::<ChatMessage>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::NewMessage(new_msg));
+            }
+        }
+        // Канал правок сообщений
+        MESSAGE_EDITED_CHANNEL => {
+            if let Ok(edited) = serde_json::from_str::<ChatMessage>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::MessageEdited(edited));
+            }
+        }
+        // Канал удаленных сообщений
+        MESSAGE_DELETED_CHANNEL => {
+            if let Ok(tombstone) = serde_json::from_str::<MessageTombstone>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::MessageDeleted(
+                    tombstone,
+                ));
+            }
+        }
+        // Канал снятых закреплений
+        MESSAGE_UNPINNED_CHANNEL => {
+            if let Ok(unpinned) = serde_json::from_str::<UnpinnedMessage>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::MessageUnpinned(
+                    unpinned,
+                ));
+            }
+        }
+        // Канал смены режима доставки
+        DELIVERY_MODE_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<DeliveryModeData>(text) {
+                modes
+                    .lock()
+                    .await
+                    .insert(data.chat_id, data.mode, Instant::now());
+            }
+        }
+        // Канал отозванных сессий
+        SESSION_REVOKED_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<SessionRevokedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::SessionRevoked(data));
+            }
+        }
+        // Канал событий набора текста
+        TYPING_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<TypingData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::Typing(data));
+            }
+        }
+        // Канал кратковременных сигналов
+        EPHEMERAL_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<EphemeralData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::Ephemeral(data));
+            }
+        }
+        // Канал сигнализации звонков
+        CALL_SIGNAL_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<CallSignalData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::CallSignal(data));
+            }
+        }
+        // Канал отметок о прочтении
+        READ_POSITION_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<ReadPositionData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::ReadPosition(data));
+            }
+        }
+        // Канал переименованных чатов
+        CHAT_RENAMED_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<ChatRenamedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::ChatRenamed(data));
+            }
+        }
+        // Канал новых имен пользователей
+        PROFILE_UPDATED_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<ProfileUpdatedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::ProfileUpdated(data));
+            }
+        }
+        // Канал исключенных участников
+        MEMBER_REMOVED_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<MemberRemovedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::MemberRemoved(data));
+            }
+        }
+        // Канал изменений списков блокировки
+        USER_BLOCKS_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<BlockChangedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::BlockChanged(data));
+            }
+        }
+        // Канал изменений настроек уведомлений
+        NOTIFICATIONS_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<NotificationsChangedData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::NotificationsChanged(
+                    data,
+                ));
+            }
+        }
+        // Канал появления в сети и выхода из нее
+        PRESENCE_CHANNEL => {
+            if let Ok(data) = serde_json::from_str::<PresenceData>(text) {
+                broker.do_send(broker_actor::messages::RedisMessage::Presence(data));
+            }
+        }
+        _ => {}
+    }
+}
+
 impl Handler<messages::WebsocketMessage> for RedisActor {
     type Result = ResponseFuture<()>;
     fn handle(
@@ -125,20 +735,332 @@ impl Handler<messages::WebsocketMessage> for RedisActor {
         msg: messages::WebsocketMessage,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let con = self.connection.clone();
+        match msg {
+            messages::WebsocketMessage::NewMessage(new_msg) => {
+                if let Some(bots) = &self.bots {
+                    bots.do_send(bot_actor::messages::DeliverToBots(new_msg.clone()));
+                }
+                let mode = self.delivery_mode(ChatId(new_msg.chat_id));
+                let pubsub = self.pubsub.clone();
+                let stream = self.stream.clone();
+                Box::pin(async move {
+                    let transport: Box<dyn Transport> = match mode.await {
+                        DeliveryMode::AtMostOnce => Box::new(pubsub),
+                        DeliveryMode::AtLeastOnce => Box::new(stream),
+                    };
+                    if let Err(e) = transport.publish(new_msg).await {
+                        warn!("Cannot publish message: {e}");
+                    }
+                })
+            }
+            // Правки не нужно досылать: актуальный текст всегда есть в истории
+            messages::WebsocketMessage::MessageEdited(edited) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(MESSAGE_EDITED_CHANNEL, &edited).await;
+                })
+            }
+            // Удаленное сообщение не должно досылаться из потока доставки
+            messages::WebsocketMessage::MessageDeleted(tombstone) => {
+                let pubsub = self.pubsub.clone();
+                let stream = self.stream.clone();
+                Box::pin(async move {
+                    if let Err(e) = stream.forget(&tombstone).await {
+                        warn!(
+                            "Cannot drop deleted message {} from delivery stream: {e}",
+                            tombstone.message_id
+                        );
+                    }
+                    let _ = pubsub.publish_to(MESSAGE_DELETED_CHANNEL, &tombstone).await;
+                })
+            }
+            messages::WebsocketMessage::MessageUnpinned(unpinned) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(MESSAGE_UNPINNED_CHANNEL, &unpinned).await;
+                })
+            }
+            // Событие typing живет несколько секунд, досылать его незачем
+            messages::WebsocketMessage::Typing(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(TYPING_CHANNEL, &data).await;
+                })
+            }
+            // Сигнал важен только сейчас: не сохраняется ни в базе, ни в потоке доставки
+            messages::WebsocketMessage::Ephemeral(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(EPHEMERAL_CHANNEL, &data).await;
+                })
+            }
+            // Сигнализация нужна только участникам звонка, которые подключены сейчас
+            messages::WebsocketMessage::CallSignal(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(CALL_SIGNAL_CHANNEL, &data).await;
+                })
+            }
+            // Отметка о прочтении нужна только подключенным сейчас устройствам
+            messages::WebsocketMessage::ReadPosition(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(READ_POSITION_CHANNEL, &data).await;
+                })
+            }
+            // Новое название всегда есть в информации о чате
+            messages::WebsocketMessage::ChatRenamed(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(CHAT_RENAMED_CHANNEL, &data).await;
+                })
+            }
+            // Новое имя всегда есть в информации о пользователе
+            messages::WebsocketMessage::ProfileUpdated(data) => {
+                let pubsub = self.pubsub.clone();
+                Box::pin(async move {
+                    let _ = pubsub.publish_to(PROFILE_UPDATED_CHANNEL, &data).await;
+                })
+            }
+            // Исключенный без сокета узнает об этом из списка своих чатов
+            messages::WebsocketMessage::MemberRemoved(data) => {
+                let control = self.control.clone();
+                Box::pin(async move {
+                    let _ = control.publish(MEMBER_REMOVED_CHANNEL, &data).await;
+                })
+            }
+            messages::WebsocketMessage::Ack {
+                chat_id,
+                user_id,
+                delivery_id,
+            } => {
+                let stream = self.stream.clone();
+                Box::pin(async move {
+                    if let Err(e) = stream.ack(chat_id, user_id, &delivery_id).await {
+                        warn!("Cannot save ack of user {user_id} in chat {chat_id}: {e}");
+                    }
+                })
+            }
+            messages::WebsocketMessage::Replay {
+                user_id,
+                chats,
+                blocked,
+                socket,
+            } => {
+                let modes: Vec<_> = chats
+                    .iter()
+                    .map(|&chat_id| self.delivery_mode(chat_id))
+                    .collect();
+                let stream = self.stream.clone();
+                let limit = self.delivery.replay_limit;
+                Box::pin(async move {
+                    for (chat_id, mode) in chats.into_iter().zip(modes) {
+                        if mode.await != DeliveryMode::AtLeastOnce {
+                            continue;
+                        }
+                        match stream.pending(chat_id, user_id, limit).await {
+                            Ok(pending) => {
+                                for message in pending
+                                    .into_iter()
+                                    .filter(|message| !blocked.contains(&UserId(message.sender_id)))
+                                {
+                                    socket.do_send(BrokerMessage::NewMessage(message));
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Cannot replay chat {chat_id} for user {user_id}: {e}")
+                            }
+                        }
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl Handler<messages::DeliveryModeChanged> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(
+        &mut self,
+        msg: messages::DeliveryModeChanged,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let control = self.control.clone();
         Box::pin(async move {
-            match msg {
-                messages::WebsocketMessage::NewMessage(new_msg) => {
-                    let _ = con
-                        .lock()
-                        .await
-                        .publish::<_, _, String>(
-                            "chat_message",
-                            serde_json::to_string(&new_msg).unwrap(),
-                        )
-                        .await;
+            let data = DeliveryModeData {
+                chat_id: msg.chat_id,
+                mode: msg.mode,
+            };
+            if let Err(e) = control.publish(DELIVERY_MODE_CHANNEL, &data).await {
+                warn!(
+                    "Cannot announce delivery mode of chat {}: {e}",
+                    data.chat_id
+                );
+            }
+        })
+    }
+}
+
+impl Handler<messages::ApiMessage> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, msg: messages::ApiMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let control = self.control.clone();
+        Box::pin(async move {
+            let (channel, data) = match msg {
+                messages::ApiMessage::NewSubscription(data) => (SUBSCRIBE_CHANNEL, data),
+                messages::ApiMessage::NewUnsubscription(data) => (UNSUBSCRIBE_CHANNEL, data),
+            };
+            if let Err(e) = control.publish(channel, &data).await {
+                warn!(
+                    "Cannot announce subscription of user {} to chat {}: {e}",
+                    data.user_id, data.chat_id
+                );
+            }
+        })
+    }
+}
+
+impl Handler<messages::BlockChanged> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, msg: messages::BlockChanged, _ctx: &mut Self::Context) -> Self::Result {
+        let control = self.control.clone();
+        Box::pin(async move {
+            let data = BlockChangedData {
+                user_id: msg.user_id,
+                blocked_id: msg.blocked_id,
+                blocked: msg.blocked,
+            };
+            if let Err(e) = control.publish(USER_BLOCKS_CHANNEL, &data).await {
+                warn!(
+                    "Cannot announce block list change of user {}: {e}",
+                    data.user_id
+                );
+            }
+        })
+    }
+}
+
+impl Handler<messages::NotificationsChanged> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(
+        &mut self,
+        msg: messages::NotificationsChanged,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let control = self.control.clone();
+        Box::pin(async move {
+            let data = NotificationsChangedData {
+                user_id: msg.user_id,
+                chat_id: msg.chat_id,
+            };
+            if let Err(e) = control.publish(NOTIFICATIONS_CHANNEL, &data).await {
+                warn!(
+                    "Cannot announce notification settings change of user {}: {e}",
+                    data.user_id
+                );
+            }
+        })
+    }
+}
+
+impl Handler<messages::SessionRevoked> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, msg: messages::SessionRevoked, _ctx: &mut Self::Context) -> Self::Result {
+        let control = self.control.clone();
+        Box::pin(async move {
+            let data = SessionRevokedData {
+                user_id: msg.user_id,
+                session_id: msg.session_id,
+            };
+            if let Err(e) = control.publish(SESSION_REVOKED_CHANNEL, &data).await {
+                warn!(
+                    "Cannot announce revoked session of user {}: {e}",
+                    data.user_id
+                );
+            }
+        })
+    }
+}
+
+impl Handler<messages::SocketPresence> for RedisActor {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, msg: messages::SocketPresence, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(tracker) = self.presence.clone() else {
+            return Box::pin(async {});
+        };
+        let sockets = self.local_sockets.clone();
+        let pubsub = self.pubsub.clone();
+        let db = self.db.clone();
+        let max_chat_size = self.presence_config.max_chat_size as u64;
+        let user_id = msg.user_id;
+        Box::pin(async move {
+            // Замок держится до записи в Redis, чтобы открытие и закрытие сокетов одного
+            // пользователя не поменялись там местами
+            let changed = {
+                let mut sockets = sockets.lock().await;
+                let count = sockets.entry(user_id).or_default();
+                let result = if msg.opened {
+                    *count += 1;
+                    if *count > 1 {
+                        return;
+                    }
+                    tracker.set_online(user_id).await
+                } else {
+                    *count = count.saturating_sub(1);
+                    if *count > 0 {
+                        return;
+                    }
+                    sockets.remove(&user_id);
+                    tracker.set_offline(user_id).await
+                };
+                match result {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        warn!("Cannot update presence of user {user_id}: {e}");
+                        return;
+                    }
                 }
+            };
+            let Some(db) = db.filter(|_| changed) else {
+                return;
+            };
+            let chats = small_chats(&db, user_id, max_chat_size).await;
+            if chats.is_empty() {
+                return;
+            }
+            let data = PresenceData {
+                user_id,
+                online: msg.opened,
+                chats,
+            };
+            if let Err(e) = pubsub.publish_to(PRESENCE_CHANNEL, &data).await {
+                warn!("Cannot announce presence of user {user_id}: {e}");
             }
         })
     }
 }
+
+/// Чаты пользователя, в которых не больше max_chat_size участников
+async fn small_chats(db: &Addr<DatabaseActor>, user_id: UserId, max_chat_size: u64) -> Vec<ChatId> {
+    let chats = match db.send(GetUserChats { user_id }).await {
+        Ok(Ok(chats)) => chats,
+        Ok(Err(e)) => {
+            warn!("Cannot get chats of user {user_id}: {e}");
+            return vec![];
+        }
+        Err(e) => {
+            warn!("Cannot get chats of user {user_id}: {e}");
+            return vec![];
+        }
+    };
+    let mut small = vec![];
+    for chat_id in chats {
+        match db.send(GetMemberCount { chat_id }).await {
+            Ok(Ok(count)) if count <= max_chat_size => small.push(chat_id),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Cannot count members of chat {chat_id}: {e}"),
+            Err(e) => warn!("Cannot count members of chat {chat_id}: {e}"),
+        }
+    }
+    small
+}
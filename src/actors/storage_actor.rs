@@ -0,0 +1,67 @@
+use actix::prelude::*;
+use std::sync::Arc;
+
+use crate::config::StorageConfig;
+use crate::metrics;
+use crate::storage::{self, ObjectStore, StorageError};
+
+// Актор хранилища вложений
+//
+// Загрузка файла может занимать секунды, поэтому она вынесена из обработчиков в отдельный актор,
+// как и запросы к базе. Если хранилище не настроено, актор отвечает NotConfigured.
+
+pub mod messages {
+    use crate::storage::{ObjectStream, StorageError};
+    use actix::Message;
+
+    /// Положить файл из length байт в хранилище, в ответ приходит ссылка на него
+    ///
+    /// Тело читается по мере загрузки, так что отправитель может передавать его кусками,
+    /// пока ждет ответа
+    #[derive(Message)]
+    #[rtype(result = "Result<String, StorageError>")]
+    pub struct PutObject {
+        pub key: String,
+        pub content_type: String,
+        pub length: u64,
+        pub body: ObjectStream,
+    }
+}
+
+pub struct StorageActor {
+    store: Option<Arc<dyn ObjectStore>>,
+}
+
+impl StorageActor {
+    pub fn new(store: Option<Arc<dyn ObjectStore>>) -> Self {
+        Self { store }
+    }
+
+    pub fn from_config(config: &StorageConfig) -> Self {
+        Self::new(
+            storage::store_from_config(config).map(|store| Arc::new(store) as Arc<dyn ObjectStore>),
+        )
+    }
+}
+
+impl Actor for StorageActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<messages::PutObject> for StorageActor {
+    type Result = ResponseFuture<Result<String, StorageError>>;
+    fn handle(&mut self, msg: messages::PutObject, _ctx: &mut Self::Context) -> Self::Result {
+        let store = self.store.clone();
+        Box::pin(async move {
+            let store = store.ok_or(StorageError::NotConfigured)?;
+            let result = store
+                .put(&msg.key, &msg.content_type, msg.length, msg.body)
+                .await;
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            metrics::ATTACHMENT_UPLOADS
+                .with_label_values(&[outcome])
+                .inc();
+            result
+        })
+    }
+}
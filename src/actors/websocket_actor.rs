@@ -1,16 +1,47 @@
 use crate::{
-    actors::broker_actor::{self, BrokerActor},
-    actors::redis_actor::{self, RedisActor},
+    actors::broker_actor::{
+        self, BrokerActor, Overflow, SocketHandle, TypingThrottle, TYPING_THROTTLE,
+    },
+    actors::redis_actor::{
+        self, CallSignalData, ChatRenamedData, EphemeralData, MemberRemovedData,
+        ProfileUpdatedData, ReadPositionData, RedisActor,
+    },
+    calls::{self, ActiveCalls, CallEvent, CallEventKind, CallSignalKind},
+    config::ConfigHandle,
+    database::{
+        data::{Attachment, ReadPosition, UnpinnedMessage},
+        DBError, DBResult,
+    },
+    events,
+    i18n::{DisplayHints, DisplayTime},
+    ids::{ChatId, UserId},
+    load_shedding, metrics,
+    moderation::{ModerationFilter, WordlistFilter},
+    rate_limit::RateLimit,
+    read_only,
     serializable_duration::SerializableDuration,
+    services::{self, ServiceError},
+    uploads::{self, CompletedUpload, Uploads},
+    usage::{NoUsageTracking, UsageKind, UsageTracker},
+    validation,
 };
 use actix::prelude::*;
+use actix_http::ws::Item;
 use actix_web_actors::ws;
-use scylla::FromRow;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_str, to_string};
+use serde_json::from_str;
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
 use uuid::Uuid;
 
 use super::database_actor::{self, DatabaseActor};
+use super::storage_actor::{self, StorageActor};
 
 // Когда пользователь пытается подключиться к чату, он отдает свой токен
 // Токен проверяется и из него берется id пользователя
@@ -22,19 +53,430 @@ use super::database_actor::{self, DatabaseActor};
 // 1) Принимает от пользователя NewChatMessage, добавляя к нему свой id и время, получая
 //    ChatMessage
 // 2) Отправляет ChatMessage в Redis-actor и Database-actor
+// 3) При подключении отправляет hello с версией протокола и возможностями сервера,
+//    клиент может ответить кадром capabilities со своими возможностями и версией схемы
+//    событий. Необязательные события отправляются только тем клиентам, которые заявили,
+//    что их понимают, а события новее версии клиента переводятся в его версию (см. events),
+//    так что старые клиенты продолжают работать по мере развития протокола
+// 4) Следит за тем, успевает ли клиент забирать сообщения: брокер сообщает о переполнении
+//    очереди сокета, и если очередь не разгружается дольше grace_secs, клиент получает
+//    предупреждение и, если так настроено, отключается
+// 5) В чатах с доставкой at_least_once клиент, который заявил delivery_ack, подтверждает
+//    полученные сообщения кадром ack, а при подключении ему досылается все неподтвержденное.
+//    Такие сообщения могут прийти повторно, клиент отбрасывает дубликаты по message_id
+// 6) Клиенту, который заявил message_ack, после сохранения каждого его сообщения приходит
+//    подтверждение с присвоенным message_id, серверным временем и client_msg_id, а если
+//    сохранить не удалось - ошибка. Повтор сообщения с тем же client_msg_id (например,
+//    после переподключения) не сохраняется и не рассылается еще раз, а подтверждается
+//    id и временем первого
+// 7) Кадр typing пересылается остальным участникам чата событием typing, не чаще раза
+//    в несколько секунд на пользователя и чат
+// 8) Отправленное сообщение приходит и на все сокеты отправителя, включая тот, с которого
+//    его отправили. Только им оно приходит с client_msg_id, по которому клиент заменяет
+//    показанное заранее сообщение настоящим
+// 9) Упомянутые в сообщении участники дополнительно получают событие mentioned
+// 10) Кадр mark_read пересылается остальным сокетам того же пользователя событием
+//    read_position_changed, чтобы счетчики непрочитанного совпадали на всех устройствах
+// 11) В режиме только для чтения сообщения клиента не сохраняются, вместо них приходит
+//    событие read_only. Сокет остается подключенным и получает сообщения, отправленные
+//    через другие экземпляры сервиса
+// 12) Если у токена есть срок, то клиент, заявивший reauth_required, за reauth.notice_secs
+//    до его истечения получает событие reauth_required и может заранее переподключиться
+//    с новым токеном. Когда токен истекает, сокет закрывается с причиной token expired
+// 13) Пока экземпляр перегружен, клиент, заявивший reconnect_hint, один раз получает
+//    событие reconnect_hint: если сокет закроется, переподключаться стоит не раньше чем
+//    через after_seconds. Новые сокеты в это время не принимаются
+// 14) Клиент, заявивший presence, получает member_online и member_offline, когда другие
+//    участники его небольших чатов (до presence.max_chat_size) появляются в сети или
+//    выходят из нее. Переходы считаются по всем экземплярам сервиса
+// 15) Кадр broadcast_ephemeral (живой курсор, геопозиция, вызов) пересылается остальным
+//    участникам чата, заявившим broadcast_ephemeral, и нигде не сохраняется: кто не был
+//    подключен или не успевает забирать события, сигнал просто не получит. Кадры сверх
+//    rate_limits.ephemeral_per_second в секунду отбрасываются
+// 16) В чатах с политикой публикации admins_only пишут только владелец и администраторы:
+//    сообщение подписчика отклоняется ошибкой еще до сохранения, а его typing и
+//    broadcast_ephemeral никуда не уходят
+// 17) Звонки (см. calls): кадр call_start записывает в историю сообщение о начале звонка,
+//    а кадры call_signal (offer, answer, ice_candidate, hangup) пересылаются только
+//    адресату to_user, если он состоит в чате, событием call_signal. Сокет звонящего
+//    следит за ответом и отбоем и записывает в историю ended с длительностью или missed
+// 18) Каждый присланный кадр учитывается в использовании API пользователя (см. usage)
+// 19) Кадр больше websocket.max_frame_bytes не читается: клиент получает error, и сокет
+//    закрывается с кодом 1009. Другие нарушения протокола закрывают сокет с кодом 1002
+// 20) Сообщение, присланное фрагментами, собирается и обрабатывается как целый кадр. Все
+//    фрагменты вместе ограничены websocket.max_frame_bytes, а собранный текст не в UTF-8
+//    закрывает сокет с кодом 1007
+// 21) Клиент, заявивший attachment_upload, может прислать вложение бинарными кадрами (см.
+//    uploads). Когда пришел последний кусок, файл сохраняется, как при загрузке через
+//    /api/chat/attachment, и клиент получает attachment_uploaded с описанием вложения или
+//    attachment_upload_failed
+// 22) Клиент, заявивший profile_updated, получает событие profile_updated, когда он сам или
+//    участник одного из его чатов сменил имя
 
-#[derive(Serialize, Deserialize, FromRow, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub chat_id: Uuid,
+    /// Вместе с date однозначно определяет сообщение в чате
+    #[serde(default)]
+    pub message_id: Uuid,
     pub sender_id: i64,
     pub date: SerializableDuration,
     pub msg_text: String,
+    /// Когда сообщение редактировали последний раз
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<SerializableDuration>,
+    /// Сообщение, на которое это сообщение отвечает
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Uuid>,
+    /// Вложения, загруженные в чат через /api/chat/attachment
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Uuid>,
+    /// Откуда сообщение переслано
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_from: Option<ForwardedFrom>,
+    /// Упомянутые в тексте участники чата, их находит сервер
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<i64>,
+    /// Системное сообщение о звонке, текст у такого сообщения пустой
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call: Option<CallEvent>,
+    /// Идентификатор, который присвоил сообщению клиент отправителя, приходит только
+    /// сокетам самого отправителя, чтобы они сопоставили сообщение с уже показанным
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    /// Позиция в потоке чата с доставкой at_least_once, ее клиент присылает в подтверждении
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<String>,
+}
+
+/// Источник пересланного сообщения
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ForwardedFrom {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    /// Кто отправил исходное сообщение
+    pub sender_id: i64,
+}
+
+/// След удаленного сообщения, по нему клиенты убирают сообщение у себя
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageTombstone {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    /// Когда удаленное сообщение было отправлено
+    pub date: SerializableDuration,
+}
+
+impl ChatMessage {
+    /// Добавляет к сообщению дату в том виде, в котором ее хочет видеть клиент
+    pub fn for_display(self, hints: &DisplayHints) -> ChatMessageView {
+        ChatMessageView {
+            display_date: hints.format_time(self.date.timestamp),
+            message: self,
+        }
+    }
+}
+
+/// Сообщение с датой для показа, так его отдает история чата
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatMessageView {
+    #[serde(flatten)]
+    pub message: ChatMessage,
+    pub display_date: DisplayTime,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct NewChatMessage {
-    chat_id: Uuid,
-    msg_text: String,
+    pub chat_id: ChatId,
+    pub msg_text: String,
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    #[serde(default)]
+    pub attachments: Vec<Uuid>,
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+}
+
+pub use crate::events::{ServerEvent, PROTOCOL_VERSION};
+
+/// Возможности протокола, которые поддерживает сервер
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    "slow_consumer",
+    "fetch_history",
+    "get_chats",
+    "get_chat_info",
+    "message_edited",
+    "message_deleted",
+    "delivery_ack",
+    "message_ack",
+    "typing",
+    "message_unpinned",
+    "read_position_changed",
+    "mentioned",
+    "chat_renamed",
+    "reauth_required",
+    "removed_from_chat",
+    "reconnect_hint",
+    "presence",
+    "broadcast_ephemeral",
+    "calls",
+    "attachment_upload",
+    "profile_updated",
+];
+
+/// Как часто сокет проверяет, не перегружен ли экземпляр
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Наибольший размер payload кадра broadcast_ephemeral в байтах JSON
+pub const MAX_EPHEMERAL_PAYLOAD_BYTES: usize = 4096;
+
+/// Сколько сообщений истории отдается на один запрос fetch_history по умолчанию и максимум
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 200;
+
+/// Запросы клиента по вебсокету, отличаются от сообщений чата наличием поля type
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientRequest {
+    /// Возможности протокола и версия схемы событий, которые понимает клиент
+    Capabilities {
+        capabilities: Vec<String>,
+        #[serde(default)]
+        version: Option<u32>,
+    },
+    /// Сообщения чата, отправленные раньше before (миллисекунды от начала эпохи)
+    FetchHistory {
+        chat_id: ChatId,
+        before: Option<i64>,
+        limit: Option<usize>,
+    },
+    /// Список чатов пользователя
+    GetChats,
+    /// Информация о чате
+    GetChatInfo { chat_id: ChatId },
+    /// Подтверждение, что получены все сообщения чата до delivery_id включительно
+    Ack {
+        chat_id: ChatId,
+        delivery_id: String,
+    },
+    /// Пользователь печатает в чате
+    Typing { chat_id: ChatId },
+    /// Кратковременный сигнал остальным участникам чата, в базу не пишется
+    BroadcastEphemeral {
+        chat_id: ChatId,
+        kind: String,
+        #[serde(default)]
+        payload: serde_json::Value,
+    },
+    /// Пользователь начал звонок в чате, call_id выбирает клиент
+    CallStart { chat_id: ChatId, call_id: Uuid },
+    /// Кадр сигнализации звонка участнику чата to_user
+    CallSignal {
+        chat_id: ChatId,
+        call_id: Uuid,
+        to_user: UserId,
+        signal: CallSignalKind,
+        #[serde(default)]
+        payload: serde_json::Value,
+    },
+    /// Пользователь прочитал чат до сообщения message_id, отправленного в date
+    MarkRead {
+        chat_id: ChatId,
+        message_id: Uuid,
+        date: SerializableDuration,
+    },
+}
+
+/// Кадр, полученный от клиента
+pub enum ClientFrame {
+    Request(ClientRequest),
+    Message(NewChatMessage),
+}
+
+impl ClientFrame {
+    pub fn parse(text: &str) -> serde_json::Result<Self> {
+        let value: serde_json::Value = from_str(text)?;
+        if value.get("type").is_some() {
+            Ok(ClientFrame::Request(serde_json::from_value(value)?))
+        } else {
+            Ok(ClientFrame::Message(serde_json::from_value(value)?))
+        }
+    }
+}
+
+/// Данные о подключении, снятые при установке вебсокета
+#[derive(Clone, Debug)]
+pub struct SessionMetadata {
+    pub client_ip: Option<IpAddr>,
+    /// Язык и часовой пояс клиента для дат в истории
+    pub display: DisplayHints,
+    /// Сессия, к которой привязан сокет, если привязка включена
+    pub session_id: Option<String>,
+    /// Когда создан аккаунт, от возраста аккаунта зависит лимит сообщений
+    pub account_created: chrono::Duration,
+    /// Когда истекает токен подключения (от начала эпохи), если у него есть срок
+    pub token_expires_at: Option<chrono::Duration>,
+}
+
+/// Сколько осталось до предупреждения об истечении токена и до самого истечения
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenDeadlines {
+    pub notice_in: Duration,
+    pub expires_in: Duration,
+}
+
+impl TokenDeadlines {
+    /// expires_at и now отсчитываются от начала эпохи, notice - за сколько предупреждать
+    pub fn new(expires_at: chrono::Duration, now: chrono::Duration, notice: Duration) -> Self {
+        let expires_in = (expires_at - now).to_std().unwrap_or_default();
+        Self {
+            notice_in: expires_in.saturating_sub(notice),
+            expires_in,
+        }
+    }
+}
+
+/// Сколько кадров сокет может отправить за секунду
+#[derive(Debug)]
+pub struct FrameBudget {
+    window_start: Instant,
+    used: u32,
+}
+
+impl FrameBudget {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            used: 0,
+        }
+    }
+
+    /// Можно ли отправить еще один кадр, если в секунду их можно per_second
+    pub fn allow(&mut self, now: Instant, per_second: u32) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.used = 0;
+        }
+        if self.used >= per_second {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/// Собирает сообщение клиента, присланное несколькими фрагментами
+///
+/// Все фрагменты сообщения вместе ограничены тем же размером, что и целый кадр
+#[derive(Debug, Default)]
+pub struct Fragments {
+    /// Собираемое сообщение: текст ли это и что уже пришло
+    pending: Option<(bool, Vec<u8>)>,
+}
+
+impl Fragments {
+    /// Добавляет фрагмент; когда пришел последний, возвращает собранное сообщение
+    pub fn push(
+        &mut self,
+        item: Item,
+        max_bytes: usize,
+    ) -> Result<Option<ws::Message>, ws::ProtocolError> {
+        let (chunk, last) = match item {
+            Item::FirstText(_) | Item::FirstBinary(_) if self.pending.is_some() => {
+                self.pending = None;
+                return Err(ws::ProtocolError::ContinuationStarted);
+            }
+            Item::FirstText(chunk) => {
+                self.pending = Some((true, vec![]));
+                (chunk, false)
+            }
+            Item::FirstBinary(chunk) => {
+                self.pending = Some((false, vec![]));
+                (chunk, false)
+            }
+            Item::Continue(chunk) => (chunk, false),
+            Item::Last(chunk) => (chunk, true),
+        };
+        let Some((_, data)) = self.pending.as_mut() else {
+            return Err(ws::ProtocolError::ContinuationNotStarted);
+        };
+        if data.len() + chunk.len() > max_bytes {
+            self.pending = None;
+            return Err(ws::ProtocolError::Overflow);
+        }
+        data.extend_from_slice(&chunk);
+        if !last {
+            return Ok(None);
+        }
+        let Some((text, data)) = self.pending.take() else {
+            unreachable!("Pending message was checked above");
+        };
+        if !text {
+            return Ok(Some(ws::Message::Binary(data.into())));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Some(ws::Message::Text(text.into()))),
+            Err(e) => Err(ws::ProtocolError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e,
+            ))),
+        }
+    }
+}
+
+/// Почему сокет закрывается по политике одновременных входов
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginConflict {
+    /// Пользователь открыл новый сокет, а этот оказался самым старым
+    Replaced,
+    /// У пользователя уже открыто столько сокетов, сколько можно
+    Denied,
+}
+
+impl LoginConflict {
+    /// Код закрытия сокета, по нему клиент понимает, переподключаться ли
+    pub fn close_code(&self) -> u16 {
+        match self {
+            LoginConflict::Replaced => 4001,
+            LoginConflict::Denied => 4002,
+        }
+    }
+
+    pub fn close_reason(&self) -> ws::CloseReason {
+        ws::CloseReason {
+            code: ws::CloseCode::Other(self.close_code()),
+            description: Some(
+                match self {
+                    LoginConflict::Replaced => "replaced by a newer session",
+                    LoginConflict::Denied => "too many sessions",
+                }
+                .into(),
+            ),
+        }
+    }
+}
+
+/// Чем закрыть сокет, когда кадр клиента не удалось прочитать
+///
+/// Слишком большой кадр закрывает сокет с кодом 1009 (Message Too Big), текст не в UTF-8 -
+/// с кодом 1007, остальные нарушения протокола - с кодом 1002
+pub fn protocol_error_close(error: &ws::ProtocolError) -> ws::CloseReason {
+    match error {
+        ws::ProtocolError::Overflow => ws::CloseReason {
+            code: ws::CloseCode::Size,
+            description: Some("frame too large".into()),
+        },
+        ws::ProtocolError::Io(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            ws::CloseReason {
+                code: ws::CloseCode::Invalid,
+                description: Some("invalid UTF-8 text".into()),
+            }
+        }
+        error => ws::CloseReason {
+            code: ws::CloseCode::Protocol,
+            description: Some(error.to_string()),
+        },
+    }
 }
 
 // Какие сообщения принимает
@@ -45,6 +487,47 @@ pub mod messages {
     #[rtype(result = "()")]
     pub enum BrokerMessage {
         NewMessage(ChatMessage),
+        /// Сообщение отредактировали, в нем уже новый текст
+        MessageEdited(ChatMessage),
+        /// Сообщение удалили
+        MessageDeleted(MessageTombstone),
+        /// С сообщения сняли закрепление
+        MessageUnpinned(UnpinnedMessage),
+        /// Сессию отозвали: сокет закрывается, если он к ней привязан
+        SessionRevoked(String),
+        /// Другой участник чата печатает
+        Typing {
+            chat_id: ChatId,
+            user_id: UserId,
+        },
+        /// Кратковременный сигнал другого участника чата
+        Ephemeral(EphemeralData),
+        /// Кадр сигнализации звонка, адресованный этому пользователю
+        CallSignal(CallSignalData),
+        /// Пользователя упомянули в сообщении
+        Mentioned {
+            chat_id: ChatId,
+            message_id: Uuid,
+            sender_id: UserId,
+            /// Начало текста сообщения для уведомления
+            preview: String,
+        },
+        /// Пользователь прочитал чат на одном из своих устройств
+        ReadPositionChanged(ReadPositionData),
+        /// Чат переименовали
+        ChatRenamed(ChatRenamedData),
+        /// Участник одного из чатов пользователя или он сам сменил имя
+        ProfileUpdated(ProfileUpdatedData),
+        /// Пользователя исключили из чата
+        RemovedFromChat(MemberRemovedData),
+        /// Участник чата появился в сети или вышел из нее
+        Presence {
+            chat_id: ChatId,
+            user_id: UserId,
+            online: bool,
+        },
+        /// Сокет закрывается по политике одновременных входов
+        LoginConflict(LoginConflict),
     }
 }
 
@@ -52,7 +535,42 @@ pub struct WebsocketActor {
     broker: Addr<BrokerActor>,
     publisher: Addr<RedisActor>,
     db: Addr<DatabaseActor>,
-    user_id: i64,
+    /// Хранилище вложений, присланных бинарными кадрами
+    storage: Option<Addr<StorageActor>>,
+    limiter: Arc<dyn RateLimit>,
+    /// Фильтр текста новых сообщений
+    moderation: Arc<dyn ModerationFilter>,
+    /// Учет кадров, присланных пользователем
+    usage: Arc<dyn UsageTracker>,
+    user_id: UserId,
+    /// Отличает сокет от других сокетов пользователя на всех экземплярах сервиса
+    connection_id: Uuid,
+    metadata: SessionMetadata,
+    config: ConfigHandle,
+    /// С какого момента очередь сокета переполнена
+    slow_since: Option<Instant>,
+    /// Когда очередь переполнялась в последний раз
+    last_overflow: Option<Instant>,
+    /// Возможности протокола, о которых договорились с клиентом
+    capabilities: HashSet<String>,
+    /// Версия схемы событий, в которой клиент получает события
+    event_version: u32,
+    /// Когда клиент последний раз сообщал, что печатает, по чатам
+    typing: TypingThrottle,
+    /// Сколько кадров broadcast_ephemeral клиент отправил за текущую секунду
+    ephemeral: FrameBudget,
+    /// Сообщение, которое клиент присылает фрагментами
+    fragments: Fragments,
+    /// Вложения, которые клиент присылает бинарными кадрами
+    uploads: Uploads,
+    /// Звонки, начатые с этого сокета
+    calls: ActiveCalls,
+    /// Сколько кадров call_signal клиент отправил за текущую секунду
+    call_signals: FrameBudget,
+    /// Клиент уже получил reauth_required
+    reauth_notified: bool,
+    /// Клиент уже получил reconnect_hint за текущую перегрузку
+    reconnect_hinted: bool,
 }
 
 impl WebsocketActor {
@@ -60,13 +578,814 @@ impl WebsocketActor {
         broker: Addr<BrokerActor>,
         publisher: Addr<RedisActor>,
         db: Addr<DatabaseActor>,
-        user_id: i64,
+        limiter: Arc<dyn RateLimit>,
+        user_id: UserId,
+        metadata: SessionMetadata,
+        config: ConfigHandle,
     ) -> Self {
         Self {
             broker,
             publisher,
             db,
+            storage: None,
+            limiter,
+            moderation: Arc::new(WordlistFilter::new(config.clone())),
+            usage: Arc::new(NoUsageTracking),
             user_id,
+            connection_id: Uuid::new_v4(),
+            metadata,
+            config,
+            slow_since: None,
+            last_overflow: None,
+            capabilities: HashSet::new(),
+            event_version: events::MIN_PROTOCOL_VERSION,
+            typing: TypingThrottle::new(TYPING_THROTTLE),
+            ephemeral: FrameBudget::new(Instant::now()),
+            fragments: Fragments::default(),
+            uploads: Uploads::new(),
+            calls: ActiveCalls::new(),
+            call_signals: FrameBudget::new(Instant::now()),
+            reauth_notified: false,
+            reconnect_hinted: false,
+        }
+    }
+
+    /// Проверять текст сообщений своим фильтром вместо списка слов из конфигурации
+    pub fn with_moderation(mut self, moderation: Arc<dyn ModerationFilter>) -> Self {
+        self.moderation = moderation;
+        self
+    }
+
+    /// Принимать вложения бинарными кадрами, без хранилища они отклоняются
+    pub fn with_storage(mut self, storage: Addr<StorageActor>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Учитывать присланные кадры
+    pub fn with_usage_tracker(mut self, usage: Arc<dyn UsageTracker>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Учитывает присланный кадр в фоне, не задерживая его обработку
+    fn record_usage(&self) {
+        let usage = self.usage.clone();
+        let user_id = self.user_id;
+        actix_web::rt::spawn(async move {
+            if let Err(e) = usage.record(user_id, UsageKind::WebsocketMessage).await {
+                error!("Cannot record websocket usage of user {user_id}: {e}");
+            }
+        });
+    }
+
+    /// Понимает ли клиент данную возможность протокола
+    pub fn client_supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Запоминает возможности клиента, которые поддерживает и сервер
+    fn negotiate(
+        &mut self,
+        capabilities: Vec<String>,
+        version: Option<u32>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.event_version = events::negotiate_version(version);
+        self.capabilities = capabilities
+            .into_iter()
+            .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()))
+            .collect();
+        let mut capabilities: Vec<_> = self.capabilities.iter().cloned().collect();
+        capabilities.sort();
+        self.send_event(
+            ctx,
+            &ServerEvent::Capabilities {
+                capabilities,
+                version: self.event_version,
+            },
+        );
+        if self.client_supports("delivery_ack") {
+            self.replay(ctx);
+        }
+        // Токен мог подойти к концу еще до того, как клиент назвал свои возможности
+        if self
+            .token_deadlines()
+            .is_some_and(|deadlines| deadlines.notice_in.is_zero())
+        {
+            self.notify_reauth(ctx);
+        }
+    }
+
+    fn token_deadlines(&self) -> Option<TokenDeadlines> {
+        let expires_at = self.metadata.token_expires_at?;
+        let notice = Duration::from_secs(self.config.current().reauth.notice_secs);
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        Some(TokenDeadlines::new(expires_at, now, notice))
+    }
+
+    /// Планирует предупреждение об истечении токена и закрытие сокета, когда он истечет
+    fn schedule_reauth(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(deadlines) = self.token_deadlines() else {
+            return;
+        };
+        ctx.run_later(deadlines.notice_in, |act, ctx| act.notify_reauth(ctx));
+        if self.config.current().reauth.close_on_expiry {
+            ctx.run_later(deadlines.expires_in, |act, ctx| {
+                info!("Token of user {} expired, closing websocket", act.user_id);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("token expired".into()),
+                }));
+                ctx.stop();
+            });
+        }
+    }
+
+    /// Отправляет reauth_required один раз и только клиенту, который его понимает
+    fn notify_reauth(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.reauth_notified || !self.client_supports("reauth_required") {
+            return;
+        }
+        let Some(deadlines) = self.token_deadlines() else {
+            return;
+        };
+        self.reauth_notified = true;
+        self.send_event(
+            ctx,
+            &ServerEvent::ReauthRequired {
+                expires_in: deadlines.expires_in.as_secs(),
+            },
+        );
+    }
+
+    /// Отправляет reconnect_hint, когда экземпляр становится перегруженным
+    ///
+    /// За одну перегрузку подсказка приходит один раз и только клиенту, который ее понимает
+    fn check_load(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let config = self.config.current();
+        if load_shedding::current_overload(&config).is_none() {
+            self.reconnect_hinted = false;
+            return;
+        }
+        if self.reconnect_hinted || !self.client_supports("reconnect_hint") {
+            return;
+        }
+        self.reconnect_hinted = true;
+        self.send_event(
+            ctx,
+            &ServerEvent::ReconnectHint {
+                after_seconds: config.load_shedding.retry_after_secs,
+            },
+        );
+    }
+
+    /// Просит дослать неподтвержденные сообщения из всех чатов пользователя
+    ///
+    /// Досылка идет мимо брокера, поэтому список блокировок передается вместе с ней
+    fn replay(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let user_id = self.user_id;
+        let chats = self
+            .db
+            .send(database_actor::messages::GetUserChats { user_id });
+        let blocked = self
+            .db
+            .send(database_actor::messages::GetBlockedUsers { user_id });
+        async move { (chats.await, blocked.await) }
+            .into_actor(self)
+            .map(|result, act, ctx| match result {
+                (Ok(Ok(chats)), Ok(Ok(blocked))) => {
+                    act.publisher
+                        .do_send(redis_actor::messages::WebsocketMessage::Replay {
+                            user_id: act.user_id,
+                            chats,
+                            blocked,
+                            socket: ctx.address().recipient(),
+                        })
+                }
+                (Ok(Err(e)), _) | (_, Ok(Err(e))) => {
+                    warn!("Cannot replay messages for user {}: {e}", act.user_id)
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    metrics::MAILBOX_ERRORS
+                        .with_label_values(&["database"])
+                        .inc();
+                    warn!("Cannot replay messages for user {}: {e}", act.user_id)
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.config.current().slow_consumer.grace_secs)
+    }
+
+    /// Отправляет событие в той версии схемы, которую понимает клиент
+    fn send_event(&self, ctx: &mut ws::WebsocketContext<Self>, event: &ServerEvent) {
+        ctx.text(events::encode(event, self.event_version));
+    }
+
+    /// Запрашивает историю чата у базы и отправляет ее клиенту кадром history
+    fn fetch_history(
+        &mut self,
+        chat_id: ChatId,
+        before: Option<i64>,
+        limit: Option<usize>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let request = database_actor::messages::GetChatHistoryBefore {
+            user_id: self.user_id,
+            chat_id,
+            before: before.map(chrono::Duration::milliseconds),
+            limit: limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .clamp(1, MAX_HISTORY_LIMIT),
+            history_limits: self.config.current().history.clone(),
+        };
+        self.query_db(request, ctx, move |messages, act| ServerEvent::History {
+            chat_id,
+            messages: messages
+                .into_iter()
+                .map(|message| message.for_display(&act.metadata.display))
+                .collect(),
+        });
+    }
+
+    /// Отправляет запрос в базу и, не блокируя сокет, отвечает клиенту событием,
+    /// которое строит to_event, или ошибкой
+    fn query_db<M, T, F>(&mut self, request: M, ctx: &mut ws::WebsocketContext<Self>, to_event: F)
+    where
+        M: Message<Result = DBResult<T>> + Send + 'static,
+        T: Send + 'static,
+        DatabaseActor: Handler<M>,
+        F: FnOnce(T, &Self) -> ServerEvent + 'static,
+    {
+        self.db
+            .send(request)
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                let event = match result {
+                    Ok(Ok(value)) => to_event(value, act),
+                    Ok(Err(e)) => ServerEvent::Error {
+                        message: e.to_string(),
+                    },
+                    Err(e) => {
+                        metrics::MAILBOX_ERRORS
+                            .with_label_values(&["database"])
+                            .inc();
+                        ServerEvent::Error {
+                            message: format!("Service is temporarily unavailable: {e}"),
+                        }
+                    }
+                };
+                act.send_event(ctx, &event);
+            })
+            .spawn(ctx);
+    }
+
+    /// Сохраняет сообщение клиента, если ему можно писать в чат
+    ///
+    /// Подписчик канала, где пишут только владелец и администраторы, сразу получает ошибку,
+    /// даже если не заявил message_ack, и не расходует лимит сообщений
+    fn post_message(&mut self, message: ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let request = database_actor::messages::CheckCanPost {
+            user_id: self.user_id,
+            chat_id: ChatId(message.chat_id),
+        };
+        self.after_check(request, ctx, move |act, ctx| {
+            act.persist_within_quota(message, ctx)
+        });
+    }
+
+    /// Выполняет then, если база подтвердила проверку request, иначе отправляет клиенту ошибку
+    fn after_check<M, F>(&mut self, request: M, ctx: &mut ws::WebsocketContext<Self>, then: F)
+    where
+        M: Message<Result = DBResult<()>> + Send + 'static,
+        DatabaseActor: Handler<M>,
+        F: FnOnce(&mut Self, &mut ws::WebsocketContext<Self>) + 'static,
+    {
+        self.db
+            .send(request)
+            .into_actor(self)
+            .map(move |result, act, ctx| match result {
+                Ok(Ok(())) => then(act, ctx),
+                Ok(Err(e)) => act.send_event(
+                    ctx,
+                    &ServerEvent::Error {
+                        message: e.to_string(),
+                    },
+                ),
+                Err(e) => {
+                    metrics::MAILBOX_ERRORS
+                        .with_label_values(&["database"])
+                        .inc();
+                    act.send_event(
+                        ctx,
+                        &ServerEvent::Error {
+                            message: format!("Service is temporarily unavailable: {e}"),
+                        },
+                    )
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Проверяет лимит messages_per_minute, который у новых аккаунтов ниже, и сохраняет
+    /// сообщение. Сообщения сверх лимита отбрасываются, клиенту отправляется ошибка
+    fn persist_within_quota(&mut self, message: ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let read_only = self.config.current().read_only.clone();
+        if read_only::WRITES.is_read_only(&read_only) {
+            self.send_event(
+                ctx,
+                &ServerEvent::ReadOnly {
+                    chat_id: ChatId(message.chat_id),
+                    client_msg_id: message.client_msg_id,
+                    retry_after_secs: read_only.cooldown_secs,
+                },
+            );
+            return;
+        }
+        let account_age = services::account_age(self.metadata.account_created);
+        let per_minute = self
+            .config
+            .current()
+            .rate_limits
+            .messages_per_minute_for(account_age);
+        let limiter = self.limiter.clone();
+        let key = format!("messages:{}", self.user_id);
+        async move { limiter.hit(&key, 60).await }
+            .into_actor(self)
+            .map(move |result, act, ctx| match result {
+                Ok(count) if count > per_minute as u64 => act.send_event(
+                    ctx,
+                    &ServerEvent::Error {
+                        message: format!(
+                            "Message rate limit exceeded: {per_minute} messages per minute"
+                        ),
+                    },
+                ),
+                Ok(_) => act.persist_message(message, ctx),
+                // Если Redis недоступен, то сообщения не ограничиваем
+                Err(e) => {
+                    error!("Cannot check message rate: {e}");
+                    act.persist_message(message, ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Сохраняет сообщение клиента и только после записи рассылает его участникам,
+    /// чтобы сообщения, которые база отвергла (например, из-за политики публикации),
+    /// никуда не ушли. Если клиент заявил message_ack, подтверждает запись или сообщает об ошибке
+    fn persist_message(&mut self, message: ChatMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        self.db
+            .send(database_actor::messages::InsertNewMessage(message))
+            .into_actor(self)
+            .map(move |result, act, ctx| {
+                // Отказ по правилам чата не говорит о том, что база не принимает записи
+                match &result {
+                    Ok(Ok(_)) => read_only::WRITES.record(true, &act.config.current().read_only),
+                    Ok(Err(DBError::LogicError(_))) => {}
+                    Ok(Err(_)) => read_only::WRITES.record(false, &act.config.current().read_only),
+                    Err(_) => {}
+                }
+                let event = match result {
+                    Ok(Ok(inserted)) => {
+                        let message = inserted.message;
+                        let event = ServerEvent::MessageAck {
+                            chat_id: ChatId(message.chat_id),
+                            message_id: message.message_id,
+                            date: message.date.clone(),
+                            client_msg_id: message.client_msg_id.clone(),
+                        };
+                        // Повтор уже сохраненного сообщения никому не рассылается,
+                        // первое сообщение уже разослано
+                        if !inserted.duplicate {
+                            // Отправляем сообщение в редис-брокер, не так важно, если не дошло
+                            act.publisher.do_send(
+                                redis_actor::messages::WebsocketMessage::NewMessage(message),
+                            );
+                        }
+                        event
+                    }
+                    Ok(Err(e)) => ServerEvent::Error {
+                        message: e.to_string(),
+                    },
+                    Err(e) => {
+                        metrics::MAILBOX_ERRORS
+                            .with_label_values(&["database"])
+                            .inc();
+                        ServerEvent::Error {
+                            message: format!("Service is temporarily unavailable: {e}"),
+                        }
+                    }
+                };
+                if act.client_supports("message_ack") {
+                    act.send_event(ctx, &event);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Рассылает участникам чата, что пользователь печатает
+    ///
+    /// Лишние кадры отбрасываются сразу, чтобы не нагружать Redis,
+    /// а в чужие чаты события не уходят
+    fn typing(&mut self, chat_id: ChatId, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.typing.allow(chat_id, self.user_id, Instant::now()) {
+            return;
+        }
+        self.when_can_post(chat_id, ctx, move |act| {
+            redis_actor::messages::WebsocketMessage::Typing(redis_actor::TypingData {
+                chat_id,
+                user_id: act.user_id,
+            })
+        });
+    }
+
+    /// Рассылает участникам чата кратковременный сигнал
+    ///
+    /// Слишком большие сигналы отклоняются с ошибкой, а слишком частые молча
+    /// отбрасываются, как и typing
+    fn broadcast_ephemeral(
+        &mut self,
+        chat_id: ChatId,
+        kind: String,
+        payload: serde_json::Value,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if payload.to_string().len() > MAX_EPHEMERAL_PAYLOAD_BYTES {
+            self.send_event(
+                ctx,
+                &ServerEvent::Error {
+                    message: format!(
+                        "Ephemeral payload is larger than {MAX_EPHEMERAL_PAYLOAD_BYTES} bytes"
+                    ),
+                },
+            );
+            return;
+        }
+        let per_second = self.config.current().rate_limits.ephemeral_per_second;
+        if !self.ephemeral.allow(Instant::now(), per_second) {
+            return;
+        }
+        self.when_can_post(chat_id, ctx, move |act| {
+            redis_actor::messages::WebsocketMessage::Ephemeral(EphemeralData {
+                chat_id,
+                sender_id: act.user_id,
+                kind,
+                payload,
+            })
+        });
+    }
+
+    /// Начинает звонок в чате и записывает это в историю
+    ///
+    /// Начать звонок может тот, кто может писать в чат. Повторный call_start с тем же
+    /// call_id ничего не делает
+    fn start_call(&mut self, chat_id: ChatId, call_id: Uuid, ctx: &mut ws::WebsocketContext<Self>) {
+        let request = database_actor::messages::CheckCanPost {
+            user_id: self.user_id,
+            chat_id,
+        };
+        self.after_check(request, ctx, move |act, _ctx| {
+            if act.calls.start(call_id, chat_id) {
+                act.record_call(
+                    chat_id,
+                    CallEvent {
+                        call_id,
+                        kind: CallEventKind::Started,
+                        duration_secs: None,
+                    },
+                );
+            }
+        });
+    }
+
+    /// Пересылает кадр сигнализации звонка участнику чата to_user
+    ///
+    /// Отбой звонка, начатого с этого сокета, заканчивает звонок. Слишком большие кадры
+    /// отклоняются с ошибкой, а слишком частые молча отбрасываются
+    fn call_signal(&mut self, data: CallSignalData, ctx: &mut ws::WebsocketContext<Self>) {
+        if data.payload.to_string().len() > calls::MAX_SIGNAL_PAYLOAD_BYTES {
+            self.send_event(
+                ctx,
+                &ServerEvent::Error {
+                    message: format!(
+                        "Call signal payload is larger than {} bytes",
+                        calls::MAX_SIGNAL_PAYLOAD_BYTES
+                    ),
+                },
+            );
+            return;
+        }
+        if !self
+            .call_signals
+            .allow(Instant::now(), calls::SIGNALS_PER_SECOND)
+        {
+            return;
+        }
+        if data.signal == CallSignalKind::Hangup {
+            self.finish_call(data.call_id);
+        }
+        self.when_member(data.chat_id, ctx, move |_act| {
+            redis_actor::messages::WebsocketMessage::CallSignal(data)
+        });
+    }
+
+    /// Следит за звонками этого сокета по кадрам, которые присылают собеседники
+    fn track_call(&mut self, data: &CallSignalData) {
+        if !self.calls.is_active(data.call_id) {
+            return;
+        }
+        match data.signal {
+            CallSignalKind::Answer => self.calls.answer(data.call_id, Instant::now()),
+            CallSignalKind::Hangup => self.finish_call(data.call_id),
+            CallSignalKind::Offer | CallSignalKind::IceCandidate => {}
+        }
+    }
+
+    /// Заканчивает звонок, начатый с этого сокета, и записывает это в историю
+    fn finish_call(&mut self, call_id: Uuid) {
+        if let Some((chat_id, call)) = self.calls.finish(call_id, Instant::now()) {
+            self.record_call(chat_id, call);
+        }
+    }
+
+    /// Записывает сообщение о звонке в историю чата и рассылает его участникам
+    ///
+    /// Запись не привязана к сокету, чтобы звонок закончился и тогда, когда сокет закрылся
+    fn record_call(&self, chat_id: ChatId, call: CallEvent) {
+        let message = services::call_message(self.user_id, chat_id, call);
+        let (db, publisher, user_id) = (self.db.clone(), self.publisher.clone(), self.user_id);
+        actix::spawn(async move {
+            match db
+                .send(database_actor::messages::InsertNewMessage(message))
+                .await
+            {
+                Ok(Ok(inserted)) => {
+                    if !inserted.duplicate {
+                        publisher.do_send(redis_actor::messages::WebsocketMessage::NewMessage(
+                            inserted.message,
+                        ));
+                    }
+                }
+                Ok(Err(e)) => warn!("Cannot record call of user {user_id}: {e}"),
+                Err(e) => {
+                    metrics::MAILBOX_ERRORS
+                        .with_label_values(&["database"])
+                        .inc();
+                    warn!("Cannot record call of user {user_id}: {e}")
+                }
+            }
+        });
+    }
+
+    /// Сообщает остальным сокетам пользователя, докуда он прочитал чат
+    fn mark_read(
+        &mut self,
+        chat_id: ChatId,
+        message_id: Uuid,
+        date: SerializableDuration,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.when_member(chat_id, ctx, move |act| {
+            act.db.do_send(database_actor::messages::MarkRead {
+                user_id: act.user_id,
+                chat_id,
+                position: ReadPosition {
+                    message_id,
+                    date: date.clone(),
+                },
+            });
+            redis_actor::messages::WebsocketMessage::ReadPosition(ReadPositionData {
+                user_id: act.user_id,
+                chat_id,
+                message_id,
+                date,
+                origin: act.connection_id,
+            })
+        });
+    }
+
+    /// Публикует событие, которое строит to_event, если пользователь состоит в чате
+    fn when_member<F>(&mut self, chat_id: ChatId, ctx: &mut ws::WebsocketContext<Self>, to_event: F)
+    where
+        F: FnOnce(&Self) -> redis_actor::messages::WebsocketMessage + 'static,
+    {
+        let request = database_actor::messages::CheckMembership {
+            user_id: self.user_id,
+            chat_id,
+        };
+        self.when_allowed(request, ctx, to_event);
+    }
+
+    /// Публикует событие, которое строит to_event, если пользователь может писать в чат
+    ///
+    /// Подписчики каналов, где пишут только владелец и администраторы, не печатают
+    /// и не рассылают сигналов
+    fn when_can_post<F>(
+        &mut self,
+        chat_id: ChatId,
+        ctx: &mut ws::WebsocketContext<Self>,
+        to_event: F,
+    ) where
+        F: FnOnce(&Self) -> redis_actor::messages::WebsocketMessage + 'static,
+    {
+        let request = database_actor::messages::CheckCanPost {
+            user_id: self.user_id,
+            chat_id,
+        };
+        self.when_allowed(request, ctx, to_event);
+    }
+
+    /// Публикует событие, которое строит to_event, если база подтвердила проверку request
+    fn when_allowed<M, F>(&mut self, request: M, ctx: &mut ws::WebsocketContext<Self>, to_event: F)
+    where
+        M: Message<Result = DBResult<()>> + Send + 'static,
+        DatabaseActor: Handler<M>,
+        F: FnOnce(&Self) -> redis_actor::messages::WebsocketMessage + 'static,
+    {
+        self.db
+            .send(request)
+            .into_actor(self)
+            .map(move |result, act, _ctx| match result {
+                Ok(Ok(())) => act.publisher.do_send(to_event(act)),
+                Ok(Err(DBError::LogicError(_))) => {}
+                Ok(Err(e)) => warn!("Cannot check chats of user {}: {e}", act.user_id),
+                Err(e) => {
+                    metrics::MAILBOX_ERRORS
+                        .with_label_values(&["database"])
+                        .inc();
+                    warn!("Cannot check chats of user {}: {e}", act.user_id)
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Добавляет кусок вложения из бинарного кадра, а когда пришел последний, сохраняет файл
+    fn upload_chunk(&mut self, frame: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.client_supports("attachment_upload") {
+            self.send_event(
+                ctx,
+                &ServerEvent::Error {
+                    message: "Binary frames require the attachment_upload capability".into(),
+                },
+            );
+            return;
+        }
+        let (header, chunk) = match uploads::parse_chunk(frame) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.send_event(
+                    ctx,
+                    &ServerEvent::Error {
+                        message: e.to_string(),
+                    },
+                );
+                return;
+            }
+        };
+        let upload_id = header.upload_id;
+        // Имя проверяется сразу, чтобы не принимать весь файл зря
+        if let Some(name) = header.name.as_deref() {
+            if let Err(e) = validation::validate_file_name("name", name) {
+                let message = e.localize(self.metadata.display.locale).message;
+                self.send_event(
+                    ctx,
+                    &ServerEvent::AttachmentUploadFailed { upload_id, message },
+                );
+                return;
+            }
+        }
+        let max_bytes = self.config.static_config().storage.max_attachment_bytes;
+        match self.uploads.push(header, chunk, max_bytes) {
+            Ok(Some(upload)) => self.store_upload(upload, ctx),
+            Ok(None) => {}
+            Err(e) => self.send_event(
+                ctx,
+                &ServerEvent::AttachmentUploadFailed {
+                    upload_id,
+                    message: e.to_string(),
+                },
+            ),
+        }
+    }
+
+    /// Кладет полученное вложение в хранилище, а описание - в базу
+    ///
+    /// Участие в чате проверяется до загрузки, чтобы посторонние не засоряли хранилище
+    fn store_upload(&mut self, upload: CompletedUpload, ctx: &mut ws::WebsocketContext<Self>) {
+        let upload_id = upload.upload_id;
+        let Some(storage) = self.storage.clone() else {
+            self.send_event(
+                ctx,
+                &ServerEvent::AttachmentUploadFailed {
+                    upload_id,
+                    message: crate::storage::StorageError::NotConfigured.to_string(),
+                },
+            );
+            return;
+        };
+        let (db, user_id) = (self.db.clone(), self.user_id);
+        let unavailable = |actor: &'static str, e: MailboxError| {
+            metrics::MAILBOX_ERRORS.with_label_values(&[actor]).inc();
+            format!("Service is temporarily unavailable: {e}")
+        };
+        async move {
+            db.send(database_actor::messages::CheckMembership {
+                user_id,
+                chat_id: ChatId(upload.chat_id),
+            })
+            .await
+            .map_err(|e| unavailable("database", e))?
+            .map_err(|e| e.to_string())?;
+            let id = Uuid::new_v4();
+            let size = upload.body.len() as u64;
+            let mime = crate::storage::attachment_content_type(&upload.mime).to_string();
+            let url = storage
+                .send(storage_actor::messages::PutObject {
+                    key: format!("{}/{id}", upload.chat_id),
+                    content_type: mime.clone(),
+                    length: size,
+                    body: crate::storage::object_stream(upload.body),
+                })
+                .await
+                .map_err(|e| unavailable("storage", e))?
+                .map_err(|e| e.to_string())?;
+            let attachment = Attachment {
+                id,
+                chat_id: upload.chat_id,
+                uploader_id: user_id.get(),
+                name: upload.name,
+                size,
+                mime,
+                url,
+                created_at: (chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH).into(),
+            };
+            db.send(database_actor::messages::AddAttachment(attachment.clone()))
+                .await
+                .map_err(|e| unavailable("database", e))?
+                .map_err(|e| e.to_string())?;
+            Ok(attachment)
+        }
+        .into_actor(self)
+        .map(move |result: Result<Attachment, String>, act, ctx| {
+            let event = match result {
+                Ok(attachment) => ServerEvent::AttachmentUploaded {
+                    upload_id,
+                    attachment,
+                },
+                Err(message) => ServerEvent::AttachmentUploadFailed { upload_id, message },
+            };
+            act.send_event(ctx, &event);
+        })
+        .spawn(ctx);
+    }
+
+    /// Обрабатывает сигнал брокера о том, что очередь сокета переполнена и событие отброшено
+    fn handle_overflow(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let now = Instant::now();
+        self.last_overflow = Some(now);
+        let Some(slow_since) = self.slow_since else {
+            self.slow_since = Some(now);
+            warn!("User {} is a slow consumer", self.user_id);
+            metrics::SLOW_CONSUMERS.with_label_values(&["warned"]).inc();
+            if self.client_supports("slow_consumer") {
+                self.send_event(
+                    ctx,
+                    &ServerEvent::SlowConsumer {
+                        grace_secs: self.config.current().slow_consumer.grace_secs,
+                    },
+                );
+            }
+            return;
+        };
+        if self.config.current().slow_consumer.disconnect && now - slow_since >= self.grace_period()
+        {
+            warn!(
+                "Disconnecting user {}: message queue overflowed for {:?}",
+                self.user_id,
+                now - slow_since
+            );
+            metrics::SLOW_CONSUMERS
+                .with_label_values(&["disconnected"])
+                .inc();
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("slow consumer".into()),
+            }));
+            ctx.stop();
+        }
+    }
+
+    /// Снимает пометку медленного клиента, если очередь не переполнялась целый grace_secs
+    fn check_recovered(&mut self) {
+        if let (Some(_), Some(last_overflow)) = (self.slow_since, self.last_overflow) {
+            if last_overflow.elapsed() >= self.grace_period() {
+                info!("User {} caught up with the message queue", self.user_id);
+                self.slow_since = None;
+            }
         }
     }
 }
@@ -74,50 +1393,239 @@ impl WebsocketActor {
 impl Actor for WebsocketActor {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.config.current().slow_consumer.mailbox_capacity);
+        self.send_event(
+            ctx,
+            &ServerEvent::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: SERVER_CAPABILITIES.to_vec(),
+            },
+        );
+        info!(
+            "User {} connected from {:?}",
+            self.user_id, self.metadata.client_ip
+        );
+        self.schedule_reauth(ctx);
+        ctx.run_interval(LOAD_CHECK_INTERVAL, |act, ctx| act.check_load(ctx));
+        // Сигналы о переполнении очереди приходят отдельным потоком, мимо самой очереди
+        let overflow = Arc::new(Notify::new());
+        ctx.add_stream(futures::stream::unfold(
+            overflow.clone(),
+            |overflow| async move {
+                overflow.notified().await;
+                Some((Overflow, overflow))
+            },
+        ));
         self.broker.do_send(
             broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
-                ctx.address(),
+                SocketHandle {
+                    addr: ctx.address().recipient(),
+                    overflow: Some(overflow),
+                },
                 self.user_id,
             ),
         );
+        self.publisher
+            .do_send(redis_actor::messages::SocketPresence {
+                user_id: self.user_id,
+                opened: true,
+            });
     }
     fn stopped(&mut self, ctx: &mut Self::Context) {
+        for (chat_id, call) in self.calls.finish_all(Instant::now()) {
+            self.record_call(chat_id, call);
+        }
         self.broker.do_send(
             broker_actor::messages::WebsocketMessage::BrokerNotifyClosed(
-                ctx.address(),
+                ctx.address().recipient(),
                 self.user_id,
             ),
         );
+        self.publisher
+            .do_send(redis_actor::messages::SocketPresence {
+                user_id: self.user_id,
+                opened: false,
+            });
+    }
+}
+
+impl StreamHandler<Overflow> for WebsocketActor {
+    fn handle(&mut self, _signal: Overflow, ctx: &mut Self::Context) {
+        self.handle_overflow(ctx);
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketActor {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if matches!(msg, Ok(ws::Message::Text(_) | ws::Message::Binary(_))) {
+            self.record_usage();
+        }
         match msg {
             // Получаем текст по вебсокету
             Ok(ws::Message::Text(text)) => {
-                // Приводим его к типу "Новое сообщение"
-                let user_msg: NewChatMessage = from_str(&text).unwrap();
+                // Разбираем кадр: это либо запрос, либо новое сообщение
+                let user_msg = match ClientFrame::parse(&text) {
+                    Ok(ClientFrame::Message(msg)) => msg,
+                    Ok(ClientFrame::Request(ClientRequest::Capabilities {
+                        capabilities,
+                        version,
+                    })) => {
+                        self.negotiate(capabilities, version, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::FetchHistory {
+                        chat_id,
+                        before,
+                        limit,
+                    })) => {
+                        self.fetch_history(chat_id, before, limit, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::GetChats)) => {
+                        let request = database_actor::messages::GetUserChats {
+                            user_id: self.user_id,
+                        };
+                        self.query_db(request, ctx, |chats, _act| ServerEvent::Chats { chats });
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::GetChatInfo { chat_id })) => {
+                        let request = database_actor::messages::GetChatInfo {
+                            user_id: self.user_id,
+                            chat_id,
+                        };
+                        self.query_db(request, ctx, |chat, act| ServerEvent::ChatInfo {
+                            chat: services::chat_for_client(chat, &act.config),
+                        });
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::Typing { chat_id })) => {
+                        self.typing(chat_id, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::BroadcastEphemeral {
+                        chat_id,
+                        kind,
+                        payload,
+                    })) => {
+                        self.broadcast_ephemeral(chat_id, kind, payload, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::CallStart { chat_id, call_id })) => {
+                        self.start_call(chat_id, call_id, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::CallSignal {
+                        chat_id,
+                        call_id,
+                        to_user,
+                        signal,
+                        payload,
+                    })) => {
+                        let data = CallSignalData {
+                            chat_id,
+                            call_id,
+                            from_user: self.user_id,
+                            to_user,
+                            signal,
+                            payload,
+                        };
+                        self.call_signal(data, ctx);
+                        return;
+                    }
+                    Ok(ClientFrame::Request(ClientRequest::MarkRead {
+                        chat_id,
+                        message_id,
+                        date,
+                    })) => {
+                        self.mark_read(chat_id, message_id, date, ctx);
+                        return;
+                    }
+                    // Подтверждения хранятся в Redis, так что чужие чаты не должны их засорять
+                    Ok(ClientFrame::Request(ClientRequest::Ack {
+                        chat_id,
+                        delivery_id,
+                    })) => {
+                        self.when_member(chat_id, ctx, move |act| {
+                            redis_actor::messages::WebsocketMessage::Ack {
+                                chat_id,
+                                user_id: act.user_id,
+                                delivery_id,
+                            }
+                        });
+                        return;
+                    }
+                    Err(e) => {
+                        self.send_event(
+                            ctx,
+                            &ServerEvent::Error {
+                                message: format!("Invalid frame: {e}"),
+                            },
+                        );
+                        return;
+                    }
+                };
 
                 // Из нового сообщения состряпываем нормальное с нужными данными
-                let chat_msg = ChatMessage {
-                    chat_id: user_msg.chat_id,
-                    sender_id: self.user_id,
-                    date: (chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH).into(),
-                    msg_text: user_msg.msg_text,
+                let (chat_id, client_msg_id) = (user_msg.chat_id, user_msg.client_msg_id.clone());
+                let rules = self.config.current().validation.message.clone();
+                let chat_msg = match services::compose_message(
+                    self.user_id,
+                    user_msg,
+                    &rules,
+                    self.moderation.as_ref(),
+                ) {
+                    Ok(message) => message,
+                    Err(ServiceError::Invalid(fields)) => {
+                        let locale = self.metadata.display.locale;
+                        self.send_event(
+                            ctx,
+                            &ServerEvent::ValidationFailed {
+                                chat_id,
+                                client_msg_id,
+                                fields: fields.into_iter().map(|e| e.localize(locale)).collect(),
+                            },
+                        );
+                        return;
+                    }
+                    Err(ServiceError::Database(e)) => {
+                        self.send_event(
+                            ctx,
+                            &ServerEvent::Error {
+                                message: e.to_string(),
+                            },
+                        );
+                        return;
+                    }
                 };
 
-                // Отправляем сообщение в базу, не так важно, если оно не дошло
-                self.db
-                    .do_send(database_actor::messages::InsertNewMessage(chat_msg.clone()));
-
-                // Отправляем сообщение в редис-брокер, не так важно, если не дошло
-                self.publisher
-                    .do_send(redis_actor::messages::WebsocketMessage::NewMessage(
-                        chat_msg,
-                    ));
+                self.post_message(chat_msg, ctx);
             }
+            Ok(ws::Message::Binary(frame)) => self.upload_chunk(&frame, ctx),
             Ok(ws::Message::Close(_)) => ctx.stop(),
+            // Собранное из фрагментов сообщение обрабатывается как целый кадр
+            Ok(ws::Message::Continuation(item)) => {
+                let max_bytes = self.config.current().websocket.max_frame_bytes;
+                let msg = match self.fragments.push(item, max_bytes) {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                };
+                StreamHandler::handle(self, msg, ctx);
+            }
+            // После ошибки кодек не может продолжить чтение, так что сокет закрывается
+            Err(e) => {
+                info!("Closing websocket of user {}: {e}", self.user_id);
+                let message = match e {
+                    ws::ProtocolError::Overflow => format!(
+                        "Frame is larger than {} bytes",
+                        self.config.current().websocket.max_frame_bytes
+                    ),
+                    ref e => e.to_string(),
+                };
+                self.send_event(ctx, &ServerEvent::Error { message });
+                ctx.close(Some(protocol_error_close(&e)));
+                ctx.stop();
+            }
             _ => (),
         }
     }
@@ -127,9 +1635,148 @@ impl Handler<messages::BrokerMessage> for WebsocketActor {
     type Result = ();
     fn handle(&mut self, msg: messages::BrokerMessage, ctx: &mut Self::Context) -> Self::Result {
         match msg {
-            messages::BrokerMessage::NewMessage(new_msg) => {
-                let m = to_string(&new_msg).unwrap();
-                ctx.text(m);
+            messages::BrokerMessage::NewMessage(mut new_msg) => {
+                self.check_recovered();
+                if UserId(new_msg.sender_id) != self.user_id {
+                    new_msg.client_msg_id = None;
+                }
+                ctx.text(events::encode(&new_msg, self.event_version));
+            }
+            messages::BrokerMessage::MessageEdited(message) => {
+                self.check_recovered();
+                if self.client_supports("message_edited") {
+                    self.send_event(ctx, &ServerEvent::MessageEdited { message });
+                }
+            }
+            messages::BrokerMessage::MessageDeleted(tombstone) => {
+                self.check_recovered();
+                if self.client_supports("message_deleted") {
+                    self.send_event(ctx, &ServerEvent::MessageDeleted { tombstone });
+                }
+            }
+            messages::BrokerMessage::MessageUnpinned(unpinned) => {
+                self.check_recovered();
+                if self.client_supports("message_unpinned") {
+                    self.send_event(ctx, &ServerEvent::MessageUnpinned { unpinned });
+                }
+            }
+            messages::BrokerMessage::ChatRenamed(data) => {
+                self.check_recovered();
+                if self.client_supports("chat_renamed") {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::ChatRenamed {
+                            chat_id: data.chat_id,
+                            name: data.name,
+                            renamed_by: data.renamed_by,
+                        },
+                    );
+                }
+            }
+            messages::BrokerMessage::ProfileUpdated(data) => {
+                self.check_recovered();
+                if self.client_supports("profile_updated") {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::ProfileUpdated {
+                            user_id: data.user_id,
+                            name: data.name,
+                            profile: data.profile,
+                        },
+                    );
+                }
+            }
+            messages::BrokerMessage::RemovedFromChat(data) => {
+                self.check_recovered();
+                if self.client_supports("removed_from_chat") {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::RemovedFromChat {
+                            chat_id: data.chat_id,
+                            removed_by: data.removed_by,
+                        },
+                    );
+                }
+            }
+            messages::BrokerMessage::SessionRevoked(session_id) => {
+                if self.metadata.session_id.as_ref() == Some(&session_id) {
+                    warn!("Closing revoked session of user {}", self.user_id);
+                    ctx.close(Some(ws::CloseReason {
+                        code: ws::CloseCode::Policy,
+                        description: Some("session revoked".into()),
+                    }));
+                    ctx.stop();
+                }
+            }
+            messages::BrokerMessage::Presence {
+                chat_id,
+                user_id,
+                online,
+            } => {
+                if self.client_supports("presence") {
+                    let event = if online {
+                        ServerEvent::MemberOnline { chat_id, user_id }
+                    } else {
+                        ServerEvent::MemberOffline { chat_id, user_id }
+                    };
+                    self.send_event(ctx, &event);
+                }
+            }
+            messages::BrokerMessage::Ephemeral(data) => {
+                if self.client_supports("broadcast_ephemeral") {
+                    self.send_event(ctx, &ServerEvent::BroadcastEphemeral { data });
+                }
+            }
+            messages::BrokerMessage::CallSignal(data) => {
+                self.track_call(&data);
+                if self.client_supports("calls") {
+                    self.send_event(ctx, &ServerEvent::CallSignal { data });
+                }
+            }
+            messages::BrokerMessage::Typing { chat_id, user_id } => {
+                if self.client_supports("typing") {
+                    self.send_event(ctx, &ServerEvent::Typing { chat_id, user_id });
+                }
+            }
+            messages::BrokerMessage::Mentioned {
+                chat_id,
+                message_id,
+                sender_id,
+                preview,
+            } => {
+                if self.client_supports("mentioned") {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::Mentioned {
+                            chat_id,
+                            message_id,
+                            sender_id,
+                            preview,
+                        },
+                    );
+                }
+            }
+            messages::BrokerMessage::ReadPositionChanged(data) => {
+                if data.origin != self.connection_id
+                    && self.client_supports("read_position_changed")
+                {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::ReadPositionChanged {
+                            chat_id: data.chat_id,
+                            message_id: data.message_id,
+                            date: data.date,
+                        },
+                    );
+                }
+            }
+            messages::BrokerMessage::LoginConflict(conflict) => {
+                info!(
+                    "Closing websocket of user {}: {:?} by duplicate login policy",
+                    self.user_id, conflict
+                );
+                ctx.close(Some(conflict.close_reason()));
+                ctx.stop();
             }
         }
     }
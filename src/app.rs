@@ -0,0 +1,236 @@
+use std::{net::ToSocketAddrs, sync::Arc};
+
+use actix_web::{middleware::Logger, web, App, HttpServer};
+
+use crate::{
+    config::ConfigHandle,
+    content::ContentProviders,
+    handlers::{
+        add_contact, add_user_to_chat, archive_chat, authorize_user, block_user,
+        create_chat_from_template, create_new_channel, create_new_group_chat,
+        create_new_private_chat, create_share_link, data_types::Addresses, delete_message,
+        discover_channels, edit_message, exit_chat, forward_message, get_all_notification_settings,
+        get_api_usage, get_attachment, get_blocked_users, get_capabilities, get_chat_events_feed,
+        get_chat_history, get_chat_info, get_chat_members, get_chat_pins, get_chat_usage,
+        get_contacts, get_draft, get_limits, get_online_members, get_shared_history, get_thread,
+        get_unread_counts, get_user_chats, get_user_chats_detailed, get_user_info,
+        get_user_list_paged, get_user_preferences, get_users_info, join_chat_by_invite,
+        join_public_channel, kick_user, metrics_endpoint, mute_chat, pin_message, register_bot,
+        reload_config, remove_contact, rename_chat, rename_user, reset_user_preferences,
+        revoke_invite_code, revoke_webhook_token, rotate_invite_code, rotate_webhook_token,
+        save_draft, search_content, send_message, set_chat_labels, set_chat_permissions,
+        set_delivery_mode, set_member_limit, set_message_ttl, set_notification_settings, set_role,
+        set_user_preferences, set_user_profile, unarchive_chat, unblock_user, unmute_chat,
+        unpin_message, unregister_bot, upload_attachment, websocket_startup,
+    },
+    middlewares::{
+        auth_lockout_middleware::AuthLockoutMiddleware,
+        authenticator_middleware::{Authenticator, AuthenticatorMiddleware},
+        client_ip_middleware::ClientIpMiddleware,
+        read_only_middleware::ReadOnlyMiddleware,
+        test_token_middleware::TestAuthMiddleware,
+        usage_middleware::UsageMiddleware,
+    },
+    moderation::{ModerationFilter, WordlistFilter},
+    presence::PresenceTracker,
+    rate_limit::RateLimit,
+    session_binding::SessionBinder,
+    sharing::ShareLinks,
+    usage::{NoUsageTracking, UsageTracker},
+};
+
+// Сборка HTTP-сервиса
+//
+// ChatApp собирает маршруты, middleware и общие данные из зависимостей, поднятых при
+// запуске. Схему авторизации, счетчики частоты запросов, фильтр модерации и учет
+// использования API он получает как типажи-объекты, так что приложение, встраивающее чат, подставляет свои реализации
+// (например, авторизацию по заголовкам service mesh), не трогая обработчики.
+
+#[derive(Clone)]
+pub struct ChatApp {
+    config: ConfigHandle,
+    addresses: web::Data<Addresses>,
+    limiter: Arc<dyn RateLimit>,
+    session_binder: web::Data<SessionBinder>,
+    presence: web::Data<PresenceTracker>,
+    content: web::Data<ContentProviders>,
+    share_links: web::Data<ShareLinks>,
+    authenticator: Arc<dyn Authenticator>,
+    moderation: Arc<dyn ModerationFilter>,
+    usage: Arc<dyn UsageTracker>,
+}
+
+impl ChatApp {
+    /// Без своих настроек пользователь берется из заголовка chat_user_id, текст
+    /// сообщений проверяется по moderation_wordlist из конфигурации, а использование
+    /// API не учитывается
+    pub fn new(
+        config: ConfigHandle,
+        addresses: Addresses,
+        limiter: Arc<dyn RateLimit>,
+        session_binder: SessionBinder,
+        presence: PresenceTracker,
+    ) -> Self {
+        let content = ContentProviders::from_config(&config.static_config().content);
+        let share_links = ShareLinks::from_config(&config.static_config().share);
+        Self {
+            moderation: Arc::new(WordlistFilter::new(config.clone())),
+            config,
+            addresses: web::Data::new(addresses),
+            limiter,
+            session_binder: web::Data::new(session_binder),
+            presence: web::Data::new(presence),
+            content: web::Data::new(content),
+            share_links: web::Data::new(share_links),
+            authenticator: Arc::new(TestAuthMiddleware),
+            usage: Arc::new(NoUsageTracking),
+        }
+    }
+
+    /// Узнавать пользователя запроса своей схемой авторизации
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Считать частоту запросов и блокировки своей реализацией
+    pub fn with_rate_limiter(mut self, limiter: Arc<dyn RateLimit>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Проверять текст сообщений своим фильтром
+    pub fn with_moderation(mut self, moderation: Arc<dyn ModerationFilter>) -> Self {
+        self.moderation = moderation;
+        self
+    }
+
+    /// Учитывать запросы и кадры вебсокета пользователей
+    pub fn with_usage_tracker(mut self, usage: Arc<dyn UsageTracker>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Регистрирует маршруты и общие данные без middleware
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(
+            web::scope("/api")
+                .service(
+                    web::scope("/user")
+                        .service(authorize_user)
+                        .service(get_user_info)
+                        .service(set_user_profile)
+                        .service(rename_user)
+                        .service(get_user_chats)
+                        .service(get_user_chats_detailed)
+                        .service(get_unread_counts)
+                        .service(get_api_usage)
+                        .service(get_all_notification_settings)
+                        .service(mute_chat)
+                        .service(unmute_chat)
+                        .service(get_user_preferences)
+                        .service(set_user_preferences)
+                        .service(reset_user_preferences)
+                        .service(block_user)
+                        .service(unblock_user)
+                        .service(get_blocked_users)
+                        .service(get_contacts)
+                        .service(add_contact)
+                        .service(remove_contact)
+                        .service(get_users_info),
+                )
+                .service(web::scope("/content").service(search_content))
+                .service(
+                    web::scope("/meta")
+                        .service(get_limits)
+                        .service(get_capabilities),
+                )
+                .service(
+                    web::scope("/admin")
+                        .service(reload_config)
+                        .service(get_user_list_paged)
+                        .service(set_delivery_mode)
+                        .service(set_member_limit)
+                        .service(get_chat_usage)
+                        .service(set_chat_labels)
+                        .service(register_bot)
+                        .service(unregister_bot),
+                )
+                .service(
+                    web::scope("/chat")
+                        .service(create_new_group_chat)
+                        .service(create_new_private_chat)
+                        .service(create_new_channel)
+                        .service(discover_channels)
+                        .service(create_chat_from_template)
+                        .service(add_user_to_chat)
+                        .service(exit_chat)
+                        .service(archive_chat)
+                        .service(unarchive_chat)
+                        .service(kick_user)
+                        .service(rename_chat)
+                        .service(set_role)
+                        .service(set_chat_permissions)
+                        .service(send_message)
+                        .service(edit_message)
+                        .service(delete_message)
+                        .service(forward_message)
+                        .service(get_chat_info)
+                        .service(get_chat_pins)
+                        .service(get_chat_events_feed)
+                        .service(upload_attachment)
+                        .service(get_attachment)
+                        .service(pin_message)
+                        .service(unpin_message)
+                        .service(set_notification_settings)
+                        .service(set_message_ttl)
+                        .service(get_draft)
+                        .service(save_draft)
+                        .service(get_chat_members)
+                        .service(get_online_members)
+                        .service(get_chat_history)
+                        .service(get_thread)
+                        .service(create_share_link)
+                        .service(rotate_invite_code)
+                        .service(revoke_invite_code)
+                        .service(join_chat_by_invite)
+                        .service(join_public_channel)
+                        .service(rotate_webhook_token)
+                        .service(revoke_webhook_token),
+                ),
+        )
+        .service(websocket_startup)
+        .service(metrics_endpoint)
+        .service(get_shared_history)
+        .app_data(self.addresses.clone())
+        .app_data(web::Data::new(self.config.clone()))
+        .app_data(web::Data::from(self.limiter.clone()))
+        .app_data(web::Data::from(self.moderation.clone()))
+        .app_data(web::Data::from(self.usage.clone()))
+        .app_data(self.session_binder.clone())
+        .app_data(self.presence.clone())
+        .app_data(self.content.clone())
+        .app_data(self.share_links.clone());
+    }
+
+    /// Запускает HTTP-сервер и ждет его остановки
+    pub async fn run(self, address: impl ToSocketAddrs) -> std::io::Result<()> {
+        HttpServer::new(move || {
+            let app = self.clone();
+            App::new()
+                .wrap(ReadOnlyMiddleware::new(app.config.clone()))
+                .wrap(Logger::default())
+                .wrap(UsageMiddleware::new(app.usage.clone()))
+                .wrap(AuthenticatorMiddleware::new(app.authenticator.clone()))
+                .wrap(AuthLockoutMiddleware::new(
+                    app.limiter.clone(),
+                    app.config.clone(),
+                ))
+                .wrap(ClientIpMiddleware::new(app.config.clone()))
+                .configure(|cfg| app.configure(cfg))
+        })
+        .bind(address)?
+        .run()
+        .await
+    }
+}
@@ -0,0 +1,70 @@
+use std::{env, error::Error, path::PathBuf};
+
+use chat::{
+    config::DatabaseConfig,
+    database::{Database, ScyllaDatabase},
+    migration::{Migration, DEFAULT_PAGE_SIZE},
+};
+use log::info;
+
+// Перенос данных чата из одной базы в другую
+//
+// migrate <источник host:port[/keyspace]> <приемник host:port[/keyspace]> [файл контрольной точки]
+//     [размер страницы]
+//
+// Если перенос прервался, повторный запуск с тем же файлом контрольной точки
+// продолжит его с того места, где он остановился
+
+const USAGE: &str = "Usage: migrate <source host:port[/keyspace]> <target host:port[/keyspace]> \
+     [checkpoint file] [page size]";
+
+/// Разбирает адрес вида host:port или host:port/keyspace
+fn parse_address(address: &str) -> Result<DatabaseConfig, Box<dyn Error>> {
+    let (address, keyspace) = match address.split_once('/') {
+        Some((address, keyspace)) => (address, Some(keyspace)),
+        None => (address, None),
+    };
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Invalid address {address}, expected host:port"))?;
+    let mut config = DatabaseConfig {
+        host: host.to_string(),
+        port: port.parse()?,
+        ..Default::default()
+    };
+    if let Some(keyspace) = keyspace {
+        config.keyspace = keyspace.to_string();
+    }
+    Ok(config)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        return Err(USAGE.into());
+    }
+    let source_config = parse_address(&args[0])?;
+    let target_config = parse_address(&args[1])?;
+    let checkpoint = args.get(2).map(PathBuf::from);
+    let page_size = match args.get(3) {
+        Some(size) => size.parse()?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+
+    let source = ScyllaDatabase::connect(&source_config).await?;
+    let target = ScyllaDatabase::connect(&target_config).await?;
+    target.init_db().await?;
+    info!("Connected to source and target databases");
+
+    let progress = Migration::new(&source, &target, checkpoint)?
+        .page_size(page_size)
+        .run()
+        .await?;
+    info!(
+        "Done: {} users, {} chats, {} messages",
+        progress.users, progress.chats, progress.messages
+    );
+    Ok(())
+}
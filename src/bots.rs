@@ -0,0 +1,140 @@
+use std::{collections::HashMap, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    actors::websocket_actor::ChatMessage,
+    config::BotsConfig,
+    database::data::{BotWebhook, UserInfo},
+    http_client::{HttpEndpoint, HttpError},
+};
+
+// Боты с вебхуками
+//
+// Бот - обычный пользователь чата, для которого администратор зарегистрировал адрес
+// вебхука. Вебсокет боту не нужен: новые сообщения чатов, в которых он состоит, сервер
+// отправляет POST-запросом на этот адрес, а отвечает бот через обычный HTTP API от своего
+// имени. Сообщение отправляет экземпляр, который его принял, так что бот получает его один
+// раз, сколько бы экземпляров ни было.
+//
+// Тело запроса подписывается HMAC-SHA256 секретом бота: в X-Chat-Signature приходит
+// sha256={hex(hmac(секрет, "{X-Chat-Timestamp}.{тело}"))}, так что бот может отбросить
+// подделанные и старые запросы. Секрет выдается один раз при регистрации и хранится в базе
+// как есть - без него нечем было бы подписывать. Если бот не ответил 2xx, запрос
+// повторяется с удваивающейся паузой, а X-Chat-Delivery (id сообщения) позволяет боту не
+// обработать повтор дважды.
+
+pub const SIGNATURE_HEADER: &str = "X-Chat-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Chat-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Chat-Delivery";
+
+/// Самая долгая пауза между повторами
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Что получает бот
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BotEvent<'a> {
+    NewMessage {
+        bot_id: i64,
+        message: &'a ChatMessage,
+    },
+}
+
+/// Подпись тела запроса, отправленного в момент timestamp (секунды от начала эпохи)
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Пауза перед попыткой attempt (вторая попытка - 2)
+pub fn retry_delay(config: &BotsConfig, attempt: u32) -> Duration {
+    let factor = 1u32
+        .checked_shl(attempt.saturating_sub(2))
+        .unwrap_or(u32::MAX);
+    Duration::from_millis(config.retry_delay_ms)
+        .saturating_mul(factor)
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Боты в каждом чате
+#[derive(Default)]
+pub struct BotRoutes {
+    chats: HashMap<Uuid, Vec<BotWebhook>>,
+}
+
+impl BotRoutes {
+    /// Раскладывает ботов по чатам, в которых они состоят (users - их профили с чатами)
+    pub fn new(bots: Vec<BotWebhook>, users: &[UserInfo]) -> Self {
+        let mut chats: HashMap<Uuid, Vec<BotWebhook>> = HashMap::new();
+        for bot in bots {
+            let Some(user) = users.iter().find(|user| user.id == bot.user_id) else {
+                continue;
+            };
+            for chat_id in &user.chats {
+                chats.entry(*chat_id).or_default().push(bot.clone());
+            }
+        }
+        Self { chats }
+    }
+
+    /// Кому из ботов отправить сообщение; свои сообщения бот не получает
+    pub fn recipients(&self, message: &ChatMessage) -> Vec<BotWebhook> {
+        self.chats
+            .get(&message.chat_id)
+            .into_iter()
+            .flatten()
+            .filter(|bot| bot.user_id != message.sender_id)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Отправляет сообщение боту, повторяя до max_attempts раз; now - часы для подписи
+pub async fn deliver(
+    bot: &BotWebhook,
+    message: &ChatMessage,
+    config: &BotsConfig,
+    now: impl Fn() -> i64,
+) -> Result<(), HttpError> {
+    let endpoint = HttpEndpoint::parse(&bot.callback_url)?;
+    let mut message = message.clone();
+    // Это метки для сокетов отправителя
+    message.client_msg_id = None;
+    message.delivery_id = None;
+    let body = serde_json::to_vec(&BotEvent::NewMessage {
+        bot_id: bot.user_id,
+        message: &message,
+    })
+    .expect("Bot event is serializable");
+    let path = if endpoint.prefix.is_empty() { "/" } else { "" };
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let mut attempt = 1;
+    loop {
+        let timestamp = now();
+        let headers = [
+            ("Content-Type", "application/json".to_string()),
+            (SIGNATURE_HEADER, sign(&bot.secret, timestamp, &body)),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (DELIVERY_HEADER, message.message_id.to_string()),
+        ];
+        match endpoint
+            .request("POST", path, &headers, body.clone(), timeout)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= config.max_attempts => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                tokio::time::sleep(retry_delay(config, attempt)).await;
+            }
+        }
+    }
+}
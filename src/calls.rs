@@ -0,0 +1,143 @@
+use std::{collections::HashMap, time::Instant};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::ids::ChatId;
+
+// Звонки
+//
+// Сам звонок идет напрямую между клиентами по WebRTC, сервер только пересылает кадры
+// сигнализации call_signal (offer, answer, ice_candidate, hangup) одному участнику чата,
+// которому они адресованы. Кадры сигнализации нигде не сохраняются, а в историю чата
+// попадают системные сообщения о звонке: started, когда звонок начали, и ended с
+// длительностью или missed, когда он закончился. Их пишет сокет звонящего: он видит ответ
+// и отбой собеседника, а если сам закрывается посреди звонка, звонок тоже заканчивается.
+
+/// Наибольший размер payload кадра call_signal в байтах JSON, описание SDP бывает большим
+pub const MAX_SIGNAL_PAYLOAD_BYTES: usize = 16 * 1024;
+
+/// Сколько кадров call_signal сокет может отправить за секунду: кандидаты ICE идут пачками
+pub const SIGNALS_PER_SECOND: u32 = 50;
+
+/// Что случилось со звонком
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CallEventKind {
+    Started,
+    /// Звонок закончился, а ответа так и не было
+    Missed,
+    Ended,
+}
+
+impl CallEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CallEventKind::Started => "started",
+            CallEventKind::Missed => "missed",
+            CallEventKind::Ended => "ended",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "started" => Some(CallEventKind::Started),
+            "missed" => Some(CallEventKind::Missed),
+            "ended" => Some(CallEventKind::Ended),
+            _ => None,
+        }
+    }
+}
+
+/// Системное сообщение о звонке в истории чата
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CallEvent {
+    pub call_id: Uuid,
+    pub kind: CallEventKind,
+    /// Сколько длился разговор, есть только у ended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u32>,
+}
+
+/// Вид кадра сигнализации
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CallSignalKind {
+    Offer,
+    Answer,
+    IceCandidate,
+    Hangup,
+}
+
+struct ActiveCall {
+    chat_id: ChatId,
+    answered_at: Option<Instant>,
+}
+
+/// Звонки, начатые с сокета и еще не закончившиеся
+#[derive(Default)]
+pub struct ActiveCalls {
+    calls: HashMap<Uuid, ActiveCall>,
+}
+
+impl ActiveCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Запоминает начатый звонок, false - если звонок с таким id уже идет
+    pub fn start(&mut self, call_id: Uuid, chat_id: ChatId) -> bool {
+        if self.calls.contains_key(&call_id) {
+            return false;
+        }
+        self.calls.insert(
+            call_id,
+            ActiveCall {
+                chat_id,
+                answered_at: None,
+            },
+        );
+        true
+    }
+
+    pub fn is_active(&self, call_id: Uuid) -> bool {
+        self.calls.contains_key(&call_id)
+    }
+
+    /// Отмечает, что на звонок ответили, разговор считается с первого ответа
+    pub fn answer(&mut self, call_id: Uuid, now: Instant) {
+        if let Some(call) = self.calls.get_mut(&call_id) {
+            call.answered_at.get_or_insert(now);
+        }
+    }
+
+    /// Заканчивает звонок и возвращает его чат и сообщение для истории: ended
+    /// с длительностью разговора, если ответ был, иначе missed
+    pub fn finish(&mut self, call_id: Uuid, now: Instant) -> Option<(ChatId, CallEvent)> {
+        let call = self.calls.remove(&call_id)?;
+        let (kind, duration_secs) = match call.answered_at {
+            Some(answered_at) => (
+                CallEventKind::Ended,
+                Some(now.duration_since(answered_at).as_secs() as u32),
+            ),
+            None => (CallEventKind::Missed, None),
+        };
+        Some((
+            call.chat_id,
+            CallEvent {
+                call_id,
+                kind,
+                duration_secs,
+            },
+        ))
+    }
+
+    /// Заканчивает все звонки, например когда сокет закрывается
+    pub fn finish_all(&mut self, now: Instant) -> Vec<(ChatId, CallEvent)> {
+        let call_ids: Vec<Uuid> = self.calls.keys().copied().collect();
+        call_ids
+            .into_iter()
+            .filter_map(|call_id| self.finish(call_id, now))
+            .collect()
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::{LazyLock, Mutex};
+
+use chrono::Duration;
+use log::warn;
+
+// Гибридные логические часы
+//
+// Время сообщения - часть ключа кластеризации таблицы сообщений, так что по нему строится
+// порядок истории. Системные часы экземпляров сервиса могут расходиться и даже идти назад
+// после синхронизации NTP, и тогда ответ может оказаться в истории раньше сообщения,
+// на которое он отвечает. Часы выдают миллисекунды от начала эпохи: каждое следующее
+// значение строго больше предыдущего, а время сообщений с других экземпляров, увиденное
+// через брокер, подтягивает часы вперед. Логический счетчик свернут в миллисекунды:
+// при потоке больше тысячи сообщений в секунду часы ненадолго уходят вперед системных
+// и догоняются, когда поток спадает.
+
+/// Насколько время с другого экземпляра может опережать системные часы,
+/// прежде чем часы перестанут под него подстраиваться
+pub const MAX_CLOCK_DRIFT_SECS: i64 = 60;
+
+/// Часы экземпляра сервиса, ими размечаются все новые сообщения
+pub static CLOCK: LazyLock<HybridClock> =
+    LazyLock::new(|| HybridClock::new(Duration::seconds(MAX_CLOCK_DRIFT_SECS)));
+
+pub struct HybridClock {
+    /// Последнее выданное или увиденное время, миллисекунды от начала эпохи
+    last: Mutex<i64>,
+    max_drift: Duration,
+}
+
+impl HybridClock {
+    pub fn new(max_drift: Duration) -> Self {
+        Self {
+            last: Mutex::new(i64::MIN),
+            max_drift,
+        }
+    }
+
+    /// Время для нового сообщения по системным часам
+    pub fn now(&self) -> Duration {
+        self.tick(chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH)
+    }
+
+    /// Время для нового сообщения, если системные часы показывают physical
+    pub fn tick(&self, physical: Duration) -> Duration {
+        let mut last = self.last.lock().unwrap();
+        *last = physical.num_milliseconds().max(last.saturating_add(1));
+        Duration::milliseconds(*last)
+    }
+
+    /// Учитывает время сообщения с другого экземпляра, чтобы следующие сообщения
+    /// этого экземпляра оказались позже него
+    ///
+    /// Время, которое опережает системные часы больше чем на max_drift, не учитывается
+    pub fn observe(&self, remote: Duration) -> bool {
+        self.observe_at(remote, chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH)
+    }
+
+    /// То же, что observe, если системные часы показывают physical
+    pub fn observe_at(&self, remote: Duration, physical: Duration) -> bool {
+        if remote - physical > self.max_drift {
+            warn!(
+                "Ignoring timestamp {} ms ahead of the local clock",
+                (remote - physical).num_milliseconds()
+            );
+            return false;
+        }
+        let mut last = self.last.lock().unwrap();
+        *last = (*last).max(remote.num_milliseconds());
+        true
+    }
+}
@@ -0,0 +1,1145 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    error::Error,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    content::ContentKind,
+    database::data::{DeliveryMode, PostPolicy},
+    ids::ChatId,
+};
+use ipnet::IpNet;
+use log::{error, info, LevelFilter};
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Конфигурация сервиса
+//
+// Читается из json-файла, путь к которому берется из переменной окружения CHAT_CONFIG.
+// Если файла нет, то используются значения по умолчанию.
+//
+// Конфигурация делится на две части:
+// 1) Статическая - адреса баз, порты и т.д., читается один раз при старте
+// 2) Динамическая - лимиты, флаги, списки слов и уровень логов, может быть перечитана
+//    без перезапуска сервиса по SIGHUP или через /api/admin/reload-config
+
+pub const CONFIG_PATH_ENV: &str = "CHAT_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+/// Стратегия репликации пространства ключей
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "class")]
+pub enum Replication {
+    SimpleStrategy {
+        replication_factor: u32,
+    },
+    /// Для кластеров из нескольких дата-центров: фактор репликации задается для каждого
+    /// дата-центра в datacenters, а replication_factor - для всех остальных
+    NetworkTopologyStrategy {
+        #[serde(default)]
+        replication_factor: Option<u32>,
+        #[serde(default)]
+        datacenters: BTreeMap<String, u32>,
+    },
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication::NetworkTopologyStrategy {
+            replication_factor: Some(1),
+            datacenters: BTreeMap::new(),
+        }
+    }
+}
+
+impl Replication {
+    /// Настройки репликации в синтаксисе CQL для CREATE KEYSPACE
+    pub fn to_cql(&self) -> String {
+        match self {
+            Replication::SimpleStrategy { replication_factor } => {
+                format!("{{'class': 'SimpleStrategy', 'replication_factor': {replication_factor}}}")
+            }
+            Replication::NetworkTopologyStrategy {
+                replication_factor,
+                datacenters,
+            } => {
+                let mut options = vec!["'class': 'NetworkTopologyStrategy'".to_string()];
+                if let Some(factor) = replication_factor {
+                    options.push(format!("'replication_factor': {factor}"));
+                }
+                for (datacenter, factor) in datacenters {
+                    options.push(format!("'{}': {factor}", datacenter.replace('\'', "''")));
+                }
+                format!("{{{}}}", options.join(", "))
+            }
+        }
+    }
+}
+
+/// Как хранятся сообщения чатов: уплотнение по окнам времени и срок хранения
+///
+/// Сообщения пишутся по времени и почти не меняются, поэтому TimeWindowCompactionStrategy
+/// уплотняет только свежее окно, а истекшие окна целиком удаляются без чтения надгробий.
+/// Раздел чата при этом лежит во всех окнах, почему это не мешает чтению истории, описано
+/// у таблицы messages в create_schema
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageStorage {
+    /// Размер окна уплотнения в днях
+    pub compaction_window_days: u32,
+    /// Сколько секунд хранятся сообщения чатов без своего срока, 0 - бессрочно
+    pub default_ttl_secs: u32,
+}
+
+impl Default for MessageStorage {
+    fn default() -> Self {
+        Self {
+            compaction_window_days: 1,
+            default_ttl_secs: 0,
+        }
+    }
+}
+
+impl MessageStorage {
+    /// Настройки таблицы сообщений в синтаксисе CQL для WITH в CREATE и ALTER TABLE
+    pub fn to_cql(&self) -> String {
+        format!(
+            "compaction = {{'class': 'TimeWindowCompactionStrategy', \
+            'compaction_window_unit': 'DAYS', 'compaction_window_size': {}}} \
+            AND default_time_to_live = {}",
+            self.compaction_window_days.max(1),
+            self.default_ttl_secs
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub host: String,
+    pub port: u16,
+    /// Пространство ключей, в котором лежат таблицы чата
+    pub keyspace: String,
+    pub replication: Replication,
+    /// Если схема базы не совпадает с ожидаемой, то создать недостающее при старте,
+    /// а не отказываться запускаться
+    pub auto_migrate: bool,
+    /// Сколько участников может быть в чате, если у чата нет своего ограничения
+    pub max_chat_members: u32,
+    pub messages: MessageStorage,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: "scylla-database".into(),
+            port: 9042,
+            keyspace: "chat".into(),
+            replication: Replication::default(),
+            auto_migrate: true,
+            max_chat_members: 10_000,
+            messages: MessageStorage::default(),
+        }
+    }
+}
+
+/// Как устроен Redis: один узел, главный узел под присмотром Sentinel или кластер
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum RedisTopology {
+    /// Один узел по host и port
+    #[default]
+    Standalone,
+    /// Адрес главного узла master_name спрашивается у Sentinel (host:port) при каждом
+    /// подключении, так что после переключения сервис находит новый главный узел
+    Sentinel {
+        master_name: String,
+        sentinels: Vec<String>,
+    },
+    /// Узлы кластера (host:port), с которых начинается знакомство с ним, остальные
+    /// узлы сервис узнает из перенаправлений
+    Cluster { nodes: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub host: String,
+    pub port: u16,
+    /// Без topology host и port указывают на единственный узел
+    pub topology: RedisTopology,
+    /// Префикс всех каналов и ключей, например chat:prod:
+    ///
+    /// Позволяет нескольким окружениям работать с одним Redis, не видя сообщений друг друга
+    pub namespace: String,
+    /// Сколько последних управляющих сообщений (подписки, блокировки, отозванные сессии)
+    /// хранится в журнале, чтобы после переподключения дочитать пропущенные
+    pub control_log_max_len: usize,
+    /// Первая и самая долгая пауза между попытками переподключиться к Redis
+    pub reconnect_min_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            host: "redis-broker".into(),
+            port: 6379,
+            topology: RedisTopology::default(),
+            namespace: String::new(),
+            control_log_max_len: 1000,
+            reconnect_min_delay_ms: 200,
+            reconnect_max_delay_ms: 10_000,
+        }
+    }
+}
+
+impl RedisConfig {
+    pub fn url(&self) -> String {
+        format!("redis://{}:{}", self.host, self.port)
+    }
+
+    /// Имя канала или ключа с префиксом окружения
+    pub fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.namespace)
+    }
+
+    /// Пауза перед попыткой переподключения номер attempt (с нуля): удваивается
+    /// с каждой неудачей, но не больше reconnect_max_delay_ms
+    pub fn reconnect_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .reconnect_min_delay_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.reconnect_max_delay_ms);
+        Duration::from_millis(delay)
+    }
+}
+
+/// Периодическая чистка брошенных чатов
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PurgeConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Чаты моложе этого возраста не удаляются, даже если выглядят брошенными
+    pub min_age_secs: u64,
+}
+
+impl Default for PurgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 3600,
+            min_age_secs: 3600,
+        }
+    }
+}
+
+/// Периодическая проверка связей между пользователями и чатами
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepairConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Чинить найденные расхождения, а не только сообщать о них
+    pub fix: bool,
+}
+
+impl Default for RepairConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 86400,
+            fix: false,
+        }
+    }
+}
+
+/// Закрепленные сообщения
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinsConfig {
+    /// Сколько сообщений может быть закреплено в чате одновременно,
+    /// при закреплении сверх этого самое старое закрепление снимается
+    pub max_per_chat: usize,
+    /// Как часто снимаются закрепления с истекшим сроком
+    pub expiry_interval_secs: u64,
+}
+
+impl Default for PinsConfig {
+    fn default() -> Self {
+        Self {
+            max_per_chat: 10,
+            expiry_interval_secs: 60,
+        }
+    }
+}
+
+/// Гарантии доставки сообщений
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    /// Режим для чатов, у которых он не задан явно
+    pub default_mode: DeliveryMode,
+    /// Сколько последних сообщений хранит поток чата с доставкой at_least_once
+    pub stream_max_len: usize,
+    /// Сколько секунд после последнего сообщения хранятся поток чата, подтверждения
+    /// и список удаленных сообщений
+    pub stream_ttl_secs: u64,
+    /// Сколько неподтвержденных сообщений одного чата досылается клиенту при подключении
+    pub replay_limit: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            default_mode: DeliveryMode::AtMostOnce,
+            stream_max_len: 10000,
+            stream_ttl_secs: 7 * 24 * 3600,
+            replay_limit: 100,
+        }
+    }
+}
+
+/// Присутствие участников в сети
+///
+/// Экземпляры отмечают в Redis пользователей, у которых на них открыты сокеты, и продлевают
+/// отметку каждые ttl_secs / 3 секунд: отметки упавшего экземпляра истекают сами. События
+/// member_online и member_offline рассылаются только в чаты не больше max_chat_size
+/// участников, и только для них /api/chat/online отдает список участников в сети
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    pub enabled: bool,
+    pub max_chat_size: usize,
+    pub ttl_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_chat_size: 100,
+            ttl_secs: 60,
+        }
+    }
+}
+
+/// Поставщик внешнего контента одного вида
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContentProviderConfig {
+    pub kind: ContentKind,
+    /// Формат API поставщика, пока поддерживается только giphy
+    pub provider: String,
+    /// Адрес API, http:// или https://
+    pub base_url: String,
+    /// Переменная окружения, из которой берется ключ API
+    pub api_key_env: String,
+    /// Возрастной рейтинг контента
+    pub rating: String,
+    pub timeout_secs: u64,
+}
+
+impl Default for ContentProviderConfig {
+    fn default() -> Self {
+        Self {
+            kind: ContentKind::Gif,
+            provider: "giphy".into(),
+            base_url: "http://giphy-proxy".into(),
+            api_key_env: "GIPHY_API_KEY".into(),
+            rating: "g".into(),
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// Учет использования API по пользователям
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub enabled: bool,
+    /// Сколько суток хранятся счетчики и за сколько суток их можно запросить
+    pub retention_days: u32,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 30,
+        }
+    }
+}
+
+/// Ссылки на историю чата, без ключа подписи в окружении они выключены
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareConfig {
+    /// Переменная окружения с ключом подписи ссылок
+    pub secret_key_env: String,
+    /// Срок ссылки, если клиент не попросил другого, и самый долгий срок
+    pub default_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+    /// Сколько последних сообщений отрезка отдается по ссылке
+    pub max_messages: usize,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            secret_key_env: "CHAT_SHARE_KEY".into(),
+            default_ttl_secs: 24 * 60 * 60,
+            max_ttl_secs: 7 * 24 * 60 * 60,
+            max_messages: 200,
+        }
+    }
+}
+
+/// Доставка сообщений ботам на их вебхуки
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BotsConfig {
+    /// Сколько ждать ответа вебхука
+    pub timeout_secs: u64,
+    /// Сколько раз пытаться доставить сообщение, считая первую попытку
+    pub max_attempts: u32,
+    /// Пауза перед второй попыткой, дальше она удваивается
+    pub retry_delay_ms: u64,
+    /// Как часто перечитывать из базы ботов и их чаты
+    pub refresh_secs: u64,
+}
+
+impl Default for BotsConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 5,
+            max_attempts: 5,
+            retry_delay_ms: 1000,
+            refresh_secs: 30,
+        }
+    }
+}
+
+/// S3-совместимое хранилище вложений (S3, MinIO), без endpoint вложения выключены
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Адрес хранилища, http:// или https://
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Переменные окружения с ключами доступа
+    pub access_key_env: String,
+    pub secret_key_env: String,
+    /// Откуда клиенты скачивают файлы, например CDN перед бакетом;
+    /// без него ссылки ведут прямо в хранилище
+    pub public_base_url: Option<String>,
+    /// Самый большой файл, который можно загрузить
+    pub max_attachment_bytes: usize,
+    /// Время на загрузку одного файла, файл идет в хранилище по мере приема от клиента
+    pub timeout_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: "attachments".into(),
+            region: "us-east-1".into(),
+            access_key_env: "STORAGE_ACCESS_KEY".into(),
+            secret_key_env: "STORAGE_SECRET_KEY".into(),
+            public_base_url: None,
+            max_attachment_bytes: 10 * 1024 * 1024,
+            timeout_secs: 30,
+        }
+    }
+}
+
+/// Поиск внешнего контента, без поставщиков поиск выключен
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContentConfig {
+    pub providers: Vec<ContentProviderConfig>,
+    /// Сколько результатов можно получить за один поиск
+    pub max_results: usize,
+}
+
+impl Default for ContentConfig {
+    fn default() -> Self {
+        Self {
+            providers: vec![],
+            max_results: 25,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Сколько сообщений пользователь может отправить за минуту
+    pub messages_per_minute: u32,
+    /// Сколько чатов пользователь может создать за час
+    pub chats_per_hour: u32,
+    /// Сколько раз в минуту пользователь может искать внешний контент
+    pub content_searches_per_minute: u32,
+    /// Сколько кадров broadcast_ephemeral сокет может отправить за секунду, лишние
+    /// отбрасываются
+    pub ephemeral_per_second: u32,
+    /// Ужесточение лимитов сообщений и чатов для новых аккаунтов
+    pub new_accounts: NewAccountLimits,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_minute: 60,
+            chats_per_hour: 20,
+            content_searches_per_minute: 30,
+            ephemeral_per_second: 20,
+            new_accounts: NewAccountLimits::default(),
+        }
+    }
+}
+
+impl RateLimits {
+    /// Сколько сообщений за минуту может отправить аккаунт, созданный account_age назад
+    pub fn messages_per_minute_for(&self, account_age: chrono::Duration) -> u32 {
+        self.new_accounts
+            .scale(self.messages_per_minute, account_age)
+    }
+
+    /// Сколько чатов за час может создать аккаунт, созданный account_age назад
+    pub fn chats_per_hour_for(&self, account_age: chrono::Duration) -> u32 {
+        self.new_accounts.scale(self.chats_per_hour, account_age)
+    }
+}
+
+/// Лимиты для новых аккаунтов, чтобы волны спам-аккаунтов не могли сразу рассылать
+/// сообщения и создавать чаты в полную силу
+///
+/// У только что созданного аккаунта лимиты составляют initial_share от обычных и равномерно
+/// растут до обычных за probation_secs секунд
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NewAccountLimits {
+    pub probation_secs: u64,
+    pub initial_share: f64,
+}
+
+impl Default for NewAccountLimits {
+    fn default() -> Self {
+        Self {
+            probation_secs: 7 * 24 * 3600,
+            initial_share: 0.1,
+        }
+    }
+}
+
+impl NewAccountLimits {
+    /// Лимит для аккаунта возраста account_age, если обычный лимит равен limit
+    ///
+    /// Ненулевой лимит не опускается ниже единицы, чтобы новый аккаунт мог хоть что-то сделать
+    pub fn scale(&self, limit: u32, account_age: chrono::Duration) -> u32 {
+        let age_secs = account_age.num_seconds().max(0) as u64;
+        if age_secs >= self.probation_secs {
+            return limit;
+        }
+        let initial_share = self.initial_share.clamp(0.0, 1.0);
+        let share =
+            initial_share + (1.0 - initial_share) * age_secs as f64 / self.probation_secs as f64;
+        ((limit as f64 * share).ceil() as u32).clamp(limit.min(1), limit)
+    }
+}
+
+/// Блокировка перебора: после max_failures неудачных попыток авторизации или подключения
+/// к вебсокету за window_secs секунд адрес или пользователь блокируется на lockout_secs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthLockout {
+    pub max_failures: u64,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for AuthLockout {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            window_secs: 60,
+            lockout_secs: 300,
+        }
+    }
+}
+
+/// Медленные клиенты: если очередь сообщений сокета переполняется (больше mailbox_capacity
+/// сообщений) и не разгружается grace_secs секунд, клиент получает предупреждение,
+/// а при disconnect = true отключается
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlowConsumer {
+    pub mailbox_capacity: usize,
+    pub grace_secs: u64,
+    pub disconnect: bool,
+}
+
+impl Default for SlowConsumer {
+    fn default() -> Self {
+        Self {
+            mailbox_capacity: 64,
+            grace_secs: 30,
+            disconnect: false,
+        }
+    }
+}
+
+/// Ограничения кадров вебсокета, применяются к новым подключениям
+///
+/// Кадр больше max_frame_bytes не читается в память: клиент получает ошибку, и сокет
+/// закрывается с кодом 1009
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebsocketLimits {
+    pub max_frame_bytes: usize,
+}
+
+impl Default for WebsocketLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Истечение токена вебсокета: за notice_secs секунд до истечения клиент, заявивший
+/// reauth_required, получает предупреждение и может заранее получить новый токен и
+/// переподключиться, а когда токен истекает, сокет закрывается, если close_on_expiry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Reauth {
+    pub notice_secs: u64,
+    pub close_on_expiry: bool,
+}
+
+impl Default for Reauth {
+    fn default() -> Self {
+        Self {
+            notice_secs: 300,
+            close_on_expiry: true,
+        }
+    }
+}
+
+/// Что делать с новым вебсокетом пользователя, у которого уже открыто max_sessions сокетов
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// Пускать все сокеты, max_sessions не учитывается
+    #[default]
+    AllowAll,
+    /// Закрыть самые старые сокеты пользователя
+    KickOldest,
+    /// Закрыть новый сокет
+    DenyNew,
+}
+
+/// Одновременные вебсокеты одного пользователя
+///
+/// Брокер считает только сокеты своего экземпляра сервиса, так что сокеты пользователя
+/// на разных экземплярах друг другу не мешают
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateLogin {
+    pub policy: DuplicateLoginPolicy,
+    pub max_sessions: usize,
+}
+
+impl Default for DuplicateLogin {
+    fn default() -> Self {
+        Self {
+            policy: DuplicateLoginPolicy::AllowAll,
+            max_sessions: 1,
+        }
+    }
+}
+
+/// Решение о новом сокете пользователя
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Пустить сокет, закрыв evict самых старых
+    Accept {
+        evict: usize,
+    },
+    Deny,
+}
+
+impl DuplicateLogin {
+    /// Пускать ли новый сокет, если у пользователя уже открыто open сокетов
+    pub fn admit(&self, open: usize) -> Admission {
+        let max_sessions = self.max_sessions.max(1);
+        match self.policy {
+            DuplicateLoginPolicy::AllowAll => Admission::Accept { evict: 0 },
+            _ if open < max_sessions => Admission::Accept { evict: 0 },
+            DuplicateLoginPolicy::KickOldest => Admission::Accept {
+                evict: open + 1 - max_sessions,
+            },
+            DuplicateLoginPolicy::DenyNew => Admission::Deny,
+        }
+    }
+}
+
+/// Привязка сессии вебсокета к адресу и User-Agent клиента
+///
+/// Первое подключение с токеном запоминает адрес и User-Agent, а подключение с тем же
+/// токеном, но с другими данными, считается кражей токена: сессия завершается, и токен
+/// не принимается, пока привязка не истечет
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionBinding {
+    pub enabled: bool,
+    pub bind_ip: bool,
+    pub bind_user_agent: bool,
+    /// Сколько секунд хранится привязка после последнего подключения
+    pub ttl_secs: u64,
+}
+
+impl Default for SessionBinding {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_ip: true,
+            bind_user_agent: true,
+            ttl_secs: 86400,
+        }
+    }
+}
+
+/// Шаблон чата для создания комнат по сценарию, например "комната инцидента"
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatTemplate {
+    /// Имя чата с подстановками: {date} и {time} - текущие дата и время UTC,
+    /// остальные {имя} берутся из параметров запроса
+    pub name_pattern: String,
+    /// Кто попадает в чат вместе с создателем
+    pub members: Vec<i64>,
+    /// Сообщение от создателя, которое отправляется и закрепляется сразу после создания
+    pub pinned_message: Option<String>,
+    pub post_policy: PostPolicy,
+}
+
+/// Ограничения на имена пользователей и чатов
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NameRules {
+    /// Длина в символах после обрезки пробелов
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Обрезать пробелы по краям перед проверкой и сохранением
+    pub trim: bool,
+    /// Если задано, то кроме букв, цифр и пробелов разрешены только эти символы,
+    /// иначе разрешено все, кроме управляющих символов
+    pub allowed_symbols: Option<String>,
+}
+
+impl NameRules {
+    fn with_max_length(max_length: usize) -> Self {
+        Self {
+            min_length: 1,
+            max_length,
+            trim: true,
+            allowed_symbols: None,
+        }
+    }
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        Self::with_max_length(64)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub user_name: NameRules,
+    pub chat_name: NameRules,
+    pub message: MessageRules,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            user_name: NameRules::with_max_length(64),
+            chat_name: NameRules::with_max_length(128),
+            message: MessageRules::default(),
+        }
+    }
+}
+
+/// Ограничения на текст и вложения сообщений
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageRules {
+    /// Длина в символах
+    pub max_length: usize,
+    /// Сколько вложений может быть у одного сообщения
+    pub max_attachments: usize,
+}
+
+impl Default for MessageRules {
+    fn default() -> Self {
+        Self {
+            max_length: 4000,
+            max_attachments: 10,
+        }
+    }
+}
+
+impl MessageRules {
+    /// Сколько вложений на самом деле можно прикрепить: больше, чем принимает база,
+    /// не разрешает никакая конфигурация
+    pub fn attachment_limit(&self) -> usize {
+        self.max_attachments
+            .min(crate::database::MAX_ATTACHMENTS_PER_MESSAGE)
+    }
+}
+
+/// Сетевые ограничения доступа
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Прокси, которым разрешено сообщать реальный адрес клиента через
+    /// Forwarded или X-Forwarded-For
+    pub trusted_proxies: Vec<IpNet>,
+    /// Если список не пуст, то пускаем только клиентов из этих подсетей
+    pub allow: Vec<IpNet>,
+    /// Клиенты из этих подсетей не пускаются никогда
+    pub deny: Vec<IpNet>,
+}
+
+impl NetworkConfig {
+    pub fn is_trusted_proxy(&self, ip: &std::net::IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn is_allowed(&self, ip: &std::net::IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Ограничение размера страниц истории
+///
+/// Если сообщения чата в среднем крупные, то страница истории уменьшается так, чтобы
+/// ответ занимал не больше max_page_bytes, но в ней остается хотя бы min_page_size
+/// сообщений (если их столько запросили)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryLimits {
+    pub max_page_bytes: u64,
+    pub min_page_size: usize,
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        Self {
+            max_page_bytes: 512 * 1024,
+            min_page_size: 10,
+        }
+    }
+}
+
+impl HistoryLimits {
+    /// Сколько сообщений отдать, если клиент запросил requested, а средний размер
+    /// сообщения в чате - average_message_bytes (None - пока неизвестен)
+    pub fn page_size(&self, requested: usize, average_message_bytes: Option<u64>) -> usize {
+        let Some(average) = average_message_bytes.filter(|&average| average > 0) else {
+            return requested;
+        };
+        let fits = usize::try_from(self.max_page_bytes / average).unwrap_or(usize::MAX);
+        requested.min(fits.max(self.min_page_size))
+    }
+}
+
+/// Режим только для чтения
+///
+/// Включается вручную (enabled) или сам, когда failure_threshold записей сообщений подряд
+/// не удались. Сам включенный режим держится cooldown_secs, затем сервис снова пробует
+/// писать: если запись опять не удалась, режим сразу включается заново
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadOnlyConfig {
+    pub enabled: bool,
+    pub failure_threshold: u32,
+    pub cooldown_secs: u64,
+}
+
+impl Default for ReadOnlyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            failure_threshold: 5,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+/// Сброс нагрузки
+///
+/// Экземпляр считается перегруженным, когда брокер держит больше max_broker_queue
+/// неразосланных сообщений или (при shed_when_read_only) база не принимает записи.
+/// Тогда новые подключения к вебсокету получают 503, а подключенные клиенты - подсказку
+/// переподключаться не раньше чем через retry_after_secs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadShedding {
+    pub enabled: bool,
+    pub max_broker_queue: usize,
+    pub shed_when_read_only: bool,
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadShedding {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_broker_queue: 10_000,
+            shed_when_read_only: true,
+            retry_after_secs: 15,
+        }
+    }
+}
+
+/// Настройки, которые можно менять без перезапуска сервиса
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DynamicConfig {
+    pub log_level: String,
+    pub rate_limits: RateLimits,
+    pub auth_lockout: AuthLockout,
+    pub slow_consumer: SlowConsumer,
+    pub websocket: WebsocketLimits,
+    pub session_binding: SessionBinding,
+    pub reauth: Reauth,
+    pub duplicate_login: DuplicateLogin,
+    pub network: NetworkConfig,
+    pub feature_flags: HashMap<String, bool>,
+    pub moderation_wordlist: Vec<String>,
+    /// Пользователи, которым доступны эндпоинты /api/admin
+    pub admins: Vec<i64>,
+    /// Если в чате больше участников, то /api/chat/info не отдает их список,
+    /// и его нужно получать постранично через /api/chat/members
+    pub max_inline_members: usize,
+    pub validation: ValidationConfig,
+    /// Шаблоны для /api/chat/from-template по их id
+    pub chat_templates: HashMap<String, ChatTemplate>,
+    /// Чаты (например, объявления и поддержка), в которые сразу добавляется каждый
+    /// новый пользователь
+    pub default_chats: Vec<ChatId>,
+    /// Сколько контактов может быть у одного пользователя
+    pub max_contacts: usize,
+    pub history: HistoryLimits,
+    pub read_only: ReadOnlyConfig,
+    pub load_shedding: LoadShedding,
+}
+
+impl Default for DynamicConfig {
+    fn default() -> Self {
+        Self {
+            log_level: "debug".into(),
+            rate_limits: RateLimits::default(),
+            auth_lockout: AuthLockout::default(),
+            slow_consumer: SlowConsumer::default(),
+            websocket: WebsocketLimits::default(),
+            session_binding: SessionBinding::default(),
+            reauth: Reauth::default(),
+            duplicate_login: DuplicateLogin::default(),
+            network: NetworkConfig::default(),
+            feature_flags: HashMap::new(),
+            moderation_wordlist: vec![],
+            admins: vec![],
+            max_inline_members: 1000,
+            validation: ValidationConfig::default(),
+            chat_templates: HashMap::new(),
+            default_chats: vec![],
+            max_contacts: 1000,
+            history: HistoryLimits::default(),
+            read_only: ReadOnlyConfig::default(),
+            load_shedding: LoadShedding::default(),
+        }
+    }
+}
+
+impl DynamicConfig {
+    pub fn is_feature_enabled(&self, feature: &str) -> bool {
+        self.feature_flags.get(feature).copied().unwrap_or(false)
+    }
+
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.admins.contains(&user_id)
+    }
+
+    /// Уровень логов из log_level
+    pub fn log_filter(&self) -> Result<LevelFilter, String> {
+        LevelFilter::from_str(&self.log_level)
+            .map_err(|_| format!("Invalid log_level {:?}", self.log_level))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub delivery: DeliveryConfig,
+    pub presence: PresenceConfig,
+    pub purge: PurgeConfig,
+    pub repair: RepairConfig,
+    pub content: ContentConfig,
+    pub pins: PinsConfig,
+    pub storage: StorageConfig,
+    pub usage: UsageConfig,
+    pub share: ShareConfig,
+    pub bots: BotsConfig,
+    #[serde(flatten)]
+    pub dynamic: DynamicConfig,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            info!("Config file {} not found, using defaults", path.display());
+            return Ok(Config::default());
+        }
+        let raw = std::fs::read_to_string(path)?;
+        let config: Config = serde_json::from_str(&raw)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Проверяет значения, которые не проверяет разбор JSON
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.dynamic.log_filter()?;
+        Ok(())
+    }
+
+    /// Какие необязательные подсистемы включены при текущих динамических настройках
+    pub fn capabilities(&self, dynamic: &DynamicConfig) -> Capabilities {
+        Capabilities {
+            reactions: dynamic.is_feature_enabled(REACTIONS_FEATURE),
+            e2ee: dynamic.is_feature_enabled(E2EE_FEATURE),
+            attachments: !self.storage.endpoint.is_empty(),
+            search: !self.content.providers.is_empty(),
+            push: dynamic.is_feature_enabled(PUSH_FEATURE),
+        }
+    }
+}
+
+/// Флаги подсистем, которые сервер не поднимает сам и которые включает развертывание
+pub const REACTIONS_FEATURE: &str = "reactions";
+pub const E2EE_FEATURE: &str = "e2ee";
+pub const PUSH_FEATURE: &str = "push";
+
+/// Необязательные подсистемы развертывания, по ним клиент решает, что показывать
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub reactions: bool,
+    /// Сквозное шифрование сообщений
+    pub e2ee: bool,
+    /// Настроено хранилище вложений
+    pub attachments: bool,
+    /// Настроен хотя бы один поставщик внешнего контента для поиска
+    pub search: bool,
+    pub push: bool,
+}
+
+/// Общий доступ к конфигурации для обработчиков и акторов
+///
+/// Динамическая часть хранится за RwLock и подменяется целиком при перезагрузке,
+/// так что читатели всегда видят согласованный снимок настроек
+#[derive(Clone)]
+pub struct ConfigHandle {
+    path: PathBuf,
+    static_config: Arc<Config>,
+    dynamic: Arc<RwLock<Arc<DynamicConfig>>>,
+}
+
+impl ConfigHandle {
+    /// Загружает конфигурацию по пути из CHAT_CONFIG
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let path = env::var(CONFIG_PATH_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.into());
+        Self::load_from(PathBuf::from(path))
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let config = Config::from_file(&path)?;
+        Ok(Self::new(path, config))
+    }
+
+    pub fn new(path: PathBuf, config: Config) -> Self {
+        let dynamic = Arc::new(RwLock::new(Arc::new(config.dynamic.clone())));
+        let handle = Self {
+            path,
+            static_config: Arc::new(config),
+            dynamic,
+        };
+        handle.apply_log_level();
+        handle
+    }
+
+    /// Статическая часть конфигурации, прочитанная при старте
+    pub fn static_config(&self) -> &Config {
+        &self.static_config
+    }
+
+    /// Текущий снимок динамических настроек
+    pub fn current(&self) -> Arc<DynamicConfig> {
+        self.dynamic
+            .read()
+            .expect("Config lock is poisoned")
+            .clone()
+    }
+
+    /// Перечитывает файл конфигурации и подменяет динамические настройки
+    ///
+    /// Статическая часть при этом не меняется, для нее нужен перезапуск
+    pub fn reload(&self) -> Result<Arc<DynamicConfig>, Box<dyn Error>> {
+        let config = Config::from_file(&self.path)?;
+        let new_dynamic = Arc::new(config.dynamic);
+        *self.dynamic.write().expect("Config lock is poisoned") = new_dynamic.clone();
+        self.apply_log_level();
+        info!("Configuration reloaded from {}", self.path.display());
+        Ok(new_dynamic)
+    }
+
+    fn apply_log_level(&self) {
+        match self.current().log_filter() {
+            Ok(level) => log::set_max_level(level),
+            Err(e) => error!("{e}, keeping log level {}", log::max_level()),
+        }
+    }
+}
+
+/// Перечитывает конфигурацию каждый раз, когда процесс получает SIGHUP
+pub async fn reload_on_sighup(handle: ConfigHandle) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Cannot listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = handle.reload() {
+            error!("Configuration reload failed: {e}");
+        }
+    }
+}
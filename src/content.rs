@@ -0,0 +1,252 @@
+use std::{collections::HashMap, env, fmt, sync::Arc, time::Duration};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ContentConfig, ContentProviderConfig};
+pub use crate::http_client::HttpEndpoint;
+use crate::http_client::HttpError;
+
+// Поиск внешнего контента (гифки, стикеры)
+//
+// Клиенты не ходят к внешним сервисам сами: поиск проксируется через сервис, так что ключи API
+// не покидают сервер, а частоту запросов можно ограничить. Каждый вид контента обслуживает
+// свой поставщик, новые поставщики подключаются через трейт ContentProvider.
+//
+// Результаты отдаются описаниями, которые клиент может отправить в чат: пока у сообщений
+// нет вложений, в текст сообщения кладется url.
+
+/// Вид внешнего контента
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentKind {
+    Gif,
+    Sticker,
+}
+
+/// Описание найденного контента, которое можно встроить в сообщение
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContentDescriptor {
+    pub provider: String,
+    pub kind: ContentKind,
+    /// id контента у поставщика
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    /// Уменьшенная версия для предпросмотра
+    pub preview_url: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ContentError {
+    /// Для этого вида контента нет поставщика
+    NotConfigured(ContentKind),
+    /// Поставщик не ответил или ответил ошибкой
+    Provider(String),
+}
+
+impl fmt::Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentError::NotConfigured(kind) => {
+                write!(f, "No content provider is configured for {kind:?}")
+            }
+            ContentError::Provider(e) => write!(f, "Content provider error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+impl From<HttpError> for ContentError {
+    fn from(e: HttpError) -> Self {
+        ContentError::Provider(e.0)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait ContentProvider: Send + Sync {
+    /// Имя поставщика, попадает в описания контента
+    fn name(&self) -> &str;
+    async fn search(
+        &self,
+        kind: ContentKind,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<ContentDescriptor>, ContentError>;
+}
+
+/// Поставщики по видам контента
+#[derive(Clone, Default)]
+pub struct ContentProviders {
+    providers: HashMap<ContentKind, Arc<dyn ContentProvider>>,
+    max_results: usize,
+}
+
+impl ContentProviders {
+    pub fn new(max_results: usize) -> Self {
+        Self {
+            providers: HashMap::new(),
+            max_results,
+        }
+    }
+
+    /// Поднимает поставщиков из конфигурации
+    ///
+    /// Поставщик с ошибкой в настройках (например, без ключа API) пропускается,
+    /// чтобы из-за него не падал весь сервис
+    pub fn from_config(config: &ContentConfig) -> Self {
+        let mut providers = Self::new(config.max_results);
+        for provider_config in &config.providers {
+            match provider_config.provider.as_str() {
+                "giphy" => match GiphyProvider::from_config(provider_config) {
+                    Ok(provider) => {
+                        info!("Content provider giphy serves {:?}", provider_config.kind);
+                        providers.register(provider_config.kind, Arc::new(provider));
+                    }
+                    Err(e) => error!("Cannot set up content provider: {e}"),
+                },
+                other => error!("Unknown content provider {other}"),
+            }
+        }
+        providers
+    }
+
+    pub fn register(&mut self, kind: ContentKind, provider: Arc<dyn ContentProvider>) {
+        self.providers.insert(kind, provider);
+    }
+
+    /// Ищет контент вида kind, не больше max_results результатов
+    pub async fn search(
+        &self,
+        kind: ContentKind,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<ContentDescriptor>, ContentError> {
+        let provider = self
+            .providers
+            .get(&kind)
+            .ok_or(ContentError::NotConfigured(kind))?;
+        let limit = limit.clamp(1, self.max_results.max(1));
+        let mut results = provider.search(kind, query, limit).await?;
+        results.truncate(limit);
+        Ok(results)
+    }
+}
+
+/// Поставщик с API в формате Giphy
+pub struct GiphyProvider {
+    endpoint: HttpEndpoint,
+    api_key: String,
+    rating: String,
+    timeout: Duration,
+}
+
+impl GiphyProvider {
+    pub fn new(endpoint: HttpEndpoint, api_key: String, rating: String, timeout: Duration) -> Self {
+        Self {
+            endpoint,
+            api_key,
+            rating,
+            timeout,
+        }
+    }
+
+    /// Ключ API берется из переменной окружения api_key_env, а не из файла конфигурации
+    pub fn from_config(config: &ContentProviderConfig) -> Result<Self, ContentError> {
+        let api_key = env::var(&config.api_key_env)
+            .map_err(|_| ContentError::Provider(format!("{} is not set", config.api_key_env)))?;
+        Ok(Self::new(
+            HttpEndpoint::parse(&config.base_url)?,
+            api_key,
+            config.rating.clone(),
+            Duration::from_secs(config.timeout_secs),
+        ))
+    }
+
+    /// Путь запроса поиска
+    pub fn search_path(&self, kind: ContentKind, query: &str, limit: usize) -> String {
+        let collection = match kind {
+            ContentKind::Gif => "gifs",
+            ContentKind::Sticker => "stickers",
+        };
+        format!(
+            "/v1/{collection}/search?api_key={}&q={}&limit={limit}&rating={}",
+            urlencoding::encode(&self.api_key),
+            urlencoding::encode(query),
+            urlencoding::encode(&self.rating),
+        )
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl ContentProvider for GiphyProvider {
+    fn name(&self) -> &str {
+        "giphy"
+    }
+
+    async fn search(
+        &self,
+        kind: ContentKind,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<ContentDescriptor>, ContentError> {
+        let body = self
+            .endpoint
+            .get(&self.search_path(kind, query, limit), self.timeout)
+            .await?;
+        parse_giphy_response(self.name(), kind, &body)
+    }
+}
+
+#[derive(Deserialize)]
+struct GiphyResponse {
+    data: Vec<GiphyItem>,
+}
+
+#[derive(Deserialize)]
+struct GiphyItem {
+    id: String,
+    #[serde(default)]
+    title: String,
+    images: HashMap<String, GiphyImage>,
+}
+
+#[derive(Deserialize)]
+struct GiphyImage {
+    url: Option<String>,
+    width: Option<String>,
+    height: Option<String>,
+}
+
+/// Переводит ответ поиска Giphy в описания контента, элементы без картинки пропускаются
+pub fn parse_giphy_response(
+    provider: &str,
+    kind: ContentKind,
+    body: &str,
+) -> Result<Vec<ContentDescriptor>, ContentError> {
+    let response: GiphyResponse =
+        serde_json::from_str(body).map_err(|e| ContentError::Provider(e.to_string()))?;
+    Ok(response
+        .data
+        .into_iter()
+        .filter_map(|item| {
+            let original = item.images.get("original")?;
+            Some(ContentDescriptor {
+                provider: provider.into(),
+                kind,
+                url: original.url.clone()?,
+                width: original.width.as_deref().and_then(|w| w.parse().ok()),
+                height: original.height.as_deref().and_then(|h| h.parse().ok()),
+                preview_url: item
+                    .images
+                    .get("fixed_width_small")
+                    .and_then(|image| image.url.clone()),
+                id: item.id,
+                title: item.title,
+            })
+        })
+        .collect())
+}
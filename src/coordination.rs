@@ -0,0 +1,169 @@
+use std::{error::Error, future::Future, time::Duration};
+
+use log::{debug, error};
+use redis::{RedisResult, Script};
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
+
+use crate::{
+    config::RedisConfig,
+    redis_topology::{RedisConnection, RedisConnector},
+};
+
+// Координация нескольких экземпляров сервиса
+//
+// Фоновые задачи (чистка старых сообщений, дайджесты и т.д.) должны выполняться только на
+// одном экземпляре сервиса. Для этого экземпляры выбирают лидера через блокировку в Redis:
+// блокировка берется с ограниченным временем жизни и продлевается, пока лидер жив.
+// Если лидер падает, блокировка истекает и ее забирает другой экземпляр.
+
+const LOCK_KEY_PREFIX: &str = "lock:";
+
+/// Взять блокировку, если она свободна, или продлить, если уже принадлежит нам
+const ACQUIRE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+    return 1
+end
+return 0
+"#;
+
+/// Отпустить блокировку, только если она принадлежит нам
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+#[derive(Clone)]
+pub struct RedisLock {
+    connection: RedisConnection,
+    config: RedisConfig,
+    instance_id: String,
+}
+
+impl RedisLock {
+    pub async fn new(host: &str, port: u16) -> Result<Self, Box<dyn Error>> {
+        Self::connect(&RedisConfig {
+            host: host.into(),
+            port,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn connect(config: &RedisConfig) -> Result<Self, Box<dyn Error>> {
+        let connection = RedisConnector::new(config).connect().await?;
+        Ok(Self {
+            connection,
+            config: config.clone(),
+            instance_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// Уникальный идентификатор этого экземпляра, которым помечаются его блокировки
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Пытается взять блокировку name на время ttl
+    ///
+    /// Возвращает true, если блокировка теперь принадлежит этому экземпляру
+    /// (в том числе, если она уже была нашей и просто продлена)
+    pub async fn try_acquire(&self, name: &str, ttl: Duration) -> RedisResult<bool> {
+        let acquired: i64 = Script::new(ACQUIRE_SCRIPT)
+            .key(self.config.key(&format!("{LOCK_KEY_PREFIX}{name}")))
+            .arg(&self.instance_id)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(acquired == 1)
+    }
+
+    /// Отпускает блокировку name, если она принадлежит этому экземпляру
+    pub async fn release(&self, name: &str) -> RedisResult<bool> {
+        let released: i64 = Script::new(RELEASE_SCRIPT)
+            .key(self.config.key(&format!("{LOCK_KEY_PREFIX}{name}")))
+            .arg(&self.instance_id)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(released == 1)
+    }
+}
+
+/// Запускает периодическую задачу, которая выполняется только на экземпляре-лидере
+///
+/// Каждые interval экземпляр пытается взять или продлить блокировку name на два интервала.
+/// Пока экземпляр жив, он остается лидером, а задача не выполняется на других экземплярах.
+/// Пока задача выполняется, блокировка продлевается; если подтвердить владение не удалось,
+/// задача прерывается, чтобы она не шла одновременно на двух экземплярах.
+pub fn spawn_singleton_job<F, Fut>(lock: RedisLock, name: &'static str, interval: Duration, job: F)
+where
+    F: Fn() -> Fut + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    actix::spawn(async move {
+        let ttl = interval * 2;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match lock.try_acquire(name, ttl).await {
+                Ok(true) => {
+                    debug!("Running singleton job {name}");
+                    if !run_while_owned(&lock, name, ttl, job()).await {
+                        // Блокировка могла остаться нашей, если не ответил Redis; скрипт
+                        // отпускает ее, только если она все еще принадлежит нам
+                        if let Err(e) = lock.release(name).await {
+                            error!("Cannot release lock for singleton job {name}: {e}");
+                        }
+                    }
+                }
+                Ok(false) => {
+                    debug!("Singleton job {name} is owned by another instance");
+                }
+                Err(e) => {
+                    error!("Cannot acquire lock for singleton job {name}: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Выполняет job, продлевая блокировку name каждую четверть ttl
+///
+/// Возвращает false, если задача прервана, потому что владение блокировкой не подтвердилось
+async fn run_while_owned(
+    lock: &RedisLock,
+    name: &str,
+    ttl: Duration,
+    job: impl Future<Output = ()>,
+) -> bool {
+    let renew = async {
+        let mut ticker = tokio::time::interval(ttl / 4);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Первый тик срабатывает сразу, а блокировка только что взята
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match lock.try_acquire(name, ttl).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("Singleton job {name} lost its lock, stopping it");
+                    return;
+                }
+                Err(e) => {
+                    error!("Cannot renew lock for singleton job {name}, stopping it: {e}");
+                    return;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = job => true,
+        _ = renew => false,
+    }
+}
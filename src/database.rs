@@ -1,13 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::actors::websocket_actor::ChatMessage;
+use crate::actors::websocket_actor::{ChatMessage, ForwardedFrom, MessageTombstone};
+use crate::calls::{CallEvent, CallEventKind};
+use futures::StreamExt;
 use scylla::{
-    prepared_statement::PreparedStatement, query::Query, statement::SerialConsistency, Bytes,
-    IntoTypedRows, Session, SessionBuilder,
+    batch::{Batch, BatchType},
+    frame::value::{Counter, SerializeValuesError, SerializedValues, Timestamp, ValueList},
+    prepared_statement::PreparedStatement,
+    query::Query,
+    statement::SerialConsistency,
+    transport::errors::QueryError,
+    Bytes, IntoTypedRows, Session, SessionBuilder,
 };
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use self::data::{ChatInfo, ChatType, UserInfo};
+use self::data::{
+    Attachment, BotWebhook, ChatEvent, ChatEventKind, ChatInfo, ChatLabels, ChatPermissions,
+    ChatRole, ChatType, DeliveryMode, Draft, Mute, NotificationPriority, NotificationSettings,
+    PinOutcome, PinnedMessage, PostPolicy, QuietHours, ReadPosition, SecretKind, UnpinReason,
+    UnpinnedMessage, UserInfo, UserPreferences, UserProfile,
+};
+use crate::{
+    clock,
+    config::{DatabaseConfig, MessageStorage},
+    i18n::{translate, Locale},
+    ids::{ChatId, UserId},
+    mentions::{self, Mention},
+    metrics, secrets,
+};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -25,22 +47,59 @@ impl PageIndex {
     fn into(self) -> Option<Bytes> {
         self.index.map_or_else(|| None, |v| Some(Bytes::from(v)))
     }
+
+    /// Есть ли за этой страницей еще одна
+    pub fn has_next_page(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Индекс в виде строки для параметра cursor, None - если страница последняя
+    pub fn to_cursor(&self) -> Option<String> {
+        self.index.as_ref().map(hex::encode)
+    }
+
+    /// Индекс из строки, которую вернул to_cursor
+    pub fn from_cursor(cursor: &str) -> Option<PageIndex> {
+        hex::decode(cursor)
+            .ok()
+            .map(|index| PageIndex { index: Some(index) })
+    }
 }
 
 pub mod data {
+    use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
     use scylla::frame::response::result::CqlValue;
-    use scylla::{
-        cql_to_rust::{FromCqlVal, FromCqlValError},
-        FromRow,
-    };
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
-    #[derive(Debug, Serialize, Deserialize, FromRow)]
+    use crate::serializable_duration::SerializableDuration;
+
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct UserInfo {
         pub id: i64,
         pub name: String,
         pub chats: Vec<Uuid>,
+        #[serde(flatten, default)]
+        pub profile: UserProfile,
+    }
+
+    /// Вебхук бота, на который доставляются сообщения его чатов
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct BotWebhook {
+        pub user_id: i64,
+        pub callback_url: String,
+        /// Секрет подписи запросов
+        pub secret: String,
+    }
+
+    /// Необязательные поля профиля пользователя, None - поле не заполнено
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct UserProfile {
+        pub avatar_url: Option<String>,
+        pub bio: Option<String>,
+        /// Короткий текст статуса, который видят другие пользователи
+        pub status: Option<String>,
     }
 
     #[derive(PartialEq, Debug, Serialize, Deserialize)]
@@ -50,6 +109,9 @@ pub mod data {
         Private,
         #[serde(rename = "group")]
         Group,
+        /// Публичный канал: его находят через поиск и входят без приглашения
+        #[serde(rename = "channel")]
+        Channel,
         #[serde(rename = "reserved")]
         Reserved,
     }
@@ -60,18 +122,886 @@ pub mod data {
                 match &*cql_val.into_string().ok_or(FromCqlValError::BadCqlType)? {
                     "group" => ChatType::Group,
                     "private" => ChatType::Private,
+                    "channel" => ChatType::Channel,
                     _ => ChatType::Reserved,
                 },
             )
         }
     }
 
-    #[derive(Debug, Serialize, Deserialize, FromRow)]
+    /// Вид секрета чата
+    #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+    pub enum SecretKind {
+        #[serde(rename = "invite_code")]
+        InviteCode,
+        #[serde(rename = "webhook_token")]
+        WebhookToken,
+    }
+
+    impl SecretKind {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                SecretKind::InviteCode => "invite_code",
+                SecretKind::WebhookToken => "webhook_token",
+            }
+        }
+    }
+
+    /// Гарантия доставки сообщений чата
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DeliveryMode {
+        /// Сообщение рассылается через pub/sub и теряется, если клиент в этот момент отключен
+        #[default]
+        AtMostOnce,
+        /// Сообщение дополнительно пишется в поток и досылается клиенту после переподключения,
+        /// пока клиент не подтвердит его получение
+        AtLeastOnce,
+    }
+
+    impl DeliveryMode {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                DeliveryMode::AtMostOnce => "at_most_once",
+                DeliveryMode::AtLeastOnce => "at_least_once",
+            }
+        }
+    }
+
+    impl FromCqlVal<CqlValue> for DeliveryMode {
+        fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+            Ok(
+                match &*cql_val.into_string().ok_or(FromCqlValError::BadCqlType)? {
+                    "at_least_once" => DeliveryMode::AtLeastOnce,
+                    _ => DeliveryMode::AtMostOnce,
+                },
+            )
+        }
+    }
+
+    /// Кто может писать в чат
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum PostPolicy {
+        #[default]
+        Everyone,
+        /// Только создатель чата, остальные участники читают
+        CreatorOnly,
+        /// Только владелец и администраторы, остальные участники - подписчики и читают
+        AdminsOnly,
+    }
+
+    impl PostPolicy {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                PostPolicy::Everyone => "everyone",
+                PostPolicy::CreatorOnly => "creator_only",
+                PostPolicy::AdminsOnly => "admins_only",
+            }
+        }
+    }
+
+    impl FromCqlVal<CqlValue> for PostPolicy {
+        fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+            Ok(
+                match &*cql_val.into_string().ok_or(FromCqlValError::BadCqlType)? {
+                    "creator_only" => PostPolicy::CreatorOnly,
+                    "admins_only" => PostPolicy::AdminsOnly,
+                    _ => PostPolicy::Everyone,
+                },
+            )
+        }
+    }
+
+    /// Роль участника чата
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ChatRole {
+        /// Создатель чата или тот, кому он передал чат; раздает роли
+        Owner,
+        /// Приглашает, исключает участников и переименовывает чат
+        Admin,
+        #[default]
+        Member,
+    }
+
+    impl ChatRole {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ChatRole::Owner => "owner",
+                ChatRole::Admin => "admin",
+                ChatRole::Member => "member",
+            }
+        }
+
+        /// Может ли участник управлять составом и названием чата
+        pub fn can_manage(&self) -> bool {
+            matches!(self, ChatRole::Owner | ChatRole::Admin)
+        }
+    }
+
+    impl FromCqlVal<CqlValue> for ChatRole {
+        fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+            Ok(
+                match &*cql_val.into_string().ok_or(FromCqlValError::BadCqlType)? {
+                    "owner" => ChatRole::Owner,
+                    "admin" => ChatRole::Admin,
+                    _ => ChatRole::Member,
+                },
+            )
+        }
+    }
+
+    /// Что в чате можно обычным участникам, владелец и администраторы могут все
+    ///
+    /// Хранится битовой маской, в JSON передается числом
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct ChatPermissions(pub u32);
+
+    impl ChatPermissions {
+        /// Приглашать пользователей в чат
+        pub const INVITE: Self = Self(1);
+        /// Закреплять и откреплять сообщения
+        pub const PIN: Self = Self(1 << 1);
+        /// Переименовывать чат
+        pub const CHANGE_INFO: Self = Self(1 << 2);
+        /// Отправлять и пересылать сообщения с вложениями, загружать вложения
+        pub const POST_MEDIA: Self = Self(1 << 3);
+        pub const ALL: Self =
+            Self(Self::INVITE.0 | Self::PIN.0 | Self::CHANGE_INFO.0 | Self::POST_MEDIA.0);
+
+        pub fn contains(&self, other: Self) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        /// Нет ли в маске неизвестных битов
+        pub fn is_known(&self) -> bool {
+            self.0 & !Self::ALL.0 == 0
+        }
+    }
+
+    /// Участники закрепляют сообщения и отправляют вложения, как до появления разрешений
+    impl Default for ChatPermissions {
+        fn default() -> Self {
+            Self(Self::PIN.0 | Self::POST_MEDIA.0)
+        }
+    }
+
+    impl std::ops::BitOr for ChatPermissions {
+        type Output = Self;
+        fn bitor(self, other: Self) -> Self {
+            Self(self.0 | other.0)
+        }
+    }
+
+    impl FromCqlVal<CqlValue> for ChatPermissions {
+        fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+            Ok(Self(
+                cql_val.as_int().ok_or(FromCqlValError::BadCqlType)? as u32
+            ))
+        }
+    }
+
+    /// Метка чата с содержимым для взрослых, такие чаты по умолчанию скрываются
+    pub const NSFW_LABEL: &str = "nsfw";
+
+    /// Язык и метки содержимого чата, их задают администраторы
+    #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+    pub struct ChatLabels {
+        /// Код языка, например en или pt-br
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub language: Option<String>,
+        #[serde(default)]
+        pub labels: Vec<String>,
+    }
+
+    impl ChatLabels {
+        pub fn is_nsfw(&self) -> bool {
+            self.labels.iter().any(|label| label == NSFW_LABEL)
+        }
+    }
+
+    /// Отбор чатов по языку и меткам
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct LabelFilter {
+        pub language: Option<String>,
+        pub label: Option<String>,
+        /// Показывать ли чаты с меткой nsfw, по умолчанию они скрыты
+        #[serde(default)]
+        pub include_nsfw: bool,
+    }
+
+    impl LabelFilter {
+        pub fn matches(&self, chat: &ChatLabels) -> bool {
+            if chat.is_nsfw() && !self.include_nsfw && self.label.as_deref() != Some(NSFW_LABEL) {
+                return false;
+            }
+            let language_matches = self.language.as_ref().is_none_or(|language| {
+                chat.language
+                    .as_ref()
+                    .is_some_and(|chat_language| chat_language.eq_ignore_ascii_case(language))
+            });
+            let label_matches = self
+                .label
+                .as_ref()
+                .is_none_or(|label| chat.labels.iter().any(|l| l.eq_ignore_ascii_case(label)));
+            language_matches && label_matches
+        }
+    }
+
+    /// Закрепленное сообщение чата
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct PinnedMessage {
+        pub message_id: Uuid,
+        /// Дата отправки сообщения, вместе с message_id определяет его в истории
+        pub date: SerializableDuration,
+        pub pinned_by: i64,
+        /// Когда сообщение закрепили
+        pub pinned_at: SerializableDuration,
+        /// Когда закрепление снимется само
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub expires_at: Option<SerializableDuration>,
+    }
+
+    /// Сколько чат занимает: счетчики сообщений и вложений и даты крайних сообщений
+    ///
+    /// Счетчики только растут: удаленные и исчезнувшие по сроку сообщения в них остаются,
+    /// а пересланное вложение учитывается в каждом чате, хотя файл в хранилище один
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct ChatUsage {
+        pub chat_id: Uuid,
+        pub message_count: u64,
+        /// Сколько байт занимали сообщения в JSON, когда их отправили
+        pub message_bytes: u64,
+        pub attachment_count: u64,
+        pub attachment_bytes: u64,
+        /// Даты самого старого и самого нового сообщения, None - сообщений нет
+        pub oldest_message: Option<SerializableDuration>,
+        pub newest_message: Option<SerializableDuration>,
+    }
+
+    /// Что случилось в чате, по этому виду событие попадает в ленту событий чата
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ChatEventKind {
+        /// Новое сообщение, в payload - само сообщение
+        Message,
+        /// Сообщение отредактировано, в payload - сообщение после правки
+        MessageEdited,
+        /// Сообщение удалено, в payload - его след
+        MessageDeleted,
+        /// В payload - {user_id: i64}
+        MemberJoined,
+        /// Участник вышел или его исключили, в payload - {user_id: i64}
+        MemberLeft,
+        /// В payload - {name: str}
+        ChatRenamed,
+    }
+
+    impl ChatEventKind {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                ChatEventKind::Message => "message",
+                ChatEventKind::MessageEdited => "message_edited",
+                ChatEventKind::MessageDeleted => "message_deleted",
+                ChatEventKind::MemberJoined => "member_joined",
+                ChatEventKind::MemberLeft => "member_left",
+                ChatEventKind::ChatRenamed => "chat_renamed",
+            }
+        }
+
+        pub fn parse(kind: &str) -> Option<Self> {
+            match kind {
+                "message" => Some(ChatEventKind::Message),
+                "message_edited" => Some(ChatEventKind::MessageEdited),
+                "message_deleted" => Some(ChatEventKind::MessageDeleted),
+                "member_joined" => Some(ChatEventKind::MemberJoined),
+                "member_left" => Some(ChatEventKind::MemberLeft),
+                "chat_renamed" => Some(ChatEventKind::ChatRenamed),
+                _ => None,
+            }
+        }
+    }
+
+    /// Событие из ленты событий чата
+    ///
+    /// position растет от события к событию и служит курсором ленты
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct ChatEvent {
+        pub position: i64,
+        pub event_id: Uuid,
+        pub kind: ChatEventKind,
+        pub date: SerializableDuration,
+        pub payload: serde_json::Value,
+    }
+
+    /// Файл, загруженный в чат, сам файл лежит в хранилище по url
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct Attachment {
+        pub id: Uuid,
+        pub chat_id: Uuid,
+        pub uploader_id: i64,
+        pub name: String,
+        /// Размер в байтах
+        pub size: u64,
+        pub mime: String,
+        pub url: String,
+        pub created_at: SerializableDuration,
+    }
+
+    /// Почему с сообщения сняли закрепление
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum UnpinReason {
+        /// Открепил участник чата
+        Manual,
+        /// Истек срок закрепления
+        Expired,
+        /// Вытеснено новым закреплением, когда их стало слишком много
+        Rotated,
+    }
+
+    /// Снятое закрепление, о нем рассказывают участникам чата
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    pub struct UnpinnedMessage {
+        pub chat_id: Uuid,
+        pub message_id: Uuid,
+        pub reason: UnpinReason,
+    }
+
+    /// Результат закрепления: новое закрепление и вытесненные им
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct PinOutcome {
+        pub pin: PinnedMessage,
+        pub rotated: Vec<UnpinnedMessage>,
+    }
+
+    /// Какие сообщения чата вызывают уведомление у пользователя
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum NotificationPriority {
+        #[default]
+        All,
+        /// Только сообщения, в которых пользователя упомянули
+        MentionsOnly,
+        None,
+    }
+
+    impl NotificationPriority {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                NotificationPriority::All => "all",
+                NotificationPriority::MentionsOnly => "mentions_only",
+                NotificationPriority::None => "none",
+            }
+        }
+    }
+
+    impl FromCqlVal<CqlValue> for NotificationPriority {
+        fn from_cql(cql_val: CqlValue) -> Result<Self, scylla::cql_to_rust::FromCqlValError> {
+            Ok(
+                match &*cql_val.into_string().ok_or(FromCqlValError::BadCqlType)? {
+                    "mentions_only" => NotificationPriority::MentionsOnly,
+                    "none" => NotificationPriority::None,
+                    _ => NotificationPriority::All,
+                },
+            )
+        }
+    }
+
+    /// Докуда пользователь прочитал чат: последнее прочитанное сообщение
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct ReadPosition {
+        pub message_id: Uuid,
+        pub date: SerializableDuration,
+    }
+
+    /// Неотправленный черновик сообщения пользователя в чате
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Draft {
+        pub chat_id: Uuid,
+        pub text: String,
+        /// Когда черновик сохранили в последний раз, сохраненный позже заменяет прежний
+        pub updated_at: SerializableDuration,
+    }
+
+    /// Настройки уведомлений пользователя в одном чате
+    #[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+    pub struct NotificationSettings {
+        #[serde(default)]
+        pub priority: NotificationPriority,
+        /// Идентификатор звука уведомления у клиента, None - звук по умолчанию
+        #[serde(default)]
+        pub sound: Option<String>,
+        /// Уведомления отключены на время или насовсем, None - не отключены
+        #[serde(default)]
+        pub mute: Option<Mute>,
+    }
+
+    impl NotificationSettings {
+        /// Нужно ли уведомлять пользователя о сообщении, mentioned - упомянут ли он в нем
+        pub fn should_notify(&self, mentioned: bool) -> bool {
+            self.should_notify_at(mentioned, chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH)
+        }
+
+        /// То же, что should_notify, если сейчас момент now (от начала эпохи)
+        pub fn should_notify_at(&self, mentioned: bool, now: chrono::Duration) -> bool {
+            if self.is_muted_at(now) {
+                return false;
+            }
+            match self.priority {
+                NotificationPriority::All => true,
+                NotificationPriority::MentionsOnly => mentioned,
+                NotificationPriority::None => false,
+            }
+        }
+
+        /// Отключены ли уведомления в момент now (от начала эпохи)
+        pub fn is_muted_at(&self, now: chrono::Duration) -> bool {
+            self.mute
+                .as_ref()
+                .is_some_and(|mute| mute.is_active_at(now))
+        }
+    }
+
+    /// Общие для всех чатов настройки уведомлений пользователя
+    ///
+    /// Действуют поверх настроек отдельных чатов: уведомление приходит, только если его
+    /// разрешают и настройки чата, и эти
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct UserPreferences {
+        /// Уведомления со звуком, false - все уведомления беззвучные
+        pub sound: bool,
+        /// Уведомлять только об упоминаниях во всех чатах
+        pub mentions_only: bool,
+        /// Часы "не беспокоить", None - не заданы
+        pub quiet_hours: Option<QuietHours>,
+    }
+
+    impl Default for UserPreferences {
+        fn default() -> Self {
+            Self {
+                sound: true,
+                mentions_only: false,
+                quiet_hours: None,
+            }
+        }
+    }
+
+    impl UserPreferences {
+        /// Нужно ли уведомлять пользователя о сообщении в чате с настройками chat,
+        /// mentioned - упомянут ли он в нем
+        ///
+        /// В часы "не беспокоить" уведомлений нет, даже об упоминаниях
+        pub fn should_notify(&self, chat: &NotificationSettings, mentioned: bool) -> bool {
+            self.should_notify_at(
+                chat,
+                mentioned,
+                chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH,
+            )
+        }
+
+        /// То же, что should_notify, если сейчас момент now (от начала эпохи)
+        pub fn should_notify_at(
+            &self,
+            chat: &NotificationSettings,
+            mentioned: bool,
+            now: chrono::Duration,
+        ) -> bool {
+            if !chat.should_notify_at(mentioned, now) {
+                return false;
+            }
+            if self.mentions_only && !mentioned {
+                return false;
+            }
+            !self
+                .quiet_hours
+                .as_ref()
+                .is_some_and(|quiet| quiet.is_active_at(now))
+        }
+    }
+
+    /// Часы "не беспокоить": с start_minute до end_minute минут от полуночи по местному
+    /// времени пользователя, которое отстоит от UTC на utc_offset_minutes
+    ///
+    /// Если start_minute больше end_minute, то часы переходят через полночь (например,
+    /// с 22:00 до 7:00), а если они равны - не действуют никогда
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct QuietHours {
+        pub start_minute: u16,
+        pub end_minute: u16,
+        #[serde(default)]
+        pub utc_offset_minutes: i16,
+    }
+
+    impl QuietHours {
+        /// Минут в сутках
+        pub const DAY_MINUTES: u16 = 24 * 60;
+
+        /// Действуют ли часы в момент now (от начала эпохи)
+        pub fn is_active_at(&self, now: chrono::Duration) -> bool {
+            let local = (now.num_minutes() + self.utc_offset_minutes as i64)
+                .rem_euclid(Self::DAY_MINUTES as i64) as u16;
+            if self.start_minute <= self.end_minute {
+                (self.start_minute..self.end_minute).contains(&local)
+            } else {
+                local >= self.start_minute || local < self.end_minute
+            }
+        }
+    }
+
+    /// Отключение уведомлений чата: до момента (миллисекунды от начала эпохи) или насовсем
+    #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Mute {
+        Until(SerializableDuration),
+        Forever,
+    }
+
+    impl Mute {
+        /// Действует ли отключение в момент now (от начала эпохи)
+        pub fn is_active_at(&self, now: chrono::Duration) -> bool {
+            match self {
+                Mute::Until(until) => until.timestamp > now,
+                Mute::Forever => true,
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct ChatInfo {
         pub id: Uuid,
         pub name: String,
+        /// Участники чата, для больших чатов может быть пустым,
+        /// тогда участников надо получать постранично
         pub users: Vec<i64>,
         pub chat_type: ChatType,
+        #[serde(default)]
+        pub member_count: usize,
+        /// None в базе означает режим по умолчанию для развертывания,
+        /// клиентам отдается уже итоговый режим
+        #[serde(default)]
+        pub delivery_mode: Option<DeliveryMode>,
+        /// Настройки уведомлений того, кто запросил информацию о чате
+        #[serde(default)]
+        pub notifications: NotificationSettings,
+        #[serde(default)]
+        pub post_policy: PostPolicy,
+        #[serde(default)]
+        pub labels: ChatLabels,
+        /// Через сколько секунд после отправки исчезают сообщения, None - не исчезают
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub message_ttl_secs: Option<u32>,
+        /// Докуда чат прочитал тот, кто запросил информацию о чате, None - еще не читал
+        #[serde(default)]
+        pub last_read: Option<ReadPosition>,
+        /// Роль в чате того, кто запросил информацию о чате
+        #[serde(default)]
+        pub role: ChatRole,
+        #[serde(default)]
+        pub permissions: ChatPermissions,
+    }
+
+    /// Публичный канал в результатах поиска
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ChannelListing {
+        pub id: Uuid,
+        pub name: String,
+        pub member_count: u64,
+    }
+
+    /// Запись о чате без проверки прав, для служебных задач
+    #[derive(Debug, Clone)]
+    pub struct ChatRecord {
+        pub id: Uuid,
+        /// Миллисекунды от начала эпохи, None - у старых чатов, созданных без даты
+        pub creation_date: Option<chrono::Duration>,
+        pub users: Vec<i64>,
+    }
+}
+
+/// Ожидаемая схема базы и проверка соответствия ей
+pub mod schema {
+    use std::collections::HashMap;
+
+    /// Версия схемы, с которой работает этот код
+    ///
+    /// Увеличивается при каждом изменении таблиц в create_schema
+    pub const SCHEMA_VERSION: i32 = 34;
+
+    /// Колонки сообщений, добавленные после первой версии таблиц сообщений
+    ///
+    /// Таблица messages создается сразу с ними, а в старые таблицы сообщений отдельных
+    /// чатов они добавляются перед тем, как сообщения из них переносятся в messages
+    pub const MESSAGE_COLUMNS: &[(&str, &str)] = &[
+        ("edited_at", "timestamp"),
+        ("reply_to", "uuid"),
+        ("attachments", "list<uuid>"),
+        ("forwarded_chat_id", "uuid"),
+        ("forwarded_message_id", "uuid"),
+        ("forwarded_sender_id", "bigint"),
+        ("mentions", "list<bigint>"),
+        ("call_id", "uuid"),
+        ("call_event", "text"),
+        ("call_duration", "int"),
+    ];
+
+    /// Таблицы пространства ключей и их колонки с типами, как их называет system_schema
+    pub const EXPECTED_TABLES: &[(&str, &[(&str, &str)])] = &[
+        (
+            "users",
+            &[
+                ("user_id", "bigint"),
+                ("creation_date", "timestamp"),
+                ("name", "text"),
+                ("chats", "set<uuid>"),
+                ("avatar_url", "text"),
+                ("bio", "text"),
+                ("status_text", "text"),
+            ],
+        ),
+        (
+            "chats",
+            &[
+                ("chat_id", "uuid"),
+                ("creation_date", "timestamp"),
+                ("name", "text"),
+                ("users", "set<bigint>"),
+                ("chat_type", "text"),
+                ("delivery_mode", "text"),
+                ("post_policy", "text"),
+                ("creator_id", "bigint"),
+                ("language", "text"),
+                ("labels", "set<text>"),
+                ("message_ttl", "int"),
+                ("permissions", "int"),
+                ("public", "boolean"),
+                ("max_members", "int"),
+            ],
+        ),
+        (
+            "public_channels",
+            &[
+                ("bucket", "int"),
+                ("name_key", "text"),
+                ("chat_id", "uuid"),
+                ("name", "text"),
+            ],
+        ),
+        (
+            "chat_pins",
+            &[
+                ("chat_id", "uuid"),
+                ("message_id", "uuid"),
+                ("date", "timestamp"),
+                ("pinned_by", "bigint"),
+                ("pinned_at", "timestamp"),
+                ("expires_at", "timestamp"),
+            ],
+        ),
+        (
+            "chat_secrets",
+            &[
+                ("chat_id", "uuid"),
+                ("kind", "text"),
+                ("secret_hash", "blob"),
+            ],
+        ),
+        (
+            "chat_members",
+            &[("chat_id", "uuid"), ("user_id", "bigint"), ("role", "text")],
+        ),
+        (
+            "chat_notification_settings",
+            &[
+                ("user_id", "bigint"),
+                ("chat_id", "uuid"),
+                ("priority", "text"),
+                ("sound", "text"),
+                ("muted_until", "timestamp"),
+                ("muted_forever", "boolean"),
+            ],
+        ),
+        (
+            "chat_message_stats",
+            &[
+                ("chat_id", "uuid"),
+                ("message_count", "counter"),
+                ("message_bytes", "counter"),
+                ("attachment_count", "counter"),
+                ("attachment_bytes", "counter"),
+            ],
+        ),
+        (
+            "chat_unread",
+            &[
+                ("user_id", "bigint"),
+                ("chat_id", "uuid"),
+                ("unread", "counter"),
+            ],
+        ),
+        (
+            "client_message_ids",
+            &[
+                ("chat_id", "uuid"),
+                ("sender_id", "bigint"),
+                ("client_msg_id", "text"),
+                ("message_id", "uuid"),
+                ("date", "timestamp"),
+            ],
+        ),
+        (
+            "chat_read_positions",
+            &[
+                ("user_id", "bigint"),
+                ("chat_id", "uuid"),
+                ("message_id", "uuid"),
+                ("date", "timestamp"),
+            ],
+        ),
+        (
+            "chat_archive",
+            &[
+                ("user_id", "bigint"),
+                ("chat_id", "uuid"),
+                ("archived_at", "timestamp"),
+            ],
+        ),
+        (
+            "user_blocks",
+            &[
+                ("user_id", "bigint"),
+                ("blocked_id", "bigint"),
+                ("blocked_at", "timestamp"),
+            ],
+        ),
+        (
+            "user_contacts",
+            &[
+                ("user_id", "bigint"),
+                ("contact_id", "bigint"),
+                ("added_at", "timestamp"),
+            ],
+        ),
+        (
+            "bot_webhooks",
+            &[
+                ("user_id", "bigint"),
+                ("callback_url", "text"),
+                ("secret", "text"),
+                ("registered_at", "timestamp"),
+            ],
+        ),
+        ("user_names", &[("name_key", "text"), ("user_id", "bigint")]),
+        (
+            "user_preferences",
+            &[
+                ("user_id", "bigint"),
+                ("sound", "boolean"),
+                ("mentions_only", "boolean"),
+                ("quiet_start", "int"),
+                ("quiet_end", "int"),
+                ("quiet_utc_offset", "int"),
+            ],
+        ),
+        (
+            "chat_drafts",
+            &[
+                ("user_id", "bigint"),
+                ("chat_id", "uuid"),
+                ("draft_text", "text"),
+                ("updated_at", "timestamp"),
+            ],
+        ),
+        (
+            "attachments",
+            &[
+                ("attachment_id", "uuid"),
+                ("chat_id", "uuid"),
+                ("uploader_id", "bigint"),
+                ("name", "text"),
+                ("size", "bigint"),
+                ("mime", "text"),
+                ("url", "text"),
+                ("created_at", "timestamp"),
+            ],
+        ),
+        (
+            "messages",
+            &[
+                ("chat_id", "uuid"),
+                ("bucket", "int"),
+                ("date", "timestamp"),
+                ("message_id", "uuid"),
+                ("user_id", "bigint"),
+                ("message_text", "text"),
+                ("edited_at", "timestamp"),
+                ("reply_to", "uuid"),
+                ("attachments", "list<uuid>"),
+                ("forwarded_chat_id", "uuid"),
+                ("forwarded_message_id", "uuid"),
+                ("forwarded_sender_id", "bigint"),
+                ("mentions", "list<bigint>"),
+                ("call_id", "uuid"),
+                ("call_event", "text"),
+                ("call_duration", "int"),
+            ],
+        ),
+        (
+            "chat_events",
+            &[
+                ("chat_id", "uuid"),
+                ("position", "bigint"),
+                ("event_id", "uuid"),
+                ("kind", "text"),
+                ("date", "timestamp"),
+                ("payload", "text"),
+            ],
+        ),
+        ("schema_version", &[("id", "int"), ("version", "int")]),
+    ];
+
+    /// Сравнивает схему базы с ожидаемой и возвращает список расхождений
+    ///
+    /// columns - строки (таблица, колонка, тип) из system_schema.columns,
+    /// stored_version - версия из таблицы schema_version, если она там есть
+    pub fn find_problems(
+        columns: &[(String, String, String)],
+        stored_version: Option<i32>,
+    ) -> Vec<String> {
+        let actual: HashMap<(&str, &str), &str> = columns
+            .iter()
+            .map(|(table, column, kind)| ((table.as_str(), column.as_str()), kind.as_str()))
+            .collect();
+        let mut problems = vec![];
+        for (table, expected_columns) in EXPECTED_TABLES {
+            if !actual.keys().any(|(t, _)| t == table) {
+                problems.push(format!("Table {table} is missing"));
+                continue;
+            }
+            for (column, expected_type) in *expected_columns {
+                match actual.get(&(*table, *column)) {
+                    None => problems.push(format!("Column {table}.{column} is missing")),
+                    Some(actual_type) if actual_type != expected_type => problems.push(format!(
+                        "Column {table}.{column} has type {actual_type}, expected {expected_type}"
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        match stored_version {
+            Some(version) if version > SCHEMA_VERSION => problems.push(format!(
+                "Schema version {version} is newer than supported version {SCHEMA_VERSION}"
+            )),
+            Some(version) if version < SCHEMA_VERSION => problems.push(format!(
+                "Schema version {version} is older than required version {SCHEMA_VERSION}"
+            )),
+            None => problems.push("Schema version is not recorded".into()),
+            _ => {}
+        }
+        problems
     }
 }
 
@@ -82,6 +1012,43 @@ pub enum DBError {
     OtherError(Box<dyn std::error::Error + Send>),
 }
 
+/// Сколько счетчиков непрочитанных обновляется одним пакетом
+const UNREAD_BATCH_SIZE: usize = 100;
+
+/// Раздел индекса публичных каналов, пока все каналы лежат в одном
+const CHANNEL_INDEX_BUCKET: i32 = 0;
+
+/// Сколько миллисекунд сообщений попадает в одну корзину таблицы messages (сутки)
+const MESSAGE_BUCKET_MILLIS: i64 = 24 * 3600 * 1000;
+
+/// Корзина сообщения, отправленного в момент date (от начала эпохи): номер суток по UTC
+///
+/// Корзина - первая колонка кластеризации в messages, поэтому сообщение с известной датой
+/// находится по ключу без перебора, а старые сообщения можно удалять целыми сутками
+pub fn message_bucket(date: chrono::Duration) -> i32 {
+    date.num_milliseconds().div_euclid(MESSAGE_BUCKET_MILLIS) as i32
+}
+
+/// Позиция события в ленте чата, если оно случилось в момент date (от начала эпохи)
+///
+/// Позиция - миллисекунды с тремя дробными знаками, которые берутся из event_id: события
+/// одного экземпляра сервиса по гибридным часам строго упорядочены, а события разных
+/// экземпляров, случившиеся в одну миллисекунду, почти никогда не получают одну позицию
+pub fn event_position(date: chrono::Duration, event_id: Uuid) -> i64 {
+    date.num_milliseconds() * 1000 + (event_id.as_u128() % 1000) as i64
+}
+
+/// Ключ имени канала в индексе: поиск по началу имени не различает регистр
+pub fn channel_name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Ошибка запроса к базе, таймауты при этом учитываются в метриках
+fn query_error(e: QueryError) -> DBError {
+    metrics::observe_scylla_error(&e);
+    DBError::QueryError(Box::new(e))
+}
+
 impl std::fmt::Display for DBError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -98,25 +1065,130 @@ impl std::fmt::Display for DBError {
     }
 }
 
+impl std::error::Error for DBError {}
+
+/// Ошибка со стабильным кодом
+///
+/// Клиент получает код и описание из каталога переводов, в логи пишется описание на английском
 #[derive(Debug)]
-struct StringError {
-    msg: String,
+pub struct StringError {
+    pub code: &'static str,
+    /// Параметры для подстановки в описание
+    pub args: Vec<(&'static str, String)>,
+}
+
+impl StringError {
+    pub fn new(code: &'static str) -> Self {
+        Self { code, args: vec![] }
+    }
 }
 
 impl std::fmt::Display for StringError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
+        f.write_str(&translate(Locale::En, self.code, &self.args))
+    }
+}
+
+impl std::error::Error for StringError {}
+
+/// В чате уже столько участников, сколько можно
+#[derive(Debug)]
+pub struct MemberLimitError {
+    pub limit: u32,
+}
+
+impl std::fmt::Display for MemberLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Chat member limit of {} reached", self.limit)
+    }
+}
+
+impl std::error::Error for MemberLimitError {}
+
+/// Пользователь заблокировал того, кто пытается создать с ним чат или пригласить его
+#[derive(Debug)]
+pub struct BlockedError {
+    pub user_id: UserId,
+}
+
+impl std::fmt::Display for BlockedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "User {} does not accept invitations from you",
+            self.user_id
+        )
+    }
+}
+
+impl std::error::Error for BlockedError {}
+
+/// У пользователя уже столько контактов, сколько можно
+#[derive(Debug)]
+pub struct ContactLimitError {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for ContactLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Contact list is full: at most {} contacts", self.limit)
+    }
+}
+
+impl std::error::Error for ContactLimitError {}
+
+/// Имя занято другим пользователем
+#[derive(Debug)]
+pub struct NameTakenError {
+    pub name: String,
+    /// Свободные имена, похожие на запрошенное
+    pub suggestions: Vec<String>,
+}
+
+impl std::fmt::Display for NameTakenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Name {} is already taken", self.name)
     }
 }
 
-impl std::error::Error for StringError {
-    fn description(&self) -> &str {
-        &self.msg
+impl std::error::Error for NameTakenError {}
+
+/// Чат не создан: пакет с его записями не применился
+#[derive(Debug)]
+pub struct ChatCreationError {
+    pub chat_id: ChatId,
+    /// Записи, которые могли успеть примениться, удалены
+    pub rolled_back: bool,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ChatCreationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cannot create chat {}: {}", self.chat_id, self.reason)?;
+        if !self.rolled_back {
+            write!(f, " (rollback failed)")?;
+        }
+        Ok(())
     }
 }
 
+impl std::error::Error for ChatCreationError {}
+
+/// По чему сравниваются имена пользователей: имена, отличающиеся только регистром, совпадают
+pub fn name_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
 pub type DBResult<T> = Result<T, DBError>;
 
+/// Имя пространства ключей подставляется в запросы как есть, поэтому пускаем только
+/// то, что Scylla и так разрешает: буквы, цифры и подчеркивания, не длиннее 48 символов
+fn is_valid_keyspace_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 48
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 #[mockall::automock]
 #[async_trait::async_trait(?Send)]
 pub trait Database {
@@ -124,53 +1196,679 @@ pub trait Database {
     async fn init_db(&self) -> DBResult<()>;
     async fn init_db_clear(&self) -> DBResult<()>;
     async fn add_new_message_to_chat(&self, msg: ChatMessage) -> DBResult<()>;
-    async fn get_chat_history_paged(
+    /// Закрепляет client_msg_id отправителя за сообщением message_id на
+    /// CLIENT_MSG_ID_WINDOW_SECS секунд
+    ///
+    /// Если client_msg_id уже закреплен за другим сообщением, то возвращает его id и дату
+    async fn claim_client_msg_id(
         &self,
-        user_id: i64,
-        chat_id: uuid::Uuid,
+        chat_id: ChatId,
+        sender_id: UserId,
+        client_msg_id: &str,
+        message_id: uuid::Uuid,
+        date: chrono::Duration,
+    ) -> DBResult<Option<(Uuid, chrono::Duration)>>;
+    /// Освобождает client_msg_id, если он закреплен за message_id (сообщение не сохранилось)
+    async fn release_client_msg_id(
+        &self,
+        chat_id: ChatId,
+        sender_id: UserId,
+        client_msg_id: &str,
+        message_id: uuid::Uuid,
+    ) -> DBResult<()>;
+    async fn get_chat_history_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        page_size: usize,
+        paging_index: Option<PageIndex>,
+    ) -> DBResult<(Vec<ChatMessage>, PageIndex)>;
+    /// Ответы на сообщение message_id с пагинацией, от новых к старым
+    async fn get_thread_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
         page_size: usize,
         paging_index: Option<PageIndex>,
     ) -> DBResult<(Vec<ChatMessage>, PageIndex)>;
+    /// Последние limit сообщений чата, отправленные раньше before (или просто последние)
+    async fn get_chat_history_before(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        before: Option<chrono::Duration>,
+        limit: usize,
+    ) -> DBResult<Vec<ChatMessage>>;
     async fn create_new_chat(
         &self,
-        user_id: i64,
-        invited_users_id: Vec<i64>,
+        user_id: UserId,
+        invited_users_id: Vec<UserId>,
         chat_type: data::ChatType,
         chat_name: String,
     ) -> DBResult<data::ChatInfo>;
     async fn add_user_to_chat(
         &self,
-        user_id: i64,
-        invited_user_id: i64,
-        chat_id: uuid::Uuid,
+        user_id: UserId,
+        invited_user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<()>;
+    async fn exit_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Исключает участника target_id из чата
+    ///
+    /// Исключать могут владелец и администраторы, владельца исключить нельзя, а
+    /// администраторов исключает только владелец. Себя не исключают, для этого есть exit_chat
+    async fn remove_user_from_chat(
+        &self,
+        admin_id: UserId,
+        target_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<()>;
+    async fn delete_chat(&self, chat_id: ChatId) -> DBResult<()>;
+    /// Задает, что можно обычным участникам чата, задать могут владелец и администраторы
+    async fn set_permissions(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        permissions: data::ChatPermissions,
+    ) -> DBResult<()>;
+    /// Назначает участнику target_id роль, роли раздает только владелец чата
+    ///
+    /// Назначив владельцем другого участника, владелец передает ему чат и сам становится
+    /// администратором
+    async fn set_role(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        target_id: UserId,
+        role: data::ChatRole,
+    ) -> DBResult<()>;
+    /// Переименовывает чат, переименовать могут владелец, администраторы и участники,
+    /// если это разрешено в чате
+    async fn rename_chat(&self, user_id: UserId, chat_id: ChatId, new_name: String)
+        -> DBResult<()>;
+    async fn get_chat_info(&self, user_id: UserId, chat_id: ChatId) -> DBResult<data::ChatInfo>;
+    async fn get_user_info(&self, user_id: UserId) -> DBResult<UserInfo>;
+    /// Когда создан аккаунт пользователя, по нему ослабляются лимиты новых аккаунтов
+    async fn get_user_creation_date(&self, user_id: UserId) -> DBResult<chrono::Duration>;
+    /// Создает пользователя, если его еще нет, и закрепляет за ним имя
+    ///
+    /// Если имя занято другим пользователем, возвращает NameTakenError без предложений
+    async fn create_new_user(&self, user_id: UserId, user_name: String) -> DBResult<UserInfo>;
+    /// Меняет имя пользователя и закрепляет за ним новое вместо старого
+    ///
+    /// Если новое имя занято другим пользователем, возвращает NameTakenError без предложений
+    async fn rename_user(&self, user_id: UserId, new_name: String) -> DBResult<UserInfo>;
+    /// Какие из имен уже заняты, в виде name_key
+    async fn taken_names(&self, names: Vec<String>) -> DBResult<HashSet<String>>;
+    /// Заменяет поля профиля пользователя, None очищает поле
+    async fn set_user_profile(&self, user_id: UserId, profile: data::UserProfile) -> DBResult<()>;
+    async fn get_user_chats(&self, user_id: UserId) -> DBResult<Vec<ChatId>>;
+    async fn get_user_list(&self) -> DBResult<Vec<UserId>>;
+    /// Все чаты с участниками, читает таблицу чатов целиком
+    async fn get_chat_list(&self) -> DBResult<Vec<data::ChatRecord>>;
+    /// Все пользователи со списками чатов, читает таблицу пользователей целиком
+    async fn get_users_with_chats(&self) -> DBResult<Vec<UserInfo>>;
+    /// Добавляет пользователя в существующий чат без проверки прав (для служебных задач)
+    ///
+    /// Ограничение числа участников при этом действует
+    async fn join_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Добавляет чат в список чатов пользователя, не трогая участников чата (для починки)
+    async fn add_chat_to_user(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Убирает чат из списка чатов пользователя, не трогая участников чата (для починки)
+    async fn remove_chat_from_user(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Убирает пользователя из участников чата, не трогая его список чатов (для починки)
+    async fn drop_chat_member(&self, chat_id: ChatId, user_id: UserId) -> DBResult<()>;
+    /// Информация сразу о нескольких пользователях одним запросом
+    ///
+    /// Несуществующие id пропускаются
+    async fn get_users_info(&self, user_ids: Vec<UserId>) -> DBResult<Vec<UserInfo>>;
+    /// Страница списка пользователей в порядке токенов ключа
+    ///
+    /// after_token - токен последнего пользователя предыдущей страницы,
+    /// вместе со страницей возвращается токен для следующей, если она может быть
+    async fn get_user_list_paged(
+        &self,
+        after_token: Option<i64>,
+        page_size: usize,
+    ) -> DBResult<(Vec<UserInfo>, Option<i64>)>;
+    /// Страница участников чата по возрастанию id
+    ///
+    /// Доступна только участникам чата. after_user - последний участник предыдущей страницы,
+    /// вместе со страницей возвращается курсор следующей, если она может быть
+    async fn get_chat_members_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        after_user: Option<UserId>,
+        page_size: usize,
+    ) -> DBResult<(Vec<UserId>, Option<UserId>)>;
+    /// Генерирует новый секрет чата взамен старого
+    ///
+    /// В базе остается только хэш, сам секрет возвращается один раз
+    async fn rotate_chat_secret(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        kind: data::SecretKind,
+    ) -> DBResult<String>;
+    async fn revoke_chat_secret(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        kind: data::SecretKind,
+    ) -> DBResult<()>;
+    /// Проверяет секрет чата, возвращает false, если секрет не совпал или отозван
+    async fn verify_chat_secret(
+        &self,
+        chat_id: ChatId,
+        kind: data::SecretKind,
+        secret: String,
+    ) -> DBResult<bool>;
+    async fn join_chat_by_invite(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        invite_code: String,
+    ) -> DBResult<data::ChatInfo>;
+    /// Входит в публичный канал без приглашения
+    async fn join_public_channel(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<data::ChatInfo>;
+    /// Публичные каналы, имя которых начинается с query без учета регистра, по алфавиту
+    async fn discover_channels(
+        &self,
+        query: String,
+        limit: usize,
+    ) -> DBResult<Vec<data::ChannelListing>>;
+    /// Режим доставки чата, None - режим по умолчанию для развертывания
+    ///
+    /// Участие в чате не проверяется: режим нужен брокеру при рассылке
+    async fn get_delivery_mode(&self, chat_id: ChatId) -> DBResult<Option<DeliveryMode>>;
+    async fn set_delivery_mode(&self, chat_id: ChatId, mode: DeliveryMode) -> DBResult<()>;
+    /// Задать, сколько участников может быть в чате, None - как во всем развертывании
+    ///
+    /// Уже вступившие участники остаются в чате, даже если их больше
+    async fn set_member_limit(&self, chat_id: ChatId, max_members: Option<u32>) -> DBResult<()>;
+    /// Настройки уведомлений пользователя в чате, по умолчанию - уведомлять обо всем
+    ///
+    /// Участие в чате не проверяется: настройки нужны при рассылке уведомлений
+    async fn get_notification_settings(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<NotificationSettings>;
+    /// Меняет настройки уведомлений пользователя в чате, в котором он состоит
+    ///
+    /// Отключение уведомлений не меняется, для него есть set_mute
+    async fn set_notification_settings(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        settings: NotificationSettings,
+    ) -> DBResult<()>;
+    /// Настройки уведомлений пользователя во всех чатах, где он их менял
+    async fn get_all_notification_settings(
+        &self,
+        user_id: UserId,
+    ) -> DBResult<HashMap<ChatId, NotificationSettings>>;
+    /// Отключает уведомления пользователя в чате, в котором он состоит, None - включает обратно
+    async fn set_mute(&self, user_id: UserId, chat_id: ChatId, mute: Option<Mute>) -> DBResult<()>;
+    /// Общие настройки уведомлений пользователя, если он их не менял - настройки по умолчанию
+    async fn get_user_preferences(&self, user_id: UserId) -> DBResult<UserPreferences>;
+    /// Заменяет общие настройки уведомлений пользователя целиком
+    async fn set_user_preferences(
+        &self,
+        user_id: UserId,
+        preferences: UserPreferences,
+    ) -> DBResult<()>;
+    /// Возвращает общим настройкам уведомлений пользователя значения по умолчанию
+    async fn delete_user_preferences(&self, user_id: UserId) -> DBResult<()>;
+    /// Средний размер сообщения чата в байтах (в JSON), None - если сообщений еще не было
+    ///
+    /// Размер приблизительный: правки и удаления сообщений в нем не учитываются
+    async fn get_average_message_size(&self, chat_id: ChatId) -> DBResult<Option<u64>>;
+    /// Примерное число сообщений чата: удаления и исчезающие сообщения в нем не учитываются
+    async fn get_message_count(&self, chat_id: ChatId) -> DBResult<u64>;
+    /// Сколько чат занимает в базе и хранилище, для решений о сроке хранения
+    async fn get_chat_usage(&self, chat_id: ChatId) -> DBResult<data::ChatUsage>;
+    /// Страница ленты событий чата по возрастанию позиции, начиная после since_position
+    ///
+    /// Участие в чате не проверяется. Вместе со страницей возвращается позиция, после
+    /// которой начинается следующая, если она может быть
+    async fn get_chat_events(
+        &self,
+        chat_id: ChatId,
+        since_position: Option<i64>,
+        page_size: usize,
+    ) -> DBResult<(Vec<ChatEvent>, Option<i64>)>;
+    /// Число участников чата
+    async fn get_member_count(&self, chat_id: ChatId) -> DBResult<u64>;
+    /// Запоминает, докуда пользователь прочитал чат
+    ///
+    /// Отметка о более раннем сообщении не заменяет отметку о более позднем
+    async fn save_read_position(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        position: ReadPosition,
+    ) -> DBResult<()>;
+    /// Докуда пользователь прочитал каждый чат, в котором он что-то читал
+    async fn get_read_positions(&self, user_id: UserId) -> DBResult<HashMap<ChatId, ReadPosition>>;
+    /// Сколько непрочитанных сообщений в каждом чате пользователя
+    async fn get_unread_counts(&self, user_id: UserId) -> DBResult<HashMap<ChatId, i64>>;
+    /// Обнуляет счетчик непрочитанных сообщений пользователя в чате
+    async fn reset_unread(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Черновик пользователя в чате, в котором он состоит, если черновик есть
+    async fn get_draft(&self, user_id: UserId, chat_id: ChatId) -> DBResult<Option<Draft>>;
+    /// Сохраняет черновик пользователя в чате, в котором он состоит, и возвращает его
+    ///
+    /// Пустой текст удаляет черновик, тогда возвращается None
+    async fn save_draft(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        text: String,
+    ) -> DBResult<Option<Draft>>;
+    /// Отправляет в архив чат, в котором пользователь состоит
+    ///
+    /// Архив у каждого участника свой: пользователь остается в чате и получает его сообщения
+    async fn archive_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Возвращает чат, в котором пользователь состоит, из архива
+    async fn unarchive_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Чаты, которые пользователь отправил в архив
+    async fn get_archived_chats(&self, user_id: UserId) -> DBResult<HashSet<ChatId>>;
+    /// Блокирует пользователя blocked_id для user_id
+    ///
+    /// Заблокированный не может создать с user_id чат или пригласить его, а его сообщения
+    /// не рассылаются user_id. Себя заблокировать нельзя
+    async fn block_user(&self, user_id: UserId, blocked_id: UserId) -> DBResult<()>;
+    /// Снимает блокировку, если она была
+    async fn unblock_user(&self, user_id: UserId, blocked_id: UserId) -> DBResult<()>;
+    /// Кого заблокировал пользователь
+    async fn get_blocked_users(&self, user_id: UserId) -> DBResult<HashSet<UserId>>;
+    /// Кто из user_ids заблокировал blocked_id
+    async fn get_blockers(
+        &self,
+        blocked_id: UserId,
+        user_ids: Vec<UserId>,
+    ) -> DBResult<Vec<UserId>>;
+    /// Добавляет contact_id в контакты user_id
+    ///
+    /// Контакты односторонние: добавленный ничего не узнает. Себя и несуществующих
+    /// пользователей добавить нельзя
+    async fn add_contact(&self, user_id: UserId, contact_id: UserId) -> DBResult<()>;
+    /// Убирает пользователя из контактов, если он там был
+    async fn remove_contact(&self, user_id: UserId, contact_id: UserId) -> DBResult<()>;
+    /// Контакты пользователя по возрастанию id
+    async fn get_contacts(&self, user_id: UserId) -> DBResult<Vec<UserId>>;
+    /// Делает пользователя ботом с вебхуком callback_url или меняет адрес вебхука
+    ///
+    /// Возвращает новый секрет подписи запросов, старый перестает действовать
+    async fn set_bot_webhook(&self, user_id: UserId, callback_url: String) -> DBResult<String>;
+    /// Перестает доставлять сообщения на вебхук бота
+    async fn remove_bot_webhook(&self, user_id: UserId) -> DBResult<()>;
+    /// Все зарегистрированные вебхуки ботов
+    async fn get_bot_webhooks(&self) -> DBResult<Vec<BotWebhook>>;
+    /// Проверяет, что пользователь состоит в чате и политика публикации разрешает ему писать
+    async fn check_can_post(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()>;
+    /// Задает, кто может писать в чат (без проверки прав, для служебных задач)
+    async fn set_post_policy(&self, chat_id: ChatId, policy: data::PostPolicy) -> DBResult<()>;
+    /// Задает время жизни новых сообщений чата в секундах, 0 - сообщения не исчезают
+    ///
+    /// Менять его может только создатель чата, на уже отправленные сообщения оно не влияет
+    async fn set_message_ttl(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        ttl_secs: u32,
     ) -> DBResult<()>;
-    async fn exit_chat(&self, user_id: i64, chat_id: uuid::Uuid) -> DBResult<()>;
-    async fn delete_chat(&self, chat_id: uuid::Uuid) -> DBResult<()>;
-    async fn get_chat_info(&self, user_id: i64, chat_id: uuid::Uuid) -> DBResult<data::ChatInfo>;
-    async fn get_user_info(&self, user_id: i64) -> DBResult<UserInfo>;
-    async fn create_new_user(&self, user_id: i64, user_name: String) -> DBResult<UserInfo>;
-    async fn get_user_chats(&self, user_id: i64) -> DBResult<Vec<Uuid>>;
-    async fn get_user_list(&self) -> DBResult<Vec<i64>>;
+    /// Задает язык и метки чата, старые метки заменяются
+    async fn set_chat_labels(&self, chat_id: ChatId, labels: data::ChatLabels) -> DBResult<()>;
+    /// Закрепляет сообщение чата, в котором состоит пользователь, если ему это разрешено
+    ///
+    /// Закрепление с expires_at снимается планировщиком через expire_pins. Если в чате
+    /// уже max_pins закреплений, то самые старые из них снимаются и возвращаются в rotated
+    async fn pin_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+        expires_at: Option<chrono::Duration>,
+        max_pins: usize,
+    ) -> DBResult<data::PinOutcome>;
+    /// Снимает закрепление с сообщения чата, в котором состоит пользователь
+    async fn unpin_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+    ) -> DBResult<data::UnpinnedMessage>;
+    /// Снимает все закрепления, срок которых истек к моменту now, читает таблицу закреплений целиком
+    async fn expire_pins(&self, now: chrono::Duration) -> DBResult<Vec<data::UnpinnedMessage>>;
+    /// Действующие закрепленные сообщения чата, доступны только участникам
+    async fn get_pins(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<Vec<data::PinnedMessage>>;
+    /// Сохраняет описание вложения, загружать вложения могут только участники чата, которым
+    /// разрешено отправлять вложения
+    async fn add_attachment(&self, attachment: data::Attachment) -> DBResult<()>;
+    /// Описание вложения, доступно только участникам чата, в который его загрузили
+    async fn get_attachment(
+        &self,
+        user_id: UserId,
+        attachment_id: uuid::Uuid,
+    ) -> DBResult<data::Attachment>;
+    /// Меняет текст сообщения и возвращает сообщение с новым текстом
+    ///
+    /// Сообщение ищется по id и дате отправки, менять его может только отправитель
+    async fn edit_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+        date: chrono::Duration,
+        msg_text: String,
+    ) -> DBResult<ChatMessage>;
+    /// Находит в тексте сообщения упоминания участников чата (кроме отправителя)
+    async fn find_mentions(
+        &self,
+        sender_id: UserId,
+        chat_id: ChatId,
+        text: &str,
+    ) -> DBResult<Vec<UserId>>;
+    /// Пересылает сообщение из одного чата в другой и возвращает новое сообщение
+    ///
+    /// Пользователь должен состоять в обоих чатах. Вложения копируются в чат назначения,
+    /// а у пересланного дальше сообщения остается первоначальный источник
+    async fn forward_message(
+        &self,
+        user_id: UserId,
+        from_chat_id: ChatId,
+        message_id: uuid::Uuid,
+        to_chat_id: ChatId,
+    ) -> DBResult<ChatMessage>;
+    /// Удаляет сообщение и возвращает его след для рассылки участникам
+    ///
+    /// Удалять сообщение может только отправитель
+    async fn delete_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+    ) -> DBResult<MessageTombstone>;
+    /// Проверяет, что схема базы совпадает с той, которую ожидает код
+    ///
+    /// Возвращает список расхождений, пустой, если все в порядке
+    async fn check_schema(&self) -> DBResult<Vec<String>>;
+    /// Создает чат с заданным id и участниками, если его еще нет (для переноса данных)
+    async fn import_chat(&self, chat: data::ChatInfo) -> DBResult<()>;
+    /// Записывает сообщения с их исходными датами (для переноса данных)
+    ///
+    /// Повторная запись тех же сообщений не создает дубликатов
+    async fn import_messages(&self, chat_id: ChatId, messages: Vec<ChatMessage>) -> DBResult<()>;
+}
+
+/// Строка таблицы сообщений: id, отправитель, дата, текст, дата правки, ответ, вложения
+/// откуда сообщение переслано (чат, сообщение и его отправитель), упомянутые пользователи
+/// и звонок, если это сообщение о звонке (id, что случилось и длительность)
+type MessageRow = (
+    Uuid,
+    i64,
+    chrono::Duration,
+    String,
+    Option<chrono::Duration>,
+    Option<Uuid>,
+    Option<Vec<Uuid>>,
+    Option<Uuid>,
+    Option<Uuid>,
+    Option<i64>,
+    Option<Vec<i64>>,
+    Option<Uuid>,
+    Option<String>,
+    Option<i32>,
+);
+
+/// Строка таблицы пользователей: id, имя, чаты и поля профиля (аватар, о себе, статус)
+type UserRow = (
+    i64,
+    String,
+    Option<Vec<Uuid>>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+fn user_from_row(row: UserRow) -> UserInfo {
+    let (id, name, chats, avatar_url, bio, status) = row;
+    UserInfo {
+        id,
+        name,
+        chats: chats.unwrap_or_default(),
+        profile: UserProfile {
+            avatar_url,
+            bio,
+            status,
+        },
+    }
+}
+
+/// Строка настроек уведомлений: приоритет, звук и отключение уведомлений
+type NotificationRow = (
+    Option<NotificationPriority>,
+    Option<String>,
+    Option<chrono::Duration>,
+    Option<bool>,
+);
+
+/// Сколько вложений может быть у одного сообщения при любой конфигурации: столько id
+/// вложений проверяется одним запросом
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 100;
+
+/// Сколько участников может быть в личном чате
+pub const PRIVATE_CHAT_MEMBERS: u64 = 2;
+
+/// Сколько секунд повторная отправка с тем же client_msg_id считается дубликатом
+pub const CLIENT_MSG_ID_WINDOW_SECS: i32 = 24 * 3600;
+
+/// Отключение уведомлений из колонок muted_until и muted_forever
+fn mute_from_columns(
+    muted_until: Option<chrono::Duration>,
+    muted_forever: Option<bool>,
+) -> Option<Mute> {
+    match (muted_forever, muted_until) {
+        (Some(true), _) => Some(Mute::Forever),
+        (_, Some(until)) => Some(Mute::Until(until.into())),
+        _ => None,
+    }
+}
+
+/// Действует ли закрепление в момент now
+fn is_active(pin: &PinnedMessage, now: chrono::Duration) -> bool {
+    pin.expires_at
+        .as_ref()
+        .is_none_or(|expires_at| expires_at.timestamp > now)
+}
+
+/// Строка старой таблицы сообщений чата вместе с оставшимся временем жизни сообщения
+type MovedMessageRow = (
+    Uuid,
+    i64,
+    chrono::Duration,
+    String,
+    Option<chrono::Duration>,
+    Option<Uuid>,
+    Option<Vec<Uuid>>,
+    Option<Uuid>,
+    Option<Uuid>,
+    Option<i64>,
+    Option<Vec<i64>>,
+    Option<Uuid>,
+    Option<String>,
+    Option<i32>,
+    Option<i32>,
+);
+
+/// Отделяет от строки старой таблицы время жизни сообщения
+fn split_moved_row(row: MovedMessageRow) -> (MessageRow, Option<i32>) {
+    let (a, b, c, d, e, f, g, h, i, j, k, l, m, n, ttl) = row;
+    ((a, b, c, d, e, f, g, h, i, j, k, l, m, n), ttl)
+}
+
+/// Сообщение для ленты событий чата, без служебных полей доставки отправителю
+fn message_payload(msg: &ChatMessage) -> serde_json::Value {
+    let msg = ChatMessage {
+        client_msg_id: None,
+        delivery_id: None,
+        ..msg.clone()
+    };
+    serde_json::to_value(msg).unwrap_or_default()
+}
+
+/// Значения для записи сообщения в messages в порядке колонок write_message
+fn message_values(msg: &ChatMessage, ttl: i32) -> Result<SerializedValues, SerializeValuesError> {
+    let mut values = SerializedValues::with_capacity(17);
+    values.add_value(&msg.chat_id)?;
+    values.add_value(&message_bucket(msg.date.timestamp))?;
+    values.add_value(&Timestamp(msg.date.timestamp))?;
+    values.add_value(&msg.message_id)?;
+    values.add_value(&msg.sender_id)?;
+    values.add_value(&msg.msg_text)?;
+    values.add_value(&msg.edited_at.as_ref().map(|date| Timestamp(date.timestamp)))?;
+    values.add_value(&msg.reply_to)?;
+    values.add_value(&msg.attachments)?;
+    let forwarded = msg.forwarded_from.as_ref();
+    values.add_value(&forwarded.map(|from| from.chat_id))?;
+    values.add_value(&forwarded.map(|from| from.message_id))?;
+    values.add_value(&forwarded.map(|from| from.sender_id))?;
+    values.add_value(&msg.mentions)?;
+    let call = msg.call.as_ref();
+    values.add_value(&call.map(|call| call.call_id))?;
+    values.add_value(&call.map(|call| call.kind.as_str()))?;
+    values.add_value(
+        &call
+            .and_then(|call| call.duration_secs)
+            .map(|secs| secs as i32),
+    )?;
+    values.add_value(&ttl)?;
+    Ok(values)
+}
+
+fn message_from_row(chat_id: ChatId, row: MessageRow) -> ChatMessage {
+    let (
+        message_id,
+        sender_id,
+        date,
+        msg_text,
+        edited_at,
+        reply_to,
+        attachments,
+        forwarded_chat_id,
+        forwarded_message_id,
+        forwarded_sender_id,
+        mentions,
+        call_id,
+        call_event,
+        call_duration,
+    ) = row;
+    let forwarded_from = match (forwarded_chat_id, forwarded_message_id, forwarded_sender_id) {
+        (Some(chat_id), Some(message_id), Some(sender_id)) => Some(ForwardedFrom {
+            chat_id,
+            message_id,
+            sender_id,
+        }),
+        _ => None,
+    };
+    let call = match (
+        call_id,
+        call_event.as_deref().and_then(CallEventKind::parse),
+    ) {
+        (Some(call_id), Some(kind)) => Some(CallEvent {
+            call_id,
+            kind,
+            duration_secs: call_duration.map(|secs| secs as u32),
+        }),
+        _ => None,
+    };
+    ChatMessage {
+        chat_id: chat_id.get(),
+        message_id,
+        sender_id,
+        date: date.into(),
+        msg_text,
+        edited_at: edited_at.map(Into::into),
+        reply_to,
+        attachments: attachments.unwrap_or_default(),
+        forwarded_from,
+        mentions: mentions.unwrap_or_default(),
+        client_msg_id: None,
+        delivery_id: None,
+        call,
+    }
 }
 
 pub struct ScyllaDatabase {
     pub client: Session,
     prepared_queries: HashMap<String, PreparedStatement>,
+    keyspace: String,
+    /// Настройки репликации в синтаксисе CQL
+    replication: String,
+    /// Сколько участников может быть в чате без своего ограничения
+    max_chat_members: u32,
+    /// Уплотнение и срок хранения таблицы сообщений
+    message_storage: MessageStorage,
     // prepared_transactions: HashMap<String, Batch>
 }
 
 impl ScyllaDatabase {
+    /// Подключается к базе с пространством ключей по умолчанию
     pub async fn new(host: String, port: u16) -> DBResult<Self> {
+        Self::connect(&DatabaseConfig {
+            host,
+            port,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn connect(config: &DatabaseConfig) -> DBResult<Self> {
+        if !is_valid_keyspace_name(&config.keyspace) {
+            return Err(DBError::LogicError(Box::new(StringError {
+                code: "invalid_keyspace",
+                args: vec![("keyspace", config.keyspace.clone())],
+            })));
+        }
+        let (host, port) = (&config.host, config.port);
         let connection_string = format!("{}:{}", host, port);
         let session: Session = SessionBuilder::new()
             .known_node(connection_string)
             .build()
             .await
             .map_err(|e| DBError::OtherError(Box::new(e)))?;
-        Ok(Self {
+        let db = Self {
             client: session,
             prepared_queries: HashMap::new(),
-        })
+            keyspace: config.keyspace.to_lowercase(),
+            replication: config.replication.to_cql(),
+            max_chat_members: config.max_chat_members,
+            message_storage: config.messages.clone(),
+        };
+        // Пространства ключей может еще не быть, тогда его выберет create_schema
+        if db.use_keyspace().await.is_err() {
+            info!("Keyspace {} does not exist yet", db.keyspace);
+        }
+        Ok(db)
+    }
+
+    async fn use_keyspace(&self) -> DBResult<()> {
+        self.client
+            .use_keyspace(&self.keyspace, false)
+            .await
+            .map_err(query_error)
     }
 
     async fn get_prepared_query(
@@ -184,535 +1882,4453 @@ impl ScyllaDatabase {
             let mut q = Query::new(query_fallback);
             q.set_consistency(scylla::statement::Consistency::One);
             q.set_serial_consistency(Some(SerialConsistency::Serial));
-            self.client
-                .prepare(q)
-                .await
-                .map_err(|e| DBError::QueryError(Box::new(e)))?
+            self.client.prepare(q).await.map_err(query_error)?
         })
     }
-}
 
-#[async_trait::async_trait(?Send)]
-impl Database for ScyllaDatabase {
-    async fn init_db(&self) -> DBResult<()> {
-        let q = self.get_prepared_query("create keyspace", r#"CREATE KEYSPACE IF NOT EXISTS chat WITH replication = {'class': 'NetworkTopologyStrategy', 'replication_factor': 1}"#)
+    /// Создает пространство ключей и все таблицы, если их еще нет
+    async fn create_schema(&self) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "create keyspace",
+                &format!(
+                    "CREATE KEYSPACE IF NOT EXISTS {} WITH replication = {}",
+                    self.keyspace, self.replication
+                ),
+            )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+        self.use_keyspace().await?;
 
         let q = self
             .get_prepared_query(
                 "create users table",
-                r#"CREATE TABLE IF NOT EXISTS chat.users (
+                r#"CREATE TABLE IF NOT EXISTS users (
                 user_id BIGINT PRIMARY KEY,
                 creation_date TIMESTAMP,
                 name TEXT,
-                chats SET<UUID>)"#,
+                chats SET<UUID>,
+                avatar_url TEXT,
+                bio TEXT,
+                status_text TEXT)"#,
             )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
         let q = self
             .get_prepared_query(
                 "create chats table",
-                r#"CREATE TABLE IF NOT EXISTS chat.chats (
+                r#"CREATE TABLE IF NOT EXISTS chats (
                 chat_id UUID PRIMARY KEY,
                 creation_date TIMESTAMP,
                 name TEXT,
                 users SET<BIGINT>,
-                chat_type TEXT)"#,
+                chat_type TEXT,
+                delivery_mode TEXT,
+                post_policy TEXT,
+                language TEXT,
+                labels SET<TEXT>,
+                message_ttl INT,
+                permissions INT,
+                public BOOLEAN,
+                creator_id BIGINT)"#,
             )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        Ok(())
-    }
-    async fn init_db_clear(&self) -> DBResult<()> {
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
         let q = self
-            .get_prepared_query("drop keyspace", r#"DROP KEYSPACE IF EXISTS chat"#)
+            .get_prepared_query(
+                "create chat members table",
+                r#"CREATE TABLE IF NOT EXISTS chat_members (
+                chat_id UUID,
+                user_id BIGINT,
+                role TEXT,
+                PRIMARY KEY (chat_id, user_id))"#,
+            )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-        let q = self.get_prepared_query("create keyspace", r#"CREATE KEYSPACE IF NOT EXISTS chat WITH replication = {'class': 'NetworkTopologyStrategy', 'replication_factor': 1}"#)
+        let q = self
+            .get_prepared_query(
+                "create schema version table",
+                r#"CREATE TABLE IF NOT EXISTS schema_version (
+                id INT PRIMARY KEY,
+                version INT)"#,
+            )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
         let q = self
             .get_prepared_query(
-                "create users table",
-                r#"CREATE TABLE IF NOT EXISTS chat.users (
-                user_id BIGINT PRIMARY KEY,
-                creation_date TIMESTAMP,
-                name TEXT,
-                chats SET<UUID>)"#,
+                "create chat pins table",
+                r#"CREATE TABLE IF NOT EXISTS chat_pins (
+                chat_id UUID,
+                message_id UUID,
+                date TIMESTAMP,
+                pinned_by BIGINT,
+                pinned_at TIMESTAMP,
+                expires_at TIMESTAMP,
+                PRIMARY KEY (chat_id, message_id))"#,
             )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
         let q = self
             .get_prepared_query(
-                "create chats table",
-                r#"CREATE TABLE IF NOT EXISTS chat.chats (
-                chat_id UUID PRIMARY KEY,
-                creation_date TIMESTAMP,
+                "create attachments table",
+                r#"CREATE TABLE IF NOT EXISTS attachments (
+                attachment_id UUID,
+                chat_id UUID,
+                uploader_id BIGINT,
                 name TEXT,
-                users SET<BIGINT>,
-                chat_type TEXT)"#,
+                size BIGINT,
+                mime TEXT,
+                url TEXT,
+                created_at TIMESTAMP,
+                PRIMARY KEY (attachment_id))"#,
             )
             .await?;
 
-        self.client
-            .execute(&q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        Ok(())
-    }
-    async fn add_new_message_to_chat(&self, msg: ChatMessage) -> DBResult<()> {
-        // Готовим транзакцию для вставки сообщения в чат
-        // 1) Проверяем наличие пользователя в чате
-        // 2) Проверяем наличие чата у пользователя
-        // 3) Всавляем сообщение в чат
-        let user_chats = self.get_user_chats(msg.sender_id).await?;
-        if !user_chats.contains(&msg.chat_id) {
-            return Err(DBError::LogicError(Box::new(StringError {
-                msg: "User is not a member of this chat".into(),
-            })));
-        }
-        let i = msg.chat_id.to_string().replace("-", "_");
-        let query_name = format!("add msg to chat_{}", i);
-        let query_body = format!(
-            r#"INSERT INTO chat.chat_{} (message_id, user_id, date, message_text, yes)
-        VALUES (uuid(), ?, toTimestamp(now()), ?, true)"#,
-            i
-        );
-        let q = self.get_prepared_query(&query_name, &query_body).await?;
-
-        // Добавляем сообщение в чат
-        self.client
-            .execute(&q, (msg.sender_id, msg.msg_text))
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        Ok(())
-    }
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-    async fn create_new_chat(
-        &self,
-        user_id: i64,
-        mut invited_users_id: Vec<i64>,
-        chat_type: data::ChatType,
-        chat_name: String,
-    ) -> DBResult<data::ChatInfo> {
-        invited_users_id.push(user_id);
-        let user_list = self.get_user_list().await?;
-        let are_invited_users_registered = invited_users_id
-            .iter()
-            .map(|elem| user_list.contains(elem))
-            .all(|elem| elem);
+        let q = self
+            .get_prepared_query(
+                "create notification settings table",
+                r#"CREATE TABLE IF NOT EXISTS chat_notification_settings (
+                user_id BIGINT,
+                chat_id UUID,
+                priority TEXT,
+                sound TEXT,
+                muted_until TIMESTAMP,
+                muted_forever BOOLEAN,
+                PRIMARY KEY (user_id, chat_id))"#,
+            )
+            .await?;
 
-        if !are_invited_users_registered {
-            return Err(DBError::LogicError(Box::new(StringError {
-                msg: "Invited user is not registered".into(),
-            })));
-        }
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-        // Готовим данные о новом чате
-        let new_chat_id = Uuid::new_v4();
-        let chat_type = match chat_type {
-            ChatType::Private => "private",
-            ChatType::Group => "group",
-            ChatType::Reserved => "reserved",
-        };
+        let q = self
+            .get_prepared_query(
+                "create message stats table",
+                r#"CREATE TABLE IF NOT EXISTS chat_message_stats (
+                chat_id UUID PRIMARY KEY,
+                message_count COUNTER,
+                message_bytes COUNTER,
+                attachment_count COUNTER,
+                attachment_bytes COUNTER)"#,
+            )
+            .await?;
 
-        // Готовим запрос на добавление информации о новом чате в таблицу чатов
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
         let q = self
             .get_prepared_query(
-                "add new chat info",
-                r#"INSERT INTO chat.chats (chat_id, creation_date, name, users, chat_type)
-            VALUES (?, toTimestamp(now()), ?, ?, ?)
-            IF NOT EXISTS"#,
+                "create unread counters table",
+                r#"CREATE TABLE IF NOT EXISTS chat_unread (
+                user_id BIGINT,
+                chat_id UUID,
+                unread COUNTER,
+                PRIMARY KEY (user_id, chat_id))"#,
             )
             .await?;
 
-        // Добавляем информацию о новом чате
-        self.client
-            .execute(&q, (new_chat_id, chat_name, &invited_users_id, chat_type))
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
         let q = self
             .get_prepared_query(
-                "update users chat lists",
-                r#"UPDATE chat.users
-            SET chats = chats + {?}
-            WHERE user_id IN ?"#,
+                "create client message ids table",
+                r#"CREATE TABLE IF NOT EXISTS client_message_ids (
+                chat_id UUID,
+                sender_id BIGINT,
+                client_msg_id TEXT,
+                message_id UUID,
+                date TIMESTAMP,
+                PRIMARY KEY ((chat_id, sender_id), client_msg_id))"#,
             )
             .await?;
 
-        self.client
-            .execute(&q, (new_chat_id, &invited_users_id))
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-        let i = new_chat_id.to_string().replace("-", "_");
-        let q = format!(
-            "CREATE TABLE IF NOT EXISTS chat.chat_{i} \
-            (message_id UUID, \
-            user_id BIGINT, \
-            date TIMESTAMP, \
-            message_text TEXT, \
-            yes BOOLEAN, \
-            PRIMARY KEY (yes, date, message_id)) \
-            WITH CLUSTERING ORDER BY (date desc)"
-        );
+        let q = self
+            .get_prepared_query(
+                "create read positions table",
+                r#"CREATE TABLE IF NOT EXISTS chat_read_positions (
+                user_id BIGINT,
+                chat_id UUID,
+                message_id UUID,
+                date TIMESTAMP,
+                PRIMARY KEY (user_id, chat_id))"#,
+            )
+            .await?;
 
-        // Создаем таблицу сообщений нового чата
-        self.client
-            .query(q, &[])
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-        // Если всё замечательно, то получаем данные о чате из базы
-        let chat_info = self.get_chat_info(user_id, new_chat_id).await?;
-        Ok(chat_info)
-    }
-    async fn add_user_to_chat(
-        &self,
-        user_id: i64,
-        invited_user_id: i64,
-        chat_id: uuid::Uuid,
-    ) -> DBResult<()> {
-        // Проверка приглашенного пользователя на регистрацию
-        let user_list = self.get_user_list().await?;
-        if !user_list.contains(&invited_user_id) || !user_list.contains(&user_id) {
-            return Err(DBError::LogicError(Box::new(StringError {
-                msg: "Invited user is not registered".into(),
-            })));
-        }
+        let q = self
+            .get_prepared_query(
+                "create chat archive table",
+                r#"CREATE TABLE IF NOT EXISTS chat_archive (
+                user_id BIGINT,
+                chat_id UUID,
+                archived_at TIMESTAMP,
+                PRIMARY KEY (user_id, chat_id))"#,
+            )
+            .await?;
 
-        // Проверка наличия чата у пользователя
-        let user_chats = self.get_user_chats(user_id).await?;
-        if !user_chats.contains(&chat_id) {
-            return Err(DBError::LogicError(Box::new(StringError {
-                msg: "User is not a member of this chat".into(),
-            })));
-        }
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-        let q_1 = self
+        let q = self
             .get_prepared_query(
-                "add user to chat",
-                "UPDATE chat.chats \
-             SET users = users + {?} \
-             WHERE chat_id = ? \
-             IF EXISTS",
+                "create drafts table",
+                r#"CREATE TABLE IF NOT EXISTS chat_drafts (
+                user_id BIGINT,
+                chat_id UUID,
+                draft_text TEXT,
+                updated_at TIMESTAMP,
+                PRIMARY KEY (user_id, chat_id))"#,
             )
             .await?;
 
-        let q_2 = self
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        // Индекс имен публичных каналов, имена в разделе отсортированы по name_key,
+        // так что поиск по началу имени - это выборка диапазона
+        let q = self
             .get_prepared_query(
-                "add chat to user",
-                "UPDATE chat.users \
-             SET chats = chats + {?} \
-             WHERE user_id = ? \
-             IF EXISTS",
+                "create public channels table",
+                r#"CREATE TABLE IF NOT EXISTS public_channels (
+                bucket INT,
+                name_key TEXT,
+                chat_id UUID,
+                name TEXT,
+                PRIMARY KEY (bucket, name_key, chat_id))"#,
             )
             .await?;
 
-        self.client
-            .execute(&q_1, (invited_user_id, chat_id))
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        self.client
-            .execute(&q_2, (chat_id, invited_user_id))
-            .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        Ok(())
-    }
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
 
-    async fn exit_chat(&self, user_id: i64, chat_id: uuid::Uuid) -> DBResult<()> {
-        // Готовим транзакцию удаления пользователя
-        // 1) Удаляем пользователя из чата
-        // 2) Удаляем чат из списка пользователя
-        let q_1 = self
+        let q = self
             .get_prepared_query(
-                "delete user from chat",
-                "UPDATE chat.chats \
-             SET users = users - {?} \
-             WHERE chat_id = ? \
-             IF EXISTS",
+                "create chat secrets table",
+                r#"CREATE TABLE IF NOT EXISTS chat_secrets (
+                chat_id UUID,
+                kind TEXT,
+                secret_hash BLOB,
+                PRIMARY KEY (chat_id, kind))"#,
             )
             .await?;
-        let q_2 = self
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        let q = self
             .get_prepared_query(
-                "delete chat from user",
-                "UPDATE chat.users \
-             SET chats = chats - {?} \
-             WHERE user_id = ? \
+                "create user blocks table",
+                r#"CREATE TABLE IF NOT EXISTS user_blocks (
+                user_id BIGINT,
+                blocked_id BIGINT,
+                blocked_at TIMESTAMP,
+                PRIMARY KEY (user_id, blocked_id))"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        let q = self
+            .get_prepared_query(
+                "create user contacts table",
+                r#"CREATE TABLE IF NOT EXISTS user_contacts (
+                user_id BIGINT,
+                contact_id BIGINT,
+                added_at TIMESTAMP,
+                PRIMARY KEY (user_id, contact_id))"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        let q = self
+            .get_prepared_query(
+                "create bot webhooks table",
+                r#"CREATE TABLE IF NOT EXISTS bot_webhooks (
+                user_id BIGINT PRIMARY KEY,
+                callback_url TEXT,
+                secret TEXT,
+                registered_at TIMESTAMP)"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        // Занятые имена пользователей, закрепляются легковесной транзакцией
+        let q = self
+            .get_prepared_query(
+                "create user names table",
+                r#"CREATE TABLE IF NOT EXISTS user_names (
+                name_key TEXT PRIMARY KEY,
+                user_id BIGINT)"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        let q = self
+            .get_prepared_query(
+                "create user preferences table",
+                r#"CREATE TABLE IF NOT EXISTS user_preferences (
+                user_id BIGINT PRIMARY KEY,
+                sound BOOLEAN,
+                mentions_only BOOLEAN,
+                quiet_start INT,
+                quiet_end INT,
+                quiet_utc_offset INT)"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        // Сообщения всех чатов: раздел на чат, внутри - по корзинам суток от новых к старым
+        //
+        // Таблица уплотняется по окнам времени (TWCS), хотя раздел чата лежит во всех окнах.
+        // Это все равно выгодно: каждая SSTable хранит наименьший и наибольший ключ
+        // кластеризации, а корзина - первая колонка кластеризации, поэтому чтение истории
+        // от новых к старым с LIMIT или страницами пропускает окна вне запрошенного отрезка
+        // и обычно заканчивается в свежих окнах. Главное же - истекшие сообщения (общий срок
+        // хранения и исчезающие сообщения) удаляются целыми SSTable, а не переписываются
+        // при уплотнении. Все окна читают только запросы по всему чату: ответы в ветке,
+        // удаление чата и дата самого старого сообщения, они редкие.
+        //
+        // Корзина в ключе раздела ((chat_id, bucket)) ограничила бы размер раздела, но
+        // тогда листать историю пришлось бы по корзинам, а пустые сутки неактивного чата -
+        // запоминать в отдельном индексе; сменить ключ раздела можно только переносом
+        // данных в новую таблицу. Пока раздел чата не упирается в размер, этого не делаем.
+        let extra_columns: String = schema::MESSAGE_COLUMNS
+            .iter()
+            .map(|(column, kind)| format!("{column} {kind}, "))
+            .collect();
+        let q = format!(
+            "CREATE TABLE IF NOT EXISTS messages \
+            (chat_id UUID, \
+            bucket INT, \
+            date TIMESTAMP, \
+            message_id UUID, \
+            user_id BIGINT, \
+            message_text TEXT, \
+            {extra_columns}\
+            PRIMARY KEY (chat_id, bucket, date, message_id)) \
+            WITH CLUSTERING ORDER BY (bucket DESC, date DESC) AND {}",
+            self.message_storage.to_cql()
+        );
+        self.client.query(q, &[]).await.map_err(query_error)?;
+        self.apply_message_storage().await?;
+
+        // Лента событий: раздел на чат, события по возрастанию позиции
+        let q = self
+            .get_prepared_query(
+                "create chat events table",
+                r#"CREATE TABLE IF NOT EXISTS chat_events (
+                chat_id UUID,
+                position BIGINT,
+                event_id UUID,
+                kind TEXT,
+                date TIMESTAMP,
+                payload TEXT,
+                PRIMARY KEY (chat_id, position, event_id))"#,
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        if let Some(version) = self.stored_schema_version().await? {
+            if version < 2 {
+                self.backfill_chat_members().await?;
+            }
+            // В версиях 5, 9, 10, 11 и 24 в таблицы сообщений добавлялись колонки
+            if version < 24 {
+                self.upgrade_messages_tables().await?;
+            }
+            if version < 4 {
+                self.add_missing_columns("chats", &[("delivery_mode", "text")])
+                    .await?;
+            }
+            if version < 7 {
+                self.add_missing_columns(
+                    "chats",
+                    &[("post_policy", "text"), ("creator_id", "bigint")],
+                )
+                .await?;
+            }
+            if version < 8 {
+                self.add_missing_columns(
+                    "chat_pins",
+                    &[("pinned_at", "timestamp"), ("expires_at", "timestamp")],
+                )
+                .await?;
+            }
+            if version < 12 {
+                self.add_missing_columns("chats", &[("language", "text"), ("labels", "set<text>")])
+                    .await?;
+            }
+            if version < 13 {
+                self.add_missing_columns("chats", &[("message_ttl", "int")])
+                    .await?;
+            }
+            // Роли не переносятся: без роли создатель чата считается владельцем
+            if version < 19 {
+                self.add_missing_columns("chat_members", &[("role", "text")])
+                    .await?;
+            }
+            // Без маски у чата разрешения по умолчанию
+            if version < 20 {
+                self.add_missing_columns("chats", &[("permissions", "int")])
+                    .await?;
+            }
+            if version < 21 {
+                self.add_missing_columns(
+                    "chat_notification_settings",
+                    &[("muted_until", "timestamp"), ("muted_forever", "boolean")],
+                )
+                .await?;
+            }
+            // Старые чаты не публичные, пока их не сделали каналами
+            if version < 23 {
+                self.add_missing_columns("chats", &[("public", "boolean")])
+                    .await?;
+            }
+            // Без своего ограничения у чата действует database.max_chat_members
+            if version < 25 {
+                self.add_missing_columns("chats", &[("max_members", "int")])
+                    .await?;
+            }
+            // У старых пользователей профиль не заполнен
+            if version < 26 {
+                self.add_missing_columns(
+                    "users",
+                    &[
+                        ("avatar_url", "text"),
+                        ("bio", "text"),
+                        ("status_text", "text"),
+                    ],
+                )
+                .await?;
+            }
+            // Имена существующих пользователей еще никому не закреплены
+            if version < 30 {
+                self.backfill_user_names().await?;
+            }
+            // Вложения стали учитываться в статистике чатов
+            if version < 31 {
+                self.add_missing_columns(
+                    "chat_message_stats",
+                    &[
+                        ("attachment_count", "counter"),
+                        ("attachment_bytes", "counter"),
+                    ],
+                )
+                .await?;
+                self.backfill_attachment_stats().await?;
+            }
+            // Сообщения переехали из таблиц отдельных чатов в messages
+            if version < 33 {
+                self.migrate_chat_tables().await?;
+            }
+        }
+
+        self.record_schema_version().await
+    }
+
+    /// Версия схемы, записанная в базе, если таблица версий уже есть и в ней есть запись
+    async fn stored_schema_version(&self) -> DBResult<Option<i32>> {
+        let q = self
+            .get_prepared_query(
+                "get schema version",
+                r#"SELECT version FROM schema_version WHERE id = 0"#,
+            )
+            .await?;
+        let version = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(i32,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(|row| row.0);
+        Ok(version)
+    }
+
+    /// Заполняет chat_members из списков участников в chats (переход со схемы версии 1)
+    async fn backfill_chat_members(&self) -> DBResult<()> {
+        info!("Backfilling chat_members table");
+        let q = self
+            .get_prepared_query("get all chat members", "SELECT chat_id, users FROM chats")
+            .await?;
+        let chats: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, Option<Vec<UserId>>)>()
+            .collect();
+        let chats = chats.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        for (chat_id, users) in chats {
+            self.insert_chat_members(chat_id, &users.unwrap_or(vec![]))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Закрепляет имена существующих пользователей (переход со схемы версии 29)
+    ///
+    /// Из пользователей с одинаковыми именами имя достается первому прочитанному,
+    /// остальные сохраняют его, но оно за ними не закреплено
+    async fn backfill_user_names(&self) -> DBResult<()> {
+        info!("Backfilling user_names table");
+        let q = self
+            .get_prepared_query("get all user names", "SELECT user_id, name FROM users")
+            .await?;
+        let users: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId, Option<String>)>()
+            .collect();
+        for (user_id, name) in users.map_err(|e| DBError::OtherError(Box::new(e)))? {
+            let Some(name) = name else { continue };
+            match self.reserve_user_name(user_id, &name).await {
+                Ok(_) => {}
+                Err(DBError::LogicError(e)) => warn!("User {user_id} keeps a duplicate name: {e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Учитывает в статистике чатов уже загруженные вложения (переход со схемы версии 30)
+    ///
+    /// Счетчики не идемпотентны: если переход прервется здесь и запустится снова, часть
+    /// вложений будет учтена дважды
+    async fn backfill_attachment_stats(&self) -> DBResult<()> {
+        info!("Backfilling attachment stats");
+        let q = self
+            .get_prepared_query(
+                "get all attachment sizes",
+                "SELECT chat_id, size FROM attachments",
+            )
+            .await?;
+        let attachments: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, Option<i64>)>()
+            .collect();
+        let mut stats: HashMap<ChatId, (i64, i64)> = HashMap::new();
+        for (chat_id, size) in attachments.map_err(|e| DBError::OtherError(Box::new(e)))? {
+            let (count, bytes) = stats.entry(chat_id).or_default();
+            *count += 1;
+            *bytes += size.unwrap_or(0);
+        }
+        for (chat_id, (count, bytes)) in stats {
+            self.count_attachments(chat_id, count, bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Закрепляет имя за пользователем; true, если закрепили сейчас, а не раньше
+    async fn reserve_user_name(&self, user_id: UserId, name: &str) -> DBResult<bool> {
+        let q = self
+            .get_prepared_query(
+                "reserve user name",
+                "INSERT INTO user_names (name_key, user_id) VALUES (?, ?) IF NOT EXISTS",
+            )
+            .await?;
+        let result = self
+            .client
+            .execute(&q, (name_key(name), user_id))
+            .await
+            .map_err(query_error)?;
+        // Если вставка не прошла, транзакция возвращает уже записанную строку
+        let owner_column = result.get_column_spec("user_id").map(|(index, _)| index);
+        let row = result.rows.unwrap_or_default().into_iter().next();
+        let applied = row
+            .as_ref()
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or(false);
+        if applied {
+            return Ok(true);
+        }
+        let owner = row
+            .zip(owner_column)
+            .and_then(|(row, index)| row.columns.get(index).cloned().flatten())
+            .and_then(|owner| owner.as_bigint());
+        if owner == Some(user_id.get()) {
+            Ok(false)
+        } else {
+            Err(DBError::LogicError(Box::new(NameTakenError {
+                name: name.into(),
+                suggestions: vec![],
+            })))
+        }
+    }
+
+    /// Освобождает имя, если оно закреплено за пользователем
+    async fn release_user_name(&self, user_id: UserId, name: &str) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "release user name",
+                "DELETE FROM user_names WHERE name_key = ? IF user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (name_key(name), user_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Переносит сообщения из таблиц отдельных чатов в messages и удаляет эти таблицы
+    /// (переход со схемы версии 32)
+    ///
+    /// Сообщения переносятся с их датами и оставшимся временем жизни. Таблица чата
+    /// удаляется только после того, как из нее перенесены все сообщения, поэтому
+    /// прерванный переход можно просто запустить снова
+    async fn migrate_chat_tables(&self) -> DBResult<()> {
+        info!("Moving chat messages into messages table");
+        let q = self
+            .get_prepared_query(
+                "get keyspace tables",
+                "SELECT table_name FROM system_schema.tables WHERE keyspace_name = ?",
+            )
+            .await?;
+        let tables: Result<HashSet<_>, _> = self
+            .client
+            .execute(&q, (&self.keyspace,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(String,)>()
+            .map(|row| row.map(|(table,)| table))
+            .collect();
+        let tables = tables.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let q = self
+            .get_prepared_query("get all chat ids", "SELECT chat_id FROM chats")
+            .await?;
+        let chats: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId,)>()
+            .collect();
+        for (chat_id,) in chats.map_err(|e| DBError::OtherError(Box::new(e)))? {
+            let table = format!("chat_{}", chat_id.to_string().replace("-", "_"));
+            if !tables.contains(&table) {
+                continue;
+            }
+            let q = format!(
+                "SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration, TTL(message_text) FROM {table}"
+            );
+            let mut rows = self
+                .client
+                .query_iter(q, &[])
+                .await
+                .map_err(query_error)?
+                .into_typed::<MovedMessageRow>();
+            let mut moved = 0;
+            while let Some(row) = rows.next().await {
+                let (row, ttl) =
+                    split_moved_row(row.map_err(|e| DBError::OtherError(Box::new(e)))?);
+                self.write_message(&message_from_row(chat_id, row), ttl.unwrap_or(0))
+                    .await?;
+                moved += 1;
+            }
+            info!("Moved {moved} messages of chat {chat_id}");
+            self.client
+                .query(format!("DROP TABLE IF EXISTS {table}"), &[])
+                .await
+                .map_err(query_error)?;
+        }
+        Ok(())
+    }
+
+    /// Записывает сообщение в messages, ttl - время жизни в секундах, 0 - бессрочно
+    ///
+    /// Повторная запись того же сообщения перезаписывает строку, а не создает копию
+    async fn write_message(&self, msg: &ChatMessage, ttl: i32) -> DBResult<()> {
+        // Явный TTL 0 отменил бы срок хранения таблицы, поэтому он передается сам
+        let ttl = self.effective_message_ttl(ttl);
+        let q = self
+            .get_prepared_query(
+                "write message",
+                r#"INSERT INTO messages (chat_id, bucket, date, message_id, user_id, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                USING TTL ?"#,
+            )
+            .await?;
+        // Значений больше, чем кортежей, которые умеет передавать драйвер
+        let values = message_values(msg, ttl).map_err(|e| DBError::OtherError(Box::new(e)))?;
+        self.client.execute(&q, values).await.map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Добавляет новые колонки во все таблицы сообщений (переход со схем версий 2, 4, 8, 9 и 10)
+    async fn upgrade_messages_tables(&self) -> DBResult<()> {
+        info!("Adding new columns to chat messages tables");
+        let q = self
+            .get_prepared_query("get all chat ids", "SELECT chat_id FROM chats")
+            .await?;
+        let chats: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId,)>()
+            .collect();
+        for (chat_id,) in chats.map_err(|e| DBError::OtherError(Box::new(e)))? {
+            self.add_message_columns(chat_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Добавляет в таблицу сообщений чата колонки из MESSAGE_COLUMNS, которых в ней нет
+    async fn add_message_columns(&self, chat_id: ChatId) -> DBResult<()> {
+        let table = format!("chat_{}", chat_id.to_string().replace("-", "_"));
+        self.add_missing_columns(&table, schema::MESSAGE_COLUMNS)
+            .await
+    }
+
+    /// Добавляет в таблицу колонки, которых в ней еще нет
+    async fn add_missing_columns(&self, table: &str, new_columns: &[(&str, &str)]) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "get table columns",
+                r#"SELECT column_name FROM system_schema.columns
+                WHERE keyspace_name = ? AND table_name = ?"#,
+            )
+            .await?;
+        let columns: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, (&self.keyspace, table))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(String,)>()
+            .map(|row| row.map(|(column,)| column))
+            .collect();
+        let columns = columns.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        // Таблицы нет - нечего и обновлять
+        if columns.is_empty() {
+            return Ok(());
+        }
+        for (column, kind) in new_columns {
+            if columns.iter().any(|c| c == column) {
+                continue;
+            }
+            self.client
+                .query(format!("ALTER TABLE {table} ADD {column} {kind}"), &[])
+                .await
+                .map_err(query_error)?;
+        }
+        Ok(())
+    }
+
+    /// Записывает участников в chat_members, по которой участники читаются постранично
+    async fn insert_chat_members(&self, chat_id: ChatId, users: &[UserId]) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "insert chat member",
+                "INSERT INTO chat_members (chat_id, user_id) VALUES (?, ?)",
+            )
+            .await?;
+        for user_id in users {
+            self.client
+                .execute(&q, (chat_id, user_id))
+                .await
+                .map_err(query_error)?;
+        }
+        Ok(())
+    }
+
+    /// Пакет записей нового чата: сам чат, чат в списках участников, участники с ролью
+    /// создателя и, для канала, запись в индексе публичных каналов
+    ///
+    /// Пакет затрагивает несколько разделов, поэтому в нем не может быть легких транзакций
+    /// (IF NOT EXISTS): уникальность чата держится на случайном chat_id
+    #[allow(clippy::too_many_arguments)]
+    async fn new_chat_batch(
+        &self,
+        chat_id: ChatId,
+        created_at: chrono::Duration,
+        name: &str,
+        members: &[UserId],
+        chat_type: &str,
+        creator_id: UserId,
+        public: bool,
+    ) -> DBResult<(Batch, Vec<SerializedValues>)> {
+        let add_chat = self
+            .get_prepared_query(
+                "add new chat info",
+                r#"INSERT INTO chats (chat_id, creation_date, name, users, chat_type, creator_id, public)
+            VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+            )
+            .await?;
+        let add_to_user = self
+            .get_prepared_query(
+                "add chat to user",
+                "UPDATE users SET chats = chats + {?} WHERE user_id = ?",
+            )
+            .await?;
+        let add_member = self
+            .get_prepared_query(
+                "insert chat member with role",
+                "INSERT INTO chat_members (chat_id, user_id, role) VALUES (?, ?, ?)",
+            )
+            .await?;
+
+        let mut batch = Batch::new(BatchType::Logged);
+        let mut values = vec![];
+        let serialize = |e: SerializeValuesError| DBError::OtherError(Box::new(e));
+        batch.append_statement(add_chat);
+        values.push(
+            (
+                chat_id,
+                Timestamp(created_at),
+                name,
+                members,
+                chat_type,
+                creator_id,
+                public,
+            )
+                .serialized()
+                .map_err(serialize)?
+                .into_owned(),
+        );
+        for &member in members {
+            let role = (member == creator_id).then_some(ChatRole::Owner.as_str());
+            batch.append_statement(add_to_user.clone());
+            values.push(
+                (chat_id, member)
+                    .serialized()
+                    .map_err(serialize)?
+                    .into_owned(),
+            );
+            batch.append_statement(add_member.clone());
+            values.push(
+                (chat_id, member, role)
+                    .serialized()
+                    .map_err(serialize)?
+                    .into_owned(),
+            );
+        }
+        if public {
+            let list = self
+                .get_prepared_query(
+                    "list public channel",
+                    "INSERT INTO public_channels (bucket, name_key, chat_id, name) VALUES (?, ?, ?, ?)",
+                )
+                .await?;
+            batch.append_statement(list);
+            values.push(
+                (CHANNEL_INDEX_BUCKET, channel_name_key(name), chat_id, name)
+                    .serialized()
+                    .map_err(serialize)?
+                    .into_owned(),
+            );
+        }
+        Ok((batch, values))
+    }
+
+    /// Удаляет все записи чата, который не удалось создать, с меткой времени позже
+    /// write_time - метки пакета создания
+    async fn discard_new_chat(
+        &self,
+        chat_id: ChatId,
+        name: &str,
+        members: &[UserId],
+        public: bool,
+        write_time: i64,
+    ) -> DBResult<()> {
+        let delete_chat = self
+            .get_prepared_query("delete chat info", "DELETE FROM chats WHERE chat_id = ?")
+            .await?;
+        let delete_members = self
+            .get_prepared_query(
+                "delete chat members",
+                "DELETE FROM chat_members WHERE chat_id = ?",
+            )
+            .await?;
+        let remove_from_user = self
+            .get_prepared_query(
+                "remove chat from user",
+                "UPDATE users SET chats = chats - {?} WHERE user_id = ?",
+            )
+            .await?;
+
+        let mut batch = Batch::new(BatchType::Logged);
+        let mut values = vec![];
+        let serialize = |e: SerializeValuesError| DBError::OtherError(Box::new(e));
+        batch.append_statement(delete_chat);
+        values.push((chat_id,).serialized().map_err(serialize)?.into_owned());
+        batch.append_statement(delete_members);
+        values.push((chat_id,).serialized().map_err(serialize)?.into_owned());
+        for &member in members {
+            batch.append_statement(remove_from_user.clone());
+            values.push(
+                (chat_id, member)
+                    .serialized()
+                    .map_err(serialize)?
+                    .into_owned(),
+            );
+        }
+        if public {
+            let unlist = self
+                .get_prepared_query(
+                    "unlist public channel",
+                    "DELETE FROM public_channels WHERE bucket = ? AND name_key = ? AND chat_id = ?",
+                )
+                .await?;
+            batch.append_statement(unlist);
+            values.push(
+                (CHANNEL_INDEX_BUCKET, channel_name_key(name), chat_id)
+                    .serialized()
+                    .map_err(serialize)?
+                    .into_owned(),
+            );
+        }
+        batch.set_timestamp(Some(write_time + 1));
+        self.client
+            .batch(&batch, values)
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Записывает текущую версию схемы, но никогда не понижает уже записанную
+    async fn record_schema_version(&self) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "insert schema version",
+                r#"INSERT INTO schema_version (id, version) VALUES (0, ?) IF NOT EXISTS"#,
+            )
+            .await?;
+        self.client
+            .execute(&q, (schema::SCHEMA_VERSION,))
+            .await
+            .map_err(query_error)?;
+
+        let q = self
+            .get_prepared_query(
+                "upgrade schema version",
+                r#"UPDATE schema_version SET version = ? WHERE id = 0 IF version < ?"#,
+            )
+            .await?;
+        self.client
+            .execute(&q, (schema::SCHEMA_VERSION, schema::SCHEMA_VERSION))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Добавляет пользователя в чат без каких-либо проверок
+    async fn add_member(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        self.ensure_room_for(user_id, chat_id).await?;
+        let q_1 = self
+            .get_prepared_query(
+                "add user to chat",
+                "UPDATE chats \
+             SET users = users + {?} \
+             WHERE chat_id = ? \
              IF EXISTS",
             )
             .await?;
-
+
+        let q_2 = self
+            .get_prepared_query(
+                "add chat to user",
+                "UPDATE users \
+             SET chats = chats + {?} \
+             WHERE user_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+
+        self.client
+            .execute(&q_1, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        self.client
+            .execute(&q_2, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        self.insert_chat_members(chat_id, &[user_id]).await?;
+        self.append_event(
+            chat_id,
+            ChatEventKind::MemberJoined,
+            serde_json::json!({ "user_id": user_id.get() }),
+            0,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Сколько участников может быть в чате: его собственное ограничение или общее,
+    /// и вид чата
+    async fn member_limit(&self, chat_id: ChatId) -> DBResult<(u32, Option<ChatType>)> {
+        let q = self
+            .get_prepared_query(
+                "get chat member limit",
+                "SELECT max_members, chat_type FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let row = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<i32>, Option<ChatType>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let (limit, chat_type) = row.unwrap_or((None, None));
+        let limit = limit.map_or(self.max_chat_members, |limit| limit.max(0) as u32);
+        Ok((limit, chat_type))
+    }
+
+    /// Проверяет, что user_id может вступить в чат, не превысив ограничение числа участников.
+    /// В личном чате не больше двух участников, пригласить в него третьего нельзя
+    ///
+    /// Тот, кто уже состоит в чате, ничего не меняет и проходит всегда
+    async fn ensure_room_for(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        if self.get_user_chats(user_id).await?.contains(&chat_id) {
+            return Ok(());
+        }
+        let (limit, chat_type) = self.member_limit(chat_id).await?;
+        let member_count = self.get_member_count(chat_id).await?;
+        if chat_type == Some(ChatType::Private) && member_count >= PRIVATE_CHAT_MEMBERS {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "private_chat_members",
+            ))));
+        }
+        if member_count >= limit as u64 {
+            return Err(DBError::LogicError(Box::new(MemberLimitError { limit })));
+        }
+        Ok(())
+    }
+
+    /// Имя чата, если чат - публичный канал
+    async fn public_channel_name(&self, chat_id: ChatId) -> DBResult<Option<String>> {
+        let q = self
+            .get_prepared_query(
+                "get public channel name",
+                "SELECT name, public FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let row = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<String>, Option<bool>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        Ok(match row {
+            Some((name, Some(true))) => Some(name.unwrap_or_default()),
+            _ => None,
+        })
+    }
+
+    /// Добавляет канал в индекс имен публичных каналов
+    async fn list_channel(&self, chat_id: ChatId, name: &str) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "list public channel",
+                "INSERT INTO public_channels (bucket, name_key, chat_id, name) VALUES (?, ?, ?, ?)",
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (CHANNEL_INDEX_BUCKET, channel_name_key(name), chat_id, name),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Убирает канал из индекса имен публичных каналов
+    async fn unlist_channel(&self, chat_id: ChatId, name: &str) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "unlist public channel",
+                "DELETE FROM public_channels WHERE bucket = ? AND name_key = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (CHANNEL_INDEX_BUCKET, channel_name_key(name), chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Учитывает новое сообщение размером message_bytes в статистике чата
+    async fn count_message(&self, chat_id: ChatId, message_bytes: usize) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "count chat message",
+                "UPDATE chat_message_stats SET message_count = message_count + 1, \
+                message_bytes = message_bytes + ? WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (Counter(message_bytes as i64), chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Учитывает count новых вложений общим размером bytes в статистике чата
+    async fn count_attachments(&self, chat_id: ChatId, count: i64, bytes: i64) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "count chat attachments",
+                "UPDATE chat_message_stats SET attachment_count = attachment_count + ?, \
+                attachment_bytes = attachment_bytes + ? WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (Counter(count), Counter(bytes), chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Срок жизни сообщения в секундах: срок чата, а если его нет - общий срок хранения
+    /// сообщений из конфигурации (0 - бессрочно)
+    fn effective_message_ttl(&self, chat_ttl: i32) -> i32 {
+        if chat_ttl > 0 {
+            chat_ttl
+        } else {
+            i32::try_from(self.message_storage.default_ttl_secs).unwrap_or(i32::MAX)
+        }
+    }
+
+    /// Приводит уплотнение и срок хранения таблицы сообщений к конфигурации
+    ///
+    /// Настройки меняются, только если отличаются от нынешних, чтобы не менять схему
+    /// при каждом запуске
+    async fn apply_message_storage(&self) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "get messages table options",
+                "SELECT compaction, default_time_to_live FROM system_schema.tables \
+                WHERE keyspace_name = ? AND table_name = 'messages'",
+            )
+            .await?;
+        let current = self
+            .client
+            .execute(&q, (&self.keyspace,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(HashMap<String, String>, Option<i32>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let storage = &self.message_storage;
+        let up_to_date = current.is_some_and(|(compaction, ttl)| {
+            let option = |name: &str| compaction.get(name).map(String::as_str);
+            option("class").is_some_and(|class| class.ends_with("TimeWindowCompactionStrategy"))
+                && option("compaction_window_unit") == Some("DAYS")
+                && option("compaction_window_size")
+                    == Some(&storage.compaction_window_days.max(1).to_string())
+                && ttl.unwrap_or(0) == self.effective_message_ttl(0)
+        });
+        if up_to_date {
+            return Ok(());
+        }
+        info!("Applying message storage settings: {}", storage.to_cql());
+        self.client
+            .query(
+                format!("ALTER TABLE messages WITH {}", storage.to_cql()),
+                &[],
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Записывает событие в ленту чата, ttl - время жизни в секундах, 0 - бессрочно
+    ///
+    /// Лента нужна внешним потребителям, из-за нее изменения в чате не должны теряться,
+    /// поэтому ошибка записи только попадает в лог
+    async fn append_event(
+        &self,
+        chat_id: ChatId,
+        kind: ChatEventKind,
+        payload: serde_json::Value,
+        ttl: i32,
+    ) {
+        let result = async {
+            let q = self
+                .get_prepared_query(
+                    "append chat event",
+                    "INSERT INTO chat_events (chat_id, position, event_id, kind, date, payload) \
+                    VALUES (?, ?, ?, ?, ?, ?) USING TTL ?",
+                )
+                .await?;
+            let (event_id, date) = (Uuid::new_v4(), clock::CLOCK.now());
+            self.client
+                .execute(
+                    &q,
+                    (
+                        chat_id,
+                        event_position(date, event_id),
+                        event_id,
+                        kind.as_str(),
+                        Timestamp(date),
+                        payload.to_string(),
+                        ttl,
+                    ),
+                )
+                .await
+                .map_err(query_error)?;
+            Ok::<_, DBError>(())
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(
+                "Cannot append {} event to chat {chat_id}: {e}",
+                kind.as_str()
+            );
+        }
+    }
+
+    /// Время жизни новых сообщений чата в секундах с учетом общего срока хранения,
+    /// 0 - бессрочно
+    async fn message_ttl(&self, chat_id: ChatId) -> DBResult<i32> {
+        let q = self
+            .get_prepared_query(
+                "get chat message ttl",
+                "SELECT message_ttl FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let ttl = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<i32>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(ttl,)| ttl);
+        Ok(self.effective_message_ttl(ttl.unwrap_or(0)))
+    }
+
+    /// Дата самого старого (oldest) или самого нового сообщения чата
+    async fn edge_message_date(
+        &self,
+        chat_id: ChatId,
+        oldest: bool,
+    ) -> DBResult<Option<chrono::Duration>> {
+        let order = if oldest { "ASC" } else { "DESC" };
+        let q = self
+            .get_prepared_query(
+                &format!("get {order} edge message date"),
+                &format!(
+                    "SELECT date FROM messages WHERE chat_id = ? \
+                    ORDER BY bucket {order}, date {order} LIMIT 1"
+                ),
+            )
+            .await?;
+        let date = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Timestamp,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(|(Timestamp(date),)| date);
+        Ok(date)
+    }
+
+    /// Докуда пользователь прочитал чат, если он его читал
+    async fn get_read_position(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<Option<ReadPosition>> {
+        let q = self
+            .get_prepared_query(
+                "get read position",
+                "SELECT message_id, date FROM chat_read_positions \
+                WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        let position = self
+            .client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Uuid, chrono::Duration)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(|(message_id, date)| ReadPosition {
+                message_id,
+                date: date.into(),
+            });
+        Ok(position)
+    }
+
+    /// Увеличивает счетчики непрочитанных у всех участников чата, кроме отправителя
+    async fn count_unread(&self, chat_id: ChatId, sender_id: UserId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "get chat users",
+                "SELECT users FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let users = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<Vec<i64>>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(users,)| users)
+            .unwrap_or_default();
+        let q = self
+            .get_prepared_query(
+                "increment unread",
+                "UPDATE chat_unread SET unread = unread + 1 WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        let recipients: Vec<(UserId, ChatId)> = users
+            .into_iter()
+            .map(UserId)
+            .filter(|&user_id| user_id != sender_id)
+            .map(|user_id| (user_id, chat_id))
+            .collect();
+        // Большие пакеты нагружают координатор, поэтому в больших чатах делим их на части
+        for chunk in recipients.chunks(UNREAD_BATCH_SIZE) {
+            let mut batch = Batch::new(BatchType::Counter);
+            for _ in chunk {
+                batch.append_statement(q.clone());
+            }
+            self.client
+                .batch(&batch, chunk)
+                .await
+                .map_err(query_error)?;
+        }
+        Ok(())
+    }
+
+    /// Проверяет, что пользователю можно писать в чат, и возвращает время жизни
+    /// сообщений чата в секундах (0 - сообщения не исчезают)
+    async fn check_post_policy(&self, user_id: UserId, chat_id: ChatId) -> DBResult<i32> {
+        let q = self
+            .get_prepared_query(
+                "get chat post policy",
+                "SELECT post_policy, creator_id, message_ttl FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let (policy, creator_id, message_ttl) = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<PostPolicy>, Option<i64>, Option<i32>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .unwrap_or((None, None, None));
+        match policy {
+            Some(PostPolicy::CreatorOnly) if creator_id != Some(user_id.get()) => {
+                return Err(DBError::LogicError(Box::new(StringError::new(
+                    "creator_only_posts",
+                ))));
+            }
+            Some(PostPolicy::AdminsOnly)
+                if !self
+                    .member_role(user_id, chat_id)
+                    .await?
+                    .is_some_and(|role| role.can_manage()) =>
+            {
+                return Err(DBError::LogicError(Box::new(StringError::new(
+                    "admins_only_posts",
+                ))));
+            }
+            _ => {}
+        }
+        Ok(message_ttl.unwrap_or(0))
+    }
+
+    /// Проверяет, что все вложения сообщения загружены в тот же чат
+    async fn check_attachments(&self, chat_id: ChatId, attachments: &[Uuid]) -> DBResult<()> {
+        if attachments.is_empty() {
+            return Ok(());
+        }
+        if attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+            return Err(DBError::LogicError(Box::new(StringError {
+                code: "too_many_attachments",
+                args: vec![("max", MAX_ATTACHMENTS_PER_MESSAGE.to_string())],
+            })));
+        }
+        let q = self
+            .get_prepared_query(
+                "get attachment chats",
+                "SELECT attachment_id, chat_id FROM attachments WHERE attachment_id IN ?",
+            )
+            .await?;
+        let found: Result<HashMap<_, _>, _> = self
+            .client
+            .execute(&q, (attachments,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Uuid, Uuid)>()
+            .collect();
+        let found = found.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        if attachments
+            .iter()
+            .any(|id| found.get(id) != Some(&chat_id.get()))
+        {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "unknown_attachment",
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Все закрепления чата, включая истекшие
+    async fn read_pins(&self, chat_id: ChatId) -> DBResult<Vec<PinnedMessage>> {
+        let q = self
+            .get_prepared_query(
+                "get chat pins",
+                r#"SELECT message_id, date, pinned_by, pinned_at, expires_at
+                FROM chat_pins WHERE chat_id = ?"#,
+            )
+            .await?;
+        let pins: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(
+                Uuid,
+                chrono::Duration,
+                i64,
+                Option<chrono::Duration>,
+                Option<chrono::Duration>,
+            )>()
+            .map(|row| {
+                row.map(
+                    |(message_id, date, pinned_by, pinned_at, expires_at)| PinnedMessage {
+                        message_id,
+                        date: date.into(),
+                        pinned_by,
+                        // У закреплений из версии схемы 7 времени закрепления нет
+                        pinned_at: pinned_at.unwrap_or(date).into(),
+                        expires_at: expires_at.map(Into::into),
+                    },
+                )
+            })
+            .collect();
+        pins.map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn remove_pin(&self, chat_id: ChatId, message_id: uuid::Uuid) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "unpin message",
+                "DELETE FROM chat_pins WHERE chat_id = ? AND message_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, message_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// Ищет сообщение чата по id
+    ///
+    /// Дата отправки неизвестна, поэтому сообщение ищется перебором,
+    /// но только внутри раздела чата в messages
+    async fn find_message(&self, chat_id: ChatId, message_id: uuid::Uuid) -> DBResult<ChatMessage> {
+        let q = self
+            .get_prepared_query(
+                "find message",
+                r#"SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration FROM messages
+                WHERE chat_id = ? AND message_id = ? ALLOW FILTERING"#,
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, message_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<MessageRow>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(|row| message_from_row(chat_id, row))
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "message_not_found",
+            ))))
+    }
+
+    /// Проверяет, что пользователь состоит в чате
+    /// Роль участника чата, None - пользователь в чате не состоит
+    ///
+    /// У участников, добавленных до появления ролей, роли нет: создатель чата считается
+    /// владельцем, остальные - обычными участниками
+    async fn member_role(&self, user_id: UserId, chat_id: ChatId) -> DBResult<Option<ChatRole>> {
+        let q = self
+            .get_prepared_query(
+                "get member role",
+                "SELECT role FROM chat_members WHERE chat_id = ? AND user_id = ?",
+            )
+            .await?;
+        let row = self
+            .client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<ChatRole>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let Some((role,)) = row else {
+            return Ok(None);
+        };
+        if let Some(role) = role {
+            return Ok(Some(role));
+        }
+        let q = self
+            .get_prepared_query(
+                "get chat creator",
+                "SELECT creator_id FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let creator_id = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<i64>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|row| row.0);
+        Ok(Some(if creator_id == Some(user_id.get()) {
+            ChatRole::Owner
+        } else {
+            ChatRole::Member
+        }))
+    }
+
+    /// Проверяет, что пользователь - владелец или администратор чата
+    async fn check_manager(&self, user_id: UserId, chat_id: ChatId) -> DBResult<ChatRole> {
+        match self.member_role(user_id, chat_id).await? {
+            Some(role) if role.can_manage() => Ok(role),
+            Some(_) => Err(DBError::LogicError(Box::new(StringError::new(
+                "admins_only",
+            )))),
+            None => Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            )))),
+        }
+    }
+
+    /// Разрешения чата для обычных участников
+    async fn chat_permissions(&self, chat_id: ChatId) -> DBResult<ChatPermissions> {
+        let q = self
+            .get_prepared_query(
+                "get chat permissions",
+                "SELECT permissions FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        Ok(self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<ChatPermissions>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|row| row.0)
+            .unwrap_or_default())
+    }
+
+    /// Проверяет, что пользователь - владелец или администратор чата, либо участник,
+    /// которому в чате разрешено permission
+    async fn check_permission(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        permission: ChatPermissions,
+    ) -> DBResult<()> {
+        match self.member_role(user_id, chat_id).await? {
+            Some(role) if role.can_manage() => Ok(()),
+            Some(_) if self.chat_permissions(chat_id).await?.contains(permission) => Ok(()),
+            Some(_) => Err(DBError::LogicError(Box::new(StringError::new(
+                "members_not_allowed",
+            )))),
+            None => Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            )))),
+        }
+    }
+
+    async fn write_role(&self, chat_id: ChatId, user_id: UserId, role: ChatRole) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set member role",
+                "UPDATE chat_members SET role = ? WHERE chat_id = ? AND user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (role.as_str(), chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn check_membership(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        let user_chats = self.get_user_chats(user_id).await?;
+        if !user_chats.contains(&chat_id) {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            ))));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Database for ScyllaDatabase {
+    async fn init_db(&self) -> DBResult<()> {
+        self.create_schema().await
+    }
+    async fn init_db_clear(&self) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "drop keyspace",
+                &format!("DROP KEYSPACE IF EXISTS {}", self.keyspace),
+            )
+            .await?;
+
+        self.client.execute(&q, &[]).await.map_err(query_error)?;
+
+        self.create_schema().await
+    }
+    async fn add_new_message_to_chat(&self, msg: ChatMessage) -> DBResult<()> {
+        // Готовим транзакцию для вставки сообщения в чат
+        // 1) Проверяем наличие пользователя в чате
+        // 2) Проверяем наличие чата у пользователя
+        // 3) Всавляем сообщение в чат
+        let user_chats = self.get_user_chats(UserId(msg.sender_id)).await?;
+        if !user_chats.contains(&ChatId(msg.chat_id)) {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            ))));
+        }
+        let message_ttl = self.effective_message_ttl(
+            self.check_post_policy(UserId(msg.sender_id), ChatId(msg.chat_id))
+                .await?,
+        );
+        if !msg.attachments.is_empty() {
+            self.check_permission(
+                UserId(msg.sender_id),
+                ChatId(msg.chat_id),
+                ChatPermissions::POST_MEDIA,
+            )
+            .await?;
+        }
+        self.check_attachments(ChatId(msg.chat_id), &msg.attachments)
+            .await?;
+        let (chat_id, sender_id) = (ChatId(msg.chat_id), UserId(msg.sender_id));
+        let message_bytes = serde_json::to_vec(&msg).map_or(msg.msg_text.len(), |json| json.len());
+
+        // Добавляем сообщение в чат с теми id и датой, с которыми его разослали клиентам,
+        // чтобы клиенты потом могли сослаться на него
+        self.write_message(&msg, message_ttl).await?;
+        // Событие живет столько же, сколько сообщение, чтобы текст не пережил его в ленте
+        self.append_event(
+            chat_id,
+            ChatEventKind::Message,
+            message_payload(&msg),
+            message_ttl,
+        )
+        .await;
+        // Статистика нужна только для подбора размера страниц истории,
+        // из-за нее сообщение не должно теряться
+        if let Err(e) = self.count_message(chat_id, message_bytes).await {
+            warn!("Cannot update message stats of chat {chat_id}: {e}");
+        }
+        if let Err(e) = self.count_unread(chat_id, sender_id).await {
+            warn!("Cannot update unread counters of chat {chat_id}: {e}");
+        }
+        Ok(())
+    }
+
+    async fn create_new_chat(
+        &self,
+        user_id: UserId,
+        mut invited_users_id: Vec<UserId>,
+        chat_type: data::ChatType,
+        chat_name: String,
+    ) -> DBResult<data::ChatInfo> {
+        invited_users_id.push(user_id);
+        let user_list = self.get_user_list().await?;
+        let are_invited_users_registered =
+            invited_users_id.iter().all(|elem| user_list.contains(elem));
+
+        if !are_invited_users_registered {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invitee_not_registered",
+            ))));
+        }
+        // У нового чата своего ограничения еще нет
+        let members: HashSet<UserId> = invited_users_id.iter().copied().collect();
+        if members.len() > self.max_chat_members as usize {
+            return Err(DBError::LogicError(Box::new(MemberLimitError {
+                limit: self.max_chat_members,
+            })));
+        }
+        if chat_type == ChatType::Private && members.len() > PRIVATE_CHAT_MEMBERS as usize {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "private_chat_members",
+            ))));
+        }
+        let invited: Vec<UserId> = members.into_iter().filter(|id| *id != user_id).collect();
+        let mut seen = HashSet::new();
+        invited_users_id.retain(|id| seen.insert(*id));
+        if let Some(&blocker) = self.get_blockers(user_id, invited).await?.first() {
+            return Err(DBError::LogicError(Box::new(BlockedError {
+                user_id: blocker,
+            })));
+        }
+
+        // Готовим данные о новом чате
+        let new_chat_id = ChatId::new_v4();
+        let public = chat_type == ChatType::Channel;
+        let chat_type = match chat_type {
+            ChatType::Private => "private",
+            ChatType::Group => "group",
+            ChatType::Channel => "channel",
+            ChatType::Reserved => "reserved",
+        };
+
+        // Все записи нового чата уходят одним журналируемым пакетом: Scylla применяет его
+        // целиком, даже если координатор упадет посреди записи
+        let created_at = clock::CLOCK.now();
+        let write_time = created_at.num_microseconds().unwrap_or_default();
+        let (mut batch, values) = self
+            .new_chat_batch(
+                new_chat_id,
+                created_at,
+                &chat_name,
+                &invited_users_id,
+                chat_type,
+                user_id,
+                public,
+            )
+            .await?;
+        batch.set_timestamp(Some(write_time));
+        if let Err(e) = self.client.batch(&batch, values).await {
+            metrics::observe_scylla_error(&e);
+            // По таймауту пакет мог попасть в журнал и примениться позже, поэтому удаляем
+            // записи с более поздней меткой времени: она перекроет и отложенную запись
+            let rolled_back = match self
+                .discard_new_chat(
+                    new_chat_id,
+                    &chat_name,
+                    &invited_users_id,
+                    public,
+                    write_time,
+                )
+                .await
+            {
+                Ok(()) => true,
+                Err(rollback) => {
+                    warn!("Cannot roll back chat {new_chat_id}: {rollback}");
+                    false
+                }
+            };
+            return Err(DBError::QueryError(Box::new(ChatCreationError {
+                chat_id: new_chat_id,
+                rolled_back,
+                reason: e.to_string(),
+            })));
+        }
+
+        // Если всё замечательно, то получаем данные о чате из базы
+        let chat_info = self.get_chat_info(user_id, new_chat_id).await?;
+        Ok(chat_info)
+    }
+    async fn add_user_to_chat(
+        &self,
+        user_id: UserId,
+        invited_user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<()> {
+        // Проверка приглашенного пользователя на регистрацию
+        let user_list = self.get_user_list().await?;
+        if !user_list.contains(&invited_user_id) || !user_list.contains(&user_id) {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invitee_not_registered",
+            ))));
+        }
+
+        self.check_permission(user_id, chat_id, ChatPermissions::INVITE)
+            .await?;
+        if !self
+            .get_blockers(user_id, vec![invited_user_id])
+            .await?
+            .is_empty()
+        {
+            return Err(DBError::LogicError(Box::new(BlockedError {
+                user_id: invited_user_id,
+            })));
+        }
+
+        self.add_member(invited_user_id, chat_id).await
+    }
+
+    async fn exit_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        // Готовим транзакцию удаления пользователя
+        // 1) Удаляем пользователя из чата
+        // 2) Удаляем чат из списка пользователя
+        let q_1 = self
+            .get_prepared_query(
+                "delete user from chat",
+                "UPDATE chats \
+             SET users = users - {?} \
+             WHERE chat_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+        let q_2 = self
+            .get_prepared_query(
+                "delete chat from user",
+                "UPDATE users \
+             SET chats = chats - {?} \
+             WHERE user_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+
+        self.client
+            .execute(&q_1, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        self.client
+            .execute(&q_2, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete chat member",
+                "DELETE FROM chat_members WHERE chat_id = ? AND user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete notification settings",
+                "DELETE FROM chat_notification_settings WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete draft",
+                "DELETE FROM chat_drafts WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "unarchive chat",
+                "DELETE FROM chat_archive WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        self.reset_unread(user_id, chat_id).await?;
+        self.append_event(
+            chat_id,
+            ChatEventKind::MemberLeft,
+            serde_json::json!({ "user_id": user_id.get() }),
+            0,
+        )
+        .await;
+
+        // Проверяем, есть ли еще кто-то в данном чате
+        // Если нет, то удаляем его
+
+        let q = self
+            .get_prepared_query(
+                "get chat user count",
+                "SELECT users FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let chat_user_list = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows
+            .ok_or(DBError::QueryError(Box::new(StringError::new("no_rows"))))?
+            .into_typed::<(Option<Vec<i64>>,)>()
+            .next()
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))))?
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .0;
+        match chat_user_list {
+            Some(v) if v.is_empty() => {
+                self.delete_chat(chat_id).await?;
+            }
+            None => {
+                self.delete_chat(chat_id).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    async fn remove_user_from_chat(
+        &self,
+        admin_id: UserId,
+        target_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<()> {
+        let admin_role = self.check_manager(admin_id, chat_id).await?;
+        if target_id == admin_id {
+            return Err(DBError::LogicError(Box::new(StringError::new("use_exit"))));
+        }
+        match self.member_role(target_id, chat_id).await? {
+            None => Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            )))),
+            Some(ChatRole::Owner) => Err(DBError::LogicError(Box::new(StringError::new(
+                "owner_cannot_be_removed",
+            )))),
+            Some(ChatRole::Admin) if admin_role != ChatRole::Owner => Err(DBError::LogicError(
+                Box::new(StringError::new("owner_only_removes_admins")),
+            )),
+            Some(_) => self.exit_chat(target_id, chat_id).await,
+        }
+    }
+    async fn set_permissions(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        permissions: ChatPermissions,
+    ) -> DBResult<()> {
+        self.check_manager(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "set chat permissions",
+                "UPDATE chats SET permissions = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q, (permissions.0 as i32, chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+    async fn set_role(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        target_id: UserId,
+        role: ChatRole,
+    ) -> DBResult<()> {
+        if self.member_role(user_id, chat_id).await? != Some(ChatRole::Owner) {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "owner_only_changes_roles",
+            ))));
+        }
+        if target_id == user_id {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "owner_role_fixed",
+            ))));
+        }
+        if self.member_role(target_id, chat_id).await?.is_none() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            ))));
+        }
+        self.write_role(chat_id, target_id, role).await?;
+        if role == ChatRole::Owner {
+            self.write_role(chat_id, user_id, ChatRole::Admin).await?;
+        }
+        Ok(())
+    }
+    async fn rename_chat(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        new_name: String,
+    ) -> DBResult<()> {
+        self.check_permission(user_id, chat_id, ChatPermissions::CHANGE_INFO)
+            .await?;
+        let listed_name = self.public_channel_name(chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "rename chat",
+                "UPDATE chats SET name = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(&q, (&new_name, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(bool,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .is_some_and(|row| row.0);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        if let Some(old_name) = listed_name {
+            self.unlist_channel(chat_id, &old_name).await?;
+            self.list_channel(chat_id, &new_name).await?;
+        }
+        self.append_event(
+            chat_id,
+            ChatEventKind::ChatRenamed,
+            serde_json::json!({ "name": new_name }),
+            0,
+        )
+        .await;
+        Ok(())
+    }
+    async fn delete_chat(&self, chat_id: ChatId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "delete chat message stats",
+                "DELETE FROM chat_message_stats WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        if let Some(name) = self.public_channel_name(chat_id).await? {
+            self.unlist_channel(chat_id, &name).await?;
+        }
+        let q_1 = self
+            .get_prepared_query(
+                "delete chat record from chats",
+                "DELETE FROM chats WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q_1, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete all chat members",
+                "DELETE FROM chat_members WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete chat secrets",
+                "DELETE FROM chat_secrets WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete chat pins",
+                "DELETE FROM chat_pins WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete chat events",
+                "DELETE FROM chat_events WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        let q_2 = self
+            .get_prepared_query(
+                "delete chat history",
+                "DELETE FROM messages WHERE chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q_2, (chat_id,))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_chat_info(&self, user_id: UserId, chat_id: ChatId) -> DBResult<data::ChatInfo> {
+        let query_body = "SELECT chat_id, name, users, chat_type, delivery_mode, post_policy, language, labels, message_ttl, permissions FROM chats WHERE chat_id = ? AND users CONTAINS ? ALLOW FILTERING";
+        let q = self.get_prepared_query("get chat info", query_body).await?;
+        let chat_info = self
+            .client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?
+            .rows
+            .ok_or(DBError::QueryError(Box::new(StringError::new("no_rows"))))?
+            .into_typed::<(
+                Uuid,
+                String,
+                Option<Vec<i64>>,
+                ChatType,
+                Option<DeliveryMode>,
+                Option<PostPolicy>,
+                Option<String>,
+                Option<Vec<String>>,
+                Option<i32>,
+                Option<ChatPermissions>,
+            )>()
+            .next()
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            ))))?
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let users = chat_info.2.unwrap_or(vec![]);
+        Ok(ChatInfo {
+            id: chat_info.0,
+            name: chat_info.1,
+            member_count: users.len(),
+            users,
+            chat_type: chat_info.3,
+            delivery_mode: chat_info.4,
+            notifications: self.get_notification_settings(user_id, chat_id).await?,
+            post_policy: chat_info.5.unwrap_or_default(),
+            labels: ChatLabels {
+                language: chat_info.6,
+                labels: chat_info.7.unwrap_or_default(),
+            },
+            message_ttl_secs: chat_info.8.filter(|&ttl| ttl > 0).map(|ttl| ttl as u32),
+            last_read: self.get_read_position(user_id, chat_id).await?,
+            role: self
+                .member_role(user_id, chat_id)
+                .await?
+                .unwrap_or_default(),
+            permissions: chat_info.9.unwrap_or_default(),
+        })
+    }
+    async fn get_chat_history_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        page_size: usize,
+        paging_index: Option<PageIndex>,
+    ) -> DBResult<(Vec<ChatMessage>, PageIndex)> {
+        // Чтобы получить сообщения чата, необходимо:
+        // 1) Проверить, есть ли пользователь в чате
+        // 2) Получить только часть данных
+        // 3) Отправить ее
+        let user_chats = self.get_user_chats(user_id).await?;
+        if !user_chats.contains(&chat_id) {
+            Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_member",
+            ))))?;
+        }
+        let mut q = self
+            .get_prepared_query(
+                "get chat messages",
+                r#"SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration FROM messages
+                WHERE chat_id = ?"#,
+            )
+            .await?;
+        q.set_page_size(page_size as i32);
+
+        let current_page = if let Some(index) = paging_index {
+            let paging_index: Option<Bytes> = index.into();
+            self.client
+                .execute_paged(&q, (chat_id,), paging_index)
+                .await
+                .map_err(query_error)?
+        } else {
+            self.client
+                .execute(&q, (chat_id,))
+                .await
+                .map_err(query_error)?
+        };
+
+        let next_index = PageIndex::from(current_page.paging_state);
+        // let next_index = to_string(&next_index).unwrap();
+
+        let messages: Result<Vec<_>, _> = current_page
+            .rows
+            .ok_or(DBError::QueryError(Box::new(StringError::new("no_rows"))))?
+            .into_typed::<MessageRow>()
+            .collect();
+        let messages: Vec<_> = messages
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .into_iter()
+            .map(|row| message_from_row(chat_id, row))
+            .collect();
+        Ok((messages, next_index))
+    }
+    async fn get_thread_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+        page_size: usize,
+        paging_index: Option<PageIndex>,
+    ) -> DBResult<(Vec<ChatMessage>, PageIndex)> {
+        self.check_membership(user_id, chat_id).await?;
+        // Ответы ищутся перебором внутри раздела чата в messages
+        let mut q = self
+            .get_prepared_query(
+                "get thread messages",
+                r#"SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration FROM messages
+                WHERE chat_id = ? AND reply_to = ? ALLOW FILTERING"#,
+            )
+            .await?;
+        q.set_page_size(page_size as i32);
+        let paging_index: Option<Bytes> = paging_index.and_then(|index| index.into());
+        let current_page = self
+            .client
+            .execute_paged(&q, (chat_id, message_id), paging_index)
+            .await
+            .map_err(query_error)?;
+        let next_index = PageIndex::from(current_page.paging_state.clone());
+        let messages: Result<Vec<_>, _> = current_page
+            .rows_typed_or_empty::<MessageRow>()
+            .map(|row| row.map(|row| message_from_row(chat_id, row)))
+            .collect();
+        let messages = messages.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        Ok((messages, next_index))
+    }
+    async fn get_chat_history_before(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        before: Option<chrono::Duration>,
+        limit: usize,
+    ) -> DBResult<Vec<ChatMessage>> {
+        self.check_membership(user_id, chat_id).await?;
+        // Корзина растет вместе с датой, поэтому сравнение пар - то же, что сравнение дат
+        let q = self
+            .get_prepared_query(
+                "get chat messages before",
+                r#"SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration FROM messages
+                WHERE chat_id = ? AND (bucket, date) < (?, ?) LIMIT ?"#,
+            )
+            .await?;
+        // Без before берем все, что старше текущего момента
+        let before = before.unwrap_or_else(|| chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH);
+        let messages: Result<Vec<_>, _> = self
+            .client
+            .execute(
+                &q,
+                (
+                    chat_id,
+                    message_bucket(before),
+                    Timestamp(before),
+                    limit as i32,
+                ),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<MessageRow>()
+            .map(|row| row.map(|row| message_from_row(chat_id, row)))
+            .collect();
+        messages.map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn get_user_info(&self, user_id: UserId) -> DBResult<UserInfo> {
+        let q = self
+            .get_prepared_query(
+                "get user info",
+                r#"SELECT user_id, name, chats, avatar_url, bio, status_text from users WHERE user_id = ?"#,
+            )
+            .await?;
+        let user_info = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows
+            .ok_or(DBError::QueryError(Box::new(StringError::new("no_rows"))))?
+            .into_typed::<UserRow>()
+            .next()
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))))?
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        Ok(user_from_row(user_info))
+    }
+    async fn get_user_creation_date(&self, user_id: UserId) -> DBResult<chrono::Duration> {
+        let q = self
+            .get_prepared_query(
+                "get user creation date",
+                "SELECT creation_date FROM users WHERE user_id = ?",
+            )
+            .await?;
+        let (creation_date,) = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<chrono::Duration>,)>()
+            .next()
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))))?
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        // У пользователей, созданных до появления даты создания, аккаунт давно не новый
+        Ok(creation_date.unwrap_or_else(chrono::Duration::zero))
+    }
+    async fn create_new_user(&self, user_id: UserId, user_name: String) -> DBResult<UserInfo> {
+        // Сначала имя, чтобы двое одновременно авторизующихся не получили одно имя
+        let reserved = self.reserve_user_name(user_id, &user_name).await?;
+        let q = self
+            .get_prepared_query(
+                "create new user",
+                r#"INSERT INTO users (user_id, creation_date, name, chats)
+               VALUES (?, ?, ?, {})
+               IF NOT EXISTS"#,
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(
+                &q,
+                (user_id, Timestamp(clock::CLOCK.now()), user_name.clone()),
+            )
+            .await
+            .map_err(query_error)?
+            .rows
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or(false);
+        // Пользователь уже был со своим именем, а новое закреплять не за кем
+        if !applied && reserved {
+            self.release_user_name(user_id, &user_name).await?;
+        }
+        let user_info = self.get_user_info(user_id).await?;
+        Ok(user_info)
+    }
+    async fn rename_user(&self, user_id: UserId, new_name: String) -> DBResult<UserInfo> {
+        let old_name = self.get_user_info(user_id).await?.name;
+        // Смена регистра имени не меняет, кому оно закреплено
+        let key_changed = name_key(&old_name) != name_key(&new_name);
+        let reserved = key_changed && self.reserve_user_name(user_id, &new_name).await?;
+        let q = self
+            .get_prepared_query(
+                "rename user",
+                "UPDATE users SET name = ? WHERE user_id = ? IF EXISTS",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(&q, (new_name.clone(), user_id))
+            .await
+            .map_err(query_error)?
+            .rows
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or(false);
+        if !applied {
+            if reserved {
+                self.release_user_name(user_id, &new_name).await?;
+            }
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))));
+        }
+        if key_changed {
+            self.release_user_name(user_id, &old_name).await?;
+        }
+        self.get_user_info(user_id).await
+    }
+    async fn taken_names(&self, names: Vec<String>) -> DBResult<HashSet<String>> {
+        let q = self
+            .get_prepared_query(
+                "get taken names",
+                "SELECT name_key FROM user_names WHERE name_key IN ?",
+            )
+            .await?;
+        let keys: Vec<String> = names.iter().map(|name| name_key(name)).collect();
+        self.client
+            .execute(&q, (keys,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(String,)>()
+            .map(|row| row.map(|(key,)| key))
+            .collect::<Result<_, _>>()
+            .map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+    async fn set_user_profile(&self, user_id: UserId, profile: data::UserProfile) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set user profile",
+                r#"UPDATE users SET avatar_url = ?, bio = ?, status_text = ?
+                WHERE user_id = ? IF EXISTS"#,
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(
+                &q,
+                (profile.avatar_url, profile.bio, profile.status, user_id),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(bool,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .is_some_and(|row| row.0);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))));
+        }
+        Ok(())
+    }
+    async fn get_user_chats(&self, user_id: UserId) -> DBResult<Vec<ChatId>> {
+        let q = self
+            .get_prepared_query(
+                "get user chats",
+                r#"SELECT chats FROM users WHERE user_id = ?"#,
+            )
+            .await?;
+        let chats = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows
+            .ok_or(DBError::QueryError(Box::new(StringError::new("no_rows"))))?
+            .into_typed::<(Option<Vec<ChatId>>,)>()
+            .next()
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))))?
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .0;
+        Ok(chats.unwrap_or(vec![]))
+    }
+
+    async fn get_user_list(&self) -> DBResult<Vec<UserId>> {
+        let q = self
+            .get_prepared_query("get user list", r#"SELECT user_id FROM users"#)
+            .await?;
+        let user_list: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId,)>()
+            .map(|elem| match elem {
+                Ok(id) => Ok(id.0),
+                Err(e) => Err(e),
+            })
+            .collect();
+        let user_list = user_list.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        Ok(user_list)
+    }
+
+    async fn get_chat_list(&self) -> DBResult<Vec<data::ChatRecord>> {
+        let q = self
+            .get_prepared_query(
+                "get chat list",
+                "SELECT chat_id, creation_date, users FROM chats",
+            )
+            .await?;
+        let chats: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Uuid, Option<chrono::Duration>, Option<Vec<i64>>)>()
+            .map(|row| {
+                row.map(|(id, creation_date, users)| data::ChatRecord {
+                    id,
+                    creation_date,
+                    users: users.unwrap_or_default(),
+                })
+            })
+            .collect();
+        chats.map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn get_users_with_chats(&self) -> DBResult<Vec<UserInfo>> {
+        let q = self
+            .get_prepared_query(
+                "get users with chats",
+                "SELECT user_id, name, chats, avatar_url, bio, status_text FROM users",
+            )
+            .await?;
+        let users: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<UserRow>()
+            .map(|row| row.map(user_from_row))
+            .collect();
+        users.map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn add_chat_to_user(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "add chat to user",
+                "UPDATE users \
+             SET chats = chats + {?} \
+             WHERE user_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn remove_chat_from_user(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "delete chat from user",
+                "UPDATE users \
+             SET chats = chats - {?} \
+             WHERE user_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn drop_chat_member(&self, chat_id: ChatId, user_id: UserId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "delete user from chat",
+                "UPDATE chats \
+             SET users = users - {?} \
+             WHERE chat_id = ? \
+             IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        let q = self
+            .get_prepared_query(
+                "delete chat member",
+                "DELETE FROM chat_members WHERE chat_id = ? AND user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, user_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn rotate_chat_secret(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        kind: SecretKind,
+    ) -> DBResult<String> {
+        self.check_manager(user_id, chat_id).await?;
+        let secret = secrets::generate_secret();
+        let q = self
+            .get_prepared_query(
+                "set chat secret",
+                "INSERT INTO chat_secrets (chat_id, kind, secret_hash) VALUES (?, ?, ?)",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, kind.as_str(), secrets::hash_secret(&secret)))
+            .await
+            .map_err(query_error)?;
+        Ok(secret)
+    }
+
+    async fn revoke_chat_secret(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        kind: SecretKind,
+    ) -> DBResult<()> {
+        self.check_manager(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "revoke chat secret",
+                "DELETE FROM chat_secrets WHERE chat_id = ? AND kind = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, kind.as_str()))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn verify_chat_secret(
+        &self,
+        chat_id: ChatId,
+        kind: SecretKind,
+        secret: String,
+    ) -> DBResult<bool> {
+        let q = self
+            .get_prepared_query(
+                "get chat secret",
+                "SELECT secret_hash FROM chat_secrets WHERE chat_id = ? AND kind = ?",
+            )
+            .await?;
+        let stored_hash = self
+            .client
+            .execute(&q, (chat_id, kind.as_str()))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Vec<u8>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?;
+        Ok(match stored_hash {
+            Some((hash,)) => secrets::verify_secret(&secret, &hash),
+            None => false,
+        })
+    }
+
+    async fn join_chat_by_invite(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        invite_code: String,
+    ) -> DBResult<data::ChatInfo> {
+        // Не различаем неверный код и несуществующий чат, чтобы не подсказывать перебором
+        if !self
+            .verify_chat_secret(chat_id, SecretKind::InviteCode, invite_code)
+            .await?
+        {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_invite_code",
+            ))));
+        }
+        // Проверяем, что пользователь зарегистрирован
+        self.get_user_info(user_id).await?;
+        self.add_member(user_id, chat_id).await?;
+        self.get_chat_info(user_id, chat_id).await
+    }
+
+    async fn join_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        // Вид есть у любого созданного чата
+        if self.member_limit(chat_id).await?.1.is_none() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        self.add_member(user_id, chat_id).await
+    }
+
+    async fn join_public_channel(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<data::ChatInfo> {
+        // Закрытый чат не отличаем от несуществующего
+        if self.public_channel_name(chat_id).await?.is_none() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "not_a_channel",
+            ))));
+        }
+        self.get_user_info(user_id).await?;
+        self.add_member(user_id, chat_id).await?;
+        self.get_chat_info(user_id, chat_id).await
+    }
+
+    async fn discover_channels(
+        &self,
+        query: String,
+        limit: usize,
+    ) -> DBResult<Vec<data::ChannelListing>> {
+        // Все ключи, начинающиеся с prefix, лежат между prefix и prefix + наибольший символ
+        let prefix = channel_name_key(&query);
+        let upper = format!("{prefix}{}", char::MAX);
+        let q = self
+            .get_prepared_query(
+                "discover public channels",
+                r#"SELECT chat_id, name FROM public_channels
+                WHERE bucket = ? AND name_key >= ? AND name_key < ? LIMIT ?"#,
+            )
+            .await?;
+        let rows: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, (CHANNEL_INDEX_BUCKET, prefix, upper, limit as i32))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, String)>()
+            .collect();
+        let rows = rows.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let mut channels = Vec::with_capacity(rows.len());
+        for (chat_id, name) in rows {
+            channels.push(data::ChannelListing {
+                id: chat_id.get(),
+                name,
+                member_count: self.get_member_count(chat_id).await?,
+            });
+        }
+        Ok(channels)
+    }
+
+    async fn get_users_info(&self, user_ids: Vec<UserId>) -> DBResult<Vec<UserInfo>> {
+        let q = self
+            .get_prepared_query(
+                "get users info",
+                r#"SELECT user_id, name, chats, avatar_url, bio, status_text FROM users WHERE user_id IN ?"#,
+            )
+            .await?;
+        let users: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, (user_ids,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<UserRow>()
+            .map(|row| row.map(user_from_row))
+            .collect();
+        users.map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn get_user_list_paged(
+        &self,
+        after_token: Option<i64>,
+        page_size: usize,
+    ) -> DBResult<(Vec<UserInfo>, Option<i64>)> {
+        // Страницы режем по токену ключа, а не по paging state драйвера,
+        // так что курсор стабилен и не зависит от соединения
+        let q = self
+            .get_prepared_query(
+                "get user list page",
+                r#"SELECT user_id, name, chats, avatar_url, bio, status_text, token(user_id) FROM users
+                WHERE token(user_id) > ? LIMIT ?"#,
+            )
+            .await?;
+        let rows: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, (after_token.unwrap_or(i64::MIN), page_size as i32))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(
+                i64,
+                String,
+                Option<Vec<Uuid>>,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                i64,
+            )>()
+            .collect();
+        let rows = rows.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let next_token = match rows.last() {
+            Some(row) if rows.len() == page_size => Some(row.6),
+            _ => None,
+        };
+        let users = rows
+            .into_iter()
+            .map(|(id, name, chats, avatar_url, bio, status, _)| {
+                user_from_row((id, name, chats, avatar_url, bio, status))
+            })
+            .collect();
+        Ok((users, next_token))
+    }
+
+    async fn get_chat_members_paged(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        after_user: Option<UserId>,
+        page_size: usize,
+    ) -> DBResult<(Vec<UserId>, Option<UserId>)> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "get chat members page",
+                "SELECT user_id FROM chat_members WHERE chat_id = ? AND user_id > ? LIMIT ?",
+            )
+            .await?;
+        let members: Result<Vec<_>, _> = self
+            .client
+            .execute(
+                &q,
+                (
+                    chat_id,
+                    after_user.unwrap_or(UserId(i64::MIN)),
+                    page_size as i32,
+                ),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId,)>()
+            .map(|row| row.map(|row| row.0))
+            .collect();
+        let members = members.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let next = match members.last() {
+            Some(&last) if members.len() == page_size => Some(last),
+            _ => None,
+        };
+        Ok((members, next))
+    }
+
+    async fn get_delivery_mode(&self, chat_id: ChatId) -> DBResult<Option<DeliveryMode>> {
+        let q = self
+            .get_prepared_query(
+                "get chat delivery mode",
+                "SELECT delivery_mode FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let mode = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<DeliveryMode>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|row| row.0);
+        Ok(mode)
+    }
+
+    async fn set_member_limit(&self, chat_id: ChatId, max_members: Option<u32>) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set chat member limit",
+                "UPDATE chats SET max_members = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(
+                &q,
+                (
+                    max_members.map(|limit| limit.min(i32::MAX as u32) as i32),
+                    chat_id,
+                ),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(bool,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .is_some_and(|row| row.0);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        Ok(())
+    }
+
+    async fn set_delivery_mode(&self, chat_id: ChatId, mode: DeliveryMode) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set chat delivery mode",
+                "UPDATE chats SET delivery_mode = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(&q, (mode.as_str(), chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(bool,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .is_some_and(|row| row.0);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        Ok(())
+    }
+
+    async fn set_message_ttl(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        ttl_secs: u32,
+    ) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "set chat message ttl",
+                "UPDATE chats SET message_ttl = ? WHERE chat_id = ? IF creator_id = ?",
+            )
+            .await?;
+        let ttl = i32::try_from(ttl_secs).map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let applied = self
+            .client
+            .execute(&q, (ttl, chat_id, user_id))
+            .await
+            .map_err(query_error)?
+            .rows
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or(false);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "creator_only_ttl",
+            ))));
+        }
+        Ok(())
+    }
+
+    async fn set_chat_labels(&self, chat_id: ChatId, labels: ChatLabels) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set chat labels",
+                "UPDATE chats SET language = ?, labels = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(&q, (labels.language, labels.labels, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(bool,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .is_some_and(|row| row.0);
+        if !applied {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        Ok(())
+    }
+
+    async fn check_can_post(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        self.check_post_policy(user_id, chat_id).await?;
+        Ok(())
+    }
+
+    async fn set_post_policy(&self, chat_id: ChatId, policy: data::PostPolicy) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set chat post policy",
+                "UPDATE chats SET post_policy = ? WHERE chat_id = ? IF EXISTS",
+            )
+            .await?;
+        self.client
+            .execute(&q, (policy.as_str(), chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn pin_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+        expires_at: Option<chrono::Duration>,
+        max_pins: usize,
+    ) -> DBResult<PinOutcome> {
+        self.check_permission(user_id, chat_id, ChatPermissions::PIN)
+            .await?;
+        let message = self.find_message(chat_id, message_id).await?;
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        // Повторное закрепление того же сообщения только обновляет срок
+        let mut pins: Vec<_> = self
+            .read_pins(chat_id)
+            .await?
+            .into_iter()
+            .filter(|pin| pin.message_id != message_id && is_active(pin, now))
+            .collect();
+        pins.sort_by_key(|pin| pin.pinned_at.timestamp);
+        let excess = (pins.len() + 1).saturating_sub(max_pins.max(1));
+        let mut rotated = vec![];
+        for pin in pins.into_iter().take(excess) {
+            self.remove_pin(chat_id, pin.message_id).await?;
+            rotated.push(UnpinnedMessage {
+                chat_id: chat_id.get(),
+                message_id: pin.message_id,
+                reason: UnpinReason::Rotated,
+            });
+        }
+
+        let q = self
+            .get_prepared_query(
+                "pin message",
+                r#"INSERT INTO chat_pins (chat_id, message_id, date, pinned_by, pinned_at, expires_at)
+                VALUES (?, ?, ?, ?, ?, ?)"#,
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (
+                    chat_id,
+                    message_id,
+                    Timestamp(message.date.timestamp),
+                    user_id,
+                    Timestamp(now),
+                    expires_at.map(Timestamp),
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(PinOutcome {
+            pin: PinnedMessage {
+                message_id,
+                date: message.date,
+                pinned_by: user_id.get(),
+                pinned_at: now.into(),
+                expires_at: expires_at.map(Into::into),
+            },
+            rotated,
+        })
+    }
+
+    async fn unpin_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+    ) -> DBResult<UnpinnedMessage> {
+        self.check_permission(user_id, chat_id, ChatPermissions::PIN)
+            .await?;
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        let pinned = self
+            .read_pins(chat_id)
+            .await?
+            .iter()
+            .any(|pin| pin.message_id == message_id && is_active(pin, now));
+        if !pinned {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "message_not_pinned",
+            ))));
+        }
+        self.remove_pin(chat_id, message_id).await?;
+        Ok(UnpinnedMessage {
+            chat_id: chat_id.get(),
+            message_id,
+            reason: UnpinReason::Manual,
+        })
+    }
+
+    async fn expire_pins(&self, now: chrono::Duration) -> DBResult<Vec<UnpinnedMessage>> {
+        let q = self
+            .get_prepared_query(
+                "get pin expiry",
+                "SELECT chat_id, message_id, expires_at FROM chat_pins",
+            )
+            .await?;
+        let rows: Result<Vec<_>, _> = self
+            .client
+            .execute(&q, &[])
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, Uuid, Option<chrono::Duration>)>()
+            .collect();
+        let rows = rows.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        let mut expired = vec![];
+        for (chat_id, message_id, expires_at) in rows {
+            if expires_at.is_none_or(|expires_at| expires_at > now) {
+                continue;
+            }
+            self.remove_pin(chat_id, message_id).await?;
+            expired.push(UnpinnedMessage {
+                chat_id: chat_id.get(),
+                message_id,
+                reason: UnpinReason::Expired,
+            });
+        }
+        Ok(expired)
+    }
+
+    async fn get_pins(&self, user_id: UserId, chat_id: ChatId) -> DBResult<Vec<PinnedMessage>> {
+        self.check_membership(user_id, chat_id).await?;
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        // Истекшие закрепления не показываются, даже если планировщик их еще не снял
+        let mut pins: Vec<_> = self
+            .read_pins(chat_id)
+            .await?
+            .into_iter()
+            .filter(|pin| is_active(pin, now))
+            .collect();
+        // Новые сообщения сверху, как в истории
+        pins.sort_by_key(|pin| std::cmp::Reverse(pin.date.timestamp));
+        Ok(pins)
+    }
+
+    async fn add_attachment(&self, attachment: Attachment) -> DBResult<()> {
+        self.check_permission(
+            UserId(attachment.uploader_id),
+            ChatId(attachment.chat_id),
+            ChatPermissions::POST_MEDIA,
+        )
+        .await?;
+        let q = self
+            .get_prepared_query(
+                "add attachment",
+                r#"INSERT INTO attachments
+                (attachment_id, chat_id, uploader_id, name, size, mime, url, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (
+                    attachment.id,
+                    attachment.chat_id,
+                    attachment.uploader_id,
+                    attachment.name,
+                    attachment.size as i64,
+                    attachment.mime,
+                    attachment.url,
+                    Timestamp(attachment.created_at.timestamp),
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        // Статистика нужна только администраторам, из-за нее вложение не должно теряться
+        let chat_id = ChatId(attachment.chat_id);
+        let size = attachment.size.min(i64::MAX as u64) as i64;
+        if let Err(e) = self.count_attachments(chat_id, 1, size).await {
+            warn!("Cannot update attachment stats of chat {chat_id}: {e}");
+        }
+        Ok(())
+    }
+
+    async fn get_attachment(
+        &self,
+        user_id: UserId,
+        attachment_id: uuid::Uuid,
+    ) -> DBResult<Attachment> {
+        let q = self
+            .get_prepared_query(
+                "get attachment",
+                r#"SELECT chat_id, uploader_id, name, size, mime, url, created_at
+                FROM attachments WHERE attachment_id = ?"#,
+            )
+            .await?;
+        let not_found = || DBError::LogicError(Box::new(StringError::new("attachment_not_found")));
+        let (chat_id, uploader_id, name, size, mime, url, created_at) = self
+            .client
+            .execute(&q, (attachment_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, i64, String, i64, String, String, chrono::Duration)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .ok_or_else(not_found)?;
+        // Чужие вложения неотличимы от несуществующих
+        self.check_membership(user_id, chat_id)
+            .await
+            .map_err(|_| not_found())?;
+        Ok(Attachment {
+            id: attachment_id,
+            chat_id: chat_id.get(),
+            uploader_id,
+            name,
+            size: size.max(0) as u64,
+            mime,
+            url,
+            created_at: created_at.into(),
+        })
+    }
+
+    async fn get_notification_settings(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+    ) -> DBResult<NotificationSettings> {
+        let q = self
+            .get_prepared_query(
+                "get notification settings",
+                "SELECT priority, sound, muted_until, muted_forever FROM chat_notification_settings \
+                WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        let settings = self
+            .client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<NotificationRow>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(
+                |(priority, sound, muted_until, muted_forever)| NotificationSettings {
+                    priority: priority.unwrap_or_default(),
+                    sound,
+                    mute: mute_from_columns(muted_until, muted_forever),
+                },
+            )
+            .unwrap_or_default();
+        Ok(settings)
+    }
+
+    async fn set_notification_settings(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        settings: NotificationSettings,
+    ) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "set notification settings",
+                "INSERT INTO chat_notification_settings (user_id, chat_id, priority, sound) \
+                VALUES (?, ?, ?, ?)",
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (user_id, chat_id, settings.priority.as_str(), settings.sound),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_all_notification_settings(
+        &self,
+        user_id: UserId,
+    ) -> DBResult<HashMap<ChatId, NotificationSettings>> {
+        let q = self
+            .get_prepared_query(
+                "get all notification settings",
+                "SELECT chat_id, priority, sound, muted_until, muted_forever \
+                FROM chat_notification_settings WHERE user_id = ?",
+            )
+            .await?;
+        let rows = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(
+                ChatId,
+                Option<NotificationPriority>,
+                Option<String>,
+                Option<chrono::Duration>,
+                Option<bool>,
+            )>();
+        let mut settings = HashMap::new();
+        for row in rows {
+            let (chat_id, priority, sound, muted_until, muted_forever) =
+                row.map_err(|e| DBError::OtherError(Box::new(e)))?;
+            settings.insert(
+                chat_id,
+                NotificationSettings {
+                    priority: priority.unwrap_or_default(),
+                    sound,
+                    mute: mute_from_columns(muted_until, muted_forever),
+                },
+            );
+        }
+        Ok(settings)
+    }
+
+    async fn set_mute(&self, user_id: UserId, chat_id: ChatId, mute: Option<Mute>) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        let (muted_until, muted_forever) = match mute {
+            Some(Mute::Until(until)) => (Some(Timestamp(until.timestamp)), None),
+            Some(Mute::Forever) => (None, Some(true)),
+            None => (None, None),
+        };
+        let q = self
+            .get_prepared_query(
+                "set mute",
+                "UPDATE chat_notification_settings SET muted_until = ?, muted_forever = ? \
+                WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (muted_until, muted_forever, user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_user_preferences(&self, user_id: UserId) -> DBResult<UserPreferences> {
+        let q = self
+            .get_prepared_query(
+                "get user preferences",
+                "SELECT sound, mentions_only, quiet_start, quiet_end, quiet_utc_offset \
+                FROM user_preferences WHERE user_id = ?",
+            )
+            .await?;
+        let preferences = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(
+                Option<bool>,
+                Option<bool>,
+                Option<i32>,
+                Option<i32>,
+                Option<i32>,
+            )>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map(
+                |(sound, mentions_only, start, end, offset)| UserPreferences {
+                    sound: sound.unwrap_or(true),
+                    mentions_only: mentions_only.unwrap_or(false),
+                    quiet_hours: start.zip(end).map(|(start, end)| QuietHours {
+                        start_minute: start as u16,
+                        end_minute: end as u16,
+                        utc_offset_minutes: offset.unwrap_or(0) as i16,
+                    }),
+                },
+            )
+            .unwrap_or_default();
+        Ok(preferences)
+    }
+
+    async fn set_user_preferences(
+        &self,
+        user_id: UserId,
+        preferences: UserPreferences,
+    ) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "set user preferences",
+                "INSERT INTO user_preferences (user_id, sound, mentions_only, quiet_start, \
+                quiet_end, quiet_utc_offset) VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .await?;
+        let quiet = preferences.quiet_hours.as_ref();
+        self.client
+            .execute(
+                &q,
+                (
+                    user_id,
+                    preferences.sound,
+                    preferences.mentions_only,
+                    quiet.map(|quiet| quiet.start_minute as i32),
+                    quiet.map(|quiet| quiet.end_minute as i32),
+                    quiet.map(|quiet| quiet.utc_offset_minutes as i32),
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn delete_user_preferences(&self, user_id: UserId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "delete user preferences",
+                "DELETE FROM user_preferences WHERE user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_average_message_size(&self, chat_id: ChatId) -> DBResult<Option<u64>> {
+        let q = self
+            .get_prepared_query(
+                "get chat message stats",
+                "SELECT message_count, message_bytes FROM chat_message_stats WHERE chat_id = ?",
+            )
+            .await?;
+        let average = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<Counter>, Option<Counter>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(count, bytes)| match (count, bytes) {
+                (Some(Counter(count)), Some(Counter(bytes))) if count > 0 && bytes > 0 => {
+                    Some(bytes as u64 / count as u64)
+                }
+                _ => None,
+            });
+        Ok(average)
+    }
+
+    async fn get_message_count(&self, chat_id: ChatId) -> DBResult<u64> {
+        let q = self
+            .get_prepared_query(
+                "get chat message count",
+                "SELECT message_count FROM chat_message_stats WHERE chat_id = ?",
+            )
+            .await?;
+        let count = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<Counter>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(count,)| count)
+            .map_or(0, |Counter(count)| count.max(0) as u64);
+        Ok(count)
+    }
+
+    async fn get_chat_usage(&self, chat_id: ChatId) -> DBResult<data::ChatUsage> {
+        let q = self
+            .get_prepared_query(
+                "check chat exists",
+                "SELECT chat_id FROM chats WHERE chat_id = ?",
+            )
+            .await?;
+        let exists = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_num()
+            .is_ok_and(|rows| rows > 0);
+        if !exists {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "chat_not_found",
+            ))));
+        }
+        let q = self
+            .get_prepared_query(
+                "get chat usage stats",
+                "SELECT message_count, message_bytes, attachment_count, attachment_bytes \
+                FROM chat_message_stats WHERE chat_id = ?",
+            )
+            .await?;
+        let counters = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(
+                Option<Counter>,
+                Option<Counter>,
+                Option<Counter>,
+                Option<Counter>,
+            )>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .unwrap_or_default();
+        let value = |counter: Option<Counter>| counter.map_or(0, |Counter(n)| n.max(0) as u64);
+        let oldest = self.edge_message_date(chat_id, true).await?;
+        let newest = self.edge_message_date(chat_id, false).await?;
+        Ok(data::ChatUsage {
+            chat_id: chat_id.get(),
+            message_count: value(counters.0),
+            message_bytes: value(counters.1),
+            attachment_count: value(counters.2),
+            attachment_bytes: value(counters.3),
+            oldest_message: oldest.map(Into::into),
+            newest_message: newest.map(Into::into),
+        })
+    }
+
+    async fn get_chat_events(
+        &self,
+        chat_id: ChatId,
+        since_position: Option<i64>,
+        page_size: usize,
+    ) -> DBResult<(Vec<ChatEvent>, Option<i64>)> {
+        let q = self
+            .get_prepared_query(
+                "get chat events page",
+                "SELECT position, event_id, kind, date, payload FROM chat_events \
+                WHERE chat_id = ? AND position > ? LIMIT ?",
+            )
+            .await?;
+        let rows: Result<Vec<_>, _> = self
+            .client
+            .execute(
+                &q,
+                (
+                    chat_id,
+                    since_position.unwrap_or(i64::MIN),
+                    page_size as i32,
+                ),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(i64, Uuid, String, chrono::Duration, String)>()
+            .collect();
+        let rows = rows.map_err(|e| DBError::OtherError(Box::new(e)))?;
+        // Курсор - позиция последней прочитанной строки, даже если ее событие пропущено
+        let next = match rows.last() {
+            Some(&(last, ..)) if rows.len() == page_size => Some(last),
+            _ => None,
+        };
+        let events = rows
+            .into_iter()
+            .filter_map(|(position, event_id, kind, date, payload)| {
+                // События незнакомых видов записаны более новой версией сервиса
+                Some(ChatEvent {
+                    position,
+                    event_id,
+                    kind: ChatEventKind::parse(&kind)?,
+                    date: date.into(),
+                    payload: serde_json::from_str(&payload).unwrap_or_default(),
+                })
+            })
+            .collect();
+        Ok((events, next))
+    }
+
+    async fn get_member_count(&self, chat_id: ChatId) -> DBResult<u64> {
+        let q = self
+            .get_prepared_query(
+                "get chat member count",
+                "SELECT COUNT(*) FROM chat_members WHERE chat_id = ?",
+            )
+            .await?;
+        let count = self
+            .client
+            .execute(&q, (chat_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(i64,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .map_or(0, |(count,)| count.max(0) as u64);
+        Ok(count)
+    }
+
+    async fn claim_client_msg_id(
+        &self,
+        chat_id: ChatId,
+        sender_id: UserId,
+        client_msg_id: &str,
+        message_id: uuid::Uuid,
+        date: chrono::Duration,
+    ) -> DBResult<Option<(Uuid, chrono::Duration)>> {
+        let q = self
+            .get_prepared_query(
+                "claim client msg id",
+                "INSERT INTO client_message_ids (chat_id, sender_id, client_msg_id, message_id, date) \
+                VALUES (?, ?, ?, ?, ?) IF NOT EXISTS USING TTL ?",
+            )
+            .await?;
+        let applied = self
+            .client
+            .execute(
+                &q,
+                (
+                    chat_id,
+                    sender_id,
+                    client_msg_id,
+                    message_id,
+                    Timestamp(date),
+                    CLIENT_MSG_ID_WINDOW_SECS,
+                ),
+            )
+            .await
+            .map_err(query_error)?
+            .rows
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| row.columns.first().cloned().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or(false);
+        if applied {
+            return Ok(None);
+        }
+        let q = self
+            .get_prepared_query(
+                "get client msg id",
+                "SELECT message_id, date FROM client_message_ids \
+                WHERE chat_id = ? AND sender_id = ? AND client_msg_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, sender_id, client_msg_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Uuid, chrono::Duration)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))
+    }
+
+    async fn release_client_msg_id(
+        &self,
+        chat_id: ChatId,
+        sender_id: UserId,
+        client_msg_id: &str,
+        message_id: uuid::Uuid,
+    ) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "release client msg id",
+                "DELETE FROM client_message_ids \
+                WHERE chat_id = ? AND sender_id = ? AND client_msg_id = ? IF message_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat_id, sender_id, client_msg_id, message_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn save_read_position(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        position: ReadPosition,
+    ) -> DBResult<()> {
+        // Время записи равно времени сообщения, поэтому из двух отметок остается отметка
+        // о более позднем сообщении, в каком бы порядке они ни пришли
+        let q = self
+            .get_prepared_query(
+                "save read position",
+                "INSERT INTO chat_read_positions (user_id, chat_id, message_id, date) \
+                VALUES (?, ?, ?, ?) USING TIMESTAMP ?",
+            )
+            .await?;
+        let date = position.date.timestamp;
+        self.client
+            .execute(
+                &q,
+                (
+                    user_id,
+                    chat_id,
+                    position.message_id,
+                    Timestamp(date),
+                    date.num_milliseconds() * 1000,
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_read_positions(&self, user_id: UserId) -> DBResult<HashMap<ChatId, ReadPosition>> {
+        let q = self
+            .get_prepared_query(
+                "get read positions",
+                "SELECT chat_id, message_id, date FROM chat_read_positions WHERE user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, Uuid, chrono::Duration)>()
+            .map(|row| {
+                let (chat_id, message_id, date) =
+                    row.map_err(|e| DBError::OtherError(Box::new(e)))?;
+                Ok((
+                    chat_id,
+                    ReadPosition {
+                        message_id,
+                        date: date.into(),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    async fn get_unread_counts(&self, user_id: UserId) -> DBResult<HashMap<ChatId, i64>> {
+        let mut counts: HashMap<ChatId, i64> = self
+            .get_user_chats(user_id)
+            .await?
+            .into_iter()
+            .map(|chat_id| (chat_id, 0))
+            .collect();
+        let q = self
+            .get_prepared_query(
+                "get unread counts",
+                "SELECT chat_id, unread FROM chat_unread WHERE user_id = ?",
+            )
+            .await?;
+        let rows = self
+            .client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId, Option<Counter>)>();
+        for row in rows {
+            let (chat_id, unread) = row.map_err(|e| DBError::OtherError(Box::new(e)))?;
+            // Счетчики чатов, из которых пользователь вышел, не отдаем
+            if let (Some(count), Some(Counter(unread))) = (counts.get_mut(&chat_id), unread) {
+                *count = unread.max(0);
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn reset_unread(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "get unread",
+                "SELECT unread FROM chat_unread WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        let unread = self
+            .client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<Counter>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(unread,)| unread)
+            .map_or(0, |Counter(unread)| unread);
+        if unread == 0 {
+            return Ok(());
+        }
+        // Счетчик нельзя записать, только изменить, а удалять счетчики, которые потом
+        // снова понадобятся, нельзя. Поэтому вычитаем прочитанное: сообщения, пришедшие
+        // между чтением и вычитанием, так и останутся непрочитанными
+        let q = self
+            .get_prepared_query(
+                "reset unread",
+                "UPDATE chat_unread SET unread = unread - ? WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (Counter(unread), user_id, chat_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_draft(&self, user_id: UserId, chat_id: ChatId) -> DBResult<Option<Draft>> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "get draft",
+                "SELECT draft_text, updated_at FROM chat_drafts WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
+        let draft = self
+            .client
+            .execute(&q, (user_id, chat_id))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<String>, Option<chrono::Duration>)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(text, updated_at)| {
+                Some(Draft {
+                    chat_id: chat_id.get(),
+                    text: text.filter(|text| !text.is_empty())?,
+                    updated_at: updated_at.unwrap_or_else(chrono::Duration::zero).into(),
+                })
+            });
+        Ok(draft)
+    }
+
+    async fn save_draft(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        text: String,
+    ) -> DBResult<Option<Draft>> {
+        self.check_membership(user_id, chat_id).await?;
+        if text.is_empty() {
+            let q = self
+                .get_prepared_query(
+                    "delete draft",
+                    "DELETE FROM chat_drafts WHERE user_id = ? AND chat_id = ?",
+                )
+                .await?;
+            self.client
+                .execute(&q, (user_id, chat_id))
+                .await
+                .map_err(query_error)?;
+            return Ok(None);
+        }
+        let q = self
+            .get_prepared_query(
+                "save draft",
+                "INSERT INTO chat_drafts (user_id, chat_id, draft_text, updated_at) \
+                VALUES (?, ?, ?, ?)",
+            )
+            .await?;
+        let updated_at = clock::CLOCK.now();
+        self.client
+            .execute(&q, (user_id, chat_id, &text, Timestamp(updated_at)))
+            .await
+            .map_err(query_error)?;
+        Ok(Some(Draft {
+            chat_id: chat_id.get(),
+            text,
+            updated_at: updated_at.into(),
+        }))
+    }
+
+    async fn archive_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "archive chat",
+                "INSERT INTO chat_archive (user_id, chat_id, archived_at) VALUES (?, ?, ?)",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, chat_id, Timestamp(clock::CLOCK.now())))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn unarchive_chat(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        self.check_membership(user_id, chat_id).await?;
+        let q = self
+            .get_prepared_query(
+                "unarchive chat",
+                "DELETE FROM chat_archive WHERE user_id = ? AND chat_id = ?",
+            )
+            .await?;
         self.client
-            .execute(&q_1, (user_id, chat_id))
+            .execute(&q, (user_id, chat_id))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_archived_chats(&self, user_id: UserId) -> DBResult<HashSet<ChatId>> {
+        let q = self
+            .get_prepared_query(
+                "get archived chats",
+                "SELECT chat_id FROM chat_archive WHERE user_id = ?",
+            )
+            .await?;
         self.client
-            .execute(&q_2, (chat_id, user_id))
+            .execute(&q, (user_id,))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-
-        // Проверяем, есть ли еще кто-то в данном чате
-        // Если нет, то удаляем его
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(ChatId,)>()
+            .map(|row| {
+                row.map(|(chat_id,)| chat_id)
+                    .map_err(|e| DBError::OtherError(Box::new(e)))
+            })
+            .collect()
+    }
 
+    async fn block_user(&self, user_id: UserId, blocked_id: UserId) -> DBResult<()> {
+        if user_id == blocked_id {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "cannot_block_self",
+            ))));
+        }
+        if self.get_users_info(vec![blocked_id]).await?.is_empty() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))));
+        }
         let q = self
             .get_prepared_query(
-                "get chat user count",
-                "SELECT users FROM chat.chats WHERE chat_id = ?",
+                "block user",
+                "INSERT INTO user_blocks (user_id, blocked_id, blocked_at) VALUES (?, ?, ?)",
             )
             .await?;
-        let chat_user_list = self
-            .client
-            .execute(&q, (chat_id,))
+        self.client
+            .execute(&q, (user_id, blocked_id, Timestamp(clock::CLOCK.now())))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?
-            .rows
-            .ok_or(DBError::QueryError(Box::new(StringError {
-                msg: "Select query didn't return rows".into(),
-            })))?
-            .into_typed::<(Option<Vec<i64>>,)>()
-            .next()
-            .ok_or(DBError::LogicError(Box::new(StringError {
-                msg: "Invalid chat ID to delete".into(),
-            })))?
-            .map_err(|e| DBError::OtherError(Box::new(e)))?
-            .0;
-        match chat_user_list {
-            Some(v) if v.is_empty() => {
-                self.delete_chat(chat_id).await?;
-            }
-            None => {
-                self.delete_chat(chat_id).await?;
-            }
-            _ => {}
-        }
+            .map_err(query_error)?;
         Ok(())
     }
-    async fn delete_chat(&self, chat_id: uuid::Uuid) -> DBResult<()> {
-        let i = chat_id.to_string().replace("-", "_");
-        let q_1 = self
+
+    async fn unblock_user(&self, user_id: UserId, blocked_id: UserId) -> DBResult<()> {
+        let q = self
             .get_prepared_query(
-                "delete chat record from chats",
-                "DELETE FROM chat.chats WHERE chat_id = ? IF EXISTS",
+                "unblock user",
+                "DELETE FROM user_blocks WHERE user_id = ? AND blocked_id = ?",
             )
             .await?;
         self.client
-            .execute(&q_1, (chat_id,))
+            .execute(&q, (user_id, blocked_id))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        let q_2 = self
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    async fn get_blocked_users(&self, user_id: UserId) -> DBResult<HashSet<UserId>> {
+        let q = self
             .get_prepared_query(
-                "delete chat history",
-                format!("DROP TABLE IF EXISTS chat.chat_{}", i).as_str(),
+                "get blocked users",
+                "SELECT blocked_id FROM user_blocks WHERE user_id = ?",
             )
             .await?;
         self.client
-            .execute(&q_2, &[])
+            .execute(&q, (user_id,))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        Ok(())
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId,)>()
+            .map(|row| {
+                row.map(|(blocked_id,)| blocked_id)
+                    .map_err(|e| DBError::OtherError(Box::new(e)))
+            })
+            .collect()
     }
 
-    async fn get_chat_info(&self, user_id: i64, chat_id: uuid::Uuid) -> DBResult<data::ChatInfo> {
-        let query_body = "SELECT chat_id, name, users, chat_type FROM chat.chats WHERE chat_id = ? AND users CONTAINS ? ALLOW FILTERING";
-        let q = self.get_prepared_query("get chat info", query_body).await?;
-        let chat_info = self
-            .client
-            .execute(&q, (chat_id, user_id))
+    async fn get_blockers(
+        &self,
+        blocked_id: UserId,
+        user_ids: Vec<UserId>,
+    ) -> DBResult<Vec<UserId>> {
+        if user_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let q = self
+            .get_prepared_query(
+                "get blockers",
+                "SELECT user_id FROM user_blocks WHERE user_id IN ? AND blocked_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_ids, blocked_id))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?
-            .rows
-            .ok_or(DBError::QueryError(Box::new(StringError {
-                msg: "Select query didn't return rows".into(),
-            })))?
-            .into_typed::<(Uuid, String, Option<Vec<i64>>, ChatType)>()
-            .next()
-            .ok_or(DBError::LogicError(Box::new(StringError {
-                msg: "Invalid chat ID or User is not a member of chat".into(),
-            })))?
-            .map_err(|e| DBError::OtherError(Box::new(e)))?;
-        Ok(ChatInfo {
-            id: chat_info.0,
-            name: chat_info.1,
-            users: chat_info.2.unwrap_or(vec![]),
-            chat_type: chat_info.3,
-        })
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId,)>()
+            .map(|row| {
+                row.map(|(user_id,)| user_id)
+                    .map_err(|e| DBError::OtherError(Box::new(e)))
+            })
+            .collect()
     }
-    async fn get_chat_history_paged(
-        &self,
-        user_id: i64,
-        chat_id: uuid::Uuid,
-        page_size: usize,
-        paging_index: Option<PageIndex>,
-    ) -> DBResult<(Vec<ChatMessage>, PageIndex)> {
-        // Чтобы получить сообщения чата, необходимо:
-        // 1) Проверить, есть ли пользователь в чате
-        // 2) Получить только часть данных
-        // 3) Отправить ее
-        let user_chats = self.get_user_chats(user_id).await?;
-        if !user_chats.contains(&chat_id) {
-            Err(DBError::LogicError(Box::new(StringError {
-                msg: "User is not a member of chat".into(),
-            })))?;
-        }
-        let i = chat_id.to_string().replace("-", "_");
-        let query_name = format!("get chat_{} messages", i);
-        let query_body = format!(r#"SELECT user_id, date, message_text FROM chat.chat_{}"#, i);
-        let mut q = self.get_prepared_query(&query_name, &query_body).await?;
-        q.set_page_size(page_size as i32);
 
-        let current_page = if let Some(index) = paging_index {
-            let paging_index: Option<Bytes> = index.into();
-            self.client
-                .execute_paged(&q, &[], paging_index)
-                .await
-                .map_err(|e| DBError::QueryError(Box::new(e)))?
-        } else {
-            self.client
-                .execute(&q, &[])
-                .await
-                .map_err(|e| DBError::QueryError(Box::new(e)))?
-        };
+    async fn add_contact(&self, user_id: UserId, contact_id: UserId) -> DBResult<()> {
+        if user_id == contact_id {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "cannot_add_self",
+            ))));
+        }
+        if self.get_users_info(vec![contact_id]).await?.is_empty() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))));
+        }
+        let q = self
+            .get_prepared_query(
+                "add contact",
+                "INSERT INTO user_contacts (user_id, contact_id, added_at) VALUES (?, ?, ?)",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, contact_id, Timestamp(clock::CLOCK.now())))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
 
-        let next_index = PageIndex::from(current_page.paging_state);
-        // let next_index = to_string(&next_index).unwrap();
+    async fn remove_contact(&self, user_id: UserId, contact_id: UserId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "remove contact",
+                "DELETE FROM user_contacts WHERE user_id = ? AND contact_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id, contact_id))
+            .await
+            .map_err(query_error)?;
+        Ok(())
+    }
 
-        let messages: Result<Vec<_>, _> = current_page
-            .rows
-            .ok_or(DBError::QueryError(Box::new(StringError {
-                msg: "Select query didn't rerurn rows".into(),
-            })))?
-            .into_typed::<(i64, chrono::Duration, String)>()
-            .collect();
-        let messages: Vec<_> = messages
-            .map_err(|e| DBError::OtherError(Box::new(e)))?
-            .into_iter()
-            .map(|msg| ChatMessage {
-                chat_id,
-                date: msg.1.into(),
-                sender_id: msg.0,
-                msg_text: msg.2,
+    async fn get_contacts(&self, user_id: UserId) -> DBResult<Vec<UserId>> {
+        let q = self
+            .get_prepared_query(
+                "get contacts",
+                "SELECT contact_id FROM user_contacts WHERE user_id = ?",
+            )
+            .await?;
+        self.client
+            .execute(&q, (user_id,))
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(UserId,)>()
+            .map(|row| {
+                row.map(|(contact_id,)| contact_id)
+                    .map_err(|e| DBError::OtherError(Box::new(e)))
             })
-            .collect();
-        Ok((messages, next_index))
+            .collect()
     }
-    async fn get_user_info(&self, user_id: i64) -> DBResult<UserInfo> {
+
+    async fn set_bot_webhook(&self, user_id: UserId, callback_url: String) -> DBResult<String> {
+        if self.get_users_info(vec![user_id]).await?.is_empty() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))));
+        }
+        let secret = secrets::generate_secret();
         let q = self
             .get_prepared_query(
-                "get user info",
-                r#"SELECT user_id, name, chats from chat.users WHERE user_id = ?"#,
+                "set bot webhook",
+                "INSERT INTO bot_webhooks (user_id, callback_url, secret, registered_at) VALUES (?, ?, ?, ?)",
             )
             .await?;
-        let user_info = self
-            .client
+        self.client
+            .execute(
+                &q,
+                (
+                    user_id,
+                    callback_url,
+                    &secret,
+                    Timestamp(clock::CLOCK.now()),
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        Ok(secret)
+    }
+
+    async fn remove_bot_webhook(&self, user_id: UserId) -> DBResult<()> {
+        let q = self
+            .get_prepared_query(
+                "remove bot webhook",
+                "DELETE FROM bot_webhooks WHERE user_id = ?",
+            )
+            .await?;
+        self.client
             .execute(&q, (user_id,))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?
-            .rows
-            .ok_or(DBError::QueryError(Box::new(StringError {
-                msg: "Select query didn't rerurn rows".into(),
-            })))?
-            .into_typed::<(i64, String, Option<Vec<Uuid>>)>()
-            .next()
-            .ok_or(DBError::LogicError(Box::new(StringError {
-                msg: "Invalid User ID".into(),
-            })))?
-            .map_err(|e| DBError::OtherError(Box::new(e)))?;
-        Ok(UserInfo {
-            id: user_info.0,
-            name: user_info.1,
-            chats: user_info.2.unwrap_or(vec![]),
-        })
+            .map_err(query_error)?;
+        Ok(())
     }
-    async fn create_new_user(&self, user_id: i64, user_name: String) -> DBResult<UserInfo> {
+
+    async fn get_bot_webhooks(&self) -> DBResult<Vec<BotWebhook>> {
         let q = self
             .get_prepared_query(
-                "create new user",
-                r#"INSERT INTO chat.users (user_id, creation_date, name, chats)
-               VALUES (?, toTimestamp(now()), ?, {})
-               IF NOT EXISTS"#,
+                "get bot webhooks",
+                "SELECT user_id, callback_url, secret FROM bot_webhooks",
             )
             .await?;
         self.client
-            .execute(&q, (user_id, user_name))
+            .execute(&q, &[])
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?;
-        let user_info = self.get_user_info(user_id).await?;
-        Ok(user_info)
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(i64, String, String)>()
+            .map(|row| {
+                row.map(|(user_id, callback_url, secret)| BotWebhook {
+                    user_id,
+                    callback_url,
+                    secret,
+                })
+                .map_err(|e| DBError::OtherError(Box::new(e)))
+            })
+            .collect()
     }
-    async fn get_user_chats(&self, user_id: i64) -> DBResult<Vec<Uuid>> {
+
+    async fn edit_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+        date: chrono::Duration,
+        msg_text: String,
+    ) -> DBResult<ChatMessage> {
+        self.check_membership(user_id, chat_id).await?;
         let q = self
             .get_prepared_query(
-                "get user chats",
-                r#"SELECT chats FROM chat.users WHERE user_id = ?"#,
+                "get message",
+                r#"SELECT message_id, user_id, date, message_text, edited_at, reply_to, attachments, forwarded_chat_id, forwarded_message_id, forwarded_sender_id, mentions, call_id, call_event, call_duration FROM messages
+                WHERE chat_id = ? AND bucket = ? AND date = ? AND message_id = ?"#,
             )
             .await?;
-        let chats = self
+        let message = self
             .client
-            .execute(&q, (user_id,))
+            .execute(
+                &q,
+                (chat_id, message_bucket(date), Timestamp(date), message_id),
+            )
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?
-            .rows
-            .ok_or(DBError::QueryError(Box::new(StringError {
-                msg: "Select query didn't return rows".into(),
-            })))?
-            .into_typed::<(Option<Vec<Uuid>>,)>()
-            .next()
-            .ok_or(DBError::LogicError(Box::new(StringError {
-                msg: "Invalid user id".into(),
-            })))?
+            .map_err(query_error)?
+            .rows_typed_or_empty::<MessageRow>()
+            .next()
+            .transpose()
             .map_err(|e| DBError::OtherError(Box::new(e)))?
-            .0;
-        Ok(chats.unwrap_or(vec![]))
+            .map(|row| message_from_row(chat_id, row))
+            .ok_or(DBError::LogicError(Box::new(StringError::new(
+                "message_not_found",
+            ))))?;
+        if message.sender_id != user_id.get() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "sender_only_edits",
+            ))));
+        }
+
+        // Исчезающее сообщение после правки должно исчезнуть в тот же момент, иначе новые
+        // текст и дата правки переживут остальные колонки
+        let q = self
+            .get_prepared_query(
+                "get message remaining ttl",
+                r#"SELECT TTL(user_id) FROM messages
+                WHERE chat_id = ? AND bucket = ? AND date = ? AND message_id = ?"#,
+            )
+            .await?;
+        let remaining_ttl = self
+            .client
+            .execute(
+                &q,
+                (chat_id, message_bucket(date), Timestamp(date), message_id),
+            )
+            .await
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(Option<i32>,)>()
+            .next()
+            .transpose()
+            .map_err(|e| DBError::OtherError(Box::new(e)))?
+            .and_then(|(ttl,)| ttl)
+            .unwrap_or(0);
+
+        let edited_at = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        let q = self
+            .get_prepared_query(
+                "edit message",
+                r#"UPDATE messages USING TTL ? SET message_text = ?, edited_at = ?
+                WHERE chat_id = ? AND bucket = ? AND date = ? AND message_id = ?"#,
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (
+                    remaining_ttl,
+                    &msg_text,
+                    Timestamp(edited_at),
+                    chat_id,
+                    message_bucket(date),
+                    Timestamp(date),
+                    message_id,
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+        let message = ChatMessage {
+            msg_text,
+            edited_at: Some(edited_at.into()),
+            ..message
+        };
+        let ttl = self.message_ttl(chat_id).await?;
+        self.append_event(
+            chat_id,
+            ChatEventKind::MessageEdited,
+            message_payload(&message),
+            ttl,
+        )
+        .await;
+        Ok(message)
+    }
+
+    async fn find_mentions(
+        &self,
+        sender_id: UserId,
+        chat_id: ChatId,
+        text: &str,
+    ) -> DBResult<Vec<UserId>> {
+        let found = mentions::parse_mentions(text);
+        if found.is_empty() {
+            return Ok(vec![]);
+        }
+        let chat = self.get_chat_info(sender_id, chat_id).await?;
+        // Имена участников нужны, только если кого-то упомянули по имени
+        let members = if found
+            .iter()
+            .all(|mention| matches!(mention, Mention::Id(_)))
+        {
+            chat.users
+                .into_iter()
+                .map(|id| UserInfo {
+                    id,
+                    name: String::new(),
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+                .collect()
+        } else {
+            self.get_users_info(chat.users.into_iter().map(UserId).collect())
+                .await?
+        };
+        Ok(
+            mentions::resolve_mentions(&found, &members, sender_id.get())
+                .into_iter()
+                .map(UserId)
+                .collect(),
+        )
+    }
+
+    async fn forward_message(
+        &self,
+        user_id: UserId,
+        from_chat_id: ChatId,
+        message_id: uuid::Uuid,
+        to_chat_id: ChatId,
+    ) -> DBResult<ChatMessage> {
+        self.check_membership(user_id, from_chat_id).await?;
+        self.check_membership(user_id, to_chat_id).await?;
+        let original = self.find_message(from_chat_id, message_id).await?;
+        if !original.attachments.is_empty() {
+            self.check_permission(user_id, to_chat_id, ChatPermissions::POST_MEDIA)
+                .await?;
+        }
+        let mut attachments = Vec::with_capacity(original.attachments.len());
+        for attachment_id in original.attachments {
+            let attachment = self.get_attachment(user_id, attachment_id).await?;
+            let copy = Attachment {
+                id: Uuid::new_v4(),
+                chat_id: to_chat_id.get(),
+                uploader_id: user_id.get(),
+                created_at: clock::CLOCK.now().into(),
+                ..attachment
+            };
+            attachments.push(copy.id);
+            self.add_attachment(copy).await?;
+        }
+        let message = ChatMessage {
+            chat_id: to_chat_id.get(),
+            message_id: Uuid::new_v4(),
+            sender_id: user_id.get(),
+            date: clock::CLOCK.now().into(),
+            msg_text: original.msg_text,
+            edited_at: None,
+            reply_to: None,
+            attachments,
+            forwarded_from: Some(original.forwarded_from.unwrap_or(ForwardedFrom {
+                chat_id: from_chat_id.get(),
+                message_id,
+                sender_id: original.sender_id,
+            })),
+            // Упоминания относятся к исходному чату, в новом никого не уведомляем
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        self.add_new_message_to_chat(message.clone()).await?;
+        Ok(message)
     }
 
-    async fn get_user_list(&self) -> DBResult<Vec<i64>> {
+    async fn delete_message(
+        &self,
+        user_id: UserId,
+        chat_id: ChatId,
+        message_id: uuid::Uuid,
+    ) -> DBResult<MessageTombstone> {
+        self.check_membership(user_id, chat_id).await?;
+        let message = self.find_message(chat_id, message_id).await?;
+        if message.sender_id != user_id.get() {
+            return Err(DBError::LogicError(Box::new(StringError::new(
+                "sender_only_deletes",
+            ))));
+        }
+
         let q = self
-            .get_prepared_query("get user list", r#"SELECT user_id FROM chat.users"#)
+            .get_prepared_query(
+                "delete message",
+                "DELETE FROM messages WHERE chat_id = ? AND bucket = ? AND date = ? \
+                AND message_id = ?",
+            )
             .await?;
-        let user_list: Result<Vec<_>, _> = self
+        let date = message.date.timestamp;
+        self.client
+            .execute(
+                &q,
+                (chat_id, message_bucket(date), Timestamp(date), message_id),
+            )
+            .await
+            .map_err(query_error)?;
+        self.remove_pin(chat_id, message_id).await?;
+        let tombstone = MessageTombstone {
+            chat_id: chat_id.get(),
+            message_id,
+            date: message.date,
+        };
+        self.append_event(
+            chat_id,
+            ChatEventKind::MessageDeleted,
+            serde_json::to_value(&tombstone).unwrap_or_default(),
+            0,
+        )
+        .await;
+        Ok(tombstone)
+    }
+
+    async fn check_schema(&self) -> DBResult<Vec<String>> {
+        let q = self
+            .get_prepared_query(
+                "get schema columns",
+                r#"SELECT table_name, column_name, type FROM system_schema.columns
+                WHERE keyspace_name = ?"#,
+            )
+            .await?;
+        let columns: Result<Vec<_>, _> = self
             .client
-            .execute(&q, &[])
+            .execute(&q, (&self.keyspace,))
             .await
-            .map_err(|e| DBError::QueryError(Box::new(e)))?
-            .rows_typed_or_empty::<(i64,)>()
-            .map(|elem| match elem {
-                Ok(id) => Ok(id.0),
-                Err(e) => Err(e),
-            })
+            .map_err(query_error)?
+            .rows_typed_or_empty::<(String, String, String)>()
             .collect();
-        let user_list = user_list.map_err(|e| DBError::OtherError(Box::new(e)))?;
-        Ok(user_list)
+        let columns = columns.map_err(|e| DBError::OtherError(Box::new(e)))?;
+
+        // Версию читаем, только если таблица с ней уже есть
+        let stored_version = if columns
+            .iter()
+            .any(|(table, _, _)| table == "schema_version")
+        {
+            self.stored_schema_version().await?
+        } else {
+            None
+        };
+        Ok(schema::find_problems(&columns, stored_version))
+    }
+
+    async fn import_chat(&self, chat: data::ChatInfo) -> DBResult<()> {
+        let public = chat.chat_type == ChatType::Channel;
+        let chat_type = match chat.chat_type {
+            ChatType::Private => "private",
+            ChatType::Group => "group",
+            ChatType::Channel => "channel",
+            ChatType::Reserved => "reserved",
+        };
+        if public {
+            self.list_channel(ChatId(chat.id), &chat.name).await?;
+        }
+        let q = self
+            .get_prepared_query(
+                "import chat info",
+                r#"INSERT INTO chats (chat_id, creation_date, name, users, chat_type, delivery_mode, post_policy, language, labels, permissions, public)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            IF NOT EXISTS"#,
+            )
+            .await?;
+        self.client
+            .execute(
+                &q,
+                (
+                    chat.id,
+                    Timestamp(clock::CLOCK.now()),
+                    chat.name,
+                    &chat.users,
+                    chat_type,
+                    chat.delivery_mode.map(|mode| mode.as_str()),
+                    chat.post_policy.as_str(),
+                    chat.labels.language,
+                    chat.labels.labels,
+                    chat.permissions.0 as i32,
+                    public,
+                ),
+            )
+            .await
+            .map_err(query_error)?;
+
+        let q = self
+            .get_prepared_query(
+                "update users chat lists",
+                r#"UPDATE users
+            SET chats = chats + {?}
+            WHERE user_id IN ?"#,
+            )
+            .await?;
+        self.client
+            .execute(&q, (chat.id, &chat.users))
+            .await
+            .map_err(query_error)?;
+        let members: Vec<UserId> = chat.users.iter().copied().map(UserId).collect();
+        self.insert_chat_members(ChatId(chat.id), &members).await
+    }
+
+    async fn import_messages(&self, chat_id: ChatId, messages: Vec<ChatMessage>) -> DBResult<()> {
+        for msg in messages {
+            // Если у источника нет id сообщений, то выводим id из содержимого,
+            // чтобы повторный импорт перезаписывал ту же строку, а не создавал копию
+            let message_id = if msg.message_id.is_nil() {
+                let mut hasher = Sha256::new();
+                hasher.update(msg.sender_id.to_be_bytes());
+                hasher.update(msg.date.timestamp.num_milliseconds().to_be_bytes());
+                hasher.update(msg.msg_text.as_bytes());
+                Uuid::from_slice(&hasher.finalize()[..16])
+                    .map_err(|e| DBError::OtherError(Box::new(e)))?
+            } else {
+                msg.message_id
+            };
+            let msg = ChatMessage {
+                chat_id: chat_id.get(),
+                message_id,
+                ..msg
+            };
+            self.write_message(&msg, 0).await?;
+        }
+        Ok(())
     }
 }
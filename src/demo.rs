@@ -0,0 +1,217 @@
+use std::{collections::HashMap, fmt};
+
+use log::info;
+use uuid::Uuid;
+
+use crate::{
+    actors::websocket_actor::ChatMessage,
+    clock,
+    database::{
+        data::{ChatInfo, ChatType},
+        DBError, DBResult, Database,
+    },
+    ids::UserId,
+};
+
+// Демонстрационные данные
+//
+// chat seed-demo заполняет пространство ключей примерами, чтобы на свежей установке можно
+// было сразу попробовать API и вебсокет: пользователи, несколько групповых чатов, личные
+// переписки и несколько тысяч сообщений. Все пишется через Database теми же запросами,
+// что и у сервиса, так что данные ничем не отличаются от настоящих.
+//
+// Демонстрационные пользователи получают id начиная с DEMO_FIRST_USER_ID, чтобы не
+// пересекаться с настоящими. Если первый из них уже есть, то повторный запуск ничего не
+// пишет: иначе каждый запуск создавал бы еще одну копию чатов.
+
+pub const USAGE: &str = "Usage: chat seed-demo [--users N] [--messages M]";
+
+/// id первого демонстрационного пользователя, остальные идут подряд
+pub const DEMO_FIRST_USER_ID: UserId = UserId(1_000_000);
+
+const NAMES: &[&str] = &[
+    "Alice", "Bob", "Carol", "Dave", "Eve", "Frank", "Grace", "Heidi", "Ivan", "Judy", "Mallory",
+    "Niaj", "Olivia", "Peggy", "Rupert", "Sybil", "Trent", "Victor", "Walter", "Zoe",
+];
+
+const GROUP_CHATS: &[&str] = &["General", "Random", "Engineering", "Support"];
+
+/// Со сколькими собеседниками у первого пользователя есть личные переписки
+const PRIVATE_CHATS: usize = 3;
+
+/// Промежуток между соседними сообщениями, история растягивается в прошлое
+const MESSAGE_INTERVAL_SECS: i64 = 45;
+
+/// Каждое REPLY_EVERY-е сообщение чата отвечает на предыдущее
+const REPLY_EVERY: usize = 7;
+
+const PHRASES: &[&str] = &[
+    "Good morning, everyone!",
+    "Has anyone looked at the latest release notes?",
+    "I'll take a look after lunch",
+    "Can we move the meeting to 3 pm?",
+    "Sounds good to me",
+    "The build is green again",
+    "Who is on call this week?",
+    "Thanks, that fixed it",
+    "Let's discuss it tomorrow",
+    "I pushed a draft, feedback welcome",
+    "Coffee anyone?",
+    "Done, please check",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedConfig {
+    pub users: usize,
+    pub messages: usize,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        Self {
+            users: 12,
+            messages: 3000,
+        }
+    }
+}
+
+impl SeedConfig {
+    /// Разбирает аргументы после chat seed-demo
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {flag}\n{USAGE}"))?;
+            let invalid = |_| format!("Invalid value {value} for {flag}\n{USAGE}");
+            match flag.as_str() {
+                "--users" => config.users = value.parse().map_err(invalid)?,
+                "--messages" => config.messages = value.parse().map_err(invalid)?,
+                _ => return Err(format!("Unknown option {flag}\n{USAGE}")),
+            }
+        }
+        // В личной переписке нужен собеседник
+        if config.users < 2 {
+            return Err(format!("At least 2 users are needed\n{USAGE}"));
+        }
+        Ok(config)
+    }
+}
+
+/// Что создал запуск
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeedReport {
+    /// Данные уже были, запуск ничего не записал
+    pub already_seeded: bool,
+    pub users: usize,
+    pub group_chats: usize,
+    pub private_chats: usize,
+    pub messages: usize,
+}
+
+impl fmt::Display for SeedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.already_seeded {
+            return write!(
+                f,
+                "Demo data already exists (user {DEMO_FIRST_USER_ID}), nothing was written"
+            );
+        }
+        write!(
+            f,
+            "Created {} users (ids from {DEMO_FIRST_USER_ID}), {} group chats, {} private chats and {} messages",
+            self.users, self.group_chats, self.private_chats, self.messages
+        )
+    }
+}
+
+/// Имя демонстрационного пользователя с номером index
+pub fn demo_name(index: usize) -> String {
+    match index / NAMES.len() {
+        0 => NAMES[index].to_string(),
+        round => format!("{} {}", NAMES[index % NAMES.len()], round + 1),
+    }
+}
+
+/// Заполняет базу демонстрационными данными
+pub async fn seed<D: Database + ?Sized>(db: &D, config: &SeedConfig) -> DBResult<SeedReport> {
+    match db.get_user_info(DEMO_FIRST_USER_ID).await {
+        Ok(_) => {
+            return Ok(SeedReport {
+                already_seeded: true,
+                ..Default::default()
+            })
+        }
+        Err(DBError::LogicError(_)) => {}
+        Err(e) => return Err(e),
+    }
+    let mut report = SeedReport::default();
+
+    let user_ids: Vec<UserId> = (0..config.users)
+        .map(|index| UserId(DEMO_FIRST_USER_ID.0 + index as i64))
+        .collect();
+    for (index, &user_id) in user_ids.iter().enumerate() {
+        db.create_new_user(user_id, demo_name(index)).await?;
+        report.users += 1;
+    }
+
+    // В первом чате все, в остальных - каждый второй, третий и так далее
+    let mut chats: Vec<ChatInfo> = vec![];
+    for (index, name) in GROUP_CHATS.iter().enumerate() {
+        let creator_id = user_ids[index % user_ids.len()];
+        let members = user_ids
+            .iter()
+            .enumerate()
+            .filter(|&(position, &id)| id != creator_id && position % (index + 1) == 0)
+            .map(|(_, &id)| id)
+            .collect();
+        chats.push(
+            db.create_new_chat(creator_id, members, ChatType::Group, name.to_string())
+                .await?,
+        );
+        report.group_chats += 1;
+    }
+    for (index, &user_id) in user_ids.iter().enumerate().skip(1).take(PRIVATE_CHATS) {
+        let name = format!("{} & {}", demo_name(0), demo_name(index));
+        chats.push(
+            db.create_new_chat(user_ids[0], vec![user_id], ChatType::Private, name)
+                .await?,
+        );
+        report.private_chats += 1;
+    }
+
+    // Сообщения идут по чатам по кругу, а последнее из них отправлено только что
+    let start = clock::CLOCK.now()
+        - chrono::Duration::seconds(MESSAGE_INTERVAL_SECS * config.messages as i64);
+    let mut last_messages: HashMap<Uuid, (Uuid, usize)> = HashMap::new();
+    for index in 0..config.messages {
+        let chat = &chats[index % chats.len()];
+        let (reply_to, count) = match last_messages.get(&chat.id) {
+            Some(&(last_id, count)) => ((count % REPLY_EVERY == 0).then_some(last_id), count),
+            None => (None, 0),
+        };
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: chat.users[(index / chats.len()) % chat.users.len()],
+            date: (start + chrono::Duration::seconds(MESSAGE_INTERVAL_SECS * index as i64)).into(),
+            msg_text: PHRASES[index % PHRASES.len()].to_string(),
+            edited_at: None,
+            reply_to,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        db.add_new_message_to_chat(message.clone()).await?;
+        last_messages.insert(chat.id, (message.message_id, count + 1));
+        report.messages += 1;
+        if report.messages % 1000 == 0 {
+            info!("Seeded {} of {} messages", report.messages, config.messages);
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,205 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    actors::redis_actor::{CallSignalData, EphemeralData},
+    actors::websocket_actor::{ChatMessage, ChatMessageView, MessageTombstone},
+    database::data::{Attachment, ChatInfo, UnpinnedMessage, UserProfile},
+    ids::{ChatId, UserId},
+    read_only::READ_ONLY_ERROR,
+    serializable_duration::SerializableDuration,
+    validation::FieldError,
+};
+
+// Схема событий
+//
+// Все, что сервер отправляет клиенту по вебсокету и другим экземплярам через Redis,
+// помечается полем v с версией схемы событий. Клиент называет версию, которую понимает,
+// в кадре capabilities, а до этого и без нее получает события версии MIN_PROTOCOL_VERSION.
+// Новые события, которых нет в версии клиента, переводятся в то, что он понимает.
+//
+// История версий:
+// 1) Исходная схема: hello, capabilities, error, history, chats, chat_info, slow_consumer,
+//    message_edited, message_deleted, message_unpinned, typing, mentioned,
+//    read_position_changed, message_ack и сообщения чата без поля event
+// 2) События validation_failed и read_only, клиенты версии 1 получают вместо них error
+//
+// Новое необязательное поле в существующем событии версию не меняет: клиенты не должны
+// отказываться от событий с незнакомыми полями. Не меняет ее и новое событие, которое
+// приходит только клиентам, заявившим одноименную возможность (например, chat_renamed).
+// Остальные новые события и изменение смысла существующего поля требуют новой версии и
+// шага в downgrade.
+
+/// Текущая версия схемы событий
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Самая старая версия, в которую сервер умеет переводить события
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Служебные события, которые сервер отправляет клиенту по вебсокету
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// Приветствие при подключении
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<&'static str>,
+    },
+    /// Возможности и версия схемы событий, о которых договорились клиент и сервер
+    Capabilities {
+        capabilities: Vec<String>,
+        version: u32,
+    },
+    /// Клиент прислал кадр, который сервер не понял, или запрос не удался
+    Error { message: String },
+    /// Ответ на fetch_history, сообщения идут от новых к старым
+    History {
+        chat_id: ChatId,
+        messages: Vec<ChatMessageView>,
+    },
+    /// Ответ на get_chats
+    Chats { chats: Vec<ChatId> },
+    /// Ответ на get_chat_info, для больших чатов список участников пустой, как и в REST API
+    ChatInfo { chat: ChatInfo },
+    /// Клиент не успевает забирать сообщения
+    SlowConsumer { grace_secs: u64 },
+    /// Сообщение в одном из чатов пользователя отредактировали
+    MessageEdited { message: ChatMessage },
+    /// Сообщение в одном из чатов пользователя удалили
+    MessageDeleted {
+        #[serde(flatten)]
+        tombstone: MessageTombstone,
+    },
+    /// С сообщения в одном из чатов пользователя сняли закрепление
+    MessageUnpinned {
+        #[serde(flatten)]
+        unpinned: UnpinnedMessage,
+    },
+    /// Другой участник чата печатает
+    Typing { chat_id: ChatId, user_id: UserId },
+    /// Кратковременный сигнал другого участника чата, в истории его нет
+    BroadcastEphemeral {
+        #[serde(flatten)]
+        data: EphemeralData,
+    },
+    /// Кадр сигнализации звонка, который другой участник чата адресовал этому пользователю
+    CallSignal {
+        #[serde(flatten)]
+        data: CallSignalData,
+    },
+    /// Пользователя упомянули в сообщении, само сообщение приходит отдельно
+    Mentioned {
+        chat_id: ChatId,
+        message_id: Uuid,
+        sender_id: UserId,
+        /// Однострочное начало текста, не длиннее text::PREVIEW_LENGTH символов
+        preview: String,
+    },
+    /// Пользователь прочитал чат на другом устройстве
+    ReadPositionChanged {
+        chat_id: ChatId,
+        message_id: Uuid,
+        date: SerializableDuration,
+    },
+    /// Сообщение клиента не прошло проверку и не сохранено
+    ValidationFailed {
+        chat_id: ChatId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        fields: Vec<FieldError>,
+    },
+    /// Сервис в режиме только для чтения, сообщение клиента не сохранено
+    ReadOnly {
+        chat_id: ChatId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        retry_after_secs: u64,
+    },
+    /// Токен подключения скоро истечет, через expires_in секунд сокет закроется
+    ReauthRequired { expires_in: u64 },
+    /// Экземпляр перегружен: если сокет закроется, переподключаться не раньше чем
+    /// через after_seconds
+    ReconnectHint { after_seconds: u64 },
+    /// Один из чатов пользователя переименовали
+    ChatRenamed {
+        chat_id: ChatId,
+        name: String,
+        renamed_by: UserId,
+    },
+    /// Пользователь или участник одного из его чатов сменил имя
+    ProfileUpdated {
+        user_id: UserId,
+        name: String,
+        #[serde(flatten)]
+        profile: UserProfile,
+    },
+    /// Участник чата появился в сети
+    MemberOnline { chat_id: ChatId, user_id: UserId },
+    /// Участник чата вышел из сети: у него не осталось ни одного сокета
+    MemberOffline { chat_id: ChatId, user_id: UserId },
+    /// Пользователя исключили из чата, событий этого чата больше не будет
+    RemovedFromChat { chat_id: ChatId, removed_by: UserId },
+    /// Сообщение клиента сохранено в базе
+    MessageAck {
+        chat_id: ChatId,
+        message_id: Uuid,
+        date: SerializableDuration,
+        /// client_msg_id из сообщения клиента, по нему клиент находит, что подтверждено
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+    },
+    /// Вложение, присланное бинарными кадрами, сохранено, его id можно указать в сообщении
+    AttachmentUploaded {
+        upload_id: Uuid,
+        attachment: Attachment,
+    },
+    /// Вложение, присланное бинарными кадрами, не сохранено, загрузку нужно начать заново
+    AttachmentUploadFailed { upload_id: Uuid, message: String },
+}
+
+/// Версия, в которой сервер будет отправлять события клиенту, запросившему requested
+pub fn negotiate_version(requested: Option<u32>) -> u32 {
+    requested
+        .unwrap_or(MIN_PROTOCOL_VERSION)
+        .clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Кадр для клиента, который понимает события версии version
+pub fn encode(event: &impl Serialize, version: u32) -> String {
+    let mut value = serde_json::to_value(event).expect("Cannot serialize event");
+    let version = version.clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+    for from in (version + 1..=PROTOCOL_VERSION).rev() {
+        value = downgrade(value, from);
+    }
+    stamp(value, version).to_string()
+}
+
+/// Сообщение для других экземпляров сервиса через Redis
+pub fn to_redis_payload(payload: &impl Serialize) -> String {
+    let value = serde_json::to_value(payload).expect("Cannot serialize payload");
+    stamp(value, PROTOCOL_VERSION).to_string()
+}
+
+fn stamp(mut value: Value, version: u32) -> Value {
+    if let Value::Object(fields) = &mut value {
+        fields.insert("v".into(), version.into());
+    }
+    value
+}
+
+/// Переводит событие версии from в версию from - 1
+fn downgrade(value: Value, from: u32) -> Value {
+    match (from, value.get("event").and_then(Value::as_str)) {
+        (2, Some("validation_failed")) => {
+            let message = value["fields"]
+                .get(0)
+                .and_then(|field| field["message"].as_str())
+                .unwrap_or("validation failed")
+                .to_string();
+            json!({ "event": "error", "message": message })
+        }
+        (2, Some("read_only")) => json!({ "event": "error", "message": READ_ONLY_ERROR }),
+        _ => value,
+    }
+}
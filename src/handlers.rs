@@ -2,34 +2,118 @@ use crate::{
     actors::{
         broker_actor::BrokerActor,
         database_actor::{self, DatabaseActor},
-        redis_actor::RedisActor,
-        websocket_actor::WebsocketActor,
+        redis_actor::{self, ChatRenamedData, MemberRemovedData, ProfileUpdatedData, RedisActor},
+        storage_actor::{self, StorageActor},
+        websocket_actor::{ChatMessageView, NewChatMessage, SessionMetadata, WebsocketActor},
+    },
+    config::ConfigHandle,
+    content::{ContentError, ContentKind, ContentProviders},
+    database::{
+        data::{
+            Attachment, ChannelListing, ChatEvent, ChatLabels, ChatRole, DeliveryMode, Mute,
+            NotificationSettings, ReadPosition, SecretKind, UserInfo, UserPreferences, UserProfile,
+        },
+        BlockedError, ContactLimitError, DBError, MemberLimitError, NameTakenError, PageIndex,
+        StringError,
+    },
+    http_client::HttpEndpoint,
+    i18n::{translate, DisplayHints, Locale},
+    ids::{ChatId, UserId},
+    load_shedding::{self, OverloadReason, OVERLOADED_ERROR},
+    metrics,
+    middlewares::{
+        auth_lockout_middleware::too_many_requests, authenticator_middleware::PUBLIC_PATH_PREFIX,
+        client_ip_middleware::ClientIp, token_middleware::TokenExpiresAt,
+    },
+    moderation::ModerationFilter,
+    pagination::PageMeta,
+    presence::PresenceTracker,
+    rate_limit::RateLimit,
+    services::{self, ServiceError},
+    session_binding::{self, BindingCheck, SessionBinder},
+    sharing::{ShareError, ShareLinks, ShareToken},
+    storage::{self, StorageError},
+    templates,
+    usage::{UsageTracker, DEFAULT_USAGE_DAYS},
+    validation::{
+        validate_draft, validate_file_name, validate_labels, validate_language,
+        validate_message_text, validate_name, validate_permissions, validate_preferences,
+        validate_profile, validate_sound, FieldError,
     },
-    database::{data::UserInfo, DBError},
 };
-use actix::Addr;
+use actix::{Addr, MailboxError};
 use actix_web::{
-    self, get, post, put,
+    self, delete, get,
+    http::{header, StatusCode},
+    post, put,
     web::{self, ReqData},
-    HttpRequest, HttpResponse, Responder,
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use actix_web_actors::ws;
+use futures::SinkExt;
+use futures_util::StreamExt;
+use log::{error, warn};
+use std::io;
 use uuid::Uuid;
 
+/// Через сколько секунд клиенту стоит повторить запрос, если актор не принял сообщение
+const MAILBOX_RETRY_AFTER_SECS: u64 = 1;
+
+/// Размер страницы списков пользователей и участников по умолчанию и максимальный
+const DEFAULT_USER_PAGE_SIZE: usize = 100;
+const MAX_USER_PAGE_SIZE: usize = 1000;
+
+/// Размер страницы ленты событий чата по умолчанию и максимальный
+const DEFAULT_EVENT_PAGE_SIZE: usize = 100;
+const MAX_EVENT_PAGE_SIZE: usize = 1000;
+
+/// Сколько публичных каналов отдается на один поиск по умолчанию и максимум
+const DEFAULT_DISCOVER_LIMIT: usize = 20;
+const MAX_DISCOVER_LIMIT: usize = 100;
+
+/// Самый долгий срок закрепления сообщения, год
+const MAX_PIN_EXPIRY_SECS: u64 = 365 * 24 * 3600;
+
+/// Самое долгое время жизни сообщений в чате, год
+const MAX_MESSAGE_TTL_SECS: u32 = 365 * 24 * 3600;
+
+/// Сколько результатов поиска контента отдается, если клиент не указал limit
+const DEFAULT_CONTENT_RESULTS: usize = 10;
+
+/// Сколько пользователей можно запросить за раз через /api/user/bulk-info
+const MAX_BULK_USERS: usize = 100;
+
 pub mod data_types {
-    use crate::database::PageIndex;
+    use std::collections::HashMap;
+
+    use crate::{content::ContentDescriptor, serializable_duration::SerializableDuration};
 
     use super::*;
     pub struct Addresses {
         pub db: Addr<DatabaseActor>,
         pub broker: Addr<BrokerActor>,
         pub redis: Addr<RedisActor>,
+        pub storage: Addr<StorageActor>,
     }
 
     #[derive(serde::Serialize, serde::Deserialize)]
     pub struct ChatHistoryRequest {
         pub chat_id: Uuid,
         pub page_index: Option<PageIndex>,
+        /// Курсор из заголовка X-Next-Cursor, заменяет page_index
+        #[serde(default)]
+        pub cursor: Option<String>,
+        pub page_size: usize,
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct ThreadRequest {
+        pub chat_id: Uuid,
+        pub message_id: Uuid,
+        pub page_index: Option<PageIndex>,
+        /// Курсор из заголовка X-Next-Cursor, заменяет page_index
+        #[serde(default)]
+        pub cursor: Option<String>,
         pub page_size: usize,
     }
 
@@ -47,6 +131,8 @@ pub mod data_types {
     pub struct UserInfoStripped {
         pub id: i64,
         pub name: String,
+        #[serde(flatten, default)]
+        pub profile: UserProfile,
     }
 
     impl From<UserInfo> for UserInfoStripped {
@@ -54,15 +140,74 @@ pub mod data_types {
             UserInfoStripped {
                 id: value.id,
                 name: value.name,
+                profile: value.profile,
             }
         }
     }
 
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserIds {
+        pub user_ids: Vec<i64>,
+    }
+
     #[derive(Debug, serde::Serialize, serde::Deserialize)]
     pub struct ChatId {
         pub chat_id: Uuid,
     }
 
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct MessageTtlChange {
+        pub chat_id: Uuid,
+        /// Через сколько секунд исчезают новые сообщения, без него или 0 - не исчезают
+        #[serde(default)]
+        pub ttl_secs: Option<u32>,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct DeliveryModeChange {
+        pub chat_id: Uuid,
+        pub mode: DeliveryMode,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct MemberLimitChange {
+        pub chat_id: Uuid,
+        /// Без значения у чата снова действует общее ограничение
+        #[serde(default)]
+        pub max_members: Option<u32>,
+    }
+
+    /// Новое имя пользователя
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserRename {
+        pub new_name: String,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatRename {
+        pub chat_id: Uuid,
+        pub new_chat_name: String,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserKick {
+        pub user_id: i64,
+        pub chat_id: Uuid,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct PermissionsChange {
+        pub chat_id: Uuid,
+        pub permissions: u32,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct RoleChange {
+        pub chat_id: Uuid,
+        pub user_id: i64,
+        pub role: ChatRole,
+    }
+
     #[derive(Debug, serde::Serialize, serde::Deserialize)]
     pub struct UserInvitation {
         pub guest_id: i64,
@@ -79,98 +224,897 @@ pub mod data_types {
     pub struct GroupChatCreationInfo {
         pub guest_users: String,
         pub new_chat_name: String,
+        /// Создать чат без незарегистрированных приглашенных и вернуть их список
+        #[serde(default)]
+        pub skip_unregistered: bool,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatSecret {
+        pub secret: String,
+    }
+
+    /// Какого пользователя сделать ботом и куда доставлять ему сообщения
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct BotRegistration {
+        pub user_id: i64,
+        pub callback_url: String,
+    }
+
+    /// Секрет, которым подписываются запросы на вебхук бота
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct BotSecret {
+        pub secret: String,
+    }
+
+    /// Отрезок истории, которым делятся: даты первого и последнего сообщения включительно
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ShareRequest {
+        pub chat_id: Uuid,
+        pub from: SerializableDuration,
+        pub to: SerializableDuration,
+        /// Срок ссылки, без него - share.default_ttl_secs
+        #[serde(default)]
+        pub expires_in_secs: Option<u64>,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ShareLink {
+        /// Путь ссылки относительно адреса сервиса
+        pub url: String,
+        pub token: String,
+        /// Секунды от начала эпохи
+        pub expires_at: i64,
+    }
+
+    /// Отрезок истории по ссылке
+    #[derive(serde::Serialize)]
+    pub struct SharedHistoryPage {
+        pub chat_id: Uuid,
+        pub name: String,
+        pub messages: Vec<ChatMessageView>,
+        pub expires_at: i64,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChannelCreationInfo {
+        pub new_chat_name: String,
+        /// Пишут только владелец и администраторы
+        #[serde(default)]
+        pub broadcast: bool,
+    }
+
+    /// Поиск публичных каналов по началу имени, пустой запрос отдает все каналы по алфавиту
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChannelDiscovery {
+        #[serde(default)]
+        pub query: String,
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChannelList {
+        pub channels: Vec<ChannelListing>,
+    }
+
+    /// Ограничения развертывания, по ним клиент проверяет ввод заранее
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct Limits {
+        /// Длина текста сообщения в символах
+        pub max_message_length: usize,
+        pub max_attachments_per_message: usize,
+        /// Размер одного вложения в байтах
+        pub max_attachment_bytes: usize,
+        pub max_chat_members: u32,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct InviteRedemption {
+        pub chat_id: Uuid,
+        pub code: String,
+    }
+
+    /// Правка сообщения: сообщение определяется id и датой отправки (в миллисекундах)
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct MessageEdit {
+        pub chat_id: Uuid,
+        pub message_id: Uuid,
+        pub date: i64,
+        pub msg_text: String,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ContentSearch {
+        #[serde(rename = "type")]
+        pub kind: ContentKind,
+        pub q: String,
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ContentSearchResults {
+        pub results: Vec<ContentDescriptor>,
+    }
+
+    /// Загрузка вложения, сам файл приходит телом запроса
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct AttachmentUpload {
+        pub chat_id: Uuid,
+        pub name: String,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct AttachmentRef {
+        pub attachment_id: Uuid,
+    }
+
+    /// Запрос на закрепление сообщения
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct PinRequest {
+        pub chat_id: Uuid,
+        pub message_id: Uuid,
+        /// Через сколько секунд закрепление снимется само, без него - бессрочно
+        #[serde(default)]
+        pub expires_in_secs: Option<u64>,
+    }
+
+    /// Запрос на создание чата по шаблону
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct TemplateChatRequest {
+        pub template_id: String,
+        /// Значения для подстановок в шаблоне имени
+        #[serde(default)]
+        pub params: HashMap<String, String>,
+        /// Участники сверх указанных в шаблоне
+        #[serde(default)]
+        pub members: Vec<i64>,
+    }
+
+    /// Новые настройки уведомлений пользователя в чате
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct NotificationSettingsChange {
+        pub chat_id: Uuid,
+        #[serde(flatten)]
+        pub settings: NotificationSettings,
+    }
+
+    /// Отключение уведомлений чата
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct MuteRequest {
+        pub chat_id: Uuid,
+        /// До какого момента отключить, без него - насовсем
+        #[serde(default)]
+        pub until: Option<SerializableDuration>,
+    }
+
+    /// Кого заблокировать или разблокировать
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserBlock {
+        pub user_id: i64,
+    }
+
+    /// Заблокированные пользователем, по возрастанию id
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct BlockList {
+        pub blocked: Vec<i64>,
+    }
+
+    /// Имя нового пользователя занято
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct NameTaken {
+        pub error: String,
+        pub name: String,
+        pub suggestions: Vec<String>,
+    }
+
+    /// Кого добавить в контакты или убрать из них
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct Contact {
+        pub user_id: i64,
+    }
+
+    /// Параметры счетчиков непрочитанных сообщений
+    #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+    pub struct UnreadRequest {
+        /// Отдать и счетчики чатов с отключенными уведомлениями
+        #[serde(default)]
+        pub include_muted: bool,
+    }
+
+    /// Параметры списка чатов пользователя
+    #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+    pub struct UserChatsRequest {
+        /// Вернуть вместе с каждым чатом, докуда пользователь его прочитал
+        #[serde(default)]
+        pub last_read: bool,
+        /// Вернуть вместе с каждым чатом, в архиве ли он у пользователя
+        #[serde(default)]
+        pub archived: bool,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UsageRequest {
+        #[serde(default)]
+        pub days: Option<u32>,
+        /// Чье использование смотреть, чужое доступно только администраторам
+        #[serde(default)]
+        pub user_id: Option<i64>,
+    }
+
+    /// Использование API пользователем за несколько суток
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserUsage {
+        pub user_id: i64,
+        /// Сумма по всем суткам
+        pub api_requests: u64,
+        pub ws_messages: u64,
+        /// По суткам, от новых к старым
+        pub days: Vec<crate::usage::DailyUsage>,
+    }
+
+    /// Чат из списка чатов пользователя вместе с отметкой о прочитанном
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserChat {
+        pub chat_id: Uuid,
+        #[serde(default)]
+        pub last_read: Option<ReadPosition>,
+        #[serde(default)]
+        pub archived: bool,
+    }
+
+    /// Новый текст черновика в чате, пустой текст удаляет черновик
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct DraftChange {
+        pub chat_id: Uuid,
+        #[serde(default)]
+        pub text: String,
+    }
+
+    /// Пересылка сообщения message_id из чата from_chat_id в чат to_chat_id
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ForwardRequest {
+        pub from_chat_id: Uuid,
+        pub message_id: Uuid,
+        pub to_chat_id: Uuid,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct MessageRef {
+        pub chat_id: Uuid,
+        pub message_id: Uuid,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserListRequest {
+        pub cursor: Option<String>,
+        pub page_size: Option<usize>,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatEventsRequest {
+        pub chat_id: Uuid,
+        pub since_position: Option<i64>,
+        pub page_size: Option<usize>,
+    }
+
+    /// Страница ленты событий чата, next_position нужно передать как since_position
+    /// за следующей страницей
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatEventsPage {
+        pub events: Vec<ChatEvent>,
+        pub next_position: Option<i64>,
+        #[serde(default)]
+        pub has_more: bool,
+    }
+
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatMembersRequest {
+        pub chat_id: Uuid,
+        pub cursor: Option<String>,
+        pub page_size: Option<usize>,
+    }
+
+    /// Страница участников чата, cursor нужно передать за следующей страницей
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ChatMembersPage {
+        pub users: Vec<i64>,
+        pub cursor: Option<String>,
+        #[serde(default)]
+        pub has_more: bool,
+    }
+
+    /// Страница списка пользователей, cursor нужно передать за следующей страницей
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct UserListPage {
+        pub users: Vec<UserInfoStripped>,
+        pub cursor: Option<String>,
+        #[serde(default)]
+        pub has_more: bool,
+    }
+
+    /// Тело ответа с ошибкой
+    ///
+    /// error - стабильный машиночитаемый код, message - описание для человека
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ErrorResponse {
+        pub error: String,
+        pub message: String,
+    }
+
+    /// Участники чата в сети
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct OnlineMembers {
+        pub chat_id: Uuid,
+        pub online_count: usize,
+        pub users: Vec<i64>,
+    }
+
+    /// Тело ответа на запрос с неправильными полями
+    #[derive(Debug, serde::Serialize, serde::Deserialize)]
+    pub struct ValidationErrorResponse {
+        pub error: String,
+        pub message: String,
+        pub fields: Vec<FieldError>,
+    }
+}
+
+/// Ответ с ошибкой: стабильный код и описание из каталога на языке клиента
+fn error_response(
+    status: StatusCode,
+    locale: Locale,
+    code: &str,
+    args: &[(&str, String)],
+) -> HttpResponse {
+    HttpResponse::build(status).json(data_types::ErrorResponse {
+        error: code.into(),
+        message: translate(locale, code, args),
+    })
+}
+
+/// Ответ на внутреннюю ошибку, подробности которой пишутся в лог, а не клиенту
+fn internal_error_response(locale: Locale, e: impl std::fmt::Display) -> HttpResponse {
+    error!("Request failed: {e}");
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        locale,
+        "internal_error",
+        &[],
+    )
+}
+
+/// Ответ на ошибку базы данных
+///
+/// Ошибка логики отдается со статусом status и своим кодом, остальные ошибки - внутренние
+fn db_error_response(locale: Locale, status: StatusCode, e: DBError) -> HttpResponse {
+    match e {
+        DBError::LogicError(e) => logic_error_response(locale, status, &*e),
+        DBError::QueryError(e) | DBError::OtherError(e) => internal_error_response(locale, e),
+    }
+}
+
+/// Ответ на ошибку логики из базы данных со статусом status
+fn logic_error_response(
+    locale: Locale,
+    status: StatusCode,
+    e: &(dyn std::error::Error + Send + 'static),
+) -> HttpResponse {
+    let (code, args) = logic_error_code(e);
+    error_response(status, locale, code, &args)
+}
+
+/// Код ошибки логики из базы данных и параметры для ее описания
+fn logic_error_code(
+    e: &(dyn std::error::Error + Send + 'static),
+) -> (&'static str, Vec<(&'static str, String)>) {
+    if let Some(e) = e.downcast_ref::<StringError>() {
+        (e.code, e.args.clone())
+    } else if let Some(e) = e.downcast_ref::<MemberLimitError>() {
+        ("member_limit", vec![("limit", e.limit.to_string())])
+    } else if let Some(e) = e.downcast_ref::<ContactLimitError>() {
+        ("contact_limit", vec![("limit", e.limit.to_string())])
+    } else if let Some(e) = e.downcast_ref::<BlockedError>() {
+        ("blocked", vec![("user_id", e.user_id.to_string())])
+    } else if let Some(e) = e.downcast_ref::<NameTakenError>() {
+        ("name_taken", vec![("name", e.name.clone())])
+    } else {
+        ("request_rejected", vec![])
+    }
+}
+
+/// Ответ на запрос администратора от обычного пользователя
+fn not_admin_response(locale: Locale) -> HttpResponse {
+    error_response(StatusCode::FORBIDDEN, locale, "not_admin", &[])
+}
+
+/// Ответ на неудачную отправку сообщения актору
+///
+/// Переполненный ящик или остановленный актор не должны ронять обработчик,
+/// поэтому возвращаем 503 с заголовком Retry-After, чтобы клиент повторил запрос позже
+fn mailbox_error_response(locale: Locale, actor: &str, e: MailboxError) -> HttpResponse {
+    metrics::MAILBOX_ERRORS.with_label_values(&[actor]).inc();
+    error!("Sending message to {actor} actor -> Failed: {e}");
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, MAILBOX_RETRY_AFTER_SECS))
+        .json(data_types::ErrorResponse {
+            error: "service_unavailable".into(),
+            message: translate(locale, "service_unavailable", &[]),
+        })
+}
+
+/// Ответ на подключение к вебсокету, когда экземпляр перегружен
+fn overloaded_response(
+    locale: Locale,
+    reason: OverloadReason,
+    retry_after_secs: u64,
+) -> HttpResponse {
+    metrics::SHED_CONNECTIONS
+        .with_label_values(&[reason.as_str()])
+        .inc();
+    warn!("Refusing websocket upgrade, instance is overloaded: {reason:?}");
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, retry_after_secs))
+        .json(data_types::ErrorResponse {
+            error: OVERLOADED_ERROR.into(),
+            message: translate(locale, OVERLOADED_ERROR, &[]),
+        })
+}
+
+/// Учитывает создание чата и проверяет лимит chats_per_hour, который у новых аккаунтов ниже
+///
+/// Если лимит исчерпан, то возвращает ответ TooManyRequests
+async fn check_chat_quota(
+    user_id: i64,
+    data: &data_types::Addresses,
+    limiter: &dyn RateLimit,
+    config: &ConfigHandle,
+    locale: Locale,
+) -> Result<(), HttpResponse> {
+    let account_age = account_age(user_id, data, locale).await?;
+    let per_hour = config.current().rate_limits.chats_per_hour_for(account_age);
+    match limiter.hit(&format!("chats:{user_id}"), 3600).await {
+        Ok(count) if count > per_hour as u64 => Err(too_many_requests(3600)),
+        Ok(_) => Ok(()),
+        // Если Redis недоступен, то создание чатов не ограничиваем
+        Err(e) => {
+            error!("Cannot check chat creation rate: {e}");
+            Ok(())
+        }
+    }
+}
+
+/// Проверяет лимит messages_per_minute, общий с сообщениями из вебсокета
+async fn check_message_quota(
+    user_id: i64,
+    data: &data_types::Addresses,
+    limiter: &dyn RateLimit,
+    config: &ConfigHandle,
+    locale: Locale,
+) -> Result<(), HttpResponse> {
+    let account_age = account_age(user_id, data, locale).await?;
+    let per_minute = config
+        .current()
+        .rate_limits
+        .messages_per_minute_for(account_age);
+    match limiter.hit(&format!("messages:{user_id}"), 60).await {
+        Ok(count) if count > per_minute as u64 => Err(too_many_requests(60)),
+        Ok(_) => Ok(()),
+        // Если Redis недоступен, то сообщения не ограничиваем
+        Err(e) => {
+            error!("Cannot check message rate: {e}");
+            Ok(())
+        }
     }
 }
 
+/// Сколько существует аккаунт пользователя, от этого зависят лимиты
+async fn account_age(
+    user_id: i64,
+    data: &data_types::Addresses,
+    locale: Locale,
+) -> Result<chrono::Duration, HttpResponse> {
+    let creation_date = match data
+        .db
+        .send(database_actor::messages::GetUserCreationDate {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return Err(mailbox_error_response(locale, "database", e)),
+    };
+    // Если возраст аккаунта узнать не удалось, то считаем аккаунт новым
+    Ok(creation_date
+        .map(services::account_age)
+        .unwrap_or_else(|_| chrono::Duration::zero()))
+}
+
+/// Ответ на запрос, поля которого не прошли проверку
+fn validation_error_response(locale: Locale, fields: Vec<FieldError>) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(data_types::ValidationErrorResponse {
+        error: "validation_failed".into(),
+        message: translate(locale, "validation_failed", &[]),
+        fields: fields.into_iter().map(|e| e.localize(locale)).collect(),
+    })
+}
+
+/// Ответ на запрос с именем пользователя, которое уже занято
+fn name_taken_response(e: &NameTakenError) -> HttpResponse {
+    HttpResponse::Conflict().json(data_types::NameTaken {
+        error: "name_taken".into(),
+        name: e.name.clone(),
+        suggestions: e.suggestions.clone(),
+    })
+}
+
+/// Создать новый приватный чат
+///
+/// Если имя чата не прошло проверку, то возвращаем UnprocessableEntity с ошибками по полям,
+/// если пользователь создает чаты слишком часто - TooManyRequests, а если гость заблокировал
+/// создателя - Forbidden
 #[post("/new-private")]
 async fn create_new_private_chat(
     user_id: web::ReqData<i64>,
     new_chat: web::Query<data_types::PrivateChatCreationInfo>,
     data: web::Data<data_types::Addresses>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
 ) -> impl Responder {
     let creator_id = user_id.into_inner();
-    let new_chat = new_chat.into_inner();
-    let new_chat_info = data
+    let mut new_chat = new_chat.into_inner();
+    new_chat.new_chat_name = match validate_name(
+        "new_chat_name",
+        &new_chat.new_chat_name,
+        &config.current().validation.chat_name,
+    ) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    if let Err(response) =
+        check_chat_quota(creator_id, &data, limiter.get_ref(), &config, locale).await
+    {
+        return response;
+    }
+    let new_chat_info = match data
         .db
         .send(database_actor::messages::CreateNewPrivateChat {
-            creator_id,
+            creator_id: UserId(creator_id),
             chat_name: new_chat.new_chat_name,
-            invited_user_id: new_chat.guest_user,
+            invited_user_id: UserId(new_chat.guest_user),
         })
         .await
-        .expect("Sending message to database actor -> Failed");
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
     match new_chat_info {
         Ok(info) => HttpResponse::Ok()
             .body(serde_json::to_string(&info).expect("Cannot convert chat info to string")),
-        Err(DBError::LogicError(e)) => HttpResponse::Conflict().body(e.to_string()),
-        Err(DBError::QueryError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
-        Err(DBError::OtherError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(DBError::LogicError(e)) if e.is::<BlockedError>() => {
+            logic_error_response(locale, StatusCode::FORBIDDEN, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::CONFLICT, e),
     }
 }
 
 /// Создать новый групповой чат
 ///
 /// Создает чат, приглашает в него пользователей и возвращает данные о чате
+/// Если имя чата не прошло проверку, то возвращаем UnprocessableEntity с ошибками по полям,
+/// если пользователь создает чаты слишком часто - TooManyRequests
+///
+/// Если кого-то из приглашенных нет среди пользователей, чат не создается (Conflict), как и
+/// если кто-то из них заблокировал создателя (Forbidden). С skip_unregistered=true чат создается с остальными, а в ответе вместе с чатом
+/// перечисляются пропущенные приглашенные
+///
+/// /api/chat/new-group?guest_users={[i64]}&new_chat_name={имя}&skip_unregistered={bool} = {ChatInfo} | {chat: {ChatInfo}, skipped: [{user_id, reason}]}
 #[post("/new-group")]
 async fn create_new_group_chat(
     user_id: web::ReqData<i64>,
     data: web::Data<data_types::Addresses>,
     new_chat: web::Query<data_types::GroupChatCreationInfo>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
 ) -> impl Responder {
     let new_chat = new_chat.into_inner();
     let creator_id = user_id.into_inner();
-    let chat_name = new_chat.new_chat_name;
+    let chat_name = match validate_name(
+        "new_chat_name",
+        &new_chat.new_chat_name,
+        &config.current().validation.chat_name,
+    ) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
     let invited_users_id = if let Ok(v) = serde_json::from_str::<Vec<i64>>(&new_chat.guest_users) {
         v
     } else {
-        return HttpResponse::BadRequest().body("Malformed json format for guest user ids");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "malformed_guest_users",
+            &[],
+        );
     };
-    let new_chat_info = data
+    if let Err(response) =
+        check_chat_quota(creator_id, &data, limiter.get_ref(), &config, locale).await
+    {
+        return response;
+    }
+    let new_chat_info = match data
         .db
         .send(database_actor::messages::CreateNewGroupChat {
-            creator_id,
+            creator_id: UserId(creator_id),
             chat_name,
-            invited_users_id,
+            invited_users_id: invited_users_id.into_iter().map(UserId).collect(),
+            skip_unregistered: new_chat.skip_unregistered,
         })
         .await
-        .expect("Sending message to database actor -> Failed");
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
     match new_chat_info {
-        Ok(info) => HttpResponse::Ok()
-            .body(serde_json::to_string(&info).expect("Cannot convert chat info to string")),
-        Err(DBError::LogicError(e)) => HttpResponse::Conflict().body(e.to_string()),
-        Err(DBError::QueryError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
-        Err(DBError::OtherError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(creation) if new_chat.skip_unregistered => HttpResponse::Ok().json(creation),
+        Ok(creation) => HttpResponse::Ok().body(
+            serde_json::to_string(&creation.chat).expect("Cannot convert chat info to string"),
+        ),
+        Err(DBError::LogicError(e)) if e.is::<BlockedError>() => {
+            logic_error_response(locale, StatusCode::FORBIDDEN, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::CONFLICT, e),
     }
 }
 
-/// Пригласить пользователя в чат
+/// Создать новый публичный канал
 ///
-/// Если приглашающий не состоит в данном чате или приглашенного пользователя в принципе не
-/// существует, то возвращается Forbidden
+/// Канал находят через /api/chat/discover и входят в него без приглашения
+/// Если имя канала не прошло проверку, то возвращаем UnprocessableEntity с ошибками по полям,
+/// если пользователь создает чаты слишком часто - TooManyRequests
 ///
-/// /api/chat/invite-user?guest_id={id пользователя}&chat_id={id чата}
-#[put("/new-user")]
+/// С broadcast=true в канал пишут только владелец и администраторы, остальные его читают
+///
+/// /api/chat/new-channel?new_chat_name={имя канала}&broadcast={bool} = {id: Uuid, name: String, users: [i64], chat_type: String}
+#[post("/new-channel")]
+async fn create_new_channel(
+    user_id: web::ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    new_chat: web::Query<data_types::ChannelCreationInfo>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let creator_id = user_id.into_inner();
+    let chat_name = match validate_name(
+        "new_chat_name",
+        &new_chat.new_chat_name,
+        &config.current().validation.chat_name,
+    ) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    if let Err(response) =
+        check_chat_quota(creator_id, &data, limiter.get_ref(), &config, locale).await
+    {
+        return response;
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::CreateNewChannel {
+            creator_id: UserId(creator_id),
+            chat_name,
+            broadcast: new_chat.broadcast,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(e) => db_error_response(locale, StatusCode::CONFLICT, e),
+    }
+}
+
+/// Найти публичные каналы по началу имени без учета регистра
+///
+/// /api/chat/discover?query={начало имени}&limit={сколько} = {channels: [{id: Uuid, name: String, member_count: u64}]}
+#[get("/discover")]
+async fn discover_channels(
+    search: web::Query<data_types::ChannelDiscovery>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let search = search.into_inner();
+    let limit = search
+        .limit
+        .unwrap_or(DEFAULT_DISCOVER_LIMIT)
+        .clamp(1, MAX_DISCOVER_LIMIT);
+    let result = match data
+        .db
+        .send(database_actor::messages::DiscoverChannels {
+            query: search.query,
+            limit,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(channels) => HttpResponse::Ok().json(data_types::ChannelList { channels }),
+        Err(e) => db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Войти в публичный канал без приглашения
+///
+/// Если чат не публичный канал или его нет, то возвращаем Forbidden, а если в канале уже
+/// столько участников, сколько можно, - Conflict
+///
+/// /api/chat/join-channel?chat_id={id чата} = {id: Uuid, name: String, users: [i64], chat_type: String}
+#[post("/join-channel")]
+async fn join_public_channel(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::JoinPublicChannel {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id.chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(DBError::LogicError(e)) if e.is::<MemberLimitError>() => {
+            logic_error_response(locale, StatusCode::CONFLICT, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Создать групповой чат по шаблону из конфигурации
+///
+/// Шаблон задает имя с подстановками {date}, {time} и {параметр}, участников, закрепленное
+/// сообщение и политику публикации. Если шаблона нет, то возвращаем NotFound, если не хватает
+/// параметра - BadRequest, если получившееся имя не прошло проверку - UnprocessableEntity,
+/// если пользователь создает чаты слишком часто - TooManyRequests
+///
+/// /api/chat/from-template {template_id: str, params: {str: str}, members: [i64]} = {id: Uuid, name: String, ...}
+#[post("/from-template")]
+async fn create_chat_from_template(
+    user_id: web::ReqData<i64>,
+    request: web::Json<data_types::TemplateChatRequest>,
+    data: web::Data<data_types::Addresses>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let creator_id = user_id.into_inner();
+    let request = request.into_inner();
+    let current = config.current();
+    let Some(template) = current.chat_templates.get(&request.template_id).cloned() else {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            locale,
+            "unknown_template",
+            &[("template", request.template_id)],
+        );
+    };
+    let name =
+        match templates::render_name(&template.name_pattern, &request.params, chrono::Utc::now()) {
+            Ok(name) => name,
+            Err(param) => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    locale,
+                    "missing_template_param",
+                    &[("param", param.to_string())],
+                )
+            }
+        };
+    let chat_name = match validate_name("new_chat_name", &name, &current.validation.chat_name) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    if let Err(response) =
+        check_chat_quota(creator_id, &data, limiter.get_ref(), &config, locale).await
+    {
+        return response;
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::CreateChatFromTemplate {
+            creator_id: UserId(creator_id),
+            template,
+            chat_name,
+            extra_members: request.members.into_iter().map(UserId).collect(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(created) => HttpResponse::Ok().json(created.chat),
+        Err(DBError::LogicError(e)) if e.is::<BlockedError>() => {
+            logic_error_response(locale, StatusCode::FORBIDDEN, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::CONFLICT, e),
+    }
+}
+
+/// Получить закрепленные сообщения чата, новые первыми
+///
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
+///
+/// /api/chat/pins?chat_id={id чата} = [{message_id: Uuid, date: i64, pinned_by: i64, pinned_at: i64, expires_at: i64?}]
+#[get("/pins")]
+async fn get_chat_pins(
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    user_id: web::ReqData<i64>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetPins {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id.chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(pins) => HttpResponse::Ok().json(pins),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Пригласить пользователя в чат
+///
+/// Приглашать могут владелец, администраторы и участники, если в чате это разрешено. Если
+/// приглашающему нельзя приглашать, приглашенного пользователя в принципе не существует, он
+/// заблокировал приглашающего или чат личный и в нем уже двое, то возвращается Forbidden, а если в чате уже столько участников, сколько можно, - Conflict
+///
+/// /api/chat/invite-user?guest_id={id пользователя}&chat_id={id чата}
+#[put("/new-user")]
 async fn add_user_to_chat(
     user_id: web::ReqData<i64>,
     invite_info: web::Query<data_types::UserInvitation>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
     let user_id = user_id.into_inner();
     let invite_info = invite_info.into_inner();
-    let result = data
+    let result = match data
         .db
         .send(database_actor::messages::InviteUserToChat {
-            user_id,
-            guest_user_id: invite_info.guest_id,
-            chat_id: invite_info.chat_id,
+            user_id: UserId(user_id),
+            guest_user_id: UserId(invite_info.guest_id),
+            chat_id: ChatId(invite_info.chat_id),
         })
         .await
-        .expect("Sending message to Database actor -> Failed");
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
     match result {
         Ok(_) => HttpResponse::Ok().finish(),
-        Err(DBError::LogicError(e)) => HttpResponse::Forbidden().body(e.to_string()),
-        Err(DBError::QueryError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
-        Err(DBError::OtherError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(DBError::LogicError(e)) if e.is::<MemberLimitError>() => {
+            logic_error_response(locale, StatusCode::CONFLICT, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
     }
 }
 
@@ -187,228 +1131,2834 @@ async fn exit_chat(
     user_id: web::ReqData<i64>,
     chat_id: web::Query<data_types::ChatId>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
     let user_id = user_id.into_inner();
     let chat_id = chat_id.chat_id;
-    let result = data
+    let result = match data
         .db
-        .send(database_actor::messages::ExitChat { user_id, chat_id })
+        .send(database_actor::messages::ExitChat {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+        })
         .await
-        .expect("Sending message to Database actor -> Failed");
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
     match result {
         Ok(_) => HttpResponse::Ok().finish(),
-        Err(DBError::LogicError(e)) => HttpResponse::Conflict().body(e.to_string()),
-        Err(DBError::QueryError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
-        Err(DBError::OtherError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => db_error_response(locale, StatusCode::CONFLICT, e),
     }
 }
 
-/// Получить информацию о чате
+/// Отправить чат в архив
 ///
-/// Берем id пользователя из токена и id чата из аргумента, возвращаем инфу о чате
-/// Если пользователь не состоит в чате или чата не существует, то возвращаем Forbidden
+/// Архив у каждого участника свой: пользователь остается в чате и получает его сообщения,
+/// а клиент прячет чат из основного списка по флагу archived в /api/user/chats.
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
 ///
-/// /api/chat/info?chat_id={id чата} = {id: Uuid, name: String, users: [i64], chat_type: String}
-#[get("/info")]
-async fn get_chat_info(
+/// /api/chat/archive?chat_id={id чата}
+#[put("/archive")]
+async fn archive_chat(
+    user_id: web::ReqData<i64>,
     chat_id: web::Query<data_types::ChatId>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    set_archived(user_id.into_inner(), chat_id.chat_id, true, &data, locale).await
+}
+
+/// Вернуть чат из архива
+///
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
+///
+/// /api/chat/archive?chat_id={id чата}
+#[delete("/archive")]
+async fn unarchive_chat(
     user_id: web::ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
-    let user_id = user_id.into_inner();
-    let chat_id = chat_id.chat_id;
-    let chat_info = data
+    set_archived(user_id.into_inner(), chat_id.chat_id, false, &data, locale).await
+}
+
+async fn set_archived(
+    user_id: i64,
+    chat_id: Uuid,
+    archived: bool,
+    data: &data_types::Addresses,
+    locale: Locale,
+) -> HttpResponse {
+    let result = match data
         .db
-        .send(database_actor::messages::GetChatInfo { user_id, chat_id })
+        .send(database_actor::messages::SetArchived {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            archived,
+        })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    let chat_info = match chat_info {
-        Ok(info) => info,
-        Err(DBError::LogicError(e)) => return HttpResponse::Forbidden().body(e.to_string()),
-        Err(DBError::QueryError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
-        Err(DBError::OtherError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
     };
-    HttpResponse::Ok().body(serde_json::to_string(&chat_info).unwrap())
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
 }
 
-/// Получить информацию о пользователе
-///
-/// Получаем информацию о любом пользователе, указав его id через аргумент user_id
-/// Токен не используется, но обязателен для доступа к апи
+/// Исключить участника из чата
 ///
-/// Если пользователя не существует, то возвращаем NotFound
+/// Исключать могут владелец и администраторы, администраторов - только владелец, владельца
+/// исключить нельзя. Подключенные сокеты исключенного получают событие removed_from_chat и
+/// перестают получать события чата. Если исключить нельзя, то возвращается Forbidden
 ///
-/// /api/user/info?user_id={id пользователя} = {id: i64, name: String}
-#[get("/info")]
-async fn get_user_info(
-    user_id: web::Query<data_types::UserId>,
+/// /api/chat/kick?user_id={id пользователя}&chat_id={id чата}
+#[put("/kick")]
+async fn kick_user(
+    user_id: web::ReqData<i64>,
+    kick: web::Query<data_types::UserKick>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
-    let user_id = user_id.user_id;
-    let user_info = data
+    let admin_id = user_id.into_inner();
+    let data_types::UserKick { user_id, chat_id } = kick.into_inner();
+    let result = match data
         .db
-        .send(database_actor::messages::GetUserInfo { user_id })
+        .send(database_actor::messages::RemoveUserFromChat {
+            admin_id: UserId(admin_id),
+            target_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+        })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    let user_info: data_types::UserInfoStripped = match user_info {
-        Ok(info) => info.into(),
-        Err(DBError::LogicError(e)) => return HttpResponse::NotFound().body(e.to_string()),
-        Err(DBError::OtherError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
-        Err(DBError::QueryError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
     };
-    return HttpResponse::Ok()
-        .body(serde_json::to_string(&user_info).expect("Failed converting user info to json"));
+    match result {
+        Ok(_) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::MemberRemoved(
+                    MemberRemovedData {
+                        chat_id: ChatId(chat_id),
+                        user_id: UserId(user_id),
+                        removed_by: UserId(admin_id),
+                    },
+                ));
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
 }
 
-/// Получить чаты текущего пользователя
-///
-/// Берет id пользователя из токена и возвращает список UUID чатов
+/// Задать, что можно обычным участникам чата
 ///
-/// Если не вышло, значит возвращаем Unauthorized
+/// permissions - битовая маска: 1 - приглашать, 2 - закреплять сообщения, 4 - переименовывать
+/// чат, 8 - отправлять вложения. Задать могут владелец и администраторы, им самим можно все.
+/// Если в маске неизвестные биты, то возвращается UnprocessableEntity, если запрашивающий не
+/// владелец и не администратор - Forbidden
 ///
-/// /api/user/chats = {[UUID]}
-#[get("/chats")]
-async fn get_user_chats(
-    user_id: ReqData<i64>,
+/// /api/chat/permissions {chat_id: UUID, permissions: u32}
+#[put("/permissions")]
+async fn set_chat_permissions(
+    user_id: web::ReqData<i64>,
+    change: web::Json<data_types::PermissionsChange>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
-    let chats = data
+    let data_types::PermissionsChange {
+        chat_id,
+        permissions,
+    } = change.into_inner();
+    let permissions = match validate_permissions("permissions", permissions) {
+        Ok(permissions) => permissions,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    let result = match data
         .db
-        .send(database_actor::messages::GetUserChats {
-            user_id: user_id.into_inner(),
+        .send(database_actor::messages::SetPermissions {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id),
+            permissions,
         })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    let chats = match chats {
-        Ok(c) => c,
-        Err(DBError::LogicError(e)) => return HttpResponse::Unauthorized().body(e.to_string()),
-        Err(DBError::OtherError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
-        Err(DBError::QueryError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
-        }
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
     };
-    HttpResponse::Ok()
-        .body(serde_json::to_string(&chats).expect("Failed converting user chats to json"))
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
 }
 
-/// Авторизация пользователя в сервисе чата
+/// Назначить участнику чата роль: owner, admin или member
 ///
-/// Берет id пользователя из токена и либо создает новый аккаунт в чате,
-/// либо ничего не делает, после чего возвращает данные о пользователе.
-/// Данный запрос может использоваться к
+/// Роли раздает только владелец. Назначив владельцем другого участника, владелец передает
+/// ему чат и сам становится администратором. Если запрашивающий не владелец, меняет свою
+/// роль или назначаемый не состоит в чате, то возвращается Forbidden
 ///
-/// Этот запрос необходимо делать каждый раз, когда пользователь только подключается к сервису
-/// чата, ибо может выйти так, что аккаунта пользователя в чате не сущетвует, из-за чего многие
-/// запросы будут выдавать ошибку Unauthorized
+/// /api/chat/role {chat_id: UUID, user_id: i64, role: str}
+#[put("/role")]
+async fn set_role(
+    user_id: web::ReqData<i64>,
+    change: web::Json<data_types::RoleChange>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let data_types::RoleChange {
+        chat_id,
+        user_id: target_id,
+        role,
+    } = change.into_inner();
+    let result = match data
+        .db
+        .send(database_actor::messages::SetRole {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id),
+            target_id: UserId(target_id),
+            role,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Переименовать чат
 ///
-/// /api/user/authorize?user_name={имя пользователя} = {id: i64, name: String, chats: [UUID]}
-#[post("/authorization")]
-async fn authorize_user(
-    user_id: ReqData<i64>,
+/// Переименовать могут владелец, администраторы и участники, если в чате это разрешено,
+/// подключенные участники получают событие chat_renamed. Если название не прошло проверку,
+/// то возвращаем UnprocessableEntity, если пользователю нельзя переименовать чат или
+/// чата нет - Forbidden
+///
+/// /api/chat/rename {chat_id: UUID, new_chat_name: str} = {chat_id: UUID, name: str, renamed_by: i64}
+#[put("/rename")]
+async fn rename_chat(
+    user_id: web::ReqData<i64>,
+    rename: web::Json<data_types::ChatRename>,
     data: web::Data<data_types::Addresses>,
-    user_name: web::Query<data_types::UserName>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
 ) -> impl Responder {
-    let user_name = user_name.into_inner().user_name;
     let user_id = user_id.into_inner();
-    let user_info = data
+    let data_types::ChatRename {
+        chat_id,
+        new_chat_name,
+    } = rename.into_inner();
+    let name = match validate_name(
+        "new_chat_name",
+        &new_chat_name,
+        &config.current().validation.chat_name,
+    ) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    let result = match data
         .db
-        .send(database_actor::messages::GetUserInfo { user_id })
+        .send(database_actor::messages::RenameChat {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            new_name: name.clone(),
+        })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    let user_info = match user_info {
-        Ok(info) => info,
-        Err(DBError::LogicError(_)) => {
-            let new_info = data
-                .db
-                .send(database_actor::messages::CreateNewUser { user_id, user_name })
-                .await
-                .expect("Sending message to Database actor -> Failed")
-                .expect("User creation failed, bruh moment");
-            new_info
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            let renamed = ChatRenamedData {
+                chat_id: ChatId(chat_id),
+                name,
+                renamed_by: UserId(user_id),
+            };
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::ChatRenamed(
+                    renamed.clone(),
+                ));
+            HttpResponse::Ok().json(renamed)
         }
-        Err(DBError::QueryError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Отредактировать свое сообщение
+///
+/// Подключенные участники чата получают событие message_edited.
+/// Если пользователь не отправлял это сообщение или его нет, то возвращаем Forbidden,
+/// если новый текст не прошел проверку или фильтр модерации - UnprocessableEntity с
+/// ошибками по полям
+///
+/// /api/chat/message {chat_id: UUID, message_id: UUID, date: i64, msg_text: str} = {сообщение}
+#[put("/message")]
+async fn edit_message(
+    user_id: web::ReqData<i64>,
+    message_edit: web::Json<data_types::MessageEdit>,
+    data: web::Data<data_types::Addresses>,
+    moderation: web::Data<dyn ModerationFilter>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let message_edit = message_edit.into_inner();
+    let msg_text = match validate_message_text(
+        "msg_text",
+        &message_edit.msg_text,
+        &config.current().validation.message,
+    )
+    .and_then(|text| moderation.check("msg_text", &text).map(|_| text))
+    {
+        Ok(text) => text,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::EditMessage {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(message_edit.chat_id),
+            message_id: message_edit.message_id,
+            date: chrono::Duration::milliseconds(message_edit.date),
+            msg_text,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(message) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::MessageEdited(
+                    message.clone(),
+                ));
+            HttpResponse::Ok().json(message)
         }
-        Err(DBError::OtherError(e)) => {
-            return HttpResponse::InternalServerError().body(e.to_string())
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Отправить сообщение в чат
+///
+/// Сообщение проверяется, сохраняется и рассылается участникам чата так же, как
+/// отправленное по вебсокету, и с тем же лимитом сообщений в минуту. Если текст или
+/// вложения не прошли проверку, то возвращаем UnprocessableEntity с ошибками по полям,
+/// если пользователь отправляет сообщения слишком часто - TooManyRequests, если ему нельзя
+/// писать в чат - Forbidden
+///
+/// /api/chat/message {chat_id: UUID, msg_text: str, reply_to: UUID?, attachments: [UUID]?, client_msg_id: str?} = {сообщение}
+#[post("/message")]
+async fn send_message(
+    user_id: web::ReqData<i64>,
+    message: web::Json<NewChatMessage>,
+    data: web::Data<data_types::Addresses>,
+    limiter: web::Data<dyn RateLimit>,
+    moderation: web::Data<dyn ModerationFilter>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let rules = config.current().validation.message.clone();
+    let message = match services::compose_message(
+        UserId(user_id),
+        message.into_inner(),
+        &rules,
+        moderation.get_ref(),
+    ) {
+        Ok(message) => message,
+        Err(ServiceError::Invalid(fields)) => return validation_error_response(locale, fields),
+        Err(ServiceError::Database(e)) => {
+            return db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e)
         }
     };
-    HttpResponse::Ok().body(serde_json::to_string(&user_info).expect("Cannot serialize user info"))
+    if let Err(response) =
+        check_message_quota(user_id, &data, limiter.get_ref(), &config, locale).await
+    {
+        return response;
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::InsertNewMessage(message))
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(inserted) => {
+            // Повтор с тем же client_msg_id не рассылается еще раз
+            if !inserted.duplicate {
+                data.redis
+                    .do_send(redis_actor::messages::WebsocketMessage::NewMessage(
+                        inserted.message.clone(),
+                    ));
+            }
+            HttpResponse::Ok().json(inserted.message)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
 }
 
-/// Получить предудыщуие сообщения из чата с пагинацией
-/// page_index может не присутствовать, при первом запросе, однако, он обязан быть при последующих
-/// Индекс можно получить из первого запроса
-/// /api/chat/history?chat_id={id_чата}&page_index={индекс}&page_size={размер_страницы}
-/// = {[[сообщения], индекс]}
-#[get("/history")]
-async fn get_chat_history(
-    user_id: ReqData<i64>,
-    req: web::Query<data_types::ChatHistoryRequest>,
+/// Удалить свое сообщение
+///
+/// Подключенные участники чата получают событие message_deleted.
+/// Если пользователь не отправлял это сообщение или его нет, то возвращаем Forbidden
+///
+/// /api/chat/message?chat_id={id чата}&message_id={id сообщения}
+#[delete("/message")]
+async fn delete_message(
+    user_id: web::ReqData<i64>,
+    message: web::Query<data_types::MessageRef>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
-    let user_id = user_id.into_inner();
-    let req_info = req.into_inner();
-    let chat_id = req_info.chat_id;
-    let page_index = req_info.page_index;
-    let page_size = req_info.page_size;
-    let chat_history = data
+    let result = match data
         .db
-        .send(database_actor::messages::GetChatHistory {
-            user_id,
-            chat_id,
-            page_size,
-            page_index,
+        .send(database_actor::messages::DeleteMessage {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(message.chat_id),
+            message_id: message.message_id,
         })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    match chat_history {
-        Ok(v) => HttpResponse::Ok().body(serde_json::to_string(&v).unwrap()),
-        Err(DBError::LogicError(e)) => HttpResponse::Forbidden().body(e.to_string()),
-        Err(DBError::QueryError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
-        Err(DBError::OtherError(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(tombstone) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::MessageDeleted(
+                    tombstone,
+                ));
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
     }
 }
 
-#[get("/ws")]
-async fn websocket_startup(
-    req: HttpRequest,
-    user_id: ReqData<i64>,
-    stream: web::Payload,
+/// Переслать сообщение в другой чат
+///
+/// Сообщение копируется в чат назначения от имени пользователя вместе с вложениями
+/// и полем forwarded_from, участники чата получают его как обычное сообщение.
+/// Если пользователь не состоит в одном из чатов или сообщения нет, то возвращаем Forbidden
+///
+/// /api/chat/forward {from_chat_id: UUID, message_id: UUID, to_chat_id: UUID} = {сообщение}
+#[post("/forward")]
+async fn forward_message(
+    user_id: web::ReqData<i64>,
+    request: web::Json<data_types::ForwardRequest>,
     data: web::Data<data_types::Addresses>,
+    locale: Locale,
 ) -> impl Responder {
-    let user_id = user_id.into_inner();
-    let user_info = data
+    let request = request.into_inner();
+    let result = match data
         .db
-        .send(database_actor::messages::GetUserInfo { user_id })
+        .send(database_actor::messages::ForwardMessage {
+            user_id: UserId(user_id.into_inner()),
+            from_chat_id: ChatId(request.from_chat_id),
+            message_id: request.message_id,
+            to_chat_id: ChatId(request.to_chat_id),
+        })
         .await
-        .expect("Sending message to Database actor -> Failed");
-    match user_info {
-        Ok(_) => {}
-        Err(DBError::LogicError(e)) => return Ok(HttpResponse::Unauthorized().body(e.to_string())),
-        Err(DBError::OtherError(e)) => {
-            return Ok(HttpResponse::InternalServerError().body(e.to_string()))
-        }
-        Err(DBError::QueryError(e)) => {
-            return Ok(HttpResponse::InternalServerError().body(e.to_string()))
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(message) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::NewMessage(
+                    message.clone(),
+                ));
+            HttpResponse::Ok().json(message)
         }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
     }
-    let new_websocket = WebsocketActor::new(
-        data.broker.clone(),
-        data.redis.clone(),
-        data.db.clone(),
-        user_id,
-    );
-    let resp = ws::start(new_websocket, &req, stream);
-    resp
+}
+
+/// Загрузить вложение в чат
+///
+/// Файл приходит телом запроса, его тип - заголовком Content-Type. Файл передается в хранилище
+/// по мере приема, а описание с ссылкой - в базу; id описания можно указать в attachments
+/// сообщения. Тип не из storage::INLINE_CONTENT_TYPES сохраняется как application/octet-stream.
+/// Если пользователь не состоит в чате, то возвращаем Forbidden, если нет Content-Length -
+/// LengthRequired, если файл больше storage.max_attachment_bytes - PayloadTooLarge, если имя
+/// файла не прошло проверку - UnprocessableEntity, если хранилище не настроено - NotFound,
+/// если оно не ответило - BadGateway
+///
+/// /api/chat/attachment?chat_id={id чата}&name={имя файла} = {id: Uuid, chat_id: Uuid, uploader_id: i64, name: String, size: u64, mime: String, url: String, created_at: i64}
+#[post("/attachment")]
+async fn upload_attachment(
+    user_id: web::ReqData<i64>,
+    upload: web::Query<data_types::AttachmentUpload>,
+    request: HttpRequest,
+    mut payload: web::Payload,
+    data: web::Data<data_types::Addresses>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let upload = upload.into_inner();
+    let name = match validate_file_name("name", &upload.name) {
+        Ok(name) => name,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    // Проверяем участие до загрузки, чтобы посторонние не засоряли хранилище
+    match data
+        .db
+        .send(database_actor::messages::CheckMembership {
+            user_id: UserId(user_id),
+            chat_id: ChatId(upload.chat_id),
+        })
+        .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return db_error_response(locale, StatusCode::FORBIDDEN, e),
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    }
+    // Файл не копится в памяти, а сразу уходит в хранилище, поэтому его длина нужна заранее:
+    // и чтобы отклонить слишком большой файл до загрузки, и для запроса к хранилищу
+    let max_bytes = config.static_config().storage.max_attachment_bytes;
+    let Some(size) = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    else {
+        return error_response(StatusCode::LENGTH_REQUIRED, locale, "length_required", &[]);
+    };
+    if size > max_bytes as u64 {
+        return error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            locale,
+            "attachment_too_large",
+            &[("max", max_bytes.to_string())],
+        );
+    }
+    let mime = storage::attachment_content_type(
+        request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(storage::FALLBACK_CONTENT_TYPE),
+    )
+    .to_string();
+    let id = Uuid::new_v4();
+    // Куски тела перекладываются в канал, из которого их читает запрос к хранилищу;
+    // ограниченный канал не дает клиенту прислать больше, чем хранилище успевает принять
+    let (mut sender, receiver) = futures::channel::mpsc::channel(4);
+    let put = data.storage.send(storage_actor::messages::PutObject {
+        key: format!("{}/{id}", upload.chat_id),
+        content_type: mime.clone(),
+        length: size,
+        body: Box::pin(receiver),
+    });
+    let forward = async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| e.to_string());
+            let failed = chunk.as_ref().err().cloned();
+            // Хранилище уже ответило, дальше читать незачем
+            if sender.send(chunk.map_err(io::Error::other)).await.is_err() {
+                break;
+            }
+            if failed.is_some() {
+                return failed;
+            }
+        }
+        None
+    };
+    let (stored, payload_error) = futures::join!(put, forward);
+    if let Some(e) = payload_error {
+        warn!("Attachment upload was interrupted: {e}");
+        return error_response(StatusCode::BAD_REQUEST, locale, "upload_interrupted", &[]);
+    }
+    let url = match stored {
+        Ok(Ok(url)) => url,
+        Ok(Err(StorageError::NotConfigured)) => {
+            return error_response(StatusCode::NOT_FOUND, locale, "storage_not_configured", &[])
+        }
+        Ok(Err(e @ StorageError::Backend(_))) => {
+            warn!("{e}");
+            return error_response(StatusCode::BAD_GATEWAY, locale, "storage_failed", &[]);
+        }
+        Err(e) => return mailbox_error_response(locale, "storage", e),
+    };
+    let attachment = Attachment {
+        id,
+        chat_id: upload.chat_id,
+        uploader_id: user_id,
+        name,
+        size,
+        mime,
+        url,
+        created_at: (chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH).into(),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::AddAttachment(attachment.clone()))
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().json(attachment),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить описание вложения
+///
+/// Если вложения нет или оно загружено в чат, где пользователь не состоит, то возвращаем NotFound
+///
+/// /api/chat/attachment?attachment_id={id вложения} = {id: Uuid, chat_id: Uuid, uploader_id: i64, name: String, size: u64, mime: String, url: String, created_at: i64}
+#[get("/attachment")]
+async fn get_attachment(
+    user_id: web::ReqData<i64>,
+    attachment: web::Query<data_types::AttachmentRef>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetAttachment {
+            user_id: UserId(user_id.into_inner()),
+            attachment_id: attachment.attachment_id,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(attachment) => HttpResponse::Ok().json(attachment),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Закрепить сообщение чата
+///
+/// Закрепление с expires_in_secs снимается автоматически. Если в чате уже закреплено
+/// pins.max_per_chat сообщений, то самые старые закрепления снимаются, и подключенные участники
+/// получают о них событие message_unpinned. Если пользователь не состоит в чате или сообщения нет,
+/// то возвращаем Forbidden
+///
+/// /api/chat/pin {chat_id: UUID, message_id: UUID, expires_in_secs: u64?} = {message_id: UUID, date: i64, pinned_by: i64, pinned_at: i64, expires_at: i64?}
+#[post("/pin")]
+async fn pin_message(
+    user_id: web::ReqData<i64>,
+    request: web::Json<data_types::PinRequest>,
+    data: web::Data<data_types::Addresses>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let request = request.into_inner();
+    if request.expires_in_secs > Some(MAX_PIN_EXPIRY_SECS) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "pin_expiry_too_long",
+            &[("max", MAX_PIN_EXPIRY_SECS.to_string())],
+        );
+    }
+    let expires_at = request.expires_in_secs.map(|secs| {
+        chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH + chrono::Duration::seconds(secs as i64)
+    });
+    let result = match data
+        .db
+        .send(database_actor::messages::PinMessage {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(request.chat_id),
+            message_id: request.message_id,
+            expires_at,
+            max_pins: config.static_config().pins.max_per_chat,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(outcome) => {
+            for unpinned in outcome.rotated {
+                data.redis
+                    .do_send(redis_actor::messages::WebsocketMessage::MessageUnpinned(
+                        unpinned,
+                    ));
+            }
+            HttpResponse::Ok().json(outcome.pin)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Открепить сообщение чата
+///
+/// Подключенные участники чата получают событие message_unpinned.
+/// Если пользователь не состоит в чате или сообщение не закреплено, то возвращаем Forbidden
+///
+/// /api/chat/pin?chat_id={id чата}&message_id={id сообщения}
+#[delete("/pin")]
+async fn unpin_message(
+    user_id: web::ReqData<i64>,
+    message: web::Query<data_types::MessageRef>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::UnpinMessage {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(message.chat_id),
+            message_id: message.message_id,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(unpinned) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::MessageUnpinned(
+                    unpinned,
+                ));
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить информацию о чате
+///
+/// Берем id пользователя из токена и id чата из аргумента, возвращаем инфу о чате
+/// Если пользователь не состоит в чате или чата не существует, то возвращаем Forbidden
+/// Если участников больше max_inline_members, то список users пустой,
+/// а участников нужно получать через /api/chat/members
+/// Если у чата не задан режим доставки, то в delivery_mode отдается режим по умолчанию
+///
+/// /api/chat/info?chat_id={id чата} = {id: Uuid, name: String, users: [i64], chat_type: String, member_count: usize, delivery_mode: String}
+#[get("/info")]
+async fn get_chat_info(
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    user_id: web::ReqData<i64>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let chat_id = chat_id.chat_id;
+    let chat_info = match data
+        .db
+        .send(database_actor::messages::GetChatInfo {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let chat_info = match chat_info {
+        Ok(info) => services::chat_for_client(info, &config),
+        Err(e) => return db_error_response(locale, StatusCode::FORBIDDEN, e),
+    };
+    HttpResponse::Ok().body(serde_json::to_string(&chat_info).unwrap())
+}
+
+/// Участники чата, которые сейчас в сети на любом из экземпляров сервиса
+///
+/// Отдается только для чатов не больше presence.max_chat_size участников, для больших
+/// возвращаем BadRequest. Если пользователь не участник чата - Forbidden
+///
+/// /api/chat/online?chat_id={id чата} = {chat_id: Uuid, online_count: usize, users: [i64]}
+#[get("/online")]
+async fn get_online_members(
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    user_id: web::ReqData<i64>,
+    presence: web::Data<PresenceTracker>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let settings = &config.static_config().presence;
+    if !settings.enabled {
+        return error_response(StatusCode::NOT_FOUND, locale, "presence_disabled", &[]);
+    }
+    let chat_info = match data
+        .db
+        .send(database_actor::messages::GetChatInfo {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id.chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let chat_info = match chat_info {
+        Ok(info) => info,
+        Err(e) => return db_error_response(locale, StatusCode::FORBIDDEN, e),
+    };
+    if chat_info.member_count > settings.max_chat_size {
+        return error_response(StatusCode::BAD_REQUEST, locale, "chat_too_large", &[]);
+    }
+    let members: Vec<UserId> = chat_info.users.iter().copied().map(UserId).collect();
+    match presence.online_users(&members).await {
+        Ok(users) => HttpResponse::Ok().json(data_types::OnlineMembers {
+            chat_id: chat_info.id,
+            online_count: users.len(),
+            users: users.into_iter().map(i64::from).collect(),
+        }),
+        Err(e) => internal_error_response(
+            locale,
+            format!("Cannot read presence of chat {}: {e}", chat_info.id),
+        ),
+    }
+}
+
+/// Задать время жизни сообщений в чате
+///
+/// Менять его может только создатель чата. Время действует только на новые сообщения,
+/// уже отправленные живут столько, сколько было задано при отправке. Если время больше
+/// года, то возвращаем BadRequest, если пользователь не создатель чата - Forbidden
+///
+/// /api/chat/ttl {chat_id: UUID, ttl_secs: u32?}
+#[put("/ttl")]
+async fn set_message_ttl(
+    user_id: web::ReqData<i64>,
+    change: web::Json<data_types::MessageTtlChange>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let change = change.into_inner();
+    let ttl_secs = change.ttl_secs.unwrap_or(0);
+    if ttl_secs > MAX_MESSAGE_TTL_SECS {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "ttl_too_long",
+            &[("max", MAX_MESSAGE_TTL_SECS.to_string())],
+        );
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::SetMessageTtl {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(change.chat_id),
+            ttl_secs,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Изменить настройки уведомлений в чате
+///
+/// Настройки действуют только для текущего пользователя и отдаются в /api/chat/info.
+/// Если пользователь не состоит в чате, то возвращаем Forbidden, если идентификатор звука
+/// не прошел проверку - UnprocessableEntity
+///
+/// /api/chat/notifications {chat_id: UUID, priority: all|mentions_only|none, sound: str?}
+#[put("/notifications")]
+async fn set_notification_settings(
+    user_id: web::ReqData<i64>,
+    change: web::Json<data_types::NotificationSettingsChange>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let data_types::NotificationSettingsChange {
+        chat_id,
+        mut settings,
+    } = change.into_inner();
+    settings.sound = match settings.sound.as_deref() {
+        None | Some("") => None,
+        Some(sound) => match validate_sound("sound", sound) {
+            Ok(sound) => Some(sound),
+            Err(e) => return validation_error_response(locale, vec![e]),
+        },
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SetNotificationSettings {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            settings,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            data.redis
+                .do_send(redis_actor::messages::NotificationsChanged {
+                    user_id: UserId(user_id),
+                    chat_id: ChatId(chat_id),
+                });
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить свой черновик в чате
+///
+/// Черновики общие для всех устройств пользователя. Если пользователь не состоит в чате,
+/// то возвращаем Forbidden, если черновика нет - NotFound
+///
+/// /api/chat/draft?chat_id={id чата} = {chat_id: Uuid, text: String, updated_at: i64}
+#[get("/draft")]
+async fn get_draft(
+    user_id: web::ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetDraft {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(chat_id.chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(Some(draft)) => HttpResponse::Ok().json(draft),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, locale, "no_draft", &[]),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Сохранить свой черновик в чате
+///
+/// Сохраненный черновик заменяет прежний, пустой текст удаляет черновик. Если пользователь
+/// не состоит в чате, то возвращаем Forbidden, если текст слишком длинный - UnprocessableEntity
+///
+/// /api/chat/draft {chat_id: Uuid, text: String} = {chat_id: Uuid, text: String, updated_at: i64}
+#[put("/draft")]
+async fn save_draft(
+    user_id: web::ReqData<i64>,
+    change: web::Json<data_types::DraftChange>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let change = change.into_inner();
+    let text = match validate_draft("text", &change.text) {
+        Ok(text) => text,
+        Err(e) => return validation_error_response(locale, vec![e]),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SaveDraft {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(change.chat_id),
+            text,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(Some(draft)) => HttpResponse::Ok().json(draft),
+        Ok(None) => HttpResponse::NoContent().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Найти внешний контент (гифки, стикеры)
+///
+/// Поиск идет через поставщика, настроенного для этого вида контента. Если поставщика нет,
+/// то возвращаем NotFound, если он не ответил - BadGateway. Частота поиска ограничена
+/// content_searches_per_minute на пользователя
+///
+/// /api/content/search?type={gif|sticker}&q={запрос}&limit={сколько} = {results: [{provider: str, kind: str, id: str, title: str, url: str, preview_url: str?, width: u32?, height: u32?}]}
+#[get("/search")]
+async fn search_content(
+    user_id: web::ReqData<i64>,
+    search: web::Query<data_types::ContentSearch>,
+    providers: web::Data<ContentProviders>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let search = search.into_inner();
+    let query = search.q.trim();
+    if query.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, locale, "empty_query", &[]);
+    }
+    let per_minute = config.current().rate_limits.content_searches_per_minute;
+    match limiter
+        .hit(&format!("content:{}", user_id.into_inner()), 60)
+        .await
+    {
+        Ok(count) if count > per_minute as u64 => return too_many_requests(60),
+        Ok(_) => {}
+        // Если Redis недоступен, то поиск не ограничиваем
+        Err(e) => error!("Cannot check content search rate: {e}"),
+    }
+    let limit = search.limit.unwrap_or(DEFAULT_CONTENT_RESULTS);
+    match providers.search(search.kind, query, limit).await {
+        Ok(results) => HttpResponse::Ok().json(data_types::ContentSearchResults { results }),
+        Err(ContentError::NotConfigured(_)) => {
+            error_response(StatusCode::NOT_FOUND, locale, "content_not_configured", &[])
+        }
+        Err(e @ ContentError::Provider(_)) => {
+            warn!("{e}");
+            error_response(
+                StatusCode::BAD_GATEWAY,
+                locale,
+                "content_provider_failed",
+                &[],
+            )
+        }
+    }
+}
+
+/// Получить страницу ленты событий чата
+///
+/// События (новые, отредактированные и удаленные сообщения, вход и выход участников,
+/// переименование чата) идут по возрастанию позиции, начиная после since_position. Лента
+/// нужна внешним потребителям вроде поисковых индексов и архивов: они читают ее до конца,
+/// запоминают последнюю позицию и продолжают с нее. Читать ленту могут участники чата и
+/// администраторы, остальным возвращаем Forbidden
+///
+/// /api/chat/events-feed?chat_id={id чата}&since_position={i64}&page_size={размер страницы} = {events: [{position: i64, event_id: UUID, kind: str, date: i64, payload: {...}}], next_position: i64?, has_more: bool}
+#[get("/events-feed")]
+async fn get_chat_events_feed(
+    http_req: HttpRequest,
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::ChatEventsRequest>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let request = request.into_inner();
+    let user_id = user_id.into_inner();
+    if !config.current().is_admin(user_id) {
+        match data
+            .db
+            .send(database_actor::messages::CheckMembership {
+                user_id: UserId(user_id),
+                chat_id: ChatId(request.chat_id),
+            })
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return db_error_response(locale, StatusCode::FORBIDDEN, e),
+            Err(e) => return mailbox_error_response(locale, "database", e),
+        }
+    }
+    let page_size = request
+        .page_size
+        .unwrap_or(DEFAULT_EVENT_PAGE_SIZE)
+        .clamp(1, MAX_EVENT_PAGE_SIZE);
+    let result = match data
+        .db
+        .send(database_actor::messages::GetChatEvents {
+            chat_id: ChatId(request.chat_id),
+            since_position: request.since_position,
+            page_size,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok((events, next)) => {
+            let meta = PageMeta::new(next.map(|position| position.to_string()));
+            let mut response = HttpResponse::Ok();
+            meta.insert_headers(&mut response, http_req.uri(), "since_position");
+            response.json(data_types::ChatEventsPage {
+                events,
+                has_more: meta.has_more,
+                next_position: next,
+            })
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить страницу участников чата
+///
+/// Участники идут по возрастанию id. Если пользователь не состоит в чате, то возвращаем
+/// Forbidden, если курсор битый - BadRequest
+///
+/// /api/chat/members?chat_id={id чата}&cursor={курсор}&page_size={размер страницы} = {users: [i64], cursor: String, has_more: bool}
+#[get("/members")]
+async fn get_chat_members(
+    http_req: HttpRequest,
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::ChatMembersRequest>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let request = request.into_inner();
+    let after_user = match request.cursor.map(|cursor| cursor.parse::<UserId>()) {
+        None => None,
+        Some(Ok(user)) => Some(user),
+        Some(Err(_)) => {
+            return error_response(StatusCode::BAD_REQUEST, locale, "invalid_cursor", &[])
+        }
+    };
+    let page_size = request
+        .page_size
+        .unwrap_or(DEFAULT_USER_PAGE_SIZE)
+        .clamp(1, MAX_USER_PAGE_SIZE);
+    let result = match data
+        .db
+        .send(database_actor::messages::GetChatMembersPaged {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(request.chat_id),
+            after_user,
+            page_size,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok((users, next)) => {
+            let meta = PageMeta::new(next.map(|user| user.to_string()));
+            let mut response = HttpResponse::Ok();
+            meta.insert_headers(&mut response, http_req.uri(), "cursor");
+            response.json(data_types::ChatMembersPage {
+                users: users.into_iter().map(i64::from).collect(),
+                has_more: meta.has_more,
+                cursor: meta.next_cursor,
+            })
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить информацию о пользователе
+///
+/// Получаем информацию о любом пользователе, указав его id через аргумент user_id
+/// Токен не используется, но обязателен для доступа к апи
+///
+/// Если пользователя не существует, то возвращаем NotFound
+///
+/// /api/user/info?user_id={id пользователя} = {id: i64, name: String, avatar_url: String?, bio: String?, status: String?}
+#[get("/info")]
+async fn get_user_info(
+    user_id: web::Query<data_types::UserId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.user_id;
+    let user_info = match data
+        .db
+        .send(database_actor::messages::GetUserInfo {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let user_info: data_types::UserInfoStripped = match user_info {
+        Ok(info) => info.into(),
+        Err(e) => return db_error_response(locale, StatusCode::NOT_FOUND, e),
+    };
+    HttpResponse::Ok()
+        .body(serde_json::to_string(&user_info).expect("Failed converting user info to json"))
+}
+
+/// Сменить свое имя
+///
+/// Новое имя проверяется, как имя нового пользователя, и закрепляется за пользователем
+/// вместо старого. Участники чатов пользователя и его другие сокеты получают событие
+/// profile_updated. Если имя не прошло проверку, то возвращаем UnprocessableEntity с ошибками
+/// по полям, если оно занято - Conflict с предложениями, как при авторизации
+///
+/// /api/user/rename {new_name: String} = {id: i64, name: String, avatar_url: String?, bio: String?, status: String?}
+#[put("/rename")]
+async fn rename_user(
+    user_id: web::ReqData<i64>,
+    rename: web::Json<data_types::UserRename>,
+    data: web::Data<data_types::Addresses>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::RenameUser {
+            user_id: UserId(user_id.into_inner()),
+            new_name: rename.into_inner().new_name,
+            name_rules: config.current().validation.user_name.clone(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(info) => {
+            data.redis
+                .do_send(redis_actor::messages::WebsocketMessage::ProfileUpdated(
+                    ProfileUpdatedData {
+                        user_id: UserId(info.id),
+                        name: info.name.clone(),
+                        profile: info.profile.clone(),
+                        chats: info.chats.iter().copied().map(ChatId).collect(),
+                    },
+                ));
+            HttpResponse::Ok().json(data_types::UserInfoStripped::from(info))
+        }
+        Err(ServiceError::Invalid(fields)) => validation_error_response(locale, fields),
+        Err(ServiceError::Database(DBError::LogicError(e))) => {
+            match e.downcast_ref::<NameTakenError>() {
+                Some(e) => name_taken_response(e),
+                None => logic_error_response(locale, StatusCode::NOT_FOUND, &*e),
+            }
+        }
+        Err(ServiceError::Database(e)) => {
+            db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    }
+}
+
+/// Изменить свой профиль
+///
+/// Профиль заменяется целиком: поле, которого нет в запросе или которое пустое, очищается.
+/// Если поля не прошли проверку, то возвращаем UnprocessableEntity с ошибками по полям
+///
+/// /api/user/profile {avatar_url: String?, bio: String?, status: String?} = {id: i64, name: String, avatar_url: String?, bio: String?, status: String?}
+#[put("/profile")]
+async fn set_user_profile(
+    user_id: web::ReqData<i64>,
+    profile: web::Json<UserProfile>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let profile = match validate_profile(&profile) {
+        Ok(profile) => profile,
+        Err(errors) => return validation_error_response(locale, errors),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SetUserProfile {
+            user_id: UserId(user_id.into_inner()),
+            profile,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(info) => HttpResponse::Ok().json(data_types::UserInfoStripped::from(info)),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Получить информацию сразу о нескольких пользователях
+///
+/// Нужна, чтобы подтянуть имена участников чата и отправителей сообщений одним запросом.
+/// Несуществующие пользователи в ответ не попадают. Если id больше MAX_BULK_USERS,
+/// то возвращаем BadRequest
+///
+/// /api/user/bulk-info + {user_ids: [i64]} = [{id: i64, name: String}]
+#[post("/bulk-info")]
+async fn get_users_info(
+    request: web::Json<data_types::UserIds>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let mut user_ids = request.into_inner().user_ids;
+    user_ids.sort();
+    user_ids.dedup();
+    if user_ids.len() > MAX_BULK_USERS {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "too_many_users",
+            &[("max", MAX_BULK_USERS.to_string())],
+        );
+    }
+    if user_ids.is_empty() {
+        return HttpResponse::Ok().json(Vec::<data_types::UserInfoStripped>::new());
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::GetUsersInfo {
+            user_ids: user_ids.into_iter().map(UserId).collect(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(users) => HttpResponse::Ok().json(
+            users
+                .into_iter()
+                .map(data_types::UserInfoStripped::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Получить чаты текущего пользователя
+///
+/// Берет id пользователя из токена и возвращает список UUID чатов
+///
+/// Если не вышло, значит возвращаем Unauthorized
+///
+/// С last_read=true или archived=true вместо UUID возвращает объекты с отметкой о том,
+/// докуда пользователь прочитал чат, и о том, в архиве ли чат
+///
+/// /api/user/chats?last_read=bool&archived=bool = {[UUID]} | {[{chat_id, last_read, archived}]}
+#[get("/chats")]
+async fn get_user_chats(
+    user_id: ReqData<i64>,
+    query: web::Query<data_types::UserChatsRequest>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let chats = match data
+        .db
+        .send(database_actor::messages::GetUserChats {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let chats = match chats {
+        Ok(c) => c,
+        Err(e) => return db_error_response(locale, StatusCode::UNAUTHORIZED, e),
+    };
+    if !query.last_read && !query.archived {
+        return HttpResponse::Ok()
+            .body(serde_json::to_string(&chats).expect("Failed converting user chats to json"));
+    }
+    let mut positions = match data
+        .db
+        .send(database_actor::messages::GetReadPositions {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(Ok(positions)) => positions,
+        Ok(Err(e)) => return db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let archived = match data
+        .db
+        .send(database_actor::messages::GetArchivedChats {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(Ok(archived)) => archived,
+        Ok(Err(e)) => return db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e),
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let chats: Vec<data_types::UserChat> = chats
+        .into_iter()
+        .map(|chat_id| data_types::UserChat {
+            chat_id: chat_id.get(),
+            last_read: positions.remove(&chat_id),
+            archived: archived.contains(&chat_id),
+        })
+        .collect();
+    HttpResponse::Ok().json(chats)
+}
+
+/// Получить чаты текущего пользователя вместе с тем, что нужно для списка чатов:
+/// названием, типом, числом участников, превью последнего сообщения и числом непрочитанных
+///
+/// Сначала идут чаты, в которые писали позже
+///
+/// Чаты с отключенными уведомлениями отмечаются muted, их счетчик клиент показывает приглушенным
+///
+/// /api/user/chats/detailed = {[{chat_id, name, chat_type, member_count, last_message_preview, last_activity, unread_count, muted}]}
+#[get("/chats/detailed")]
+async fn get_user_chats_detailed(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetChatSummaries {
+            user_id: UserId(user_id.into_inner()),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(chats) => HttpResponse::Ok().json(chats),
+        Err(e) => db_error_response(locale, StatusCode::UNAUTHORIZED, e),
+    }
+}
+
+/// Получить число запросов к API и кадров вебсокета по суткам (UTC)
+///
+/// По умолчанию за последние 7 суток, но не больше usage.retention_days, иначе BadRequest.
+/// Чужое использование могут смотреть только администраторы, остальным - Forbidden
+///
+/// /api/user/usage/api?days={u32}&user_id={i64} = {user_id, api_requests, ws_messages, days: [{date, api_requests, ws_messages}]}
+#[get("/usage/api")]
+async fn get_api_usage(
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::UsageRequest>,
+    usage: web::Data<dyn UsageTracker>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let requester = user_id.into_inner();
+    let user_id = request.user_id.unwrap_or(requester);
+    if user_id != requester && !config.current().is_admin(requester) {
+        return not_admin_response(locale);
+    }
+    let retention_days = config.static_config().usage.retention_days;
+    let days = request
+        .days
+        .unwrap_or(DEFAULT_USAGE_DAYS.min(retention_days));
+    if days == 0 || days > retention_days {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "usage_days_out_of_range",
+            &[("max", retention_days.to_string())],
+        );
+    }
+    match usage.usage(UserId(user_id), days).await {
+        Ok(days) => HttpResponse::Ok().json(data_types::UserUsage {
+            user_id,
+            api_requests: days.iter().map(|day| day.api_requests).sum(),
+            ws_messages: days.iter().map(|day| day.ws_messages).sum(),
+            days,
+        }),
+        Err(e) => internal_error_response(
+            locale,
+            format!("Cannot read API usage of user {user_id}: {e}"),
+        ),
+    }
+}
+
+/// Получить число непрочитанных сообщений во всех чатах текущего пользователя
+///
+/// Счетчик чата обнуляется, когда клиент отправляет по вебсокету mark_read.
+/// Чаты с отключенными уведомлениями не отдаются, если не передан include_muted=true
+///
+/// /api/user/unread?include_muted={bool} = {UUID: i64}
+#[get("/unread")]
+async fn get_unread_counts(
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::UnreadRequest>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetUnreadCounts {
+            user_id: UserId(user_id.into_inner()),
+            include_muted: request.include_muted,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(counts) => HttpResponse::Ok().json(counts),
+        Err(e) => db_error_response(locale, StatusCode::UNAUTHORIZED, e),
+    }
+}
+
+/// Получить настройки уведомлений текущего пользователя во всех чатах, где он их менял
+///
+/// Истекшие отключения уведомлений не отдаются
+///
+/// /api/user/notifications = {UUID: {priority: all|mentions_only|none, sound: str?, mute: {until: i64}|forever?}}
+#[get("/notifications")]
+async fn get_all_notification_settings(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetAllNotificationSettings {
+            user_id: UserId(user_id.into_inner()),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(mut settings) => {
+            let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+            for settings in settings.values_mut() {
+                if !settings.is_muted_at(now) {
+                    settings.mute = None;
+                }
+            }
+            HttpResponse::Ok().json(settings)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Отключить уведомления чата до момента until или насовсем
+///
+/// Пока уведомления отключены, пользователь не получает уведомлений о сообщениях чата,
+/// а счетчик непрочитанных чата не отдается в /api/user/unread. Если пользователь не
+/// состоит в чате, то возвращаем Forbidden, если until уже прошел - BadRequest
+///
+/// /api/user/notifications {chat_id: UUID, until: i64?}
+#[put("/notifications")]
+async fn mute_chat(
+    user_id: ReqData<i64>,
+    request: web::Json<data_types::MuteRequest>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let data_types::MuteRequest { chat_id, until } = request.into_inner();
+    let mute = match until {
+        Some(until) => {
+            if until.timestamp <= chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH {
+                return error_response(StatusCode::BAD_REQUEST, locale, "mute_in_past", &[]);
+            }
+            Mute::Until(until)
+        }
+        None => Mute::Forever,
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SetMute {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            mute: Some(mute),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            data.redis
+                .do_send(redis_actor::messages::NotificationsChanged {
+                    user_id: UserId(user_id),
+                    chat_id: ChatId(chat_id),
+                });
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Снова включить уведомления чата
+///
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
+///
+/// /api/user/notifications?chat_id={id чата}
+#[delete("/notifications")]
+async fn unmute_chat(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let (user_id, chat_id) = (user_id.into_inner(), chat_id.chat_id);
+    let result = match data
+        .db
+        .send(database_actor::messages::SetMute {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            mute: None,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            data.redis
+                .do_send(redis_actor::messages::NotificationsChanged {
+                    user_id: UserId(user_id),
+                    chat_id: ChatId(chat_id),
+                });
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить свои общие настройки уведомлений, если они не менялись - значения по умолчанию
+///
+/// /api/user/preferences = {sound: bool, mentions_only: bool, quiet_hours: {start_minute: u16, end_minute: u16, utc_offset_minutes: i16}?}
+#[get("/preferences")]
+async fn get_user_preferences(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetUserPreferences {
+            user_id: UserId(user_id.into_inner()),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Изменить свои общие настройки уведомлений
+///
+/// Настройки заменяются целиком: поле, которого нет в запросе, получает значение по
+/// умолчанию. Они действуют поверх настроек отдельных чатов. Если поля не прошли проверку,
+/// то возвращаем UnprocessableEntity с ошибками по полям
+///
+/// /api/user/preferences {sound: bool?, mentions_only: bool?, quiet_hours: {start_minute: u16, end_minute: u16, utc_offset_minutes: i16?}?} = {sound: bool, mentions_only: bool, quiet_hours: {...}?}
+#[put("/preferences")]
+async fn set_user_preferences(
+    user_id: ReqData<i64>,
+    preferences: web::Json<UserPreferences>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let preferences = match validate_preferences(&preferences) {
+        Ok(preferences) => preferences,
+        Err(errors) => return validation_error_response(locale, errors),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SetUserPreferences {
+            user_id: UserId(user_id.into_inner()),
+            preferences: Some(preferences),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Сбросить свои общие настройки уведомлений к значениям по умолчанию
+///
+/// /api/user/preferences = {sound: true, mentions_only: false, quiet_hours: null}
+#[delete("/preferences")]
+async fn reset_user_preferences(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::SetUserPreferences {
+            user_id: UserId(user_id.into_inner()),
+            preferences: None,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Заблокировать пользователя
+///
+/// Заблокированный не может создать с текущим пользователем чат или пригласить его в чат,
+/// а его сообщения и упоминания текущему пользователю не рассылаются. Если такого
+/// пользователя нет или это сам текущий пользователь, то возвращаем BadRequest
+///
+/// /api/user/block {user_id: i64}
+#[post("/block")]
+async fn block_user(
+    user_id: ReqData<i64>,
+    request: web::Json<data_types::UserBlock>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    set_blocked(user_id.into_inner(), request.user_id, true, &data, locale).await
+}
+
+/// Снять блокировку пользователя
+///
+/// /api/user/block?user_id={id пользователя}
+#[delete("/block")]
+async fn unblock_user(
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::UserBlock>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    set_blocked(user_id.into_inner(), request.user_id, false, &data, locale).await
+}
+
+async fn set_blocked(
+    user_id: i64,
+    blocked_id: i64,
+    blocked: bool,
+    data: &data_types::Addresses,
+    locale: Locale,
+) -> HttpResponse {
+    let result = match data
+        .db
+        .send(database_actor::messages::SetBlocked {
+            user_id: UserId(user_id),
+            blocked_id: UserId(blocked_id),
+            blocked,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            data.redis.do_send(redis_actor::messages::BlockChanged {
+                user_id: UserId(user_id),
+                blocked_id: UserId(blocked_id),
+                blocked,
+            });
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Получить пользователей, которых заблокировал текущий пользователь
+///
+/// /api/user/blocked = {blocked: [i64]}
+#[get("/blocked")]
+async fn get_blocked_users(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetBlockedUsers {
+            user_id: UserId(user_id.into_inner()),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(blocked) => {
+            let mut blocked: Vec<i64> = blocked.into_iter().map(i64::from).collect();
+            blocked.sort_unstable();
+            HttpResponse::Ok().json(data_types::BlockList { blocked })
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Получить контакты текущего пользователя, по имени
+///
+/// /api/user/contacts = [{id: i64, name: String}]
+#[get("/contacts")]
+async fn get_contacts(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::GetContacts {
+            user_id: UserId(user_id.into_inner()),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(contacts) => HttpResponse::Ok().json(
+            contacts
+                .into_iter()
+                .map(data_types::UserInfoStripped::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Добавить пользователя в контакты
+///
+/// Контакты односторонние: добавленный об этом не узнает. Если такого пользователя нет или
+/// это сам текущий пользователь, то возвращаем BadRequest, а если контактов уже
+/// max_contacts из конфигурации - Conflict
+///
+/// /api/user/contacts {user_id: i64}
+#[post("/contacts")]
+async fn add_contact(
+    user_id: ReqData<i64>,
+    request: web::Json<data_types::Contact>,
+    data: web::Data<data_types::Addresses>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::AddContact {
+            user_id: UserId(user_id.into_inner()),
+            contact_id: UserId(request.user_id),
+            max_contacts: config.current().max_contacts,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(DBError::LogicError(e)) if e.is::<ContactLimitError>() => {
+            logic_error_response(locale, StatusCode::CONFLICT, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Убрать пользователя из контактов
+///
+/// /api/user/contacts?user_id={id пользователя}
+#[delete("/contacts")]
+async fn remove_contact(
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::Contact>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let result = match data
+        .db
+        .send(database_actor::messages::RemoveContact {
+            user_id: UserId(user_id.into_inner()),
+            contact_id: UserId(request.user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+/// Авторизация пользователя в сервисе чата
+///
+/// Берет id пользователя из токена и либо создает новый аккаунт в чате,
+/// либо ничего не делает, после чего возвращает данные о пользователе.
+/// Данный запрос может использоваться к
+///
+/// Этот запрос необходимо делать каждый раз, когда пользователь только подключается к сервису
+/// чата, ибо может выйти так, что аккаунта пользователя в чате не сущетвует, из-за чего многие
+/// запросы будут выдавать ошибку Unauthorized
+///
+/// Имя нового пользователя проверяется, и если оно не прошло проверку, то возвращаем
+/// UnprocessableEntity с ошибками по полям
+///
+/// Имена уникальны без учета регистра: если имя занято, возвращаем Conflict
+/// {error: "name_taken", name, suggestions: [свободные похожие имена]}
+///
+/// /api/user/authorize?user_name={имя пользователя} = {id: i64, name: String, chats: [UUID]}
+#[post("/authorization")]
+async fn authorize_user(
+    user_id: ReqData<i64>,
+    data: web::Data<data_types::Addresses>,
+    user_name: web::Query<data_types::UserName>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    let current = config.current();
+    let authorization = match data
+        .db
+        .send(database_actor::messages::AuthorizeUser {
+            user_id: UserId(user_id.into_inner()),
+            user_name: user_name.into_inner().user_name,
+            name_rules: current.validation.user_name.clone(),
+            default_chats: current.default_chats.clone(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    let authorization = match authorization {
+        Ok(authorization) => authorization,
+        Err(ServiceError::Invalid(fields)) => return validation_error_response(locale, fields),
+        Err(ServiceError::Database(DBError::LogicError(e))) if e.is::<NameTakenError>() => {
+            return name_taken_response(e.downcast_ref().expect("Checked above"));
+        }
+        Err(ServiceError::Database(e)) => {
+            return db_error_response(locale, StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    };
+    // Сокет нового пользователя мог подключиться раньше авторизации
+    let user_info = authorization.user;
+    for chat_id in authorization.joined {
+        data.redis
+            .do_send(redis_actor::messages::ApiMessage::NewSubscription(
+                redis_actor::SubscriptionData {
+                    chat_id,
+                    user_id: UserId(user_info.id),
+                },
+            ));
+    }
+    HttpResponse::Ok().body(serde_json::to_string(&user_info).expect("Cannot serialize user info"))
+}
+
+/// Получить предудыщуие сообщения из чата с пагинацией
+/// page_index может не присутствовать, при первом запросе, однако, он обязан быть при последующих
+/// Индекс можно получить из первого запроса
+/// Если сообщения чата крупные, то на странице может оказаться меньше page_size сообщений
+/// У каждого сообщения есть display_date - дата в UTC и в часовом поясе из X-Timezone
+/// Вместо индекса можно передать cursor из заголовка X-Next-Cursor, к первой странице
+/// добавляется примерное число сообщений чата в X-Total-Count
+/// /api/chat/history?chat_id={id_чата}&page_index={индекс}&cursor={курсор}&page_size={размер_страницы}
+/// = {[[сообщения], индекс]}
+#[get("/history")]
+async fn get_chat_history(
+    http_req: HttpRequest,
+    user_id: ReqData<i64>,
+    req: web::Query<data_types::ChatHistoryRequest>,
+    data: web::Data<data_types::Addresses>,
+    config: web::Data<ConfigHandle>,
+    hints: DisplayHints,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let req_info = req.into_inner();
+    let chat_id = req_info.chat_id;
+    let page_index = match page_index_from_cursor(req_info.cursor.as_deref(), hints.locale) {
+        Ok(None) => req_info.page_index,
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let first_page = page_index.is_none();
+    let page_size = req_info.page_size;
+    let chat_history = match data
+        .db
+        .send(database_actor::messages::GetChatHistory {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            page_size,
+            page_index,
+            history_limits: config.current().history.clone(),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(hints.locale, "database", e),
+    };
+    match chat_history {
+        Ok((messages, page_index)) => {
+            let messages: Vec<_> = messages
+                .into_iter()
+                .map(|message| message.for_display(&hints))
+                .collect();
+            let total = match first_page {
+                true => approximate_message_count(&data, chat_id).await,
+                false => None,
+            };
+            let meta = PageMeta::new(page_index.to_cursor()).with_total(total);
+            let mut response = HttpResponse::Ok();
+            response.insert_header((header::VARY, "Accept-Language, X-Timezone"));
+            meta.insert_headers(&mut response, http_req.uri(), "cursor");
+            response.body(serde_json::to_string(&(messages, page_index)).unwrap())
+        }
+        Err(e) => db_error_response(hints.locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Индекс страницы истории из параметра cursor, битый курсор - BadRequest
+fn page_index_from_cursor(
+    cursor: Option<&str>,
+    locale: Locale,
+) -> Result<Option<PageIndex>, HttpResponse> {
+    match cursor.map(PageIndex::from_cursor) {
+        None => Ok(None),
+        Some(Some(index)) => Ok(Some(index)),
+        Some(None) => Err(error_response(
+            StatusCode::BAD_REQUEST,
+            locale,
+            "invalid_cursor",
+            &[],
+        )),
+    }
+}
+
+/// Примерное число сообщений чата для первой страницы истории, None - если узнать не удалось
+async fn approximate_message_count(data: &data_types::Addresses, chat_id: Uuid) -> Option<u64> {
+    match data
+        .db
+        .send(database_actor::messages::GetMessageCount {
+            chat_id: ChatId(chat_id),
+        })
+        .await
+    {
+        Ok(Ok(count)) => Some(count),
+        Ok(Err(e)) => {
+            warn!("Cannot count messages of chat {chat_id}: {e}");
+            None
+        }
+        Err(e) => {
+            warn!("Cannot count messages of chat {chat_id}: {e}");
+            None
+        }
+    }
+}
+
+/// Получить ответы на сообщение с пагинацией
+/// Ответы идут от новых к старым, индекс страницы работает так же, как в /api/chat/history
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
+/// /api/chat/thread?chat_id={id_чата}&message_id={id_сообщения}&page_index={индекс}&cursor={курсор}&page_size={размер_страницы}
+/// = {[[сообщения], индекс]}
+#[get("/thread")]
+async fn get_thread(
+    http_req: HttpRequest,
+    user_id: ReqData<i64>,
+    req: web::Query<data_types::ThreadRequest>,
+    data: web::Data<data_types::Addresses>,
+    hints: DisplayHints,
+) -> impl Responder {
+    let req_info = req.into_inner();
+    let page_index = match page_index_from_cursor(req_info.cursor.as_deref(), hints.locale) {
+        Ok(None) => req_info.page_index,
+        Ok(index) => index,
+        Err(response) => return response,
+    };
+    let thread = match data
+        .db
+        .send(database_actor::messages::GetThread {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(req_info.chat_id),
+            message_id: req_info.message_id,
+            page_size: req_info.page_size,
+            page_index,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(hints.locale, "database", e),
+    };
+    match thread {
+        Ok((messages, page_index)) => {
+            let messages: Vec<_> = messages
+                .into_iter()
+                .map(|message| message.for_display(&hints))
+                .collect();
+            let meta = PageMeta::new(page_index.to_cursor());
+            let mut response = HttpResponse::Ok();
+            response.insert_header((header::VARY, "Accept-Language, X-Timezone"));
+            meta.insert_headers(&mut response, http_req.uri(), "cursor");
+            response.body(serde_json::to_string(&(messages, page_index)).unwrap())
+        }
+        Err(e) => db_error_response(hints.locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Поделиться отрезком истории чата
+///
+/// Возвращает ссылку, по которой сообщения чата с датами от from до to включительно (не больше
+/// share.max_messages последних) отдаются без авторизации, пока ссылка не истечет. Срок ссылки -
+/// expires_in_secs, но не больше share.max_ttl_secs. Сообщения читаются от имени того, кто
+/// поделился: если он выйдет из чата, ссылка перестанет работать.
+/// Если пользователь не состоит в чате, то возвращаем Forbidden, если from позже to -
+/// BadRequest, а если ключ подписи ссылок не задан - NotFound
+///
+/// /api/chat/share {chat_id: UUID, from: i64, to: i64, expires_in_secs: u64?} = {url: str, token: str, expires_at: i64}
+#[post("/share")]
+async fn create_share_link(
+    user_id: ReqData<i64>,
+    request: web::Json<data_types::ShareRequest>,
+    data: web::Data<data_types::Addresses>,
+    links: web::Data<ShareLinks>,
+    locale: Locale,
+) -> impl Responder {
+    let user_id = user_id.into_inner();
+    let request = request.into_inner();
+    if request.from.timestamp > request.to.timestamp {
+        return error_response(StatusCode::BAD_REQUEST, locale, "invalid_range", &[]);
+    }
+    match data
+        .db
+        .send(database_actor::messages::CheckMembership {
+            user_id: UserId(user_id),
+            chat_id: ChatId(request.chat_id),
+        })
+        .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return db_error_response(locale, StatusCode::FORBIDDEN, e),
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    }
+    let ttl_secs = links.ttl_secs(request.expires_in_secs);
+    let token = ShareToken {
+        chat_id: request.chat_id,
+        created_by: user_id,
+        from: request.from.timestamp.num_milliseconds(),
+        to: request.to.timestamp.num_milliseconds(),
+        expires_at: chrono::Utc::now().timestamp() + ttl_secs as i64,
+    };
+    match links.sign(&token) {
+        Ok(signed) => HttpResponse::Ok().json(data_types::ShareLink {
+            url: format!("{PUBLIC_PATH_PREFIX}{signed}"),
+            token: signed,
+            expires_at: token.expires_at,
+        }),
+        Err(e) => share_error_response(locale, e),
+    }
+}
+
+/// Ответ на ссылку на историю, которую нельзя выпустить или принять
+fn share_error_response(locale: Locale, e: ShareError) -> HttpResponse {
+    match e {
+        ShareError::NotConfigured => {
+            error_response(StatusCode::NOT_FOUND, locale, "sharing_not_configured", &[])
+        }
+        ShareError::Invalid => {
+            error_response(StatusCode::NOT_FOUND, locale, "invalid_share_link", &[])
+        }
+        ShareError::Expired => error_response(StatusCode::GONE, locale, "share_link_expired", &[]),
+    }
+}
+
+/// Получить отрезок истории чата по ссылке, без авторизации
+///
+/// Если ссылка повреждена или тот, кто ею поделился, уже не в чате, то возвращаем NotFound,
+/// а если ссылка истекла - Gone
+///
+/// /share/{токен} = {chat_id: UUID, name: str, messages: [сообщения], expires_at: i64}
+#[get("/share/{token}")]
+async fn get_shared_history(
+    token: web::Path<String>,
+    data: web::Data<data_types::Addresses>,
+    links: web::Data<ShareLinks>,
+    hints: DisplayHints,
+) -> impl Responder {
+    let token = match links.verify(&token, chrono::Utc::now().timestamp()) {
+        Ok(token) => token,
+        Err(e) => return share_error_response(hints.locale, e),
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::GetSharedHistory {
+            created_by: UserId(token.created_by),
+            chat_id: ChatId(token.chat_id),
+            from: chrono::Duration::milliseconds(token.from),
+            to: chrono::Duration::milliseconds(token.to),
+            limit: links.config.max_messages,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(hints.locale, "database", e),
+    };
+    match result {
+        Ok(history) => {
+            let mut response = HttpResponse::Ok();
+            response.insert_header((header::VARY, "Accept-Language, X-Timezone"));
+            response.json(data_types::SharedHistoryPage {
+                chat_id: history.chat_id,
+                name: history.name,
+                messages: history
+                    .messages
+                    .into_iter()
+                    .map(|message| message.for_display(&hints))
+                    .collect(),
+                expires_at: token.expires_at,
+            })
+        }
+        Err(e) => db_error_response(hints.locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Сверяет подключение с привязкой сессии, если она включена
+///
+/// Возвращает id сессии, к которой надо привязать сокет, или ответ с отказом.
+/// Если Redis недоступен, то подключение пропускается без привязки
+async fn bind_session(
+    req: &HttpRequest,
+    user_id: i64,
+    client_ip: Option<std::net::IpAddr>,
+    config: &ConfigHandle,
+    data: &data_types::Addresses,
+) -> Result<Option<String>, HttpResponse> {
+    let rules = config.current().session_binding.clone();
+    if !rules.enabled {
+        return Ok(None);
+    }
+    let (Some(token), Some(binder)) = (
+        req.cookie("token"),
+        req.app_data::<web::Data<SessionBinder>>(),
+    ) else {
+        return Ok(None);
+    };
+    let session_id = session_binding::session_id(token.value());
+    let fingerprint = session_binding::request_fingerprint(req, client_ip, &rules);
+    match binder
+        .check(&session_id, &fingerprint, rules.ttl_secs)
+        .await
+    {
+        Ok(BindingCheck::New | BindingCheck::Matches) => Ok(Some(session_id)),
+        Ok(BindingCheck::Mismatch) => {
+            warn!("Session of user {user_id} is used from another client, revoking it");
+            data.redis.do_send(redis_actor::messages::SessionRevoked {
+                user_id: UserId(user_id),
+                session_id,
+            });
+            Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                Locale::from_request(req),
+                "session_bound",
+                &[],
+            ))
+        }
+        Err(e) => {
+            error!("Cannot check session binding: {e}");
+            Ok(None)
+        }
+    }
+}
+
+#[get("/ws")]
+async fn websocket_startup(
+    req: HttpRequest,
+    user_id: ReqData<i64>,
+    client_ip: Option<ReqData<ClientIp>>,
+    stream: web::Payload,
+    data: web::Data<data_types::Addresses>,
+    limiter: web::Data<dyn RateLimit>,
+    config: web::Data<ConfigHandle>,
+) -> impl Responder {
+    let locale = Locale::from_request(&req);
+    // Перегруженный экземпляр не принимает новые сокеты, клиент подключится позже
+    let current = config.current();
+    if let Some(reason) = load_shedding::current_overload(&current) {
+        return Ok(overloaded_response(
+            locale,
+            reason,
+            current.load_shedding.retry_after_secs,
+        ));
+    }
+    let user_id = user_id.into_inner();
+    match limiter.lockout_remaining(&format!("user:{user_id}")).await {
+        Ok(Some(remaining)) => return Ok(too_many_requests(remaining)),
+        Ok(None) => {}
+        Err(e) => error!("Cannot check auth lockout: {e}"),
+    }
+    let creation_date = match data
+        .db
+        .send(database_actor::messages::GetUserCreationDate {
+            user_id: UserId(user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return Ok(mailbox_error_response(locale, "database", e)),
+    };
+    let account_created = match creation_date {
+        Ok(created) => created,
+        Err(e) => return Ok(db_error_response(locale, StatusCode::UNAUTHORIZED, e)),
+    };
+    let client_ip = client_ip.map(|ip| ip.into_inner().0);
+    let session_id = match bind_session(&req, user_id, client_ip, &config, &data).await {
+        Ok(session_id) => session_id,
+        Err(response) => return Ok(response),
+    };
+    let mut new_websocket = WebsocketActor::new(
+        data.broker.clone(),
+        data.redis.clone(),
+        data.db.clone(),
+        limiter.into_inner(),
+        UserId(user_id),
+        SessionMetadata {
+            client_ip,
+            display: DisplayHints::from_request(&req),
+            session_id,
+            account_created,
+            token_expires_at: req
+                .extensions()
+                .get::<TokenExpiresAt>()
+                .map(|expires_at| chrono::Duration::seconds(expires_at.0)),
+        },
+        config.get_ref().clone(),
+    );
+    // Без своего фильтра сокет проверяет сообщения по списку слов из конфигурации
+    if let Some(moderation) = req.app_data::<web::Data<dyn ModerationFilter>>() {
+        new_websocket = new_websocket.with_moderation(moderation.clone().into_inner());
+    }
+    if let Some(usage) = req.app_data::<web::Data<dyn UsageTracker>>() {
+        new_websocket = new_websocket.with_usage_tracker(usage.clone().into_inner());
+    }
+    new_websocket = new_websocket.with_storage(data.storage.clone());
+    ws::WsResponseBuilder::new(new_websocket, &req, stream)
+        .frame_size(current.websocket.max_frame_bytes)
+        .start()
+}
+
+/// Метрики сервиса в формате Prometheus
+///
+/// /metrics
+#[get("/metrics")]
+async fn metrics_endpoint() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::gather())
+}
+
+/// Перечитать динамическую конфигурацию сервиса
+///
+/// Обновляет лимиты, флаги, списки слов и уровень логов без перезапуска и без разрыва
+/// вебсокетов. Доступно только администраторам, остальным возвращаем Forbidden
+///
+/// /api/admin/reload-config = {новая динамическая конфигурация}
+#[post("/reload-config")]
+async fn reload_config(
+    user_id: ReqData<i64>,
+    config: web::Data<ConfigHandle>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    match config.reload() {
+        Ok(new_config) => HttpResponse::Ok().json(&*new_config),
+        Err(e) => internal_error_response(locale, format!("Cannot reload config: {e}")),
+    }
+}
+
+/// Ограничения развертывания на сообщения, вложения и чаты
+///
+/// /api/meta/limits = {max_message_length: usize, max_attachments_per_message: usize, max_attachment_bytes: usize, max_chat_members: u32}
+#[get("/limits")]
+async fn get_limits(config: web::Data<ConfigHandle>) -> impl Responder {
+    let rules = &config.current().validation.message;
+    let static_config = config.static_config();
+    HttpResponse::Ok().json(data_types::Limits {
+        max_message_length: rules.max_length,
+        max_attachments_per_message: rules.attachment_limit(),
+        max_attachment_bytes: static_config.storage.max_attachment_bytes,
+        max_chat_members: static_config.database.max_chat_members,
+    })
+}
+
+/// Какие необязательные подсистемы включены в этом развертывании
+///
+/// /api/meta/capabilities = {reactions: bool, e2ee: bool, attachments: bool, search: bool, push: bool}
+#[get("/capabilities")]
+async fn get_capabilities(config: web::Data<ConfigHandle>) -> impl Responder {
+    HttpResponse::Ok().json(config.static_config().capabilities(&config.current()))
+}
+
+/// Задать режим доставки сообщений чата
+///
+/// Доступно только администраторам. Режим сразу начинает действовать на всех экземплярах
+/// сервиса. Если чата не существует, то возвращаем NotFound
+///
+/// /api/admin/delivery-mode?chat_id={id чата}&mode={at_most_once|at_least_once}
+#[put("/delivery-mode")]
+async fn set_delivery_mode(
+    user_id: ReqData<i64>,
+    change: web::Query<data_types::DeliveryModeChange>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let data_types::DeliveryModeChange { chat_id, mode } = change.into_inner();
+    let result = match data
+        .db
+        .send(database_actor::messages::SetDeliveryMode {
+            chat_id: ChatId(chat_id),
+            mode,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => {
+            data.redis
+                .do_send(redis_actor::messages::DeliveryModeChanged {
+                    chat_id: ChatId(chat_id),
+                    mode,
+                });
+            HttpResponse::Ok().finish()
+        }
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Сколько сообщений и вложений накопилось в чате и за какой срок
+///
+/// Доступно только администраторам. Счетчики только растут, так что после удалений и
+/// исчезновения сообщений по сроку они больше, чем реально хранится. Если чата не
+/// существует, то возвращаем NotFound
+///
+/// /api/admin/chat/usage?chat_id={id чата}
+#[get("/chat/usage")]
+async fn get_chat_usage(
+    user_id: ReqData<i64>,
+    chat: web::Query<data_types::ChatId>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::GetChatUsage {
+            chat_id: ChatId(chat.chat_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Задать, сколько участников может быть в чате
+///
+/// Доступно только администраторам. Без max_members у чата снова действует ограничение
+/// database.max_chat_members. Если чата не существует, то возвращаем NotFound
+///
+/// /api/admin/member-limit?chat_id={id чата}&max_members={число}
+#[put("/member-limit")]
+async fn set_member_limit(
+    user_id: ReqData<i64>,
+    change: web::Query<data_types::MemberLimitChange>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let data_types::MemberLimitChange {
+        chat_id,
+        max_members,
+    } = change.into_inner();
+    let result = match data
+        .db
+        .send(database_actor::messages::SetMemberLimit {
+            chat_id: ChatId(chat_id),
+            max_members,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Задать язык и метки содержимого чата
+///
+/// Доступно только администраторам. Метки заменяют прежние, чаты с меткой nsfw по умолчанию
+/// не попадают в отбор по меткам. Если метки не прошли проверку, то возвращаем
+/// UnprocessableEntity, если чата не существует - NotFound
+///
+/// /api/admin/chat-labels?chat_id={id чата} {language: str?, labels: [str]}
+#[put("/chat-labels")]
+async fn set_chat_labels(
+    user_id: ReqData<i64>,
+    chat: web::Query<data_types::ChatId>,
+    labels: web::Json<ChatLabels>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let labels = labels.into_inner();
+    let language = labels
+        .language
+        .as_deref()
+        .map(|language| validate_language("language", language))
+        .transpose();
+    let (language, labels) = match (language, validate_labels("labels", &labels.labels)) {
+        (Ok(language), Ok(labels)) => (language, labels),
+        (language, labels) => {
+            let errors = language.err().into_iter().chain(labels.err()).collect();
+            return validation_error_response(locale, errors);
+        }
+    };
+    let result = match data
+        .db
+        .send(database_actor::messages::SetChatLabels {
+            chat_id: ChatId(chat.chat_id),
+            labels: ChatLabels { language, labels },
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Сделать пользователя ботом: новые сообщения его чатов будут доставляться POST-запросом
+/// на callback_url вместо вебсокета
+///
+/// Доступно только администраторам. В ответ приходит секрет подписи запросов, повторная
+/// регистрация меняет адрес и секрет. Бот начинает получать сообщения в течение
+/// bots.refresh_secs. Если адрес не http:// и не https://, то возвращаем UnprocessableEntity, если
+/// пользователя нет - NotFound
+///
+/// /api/admin/bot {user_id: i64, callback_url: str} = {secret: String}
+#[put("/bot")]
+async fn register_bot(
+    user_id: ReqData<i64>,
+    registration: web::Json<data_types::BotRegistration>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let registration = registration.into_inner();
+    if let Err(e) = HttpEndpoint::parse(&registration.callback_url) {
+        warn!(
+            "Bot callback {} is rejected: {e}",
+            registration.callback_url
+        );
+        return error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            locale,
+            "invalid_callback_url",
+            &[],
+        );
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::SetBotWebhook {
+            user_id: UserId(registration.user_id),
+            callback_url: registration.callback_url,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(secret) => HttpResponse::Ok().json(data_types::BotSecret { secret }),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Перестать доставлять сообщения на вебхук бота
+///
+/// Доступно только администраторам. Пользователь остается в своих чатах
+///
+/// /api/admin/bot?user_id={id пользователя}
+#[delete("/bot")]
+async fn unregister_bot(
+    user_id: ReqData<i64>,
+    bot: web::Query<data_types::UserId>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let result = match data
+        .db
+        .send(database_actor::messages::RemoveBotWebhook {
+            user_id: UserId(bot.user_id),
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::NOT_FOUND, e),
+    }
+}
+
+/// Получить страницу списка пользователей
+///
+/// Доступно только администраторам. Страницы стабильны: пользователи идут в порядке токенов
+/// ключа, курсор указывает на последнего выданного. Если курсор битый, то возвращаем BadRequest
+///
+/// /api/admin/users?cursor={курсор}&page_size={размер страницы} = {users: [{id: i64, name: String}], cursor: String, has_more: bool}
+#[get("/users")]
+async fn get_user_list_paged(
+    http_req: HttpRequest,
+    user_id: ReqData<i64>,
+    request: web::Query<data_types::UserListRequest>,
+    config: web::Data<ConfigHandle>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    if !config.current().is_admin(user_id.into_inner()) {
+        return not_admin_response(locale);
+    }
+    let request = request.into_inner();
+    let after_token = match request.cursor.map(|cursor| cursor.parse::<i64>()) {
+        None => None,
+        Some(Ok(token)) => Some(token),
+        Some(Err(_)) => {
+            return error_response(StatusCode::BAD_REQUEST, locale, "invalid_cursor", &[])
+        }
+    };
+    let page_size = request
+        .page_size
+        .unwrap_or(DEFAULT_USER_PAGE_SIZE)
+        .clamp(1, MAX_USER_PAGE_SIZE);
+    let result = match data
+        .db
+        .send(database_actor::messages::GetUserListPaged {
+            after_token,
+            page_size,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok((users, next_token)) => {
+            let meta = PageMeta::new(next_token.map(|token| token.to_string()));
+            let mut response = HttpResponse::Ok();
+            meta.insert_headers(&mut response, http_req.uri(), "cursor");
+            response.json(data_types::UserListPage {
+                users: users.into_iter().map(Into::into).collect(),
+                has_more: meta.has_more,
+                cursor: meta.next_cursor,
+            })
+        }
+        Err(e) => db_error_response(locale, StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn rotate_chat_secret(
+    user_id: i64,
+    chat_id: Uuid,
+    kind: SecretKind,
+    data: &data_types::Addresses,
+    locale: Locale,
+) -> HttpResponse {
+    let result = match data
+        .db
+        .send(database_actor::messages::RotateChatSecret {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            kind,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(secret) => HttpResponse::Ok().json(data_types::ChatSecret { secret }),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+async fn revoke_chat_secret(
+    user_id: i64,
+    chat_id: Uuid,
+    kind: SecretKind,
+    data: &data_types::Addresses,
+    locale: Locale,
+) -> HttpResponse {
+    let result = match data
+        .db
+        .send(database_actor::messages::RevokeChatSecret {
+            user_id: UserId(user_id),
+            chat_id: ChatId(chat_id),
+            kind,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Выпустить новый код приглашения в чат
+///
+/// Старый код перестает работать. Код возвращается только один раз, в базе хранится его хэш.
+/// Если пользователь не состоит в чате, то возвращаем Forbidden
+///
+/// /api/chat/invite-code?chat_id={id чата} = {secret: String}
+#[post("/invite-code")]
+async fn rotate_invite_code(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    rotate_chat_secret(
+        user_id.into_inner(),
+        chat_id.chat_id,
+        SecretKind::InviteCode,
+        &data,
+        locale,
+    )
+    .await
+}
+
+/// Отозвать код приглашения в чат
+///
+/// /api/chat/invite-code?chat_id={id чата}
+#[delete("/invite-code")]
+async fn revoke_invite_code(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    revoke_chat_secret(
+        user_id.into_inner(),
+        chat_id.chat_id,
+        SecretKind::InviteCode,
+        &data,
+        locale,
+    )
+    .await
+}
+
+/// Войти в чат по коду приглашения
+///
+/// Если код неверный или отозван, то возвращаем Forbidden, а если в чате уже столько
+/// участников, сколько можно, - Conflict
+///
+/// /api/chat/join?chat_id={id чата}&code={код} = {id: Uuid, name: String, users: [i64], chat_type: String}
+#[post("/join")]
+async fn join_chat_by_invite(
+    user_id: ReqData<i64>,
+    invite: web::Query<data_types::InviteRedemption>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    let invite = invite.into_inner();
+    let result = match data
+        .db
+        .send(database_actor::messages::JoinChatByInvite {
+            user_id: UserId(user_id.into_inner()),
+            chat_id: ChatId(invite.chat_id),
+            invite_code: invite.code,
+        })
+        .await
+    {
+        Ok(result) => result,
+        Err(e) => return mailbox_error_response(locale, "database", e),
+    };
+    match result {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(DBError::LogicError(e)) if e.is::<MemberLimitError>() => {
+            logic_error_response(locale, StatusCode::CONFLICT, &*e)
+        }
+        Err(e) => db_error_response(locale, StatusCode::FORBIDDEN, e),
+    }
+}
+
+/// Выпустить новый токен вебхука чата
+///
+/// /api/chat/webhook-token?chat_id={id чата} = {secret: String}
+#[post("/webhook-token")]
+async fn rotate_webhook_token(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    rotate_chat_secret(
+        user_id.into_inner(),
+        chat_id.chat_id,
+        SecretKind::WebhookToken,
+        &data,
+        locale,
+    )
+    .await
+}
+
+/// Отозвать токен вебхука чата
+///
+/// /api/chat/webhook-token?chat_id={id чата}
+#[delete("/webhook-token")]
+async fn revoke_webhook_token(
+    user_id: ReqData<i64>,
+    chat_id: web::Query<data_types::ChatId>,
+    data: web::Data<data_types::Addresses>,
+    locale: Locale,
+) -> impl Responder {
+    revoke_chat_secret(
+        user_id.into_inner(),
+        chat_id.chat_id,
+        SecretKind::WebhookToken,
+        &data,
+        locale,
+    )
+    .await
 }
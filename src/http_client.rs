@@ -0,0 +1,179 @@
+use std::{fmt, sync::OnceLock, time::Duration};
+
+use reqwest::{Body, Client, Method};
+
+// HTTP-клиент для внешних API (поставщики контента, хранилище вложений, вебхуки ботов)
+//
+// Запросы идут через общий клиент reqwest с пулом соединений и TLS на rustls, так что
+// https-API подключаются напрямую. Время на весь запрос ограничено таймаутом вызова,
+// а тело ответа - MAX_RESPONSE_BYTES, чтобы чужой сервер не мог заставить нас читать без конца.
+
+/// Наибольший размер тела ответа, которое мы готовы прочитать
+pub const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Время на установку соединения, общее для всех запросов
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct HttpError(pub String);
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<reqwest::Error> for HttpError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            HttpError("Request timed out".into())
+        } else {
+            HttpError(e.to_string())
+        }
+    }
+}
+
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            // Переходы по редиректам уводили бы подписанные запросы на чужие адреса
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client settings are valid")
+    })
+}
+
+/// Адрес API вида http[s]://host[:port][/prefix]
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpEndpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+}
+
+impl HttpEndpoint {
+    pub fn parse(url: &str) -> Result<Self, HttpError> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(HttpError(format!(
+                "Only http:// and https:// endpoints are supported, got {url}"
+            )));
+        };
+        let (authority, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| HttpError(format!("Invalid port in {url}")))?,
+            ),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(HttpError(format!("No host in {url}")));
+        }
+        Ok(Self {
+            tls,
+            host: host.into(),
+            port,
+            prefix: prefix.into(),
+        })
+    }
+
+    fn default_port(&self) -> u16 {
+        if self.tls {
+            443
+        } else {
+            80
+        }
+    }
+
+    /// Значение заголовка Host, порт указывается, только если он не стандартный
+    pub fn host_header(&self) -> String {
+        if self.port == self.default_port() {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Полный адрес пути на этом API
+    pub fn url(&self, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}{}{path}", self.host_header(), self.prefix)
+    }
+
+    /// Выполняет GET и возвращает тело успешного ответа
+    pub async fn get(&self, path: &str, timeout: Duration) -> Result<String, HttpError> {
+        self.request(
+            "GET",
+            path,
+            &[("Accept", "application/json".into())],
+            Vec::new(),
+            timeout,
+        )
+        .await
+    }
+
+    /// Выполняет запрос и возвращает тело успешного ответа
+    ///
+    /// path дописывается к префиксу адреса, ответ не из 2xx считается ошибкой. timeout
+    /// ограничивает весь запрос вместе с отправкой тела и чтением ответа
+    pub async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: impl Into<Body>,
+        timeout: Duration,
+    ) -> Result<String, HttpError> {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|_| HttpError(format!("Invalid HTTP method {method}")))?;
+        let mut request = client()
+            .request(method, self.url(path))
+            .timeout(timeout)
+            .body(body);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let mut response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(HttpError(format!(
+                "{} answered with status {}",
+                self.host,
+                status.as_u16()
+            )));
+        }
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+        {
+            return Err(HttpError(format!(
+                "{} sent a response that is too large",
+                self.host
+            )));
+        }
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+                return Err(HttpError(format!(
+                    "{} sent a response that is too large",
+                    self.host
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
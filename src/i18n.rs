@@ -0,0 +1,335 @@
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, http::header, FromRequest, HttpRequest};
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+// Перевод сообщений об ошибках
+//
+// Клиенту в теле ошибки отдаются стабильный код и описание для человека. Код не зависит
+// от языка, а описание берется из каталога на языке, который клиент указал в Accept-Language.
+// Если подходящего языка нет, то описание отдается на английском.
+//
+// В шаблонах сообщений можно использовать параметры вида {name}, они подставляются
+// при переводе.
+//
+// Здесь же форматируются даты для показа: клиент может прислать свой часовой пояс
+// в заголовке X-Timezone, и тогда рядом с датой в UTC он получит дату в своем поясе,
+// записанную по правилам своего языка. Это нужно клиентам, которые сами не умеют
+// работать с часовыми поясами.
+
+/// Заголовок с часовым поясом клиента в формате IANA, например Europe/Moscow
+pub const TIMEZONE_HEADER: &str = "X-Timezone";
+
+/// Язык, на котором клиент хочет видеть сообщения
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    En,
+    Ru,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Ru];
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Ru => "ru",
+        }
+    }
+
+    /// Язык по тегу вида ru или ru-RU
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim();
+        Locale::ALL
+            .into_iter()
+            .find(|locale| locale.tag().eq_ignore_ascii_case(primary))
+    }
+
+    /// Выбирает из значения Accept-Language поддерживаемый язык с наибольшим весом
+    pub fn from_accept_language(value: &str) -> Self {
+        let mut best: Option<(Locale, f32)> = None;
+        for range in value.split(',') {
+            let mut parts = range.split(';');
+            let Some(locale) = parts.next().and_then(Locale::from_tag) else {
+                continue;
+            };
+            let weight = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight > 0.0 && best.is_none_or(|(_, best_weight)| weight > best_weight) {
+                best = Some((locale, weight));
+            }
+        }
+        best.map(|(locale, _)| locale).unwrap_or_default()
+    }
+
+    pub fn from_request(req: &HttpRequest) -> Self {
+        req.headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(Locale::from_accept_language)
+            .unwrap_or_default()
+    }
+}
+
+impl FromRequest for Locale {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Locale::from_request(req)))
+    }
+}
+
+/// Шаблон сообщения для кода ошибки
+///
+/// Для неизвестного кода возвращает None, и вызывающий сам решает, что показать
+fn template(locale: Locale, code: &str) -> Option<&'static str> {
+    let template = match (locale, code) {
+        (Locale::En, "service_unavailable") => "Service is temporarily unavailable",
+        (Locale::Ru, "service_unavailable") => "Сервис временно недоступен",
+        (Locale::En, "overloaded") => "Server is overloaded, reconnect later",
+        (Locale::Ru, "overloaded") => "Сервер перегружен, подключитесь позже",
+        (Locale::En, "validation_failed") => "Request contains invalid fields",
+        (Locale::Ru, "validation_failed") => "Запрос содержит неправильные поля",
+        (Locale::En, "control_characters") => "Must not contain control characters",
+        (Locale::Ru, "control_characters") => "Не должно содержать управляющих символов",
+        (Locale::En, "blank") => "Must not be empty",
+        (Locale::Ru, "blank") => "Не должно быть пустым",
+        (Locale::En, "too_short") => "Must be at least {min} characters long",
+        (Locale::Ru, "too_short") => "Должно быть не короче {min} символов",
+        (Locale::En, "too_long") => "Must be at most {max} characters long",
+        (Locale::Ru, "too_long") => "Должно быть не длиннее {max} символов",
+        (Locale::En, "invalid_characters") => "Character {character} is not allowed",
+        (Locale::Ru, "invalid_characters") => "Символ {character} не разрешен",
+        (Locale::En, "too_many") => "Must contain at most {max} items",
+        (Locale::Ru, "too_many") => "Должно содержать не больше {max} элементов",
+        (Locale::En, "blocked_word") => "Contains a forbidden word",
+        (Locale::Ru, "blocked_word") => "Содержит запрещенное слово",
+        (Locale::En, "invalid_url") => "Must be an http or https URL",
+        (Locale::Ru, "invalid_url") => "Должно быть адресом http или https",
+        (Locale::En, "out_of_range") => "Must be between {min} and {max}",
+        (Locale::Ru, "out_of_range") => "Должно быть от {min} до {max}",
+        (Locale::En, "unknown_permissions") => "Unknown permission bits {bits}",
+        (Locale::Ru, "unknown_permissions") => "Неизвестные биты разрешений {bits}",
+        (Locale::En, "internal_error") => "Internal server error",
+        (Locale::Ru, "internal_error") => "Внутренняя ошибка сервера",
+        (Locale::En, "request_rejected") => "Request cannot be completed",
+        (Locale::Ru, "request_rejected") => "Запрос не может быть выполнен",
+        (Locale::En, "not_admin") => "User is not an administrator",
+        (Locale::Ru, "not_admin") => "Пользователь не администратор",
+        (Locale::En, "not_a_member") => "User is not a member of this chat",
+        (Locale::Ru, "not_a_member") => "Пользователь не состоит в этом чате",
+        (Locale::En, "chat_not_found") => "Chat does not exist",
+        (Locale::Ru, "chat_not_found") => "Чата не существует",
+        (Locale::En, "invalid_user") => "Invalid User ID",
+        (Locale::Ru, "invalid_user") => "Неправильный id пользователя",
+        (Locale::En, "message_not_found") => "Message not found",
+        (Locale::Ru, "message_not_found") => "Сообщение не найдено",
+        (Locale::En, "message_not_pinned") => "Message is not pinned",
+        (Locale::Ru, "message_not_pinned") => "Сообщение не закреплено",
+        (Locale::En, "attachment_not_found") => "Attachment not found",
+        (Locale::Ru, "attachment_not_found") => "Вложение не найдено",
+        (Locale::En, "unknown_attachment") => "Unknown attachment",
+        (Locale::Ru, "unknown_attachment") => "Неизвестное вложение",
+        (Locale::En, "too_many_attachments") => "A message can have at most {max} attachments",
+        (Locale::Ru, "too_many_attachments") => "В сообщении может быть не больше {max} вложений",
+        (Locale::En, "private_chat_members") => "Private chat can't have more than two members",
+        (Locale::Ru, "private_chat_members") => {
+            "В приватном чате не может быть больше двух участников"
+        }
+        (Locale::En, "creator_only_posts") => "Only the chat creator can post in this chat",
+        (Locale::Ru, "creator_only_posts") => "Писать в этот чат может только его создатель",
+        (Locale::En, "admins_only_posts") => "Only the chat owner and admins can post in this chat",
+        (Locale::Ru, "admins_only_posts") => {
+            "Писать в этот чат могут только владелец и администраторы"
+        }
+        (Locale::En, "admins_only") => "Only the chat owner and admins can do this",
+        (Locale::Ru, "admins_only") => "Это могут только владелец чата и администраторы",
+        (Locale::En, "members_not_allowed") => {
+            "This action is not allowed for members of this chat"
+        }
+        (Locale::Ru, "members_not_allowed") => "Участникам этого чата это действие запрещено",
+        (Locale::En, "invitee_not_registered") => "Invited user is not registered",
+        (Locale::Ru, "invitee_not_registered") => "Приглашенный пользователь не зарегистрирован",
+        (Locale::En, "use_exit") => "Use exit to leave the chat",
+        (Locale::Ru, "use_exit") => "Чтобы покинуть чат, используйте выход",
+        (Locale::En, "owner_cannot_be_removed") => "The chat owner cannot be removed",
+        (Locale::Ru, "owner_cannot_be_removed") => "Владельца чата нельзя исключить",
+        (Locale::En, "owner_only_removes_admins") => "Only the chat owner can remove admins",
+        (Locale::Ru, "owner_only_removes_admins") => {
+            "Исключать администраторов может только владелец чата"
+        }
+        (Locale::En, "owner_only_changes_roles") => "Only the chat owner can change roles",
+        (Locale::Ru, "owner_only_changes_roles") => "Менять роли может только владелец чата",
+        (Locale::En, "owner_role_fixed") => "Owner cannot change their own role",
+        (Locale::Ru, "owner_role_fixed") => "Владелец не может сменить свою роль",
+        (Locale::En, "invalid_invite_code") => "Invalid invite code",
+        (Locale::Ru, "invalid_invite_code") => "Неправильный код приглашения",
+        (Locale::En, "not_a_channel") => "Chat is not a public channel",
+        (Locale::Ru, "not_a_channel") => "Чат не публичный канал",
+        (Locale::En, "creator_only_ttl") => "Only the chat creator can change message TTL",
+        (Locale::Ru, "creator_only_ttl") => {
+            "Время жизни сообщений может менять только создатель чата"
+        }
+        (Locale::En, "cannot_block_self") => "Cannot block yourself",
+        (Locale::Ru, "cannot_block_self") => "Нельзя заблокировать себя",
+        (Locale::En, "cannot_add_self") => "Cannot add yourself to contacts",
+        (Locale::Ru, "cannot_add_self") => "Нельзя добавить себя в контакты",
+        (Locale::En, "sender_only_edits") => "Only the sender can edit this message",
+        (Locale::Ru, "sender_only_edits") => "Изменить сообщение может только его отправитель",
+        (Locale::En, "sender_only_deletes") => "Only the sender can delete this message",
+        (Locale::Ru, "sender_only_deletes") => "Удалить сообщение может только его отправитель",
+        (Locale::En, "no_rows") => "Select query didn't return rows",
+        (Locale::Ru, "no_rows") => "Запрос не вернул строк",
+        (Locale::En, "invalid_keyspace") => "Invalid keyspace name {keyspace}",
+        (Locale::Ru, "invalid_keyspace") => "Неправильное имя пространства ключей {keyspace}",
+        (Locale::En, "member_limit") => "Chat member limit of {limit} reached",
+        (Locale::Ru, "member_limit") => "В чате уже {limit} участников, больше нельзя",
+        (Locale::En, "contact_limit") => "Contact list is full: at most {limit} contacts",
+        (Locale::Ru, "contact_limit") => "Список контактов заполнен: не больше {limit} контактов",
+        (Locale::En, "blocked") => "User {user_id} does not accept invitations from you",
+        (Locale::Ru, "blocked") => "Пользователь {user_id} не принимает ваши приглашения",
+        (Locale::En, "name_taken") => "Name {name} is already taken",
+        (Locale::Ru, "name_taken") => "Имя {name} уже занято",
+        (Locale::En, "malformed_guest_users") => "Malformed json format for guest user ids",
+        (Locale::Ru, "malformed_guest_users") => {
+            "Список id приглашенных пользователей - неправильный json"
+        }
+        (Locale::En, "unknown_template") => "Unknown chat template {template}",
+        (Locale::Ru, "unknown_template") => "Неизвестный шаблон чата {template}",
+        (Locale::En, "missing_template_param") => "Missing template parameter {param}",
+        (Locale::Ru, "missing_template_param") => "Не хватает параметра шаблона {param}",
+        (Locale::En, "length_required") => "Attachment size is not known",
+        (Locale::Ru, "length_required") => "Размер вложения неизвестен",
+        (Locale::En, "attachment_too_large") => "Attachment is larger than {max} bytes",
+        (Locale::Ru, "attachment_too_large") => "Вложение больше {max} байт",
+        (Locale::En, "upload_interrupted") => "Attachment upload was interrupted",
+        (Locale::Ru, "upload_interrupted") => "Загрузка вложения прервалась",
+        (Locale::En, "storage_not_configured") => "Attachment storage is not configured",
+        (Locale::Ru, "storage_not_configured") => "Хранилище вложений не настроено",
+        (Locale::En, "storage_failed") => "Attachment storage is unavailable",
+        (Locale::Ru, "storage_failed") => "Хранилище вложений недоступно",
+        (Locale::En, "pin_expiry_too_long") => "Pin cannot expire later than in {max} seconds",
+        (Locale::Ru, "pin_expiry_too_long") => "Закрепление не может длиться дольше {max} секунд",
+        (Locale::En, "presence_disabled") => "Presence is disabled",
+        (Locale::Ru, "presence_disabled") => "Присутствие отключено",
+        (Locale::En, "chat_too_large") => "Chat is too large to track presence",
+        (Locale::Ru, "chat_too_large") => "Чат слишком большой, чтобы следить за присутствием",
+        (Locale::En, "ttl_too_long") => "Message TTL cannot be longer than {max} seconds",
+        (Locale::Ru, "ttl_too_long") => "Время жизни сообщений не может быть больше {max} секунд",
+        (Locale::En, "no_draft") => "Chat has no draft",
+        (Locale::Ru, "no_draft") => "В чате нет черновика",
+        (Locale::En, "empty_query") => "Search query is empty",
+        (Locale::Ru, "empty_query") => "Поисковый запрос пуст",
+        (Locale::En, "content_not_configured") => "No content provider is configured for this kind",
+        (Locale::Ru, "content_not_configured") => "Для этого вида контента нет поставщика",
+        (Locale::En, "content_provider_failed") => "Content provider is unavailable",
+        (Locale::Ru, "content_provider_failed") => "Поставщик контента недоступен",
+        (Locale::En, "invalid_cursor") => "Invalid cursor",
+        (Locale::Ru, "invalid_cursor") => "Неправильный курсор",
+        (Locale::En, "too_many_users") => "At most {max} users can be requested at once",
+        (Locale::Ru, "too_many_users") => "За раз можно запросить не больше {max} пользователей",
+        (Locale::En, "usage_days_out_of_range") => "Usage is kept for 1 to {max} days",
+        (Locale::Ru, "usage_days_out_of_range") => "Статистика хранится от 1 до {max} дней",
+        (Locale::En, "mute_in_past") => "Mute must end in the future",
+        (Locale::Ru, "mute_in_past") => "Отключение уведомлений должно закончиться в будущем",
+        (Locale::En, "invalid_range") => "from must not be later than to",
+        (Locale::Ru, "invalid_range") => "from не может быть позже to",
+        (Locale::En, "sharing_not_configured") => "History sharing is not configured",
+        (Locale::Ru, "sharing_not_configured") => "Ссылки на историю не настроены",
+        (Locale::En, "invalid_share_link") => "Invalid share link",
+        (Locale::Ru, "invalid_share_link") => "Неправильная ссылка на историю",
+        (Locale::En, "share_link_expired") => "Share link has expired",
+        (Locale::Ru, "share_link_expired") => "Срок ссылки на историю истек",
+        (Locale::En, "session_bound") => "Session is bound to another client",
+        (Locale::Ru, "session_bound") => "Сессия привязана к другому клиенту",
+        (Locale::En, "invalid_callback_url") => "Callback URL must be an http or https URL",
+        (Locale::Ru, "invalid_callback_url") => "Адрес вебхука должен быть адресом http или https",
+        _ => return None,
+    };
+    Some(template)
+}
+
+/// Переводит сообщение для кода ошибки, подставляя параметры
+///
+/// Если перевода на нужный язык нет, то берется английский, а если нет и его, то сам код
+pub fn translate(locale: Locale, code: &str, args: &[(&str, String)]) -> String {
+    let template = template(locale, code)
+        .or_else(|| template(Locale::En, code))
+        .unwrap_or(code);
+    args.iter()
+        .fold(template.to_string(), |message, (name, value)| {
+            message.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+/// Дата в UTC и в часовом поясе клиента
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisplayTime {
+    /// RFC 3339
+    pub utc: String,
+    /// Для показа человеку, на его языке и в его часовом поясе
+    pub local: String,
+}
+
+/// Пожелания клиента к тому, как показывать ему данные
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayHints {
+    pub locale: Locale,
+    pub timezone: Tz,
+}
+
+impl Default for DisplayHints {
+    fn default() -> Self {
+        Self {
+            locale: Locale::default(),
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+impl DisplayHints {
+    /// Берет язык из Accept-Language и часовой пояс из X-Timezone
+    ///
+    /// Неизвестный часовой пояс не ошибка: даты просто показываются в UTC
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let timezone = req
+            .headers()
+            .get(TIMEZONE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC);
+        Self {
+            locale: Locale::from_request(req),
+            timezone,
+        }
+    }
+
+    /// Форматирует дату (в миллисекундах от начала эпохи)
+    pub fn format_time(&self, date: chrono::Duration) -> DisplayTime {
+        let utc = DateTime::<Utc>::UNIX_EPOCH + date;
+        let local = utc.with_timezone(&self.timezone);
+        let pattern = match self.locale {
+            Locale::En => "%b %-d, %Y, %H:%M %Z",
+            Locale::Ru => "%d.%m.%Y %H:%M %Z",
+        };
+        DisplayTime {
+            utc: utc.to_rfc3339_opts(SecondsFormat::Millis, true),
+            local: local.format(pattern).to_string(),
+        }
+    }
+}
+
+impl FromRequest for DisplayHints {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(DisplayHints::from_request(req)))
+    }
+}
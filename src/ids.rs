@@ -0,0 +1,121 @@
+use std::{fmt, str::FromStr};
+
+use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
+use scylla::frame::response::result::CqlValue;
+use scylla::frame::value::{Value, ValueTooBig};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Идентификаторы пользователей и чатов
+//
+// id пользователя - i64, а id чата - Uuid, но почти все запросы принимают оба сразу, и
+// переставленные местами аргументы (user_id, chat_id) вроде (chat_id, user_id) раньше
+// замечались только по пустому результату запроса. UserId и ChatId - обертки, которые
+// принимают Database, сообщения актора базы и обработчики, так что перепутать их нельзя.
+// В JSON и в Scylla они выглядят так же, как обернутые значения: клиентам и схеме базы
+// ничего менять не нужно.
+
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Default, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct UserId(pub i64);
+
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Default, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct ChatId(pub Uuid);
+
+impl UserId {
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
+impl ChatId {
+    pub fn get(self) -> Uuid {
+        self.0
+    }
+
+    /// Новый случайный id чата
+    pub fn new_v4() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl From<i64> for UserId {
+    fn from(id: i64) -> Self {
+        Self(id)
+    }
+}
+
+impl From<UserId> for i64 {
+    fn from(id: UserId) -> Self {
+        id.0
+    }
+}
+
+impl From<Uuid> for ChatId {
+    fn from(id: Uuid) -> Self {
+        Self(id)
+    }
+}
+
+impl From<ChatId> for Uuid {
+    fn from(id: ChatId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for ChatId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl FromStr for ChatId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+impl FromCqlVal<CqlValue> for UserId {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        i64::from_cql(cql_val).map(Self)
+    }
+}
+
+impl FromCqlVal<CqlValue> for ChatId {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        Uuid::from_cql(cql_val).map(Self)
+    }
+}
+
+impl Value for UserId {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        Value::serialize(&self.0, buf)
+    }
+}
+
+impl Value for ChatId {
+    fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), ValueTooBig> {
+        Value::serialize(&self.0, buf)
+    }
+}
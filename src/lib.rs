@@ -1,5 +1,41 @@
 pub mod actors;
+pub mod app;
+pub mod bots;
+pub mod calls;
+pub mod clock;
+pub mod config;
+pub mod content;
+pub mod coordination;
 pub mod database;
+pub mod demo;
+pub mod events;
 pub mod handlers;
+pub mod http_client;
+pub mod i18n;
+pub mod ids;
+pub mod load_shedding;
+pub mod mentions;
+pub mod metrics;
 pub mod middlewares;
+pub mod migration;
+pub mod moderation;
+pub mod pagination;
+pub mod presence;
+pub mod purge;
+pub mod rate_limit;
+pub mod read_only;
+pub mod redis_topology;
+pub mod repair;
+pub mod secrets;
 pub mod serializable_duration;
+pub mod services;
+pub mod session_binding;
+pub mod sharing;
+pub mod soak;
+pub mod storage;
+pub mod templates;
+pub mod text;
+pub mod transport;
+pub mod uploads;
+pub mod usage;
+pub mod validation;
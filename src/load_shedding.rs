@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    config::{DynamicConfig, LoadShedding},
+    metrics, read_only,
+};
+
+// Сброс нагрузки
+//
+// Перегруженный экземпляр не должен принимать новые сокеты: клиенты, которые переподключаются
+// сразу после отказа, только добавляют ему работы. Пока брокер не успевает разослать
+// сообщения или база не принимает записи, запрос на подключение к вебсокету получает 503
+// с ошибкой overloaded и Retry-After, а уже подключенные клиенты, заявившие reconnect_hint,
+// один раз за время перегрузки получают событие reconnect_hint {after_seconds}: если сокет
+// закроется, переподключаться стоит не раньше этого срока.
+
+/// Код ошибки, которую получает запрос на подключение к перегруженному экземпляру
+pub const OVERLOADED_ERROR: &str = "overloaded";
+
+/// Сообщения, которые брокер этого экземпляра принял, но еще не разослал
+pub static BROKER_QUEUE: QueueDepth = QueueDepth::new();
+
+/// Почему экземпляр считается перегруженным
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadReason {
+    /// Брокер не успевает рассылать сообщения
+    BrokerQueue,
+    /// База не принимает записи
+    ReadOnly,
+}
+
+impl OverloadReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OverloadReason::BrokerQueue => "broker_queue",
+            OverloadReason::ReadOnly => "read_only",
+        }
+    }
+}
+
+/// Счетчик сообщений в очереди
+#[derive(Default)]
+pub struct QueueDepth {
+    depth: AtomicUsize,
+}
+
+impl QueueDepth {
+    pub const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// Учитывает сообщение в очереди, пока жива возвращенная отметка
+    pub fn enter(&self) -> Queued<'_> {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::BROKER_QUEUE_DEPTH.set(depth as i64);
+        Queued { queue: self }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// Сообщение, которое еще в очереди
+pub struct Queued<'a> {
+    queue: &'a QueueDepth,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let depth = self.queue.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::BROKER_QUEUE_DEPTH.set(depth as i64);
+    }
+}
+
+/// Перегружен ли экземпляр, если брокер держит broker_queue сообщений, а read_only -
+/// включен ли режим только для чтения
+pub fn overload_reason(
+    config: &LoadShedding,
+    broker_queue: usize,
+    read_only: bool,
+) -> Option<OverloadReason> {
+    if !config.enabled {
+        return None;
+    }
+    if broker_queue > config.max_broker_queue {
+        return Some(OverloadReason::BrokerQueue);
+    }
+    if read_only && config.shed_when_read_only {
+        return Some(OverloadReason::ReadOnly);
+    }
+    None
+}
+
+/// Перегружен ли экземпляр сейчас
+pub fn current_overload(config: &DynamicConfig) -> Option<OverloadReason> {
+    overload_reason(
+        &config.load_shedding,
+        BROKER_QUEUE.depth(),
+        read_only::WRITES.is_read_only(&config.read_only),
+    )
+}
@@ -1,28 +1,35 @@
 use actix::Actor;
-use actix_web::{
-    self,
-    middleware::Logger,
-    web::{self},
-    App, HttpServer,
-};
 
-use std::error::Error;
+use std::{error::Error, sync::Arc, time::Duration};
 
 use chat::{
     actors::{
+        bot_actor::BotActor,
         broker_actor::BrokerActor,
-        database_actor::{messages::InitDatabase, DatabaseActor},
-        redis_actor::RedisActor,
-    },
-    handlers::{
-        add_user_to_chat, authorize_user, create_new_group_chat, create_new_private_chat,
-        data_types::Addresses, exit_chat, get_chat_history, get_chat_info, get_user_chats,
-        get_user_info, websocket_startup,
+        database_actor::{
+            messages::{
+                CheckSchema, ExpirePins, InitDatabase, PurgeAbandonedChats, RepairMemberships,
+            },
+            DatabaseActor,
+        },
+        redis_actor::{messages::WebsocketMessage, RedisActor},
+        storage_actor::StorageActor,
     },
-    middlewares::test_token_middleware::TestAuthMiddleware,
+    app::ChatApp,
+    config::{self, ConfigHandle, DatabaseConfig},
+    coordination::{spawn_singleton_job, RedisLock},
+    database::{Database, ScyllaDatabase},
+    demo,
+    handlers::data_types::Addresses,
+    presence::PresenceTracker,
+    rate_limit::RateLimiter,
+    repair,
+    session_binding::SessionBinder,
+    soak,
+    usage::RedisUsageTracker,
 };
 
-use log::info;
+use log::{error, info, warn, LevelFilter};
 // Что вообще должен делать чат?
 // - Принимать сообщения от пользователя +
 // - Выдавать новые сообщения пользователю +
@@ -43,57 +50,198 @@ use log::info;
 // 6) /api/get_user_info
 // 7) /api/get_user_chats
 
+/// chat repair [--fix]: проверить связи пользователей и чатов и, с --fix, починить их
+async fn run_repair(config: &DatabaseConfig, fix: bool) -> Result<(), Box<dyn Error>> {
+    let db = ScyllaDatabase::connect(config)
+        .await
+        .map_err(|e| e.to_string())?;
+    let report = repair::repair(&db, fix).await.map_err(|e| e.to_string())?;
+    for problem in &report.inconsistencies {
+        println!("{problem}");
+    }
+    println!(
+        "Found {} inconsistencies, fixed {}",
+        report.inconsistencies.len(),
+        report.fixed
+    );
+    Ok(())
+}
+
+/// chat seed-demo [--users N] [--messages M]: заполнить базу демонстрационными данными
+async fn run_seed_demo(config: &DatabaseConfig, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let seed_config = demo::SeedConfig::parse(args)?;
+    let db = ScyllaDatabase::connect(config)
+        .await
+        .map_err(|e| e.to_string())?;
+    let problems = db.check_schema().await.map_err(|e| e.to_string())?;
+    if !problems.is_empty() {
+        if !config.auto_migrate {
+            return Err("Database schema is incompatible, refusing to seed".into());
+        }
+        db.init_db().await.map_err(|e| e.to_string())?;
+    }
+    let report = demo::seed(&db, &seed_config)
+        .await
+        .map_err(|e| e.to_string())?;
+    println!("{report}");
+    Ok(())
+}
+
+/// chat soak --users N --chats M --rate R [--duration SECS]: нагрузочный прогон в памяти
+async fn run_soak(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let config = soak::SoakConfig::parse(args)?;
+    let report = soak::run(&config).await;
+    println!("{report}");
+    let problems = report.problems(&config);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        return Err(format!("Soak test found {} problems", problems.len()).into());
+    }
+    Ok(())
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+    // Фильтр env_logger пропускает все, уровень задает только log::set_max_level из log_level
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .init();
+    // До чтения конфигурации действует уровень по умолчанию
+    log::set_max_level(LevelFilter::Debug);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Нагрузочному прогону не нужны ни конфигурация, ни Scylla, ни Redis
+    if args.first().map(String::as_str) == Some("soak") {
+        return run_soak(&args[1..]).await;
+    }
     info!("Initializing service");
-    let db = DatabaseActor::new("scylla-database".into(), 9042)
+    let config = ConfigHandle::load()?;
+    let static_config = config.static_config().clone();
+    if args.first().map(String::as_str) == Some("repair") {
+        return run_repair(
+            &static_config.database,
+            args.iter().any(|arg| arg == "--fix"),
+        )
+        .await;
+    }
+    if args.first().map(String::as_str) == Some("seed-demo") {
+        return run_seed_demo(&static_config.database, &args[1..]).await;
+    }
+    actix_web::rt::spawn(config::reload_on_sighup(config.clone()));
+    let db = DatabaseActor::connect(&static_config.database)
         .await
         .map_err(|e| e.to_string())?
         .start();
     info!("Connected to db");
-    db.send(InitDatabase).await.unwrap().unwrap();
+    let mut problems = db.send(CheckSchema).await??;
+    if !problems.is_empty() && static_config.database.auto_migrate {
+        for problem in &problems {
+            warn!("Schema problem: {problem}");
+        }
+        info!("Migrating database schema");
+        db.send(InitDatabase).await??;
+        problems = db.send(CheckSchema).await??;
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("Schema problem: {problem}");
+        }
+        return Err("Database schema is incompatible, refusing to start".into());
+    }
     info!("Initialized db");
-    let broker = BrokerActor::new(db.clone()).await.start();
-    let redis = RedisActor::new("redis-broker", 6379, broker.clone())
+    let broker = BrokerActor::new(db.clone())
         .await
-        .map_err(|e| e.to_string())?
+        .with_config(config.clone())
         .start();
+    let presence = PresenceTracker::connect(
+        &static_config.redis,
+        Duration::from_secs(static_config.presence.ttl_secs),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut redis = RedisActor::connect(&static_config.redis, broker.clone())
+        .await
+        .map_err(|e| e.to_string())?
+        .with_delivery(static_config.delivery.clone(), db.clone())
+        .with_bots(BotActor::new(db.clone(), static_config.bots.clone()).start());
+    if static_config.presence.enabled {
+        redis = redis.with_presence(presence.clone(), static_config.presence.clone());
+    }
+    let redis = redis.start();
+    let limiter = RateLimiter::connect(&static_config.redis)
+        .await
+        .map_err(|e| e.to_string())?;
+    let session_binder = SessionBinder::connect(&static_config.redis)
+        .await
+        .map_err(|e| e.to_string())?;
     info!("Connected to redis");
+    let lock = RedisLock::connect(&static_config.redis)
+        .await
+        .map_err(|e| e.to_string())?;
+    if static_config.purge.enabled {
+        let purge = static_config.purge.clone();
+        let interval = Duration::from_secs(purge.interval_secs);
+        let db = db.clone();
+        spawn_singleton_job(lock.clone(), "purge_abandoned_chats", interval, move || {
+            let db = db.clone();
+            let purge = purge.clone();
+            async move {
+                match db.send(PurgeAbandonedChats(purge)).await {
+                    Ok(Ok(report)) => info!("Purged abandoned chats: {report:?}"),
+                    Ok(Err(e)) => error!("Cannot purge abandoned chats: {e}"),
+                    Err(e) => error!("Cannot purge abandoned chats: {e}"),
+                }
+            }
+        });
+    }
+    if static_config.repair.enabled {
+        let fix = static_config.repair.fix;
+        let interval = Duration::from_secs(static_config.repair.interval_secs);
+        let db = db.clone();
+        spawn_singleton_job(lock.clone(), "repair_memberships", interval, move || {
+            let db = db.clone();
+            async move {
+                if let Err(e) = db.send(RepairMemberships { fix }).await {
+                    error!("Cannot check chat memberships: {e}");
+                }
+            }
+        });
+    }
+    {
+        let interval = Duration::from_secs(static_config.pins.expiry_interval_secs);
+        let db = db.clone();
+        let redis = redis.clone();
+        spawn_singleton_job(lock.clone(), "expire_pins", interval, move || {
+            let db = db.clone();
+            let redis = redis.clone();
+            async move {
+                match db.send(ExpirePins).await {
+                    Ok(Ok(expired)) => {
+                        for unpinned in expired {
+                            redis.do_send(WebsocketMessage::MessageUnpinned(unpinned));
+                        }
+                    }
+                    Ok(Err(e)) => error!("Cannot expire pins: {e}"),
+                    Err(e) => error!("Cannot expire pins: {e}"),
+                }
+            }
+        });
+    }
     let addrs = Addresses {
         db: db.clone(),
         broker: broker.clone(),
         redis: redis.clone(),
+        storage: StorageActor::from_config(&static_config.storage).start(),
     };
-    let data = web::Data::new(addrs);
+    let mut app = ChatApp::new(config, addrs, Arc::new(limiter), session_binder, presence);
+    if static_config.usage.enabled {
+        let usage = RedisUsageTracker::connect(&static_config.redis, &static_config.usage)
+            .await
+            .map_err(|e| e.to_string())?;
+        app = app.with_usage_tracker(Arc::new(usage));
+    }
     info!("Starting service");
-    let _ = HttpServer::new(move || {
-        App::new()
-            .wrap(Logger::default())
-            .wrap(TestAuthMiddleware)
-            .service(
-                web::scope("/api")
-                    .service(
-                        web::scope("/user")
-                            .service(authorize_user)
-                            .service(get_user_info)
-                            .service(get_user_chats),
-                    )
-                    .service(
-                        web::scope("/chat")
-                            .service(create_new_group_chat)
-                            .service(create_new_private_chat)
-                            .service(add_user_to_chat)
-                            .service(exit_chat)
-                            .service(get_chat_info)
-                            .service(get_chat_history),
-                    ),
-            )
-            .service(websocket_startup)
-            .app_data(data.clone())
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await;
+    app.run(("0.0.0.0", 8080)).await?;
     Ok(())
 }
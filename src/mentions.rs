@@ -0,0 +1,81 @@
+use crate::database::data::UserInfo;
+
+// Упоминания в сообщениях
+//
+// Пользователя можно упомянуть по id (@42) или по имени (@Alice). Имена с пробелами
+// упоминаются с подчеркиваниями вместо пробелов (@Alice_Smith), регистр не важен.
+// Упомянутыми считаются только участники чата, сам отправитель в список не попадает.
+
+/// Сколько упоминаний из одного сообщения учитывается
+pub const MAX_MENTIONS_PER_MESSAGE: usize = 20;
+
+/// Упоминание в тексте сообщения, еще не сопоставленное с участниками чата
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mention {
+    Id(i64),
+    Name(String),
+}
+
+/// Находит упоминания в тексте
+///
+/// Упоминание начинается с '@' в начале текста или после символа, который не может быть
+/// частью слова (чтобы адреса почты не считались упоминаниями), и продолжается буквами,
+/// цифрами и '_'
+pub fn parse_mentions(text: &str) -> Vec<Mention> {
+    let mut mentions = Vec::new();
+    let mut previous: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let starts_word = previous.is_none_or(|p| !is_word_char(p));
+        previous = Some(c);
+        if c != '@' || !starts_word {
+            continue;
+        }
+        let mut end = start + 1;
+        while let Some(&(i, next)) = chars.peek() {
+            if !is_word_char(next) {
+                break;
+            }
+            end = i + next.len_utf8();
+            previous = Some(next);
+            chars.next();
+        }
+        let token = &text[start + 1..end];
+        if token.is_empty() {
+            continue;
+        }
+        let mention = match token.parse() {
+            Ok(id) => Mention::Id(id),
+            Err(_) => Mention::Name(token.to_lowercase()),
+        };
+        if !mentions.contains(&mention) {
+            mentions.push(mention);
+        }
+        if mentions.len() == MAX_MENTIONS_PER_MESSAGE {
+            break;
+        }
+    }
+    mentions
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Сопоставляет упоминания с участниками чата и возвращает id упомянутых по возрастанию
+pub fn resolve_mentions(mentions: &[Mention], members: &[UserInfo], sender_id: i64) -> Vec<i64> {
+    let mut ids: Vec<i64> = members
+        .iter()
+        .filter(|member| member.id != sender_id)
+        .filter(|member| {
+            let name = member.name.to_lowercase().replace(' ', "_");
+            mentions.iter().any(|mention| match mention {
+                Mention::Id(id) => *id == member.id,
+                Mention::Name(mentioned) => *mentioned == name,
+            })
+        })
+        .map(|member| member.id)
+        .collect();
+    ids.sort_unstable();
+    ids
+}
@@ -0,0 +1,427 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+// Метрики сервиса
+//
+// Все метрики регистрируются в одном реестре, который отдается эндпоинтом /metrics
+// в текстовом формате Prometheus
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+/// Количество ошибок отправки сообщений акторам (переполненный ящик или остановленный актор)
+pub static MAILBOX_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_actor_mailbox_errors_total",
+            "Failed deliveries of messages to actors",
+        ),
+        &["actor"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Медленные клиенты: сколько раз предупредили и сколько отключили
+pub static SLOW_CONSUMERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_slow_consumers_total",
+            "Websocket clients that could not keep up with their message queue",
+        ),
+        &["action"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Сокеты, закрытые из-за одновременных входов пользователя: вытесненные старые и отклоненные новые
+pub static DUPLICATE_LOGINS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_duplicate_logins_total",
+            "Websockets closed by the duplicate login policy",
+        ),
+        &["action"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Удаленные брошенные чаты: без участников или с несуществующими участниками
+pub static PURGED_CHATS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_purged_chats_total",
+            "Abandoned chats deleted by the purge job",
+        ),
+        &["reason"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Загрузки вложений в хранилище по результату
+pub static ATTACHMENT_UPLOADS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_attachment_uploads_total",
+            "Attachment uploads to object storage",
+        ),
+        &["result"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Доставки сообщений на вебхуки ботов по результату (после всех повторов)
+pub static BOT_DELIVERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_bot_deliveries_total",
+            "Messages delivered to bot webhooks",
+        ),
+        &["result"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Страницы истории, уменьшенные из-за крупных сообщений чата
+pub static SHRUNK_HISTORY_PAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    let counter = IntCounter::new(
+        "chat_history_pages_shrunk_total",
+        "History pages shrunk to fit the response size limit",
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Сколько раз сервис сам переходил в режим только для чтения
+pub static READ_ONLY_TRIPS: LazyLock<IntCounter> = LazyLock::new(|| {
+    let counter = IntCounter::new(
+        "chat_read_only_trips_total",
+        "Times the service switched to read-only mode after failed writes",
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Сколько раз экземпляр заново подключался к Redis после потери подписок
+pub static REDIS_RECONNECTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    let counter = IntCounter::new(
+        "chat_redis_reconnects_total",
+        "Times the pub/sub connection to Redis was restored after being lost",
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Сколько сообщений брокер принял, но еще не разослал по сокетам
+pub static BROKER_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    let gauge = IntGauge::new(
+        "chat_broker_queue_depth",
+        "Messages accepted by the broker but not yet delivered to sockets",
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(gauge.clone()))
+        .expect("Metric registered twice");
+    gauge
+});
+
+/// Сколько мертвых сокетов брокер убрал при последней чистке
+pub static DEAD_SESSIONS_CLEANED: LazyLock<IntGauge> = LazyLock::new(|| {
+    let gauge = IntGauge::new(
+        "chat_broker_dead_sessions_cleaned",
+        "Dead sockets removed from the broker by the last sweep",
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(gauge.clone()))
+        .expect("Metric registered twice");
+    gauge
+});
+
+/// Подключения к вебсокету, отклоненные из-за перегрузки, по причине
+pub static SHED_CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_shed_connections_total",
+            "Websocket upgrades refused because the instance is overloaded",
+        ),
+        &["reason"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Корзины задержек доставки сообщений в секундах
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Время от получения сообщения вебсокетом до рассылки брокером по сокетам
+pub static MESSAGE_DELIVERY_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(
+            "chat_message_delivery_seconds",
+            "Latency from websocket receive to broker delivery",
+        )
+        .buckets(LATENCY_BUCKETS.to_vec()),
+        &["chat_size"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(histogram.clone()))
+        .expect("Metric registered twice");
+    histogram
+});
+
+/// Время от получения сообщения вебсокетом до записи в базу
+pub static MESSAGE_PERSIST_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(
+            "chat_message_persist_seconds",
+            "Latency from websocket receive to database acknowledgement",
+        )
+        .buckets(LATENCY_BUCKETS.to_vec()),
+        &["result"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(histogram.clone()))
+        .expect("Metric registered twice");
+    histogram
+});
+
+/// Время публикации в Redis по каналам, в том числе записи в поток чата (канал stream)
+pub static REDIS_PUBLISH_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    let histogram = HistogramVec::new(
+        HistogramOpts::new(
+            "chat_redis_publish_seconds",
+            "Latency of publishing to Redis",
+        )
+        .buckets(LATENCY_BUCKETS.to_vec()),
+        &["channel"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(histogram.clone()))
+        .expect("Metric registered twice");
+    histogram
+});
+
+/// Неудачные публикации в Redis по каналам
+pub static REDIS_PUBLISH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_redis_publish_failures_total",
+            "Failed publishes to Redis",
+        ),
+        &["channel"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Запросы к Scylla, завершившиеся таймаутом: на стороне клиента (client)
+/// или координатора при чтении (read) и записи (write)
+pub static SCYLLA_TIMEOUTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new(
+            "chat_scylla_timeouts_total",
+            "Scylla requests that timed out",
+        ),
+        &["kind"],
+    )
+    .expect("Invalid metric definition");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric registered twice");
+    counter
+});
+
+/// Метрики драйвера сессии, через которую работает сервис
+static SCYLLA_DRIVER: RwLock<Option<Arc<scylla::Metrics>>> = RwLock::new(None);
+
+static SCYLLA_DRIVER_COLLECTOR: LazyLock<()> = LazyLock::new(|| {
+    REGISTRY
+        .register(Box::new(ScyllaDriverCollector::new()))
+        .expect("Metric registered twice");
+});
+
+/// Начинает отдавать в /metrics внутренние метрики драйвера Scylla
+///
+/// Драйвер сам считает запросы, ошибки, повторы и задержки, их значения читаются
+/// при каждом сборе метрик. Если сессий несколько, то отдаются метрики последней
+pub fn watch_scylla_driver(driver: Arc<scylla::Metrics>) {
+    *SCYLLA_DRIVER.write().unwrap() = Some(driver);
+    LazyLock::force(&SCYLLA_DRIVER_COLLECTOR);
+}
+
+/// Имена и описания метрик драйвера Scylla
+const SCYLLA_DRIVER_METRICS: &[(&str, &str)] = &[
+    (
+        "chat_scylla_queries_total",
+        "Queries sent by the Scylla driver",
+    ),
+    (
+        "chat_scylla_errors_total",
+        "Queries that failed in the Scylla driver",
+    ),
+    (
+        "chat_scylla_paged_queries_total",
+        "Pages of paged queries sent by the Scylla driver",
+    ),
+    (
+        "chat_scylla_paged_errors_total",
+        "Pages of paged queries that failed in the Scylla driver",
+    ),
+    (
+        "chat_scylla_retries_total",
+        "Queries retried by the Scylla driver",
+    ),
+    (
+        "chat_scylla_latency_avg_ms",
+        "Average query latency measured by the Scylla driver",
+    ),
+    (
+        "chat_scylla_latency_p99_ms",
+        "99th percentile of query latency measured by the Scylla driver",
+    ),
+];
+
+struct ScyllaDriverCollector {
+    descs: Vec<Desc>,
+}
+
+impl ScyllaDriverCollector {
+    fn new() -> Self {
+        let descs = SCYLLA_DRIVER_METRICS
+            .iter()
+            .map(|(name, help)| {
+                Desc::new(name.to_string(), help.to_string(), vec![], HashMap::new())
+                    .expect("Invalid metric definition")
+            })
+            .collect();
+        Self { descs }
+    }
+}
+
+impl Collector for ScyllaDriverCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let Some(driver) = SCYLLA_DRIVER.read().unwrap().clone() else {
+            return vec![];
+        };
+        // Гистограмма задержек пуста, пока не было ни одного запроса
+        let values = [
+            Some(driver.get_queries_num()),
+            Some(driver.get_errors_num()),
+            Some(driver.get_queries_iter_num()),
+            Some(driver.get_errors_iter_num()),
+            Some(driver.get_retries_num()),
+            driver.get_latency_avg_ms().ok(),
+            driver.get_latency_percentile_ms(99.0).ok(),
+        ];
+        SCYLLA_DRIVER_METRICS
+            .iter()
+            .zip(values)
+            .filter_map(|(&(name, help), value)| {
+                let value = value?;
+                if name.ends_with("_total") {
+                    let counter = IntCounter::new(name, help).ok()?;
+                    counter.inc_by(value);
+                    counter.collect().pop()
+                } else {
+                    let gauge = IntGauge::new(name, help).ok()?;
+                    gauge.set(value as i64);
+                    gauge.collect().pop()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Учитывает ошибку запроса к Scylla, если это таймаут
+pub fn observe_scylla_error(error: &scylla::transport::errors::QueryError) {
+    use scylla::transport::errors::{DbError, QueryError};
+    let kind = match error {
+        QueryError::TimeoutError | QueryError::RequestTimeout(_) => "client",
+        QueryError::DbError(DbError::ReadTimeout { .. }, _) => "read",
+        QueryError::DbError(DbError::WriteTimeout { .. }, _) => "write",
+        _ => return,
+    };
+    SCYLLA_TIMEOUTS.with_label_values(&[kind]).inc();
+}
+
+/// Корзина размера чата для меток метрик, чтобы не плодить метку на каждый размер
+pub fn chat_size_bucket(size: usize) -> &'static str {
+    match size {
+        0..=2 => "1-2",
+        3..=10 => "3-10",
+        11..=100 => "11-100",
+        101..=1000 => "101-1000",
+        _ => "1000+",
+    }
+}
+
+/// Сколько секунд прошло с момента date (в миллисекундах от начала эпохи)
+pub fn seconds_since(date: chrono::Duration) -> f64 {
+    let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+    ((now - date).num_milliseconds().max(0) as f64) / 1000.0
+}
+
+/// Отдает все метрики в текстовом формате Prometheus
+pub fn gather() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("Cannot encode metrics");
+    String::from_utf8(buffer).expect("Metrics are not valid utf-8")
+}
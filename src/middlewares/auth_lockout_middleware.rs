@@ -0,0 +1,136 @@
+use actix_web::{
+    self,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, StatusCode},
+    Error, HttpMessage, HttpResponse,
+};
+use log::{error, warn};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
+
+use crate::{config::ConfigHandle, rate_limit::RateLimit};
+
+use super::client_ip_middleware::ClientIp;
+
+// Защита от перебора при авторизации
+//
+// Считает ответы 401 и неудачные подключения к вебсокету по адресу клиента и по
+// пользователю (если он известен). Когда неудач становится слишком много, адрес или
+// пользователь блокируется на время, а все его запросы получают 429 с Retry-After.
+//
+// Должен стоять между ClientIpMiddleware и авторизацией: адрес клиента уже известен,
+// а ответы авторизации еще видны.
+
+/// Путь, на котором открывается вебсокет
+const WEBSOCKET_PATH: &str = "/ws";
+
+pub struct AuthLockoutMiddleware {
+    limiter: Arc<dyn RateLimit>,
+    config: ConfigHandle,
+}
+
+impl AuthLockoutMiddleware {
+    pub fn new(limiter: Arc<dyn RateLimit>, config: ConfigHandle) -> Self {
+        Self { limiter, config }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthLockoutMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthLockoutMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthLockoutMiddlewareInner {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct AuthLockoutMiddlewareInner<S> {
+    service: Rc<S>,
+    limiter: Arc<dyn RateLimit>,
+    config: ConfigHandle,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthLockoutMiddlewareInner<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+        let limits = self.config.current().auth_lockout.clone();
+        let ip_subject = req
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ip| format!("ip:{}", ip.0));
+
+        Box::pin(async move {
+            if let Some(subject) = &ip_subject {
+                match limiter.lockout_remaining(subject).await {
+                    Ok(Some(remaining)) => {
+                        let (req, _req_body) = req.into_parts();
+                        let response = too_many_requests(remaining).map_into_right_body();
+                        return Ok(ServiceResponse::new(req, response));
+                    }
+                    Ok(None) => {}
+                    // Если Redis недоступен, то не мешаем пользователям входить
+                    Err(e) => error!("Cannot check auth lockout: {e}"),
+                }
+            }
+
+            let is_websocket = req.path() == WEBSOCKET_PATH;
+            let res = service.call(req).await?;
+
+            let status = res.status();
+            let is_failure = status == StatusCode::UNAUTHORIZED
+                || (is_websocket
+                    && status.is_client_error()
+                    && status != StatusCode::TOO_MANY_REQUESTS);
+            if is_failure {
+                let user_subject = res
+                    .request()
+                    .extensions()
+                    .get::<i64>()
+                    .map(|id| format!("user:{id}"));
+                for subject in ip_subject.iter().chain(user_subject.iter()) {
+                    match limiter.register_auth_failure(subject, &limits).await {
+                        Ok(true) => warn!("Too many auth failures, locking out {subject}"),
+                        Ok(false) => {}
+                        Err(e) => error!("Cannot register auth failure: {e}"),
+                    }
+                }
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+pub fn too_many_requests(retry_after_secs: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after_secs))
+        .finish()
+}
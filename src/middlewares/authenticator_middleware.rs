@@ -0,0 +1,113 @@
+use actix_web::{
+    self,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage, HttpResponse,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    sync::Arc,
+};
+
+use super::token_middleware::TokenExpiresAt;
+
+// Авторизация запросов
+//
+// Middleware только спрашивает у Authenticator, кто отправил запрос, и кладет ответ в
+// расширения запроса: id пользователя для обработчиков и срок токена для вебсокета.
+// Сама схема авторизации (JWT в cookie, заголовки service mesh, тестовый заголовок)
+// подставляется при сборке ChatApp, обработчики о ней ничего не знают.
+//
+// Ссылки на историю чата открываются без авторизации: доступ к ним дает подпись токена.
+
+/// Пути, которые отдаются без авторизации
+pub const PUBLIC_PATH_PREFIX: &str = "/share/";
+
+/// Кто отправил запрос
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub user_id: i64,
+    /// Когда истекает токен (секунды от начала эпохи), если схема это знает
+    pub expires_at: Option<i64>,
+}
+
+/// Схема авторизации
+pub trait Authenticator: Send + Sync {
+    /// Узнает пользователя по запросу, а если не удалось - возвращает ответ клиенту
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Identity, HttpResponse>;
+}
+
+pub struct AuthenticatorMiddleware {
+    authenticator: Arc<dyn Authenticator>,
+}
+
+impl AuthenticatorMiddleware {
+    pub fn new(authenticator: Arc<dyn Authenticator>) -> Self {
+        Self { authenticator }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthenticatorMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticatorMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticatorMiddlewareInner {
+            service,
+            authenticator: self.authenticator.clone(),
+        }))
+    }
+}
+
+pub struct AuthenticatorMiddlewareInner<S> {
+    service: S,
+    authenticator: Arc<dyn Authenticator>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticatorMiddlewareInner<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if req.path().starts_with(PUBLIC_PATH_PREFIX) {
+            let res = self.service.call(req);
+            return Box::pin(async move { Ok(res.await?.map_into_left_body()) });
+        }
+        let identity = match self.authenticator.authenticate(&req) {
+            Ok(identity) => identity,
+            Err(response) => {
+                let (req, _req_body) = req.into_parts();
+                let response = response.map_into_right_body();
+                return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+            }
+        };
+
+        req.extensions_mut().insert(identity.user_id);
+        if let Some(expires_at) = identity.expires_at {
+            req.extensions_mut().insert(TokenExpiresAt(expires_at));
+        }
+
+        let res = self.service.call(req);
+        Box::pin(async move {
+            let res = res.await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
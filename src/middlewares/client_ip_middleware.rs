@@ -0,0 +1,161 @@
+use actix_web::{
+    self,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderMap},
+    Error, HttpMessage, HttpResponse,
+};
+use std::{
+    future::{ready, Future, Ready},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+};
+
+use crate::config::{ConfigHandle, NetworkConfig};
+
+// Определение реального адреса клиента
+//
+// Если запрос пришел от доверенного прокси, то адрес клиента берется из заголовков
+// Forwarded или X-Forwarded-For. Цепочка адресов читается справа налево, пока встречаются
+// доверенные прокси: первый недоверенный адрес и есть клиент. Заголовкам от недоверенных
+// узлов не верим, иначе клиент мог бы подставить любой адрес.
+//
+// Найденный адрес кладется в extensions запроса как ClientIp, где его могут взять
+// ограничители частоты запросов и вебсокет-сессии. Клиенты, не прошедшие списки
+// allow/deny из конфигурации, получают Forbidden.
+
+/// Реальный адрес клиента
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+pub struct ClientIpMiddleware {
+    config: ConfigHandle,
+}
+
+impl ClientIpMiddleware {
+    pub fn new(config: ConfigHandle) -> Self {
+        Self { config }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ClientIpMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientIpMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ClientIpMiddlewareInner {
+            service,
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct ClientIpMiddlewareInner<S> {
+    service: S,
+    config: ConfigHandle,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientIpMiddlewareInner<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let network = &self.config.current().network;
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let client_ip = resolve_client_ip(peer, req.headers(), network);
+
+        if let Some(ip) = client_ip {
+            if !network.is_allowed(&ip) {
+                let (req, _req_body) = req.into_parts();
+                let response = HttpResponse::Forbidden().finish().map_into_right_body();
+                return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+            }
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+
+        let res = self.service.call(req);
+        Box::pin(async move {
+            let res = res.await?;
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+/// Определяет адрес клиента по адресу соединения и заголовкам прокси
+pub fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    network: &NetworkConfig,
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if !network.is_trusted_proxy(&client) {
+        return Some(client);
+    }
+    let chain = forwarded_chain(headers);
+    for hop in chain.iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) => {
+                client = ip;
+                if !network.is_trusted_proxy(&ip) {
+                    break;
+                }
+            }
+            // Скрытый или битый адрес: дальше по цепочке верить нечему
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+/// Цепочка адресов из Forwarded, а если его нет - из X-Forwarded-For
+fn forwarded_chain(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<String> = headers
+        .get_all(header::FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .collect()
+}
+
+/// Разбирает один адрес цепочки: "1.2.3.4", "1.2.3.4:80", "[::1]:80" или "::1"
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(hop) {
+        return Some(ip);
+    }
+    if let Ok(addr) = SocketAddr::from_str(hop) {
+        return Some(addr.ip());
+    }
+    IpAddr::from_str(hop.trim_start_matches('[').trim_end_matches(']')).ok()
+}
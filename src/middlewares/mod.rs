@@ -1,2 +1,7 @@
+pub mod auth_lockout_middleware;
+pub mod authenticator_middleware;
+pub mod client_ip_middleware;
+pub mod read_only_middleware;
 pub mod test_token_middleware;
 pub mod token_middleware;
+pub mod usage_middleware;
@@ -0,0 +1,98 @@
+use actix_web::{
+    self,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpResponse,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+};
+
+use crate::{
+    config::ConfigHandle,
+    read_only::{READ_ONLY_ERROR, WRITES},
+};
+
+// Отказ в записи в режиме только для чтения
+//
+// Пока сервис в режиме только для чтения, запросы к /api, которые что-то меняют, получают
+// 503 с {"error": "read_only"} и Retry-After. GET проходят как обычно, как и POST-запросы,
+// которые ничего не пишут. Вебсокет открывается через GET и не закрывается.
+
+/// Запросы не через GET, которые ничего не пишут в базу
+const READ_PATHS: &[&str] = &["/api/user/bulk-info", "/api/admin/reload-config"];
+
+pub struct ReadOnlyMiddleware {
+    config: ConfigHandle,
+}
+
+impl ReadOnlyMiddleware {
+    pub fn new(config: ConfigHandle) -> Self {
+        Self { config }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ReadOnlyMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ReadOnlyMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ReadOnlyMiddlewareInner {
+            service: Rc::new(service),
+            config: self.config.clone(),
+        }))
+    }
+}
+
+pub struct ReadOnlyMiddlewareInner<S> {
+    service: Rc<S>,
+    config: ConfigHandle,
+}
+
+impl<S, B> Service<ServiceRequest> for ReadOnlyMiddlewareInner<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let settings = self.config.current().read_only.clone();
+        if is_write(req.method(), req.path()) && WRITES.is_read_only(&settings) {
+            let (req, _req_body) = req.into_parts();
+            let response = read_only_response(settings.cooldown_secs).map_into_right_body();
+            return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+        }
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) })
+    }
+}
+
+/// Меняет ли запрос что-нибудь в базе
+pub fn is_write(method: &Method, path: &str) -> bool {
+    path.starts_with("/api/")
+        && !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+        && !READ_PATHS.contains(&path)
+}
+
+pub fn read_only_response(retry_after_secs: u64) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, retry_after_secs))
+        .json(serde_json::json!({ "error": READ_ONLY_ERROR }))
+}
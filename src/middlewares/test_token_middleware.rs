@@ -1,50 +1,19 @@
 use actix_web::{
     self,
     body::EitherBody,
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage, HttpResponse,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
 };
-use std::{
-    future::{ready, Future, Ready},
-    pin::Pin,
+use std::{future::Ready, sync::Arc};
+
+use super::authenticator_middleware::{
+    Authenticator, AuthenticatorMiddleware, AuthenticatorMiddlewareInner, Identity,
 };
 
 pub struct TestAuthMiddleware;
 
-impl<S, B> Transform<S, ServiceRequest> for TestAuthMiddleware
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<EitherBody<B>>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = TestAuthMiddlewareInner<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(TestAuthMiddlewareInner { service }))
-    }
-}
-
-pub struct TestAuthMiddlewareInner<S> {
-    service: S,
-}
-
-impl<S, B> Service<ServiceRequest> for TestAuthMiddlewareInner<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<EitherBody<B>>;
-    type Error = Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
-
-    forward_ready!(service);
-
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+impl Authenticator for TestAuthMiddleware {
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Identity, HttpResponse> {
         let user_id = req
             .headers()
             .get("chat_user_id")
@@ -53,21 +22,38 @@ where
         let user_id = if let Some(id) = user_id {
             id
         } else {
-            let (req, _req_body) = req.into_parts();
-            let response = HttpResponse::Unauthorized().finish().map_into_right_body();
-            // let response = HttpResponse::PermanentRedirect()
+            return Err(HttpResponse::Unauthorized().finish());
+            // return Err(HttpResponse::PermanentRedirect()
             //     .insert_header(("Location", "/login"))
-            //     .finish()
-            //     .map_into_right_body();
-            return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+            //     .finish());
         };
 
-        req.extensions_mut().insert(user_id);
-
-        let res = self.service.call(req);
-        Box::pin(async move {
-            let res = res.await?;
-            Ok(res.map_into_left_body())
+        // Срок токена задается заголовком, чтобы проверять истечение без настоящих токенов
+        let expires_at = req
+            .headers()
+            .get("chat_token_expires_at")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|raw_value| raw_value.parse::<i64>().ok());
+        Ok(Identity {
+            user_id,
+            expires_at,
         })
     }
 }
+
+impl<S, B> Transform<S, ServiceRequest> for TestAuthMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticatorMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        AuthenticatorMiddleware::new(Arc::new(TestAuthMiddleware)).new_transform(service)
+    }
+}
@@ -1,17 +1,16 @@
 use actix_web::{
     self,
     body::EitherBody,
-    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage, HttpResponse,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
 };
 use jsonwebtoken::jwk;
 use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde_json;
-use std::{
-    collections::HashMap,
-    env,
-    future::{ready, Future, Ready},
-    pin::Pin,
+use std::{collections::HashMap, env, future::Ready, sync::Arc};
+
+use super::authenticator_middleware::{
+    Authenticator, AuthenticatorMiddleware, AuthenticatorMiddlewareInner, Identity,
 };
 
 // .wrap_fn(|req, srv| {
@@ -29,53 +28,23 @@ use std::{
 //         Ok(ServiceResponse::new(req, res))
 // }})
 
-pub struct AuthMiddleware;
-
-impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<EitherBody<B>>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = AuthMiddlewareInner<S>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
-
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(AuthMiddlewareInner { service }))
-    }
-}
-
-pub struct AuthMiddlewareInner<S> {
-    service: S,
-}
-
-impl<S, B> Service<ServiceRequest> for AuthMiddlewareInner<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Response = ServiceResponse<EitherBody<B>>;
-    type Error = Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+/// Когда истекает токен запроса (секунды от начала эпохи), если в нем есть exp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenExpiresAt(pub i64);
 
-    forward_ready!(service);
+pub struct AuthMiddleware;
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+impl Authenticator for AuthMiddleware {
+    fn authenticate(&self, req: &ServiceRequest) -> Result<Identity, HttpResponse> {
         let user_id: i64;
+        let expires_at: Option<i64>;
 
         let token = if let Some(t) = req.cookie("token") {
             t
         } else {
-            let (req, _req_body) = req.into_parts();
-            let response = HttpResponse::PermanentRedirect()
+            return Err(HttpResponse::PermanentRedirect()
                 .insert_header(("Location", "/login"))
-                .finish()
-                .map_into_right_body();
-            return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+                .finish());
         };
         let token = token.value();
         let jwk: jwk::Jwk =
@@ -94,24 +63,36 @@ where
                         .expect("user_id field is not present in JWT")
                         .as_i64()
                         .expect("user_id field is not i64 convertable");
+                    expires_at = token.claims.get("exp").and_then(|exp| exp.as_i64());
                 } else {
-                    let (req, _req_body) = req.into_parts();
-                    let response = HttpResponse::PermanentRedirect()
+                    return Err(HttpResponse::PermanentRedirect()
                         .insert_header(("Location", "/login"))
-                        .finish()
-                        .map_into_right_body();
-                    return Box::pin(async move { Ok(ServiceResponse::new(req, response)) });
+                        .finish());
                 }
             }
             _ => unreachable!("should be rsa"),
         }
 
-        req.extensions_mut().insert(user_id);
-
-        let res = self.service.call(req);
-        Box::pin(async move {
-            let res = res.await?;
-            Ok(res.map_into_left_body())
+        Ok(Identity {
+            user_id,
+            expires_at,
         })
     }
 }
+
+impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticatorMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        AuthenticatorMiddleware::new(Arc::new(AuthMiddleware)).new_transform(service)
+    }
+}
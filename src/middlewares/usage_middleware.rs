@@ -0,0 +1,79 @@
+use actix_web::{
+    self,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use log::error;
+use std::{
+    future::{ready, Ready},
+    sync::Arc,
+};
+
+use crate::ids::UserId;
+use crate::usage::{UsageKind, UsageTracker};
+
+// Учет запросов к API по пользователям
+//
+// Должен стоять внутри авторизации: учитываются только запросы, пользователь которых
+// известен. Счетчик увеличивается в фоне, так что Redis не задерживает ответ, а его
+// недоступность не мешает запросу.
+
+pub struct UsageMiddleware {
+    tracker: Arc<dyn UsageTracker>,
+}
+
+impl UsageMiddleware {
+    pub fn new(tracker: Arc<dyn UsageTracker>) -> Self {
+        Self { tracker }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for UsageMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = UsageMiddlewareInner<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UsageMiddlewareInner {
+            service,
+            tracker: self.tracker.clone(),
+        }))
+    }
+}
+
+pub struct UsageMiddlewareInner<S> {
+    service: S,
+    tracker: Arc<dyn UsageTracker>,
+}
+
+impl<S, B> Service<ServiceRequest> for UsageMiddlewareInner<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(user_id) = req.extensions().get::<i64>().copied() {
+            let tracker = self.tracker.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = tracker.record(UserId(user_id), UsageKind::ApiRequest).await {
+                    error!("Cannot record API usage of user {user_id}: {e}");
+                }
+            });
+        }
+        self.service.call(req)
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{DBError, Database, NameTakenError, PageIndex},
+    ids::{ChatId, UserId},
+};
+
+// Перенос данных между хранилищами
+//
+// Копирует пользователей, чаты и сообщения из одной реализации Database в другую, пользуясь
+// только методами трейта, так что схема хранения у источника и приемника может отличаться.
+// Чаты читаются от лица их участников, поэтому чаты, из которых все вышли, не переносятся.
+//
+// После каждой страницы сообщений прогресс сохраняется в файл контрольной точки, и прерванный
+// перенос продолжается с того же места. Повторная запись уже перенесенных данных ничего
+// не портит: импорт в приемник идемпотентен.
+
+pub const DEFAULT_PAGE_SIZE: usize = 500;
+
+/// Сколько всего перенесено
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MigrationProgress {
+    pub users: usize,
+    pub chats: usize,
+    pub messages: usize,
+}
+
+/// Состояние переноса, которое сохраняется между запусками
+#[derive(Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub progress: MigrationProgress,
+    pub users_done: bool,
+    pub chats_done: HashSet<ChatId>,
+    /// Чат, перенос которого прервался, и страница, с которой надо продолжить
+    pub current_chat: Option<ChatId>,
+    pub current_page: Option<PageIndex>,
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Checkpoint::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Сохраняет состояние через временный файл, чтобы падение посреди записи
+    /// не оставило испорченную контрольную точку
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+pub struct Migration<'a, S: Database, T: Database> {
+    source: &'a S,
+    target: &'a T,
+    checkpoint_path: Option<PathBuf>,
+    page_size: usize,
+    checkpoint: Checkpoint,
+}
+
+impl<'a, S: Database, T: Database> Migration<'a, S, T> {
+    /// Готовит перенос, продолжая с контрольной точки, если она уже есть
+    pub fn new(
+        source: &'a S,
+        target: &'a T,
+        checkpoint_path: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let checkpoint = match &checkpoint_path {
+            Some(path) => Checkpoint::load(path)?,
+            None => Checkpoint::default(),
+        };
+        Ok(Self {
+            source,
+            target,
+            checkpoint_path,
+            page_size: DEFAULT_PAGE_SIZE,
+            checkpoint,
+        })
+    }
+
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn progress(&self) -> &MigrationProgress {
+        &self.checkpoint.progress
+    }
+
+    pub async fn run(mut self) -> Result<MigrationProgress, Box<dyn Error>> {
+        let mut users = self.source.get_user_list().await?;
+        users.sort();
+
+        if !self.checkpoint.users_done {
+            for &user_id in &users {
+                let user = self.source.get_user_info(user_id).await?;
+                match self
+                    .target
+                    .create_new_user(UserId(user.id), user.name.clone())
+                    .await
+                {
+                    Ok(_) => {}
+                    // В источнике имена могли повторяться, а в приемнике они уникальны
+                    Err(DBError::LogicError(e)) if e.is::<NameTakenError>() => {
+                        let name = format!("{} ({})", user.name, user.id);
+                        warn!("Name of user {} is taken, renamed to {name}", user.id);
+                        self.target.create_new_user(UserId(user.id), name).await?;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                if user.profile != Default::default() {
+                    self.target
+                        .set_user_profile(UserId(user.id), user.profile)
+                        .await?;
+                }
+                self.checkpoint.progress.users += 1;
+            }
+            self.checkpoint.users_done = true;
+            self.save()?;
+            info!("Migrated {} users", self.checkpoint.progress.users);
+        }
+
+        for &user_id in &users {
+            for chat_id in self.source.get_user_chats(user_id).await? {
+                if self.checkpoint.chats_done.contains(&chat_id) {
+                    continue;
+                }
+                self.migrate_chat(user_id, chat_id).await?;
+            }
+        }
+
+        info!("Migration finished: {:?}", self.checkpoint.progress);
+        Ok(self.checkpoint.progress)
+    }
+
+    /// Переносит чат и его историю, читая ее от лица участника member_id
+    async fn migrate_chat(
+        &mut self,
+        member_id: UserId,
+        chat_id: ChatId,
+    ) -> Result<(), Box<dyn Error>> {
+        let chat_info = match self.source.get_chat_info(member_id, chat_id).await {
+            Ok(info) => info,
+            Err(DBError::LogicError(e)) => {
+                warn!("Skipping chat {chat_id}: {e}");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut page = if self.checkpoint.current_chat == Some(chat_id) {
+            info!("Resuming chat {chat_id}");
+            self.checkpoint.current_page.take()
+        } else {
+            self.target.import_chat(chat_info).await?;
+            None
+        };
+        self.checkpoint.current_chat = Some(chat_id);
+
+        loop {
+            let (messages, next_page) = self
+                .source
+                .get_chat_history_paged(member_id, chat_id, self.page_size, page)
+                .await?;
+            self.checkpoint.progress.messages += messages.len();
+            self.target.import_messages(chat_id, messages).await?;
+            if !next_page.has_next_page() {
+                break;
+            }
+            self.checkpoint.current_page = Some(next_page);
+            self.save()?;
+            page = self.checkpoint.current_page.take();
+        }
+
+        self.checkpoint.current_chat = None;
+        self.checkpoint.current_page = None;
+        self.checkpoint.chats_done.insert(chat_id);
+        self.checkpoint.progress.chats += 1;
+        self.save()?;
+        info!(
+            "Migrated chat {chat_id} ({} chats, {} messages so far)",
+            self.checkpoint.progress.chats, self.checkpoint.progress.messages
+        );
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        match &self.checkpoint_path {
+            Some(path) => self.checkpoint.save(path),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{config::ConfigHandle, validation::FieldError};
+
+// Модерация текста сообщений
+//
+// Текст нового сообщения и новый текст при правке проходят через фильтр модерации после
+// обычной проверки ввода. По умолчанию фильтр - список запрещенных слов moderation_wordlist
+// из конфигурации, который перечитывается без перезапуска. Встраивающее чат приложение может
+// подставить свой фильтр через ChatApp::with_moderation.
+
+/// Фильтр модерации текста сообщений
+pub trait ModerationFilter: Send + Sync {
+    /// Проверяет текст поля field, ошибка возвращается клиенту как ошибка проверки ввода
+    fn check(&self, field: &str, text: &str) -> Result<(), FieldError>;
+}
+
+/// Пропускает любой текст
+pub struct NoModeration;
+
+impl ModerationFilter for NoModeration {
+    fn check(&self, _field: &str, _text: &str) -> Result<(), FieldError> {
+        Ok(())
+    }
+}
+
+/// Отклоняет текст со словами из moderation_wordlist текущей конфигурации
+pub struct WordlistFilter {
+    config: ConfigHandle,
+}
+
+impl WordlistFilter {
+    pub fn new(config: ConfigHandle) -> Self {
+        Self { config }
+    }
+}
+
+impl ModerationFilter for WordlistFilter {
+    fn check(&self, field: &str, text: &str) -> Result<(), FieldError> {
+        check_wordlist(field, text, &self.config.current().moderation_wordlist)
+    }
+}
+
+/// Ищет в text слова из words целиком и без учета регистра
+pub fn check_wordlist(field: &str, text: &str, words: &[String]) -> Result<(), FieldError> {
+    if words.is_empty() {
+        return Ok(());
+    }
+    let text = text.to_lowercase();
+    let blocked = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .any(|word| words.iter().any(|blocked| blocked.to_lowercase() == word));
+    if blocked {
+        return Err(FieldError::new(field, "blocked_word", vec![]));
+    }
+    Ok(())
+}
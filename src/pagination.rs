@@ -0,0 +1,82 @@
+use actix_web::{
+    http::{header, Uri},
+    HttpResponseBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+// Метаданные постраничных ответов
+//
+// Все постраничные эндпоинты (история, ответы на сообщение, участники чата, список
+// пользователей для администраторов) сообщают одно и то же: курсор следующей страницы,
+// есть ли она и, если это дешево узнать, примерное число элементов. Метаданные дублируются
+// в заголовках ответа, а Link с rel="next" содержит готовую ссылку на следующую страницу,
+// так что клиенту не нужно собирать запрос самому. Тело истории и ответов остается парой
+// [сообщения, индекс] ради старых клиентов, для них метаданные есть только в заголовках.
+
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+pub const HAS_MORE_HEADER: &str = "x-has-more";
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Метаданные страницы
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageMeta {
+    /// Курсор следующей страницы, None - страница последняя
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+    /// Примерное число элементов, если его дешево узнать
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+impl PageMeta {
+    pub fn new(next_cursor: Option<String>) -> Self {
+        Self {
+            has_more: next_cursor.is_some(),
+            next_cursor,
+            total: None,
+        }
+    }
+
+    pub fn with_total(mut self, total: Option<u64>) -> Self {
+        self.total = total;
+        self
+    }
+
+    /// Ссылка на следующую страницу для заголовка Link: тот же запрос,
+    /// в котором параметр cursor_param заменен курсором следующей страницы
+    pub fn next_link(&self, uri: &Uri, cursor_param: &str) -> Option<String> {
+        let cursor = self.next_cursor.as_ref()?;
+        let mut query: Vec<&str> = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some(cursor_param))
+            .collect();
+        let cursor = format!("{cursor_param}={}", urlencoding::encode(cursor));
+        query.push(&cursor);
+        Some(format!(
+            "<{}?{}>; rel=\"next\"",
+            uri.path(),
+            query.join("&")
+        ))
+    }
+
+    /// Добавляет метаданные в заголовки ответа
+    pub fn insert_headers(
+        &self,
+        response: &mut HttpResponseBuilder,
+        uri: &Uri,
+        cursor_param: &str,
+    ) {
+        if let Some(cursor) = &self.next_cursor {
+            response.insert_header((NEXT_CURSOR_HEADER, cursor.as_str()));
+        }
+        response.insert_header((HAS_MORE_HEADER, self.has_more.to_string()));
+        if let Some(total) = self.total {
+            response.insert_header((TOTAL_COUNT_HEADER, total));
+        }
+        if let Some(link) = self.next_link(uri, cursor_param) {
+            response.insert_header((header::LINK, link));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::{error::Error, time::Duration};
+
+use redis::{RedisResult, Script};
+use uuid::Uuid;
+
+use crate::{
+    config::RedisConfig,
+    ids::UserId,
+    redis_topology::{RedisConnection, RedisConnector},
+};
+
+// Присутствие пользователей в сети
+//
+// У каждого пользователя в Redis есть сортированное множество экземпляров сервиса, на которых
+// у него открыты сокеты, с временем истечения отметки в качестве веса. Экземпляр добавляет
+// себя при первом сокете пользователя, убирает при последнем и периодически продлевает
+// отметки всех своих пользователей, так что отметки упавшего экземпляра истекают сами.
+// Пользователь в сети, пока у него есть хотя бы одна неистекшая отметка.
+//
+// Переходы "появился в сети" и "вышел из сети" считаются по всем экземплярам сразу: второй
+// сокет пользователя на другом экземпляре не порождает еще одно событие member_online.
+
+const PRESENCE_KEY_PREFIX: &str = "presence:";
+
+/// Отметить экземпляр и вернуть, на скольких других экземплярах пользователь уже в сети
+const ONLINE_SCRIPT: &str = r#"
+redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", ARGV[2])
+local others = redis.call("ZCARD", KEYS[1])
+if redis.call("ZSCORE", KEYS[1], ARGV[1]) then
+    others = others - 1
+end
+redis.call("ZADD", KEYS[1], ARGV[3], ARGV[1])
+redis.call("PEXPIRE", KEYS[1], ARGV[4])
+return others
+"#;
+
+/// Убрать отметку экземпляра и вернуть, на скольких экземплярах пользователь еще в сети
+const OFFLINE_SCRIPT: &str = r#"
+redis.call("ZREM", KEYS[1], ARGV[1])
+redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", ARGV[2])
+return redis.call("ZCARD", KEYS[1])
+"#;
+
+#[derive(Clone)]
+pub struct PresenceTracker {
+    connection: RedisConnection,
+    config: RedisConfig,
+    /// Отличает этот экземпляр сервиса от остальных
+    instance_id: String,
+    ttl: Duration,
+}
+
+impl PresenceTracker {
+    pub async fn connect(config: &RedisConfig, ttl: Duration) -> Result<Self, Box<dyn Error>> {
+        let connection = RedisConnector::new(config).connect().await?;
+        Ok(Self {
+            connection,
+            config: config.clone(),
+            instance_id: Uuid::new_v4().to_string(),
+            ttl,
+        })
+    }
+
+    /// Как часто продлевать отметки, чтобы они не истекли между продлениями
+    pub fn refresh_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    fn key(&self, user_id: UserId) -> String {
+        self.config.key(&format!("{PRESENCE_KEY_PREFIX}{user_id}"))
+    }
+
+    fn now_ms() -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+
+    fn ttl_ms(&self) -> i64 {
+        self.ttl.as_millis() as i64
+    }
+
+    /// Отмечает, что у пользователя открылся первый сокет на этом экземпляре
+    ///
+    /// Возвращает true, если до этого пользователя не было в сети ни на одном экземпляре
+    pub async fn set_online(&self, user_id: UserId) -> RedisResult<bool> {
+        let now = Self::now_ms();
+        let others: i64 = Script::new(ONLINE_SCRIPT)
+            .key(self.key(user_id))
+            .arg(&self.instance_id)
+            .arg(now)
+            .arg(now + self.ttl_ms())
+            .arg(self.ttl_ms())
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(others == 0)
+    }
+
+    /// Отмечает, что закрылся последний сокет пользователя на этом экземпляре
+    ///
+    /// Возвращает true, если теперь пользователя нет в сети ни на одном экземпляре
+    pub async fn set_offline(&self, user_id: UserId) -> RedisResult<bool> {
+        let remaining: i64 = Script::new(OFFLINE_SCRIPT)
+            .key(self.key(user_id))
+            .arg(&self.instance_id)
+            .arg(Self::now_ms())
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(remaining == 0)
+    }
+
+    /// Продлевает отметки пользователей, у которых есть сокеты на этом экземпляре
+    pub async fn refresh(&self, users: &[UserId]) -> RedisResult<()> {
+        if users.is_empty() {
+            return Ok(());
+        }
+        let expires_at = Self::now_ms() + self.ttl_ms();
+        let mut pipe = redis::pipe();
+        for &user_id in users {
+            let key = self.key(user_id);
+            pipe.zadd(&key, &self.instance_id, expires_at)
+                .ignore()
+                .pexpire(&key, self.ttl_ms() as usize)
+                .ignore();
+        }
+        pipe.query_async(&mut self.connection.clone()).await
+    }
+
+    /// Те из users, кто сейчас в сети, в том же порядке
+    pub async fn online_users(&self, users: &[UserId]) -> RedisResult<Vec<UserId>> {
+        if users.is_empty() {
+            return Ok(vec![]);
+        }
+        let now = Self::now_ms();
+        let mut pipe = redis::pipe();
+        for &user_id in users {
+            pipe.zcount(self.key(user_id), format!("({now}"), "+inf");
+        }
+        let counts: Vec<i64> = pipe.query_async(&mut self.connection.clone()).await?;
+        Ok(users
+            .iter()
+            .zip(counts)
+            .filter(|&(_, count)| count > 0)
+            .map(|(&user_id, _)| user_id)
+            .collect())
+    }
+}
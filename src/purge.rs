@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+
+use log::{info, warn};
+
+use crate::{
+    config::PurgeConfig,
+    database::{DBResult, Database},
+    ids::{ChatId, UserId},
+    metrics,
+};
+
+// Чистка брошенных чатов
+//
+// Чат удаляется, когда из него выходит последний участник, но после частичных сбоев
+// в базе остаются чаты без участников или с участниками, которых уже нет. Такие чаты
+// никто не может открыть, поэтому периодическая задача находит их и удаляет вместе
+// с историей.
+//
+// Совсем новые чаты не трогаем: чат создается несколькими запросами, и посреди создания
+// у него может еще не быть участников.
+
+/// Сколько чатов просмотрено и удалено за один проход
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PurgeReport {
+    pub scanned: usize,
+    /// Чаты без участников
+    pub empty: usize,
+    /// Чаты, все участники которых не существуют
+    pub orphaned: usize,
+}
+
+/// Находит и удаляет брошенные чаты старше config.min_age_secs
+///
+/// Ошибка удаления одного чата не останавливает проход, чат удалится в следующий раз
+pub async fn purge_abandoned_chats<D: Database + ?Sized>(
+    db: &D,
+    config: &PurgeConfig,
+) -> DBResult<PurgeReport> {
+    let users: HashSet<UserId> = db.get_user_list().await?.into_iter().collect();
+    let chats = db.get_chat_list().await?;
+    let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+    let min_age = chrono::Duration::seconds(config.min_age_secs as i64);
+
+    let mut report = PurgeReport {
+        scanned: chats.len(),
+        ..Default::default()
+    };
+    for chat in chats {
+        // Возраст чата без даты создания неизвестен, такой чат может быть и новым
+        match chat.creation_date {
+            Some(created) if now - created >= min_age => {}
+            _ => continue,
+        }
+        let reason = if chat.users.is_empty() {
+            "empty"
+        } else if chat
+            .users
+            .iter()
+            .all(|&user| !users.contains(&UserId(user)))
+        {
+            "orphaned"
+        } else {
+            continue;
+        };
+        if let Err(e) = db.delete_chat(ChatId(chat.id)).await {
+            warn!("Cannot purge {reason} chat {}: {e}", chat.id);
+            continue;
+        }
+        info!("Purged {reason} chat {}", chat.id);
+        metrics::PURGED_CHATS.with_label_values(&[reason]).inc();
+        match reason {
+            "empty" => report.empty += 1,
+            _ => report.orphaned += 1,
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,117 @@
+use std::error::Error;
+
+use crate::{
+    config::{AuthLockout, RedisConfig},
+    redis_topology::{RedisConnection, RedisConnector},
+};
+use async_trait::async_trait;
+use redis::{AsyncCommands, RedisResult, Script};
+
+// Счетчики частоты запросов в Redis
+//
+// Счетчики общие для всех экземпляров сервиса, так что ограничение работает и при
+// нескольких репликах за балансировщиком. Каждый счетчик живет одно окно и сбрасывается
+// сам, когда истекает его время жизни.
+//
+// Обработчики, сокеты и проверка блокировок видят счетчики только через RateLimit, так что
+// встраивающее чат приложение может подставить в ChatApp свою реализацию.
+
+const COUNTER_KEY_PREFIX: &str = "rate:";
+const LOCKOUT_KEY_PREFIX: &str = "lockout:";
+
+/// Увеличить счетчик и выставить время жизни, если окно только началось
+const HIT_SCRIPT: &str = r#"
+local count = redis.call("INCR", KEYS[1])
+if count == 1 then
+    redis.call("EXPIRE", KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+/// Счетчики частоты запросов и блокировки
+#[async_trait(?Send)]
+pub trait RateLimit: Send + Sync {
+    /// Увеличивает счетчик key в окне window_secs и возвращает его новое значение
+    async fn hit(&self, key: &str, window_secs: u64) -> RedisResult<u64>;
+
+    /// Сколько секунд еще действует блокировка subject, если она есть
+    async fn lockout_remaining(&self, subject: &str) -> RedisResult<Option<u64>>;
+
+    /// Блокирует subject на lockout_secs секунд
+    async fn lock_out(&self, subject: &str, lockout_secs: u64) -> RedisResult<()>;
+
+    /// Учитывает неудачную попытку авторизации subject
+    ///
+    /// Возвращает true, если попыток стало слишком много и subject заблокирован
+    async fn register_auth_failure(
+        &self,
+        subject: &str,
+        limits: &AuthLockout,
+    ) -> RedisResult<bool> {
+        let failures = self
+            .hit(&format!("auth_failures:{subject}"), limits.window_secs)
+            .await?;
+        if failures >= limits.max_failures {
+            self.lock_out(subject, limits.lockout_secs).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Счетчики в Redis, общие для всех экземпляров
+#[derive(Clone)]
+pub struct RateLimiter {
+    connection: RedisConnection,
+    config: RedisConfig,
+}
+
+impl RateLimiter {
+    pub async fn new(host: &str, port: u16) -> Result<Self, Box<dyn Error>> {
+        Self::connect(&RedisConfig {
+            host: host.into(),
+            port,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn connect(config: &RedisConfig) -> Result<Self, Box<dyn Error>> {
+        let connection = RedisConnector::new(config).connect().await?;
+        Ok(Self {
+            connection,
+            config: config.clone(),
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl RateLimit for RateLimiter {
+    async fn hit(&self, key: &str, window_secs: u64) -> RedisResult<u64> {
+        Script::new(HIT_SCRIPT)
+            .key(self.config.key(&format!("{COUNTER_KEY_PREFIX}{key}")))
+            .arg(window_secs)
+            .invoke_async(&mut self.connection.clone())
+            .await
+    }
+
+    async fn lockout_remaining(&self, subject: &str) -> RedisResult<Option<u64>> {
+        let ttl: i64 = self
+            .connection
+            .clone()
+            .ttl(self.config.key(&format!("{LOCKOUT_KEY_PREFIX}{subject}")))
+            .await?;
+        Ok((ttl > 0).then_some(ttl as u64))
+    }
+
+    async fn lock_out(&self, subject: &str, lockout_secs: u64) -> RedisResult<()> {
+        self.connection
+            .clone()
+            .set_ex(
+                self.config.key(&format!("{LOCKOUT_KEY_PREFIX}{subject}")),
+                1,
+                lockout_secs as usize,
+            )
+            .await
+    }
+}
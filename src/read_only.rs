@@ -0,0 +1,81 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{config::ReadOnlyConfig, metrics};
+
+// Режим только для чтения
+//
+// Если Scylla перестала принимать записи, то сервис не падает, а продолжает отдавать
+// историю и информацию о чатах. Запросы, которые что-то меняют, получают ошибку read_only,
+// а вебсокеты остаются подключенными и получают через брокер сообщения, которые отправили
+// через здоровые экземпляры сервиса. Режим включается в конфигурации или сам, после
+// нескольких неудачных записей сообщений подряд.
+
+/// Код ошибки, которую получают запросы на запись в режиме только для чтения
+pub const READ_ONLY_ERROR: &str = "read_only";
+
+/// Состояние записей в базу на этом экземпляре сервиса
+pub static WRITES: LazyLock<WriteHealth> = LazyLock::new(WriteHealth::default);
+
+#[derive(Default)]
+pub struct WriteHealth {
+    state: Mutex<WriteState>,
+}
+
+#[derive(Default)]
+struct WriteState {
+    /// Сколько записей подряд не удалось
+    failures: u32,
+    /// Когда режим включился сам
+    tripped_at: Option<Instant>,
+}
+
+impl WriteHealth {
+    /// Учитывает результат записи сообщения в базу
+    pub fn record(&self, succeeded: bool, config: &ReadOnlyConfig) {
+        self.record_at(succeeded, config, Instant::now())
+    }
+
+    /// То же, что record, если сейчас момент now
+    pub fn record_at(&self, succeeded: bool, config: &ReadOnlyConfig, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if succeeded {
+            if state.tripped_at.take().is_some() {
+                info!("Writes recovered, leaving read-only mode");
+            }
+            state.failures = 0;
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= config.failure_threshold.max(1) {
+            if state.tripped_at.is_none() {
+                warn!(
+                    "{} writes failed in a row, switching to read-only mode",
+                    state.failures
+                );
+                metrics::READ_ONLY_TRIPS.inc();
+            }
+            state.tripped_at = Some(now);
+        }
+    }
+
+    /// Нужно ли сейчас отказывать в записи
+    pub fn is_read_only(&self, config: &ReadOnlyConfig) -> bool {
+        self.is_read_only_at(config, Instant::now())
+    }
+
+    /// То же, что is_read_only, если сейчас момент now
+    pub fn is_read_only_at(&self, config: &ReadOnlyConfig, now: Instant) -> bool {
+        if config.enabled {
+            return true;
+        }
+        let state = self.state.lock().unwrap();
+        state.tripped_at.is_some_and(|tripped_at| {
+            now.saturating_duration_since(tripped_at) < Duration::from_secs(config.cooldown_secs)
+        })
+    }
+}
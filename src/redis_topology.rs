@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError},
+};
+
+use log::{info, warn};
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection, PubSub},
+    sentinel::Sentinel,
+    Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+};
+use tokio::sync::Mutex;
+
+use crate::config::{RedisConfig, RedisTopology};
+
+// Подключения к Redis в разных топологиях
+//
+// 1) standalone - один узел по host и port
+// 2) sentinel - адрес главного узла спрашивается у Sentinel при каждом подключении, так что
+//    после переключения новое подключение попадает уже на новый главный узел
+// 3) cluster - команда с ключом уходит на узел, который отвечает за слот ключа. Слоты
+//    узнаются из перенаправлений: после MOVED слот запоминается за новым узлом, а после ASK
+//    команда один раз выполняется на указанном узле. Pub/sub в кластере общий для всех
+//    узлов, поэтому подписка держится на любом доступном узле
+//
+// RedisConnection сама переподключается, если подключение оборвалось или узел перестал
+// быть главным, так что ее можно хранить долго, как и MultiplexedConnection. Команду,
+// которую узел отверг (перенаправление, READONLY, MASTERDOWN), она повторяет всегда,
+// а после обрыва - только команду на чтение: записывающая команда могла выполниться
+// до обрыва, и повтор, например, INCR или XADD сделал бы это дважды. Конвейеры без
+// транзакции в кластере выполняются по одной команде, потому что их ключи могут лежать
+// на разных узлах.
+
+/// Сколько раз команда переотправляется после перенаправлений и обрывов
+const MAX_ATTEMPTS: usize = 5;
+
+/// Число слотов кластера Redis
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// Адрес единственного узла в топологиях без кластера
+const PRIMARY: &str = "";
+
+/// Слот кластера, за которым лежит ключ
+///
+/// Если в ключе есть непустой хеш-тег {...}, слот считается только по нему, чтобы
+/// связанные ключи попадали на один узел
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let tag = &key[open + 1..];
+        let close = tag.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &tag[..close])
+    });
+    crc16(tagged.unwrap_or(key)) % CLUSTER_SLOTS
+}
+
+/// CRC16-CCITT (XMODEM), которым Redis Cluster раскладывает ключи по слотам
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Ключ, по которому команда направляется в кластере, если он у нее есть
+fn command_key(cmd: &Cmd) -> Option<&[u8]> {
+    let mut args = cmd.args_iter().filter_map(|arg| match arg {
+        Arg::Simple(arg) => Some(arg),
+        Arg::Cursor => None,
+    });
+    let name = args.next()?.to_ascii_uppercase();
+    match name.as_slice() {
+        // EVAL скрипт число_ключей ключ...
+        b"EVAL" | b"EVALSHA" => {
+            let keys: usize = std::str::from_utf8(args.nth(1)?).ok()?.parse().ok()?;
+            if keys == 0 {
+                return None;
+            }
+            args.next()
+        }
+        // Каналы pub/sub общие для всего кластера
+        b"PUBLISH" | b"SCRIPT" | b"PING" | b"INFO" | b"ASKING" | b"CLUSTER" => None,
+        _ => args.next(),
+    }
+}
+
+/// После такой ошибки подключение к узлу надо открыть заново
+fn is_stale_connection(error: &RedisError) -> bool {
+    error.is_io_error() || matches!(error.kind(), ErrorKind::ReadOnly | ErrorKind::MasterDown)
+}
+
+/// Команды, которые ничего не меняют, их можно повторить после обрыва
+const READ_ONLY_COMMANDS: &[&[u8]] = &[
+    b"EXISTS",
+    b"GET",
+    b"HEXISTS",
+    b"HGET",
+    b"HGETALL",
+    b"HLEN",
+    b"HMGET",
+    b"INFO",
+    b"LLEN",
+    b"LRANGE",
+    b"MGET",
+    b"PING",
+    b"PTTL",
+    b"SCARD",
+    b"SISMEMBER",
+    b"SMEMBERS",
+    b"TTL",
+    b"TYPE",
+    b"XLEN",
+    b"XRANGE",
+    b"XREVRANGE",
+    b"ZCARD",
+    b"ZRANGE",
+    b"ZRANGEBYSCORE",
+    b"ZSCORE",
+];
+
+/// Можно ли повторить команду, если подключение оборвалось, пока она выполнялась
+fn is_retryable(cmd: &Cmd) -> bool {
+    cmd.args_iter()
+        .next()
+        .and_then(|arg| match arg {
+            Arg::Simple(name) => Some(name.to_ascii_uppercase()),
+            Arg::Cursor => None,
+        })
+        .is_some_and(|name| READ_ONLY_COMMANDS.contains(&name.as_slice()))
+}
+
+/// Можно ли отправить команду еще раз после ошибки error
+///
+/// Отказ узла значит, что команда не выполнялась, а после обрыва это неизвестно
+fn can_resend(error: &RedisError, retryable: bool) -> bool {
+    !error.is_io_error() || retryable
+}
+
+/// Открывает подключения к Redis в топологии из конфигурации
+#[derive(Clone)]
+pub struct RedisConnector {
+    config: RedisConfig,
+}
+
+impl RedisConnector {
+    pub fn new(config: &RedisConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Клиент узла, с которым надо работать сейчас: единственного, главного по мнению
+    /// Sentinel или первого доступного узла кластера
+    async fn client(&self) -> RedisResult<redis::Client> {
+        match &self.config.topology {
+            RedisTopology::Standalone => redis::Client::open(self.config.url()),
+            RedisTopology::Sentinel {
+                master_name,
+                sentinels,
+            } => {
+                let urls: Vec<_> = sentinels.iter().map(|node| node_url(node)).collect();
+                Sentinel::build(urls)?
+                    .async_master_for(master_name, None)
+                    .await
+            }
+            RedisTopology::Cluster { nodes } => {
+                let mut last_error = None;
+                for node in nodes {
+                    let client = redis::Client::open(node_url(node))?;
+                    match client.get_multiplexed_tokio_connection().await {
+                        Ok(_) => return Ok(client),
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                Err(last_error.unwrap_or_else(|| {
+                    (ErrorKind::InvalidClientConfig, "No redis cluster nodes").into()
+                }))
+            }
+        }
+    }
+
+    /// Долгоживущее подключение для команд
+    pub async fn connect(&self) -> RedisResult<RedisConnection> {
+        let connection = RedisConnection {
+            shared: Arc::new(Shared {
+                connector: self.clone(),
+                nodes: Mutex::new(HashMap::new()),
+                slots: std::sync::Mutex::new(HashMap::new()),
+            }),
+            last_node: None,
+        };
+        // Сразу проверяем, что Redis доступен
+        connection.shared.node(None).await?;
+        Ok(connection)
+    }
+
+    /// Отдельное подключение для подписки на каналы
+    pub async fn pubsub(&self) -> RedisResult<PubSub> {
+        let connection = self.client().await?.get_async_connection().await?;
+        Ok(connection.into_pubsub())
+    }
+
+    fn is_cluster(&self) -> bool {
+        matches!(self.config.topology, RedisTopology::Cluster { .. })
+    }
+}
+
+fn node_url(node: &str) -> String {
+    format!("redis://{node}")
+}
+
+struct Shared {
+    connector: RedisConnector,
+    /// Подключения к узлам по адресам host:port, без кластера узел один - PRIMARY
+    nodes: Mutex<HashMap<String, MultiplexedConnection>>,
+    /// Какой узел кластера отвечает за слот, по последним перенаправлениям
+    slots: std::sync::Mutex<HashMap<u16, String>>,
+}
+
+impl Shared {
+    /// Подключение к узлу address, а без адреса - к любому узлу
+    ///
+    /// Новое подключение открывается без блокировки nodes: пока один узел не отвечает,
+    /// команды к уже подключенным узлам не ждут
+    async fn node(&self, address: Option<&str>) -> RedisResult<(String, MultiplexedConnection)> {
+        let address = if self.connector.is_cluster() {
+            address.map(str::to_string)
+        } else {
+            Some(PRIMARY.to_string())
+        };
+        {
+            let nodes = self.nodes.lock().await;
+            let known = match &address {
+                Some(address) => nodes.get_key_value(address),
+                None => nodes.iter().next(),
+            };
+            if let Some((address, connection)) = known {
+                return Ok((address.clone(), connection.clone()));
+            }
+        }
+        let (address, client) = match address {
+            Some(address) if self.connector.is_cluster() => {
+                info!("Connecting to redis cluster node {address}");
+                let client = redis::Client::open(node_url(&address))?;
+                (address, client)
+            }
+            Some(address) => (address, self.connector.client().await?),
+            None => {
+                let client = self.connector.client().await?;
+                (client.get_connection_info().addr.to_string(), client)
+            }
+        };
+        let connection = client.get_multiplexed_tokio_connection().await?;
+        // Пока мы подключались, к узлу могла подключиться другая команда
+        let connection = self
+            .nodes
+            .lock()
+            .await
+            .entry(address.clone())
+            .or_insert(connection)
+            .clone();
+        Ok((address, connection))
+    }
+
+    /// Забывает подключение к узлу и слоты, которые за ним числились
+    async fn forget(&self, address: &str) {
+        self.nodes.lock().await.remove(address);
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, node| node != address);
+    }
+
+    fn slot_node(&self, slot: u16) -> Option<String> {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&slot)
+            .cloned()
+    }
+
+    fn remember_slot(&self, slot: u16, address: &str) {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(slot, address.to_string());
+    }
+}
+
+/// Подключение к Redis любой топологии, которое переживает обрывы и перенаправления
+#[derive(Clone)]
+pub struct RedisConnection {
+    shared: Arc<Shared>,
+    /// Узел последней команды этой копии. На него уходят команды без ключа, например
+    /// SCRIPT LOAD после того, как узел ключа не нашел скрипт
+    last_node: Option<String>,
+}
+
+impl RedisConnection {
+    /// Узел, на который стоит отправить команду с ключом key
+    fn route(&self, key: Option<&[u8]>) -> Option<String> {
+        key.and_then(|key| self.shared.slot_node(key_slot(key)))
+            .or_else(|| self.last_node.clone())
+    }
+
+    async fn run(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        let retryable = is_retryable(cmd);
+        let mut address = self.route(command_key(cmd));
+        let mut asking = false;
+        let mut last_error = None;
+        for _ in 0..MAX_ATTEMPTS {
+            let (node, mut connection) = match self.shared.node(address.as_deref()).await {
+                Ok(node) => node,
+                Err(e) => {
+                    // Узел мог пропасть из кластера, попробуем любой другой
+                    address = None;
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            if asking {
+                redis::cmd("ASKING")
+                    .query_async::<_, ()>(&mut connection)
+                    .await?;
+                asking = false;
+            }
+            match connection.req_packed_command(cmd).await {
+                Err(e) if matches!(e.kind(), ErrorKind::Moved | ErrorKind::Ask) => {
+                    let Some((target, slot)) = e.redirect_node() else {
+                        return Err(e);
+                    };
+                    if e.kind() == ErrorKind::Moved {
+                        self.shared.remember_slot(slot, target);
+                    } else {
+                        asking = true;
+                    }
+                    address = Some(target.to_string());
+                    last_error = Some(e);
+                }
+                Err(e) if is_stale_connection(&e) => {
+                    warn!("Redis connection is stale, reconnecting: {e}");
+                    self.shared.forget(&node).await;
+                    if !can_resend(&e, retryable) {
+                        return Err(e);
+                    }
+                    address = None;
+                    last_error = Some(e);
+                }
+                result => {
+                    self.last_node = Some(node);
+                    return result;
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| (ErrorKind::IoError, "Redis is unavailable").into()))
+    }
+
+    async fn run_pipeline(
+        &mut self,
+        pipeline: &Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let commands: Vec<&Cmd> = pipeline.cmd_iter().collect();
+        // Конвейер без транзакции можно разбить на команды, а транзакция целиком уходит
+        // на узел первого ключа: ключи транзакции в кластере обязаны быть в одном слоте
+        if self.shared.connector.is_cluster() && offset == 0 && count == commands.len() {
+            let mut values = Vec::with_capacity(count);
+            for cmd in commands {
+                values.push(self.run(cmd).await?);
+            }
+            return Ok(values);
+        }
+        let retryable = commands.iter().all(|cmd| is_retryable(cmd));
+        let key = commands.iter().find_map(|cmd| command_key(cmd));
+        if self.shared.connector.is_cluster() {
+            if let Some(key) = key.filter(|key| self.shared.slot_node(key_slot(key)).is_none()) {
+                // Узнаем узел слота по перенаправлению на безобидную команду
+                self.run(redis::cmd("EXISTS").arg(key)).await?;
+            }
+        }
+        let mut address = self.route(key);
+        let mut last_error = None;
+        for _ in 0..MAX_ATTEMPTS {
+            let (node, mut connection) = self.shared.node(address.as_deref()).await?;
+            match connection
+                .req_packed_commands(pipeline, offset, count)
+                .await
+            {
+                Err(e) if e.kind() == ErrorKind::Moved => {
+                    let Some((target, slot)) = e.redirect_node() else {
+                        return Err(e);
+                    };
+                    self.shared.remember_slot(slot, target);
+                    address = Some(target.to_string());
+                    last_error = Some(e);
+                }
+                Err(e) if is_stale_connection(&e) => {
+                    warn!("Redis connection is stale, reconnecting: {e}");
+                    self.shared.forget(&node).await;
+                    if !can_resend(&e, retryable) {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                }
+                result => {
+                    self.last_node = Some(node);
+                    return result;
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| (ErrorKind::IoError, "Redis is unavailable").into()))
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(self.run(cmd))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(self.run_pipeline(pipeline, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
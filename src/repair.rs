@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    database::{DBResult, Database},
+    ids::{ChatId, UserId},
+};
+
+// Починка связей между пользователями и чатами
+//
+// Участники чата хранятся дважды: в chats.users и в users.chats, и пишутся эти списки
+// разными запросами без транзакции. Если сервис упал между запросами, списки расходятся.
+// Проверка читает обе таблицы целиком, находит расхождения и, если попросили, чинит их.
+//
+// Источником правды считается chats.users: и добавление в чат, и выход из него сначала
+// меняют список участников чата, а потом список чатов пользователя. Поэтому расхождение
+// означает, что вторая запись не дошла, и починка доделывает ее.
+
+/// Расхождение между списком участников чата и списками чатов пользователей
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Inconsistency {
+    /// Пользователь есть среди участников чата, но чата нет в его списке
+    MissingUserChat { user_id: i64, chat_id: Uuid },
+    /// Среди участников чата есть несуществующий пользователь
+    UnknownMember { chat_id: Uuid, user_id: i64 },
+    /// Чат есть в списке пользователя, но пользователя нет среди участников
+    /// или самого чата уже нет
+    StaleUserChat { user_id: i64, chat_id: Uuid },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::MissingUserChat { user_id, chat_id } => write!(
+                f,
+                "User {user_id} is a member of chat {chat_id}, but the chat is missing from the user's list"
+            ),
+            Inconsistency::UnknownMember { chat_id, user_id } => {
+                write!(f, "Chat {chat_id} lists user {user_id}, who does not exist")
+            }
+            Inconsistency::StaleUserChat { user_id, chat_id } => write!(
+                f,
+                "User {user_id} lists chat {chat_id}, but is not its member"
+            ),
+        }
+    }
+}
+
+/// Что нашла и что починила проверка
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepairReport {
+    pub inconsistencies: Vec<Inconsistency>,
+    pub fixed: usize,
+}
+
+/// Сравнивает chats.users с users.chats
+pub async fn find_inconsistencies<D: Database + ?Sized>(db: &D) -> DBResult<Vec<Inconsistency>> {
+    let users: HashMap<i64, HashSet<Uuid>> = db
+        .get_users_with_chats()
+        .await?
+        .into_iter()
+        .map(|user| (user.id, user.chats.into_iter().collect()))
+        .collect();
+    let chats: HashMap<Uuid, HashSet<i64>> = db
+        .get_chat_list()
+        .await?
+        .into_iter()
+        .map(|chat| (chat.id, chat.users.into_iter().collect()))
+        .collect();
+
+    let mut problems = vec![];
+    for (&chat_id, members) in &chats {
+        for &user_id in members {
+            match users.get(&user_id) {
+                None => problems.push(Inconsistency::UnknownMember { chat_id, user_id }),
+                Some(user_chats) if !user_chats.contains(&chat_id) => {
+                    problems.push(Inconsistency::MissingUserChat { user_id, chat_id })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    for (&user_id, user_chats) in &users {
+        for &chat_id in user_chats {
+            if !chats
+                .get(&chat_id)
+                .is_some_and(|members| members.contains(&user_id))
+            {
+                problems.push(Inconsistency::StaleUserChat { user_id, chat_id });
+            }
+        }
+    }
+    problems.sort();
+    Ok(problems)
+}
+
+/// Находит расхождения и, если fix = true, чинит их
+///
+/// Ошибка починки одного расхождения не останавливает остальные
+pub async fn repair<D: Database + ?Sized>(db: &D, fix: bool) -> DBResult<RepairReport> {
+    let inconsistencies = find_inconsistencies(db).await?;
+    let mut fixed = 0;
+    for problem in &inconsistencies {
+        warn!("Inconsistency: {problem}");
+        if !fix {
+            continue;
+        }
+        let result = match *problem {
+            Inconsistency::MissingUserChat { user_id, chat_id } => {
+                db.add_chat_to_user(UserId(user_id), ChatId(chat_id)).await
+            }
+            Inconsistency::UnknownMember { chat_id, user_id } => {
+                db.drop_chat_member(ChatId(chat_id), UserId(user_id)).await
+            }
+            Inconsistency::StaleUserChat { user_id, chat_id } => {
+                db.remove_chat_from_user(UserId(user_id), ChatId(chat_id))
+                    .await
+            }
+        };
+        match result {
+            Ok(()) => fixed += 1,
+            Err(e) => warn!("Cannot fix inconsistency: {e}"),
+        }
+    }
+    info!(
+        "Consistency check found {} problems, fixed {fixed}",
+        inconsistencies.len()
+    );
+    Ok(RepairReport {
+        inconsistencies,
+        fixed,
+    })
+}
@@ -0,0 +1,26 @@
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+// Секреты чатов (коды приглашений, токены вебхуков)
+//
+// В базе хранится только sha256 от секрета, сам секрет отдается пользователю один раз
+// при генерации. Секреты случайные и длинные, так что медленный хэш тут не нужен.
+// Сравнение хэшей делается за постоянное время, чтобы не подсказывать перебором.
+
+/// Генерирует новый случайный секрет из двух UUIDv4 (244 случайных бита) в hex-представлении
+pub fn generate_secret() -> String {
+    let mut bytes = Vec::with_capacity(32);
+    bytes.extend_from_slice(Uuid::new_v4().as_bytes());
+    bytes.extend_from_slice(Uuid::new_v4().as_bytes());
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn hash_secret(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+/// Проверяет секрет против сохраненного хэша за постоянное время
+pub fn verify_secret(secret: &str, stored_hash: &[u8]) -> bool {
+    hash_secret(secret).ct_eq(stored_hash).into()
+}
@@ -4,7 +4,7 @@ use scylla::frame::response::result::CqlValue;
 use serde::de::Visitor;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SerializableDuration {
     pub timestamp: Duration,
 }
@@ -60,7 +60,7 @@ impl<'de> Visitor<'de> for DurationVisitor {
     where
         E: serde::de::Error,
     {
-        Ok(Duration::milliseconds(i64::from(v)).into())
+        Ok(Duration::milliseconds(v).into())
     }
 
     fn visit_u8<E>(self, v: u8) -> Result<Self::Value, E>
@@ -0,0 +1,537 @@
+use log::warn;
+use uuid::Uuid;
+
+use crate::{
+    actors::websocket_actor::{ChatMessage, NewChatMessage},
+    calls::CallEvent,
+    clock,
+    config::{ConfigHandle, MessageRules, NameRules},
+    database::{
+        data::{ChatInfo, ChatType, UserInfo},
+        name_key, ContactLimitError, DBError, DBResult, Database, NameTakenError, StringError,
+    },
+    ids::{ChatId, UserId},
+    moderation::ModerationFilter,
+    serializable_duration::SerializableDuration,
+    text,
+    validation::{self, FieldError},
+};
+
+// Правила чатов и пользователей
+//
+// Здесь собраны правила, которые должны одинаково работать в REST API и в вебсокете:
+// кто может писать в чат, как из кадра клиента получается сообщение, как не сохранить
+// повтор, как отдавать информацию о чате. Сервисы не зависят от actix и работают с любой
+// реализацией Database, так что их можно проверять на MockDatabase. Акторы и обработчики
+// только доставляют запросы до сервисов и превращают ошибки в ответы.
+
+/// Ошибка сервиса: запрос не прошел проверку или не удался запрос к базе
+#[derive(Debug)]
+pub enum ServiceError {
+    Invalid(Vec<FieldError>),
+    Database(DBError),
+}
+
+impl From<DBError> for ServiceError {
+    fn from(e: DBError) -> Self {
+        ServiceError::Database(e)
+    }
+}
+
+impl From<FieldError> for ServiceError {
+    fn from(e: FieldError) -> Self {
+        ServiceError::Invalid(vec![e])
+    }
+}
+
+/// Сколько приглашенных или контактов искать одним запросом к базе
+const INVITE_LOOKUP_BATCH: usize = 100;
+
+/// Сколько вариантов занятого имени проверять и сколько свободных предлагать
+const NAME_CANDIDATES: usize = 10;
+const NAME_SUGGESTIONS: usize = 3;
+
+/// Почему приглашенный не попал в созданный чат
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    NotRegistered,
+}
+
+/// Приглашенный, которого не добавили в созданный чат
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SkippedUser {
+    pub user_id: i64,
+    pub reason: SkipReason,
+}
+
+/// Созданный групповой чат и приглашенные, которых в него не добавили
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct GroupChatCreation {
+    pub chat: ChatInfo,
+    pub skipped: Vec<SkippedUser>,
+}
+
+/// Сохраненное сообщение
+pub struct InsertedMessage {
+    pub message: ChatMessage,
+    /// Клиент повторил уже сохраненное сообщение: в message id и дата первого,
+    /// рассылать его еще раз не нужно
+    pub duplicate: bool,
+}
+
+/// Сколько существует аккаунт, созданный в created (от начала эпохи)
+pub fn account_age(created: chrono::Duration) -> chrono::Duration {
+    chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH - created
+}
+
+/// Делает из кадра клиента сообщение чата с новым id и временем по часам сервиса
+///
+/// Текст и число вложений проверяются по rules, пустой текст разрешен только у сообщения
+/// с вложениями. Непустой текст затем проходит фильтр moderation
+pub fn compose_message(
+    sender_id: UserId,
+    message: NewChatMessage,
+    rules: &MessageRules,
+    moderation: &dyn ModerationFilter,
+) -> Result<ChatMessage, ServiceError> {
+    let mut errors = vec![];
+    let msg_text = if message.msg_text.trim().is_empty() && !message.attachments.is_empty() {
+        String::new()
+    } else {
+        validation::validate_message_text("msg_text", &message.msg_text, rules).unwrap_or_else(
+            |e| {
+                errors.push(e);
+                String::new()
+            },
+        )
+    };
+    if !msg_text.is_empty() {
+        if let Err(e) = moderation.check("msg_text", &msg_text) {
+            errors.push(e);
+        }
+    }
+    if let Err(e) = validation::validate_attachments("attachments", &message.attachments, rules) {
+        errors.push(e);
+    }
+    let client_msg_id = message.client_msg_id.as_deref().and_then(|id| {
+        validation::validate_client_msg_id("client_msg_id", id)
+            .map_err(|e| errors.push(e))
+            .ok()
+    });
+    if !errors.is_empty() {
+        return Err(ServiceError::Invalid(errors));
+    }
+    Ok(ChatMessage {
+        chat_id: message.chat_id.get(),
+        message_id: Uuid::new_v4(),
+        sender_id: sender_id.get(),
+        date: clock::CLOCK.now().into(),
+        msg_text,
+        edited_at: None,
+        reply_to: message.reply_to,
+        attachments: message.attachments,
+        forwarded_from: None,
+        mentions: vec![],
+        client_msg_id,
+        delivery_id: None,
+        call: None,
+    })
+}
+
+/// Системное сообщение о звонке от имени звонящего с новым id и временем по часам сервиса
+pub fn call_message(sender_id: UserId, chat_id: ChatId, call: CallEvent) -> ChatMessage {
+    ChatMessage {
+        chat_id: chat_id.get(),
+        message_id: Uuid::new_v4(),
+        sender_id: sender_id.get(),
+        date: clock::CLOCK.now().into(),
+        msg_text: String::new(),
+        edited_at: None,
+        reply_to: None,
+        attachments: vec![],
+        forwarded_from: None,
+        mentions: vec![],
+        client_msg_id: None,
+        delivery_id: None,
+        call: Some(call),
+    }
+}
+
+/// Информация о чате в том виде, в котором ее получает клиент: без списка участников
+/// для больших чатов и с режимом доставки по умолчанию, если у чата он не задан
+pub fn chat_for_client(mut chat: ChatInfo, config: &ConfigHandle) -> ChatInfo {
+    if chat.users.len() > config.current().max_inline_members {
+        chat.users.clear();
+    }
+    chat.delivery_mode
+        .get_or_insert(config.static_config().delivery.default_mode);
+    chat
+}
+
+/// Чат в подробном списке чатов пользователя
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChatSummary {
+    pub chat_id: ChatId,
+    pub name: String,
+    pub chat_type: ChatType,
+    pub member_count: usize,
+    /// Превью последнего сообщения, None - в чате еще нет сообщений
+    pub last_message_preview: Option<String>,
+    /// Когда в чат последний раз писали
+    pub last_activity: Option<SerializableDuration>,
+    pub unread_count: i64,
+    /// Уведомления чата сейчас отключены: клиент показывает счетчик приглушенным
+    #[serde(default)]
+    pub muted: bool,
+}
+
+/// Отрезок истории чата, которым поделились по ссылке
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SharedHistory {
+    pub chat_id: Uuid,
+    pub name: String,
+    /// Сообщения отрезка от старых к новым
+    pub messages: Vec<ChatMessage>,
+}
+
+pub struct ChatService<'a, D: Database + ?Sized> {
+    db: &'a D,
+}
+
+impl<'a, D: Database + ?Sized> ChatService<'a, D> {
+    pub fn new(db: &'a D) -> Self {
+        Self { db }
+    }
+
+    /// Проверяет, что пользователь состоит в чате
+    pub async fn ensure_member(&self, user_id: UserId, chat_id: ChatId) -> DBResult<()> {
+        if self.db.get_user_chats(user_id).await?.contains(&chat_id) {
+            return Ok(());
+        }
+        Err(DBError::LogicError(Box::new(StringError::new(
+            "not_a_member",
+        ))))
+    }
+
+    /// Сохраняет сообщение, найдя в нем упоминания
+    ///
+    /// Повтор сообщения с тем же client_msg_id не сохраняется еще раз
+    pub async fn send_message(&self, mut message: ChatMessage) -> DBResult<InsertedMessage> {
+        let (chat_id, sender_id) = (ChatId(message.chat_id), UserId(message.sender_id));
+        let client_msg_id = message.client_msg_id.clone();
+        if let Some(client_msg_id) = &client_msg_id {
+            // Повтор может прийти, пока первое сообщение еще записывается,
+            // тогда клиент получит подтверждение чуть раньше записи
+            if let Some((message_id, date)) = self
+                .db
+                .claim_client_msg_id(
+                    chat_id,
+                    sender_id,
+                    client_msg_id,
+                    message.message_id,
+                    message.date.timestamp,
+                )
+                .await?
+            {
+                message.message_id = message_id;
+                message.date = date.into();
+                return Ok(InsertedMessage {
+                    message,
+                    duplicate: true,
+                });
+            }
+        }
+        message.mentions = self
+            .db
+            .find_mentions(sender_id, chat_id, &message.msg_text)
+            .await?
+            .into_iter()
+            .map(i64::from)
+            .collect();
+        if let Err(e) = self.db.add_new_message_to_chat(message.clone()).await {
+            // Иначе повтор этого сообщения сочли бы дубликатом несохраненного
+            if let Some(client_msg_id) = &client_msg_id {
+                if let Err(e) = self
+                    .db
+                    .release_client_msg_id(chat_id, sender_id, client_msg_id, message.message_id)
+                    .await
+                {
+                    warn!("Cannot release client message id {client_msg_id}: {e}");
+                }
+            }
+            return Err(e);
+        }
+        Ok(InsertedMessage {
+            message,
+            duplicate: false,
+        })
+    }
+
+    /// Создает групповой чат
+    ///
+    /// Обычно чат не создается, если хоть одного приглашенного нет среди пользователей.
+    /// С skip_unregistered такие приглашенные пропускаются: чат создается с остальными,
+    /// а пропущенные возвращаются вместе с чатом
+    pub async fn create_group_chat(
+        &self,
+        creator_id: UserId,
+        invited_users_id: Vec<UserId>,
+        chat_name: String,
+        skip_unregistered: bool,
+    ) -> DBResult<GroupChatCreation> {
+        let mut skipped = vec![];
+        let mut invited = invited_users_id;
+        if skip_unregistered {
+            let mut registered = std::collections::HashSet::new();
+            for batch in invited.chunks(INVITE_LOOKUP_BATCH) {
+                registered.extend(
+                    self.db
+                        .get_users_info(batch.to_vec())
+                        .await?
+                        .into_iter()
+                        .map(|user| user.id),
+                );
+            }
+            invited.retain(|user_id| {
+                if registered.contains(&user_id.get()) {
+                    return true;
+                }
+                let skip = SkippedUser {
+                    user_id: user_id.get(),
+                    reason: SkipReason::NotRegistered,
+                };
+                if !skipped.contains(&skip) {
+                    skipped.push(skip);
+                }
+                false
+            });
+        }
+        let chat = self
+            .db
+            .create_new_chat(creator_id, invited, ChatType::Group, chat_name)
+            .await?;
+        Ok(GroupChatCreation { chat, skipped })
+    }
+
+    /// Чаты пользователя с последним сообщением и числом непрочитанных, сначала те,
+    /// в которые писали позже
+    ///
+    /// Чаты, из которых пользователь вышел, пока собирался список, пропускаются
+    pub async fn chat_summaries(&self, user_id: UserId) -> DBResult<Vec<ChatSummary>> {
+        let unread = self.db.get_unread_counts(user_id).await?;
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        let mut summaries = vec![];
+        for chat_id in self.db.get_user_chats(user_id).await? {
+            let chat = match self.db.get_chat_info(user_id, chat_id).await {
+                Ok(chat) => chat,
+                Err(DBError::LogicError(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            let last_message = match self
+                .db
+                .get_chat_history_before(user_id, chat_id, None, 1)
+                .await
+            {
+                Ok(mut messages) => messages.pop(),
+                Err(DBError::LogicError(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            summaries.push(ChatSummary {
+                chat_id,
+                name: chat.name,
+                chat_type: chat.chat_type,
+                member_count: chat.member_count,
+                last_message_preview: last_message
+                    .as_ref()
+                    .map(|message| text::preview(&message.msg_text, text::PREVIEW_LENGTH)),
+                last_activity: last_message.map(|message| message.date),
+                unread_count: unread.get(&chat_id).copied().unwrap_or(0),
+                muted: chat.notifications.is_muted_at(now),
+            });
+        }
+        summaries.sort_by(|a, b| {
+            let activity =
+                |summary: &ChatSummary| summary.last_activity.as_ref().map(|date| date.timestamp);
+            activity(b).cmp(&activity(a))
+        });
+        Ok(summaries)
+    }
+
+    /// Последние limit сообщений чата с датами от from до to включительно, от старых к новым
+    ///
+    /// Сообщения читаются от имени created_by, так что если он уже не в чате, то это LogicError
+    pub async fn shared_history(
+        &self,
+        created_by: UserId,
+        chat_id: ChatId,
+        from: chrono::Duration,
+        to: chrono::Duration,
+        limit: usize,
+    ) -> DBResult<SharedHistory> {
+        let chat = self.db.get_chat_info(created_by, chat_id).await?;
+        let mut messages = self
+            .db
+            .get_chat_history_before(
+                created_by,
+                chat_id,
+                Some(to + chrono::Duration::milliseconds(1)),
+                limit,
+            )
+            .await?;
+        messages.retain(|message| message.date.timestamp >= from);
+        messages.sort_by_key(|message| message.date.timestamp);
+        Ok(SharedHistory {
+            chat_id: chat_id.get(),
+            name: chat.name,
+            messages,
+        })
+    }
+}
+
+/// Пользователь после авторизации
+pub struct Authorization {
+    pub user: UserInfo,
+    /// Чаты по умолчанию, в которые только что добавили нового пользователя
+    pub joined: Vec<ChatId>,
+}
+
+pub struct UserService<'a, D: Database + ?Sized> {
+    db: &'a D,
+}
+
+impl<'a, D: Database + ?Sized> UserService<'a, D> {
+    pub fn new(db: &'a D) -> Self {
+        Self { db }
+    }
+
+    /// Возвращает пользователя, а если его еще нет - создает с именем user_name
+    ///
+    /// Имя проверяется по name_rules только у нового пользователя
+    pub async fn authorize(
+        &self,
+        user_id: UserId,
+        user_name: &str,
+        name_rules: &NameRules,
+        default_chats: &[ChatId],
+    ) -> Result<Authorization, ServiceError> {
+        match self.db.get_user_info(user_id).await {
+            Ok(user) => Ok(Authorization {
+                user,
+                joined: vec![],
+            }),
+            Err(DBError::LogicError(_)) => {
+                let user_name = validation::validate_name("user_name", user_name, name_rules)?;
+                let result = self.db.create_new_user(user_id, user_name.clone()).await;
+                let mut user = self.suggest_if_taken(result, user_name, name_rules).await?;
+                let joined = self.join_default_chats(user_id, default_chats).await?;
+                user.chats
+                    .extend(joined.iter().map(|chat_id| chat_id.get()));
+                Ok(Authorization { user, joined })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Меняет имя пользователя на проверенное по name_rules
+    pub async fn rename(
+        &self,
+        user_id: UserId,
+        new_name: &str,
+        name_rules: &NameRules,
+    ) -> Result<UserInfo, ServiceError> {
+        let new_name = validation::validate_name("new_name", new_name, name_rules)?;
+        let result = self.db.rename_user(user_id, new_name.clone()).await;
+        self.suggest_if_taken(result, new_name, name_rules).await
+    }
+
+    /// Если имя name занято, дополняет ошибку свободными похожими именами
+    async fn suggest_if_taken(
+        &self,
+        result: DBResult<UserInfo>,
+        name: String,
+        name_rules: &NameRules,
+    ) -> Result<UserInfo, ServiceError> {
+        match result {
+            Err(DBError::LogicError(e)) if e.is::<NameTakenError>() => {
+                let suggestions = self.suggest_names(&name, name_rules).await?;
+                Err(DBError::LogicError(Box::new(NameTakenError { name, suggestions })).into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Свободные имена вида {имя}{число} вместо занятого name
+    ///
+    /// Имя укорачивается, чтобы с числом уложиться в name_rules
+    async fn suggest_names(&self, name: &str, name_rules: &NameRules) -> DBResult<Vec<String>> {
+        let candidates: Vec<String> = (2..2 + NAME_CANDIDATES)
+            .filter_map(|n| {
+                let suffix = n.to_string();
+                let base: String = name
+                    .chars()
+                    .take(name_rules.max_length.saturating_sub(suffix.len()))
+                    .collect();
+                validation::validate_name("user_name", &format!("{base}{suffix}"), name_rules).ok()
+            })
+            .collect();
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let taken = self.db.taken_names(candidates.clone()).await?;
+        Ok(candidates
+            .into_iter()
+            .filter(|candidate| !taken.contains(&name_key(candidate)))
+            .take(NAME_SUGGESTIONS)
+            .collect())
+    }
+
+    /// Добавляет пользователя в чаты по умолчанию и возвращает те, в которые добавил
+    ///
+    /// Удаленные и заполненные чаты пропускаются: из-за ошибки в конфигурации
+    /// пользователь не должен остаться без входа
+    async fn join_default_chats(&self, user_id: UserId, chats: &[ChatId]) -> DBResult<Vec<ChatId>> {
+        let mut joined = vec![];
+        for &chat_id in chats {
+            match self.db.join_chat(user_id, chat_id).await {
+                Ok(()) => joined.push(chat_id),
+                Err(DBError::LogicError(e)) => {
+                    warn!("Cannot add user {user_id} to default chat {chat_id}: {e}")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(joined)
+    }
+
+    /// Добавляет contact_id в контакты, если их меньше max_contacts
+    ///
+    /// Повторное добавление уже записанного контакта проходит и при заполненном списке
+    pub async fn add_contact(
+        &self,
+        user_id: UserId,
+        contact_id: UserId,
+        max_contacts: usize,
+    ) -> DBResult<()> {
+        let contacts = self.db.get_contacts(user_id).await?;
+        if contacts.len() >= max_contacts && !contacts.contains(&contact_id) {
+            return Err(DBError::LogicError(Box::new(ContactLimitError {
+                limit: max_contacts,
+            })));
+        }
+        self.db.add_contact(user_id, contact_id).await
+    }
+
+    /// Контакты пользователя с именами, по имени
+    ///
+    /// Удаленные пользователи в список не попадают
+    pub async fn contacts(&self, user_id: UserId) -> DBResult<Vec<UserInfo>> {
+        let contact_ids = self.db.get_contacts(user_id).await?;
+        let mut contacts = vec![];
+        for batch in contact_ids.chunks(INVITE_LOOKUP_BATCH) {
+            contacts.extend(self.db.get_users_info(batch.to_vec()).await?);
+        }
+        contacts.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        Ok(contacts)
+    }
+}
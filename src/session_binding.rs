@@ -0,0 +1,133 @@
+use std::{error::Error, net::IpAddr};
+
+use actix_web::{http::header, HttpRequest};
+use redis::{RedisResult, Script};
+
+use crate::{
+    config::{RedisConfig, SessionBinding},
+    redis_topology::{RedisConnection, RedisConnector},
+    secrets,
+};
+
+// Привязка сессий вебсокета к клиенту
+//
+// Сессия определяется токеном из cookie. При первом подключении запоминается отпечаток
+// клиента (адрес и User-Agent), и следующие подключения с тем же токеном должны приходить
+// с тем же отпечатком. Если отпечаток не совпал, то токен, скорее всего, украден: сессия
+// отзывается, открытые с ней сокеты закрываются, а новые подключения с этим токеном
+// не принимаются, пока привязка не истечет.
+//
+// Токены в Redis не хранятся, ключом служит хеш токена.
+
+const BINDING_KEY_PREFIX: &str = "session:";
+
+/// Значение привязки отозванной сессии
+const REVOKED: &str = "revoked";
+
+/// Запомнить отпечаток, если его еще нет, и сравнить с ним, если есть
+const CHECK_SCRIPT: &str = r#"
+local current = redis.call("GET", KEYS[1])
+if not current then
+    redis.call("SET", KEYS[1], ARGV[1], "EX", ARGV[2])
+    return 0
+end
+if current == ARGV[1] then
+    redis.call("EXPIRE", KEYS[1], ARGV[2])
+    return 1
+end
+if current ~= ARGV[3] then
+    redis.call("SET", KEYS[1], ARGV[3], "EX", ARGV[2])
+end
+return 2
+"#;
+
+/// Результат сверки подключения с привязкой сессии
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingCheck {
+    /// Сессия подключается впервые, привязка создана
+    New,
+    /// Отпечаток совпал
+    Matches,
+    /// Отпечаток не совпал или сессия уже отозвана
+    Mismatch,
+}
+
+/// Идентификатор сессии: hex хеша токена
+pub fn session_id(token: &str) -> String {
+    secrets::hash_secret(token)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Отпечаток клиента из тех данных, которые нужно привязывать
+pub fn fingerprint(
+    client_ip: Option<IpAddr>,
+    user_agent: Option<&str>,
+    rules: &SessionBinding,
+) -> String {
+    let ip = match client_ip {
+        Some(ip) if rules.bind_ip => ip.to_string(),
+        _ => String::new(),
+    };
+    let user_agent = match user_agent {
+        Some(user_agent) if rules.bind_user_agent => user_agent,
+        _ => "",
+    };
+    format!("{ip}|{user_agent}")
+}
+
+/// Отпечаток клиента, который открывает вебсокет
+pub fn request_fingerprint(
+    req: &HttpRequest,
+    client_ip: Option<IpAddr>,
+    rules: &SessionBinding,
+) -> String {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    fingerprint(client_ip, user_agent, rules)
+}
+
+#[derive(Clone)]
+pub struct SessionBinder {
+    connection: RedisConnection,
+    config: RedisConfig,
+}
+
+impl SessionBinder {
+    pub async fn connect(config: &RedisConfig) -> Result<Self, Box<dyn Error>> {
+        let connection = RedisConnector::new(config).connect().await?;
+        Ok(Self {
+            connection,
+            config: config.clone(),
+        })
+    }
+
+    /// Сверяет отпечаток подключения с привязкой сессии session_id
+    ///
+    /// При несовпадении сессия отзывается на ttl_secs секунд
+    pub async fn check(
+        &self,
+        session_id: &str,
+        fingerprint: &str,
+        ttl_secs: u64,
+    ) -> RedisResult<BindingCheck> {
+        let result: i64 = Script::new(CHECK_SCRIPT)
+            .key(
+                self.config
+                    .key(&format!("{BINDING_KEY_PREFIX}{session_id}")),
+            )
+            .arg(fingerprint)
+            .arg(ttl_secs)
+            .arg(REVOKED)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(match result {
+            0 => BindingCheck::New,
+            1 => BindingCheck::Matches,
+            _ => BindingCheck::Mismatch,
+        })
+    }
+}
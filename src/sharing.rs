@@ -0,0 +1,119 @@
+use std::{env, fmt};
+
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::config::ShareConfig;
+
+// Ссылки на историю чата
+//
+// Участник чата может поделиться отрезком истории: сервер выдает ссылку /share/{токен},
+// по которой сообщения отрезка отдаются без авторизации, пока не истечет срок ссылки.
+// Ссылки нигде не хранятся: в токене записаны чат, отрезок, автор и срок, а подпись
+// HMAC-SHA256 ключом из переменной окружения не дает их подделать. Поэтому отдельную ссылку
+// отозвать нельзя, но сообщения читаются от имени автора: вышел он из чата - ссылка
+// перестает работать, а смена ключа отзывает все ссылки сразу.
+
+/// Что открывает ссылка
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareToken {
+    pub chat_id: Uuid,
+    /// Кто поделился, от его имени читаются сообщения
+    pub created_by: i64,
+    /// Первое и последнее сообщение отрезка по дате (миллисекунды от начала эпохи)
+    pub from: i64,
+    pub to: i64,
+    /// Когда ссылка истекает (секунды от начала эпохи)
+    pub expires_at: i64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShareError {
+    /// Ключ подписи не задан, делиться историей нельзя
+    NotConfigured,
+    /// Токен поврежден или подписан не нашим ключом
+    Invalid,
+    Expired,
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareError::NotConfigured => write!(f, "History sharing is not configured"),
+            ShareError::Invalid => write!(f, "Invalid share link"),
+            ShareError::Expired => write!(f, "Share link has expired"),
+        }
+    }
+}
+
+impl std::error::Error for ShareError {}
+
+/// Выпускает и проверяет ссылки
+pub struct ShareLinks {
+    key: Option<Vec<u8>>,
+    pub config: ShareConfig,
+}
+
+impl ShareLinks {
+    pub fn new(key: Option<Vec<u8>>, config: ShareConfig) -> Self {
+        Self { key, config }
+    }
+
+    /// Ключ подписи берется из переменной окружения, а не из файла конфигурации;
+    /// без нее ссылки не выпускаются
+    pub fn from_config(config: &ShareConfig) -> Self {
+        let key = env::var(&config.secret_key_env)
+            .ok()
+            .filter(|key| !key.is_empty());
+        if key.is_none() {
+            info!(
+                "{} is not set, history sharing is disabled",
+                config.secret_key_env
+            );
+        }
+        Self::new(key.map(String::into_bytes), config.clone())
+    }
+
+    /// Срок новой ссылки: запрошенный, но не больше max_ttl_secs и не меньше секунды,
+    /// даже если в конфигурации max_ttl_secs равен нулю
+    pub fn ttl_secs(&self, requested: Option<u64>) -> u64 {
+        requested
+            .unwrap_or(self.config.default_ttl_secs)
+            .clamp(1, self.config.max_ttl_secs.max(1))
+    }
+
+    pub fn sign(&self, token: &ShareToken) -> Result<String, ShareError> {
+        let key = self.key.as_ref().ok_or(ShareError::NotConfigured)?;
+        let payload = hex::encode(serde_json::to_vec(token).expect("Share token is serializable"));
+        let signature = hex::encode(hmac(key, payload.as_bytes()));
+        Ok(format!("{payload}.{signature}"))
+    }
+
+    /// Проверяет подпись и срок ссылки в момент now (секунды от начала эпохи)
+    pub fn verify(&self, token: &str, now: i64) -> Result<ShareToken, ShareError> {
+        let key = self.key.as_ref().ok_or(ShareError::NotConfigured)?;
+        let (payload, signature) = token.split_once('.').ok_or(ShareError::Invalid)?;
+        let signature = hex::decode(signature).map_err(|_| ShareError::Invalid)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        // Сравнение за постоянное время
+        mac.verify_slice(&signature)
+            .map_err(|_| ShareError::Invalid)?;
+        let payload = hex::decode(payload).map_err(|_| ShareError::Invalid)?;
+        let token: ShareToken =
+            serde_json::from_slice(&payload).map_err(|_| ShareError::Invalid)?;
+        if token.expires_at <= now {
+            return Err(ShareError::Expired);
+        }
+        Ok(token)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
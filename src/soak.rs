@@ -0,0 +1,389 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    actors::{
+        broker_actor::{self, BrokerActor, BrokerStats},
+        database_actor::{self, DatabaseActor},
+        websocket_actor::{messages::BrokerMessage, NewChatMessage},
+    },
+    config::MessageRules,
+    database::MockDatabase,
+    ids::{ChatId, UserId},
+    moderation::NoModeration,
+    services,
+};
+
+// Нагрузочный прогон
+//
+// chat soak поднимает сервис прямо в процессе, без Scylla и Redis: база живет в памяти,
+// актор базы и брокер настоящие, а вместо Redis опубликованное сообщение сразу уходит
+// брокеру, как его отдал бы Redis-актор. Постоянные пользователи подключены весь прогон
+// и отправляют rate сообщений в секунду в свои чаты, а рядом все время подключаются и
+// отключаются гости. В конце прогон проверяет, что каждое сохраненное сообщение дошло до
+// каждого постоянного участника чата ровно один раз и что таблицы брокера не выросли
+// из-за гостей.
+//
+// Пользователь u состоит в чате u % chats. База в памяти отвечает только на те запросы,
+// которые делает этот путь сообщения, так что любой новый запрос на нем остановит прогон
+// сразу, а не исказит его результат.
+
+pub const USAGE: &str = "Usage: chat soak --users N --chats M --rate R [--duration SECS]";
+
+/// Как часто прогон отправляет очередную порцию сообщений и подключает нового гостя
+const TICK: Duration = Duration::from_millis(10);
+
+/// Сколько ждать доставки после окончания прогона
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakConfig {
+    pub users: usize,
+    pub chats: usize,
+    /// Сообщений в секунду
+    pub rate: u32,
+    pub duration: Duration,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            users: 100,
+            chats: 10,
+            rate: 100,
+            duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl SoakConfig {
+    /// Разбирает аргументы после chat soak
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {flag}\n{USAGE}"))?;
+            let invalid = |_| format!("Invalid value {value} for {flag}\n{USAGE}");
+            match flag.as_str() {
+                "--users" => config.users = value.parse().map_err(invalid)?,
+                "--chats" => config.chats = value.parse().map_err(invalid)?,
+                "--rate" => config.rate = value.parse().map_err(invalid)?,
+                "--duration" => {
+                    config.duration = Duration::from_secs(value.parse().map_err(invalid)?)
+                }
+                _ => return Err(format!("Unknown option {flag}\n{USAGE}")),
+            }
+        }
+        if config.users == 0 || config.chats == 0 || config.rate == 0 {
+            return Err(format!("Users, chats and rate must be positive\n{USAGE}"));
+        }
+        Ok(config)
+    }
+}
+
+/// Что получилось за прогон
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SoakReport {
+    pub sent: usize,
+    /// Сколько сообщений записано в базу
+    pub stored: usize,
+    /// Сколько сообщений не удалось сохранить
+    pub failed: usize,
+    /// Сколько раз сообщения должны были дойти до постоянных пользователей
+    pub expected_deliveries: usize,
+    pub delivered: usize,
+    pub duplicates: usize,
+    pub guest_connections: usize,
+    /// Таблицы брокера после отключения всех гостей
+    pub broker: BrokerStats,
+    /// Резидентная память процесса до и после прогона, если ее удалось узнать
+    pub memory_before: Option<u64>,
+    pub memory_after: Option<u64>,
+}
+
+impl SoakReport {
+    /// Нарушения, которые нашел прогон; пустой список - прогон прошел
+    pub fn problems(&self, config: &SoakConfig) -> Vec<String> {
+        let mut problems = vec![];
+        if self.failed > 0 || self.stored != self.sent {
+            problems.push(format!(
+                "Sent {} messages, but stored {} and failed to store {}",
+                self.sent, self.stored, self.failed
+            ));
+        }
+        if self.delivered < self.expected_deliveries {
+            problems.push(format!(
+                "Lost {} of {} deliveries",
+                self.expected_deliveries - self.delivered,
+                self.expected_deliveries
+            ));
+        }
+        if self.duplicates > 0 {
+            problems.push(format!("Delivered {} duplicates", self.duplicates));
+        }
+        // Каждый постоянный пользователь держит один сокет и подписан на один чат
+        let broker = self.broker;
+        if broker.users > config.users
+            || broker.sockets > config.users
+            || broker.subscriptions > config.users
+            || broker.chats > config.chats
+        {
+            problems.push(format!(
+                "Broker grew after {} guest connections: {broker:?}",
+                self.guest_connections
+            ));
+        }
+        problems
+    }
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sent {}, stored {}, failed {}",
+            self.sent, self.stored, self.failed
+        )?;
+        writeln!(
+            f,
+            "Delivered {} of {}, duplicates {}",
+            self.delivered, self.expected_deliveries, self.duplicates
+        )?;
+        writeln!(
+            f,
+            "Guest connections {}, broker {:?}",
+            self.guest_connections, self.broker
+        )?;
+        match (self.memory_before, self.memory_after) {
+            (Some(before), Some(after)) => write!(
+                f,
+                "Resident memory {} KiB before, {} KiB after",
+                before / 1024,
+                after / 1024
+            ),
+            _ => write!(f, "Resident memory is unknown"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    delivered: AtomicUsize,
+    duplicates: AtomicUsize,
+    stored: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// Сокет пользователя: считает полученные сообщения, гости ничего не считают
+struct SoakClient {
+    seen: HashSet<Uuid>,
+    counters: Option<Arc<Counters>>,
+}
+
+impl Actor for SoakClient {
+    type Context = Context<Self>;
+}
+
+impl Handler<BrokerMessage> for SoakClient {
+    type Result = ();
+    fn handle(&mut self, msg: BrokerMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let (BrokerMessage::NewMessage(message), Some(counters)) = (msg, &self.counters) else {
+            return;
+        };
+        if self.seen.insert(message.message_id) {
+            counters.delivered.fetch_add(1, Ordering::Relaxed);
+        } else {
+            counters.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn chat_of(chat_ids: &[ChatId], user_id: UserId) -> ChatId {
+    chat_ids[user_id.get() as usize % chat_ids.len()]
+}
+
+/// База в памяти: знает, в каком чате кто состоит, и считает записанные сообщения
+fn memory_database(chat_ids: Arc<Vec<ChatId>>, counters: Arc<Counters>) -> MockDatabase {
+    let mut db = MockDatabase::new();
+    db.expect_get_user_chats()
+        .returning(move |user_id| Ok(vec![chat_of(&chat_ids, user_id)]));
+    db.expect_find_mentions().returning(|_, _, _| Ok(vec![]));
+    db.expect_add_new_message_to_chat().returning(move |_| {
+        counters.stored.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    });
+    db
+}
+
+fn connect(
+    broker: &Addr<BrokerActor>,
+    user_id: UserId,
+    counters: Option<Arc<Counters>>,
+) -> Recipient<BrokerMessage> {
+    let socket = SoakClient {
+        seen: HashSet::new(),
+        counters,
+    }
+    .start()
+    .recipient();
+    broker.do_send(
+        broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+            socket.clone().into(),
+            user_id,
+        ),
+    );
+    socket
+}
+
+/// Проходит путь сообщения от сокета: сохранение в базе, затем рассылка через брокер
+async fn send(
+    db: Addr<DatabaseActor>,
+    broker: Addr<BrokerActor>,
+    sender_id: UserId,
+    chat_id: ChatId,
+    counters: Arc<Counters>,
+) {
+    let message = NewChatMessage {
+        chat_id,
+        msg_text: "soak".into(),
+        reply_to: None,
+        attachments: vec![],
+        client_msg_id: None,
+    };
+    let result = match services::compose_message(
+        sender_id,
+        message,
+        &MessageRules::default(),
+        &NoModeration,
+    ) {
+        Ok(message) => db
+            .send(database_actor::messages::InsertNewMessage(message))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string())),
+        Err(e) => Err(format!("{e:?}")),
+    };
+    match result {
+        Ok(inserted) => broker.do_send(broker_actor::messages::RedisMessage::NewMessage(
+            inserted.message,
+        )),
+        Err(e) => {
+            warn!("Soak message was not stored: {e}");
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn broker_stats(broker: &Addr<BrokerActor>) -> BrokerStats {
+    broker
+        .send(broker_actor::messages::GetStats)
+        .await
+        .unwrap_or_default()
+}
+
+/// Резидентная память процесса в байтах, известна только в Linux
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+/// Проводит прогон, должен выполняться внутри системы actix
+pub async fn run(config: &SoakConfig) -> SoakReport {
+    let chat_ids: Arc<Vec<ChatId>> =
+        Arc::new((0..config.chats).map(|_| ChatId::new_v4()).collect());
+    let counters = Arc::new(Counters::default());
+    let db =
+        DatabaseActor::from_database(memory_database(chat_ids.clone(), counters.clone())).start();
+    let broker = BrokerActor::new(db.clone()).await.start();
+
+    let mut members = vec![0; config.chats];
+    let _users: Vec<_> = (0..config.users as i64)
+        .map(|user_id| {
+            members[user_id as usize % config.chats] += 1;
+            connect(&broker, UserId(user_id), Some(counters.clone()))
+        })
+        .collect();
+    // Сообщения, отправленные до подписки, до пользователей и не должны доходить
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while broker_stats(&broker).await.subscriptions < config.users && Instant::now() < deadline {
+        actix::clock::sleep(TICK).await;
+    }
+    info!(
+        "Soak test: {} users in {} chats, {} messages per second for {:?}",
+        config.users, config.chats, config.rate, config.duration
+    );
+
+    let mut report = SoakReport {
+        memory_before: resident_memory(),
+        ..Default::default()
+    };
+    let mut guests: Vec<(Recipient<BrokerMessage>, UserId)> = vec![];
+    let mut next_guest = UserId(config.users as i64);
+    let mut budget = 0.0;
+    let mut ticker = actix::clock::interval(TICK);
+    let started = Instant::now();
+    while started.elapsed() < config.duration {
+        ticker.tick().await;
+        for (socket, user_id) in guests.drain(..) {
+            broker.do_send(
+                broker_actor::messages::WebsocketMessage::BrokerNotifyClosed(socket, user_id),
+            );
+        }
+        guests.push((connect(&broker, next_guest, None), next_guest));
+        next_guest = UserId(next_guest.get() + 1);
+        report.guest_connections += 1;
+
+        budget += config.rate as f64 * TICK.as_secs_f64();
+        while budget >= 1.0 {
+            budget -= 1.0;
+            let sender_id = UserId((report.sent % config.users) as i64);
+            report.sent += 1;
+            report.expected_deliveries += members[sender_id.get() as usize % config.chats];
+            actix::spawn(send(
+                db.clone(),
+                broker.clone(),
+                sender_id,
+                chat_of(&chat_ids, sender_id),
+                counters.clone(),
+            ));
+        }
+    }
+    for (socket, user_id) in guests {
+        broker
+            .do_send(broker_actor::messages::WebsocketMessage::BrokerNotifyClosed(socket, user_id));
+    }
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    loop {
+        let settled = counters.stored.load(Ordering::Relaxed)
+            + counters.failed.load(Ordering::Relaxed)
+            >= report.sent
+            && counters.delivered.load(Ordering::Relaxed) >= report.expected_deliveries
+            && broker_stats(&broker).await.users <= config.users;
+        if settled || Instant::now() >= deadline {
+            break;
+        }
+        actix::clock::sleep(TICK).await;
+    }
+    report.stored = counters.stored.load(Ordering::Relaxed);
+    report.failed = counters.failed.load(Ordering::Relaxed);
+    report.delivered = counters.delivered.load(Ordering::Relaxed);
+    report.duplicates = counters.duplicates.load(Ordering::Relaxed);
+    report.broker = broker_stats(&broker).await;
+    report.memory_after = resident_memory();
+    report
+}
@@ -0,0 +1,288 @@
+use std::{env, fmt, io, pin::Pin, time::Duration};
+
+use bytes::Bytes;
+use futures::Stream;
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use reqwest::Body;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::StorageConfig,
+    http_client::{HttpEndpoint, HttpError},
+};
+
+// Хранилище вложений
+//
+// Файлы лежат в S3-совместимом хранилище (S3, MinIO), в Scylla хранятся только их описания.
+// Запросы подписываются AWS Signature V4.
+//
+// Файл передается в хранилище потоком по мере того, как приходит от клиента, и целиком
+// в памяти не держится; тело при этом не подписывается (UNSIGNED-PAYLOAD), его длина
+// известна заранее. Отдавать файлы встроенными в страницу можно только для типов из
+// INLINE_CONTENT_TYPES, остальные сохраняются как application/octet-stream
+// с Content-Disposition: attachment, чтобы загруженный HTML или SVG не исполнился
+// в браузере на домене хранилища.
+
+/// Типы файлов, которые браузер может показать сам, не исполняя их содержимое
+pub const INLINE_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/webm",
+    "video/mp4",
+    "video/webm",
+    "application/pdf",
+];
+
+/// Тип, под которым сохраняются файлы не из INLINE_CONTENT_TYPES
+pub const FALLBACK_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Тип, под которым будет сохранен файл с заявленным клиентом типом declared
+///
+/// Параметры вроде charset отбрасываются, регистр не важен
+pub fn attachment_content_type(declared: &str) -> &'static str {
+    let essence = declared.split(';').next().unwrap_or_default().trim();
+    INLINE_CONTENT_TYPES
+        .iter()
+        .find(|allowed| allowed.eq_ignore_ascii_case(essence))
+        .copied()
+        .unwrap_or(FALLBACK_CONTENT_TYPE)
+}
+
+/// Тело объекта, которое уходит в хранилище по кускам
+pub type ObjectStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send + Sync>>;
+
+/// Тело объекта, которое уже целиком в памяти
+pub fn object_stream(body: Vec<u8>) -> ObjectStream {
+    Box::pin(futures::stream::iter([Ok(Bytes::from(body))]))
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// Хранилище не настроено
+    NotConfigured,
+    /// Хранилище не ответило или ответило ошибкой
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotConfigured => write!(f, "Attachment storage is not configured"),
+            StorageError::Backend(e) => write!(f, "Attachment storage error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<HttpError> for StorageError {
+    fn from(e: HttpError) -> Self {
+        StorageError::Backend(e.0)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait ObjectStore: Send + Sync {
+    /// Кладет объект из length байт под ключом key и возвращает ссылку, по которой его
+    /// можно скачать
+    ///
+    /// content_type должен быть результатом attachment_content_type
+    async fn put(
+        &self,
+        key: &str,
+        content_type: &str,
+        length: u64,
+        body: ObjectStream,
+    ) -> Result<String, StorageError>;
+}
+
+/// Хранилище с API S3, адресация бакета в пути (так ее понимает и MinIO)
+pub struct S3Store {
+    endpoint: HttpEndpoint,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    public_base_url: Option<String>,
+    timeout: Duration,
+}
+
+impl S3Store {
+    /// Ключи доступа берутся из переменных окружения, а не из файла конфигурации
+    pub fn from_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        let key = |name: &str| {
+            env::var(name).map_err(|_| StorageError::Backend(format!("{name} is not set")))
+        };
+        Ok(Self {
+            endpoint: HttpEndpoint::parse(&config.endpoint)?,
+            bucket: config.bucket.clone(),
+            region: config.region.clone(),
+            access_key: key(&config.access_key_env)?,
+            secret_key: key(&config.secret_key_env)?,
+            public_base_url: config
+                .public_base_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            timeout: Duration::from_secs(config.timeout_secs),
+        })
+    }
+
+    /// Путь объекта в хранилище
+    fn object_path(&self, key: &str) -> String {
+        format!(
+            "/{}/{}",
+            self.bucket,
+            urlencoding::encode(key).replace("%2F", "/")
+        )
+    }
+
+    /// Ссылка на объект для клиентов
+    pub fn public_url(&self, key: &str) -> String {
+        match &self.public_base_url {
+            Some(base) => format!("{base}/{key}"),
+            None => self.endpoint.url(&self.object_path(key)),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl ObjectStore for S3Store {
+    async fn put(
+        &self,
+        key: &str,
+        content_type: &str,
+        length: u64,
+        body: ObjectStream,
+    ) -> Result<String, StorageError> {
+        let path = self.object_path(key);
+        let payload_hash = UNSIGNED_PAYLOAD.to_string();
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let inline = INLINE_CONTENT_TYPES.contains(&content_type);
+        let (content_type, disposition) = if inline {
+            (content_type, "inline")
+        } else {
+            (FALLBACK_CONTENT_TYPE, "attachment")
+        };
+        let headers = vec![
+            ("content-disposition".to_string(), disposition.to_string()),
+            ("content-type".to_string(), content_type.to_string()),
+            ("host".to_string(), self.endpoint.host_header()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        let authorization = sign_v4(&SigningRequest {
+            method: "PUT",
+            path: &format!("{}{path}", self.endpoint.prefix),
+            headers: &headers,
+            payload_hash: &payload_hash,
+            amz_date: &amz_date,
+            region: &self.region,
+            service: "s3",
+            access_key: &self.access_key,
+            secret_key: &self.secret_key,
+        });
+        self.endpoint
+            .request(
+                "PUT",
+                &path,
+                &[
+                    ("Content-Disposition", disposition.to_string()),
+                    ("Content-Type", content_type.to_string()),
+                    // С известной длиной тело уходит без chunked-кодирования, которого S3 не принимает
+                    ("Content-Length", length.to_string()),
+                    ("x-amz-content-sha256", payload_hash),
+                    ("x-amz-date", amz_date),
+                    ("Authorization", authorization),
+                ],
+                Body::wrap_stream(body),
+                self.timeout,
+            )
+            .await?;
+        Ok(self.public_url(key))
+    }
+}
+
+/// Поднимает хранилище из конфигурации, без адреса или с ошибкой в настройках вложения выключены
+pub fn store_from_config(config: &StorageConfig) -> Option<S3Store> {
+    if config.endpoint.is_empty() {
+        return None;
+    }
+    match S3Store::from_config(config) {
+        Ok(store) => {
+            info!("Attachments are stored in {}", config.endpoint);
+            Some(store)
+        }
+        Err(e) => {
+            error!("Cannot set up attachment storage: {e}");
+            None
+        }
+    }
+}
+
+/// Хэш тела запроса, которое не подписывается
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Запрос, который нужно подписать по AWS Signature V4
+pub struct SigningRequest<'a> {
+    pub method: &'a str,
+    /// Путь запроса, уже закодированный
+    pub path: &'a str,
+    /// Подписываемые заголовки, имена в нижнем регистре
+    pub headers: &'a [(String, String)],
+    /// sha256 тела запроса в hex или UNSIGNED_PAYLOAD
+    pub payload_hash: &'a str,
+    /// Время запроса в формате YYYYMMDDTHHMMSSZ
+    pub amz_date: &'a str,
+    pub region: &'a str,
+    pub service: &'a str,
+    pub access_key: &'a str,
+    pub secret_key: &'a str,
+}
+
+/// Значение заголовка Authorization для запроса без параметров в строке запроса
+pub fn sign_v4(request: &SigningRequest) -> String {
+    let mut headers: Vec<_> = request.headers.iter().collect();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{canonical_headers}\n{signed_headers}\n{}",
+        request.method, request.path, request.payload_hash
+    );
+    let date = &request.amz_date[..8];
+    let scope = format!("{date}/{}/{}/aws4_request", request.region, request.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{scope}\n{}",
+        request.amz_date,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, request.region, request.service, "aws4_request"]
+        .iter()
+        .fold(
+            format!("AWS4{}", request.secret_key).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        request.access_key
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
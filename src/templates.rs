@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{
+    actors::websocket_actor::ChatMessage,
+    clock,
+    config::ChatTemplate,
+    database::{
+        data::{ChatInfo, ChatType, PostPolicy},
+        DBResult, Database,
+    },
+    ids::{ChatId, UserId},
+};
+
+// Создание чатов по шаблонам
+//
+// Шаблоны описываются в конфигурации и нужны автоматизации: например, система мониторинга
+// создает комнату инцидента с дежурными, закрепленной инструкцией и правом писать только
+// у создателя. Шаблон задает имя с подстановками, участников, закрепленное сообщение
+// и то, кто может писать в чат.
+
+/// Подставляет в шаблон имени дату, время и параметры запроса
+///
+/// Если какого-то параметра не хватило, то возвращает его имя
+pub fn render_name(
+    pattern: &str,
+    params: &HashMap<String, String>,
+    now: DateTime<Utc>,
+) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        // Незакрытая скобка остается в имени как есть
+        let Some(length) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        match &rest[start + 1..start + length] {
+            "date" => name.push_str(&now.format("%Y-%m-%d").to_string()),
+            "time" => name.push_str(&now.format("%H:%M").to_string()),
+            key => name.push_str(params.get(key).ok_or_else(|| key.to_string())?),
+        }
+        rest = &rest[start + length + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Чат, созданный по шаблону, и закрепленное в нем сообщение
+pub struct TemplateChat {
+    pub chat: ChatInfo,
+    pub pinned_message: Option<ChatMessage>,
+}
+
+/// Создает групповой чат по шаблону
+///
+/// В чат попадают участники шаблона и extra_members, имя уже должно быть подставлено
+/// и проверено
+pub async fn create_from_template<D: Database + ?Sized>(
+    db: &D,
+    creator_id: UserId,
+    template: &ChatTemplate,
+    chat_name: String,
+    extra_members: Vec<UserId>,
+) -> DBResult<TemplateChat> {
+    let mut members: Vec<UserId> = template
+        .members
+        .iter()
+        .copied()
+        .map(UserId)
+        .chain(extra_members)
+        .filter(|&id| id != creator_id)
+        .collect();
+    members.sort_unstable();
+    members.dedup();
+    let chat = db
+        .create_new_chat(creator_id, members, ChatType::Group, chat_name)
+        .await?;
+    if template.post_policy != PostPolicy::Everyone {
+        db.set_post_policy(ChatId(chat.id), template.post_policy)
+            .await?;
+    }
+    let pinned_message = match &template.pinned_message {
+        Some(text) => {
+            let message = ChatMessage {
+                chat_id: chat.id,
+                message_id: Uuid::new_v4(),
+                sender_id: creator_id.get(),
+                date: clock::CLOCK.now().into(),
+                msg_text: text.clone(),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            db.add_new_message_to_chat(message.clone()).await?;
+            // В только что созданном чате других закреплений нет, вытеснять нечего
+            db.pin_message(
+                creator_id,
+                ChatId(chat.id),
+                message.message_id,
+                None,
+                usize::MAX,
+            )
+            .await?;
+            Some(message)
+        }
+        None => None,
+    };
+    let chat = db.get_chat_info(creator_id, ChatId(chat.id)).await?;
+    Ok(TemplateChat {
+        chat,
+        pinned_message,
+    })
+}
@@ -0,0 +1,64 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Обработка текста
+//
+// Длина имен, сообщений и черновиков считается в графемах - в том, что человек видит как
+// один символ: эмодзи с модификаторами, флаги и буквы с диакритикой занимают несколько
+// кодовых точек, но считаются за один символ и никогда не разрезаются при обрезке.
+// Имена и тексты перед сохранением приводятся к NFC, чтобы одинаково выглядящие строки
+// совпадали и по байтам.
+
+/// Сколько графем в превью сообщения
+pub const PREVIEW_LENGTH: usize = 100;
+
+/// Длина строки в графемах
+pub fn grapheme_len(value: &str) -> usize {
+    value.graphemes(true).count()
+}
+
+/// Первые max графем строки, без разрезания многобайтовых символов и эмодзи
+pub fn truncate(value: &str, max: usize) -> &str {
+    match value.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &value[..end],
+        None => value,
+    }
+}
+
+/// Приводит строку к NFC
+pub fn normalize(value: &str) -> String {
+    value.nfc().collect()
+}
+
+/// Есть ли в строке управляющие символы, кроме разрешенных allowed
+pub fn has_control(value: &str, allowed: &[char]) -> bool {
+    value
+        .chars()
+        .any(|c| c.is_control() && !allowed.contains(&c))
+}
+
+/// Убирает управляющие символы, кроме разрешенных allowed
+pub fn strip_control(value: &str, allowed: &[char]) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_control() || allowed.contains(c))
+        .collect()
+}
+
+/// Однострочное превью текста не длиннее max графем: переводы строк, управляющие символы
+/// и повторные пробелы заменяются одним пробелом, обрезанный текст заканчивается многоточием
+pub fn preview(value: &str, max: usize) -> String {
+    let line = value
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if grapheme_len(&line) <= max {
+        return line;
+    }
+    let mut preview = truncate(&line, max.saturating_sub(1))
+        .trim_end()
+        .to_string();
+    preview.push('…');
+    preview
+}
@@ -0,0 +1,375 @@
+use std::{collections::HashSet, future::Future};
+
+use redis::{AsyncCommands, RedisResult, Script};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    actors::websocket_actor::{ChatMessage, MessageTombstone},
+    config::RedisConfig,
+    events,
+    ids::{ChatId, UserId},
+    metrics,
+    redis_topology::RedisConnection,
+};
+
+// Транспорт сообщений чатов между экземплярами сервиса
+//
+// Есть два транспорта с разными гарантиями доставки:
+// 1) PubSubTransport - сообщение публикуется в канал Redis и доходит только до тех,
+//    кто подключен прямо сейчас (at-most-once)
+// 2) StreamTransport - сообщение сначала пишется в поток Redis чата, а потом публикуется
+//    так же, как в первом случае. Клиенты подтверждают полученные сообщения, и при
+//    переподключении им досылается все, что они не подтвердили (at-least-once), кроме
+//    сообщений, удаленных после отправки
+//
+// Какой транспорт использовать, решается для каждого чата по его режиму доставки.
+//
+// Управляющие сообщения (подписки, блокировки, отозванные сессии) публикуются через
+// ControlLog: он пишет их еще и в короткий общий поток, из которого экземпляр после
+// переподключения к Redis дочитывает то, что пропустил, пока был отключен.
+
+/// Канал новых сообщений чатов
+pub const MESSAGE_CHANNEL: &str = "chat_message";
+
+/// Сравнить id записей потока вида <миллисекунды>-<номер> и записать новый,
+/// только если он больше уже подтвержденного; подтверждения живут столько же, сколько поток
+const ACK_SCRIPT: &str = r#"
+local new_ms, new_seq = string.match(ARGV[2], "^(%d+)-(%d+)$")
+if not new_ms then
+    return 0
+end
+local current = redis.call("HGET", KEYS[1], ARGV[1])
+if current then
+    local ms, seq = string.match(current, "^(%d+)-(%d+)$")
+    if tonumber(new_ms) < tonumber(ms)
+        or (tonumber(new_ms) == tonumber(ms) and tonumber(new_seq) <= tonumber(seq)) then
+        return 0
+    end
+end
+redis.call("HSET", KEYS[1], ARGV[1], ARGV[2])
+redis.call("EXPIRE", KEYS[1], ARGV[3])
+return 1
+"#;
+
+/// Выполняет публикацию в channel, записывая в метрики ее время и неудачи
+async fn measure_publish<T>(
+    channel: &str,
+    publish: impl Future<Output = RedisResult<T>>,
+) -> RedisResult<T> {
+    let timer = metrics::REDIS_PUBLISH_LATENCY
+        .with_label_values(&[channel])
+        .start_timer();
+    let result = publish.await;
+    timer.observe_duration();
+    if result.is_err() {
+        metrics::REDIS_PUBLISH_FAILURES
+            .with_label_values(&[channel])
+            .inc();
+    }
+    result
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait Transport {
+    /// Рассылает сообщение всем экземплярам сервиса
+    async fn publish(&self, message: ChatMessage) -> RedisResult<()>;
+}
+
+#[derive(Clone)]
+pub struct PubSubTransport {
+    /// Подключение само переподключается к Redis, в том числе к новому главному узлу
+    connection: RedisConnection,
+    config: RedisConfig,
+}
+
+impl PubSubTransport {
+    pub fn new(connection: RedisConnection, config: RedisConfig) -> Self {
+        Self { connection, config }
+    }
+
+    fn connection(&self) -> RedisConnection {
+        self.connection.clone()
+    }
+
+    /// Публикует payload в канал channel с префиксом окружения
+    pub async fn publish_to(&self, channel: &str, payload: &impl Serialize) -> RedisResult<()> {
+        let payload = events::to_redis_payload(payload);
+        let mut connection = self.connection();
+        measure_publish(
+            channel,
+            connection.publish(self.config.key(channel), payload),
+        )
+        .await
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Transport for PubSubTransport {
+    async fn publish(&self, message: ChatMessage) -> RedisResult<()> {
+        self.publish_to(MESSAGE_CHANNEL, &message).await
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamTransport {
+    pubsub: PubSubTransport,
+    max_len: usize,
+    /// Сколько секунд после последней записи живут поток, подтверждения и удаленные
+    ttl_secs: u64,
+}
+
+impl StreamTransport {
+    pub fn new(pubsub: PubSubTransport, max_len: usize, ttl_secs: u64) -> Self {
+        Self {
+            pubsub,
+            max_len,
+            ttl_secs,
+        }
+    }
+
+    fn stream_key(&self, chat_id: Uuid) -> String {
+        self.pubsub.config.key(&format!("stream:{chat_id}"))
+    }
+
+    fn acks_key(&self, chat_id: Uuid) -> String {
+        self.pubsub.config.key(&format!("acks:{chat_id}"))
+    }
+
+    fn deleted_key(&self, chat_id: Uuid) -> String {
+        self.pubsub.config.key(&format!("deleted:{chat_id}"))
+    }
+
+    /// Запоминает удаленное сообщение, чтобы pending его больше не досылал
+    ///
+    /// Запись потока по id сообщения не найти без перебора всего потока, поэтому она
+    /// остается до вытеснения по MAXLEN, а id удаленных сообщений хранятся рядом с потоком
+    /// в сортированном множестве по дате сообщения. Поток держит не больше max_len сообщений,
+    /// так что и удаленных хранится не больше max_len самых новых
+    pub async fn forget(&self, tombstone: &MessageTombstone) -> RedisResult<()> {
+        let mut connection = self.pubsub.connection();
+        // У чата без потока досылать нечего
+        let has_stream: bool = connection
+            .exists(self.stream_key(tombstone.chat_id))
+            .await?;
+        if !has_stream {
+            return Ok(());
+        }
+        let key = self.deleted_key(tombstone.chat_id);
+        connection
+            .zadd::<_, _, _, ()>(
+                &key,
+                tombstone.message_id.to_string(),
+                tombstone.date.timestamp.num_milliseconds(),
+            )
+            .await?;
+        connection
+            .zremrangebyrank::<_, ()>(&key, 0, -(self.max_len as isize) - 1)
+            .await?;
+        connection.expire(&key, self.ttl_secs as usize).await
+    }
+
+    /// Запоминает, что пользователь получил все сообщения чата до delivery_id включительно
+    ///
+    /// Возвращает false, если подтверждение устарело или id не похож на id записи потока
+    pub async fn ack(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        delivery_id: &str,
+    ) -> RedisResult<bool> {
+        let updated: i64 = Script::new(ACK_SCRIPT)
+            .key(self.acks_key(chat_id.get()))
+            .arg(user_id.get())
+            .arg(delivery_id)
+            .arg(self.ttl_secs)
+            .invoke_async(&mut self.pubsub.connection())
+            .await?;
+        Ok(updated == 1)
+    }
+
+    /// Сообщения чата, которые пользователь еще не подтвердил, от старых к новым
+    pub async fn pending(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        limit: usize,
+    ) -> RedisResult<Vec<ChatMessage>> {
+        let chat_id = chat_id.get();
+        let mut connection = self.pubsub.connection();
+        let acked: Option<String> = connection
+            .hget(self.acks_key(chat_id), user_id.get())
+            .await?;
+        let start = match acked {
+            Some(id) => format!("({id}"),
+            None => "-".into(),
+        };
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(self.stream_key(chat_id))
+            .arg(start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(limit)
+            .query_async(&mut connection)
+            .await?;
+        let messages: Vec<ChatMessage> = entries
+            .into_iter()
+            .filter_map(|(id, fields)| {
+                let payload = fields.get(1)?;
+                let mut message: ChatMessage = serde_json::from_str(payload).ok()?;
+                message.delivery_id = Some(id);
+                Some(message)
+            })
+            .collect();
+        let dates = messages
+            .iter()
+            .map(|message| message.date.timestamp.num_milliseconds());
+        let (Some(first), Some(last)) = (dates.clone().min(), dates.max()) else {
+            return Ok(messages);
+        };
+        // Удаленные сообщения из того же промежутка дат, что и прочитанные записи
+        let deleted: HashSet<String> = connection
+            .zrangebyscore(self.deleted_key(chat_id), first, last)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter(|message| !deleted.contains(&message.message_id.to_string()))
+            .collect())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Transport for StreamTransport {
+    async fn publish(&self, mut message: ChatMessage) -> RedisResult<()> {
+        let mut connection = self.pubsub.connection();
+        let ttl = self.ttl_secs as usize;
+        // Вместе с потоком продлеваются подтверждения и удаленные, иначе они истекли бы
+        // раньше записей, к которым относятся
+        let (delivery_id,): (String,) = measure_publish(
+            "stream",
+            redis::pipe()
+                .cmd("XADD")
+                .arg(self.stream_key(message.chat_id))
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(self.max_len)
+                .arg("*")
+                .arg("message")
+                .arg(events::to_redis_payload(&message))
+                .expire(self.stream_key(message.chat_id), ttl)
+                .ignore()
+                .expire(self.acks_key(message.chat_id), ttl)
+                .ignore()
+                .expire(self.deleted_key(message.chat_id), ttl)
+                .ignore()
+                .query_async(&mut connection),
+        )
+        .await?;
+        message.delivery_id = Some(delivery_id);
+        self.pubsub.publish(message).await
+    }
+}
+
+/// Добавляет к сообщению из журнала поле log_id с id его записи
+fn with_log_id(text: &str, id: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            fields.insert("log_id".into(), id.into());
+            serde_json::Value::Object(fields).to_string()
+        }
+        _ => text.into(),
+    }
+}
+
+/// Запись журнала управляющих сообщений: id в потоке, канал без префикса и сообщение
+pub type ControlEntry = (String, String, String);
+
+#[derive(Clone)]
+pub struct ControlLog {
+    pubsub: PubSubTransport,
+    max_len: usize,
+}
+
+impl ControlLog {
+    pub fn new(pubsub: PubSubTransport, max_len: usize) -> Self {
+        Self { pubsub, max_len }
+    }
+
+    fn log_key(&self) -> String {
+        self.pubsub.config.key("control_log")
+    }
+
+    /// Записывает сообщение в журнал и публикует его в канал channel
+    ///
+    /// Если записать в журнал не удалось, сообщение все равно публикуется: подключенные
+    /// экземпляры получат его как обычно
+    pub async fn publish(&self, channel: &str, payload: &impl Serialize) -> RedisResult<()> {
+        let text = events::to_redis_payload(payload);
+        let mut connection = self.pubsub.connection();
+        let logged: RedisResult<String> = measure_publish(
+            "control_log",
+            redis::cmd("XADD")
+                .arg(self.log_key())
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(self.max_len)
+                .arg("*")
+                .arg("channel")
+                .arg(channel)
+                .arg("payload")
+                .arg(&text)
+                .query_async(&mut connection),
+        )
+        .await;
+        // С id записи получатель знает, докуда он дочитал журнал
+        let text = match &logged {
+            Ok(id) => with_log_id(&text, id),
+            Err(_) => text,
+        };
+        let published = measure_publish(
+            channel,
+            connection.publish::<_, _, ()>(self.pubsub.config.key(channel), text),
+        )
+        .await;
+        logged.and(published)
+    }
+
+    /// id последней записи журнала, с него начнется дочитывание после переподключения
+    pub async fn last_id(&self) -> RedisResult<Option<String>> {
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XREVRANGE")
+            .arg(self.log_key())
+            .arg("+")
+            .arg("-")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut self.pubsub.connection())
+            .await?;
+        Ok(entries.into_iter().next().map(|(id, _)| id))
+    }
+
+    /// Записи журнала после after (или с начала, если after нет), от старых к новым
+    pub async fn since(&self, after: Option<&str>) -> RedisResult<Vec<ControlEntry>> {
+        let start = match after {
+            Some(id) => format!("({id}"),
+            None => "-".into(),
+        };
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(self.log_key())
+            .arg(start)
+            .arg("+")
+            .query_async(&mut self.pubsub.connection())
+            .await?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(id, fields)| {
+                let field = |name: &str| {
+                    fields
+                        .chunks(2)
+                        .find(|pair| pair[0] == name)
+                        .and_then(|pair| pair.get(1).cloned())
+                };
+                Some((id, field("channel")?, field("payload")?))
+            })
+            .collect())
+    }
+}
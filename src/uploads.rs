@@ -0,0 +1,220 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Загрузка вложений по вебсокету
+//
+// Клиенты, которые не умеют отправлять файлы обычным HTTP-запросом, присылают вложение
+// по уже открытому сокету бинарными кадрами. Каждый кадр - конверт: два байта длины
+// заголовка (big endian), заголовок в JSON и кусок файла. В заголовке - upload_id, который
+// выбирает клиент, и номер куска seq с нуля; в первом куске еще chat_id, name и mime, а в
+// последнем last: true. Куски копятся в памяти сокета, и когда пришел последний, файл
+// целиком уходит в хранилище, как при загрузке через /api/chat/attachment.
+//
+// Размер одного кадра ограничен websocket.max_frame_bytes, всего файла - тем же
+// storage.max_attachment_bytes, что и при загрузке по HTTP, а незаконченных загрузок на
+// сокет не больше MAX_PENDING_UPLOADS, так что брошенные загрузки не съедают память.
+
+/// Сколько загрузок сокет может вести одновременно
+pub const MAX_PENDING_UPLOADS: usize = 4;
+
+/// Тип файла, если клиент его не назвал
+pub const DEFAULT_MIME: &str = "application/octet-stream";
+
+/// Заголовок куска вложения
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkHeader {
+    pub upload_id: Uuid,
+    /// Номер куска, куски идут подряд с нуля
+    pub seq: u32,
+    /// Это последний кусок файла
+    #[serde(default)]
+    pub last: bool,
+    /// Чат, имя и тип файла, обязательны в первом куске
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UploadError {
+    /// Кадр не разбирается как конверт
+    InvalidEnvelope(String),
+    /// В первом куске нет чата или имени файла
+    MissingMetadata,
+    /// Кусок не той загрузки или не по порядку
+    UnexpectedChunk {
+        expected: u32,
+        got: u32,
+    },
+    /// Файл больше, чем можно загрузить
+    TooLarge {
+        max_bytes: usize,
+    },
+    TooManyUploads,
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::InvalidEnvelope(e) => write!(f, "Invalid attachment chunk: {e}"),
+            UploadError::MissingMetadata => {
+                write!(f, "First attachment chunk must have chat_id and name")
+            }
+            UploadError::UnexpectedChunk { expected, got } => {
+                write!(f, "Expected attachment chunk {expected}, got {got}")
+            }
+            UploadError::TooLarge { max_bytes } => {
+                write!(f, "Attachment is larger than {max_bytes} bytes")
+            }
+            UploadError::TooManyUploads => write!(
+                f,
+                "At most {MAX_PENDING_UPLOADS} attachments can be uploaded at once"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Собирает бинарный кадр из заголовка и куска файла
+pub fn encode_chunk(header: &ChunkHeader, chunk: &[u8]) -> Vec<u8> {
+    let header = serde_json::to_vec(header).expect("Chunk header is serializable");
+    let length = u16::try_from(header.len()).expect("Chunk header is shorter than 64 KiB");
+    let mut frame = Vec::with_capacity(2 + header.len() + chunk.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(chunk);
+    frame
+}
+
+/// Разбирает бинарный кадр на заголовок и кусок файла
+pub fn parse_chunk(frame: &[u8]) -> Result<(ChunkHeader, &[u8]), UploadError> {
+    let invalid = |e: &str| UploadError::InvalidEnvelope(e.into());
+    let (length, rest) = frame
+        .split_first_chunk::<2>()
+        .ok_or_else(|| invalid("frame is too short"))?;
+    let length = u16::from_be_bytes(*length) as usize;
+    if rest.len() < length {
+        return Err(invalid("header is longer than the frame"));
+    }
+    let (header, chunk) = rest.split_at(length);
+    let header =
+        serde_json::from_slice(header).map_err(|e| UploadError::InvalidEnvelope(e.to_string()))?;
+    Ok((header, chunk))
+}
+
+/// Полностью полученное вложение
+#[derive(Debug)]
+pub struct CompletedUpload {
+    pub upload_id: Uuid,
+    pub chat_id: Uuid,
+    pub name: String,
+    pub mime: String,
+    pub body: Vec<u8>,
+}
+
+struct PendingUpload {
+    chat_id: Uuid,
+    name: String,
+    mime: String,
+    next_seq: u32,
+    body: Vec<u8>,
+}
+
+/// Незаконченные загрузки сокета
+#[derive(Default)]
+pub struct Uploads {
+    pending: HashMap<Uuid, PendingUpload>,
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Добавляет кусок; когда пришел последний, возвращает файл целиком
+    ///
+    /// После ошибки загрузка забывается, и клиенту нужно начинать ее заново
+    pub fn push(
+        &mut self,
+        header: ChunkHeader,
+        chunk: &[u8],
+        max_bytes: usize,
+    ) -> Result<Option<CompletedUpload>, UploadError> {
+        let upload_id = header.upload_id;
+        let result = self.append(header, chunk, max_bytes);
+        if result.is_err() {
+            self.pending.remove(&upload_id);
+        }
+        result
+    }
+
+    fn append(
+        &mut self,
+        header: ChunkHeader,
+        chunk: &[u8],
+        max_bytes: usize,
+    ) -> Result<Option<CompletedUpload>, UploadError> {
+        if header.seq == 0 {
+            if self.pending.contains_key(&header.upload_id) {
+                return Err(UploadError::UnexpectedChunk {
+                    expected: self.pending[&header.upload_id].next_seq,
+                    got: 0,
+                });
+            }
+            if self.pending.len() >= MAX_PENDING_UPLOADS {
+                return Err(UploadError::TooManyUploads);
+            }
+            let (Some(chat_id), Some(name)) = (header.chat_id, header.name) else {
+                return Err(UploadError::MissingMetadata);
+            };
+            self.pending.insert(
+                header.upload_id,
+                PendingUpload {
+                    chat_id,
+                    name,
+                    mime: header.mime.unwrap_or_else(|| DEFAULT_MIME.into()),
+                    next_seq: 0,
+                    body: vec![],
+                },
+            );
+        }
+        let upload =
+            self.pending
+                .get_mut(&header.upload_id)
+                .ok_or(UploadError::UnexpectedChunk {
+                    expected: 0,
+                    got: header.seq,
+                })?;
+        if header.seq != upload.next_seq {
+            return Err(UploadError::UnexpectedChunk {
+                expected: upload.next_seq,
+                got: header.seq,
+            });
+        }
+        if upload.body.len() + chunk.len() > max_bytes {
+            return Err(UploadError::TooLarge { max_bytes });
+        }
+        upload.body.extend_from_slice(chunk);
+        upload.next_seq += 1;
+        if !header.last {
+            return Ok(None);
+        }
+        let upload = self
+            .pending
+            .remove(&header.upload_id)
+            .expect("Upload was found above");
+        Ok(Some(CompletedUpload {
+            upload_id: header.upload_id,
+            chat_id: upload.chat_id,
+            name: upload.name,
+            mime: upload.mime,
+            body: upload.body,
+        }))
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, error::Error};
+
+use async_trait::async_trait;
+use chrono::{Days, NaiveDate};
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{RedisConfig, UsageConfig},
+    ids::UserId,
+    redis_topology::{RedisConnection, RedisConnector},
+};
+
+// Учет использования API пользователями
+//
+// Каждый запрос к API и каждый кадр, присланный по вебсокету, увеличивают счетчик
+// пользователя за текущие сутки (UTC). Счетчики лежат в Redis, так что их видят все
+// экземпляры сервиса: у пользователя за сутки один хеш usage:{user_id}:{YYYYMMDD}, он живет
+// usage.retention_days дней и потом удаляется сам. По счетчикам разбирают злоупотребления,
+// на них же можно будет построить квоты и биллинг.
+//
+// Счет ведется через UsageTracker, так что встраивающее чат приложение может подставить
+// в ChatApp свою реализацию или не вести учет вовсе.
+
+const USAGE_KEY_PREFIX: &str = "usage:";
+const API_REQUESTS_FIELD: &str = "api_requests";
+const WS_MESSAGES_FIELD: &str = "ws_messages";
+
+/// За сколько суток отдается использование, если клиент не попросил другого
+pub const DEFAULT_USAGE_DAYS: u32 = 7;
+
+/// Что сделал пользователь
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageKind {
+    /// Запрос к HTTP API
+    ApiRequest,
+    /// Кадр, присланный по вебсокету
+    WebsocketMessage,
+}
+
+impl UsageKind {
+    fn field(self) -> &'static str {
+        match self {
+            UsageKind::ApiRequest => API_REQUESTS_FIELD,
+            UsageKind::WebsocketMessage => WS_MESSAGES_FIELD,
+        }
+    }
+}
+
+/// Использование за одни сутки
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub date: NaiveDate,
+    pub api_requests: u64,
+    pub ws_messages: u64,
+}
+
+impl DailyUsage {
+    fn from_fields(date: NaiveDate, fields: &HashMap<String, u64>) -> Self {
+        Self {
+            date,
+            api_requests: fields.get(API_REQUESTS_FIELD).copied().unwrap_or(0),
+            ws_messages: fields.get(WS_MESSAGES_FIELD).copied().unwrap_or(0),
+        }
+    }
+}
+
+/// Ключ счетчиков пользователя за сутки day
+pub fn usage_key(user_id: UserId, day: NaiveDate) -> String {
+    format!("{USAGE_KEY_PREFIX}{user_id}:{}", day.format("%Y%m%d"))
+}
+
+/// Последние days суток, начиная с today, от новых к старым
+pub fn usage_days(today: NaiveDate, days: u32) -> Vec<NaiveDate> {
+    (0..days)
+        .map_while(|back| today.checked_sub_days(Days::new(back.into())))
+        .collect()
+}
+
+/// Текущие сутки по UTC
+fn today() -> NaiveDate {
+    chrono::Utc::now().date_naive()
+}
+
+/// Учет использования API
+#[async_trait(?Send)]
+pub trait UsageTracker: Send + Sync {
+    /// Учитывает одно действие пользователя за текущие сутки
+    async fn record(&self, user_id: UserId, kind: UsageKind) -> RedisResult<()>;
+
+    /// Использование за последние days суток, от новых к старым
+    async fn usage(&self, user_id: UserId, days: u32) -> RedisResult<Vec<DailyUsage>>;
+}
+
+/// Ничего не учитывает
+pub struct NoUsageTracking;
+
+#[async_trait(?Send)]
+impl UsageTracker for NoUsageTracking {
+    async fn record(&self, _user_id: UserId, _kind: UsageKind) -> RedisResult<()> {
+        Ok(())
+    }
+
+    async fn usage(&self, _user_id: UserId, days: u32) -> RedisResult<Vec<DailyUsage>> {
+        Ok(usage_days(today(), days)
+            .into_iter()
+            .map(|date| DailyUsage::from_fields(date, &HashMap::new()))
+            .collect())
+    }
+}
+
+/// Счетчики в Redis, общие для всех экземпляров
+#[derive(Clone)]
+pub struct RedisUsageTracker {
+    connection: RedisConnection,
+    config: RedisConfig,
+    retention_secs: usize,
+}
+
+impl RedisUsageTracker {
+    pub async fn connect(
+        config: &RedisConfig,
+        usage: &UsageConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let connection = RedisConnector::new(config).connect().await?;
+        Ok(Self {
+            connection,
+            config: config.clone(),
+            retention_secs: usage.retention_days as usize * 86400,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl UsageTracker for RedisUsageTracker {
+    async fn record(&self, user_id: UserId, kind: UsageKind) -> RedisResult<()> {
+        let key = self.config.key(&usage_key(user_id, today()));
+        redis::pipe()
+            .atomic()
+            .hincr(&key, kind.field(), 1)
+            .ignore()
+            .expire(&key, self.retention_secs)
+            .ignore()
+            .query_async(&mut self.connection.clone())
+            .await
+    }
+
+    async fn usage(&self, user_id: UserId, days: u32) -> RedisResult<Vec<DailyUsage>> {
+        let days = usage_days(today(), days);
+        let mut pipe = redis::pipe();
+        for day in &days {
+            pipe.hgetall(self.config.key(&usage_key(user_id, *day)));
+        }
+        let counters: Vec<HashMap<String, u64>> =
+            pipe.query_async(&mut self.connection.clone()).await?;
+        Ok(days
+            .into_iter()
+            .zip(counters)
+            .map(|(date, fields)| DailyUsage::from_fields(date, &fields))
+            .collect())
+    }
+}
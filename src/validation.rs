@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{MessageRules, NameRules},
+    database::data::{ChatPermissions, QuietHours, UserPreferences, UserProfile},
+    i18n::{translate, Locale},
+    text,
+};
+
+// Проверка пользовательского ввода
+//
+// Имена пользователей и чатов проверяются по правилам из конфигурации до того, как попасть
+// в базу. Ошибки привязаны к полям запроса, чтобы клиент мог показать их рядом с нужным полем.
+
+/// Ошибка в конкретном поле запроса
+///
+/// code - стабильный машиночитаемый код, message - описание для человека
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+    /// Параметры сообщения, нужны для перевода на другой язык
+    #[serde(skip)]
+    args: Vec<(&'static str, String)>,
+}
+
+impl FieldError {
+    /// Ошибка с кодом code, args подставляются в перевод описания
+    pub fn new(field: &str, code: &str, args: Vec<(&'static str, String)>) -> Self {
+        Self {
+            field: field.into(),
+            code: code.into(),
+            message: translate(Locale::default(), code, &args),
+            args,
+        }
+    }
+
+    /// Переводит описание ошибки на язык клиента
+    pub fn localize(mut self, locale: Locale) -> Self {
+        self.message = translate(locale, &self.code, &self.args);
+        self
+    }
+}
+
+/// Самый длинный идентификатор звука уведомления
+pub const MAX_SOUND_LENGTH: usize = 64;
+
+/// Проверяет идентификатор звука уведомления: латиница, цифры, '_', '-' и '.'
+pub fn validate_sound(field: &str, value: &str) -> Result<String, FieldError> {
+    if value.chars().count() > MAX_SOUND_LENGTH {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", MAX_SOUND_LENGTH.to_string())],
+        ));
+    }
+    let is_allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if let Some(c) = value.chars().find(|&c| !is_allowed(c)) {
+        return Err(FieldError::new(
+            field,
+            "invalid_characters",
+            vec![("character", format!("{c:?}"))],
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Самый длинный код языка чата
+pub const MAX_LANGUAGE_LENGTH: usize = 35;
+/// Самая длинная метка чата
+pub const MAX_LABEL_LENGTH: usize = 32;
+/// Сколько меток может быть у чата
+pub const MAX_CHAT_LABELS: usize = 10;
+
+/// Проверяет код языка (латиница, цифры и '-', например pt-BR) и приводит его к нижнему регистру
+pub fn validate_language(field: &str, value: &str) -> Result<String, FieldError> {
+    validate_token(field, value.trim(), MAX_LANGUAGE_LENGTH, |c| {
+        c.is_ascii_alphanumeric() || c == '-'
+    })
+}
+
+/// Проверяет метки чата (латиница, цифры, '_' и '-') и приводит их к нижнему регистру
+///
+/// Повторы меток убираются
+pub fn validate_labels(field: &str, values: &[String]) -> Result<Vec<String>, FieldError> {
+    let mut labels = Vec::with_capacity(values.len());
+    for value in values {
+        let label = validate_token(field, value.trim(), MAX_LABEL_LENGTH, |c| {
+            c.is_ascii_alphanumeric() || matches!(c, '_' | '-')
+        })?;
+        if !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+    if labels.len() > MAX_CHAT_LABELS {
+        return Err(FieldError::new(
+            field,
+            "too_many",
+            vec![("max", MAX_CHAT_LABELS.to_string())],
+        ));
+    }
+    Ok(labels)
+}
+
+/// Проверяет, что в маске разрешений чата только известные биты
+pub fn validate_permissions(field: &str, bits: u32) -> Result<ChatPermissions, FieldError> {
+    let permissions = ChatPermissions(bits);
+    if !permissions.is_known() {
+        return Err(FieldError::new(
+            field,
+            "unknown_permissions",
+            vec![("bits", (bits & !ChatPermissions::ALL.0).to_string())],
+        ));
+    }
+    Ok(permissions)
+}
+
+/// Проверяет непустое короткое значение из разрешенных символов и приводит его к нижнему регистру
+fn validate_token(
+    field: &str,
+    value: &str,
+    max_length: usize,
+    is_allowed: impl Fn(char) -> bool,
+) -> Result<String, FieldError> {
+    if value.is_empty() {
+        return Err(FieldError::new(
+            field,
+            "too_short",
+            vec![("min", "1".into())],
+        ));
+    }
+    if value.chars().count() > max_length {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", max_length.to_string())],
+        ));
+    }
+    if let Some(c) = value.chars().find(|&c| !is_allowed(c)) {
+        return Err(FieldError::new(
+            field,
+            "invalid_characters",
+            vec![("character", format!("{c:?}"))],
+        ));
+    }
+    Ok(value.to_ascii_lowercase())
+}
+
+/// Самый длинный идентификатор, который клиент присваивает своему сообщению
+pub const MAX_CLIENT_MSG_ID_LENGTH: usize = 64;
+
+/// Проверяет идентификатор сообщения, присвоенный клиентом: непустой, без управляющих символов
+pub fn validate_client_msg_id(field: &str, value: &str) -> Result<String, FieldError> {
+    if value.is_empty() {
+        return Err(FieldError::new(
+            field,
+            "too_short",
+            vec![("min", "1".into())],
+        ));
+    }
+    if value.chars().count() > MAX_CLIENT_MSG_ID_LENGTH {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", MAX_CLIENT_MSG_ID_LENGTH.to_string())],
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(FieldError::new(field, "control_characters", vec![]));
+    }
+    Ok(value.to_string())
+}
+
+/// Проверяет текст сообщения: не пустой и не из одних пробелов, не длиннее
+/// rules.max_length символов, без управляющих символов, кроме переводов строк и табуляции
+///
+/// Возвращает текст, приведенный к NFC
+pub fn validate_message_text(
+    field: &str,
+    value: &str,
+    rules: &MessageRules,
+) -> Result<String, FieldError> {
+    if value.trim().is_empty() {
+        return Err(FieldError::new(field, "blank", vec![]));
+    }
+    let value = text::normalize(value);
+    if text::grapheme_len(&value) > rules.max_length {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", rules.max_length.to_string())],
+        ));
+    }
+    if text::has_control(&value, &['\n', '\r', '\t']) {
+        return Err(FieldError::new(field, "control_characters", vec![]));
+    }
+    Ok(value)
+}
+
+/// Проверяет, что вложений у сообщения не больше, чем разрешено в rules
+pub fn validate_attachments(
+    field: &str,
+    attachments: &[Uuid],
+    rules: &MessageRules,
+) -> Result<(), FieldError> {
+    let max = rules.attachment_limit();
+    if attachments.len() > max {
+        return Err(FieldError::new(
+            field,
+            "too_many",
+            vec![("max", max.to_string())],
+        ));
+    }
+    Ok(())
+}
+
+/// Самый длинный черновик сообщения
+pub const MAX_DRAFT_LENGTH: usize = 10_000;
+
+/// Проверяет текст черновика: не длиннее MAX_DRAFT_LENGTH символов
+pub fn validate_draft(field: &str, value: &str) -> Result<String, FieldError> {
+    if text::grapheme_len(value) > MAX_DRAFT_LENGTH {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", MAX_DRAFT_LENGTH.to_string())],
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Самый длинный адрес аватара
+pub const MAX_AVATAR_URL_LENGTH: usize = 2048;
+/// Самый длинный текст о себе
+pub const MAX_BIO_LENGTH: usize = 500;
+/// Самый длинный текст статуса
+pub const MAX_STATUS_LENGTH: usize = 140;
+
+/// Проверяет поля профиля: адрес аватара - http(s) без пробелов, текст о себе может быть
+/// в несколько строк, статус - в одну
+///
+/// Пустые поля и поля из одних пробелов очищаются, тексты приводятся к NFC.
+/// Все ошибки сообщаются разом
+pub fn validate_profile(profile: &UserProfile) -> Result<UserProfile, Vec<FieldError>> {
+    let mut errors = vec![];
+    let mut check = |field: &str, value: &Option<String>, max: usize, allowed: &[char]| {
+        let value = value.as_deref().map(str::trim).filter(|v| !v.is_empty())?;
+        let value = text::normalize(value);
+        if text::grapheme_len(&value) > max {
+            errors.push(FieldError::new(
+                field,
+                "too_long",
+                vec![("max", max.to_string())],
+            ));
+            return None;
+        }
+        if text::has_control(&value, allowed) {
+            errors.push(FieldError::new(field, "control_characters", vec![]));
+            return None;
+        }
+        Some(value)
+    };
+    let avatar_url = check(
+        "avatar_url",
+        &profile.avatar_url,
+        MAX_AVATAR_URL_LENGTH,
+        &[],
+    );
+    let bio = check("bio", &profile.bio, MAX_BIO_LENGTH, &['\n', '\r', '\t']);
+    let status = check("status", &profile.status, MAX_STATUS_LENGTH, &[]);
+    if let Some(url) = &avatar_url {
+        let has_scheme = url.starts_with("https://") || url.starts_with("http://");
+        if !has_scheme || url.chars().any(char::is_whitespace) {
+            errors.push(FieldError::new("avatar_url", "invalid_url", vec![]));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(UserProfile {
+        avatar_url,
+        bio,
+        status,
+    })
+}
+
+/// Самое большое по модулю смещение местного времени от UTC в минутах
+pub const MAX_UTC_OFFSET_MINUTES: i16 = 14 * 60;
+
+/// Проверяет общие настройки уведомлений: границы часов "не беспокоить" - минуты внутри
+/// суток, смещение от UTC - не больше MAX_UTC_OFFSET_MINUTES по модулю
+///
+/// Все ошибки сообщаются разом
+pub fn validate_preferences(
+    preferences: &UserPreferences,
+) -> Result<UserPreferences, Vec<FieldError>> {
+    let mut errors = vec![];
+    if let Some(quiet) = &preferences.quiet_hours {
+        let last_minute = (QuietHours::DAY_MINUTES - 1).to_string();
+        for (field, value) in [
+            ("quiet_hours.start_minute", quiet.start_minute),
+            ("quiet_hours.end_minute", quiet.end_minute),
+        ] {
+            if value >= QuietHours::DAY_MINUTES {
+                errors.push(FieldError::new(
+                    field,
+                    "out_of_range",
+                    vec![("min", "0".into()), ("max", last_minute.clone())],
+                ));
+            }
+        }
+        if quiet.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            errors.push(FieldError::new(
+                "quiet_hours.utc_offset_minutes",
+                "out_of_range",
+                vec![
+                    ("min", (-MAX_UTC_OFFSET_MINUTES).to_string()),
+                    ("max", MAX_UTC_OFFSET_MINUTES.to_string()),
+                ],
+            ));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(preferences.clone())
+}
+
+/// Самое длинное имя файла вложения
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// Проверяет имя файла вложения: непустое, без управляющих символов и разделителей пути
+pub fn validate_file_name(field: &str, value: &str) -> Result<String, FieldError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(FieldError::new(
+            field,
+            "too_short",
+            vec![("min", "1".into())],
+        ));
+    }
+    if text::grapheme_len(value) > MAX_FILE_NAME_LENGTH {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", MAX_FILE_NAME_LENGTH.to_string())],
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(FieldError::new(field, "control_characters", vec![]));
+    }
+    if let Some(c) = value.chars().find(|c| matches!(c, '/' | '\\')) {
+        return Err(FieldError::new(
+            field,
+            "invalid_characters",
+            vec![("character", format!("{c:?}"))],
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Проверяет имя по правилам и возвращает его в том виде, в котором его нужно сохранить:
+/// обрезанным по rules.trim и приведенным к NFC
+pub fn validate_name(field: &str, value: &str, rules: &NameRules) -> Result<String, FieldError> {
+    let value = text::normalize(if rules.trim { value.trim() } else { value });
+    if text::has_control(&value, &[]) {
+        return Err(FieldError::new(field, "control_characters", vec![]));
+    }
+    let length = text::grapheme_len(&value);
+    if length < rules.min_length {
+        return Err(FieldError::new(
+            field,
+            "too_short",
+            vec![("min", rules.min_length.to_string())],
+        ));
+    }
+    if length > rules.max_length {
+        return Err(FieldError::new(
+            field,
+            "too_long",
+            vec![("max", rules.max_length.to_string())],
+        ));
+    }
+    if let Some(allowed) = &rules.allowed_symbols {
+        let is_allowed = |c: char| c.is_alphanumeric() || c == ' ' || allowed.contains(c);
+        if let Some(c) = value.chars().find(|&c| !is_allowed(c)) {
+            return Err(FieldError::new(
+                field,
+                "invalid_characters",
+                vec![("character", format!("{c:?}"))],
+            ));
+        }
+    }
+    Ok(value)
+}
@@ -11,12 +11,19 @@ use chat::{
         broker_actor::BrokerActor,
         database_actor::{self, DatabaseActor},
         redis_actor::RedisActor,
+        storage_actor::StorageActor,
     },
+    config::{Config, ConfigHandle},
     handlers::{
         add_user_to_chat, authorize_user, create_new_group_chat, create_new_private_chat,
-        data_types::Addresses, exit_chat, get_chat_info, get_user_chats, get_user_info,
+        data_types::{Addresses, ErrorResponse, Limits},
+        exit_chat, get_chat_info, get_limits, get_user_chats, get_user_info, reload_config,
+        set_user_profile,
+    },
+    middlewares::{
+        authenticator_middleware::{Authenticator, AuthenticatorMiddleware, Identity},
+        test_token_middleware::TestAuthMiddleware,
     },
-    middlewares::test_token_middleware::TestAuthMiddleware,
 };
 use serial_test::serial;
 use urlencoding::encode;
@@ -88,20 +95,18 @@ mod api_tests {
 
     fn create_new_user_request(user_name: &str, user_id: i64) -> actix_http::Request {
         let uri = uri!("/authorization?user_name={}", user_name);
-        let request = actix_web::test::TestRequest::post()
+        actix_web::test::TestRequest::post()
             .uri(&uri)
             .insert_header(("chat_user_id", user_id))
-            .to_request();
-        request
+            .to_request()
     }
 
     fn get_user_chats_request(user_id: i64) -> actix_http::Request {
         let uri = uri!("/chats");
-        let request = actix_web::test::TestRequest::get()
+        actix_web::test::TestRequest::get()
             .uri(&uri)
             .insert_header(("chat_user_id", user_id))
-            .to_request();
-        request
+            .to_request()
     }
 
     fn create_new_private_chat_request(
@@ -114,11 +119,17 @@ mod api_tests {
             &guest_id.to_string(),
             chat_name
         );
-        let req = actix_web::test::TestRequest::post()
+        actix_web::test::TestRequest::post()
             .uri(&uri)
             .insert_header(("chat_user_id", creator_id))
-            .to_request();
-        req
+            .to_request()
+    }
+
+    fn default_config() -> web::Data<ConfigHandle> {
+        web::Data::new(ConfigHandle::new(
+            std::path::PathBuf::from("config.json"),
+            Config::default(),
+        ))
     }
 
     async fn prepare_database() -> web::Data<chat::handlers::data_types::Addresses> {
@@ -139,9 +150,98 @@ mod api_tests {
             db: db.clone(),
             broker: broker.clone(),
             redis: redis.clone(),
+            storage: StorageActor::new(None).start(),
         };
-        let data = web::Data::new(addrs);
-        data
+        web::Data::new(addrs)
+    }
+
+    #[actix_web::test]
+    async fn get_limits_test() {
+        let mut config = Config::default();
+        config.dynamic.validation.message.max_attachments = 4;
+        config.database.max_chat_members = 50;
+        let app =
+            actix_web::test::init_service(App::new().service(get_limits).app_data(web::Data::new(
+                ConfigHandle::new(std::path::PathBuf::from("config.json"), config),
+            )))
+            .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/limits")
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let limits: Limits = actix_web::test::read_body_json(res).await;
+        assert_eq!(limits.max_message_length, 4000);
+        assert_eq!(limits.max_attachments_per_message, 4);
+        assert_eq!(limits.max_attachment_bytes, 10 * 1024 * 1024);
+        assert_eq!(limits.max_chat_members, 50);
+    }
+
+    #[actix_web::test]
+    async fn error_response_test() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .service(reload_config)
+                .app_data(default_config())
+                .wrap(TestAuthMiddleware),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/reload-config")
+            .insert_header(("chat_user_id", "1"))
+            .insert_header(("Accept-Language", "ru"))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let error: ErrorResponse = actix_web::test::read_body_json(res).await;
+        assert_eq!(error.error, "not_admin");
+        assert_eq!(error.message, "Пользователь не администратор");
+    }
+
+    /// Авторизация по заголовку, который ставит service mesh
+    struct MeshAuthenticator;
+
+    impl Authenticator for MeshAuthenticator {
+        fn authenticate(
+            &self,
+            req: &actix_web::dev::ServiceRequest,
+        ) -> Result<Identity, actix_web::HttpResponse> {
+            req.headers()
+                .get("x-mesh-user")
+                .and_then(|header| header.to_str().ok())
+                .and_then(|raw_value| raw_value.parse::<i64>().ok())
+                .map(|user_id| Identity {
+                    user_id,
+                    expires_at: None,
+                })
+                .ok_or_else(|| actix_web::HttpResponse::Forbidden().finish())
+        }
+    }
+
+    #[actix_web::test]
+    async fn custom_authenticator_test() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(AuthenticatorMiddleware::new(std::sync::Arc::new(
+                    MeshAuthenticator,
+                )))
+                .service(get_limits)
+                .app_data(default_config()),
+        )
+        .await;
+        // Тестовый заголовок другой схемы авторизации не подходит
+        let req = actix_web::test::TestRequest::get()
+            .uri("/limits")
+            .insert_header(("chat_user_id", "1"))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let req = actix_web::test::TestRequest::get()
+            .uri("/limits")
+            .insert_header(("x-mesh-user", "1"))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
     }
 
     #[actix_web::test]
@@ -154,6 +254,7 @@ mod api_tests {
                 .service(get_user_info)
                 .service(create_new_private_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -179,6 +280,48 @@ mod api_tests {
         assert_eq!(&result.name, "Test user 2");
     }
 
+    #[actix_web::test]
+    #[serial]
+    async fn set_user_profile_test() {
+        let data = prepare_database().await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .service(authorize_user)
+                .service(get_user_info)
+                .service(set_user_profile)
+                .app_data(data)
+                .app_data(default_config())
+                .wrap(TestAuthMiddleware),
+        )
+        .await;
+        let _r = app
+            .call(create_new_user_request("Test user 1", 1))
+            .await
+            .unwrap();
+        let req = actix_web::test::TestRequest::put()
+            .uri("/profile")
+            .insert_header(("chat_user_id", 1))
+            .set_json(serde_json::json!({"avatar_url": "ftp://example.com/a.png"}))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let req = actix_web::test::TestRequest::put()
+            .uri("/profile")
+            .insert_header(("chat_user_id", 1))
+            .set_json(serde_json::json!({"bio": "Люблю Rust", "status": "В отпуске"}))
+            .to_request();
+        let _r = app.call(req).await.unwrap();
+        let req = actix_web::test::TestRequest::get()
+            .uri(&uri!("/info?user_id={}", "1"))
+            .insert_header(("chat_user_id", 1))
+            .to_request();
+        let res = app.call(req).await.unwrap();
+        let result: UserInfoStripped = parse_response(res, StatusCode::OK).await.unwrap();
+        assert_eq!(result.profile.bio.as_deref(), Some("Люблю Rust"));
+        assert_eq!(result.profile.status.as_deref(), Some("В отпуске"));
+        assert_eq!(result.profile.avatar_url, None);
+    }
+
     #[actix_web::test]
     #[serial]
     async fn add_new_chat_member_test() {
@@ -190,6 +333,7 @@ mod api_tests {
                 .service(create_new_private_chat)
                 .service(add_user_to_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -235,6 +379,7 @@ mod api_tests {
                 .service(get_chat_info)
                 .service(create_new_private_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -274,6 +419,7 @@ mod api_tests {
                 .service(create_new_private_chat)
                 .service(exit_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -311,6 +457,7 @@ mod api_tests {
                 .service(get_user_chats)
                 .service(create_new_group_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -357,6 +504,7 @@ mod api_tests {
                 .service(get_user_chats)
                 .service(create_new_private_chat)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -395,6 +543,7 @@ mod api_tests {
                 .service(authorize_user)
                 .service(get_user_chats)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
@@ -423,6 +572,7 @@ mod api_tests {
             App::new()
                 .service(authorize_user)
                 .app_data(data)
+                .app_data(default_config())
                 .wrap(TestAuthMiddleware),
         )
         .await;
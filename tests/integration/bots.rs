@@ -0,0 +1,197 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chat::actors::websocket_actor::{ChatMessage, NewChatMessage};
+    use chat::bots::{deliver, retry_delay, sign, BotRoutes};
+    use chat::config::{BotsConfig, MessageRules};
+    use chat::database::data::{BotWebhook, UserInfo};
+    use chat::ids::{ChatId, UserId};
+    use chat::moderation::NoModeration;
+    use chat::services::compose_message;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use uuid::Uuid;
+
+    fn message(chat_id: Uuid, sender_id: i64) -> ChatMessage {
+        compose_message(
+            UserId(sender_id),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: "ping".into(),
+                reply_to: None,
+                attachments: vec![],
+                client_msg_id: Some("local-1".into()),
+            },
+            &MessageRules::default(),
+            &NoModeration,
+        )
+        .unwrap()
+    }
+
+    fn bot(user_id: i64, callback_url: String) -> BotWebhook {
+        BotWebhook {
+            user_id,
+            callback_url,
+            secret: "secret".into(),
+        }
+    }
+
+    fn user(id: i64, chats: Vec<Uuid>) -> UserInfo {
+        UserInfo {
+            id,
+            name: format!("Bot {id}"),
+            chats,
+            profile: Default::default(),
+        }
+    }
+
+    /// Отвечает на запросы по очереди статусами из statuses и возвращает запросы
+    async fn fake_bot(statuses: Vec<&'static str>) -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buffer = vec![0; 1024];
+                loop {
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        if body.len() >= length || n == 0 {
+                            break;
+                        }
+                    }
+                }
+                socket
+                    .write_all(format!("HTTP/1.0 {status}\r\n\r\n").as_bytes())
+                    .await
+                    .unwrap();
+                requests.push(String::from_utf8_lossy(&request).to_string());
+            }
+            requests
+        });
+        (port, server)
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> &'a str {
+        request
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", name.to_lowercase())))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signature() {
+        let signature = sign("secret", 1700000000, b"{}");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, sign("secret", 1700000000, b"{}"));
+        // Подпись зависит от секрета, времени и тела
+        assert_ne!(signature, sign("other", 1700000000, b"{}"));
+        assert_ne!(signature, sign("secret", 1700000001, b"{}"));
+        assert_ne!(signature, sign("secret", 1700000000, b"[]"));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let config = BotsConfig {
+            retry_delay_ms: 1000,
+            ..Default::default()
+        };
+        assert_eq!(retry_delay(&config, 2), Duration::from_secs(1));
+        assert_eq!(retry_delay(&config, 3), Duration::from_secs(2));
+        assert_eq!(retry_delay(&config, 5), Duration::from_secs(8));
+        assert_eq!(retry_delay(&config, 40), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_routes() {
+        let chat = Uuid::new_v4();
+        let other_chat = Uuid::new_v4();
+        let routes = BotRoutes::new(
+            vec![
+                bot(10, "http://a".into()),
+                bot(11, "http://b".into()),
+                // Удаленный пользователь
+                bot(12, "http://c".into()),
+            ],
+            &[user(10, vec![chat]), user(11, vec![chat, other_chat])],
+        );
+        let recipients = |message: ChatMessage| {
+            let mut ids: Vec<i64> = routes
+                .recipients(&message)
+                .into_iter()
+                .map(|bot| bot.user_id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(recipients(message(chat, 1)), vec![10, 11]);
+        assert_eq!(recipients(message(other_chat, 1)), vec![11]);
+        // Бот не получает свои сообщения
+        assert_eq!(recipients(message(chat, 10)), vec![11]);
+        assert!(recipients(message(Uuid::new_v4(), 1)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed_and_retried() {
+        let (port, server) = fake_bot(vec!["503 Service Unavailable", "200 OK"]).await;
+        let config = BotsConfig {
+            max_attempts: 3,
+            retry_delay_ms: 1,
+            ..Default::default()
+        };
+        let bot = bot(10, format!("http://127.0.0.1:{port}/hook"));
+        let message = message(Uuid::new_v4(), 1);
+        deliver(&bot, &message, &config, || 1700000000)
+            .await
+            .unwrap();
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            header(request, "X-Chat-Signature"),
+            sign("secret", 1700000000, body.as_bytes())
+        );
+        assert_eq!(header(request, "X-Chat-Timestamp"), "1700000000");
+        assert_eq!(
+            header(request, "X-Chat-Delivery"),
+            message.message_id.to_string()
+        );
+        let event: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(event["event"], "new_message");
+        assert_eq!(event["bot_id"], 10);
+        assert_eq!(event["message"]["msg_text"], "ping");
+        // Метка клиента отправителя боту не нужна
+        assert!(event["message"].get("client_msg_id").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delivery_gives_up() {
+        let (port, server) = fake_bot(vec!["500 Internal Server Error"; 2]).await;
+        let config = BotsConfig {
+            max_attempts: 2,
+            retry_delay_ms: 1,
+            ..Default::default()
+        };
+        let bot = bot(10, format!("http://127.0.0.1:{port}"));
+        assert!(deliver(&bot, &message(Uuid::new_v4(), 1), &config, || 0)
+            .await
+            .is_err());
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST / HTTP/1.1"));
+    }
+}
@@ -0,0 +1,115 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chat::actors::websocket_actor::ChatMessage;
+    use chat::calls::{ActiveCalls, CallEvent, CallEventKind};
+    use chat::ids::{ChatId, UserId};
+    use chat::services;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_call_event_kind() {
+        for kind in [
+            CallEventKind::Started,
+            CallEventKind::Missed,
+            CallEventKind::Ended,
+        ] {
+            assert_eq!(CallEventKind::parse(kind.as_str()), Some(kind));
+            assert_eq!(
+                serde_json::to_value(kind).unwrap(),
+                serde_json::json!(kind.as_str())
+            );
+        }
+        assert_eq!(CallEventKind::parse("ringing"), None);
+    }
+
+    #[test]
+    fn test_unanswered_call_is_missed() {
+        let (call_id, chat_id) = (Uuid::new_v4(), ChatId::new_v4());
+        let mut calls = ActiveCalls::new();
+        assert!(calls.start(call_id, chat_id));
+        // Повторный call_start не начинает звонок заново
+        assert!(!calls.start(call_id, chat_id));
+        assert_eq!(
+            calls.finish(call_id, Instant::now()),
+            Some((
+                chat_id,
+                CallEvent {
+                    call_id,
+                    kind: CallEventKind::Missed,
+                    duration_secs: None,
+                }
+            ))
+        );
+        assert!(!calls.is_active(call_id));
+        assert_eq!(calls.finish(call_id, Instant::now()), None);
+    }
+
+    #[test]
+    fn test_answered_call_duration() {
+        let (call_id, chat_id) = (Uuid::new_v4(), ChatId::new_v4());
+        let start = Instant::now();
+        let mut calls = ActiveCalls::new();
+        calls.start(call_id, chat_id);
+        calls.answer(call_id, start + Duration::from_secs(5));
+        // Разговор считается с первого ответа
+        calls.answer(call_id, start + Duration::from_secs(20));
+        let (_, event) = calls
+            .finish(call_id, start + Duration::from_secs(65))
+            .unwrap();
+        assert_eq!(event.kind, CallEventKind::Ended);
+        assert_eq!(event.duration_secs, Some(60));
+        // Ответ на чужой звонок ничего не начинает
+        calls.answer(Uuid::new_v4(), start);
+        assert!(calls.finish_all(start).is_empty());
+    }
+
+    #[test]
+    fn test_finish_all_calls() {
+        let mut calls = ActiveCalls::new();
+        let chats = [ChatId::new_v4(), ChatId::new_v4()];
+        for chat_id in chats {
+            calls.start(Uuid::new_v4(), chat_id);
+        }
+        let mut finished: Vec<ChatId> = calls
+            .finish_all(Instant::now())
+            .into_iter()
+            .map(|(chat_id, event)| {
+                assert_eq!(event.kind, CallEventKind::Missed);
+                chat_id
+            })
+            .collect();
+        finished.sort();
+        let mut expected = chats.to_vec();
+        expected.sort();
+        assert_eq!(finished, expected);
+    }
+
+    #[test]
+    fn test_call_message() {
+        let chat_id = ChatId::new_v4();
+        let call = CallEvent {
+            call_id: Uuid::new_v4(),
+            kind: CallEventKind::Ended,
+            duration_secs: Some(42),
+        };
+        let message = services::call_message(UserId(7), chat_id, call.clone());
+        assert_eq!(message.sender_id, 7);
+        assert!(message.msg_text.is_empty());
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["call"]["kind"], "ended");
+        assert_eq!(json["call"]["duration_secs"], 42);
+        let parsed: ChatMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.call, Some(call));
+        // У missed длительности нет
+        let missed = CallEvent {
+            kind: CallEventKind::Missed,
+            duration_secs: None,
+            ..message.call.clone().unwrap()
+        };
+        let json =
+            serde_json::to_value(services::call_message(UserId(7), chat_id, missed)).unwrap();
+        assert!(json["call"].get("duration_secs").is_none());
+    }
+}
@@ -0,0 +1,62 @@
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chat::config::NetworkConfig;
+    use chat::middlewares::client_ip_middleware::resolve_client_ip;
+    use std::net::IpAddr;
+
+    fn network(trusted: &[&str], allow: &[&str], deny: &[&str]) -> NetworkConfig {
+        NetworkConfig {
+            trusted_proxies: trusted.iter().map(|n| n.parse().unwrap()).collect(),
+            allow: allow.iter().map(|n| n.parse().unwrap()).collect(),
+            deny: deny.iter().map(|n| n.parse().unwrap()).collect(),
+        }
+    }
+
+    fn ip(raw: &str) -> IpAddr {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_are_ignored() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .to_http_request();
+        let config = network(&["10.0.0.0/8"], &[], &[]);
+        let resolved = resolve_client_ip(Some(ip("8.8.8.8")), req.headers(), &config);
+        assert_eq!(resolved, Some(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_skips_trusted_proxies() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "6.6.6.6, 1.1.1.1, 10.0.0.2"))
+            .to_http_request();
+        let config = network(&["10.0.0.0/8"], &[], &[]);
+        let resolved = resolve_client_ip(Some(ip("10.0.0.1")), req.headers(), &config);
+        assert_eq!(resolved, Some(ip("1.1.1.1")));
+    }
+
+    #[test]
+    fn test_forwarded_header_takes_precedence() {
+        let req = TestRequest::default()
+            .insert_header((
+                "Forwarded",
+                r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#,
+            ))
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .to_http_request();
+        let config = network(&["10.0.0.0/8"], &[], &[]);
+        let resolved = resolve_client_ip(Some(ip("10.0.0.1")), req.headers(), &config);
+        assert_eq!(resolved, Some(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let config = network(&[], &["192.168.0.0/16"], &["192.168.1.0/24"]);
+        assert!(config.is_allowed(&ip("192.168.2.1")));
+        assert!(!config.is_allowed(&ip("192.168.1.1")));
+        assert!(!config.is_allowed(&ip("8.8.8.8")));
+        assert!(network(&[], &[], &[]).is_allowed(&ip("8.8.8.8")));
+    }
+}
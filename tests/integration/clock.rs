@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod tests {
+    use chat::clock::HybridClock;
+    use chrono::Duration;
+
+    #[test]
+    fn test_clock_is_monotonic() {
+        let clock = HybridClock::new(Duration::seconds(60));
+        let first = clock.tick(Duration::milliseconds(10_000));
+        assert_eq!(first, Duration::milliseconds(10_000));
+        // Системные часы не сдвинулись или пошли назад, а время все равно растет
+        let second = clock.tick(Duration::milliseconds(10_000));
+        let third = clock.tick(Duration::milliseconds(9_000));
+        assert_eq!(second, Duration::milliseconds(10_001));
+        assert_eq!(third, Duration::milliseconds(10_002));
+        // Когда системные часы уходят вперед, часы идут за ними
+        assert_eq!(
+            clock.tick(Duration::milliseconds(20_000)),
+            Duration::milliseconds(20_000)
+        );
+    }
+
+    #[test]
+    fn test_clock_observes_other_instances() {
+        let clock = HybridClock::new(Duration::seconds(60));
+        // Другой экземпляр спешит на 5 секунд: наши сообщения должны оказаться позже его
+        assert!(clock.observe_at(
+            Duration::milliseconds(15_000),
+            Duration::milliseconds(10_000)
+        ));
+        assert_eq!(
+            clock.tick(Duration::milliseconds(10_001)),
+            Duration::milliseconds(15_001)
+        );
+        // Отставшее время ничего не меняет
+        assert!(clock.observe_at(
+            Duration::milliseconds(1_000),
+            Duration::milliseconds(10_002)
+        ));
+        assert_eq!(
+            clock.tick(Duration::milliseconds(10_002)),
+            Duration::milliseconds(15_002)
+        );
+    }
+
+    #[test]
+    fn test_clock_ignores_far_future() {
+        let clock = HybridClock::new(Duration::seconds(60));
+        assert!(!clock.observe_at(
+            Duration::milliseconds(10_000 + 61_000),
+            Duration::milliseconds(10_000)
+        ));
+        assert_eq!(
+            clock.tick(Duration::milliseconds(10_000)),
+            Duration::milliseconds(10_000)
+        );
+    }
+}
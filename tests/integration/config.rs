@@ -0,0 +1,198 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::{
+        Admission, Capabilities, Config, ConfigHandle, ContentProviderConfig, DuplicateLogin,
+        DuplicateLoginPolicy, HistoryLimits, MessageStorage, RateLimits, Replication,
+    };
+    use chrono::Duration;
+    use std::path::PathBuf;
+
+    fn temp_config_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chat_config_{}_{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_missing_config_uses_defaults() {
+        let config = Config::from_file(&temp_config_path("missing")).unwrap();
+        assert_eq!(config.database.port, 9042);
+        assert_eq!(config.redis.port, 6379);
+        assert_eq!(config.database.max_chat_members, 10_000);
+        assert!(config.dynamic.admins.is_empty());
+    }
+
+    #[test]
+    fn test_reload_replaces_dynamic_settings_only() {
+        let path = temp_config_path("reload");
+        std::fs::write(
+            &path,
+            r#"{"database": {"port": 1111}, "rate_limits": {"messages_per_minute": 5}}"#,
+        )
+        .unwrap();
+        let handle = ConfigHandle::load_from(path.clone()).unwrap();
+        assert_eq!(handle.current().rate_limits.messages_per_minute, 5);
+        assert!(!handle.current().is_feature_enabled("reactions"));
+
+        std::fs::write(
+            &path,
+            r#"{"database": {"port": 2222}, "feature_flags": {"reactions": true}, "admins": [7]}"#,
+        )
+        .unwrap();
+        handle.reload().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(handle.current().is_feature_enabled("reactions"));
+        assert!(handle.current().is_admin(7));
+        assert_eq!(handle.current().rate_limits.messages_per_minute, 60);
+        assert_eq!(handle.static_config().database.port, 1111);
+    }
+
+    #[test]
+    fn test_invalid_log_level_is_rejected() {
+        let path = temp_config_path("log_level");
+        std::fs::write(&path, r#"{"log_level": "verbose"}"#).unwrap();
+        assert!(Config::from_file(&path).is_err());
+
+        std::fs::write(&path, r#"{"log_level": "warn"}"#).unwrap();
+        let handle = ConfigHandle::load_from(path.clone()).unwrap();
+        assert_eq!(handle.current().log_filter(), Ok(log::LevelFilter::Warn));
+        // Неверный уровень при перезагрузке не подменяет действующие настройки
+        std::fs::write(&path, r#"{"log_level": "verbose"}"#).unwrap();
+        assert!(handle.reload().is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(handle.current().log_level, "warn");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.capabilities(&config.dynamic),
+            Capabilities {
+                reactions: false,
+                e2ee: false,
+                attachments: false,
+                search: false,
+                push: false,
+            }
+        );
+        config.storage.endpoint = "http://127.0.0.1:9000".into();
+        config.content.providers = vec![ContentProviderConfig::default()];
+        config
+            .dynamic
+            .feature_flags
+            .insert("reactions".into(), true);
+        config.dynamic.feature_flags.insert("push".into(), false);
+        let capabilities = config.capabilities(&config.dynamic);
+        assert!(capabilities.reactions && capabilities.attachments && capabilities.search);
+        assert!(!capabilities.e2ee && !capabilities.push);
+    }
+
+    #[test]
+    fn test_replication_settings() {
+        assert_eq!(
+            Replication::default().to_cql(),
+            "{'class': 'NetworkTopologyStrategy', 'replication_factor': 1}"
+        );
+        let config: Config = serde_json::from_str(
+            r#"{"database": {"keyspace": "chat_staging", "replication": {
+                "class": "NetworkTopologyStrategy", "datacenters": {"dc1": 3, "dc2": 2}
+            }}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.database.keyspace, "chat_staging");
+        assert_eq!(
+            config.database.replication.to_cql(),
+            "{'class': 'NetworkTopologyStrategy', 'dc1': 3, 'dc2': 2}"
+        );
+        let config: Config = serde_json::from_str(
+            r#"{"database": {"replication": {"class": "SimpleStrategy", "replication_factor": 3}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.database.replication.to_cql(),
+            "{'class': 'SimpleStrategy', 'replication_factor': 3}"
+        );
+    }
+
+    #[test]
+    fn test_message_storage_settings() {
+        let config = Config::default();
+        assert_eq!(config.database.messages, MessageStorage::default());
+        assert_eq!(
+            config.database.messages.to_cql(),
+            "compaction = {'class': 'TimeWindowCompactionStrategy', \
+            'compaction_window_unit': 'DAYS', 'compaction_window_size': 1} \
+            AND default_time_to_live = 0"
+        );
+        let config: Config = serde_json::from_str(
+            r#"{"database": {"messages": {"compaction_window_days": 7, "default_ttl_secs": 7776000}}}"#,
+        )
+        .unwrap();
+        assert!(config
+            .database
+            .messages
+            .to_cql()
+            .ends_with("'compaction_window_size': 7} AND default_time_to_live = 7776000"));
+        // Окно меньше суток не задается
+        let storage = MessageStorage {
+            compaction_window_days: 0,
+            default_ttl_secs: 0,
+        };
+        assert!(storage.to_cql().contains("'compaction_window_size': 1}"));
+    }
+
+    #[test]
+    fn test_new_account_limits() {
+        let limits: RateLimits = serde_json::from_str(
+            r#"{"messages_per_minute": 60, "chats_per_hour": 20,
+                "new_accounts": {"probation_secs": 1000, "initial_share": 0.1}}"#,
+        )
+        .unwrap();
+        assert_eq!(limits.messages_per_minute_for(Duration::zero()), 6);
+        assert_eq!(limits.chats_per_hour_for(Duration::zero()), 2);
+        assert_eq!(limits.messages_per_minute_for(Duration::seconds(500)), 33);
+        assert_eq!(limits.messages_per_minute_for(Duration::seconds(1000)), 60);
+        assert_eq!(limits.chats_per_hour_for(Duration::days(365)), 20);
+        // Часы экземпляров могут расходиться, аккаунт "из будущего" считается только что созданным
+        assert_eq!(limits.messages_per_minute_for(Duration::seconds(-5)), 6);
+        // Даже самому новому аккаунту доступно хотя бы одно действие
+        assert_eq!(limits.new_accounts.scale(3, Duration::zero()), 1);
+        assert_eq!(limits.new_accounts.scale(0, Duration::zero()), 0);
+    }
+
+    #[test]
+    fn test_history_page_size() {
+        let limits = HistoryLimits {
+            max_page_bytes: 10_000,
+            min_page_size: 5,
+        };
+        // Пока о чате ничего не известно, отдаем столько, сколько просят
+        assert_eq!(limits.page_size(50, None), 50);
+        assert_eq!(limits.page_size(50, Some(100)), 50);
+        assert_eq!(limits.page_size(50, Some(1000)), 10);
+        assert_eq!(limits.page_size(50, Some(1_000_000)), 5);
+        assert_eq!(limits.page_size(3, Some(1_000_000)), 3);
+    }
+
+    #[test]
+    fn test_duplicate_login_admission() {
+        let allow_all = DuplicateLogin::default();
+        assert_eq!(allow_all.admit(10), Admission::Accept { evict: 0 });
+
+        let kick_oldest = DuplicateLogin {
+            policy: DuplicateLoginPolicy::KickOldest,
+            max_sessions: 2,
+        };
+        assert_eq!(kick_oldest.admit(1), Admission::Accept { evict: 0 });
+        assert_eq!(kick_oldest.admit(2), Admission::Accept { evict: 1 });
+        // Лимит уменьшили, пока сокеты были открыты
+        assert_eq!(kick_oldest.admit(4), Admission::Accept { evict: 3 });
+
+        let deny_new = DuplicateLogin {
+            policy: DuplicateLoginPolicy::DenyNew,
+            max_sessions: 0,
+        };
+        assert_eq!(deny_new.admit(0), Admission::Accept { evict: 0 });
+        assert_eq!(deny_new.admit(1), Admission::Deny);
+    }
+}
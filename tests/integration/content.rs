@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use chat::content::{
+        parse_giphy_response, ContentDescriptor, ContentError, ContentKind, ContentProvider,
+        ContentProviders, GiphyProvider, HttpEndpoint,
+    };
+    use chat::http_client::MAX_RESPONSE_BYTES;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const GIPHY_RESPONSE: &str = r#"{"data": [
+        {"id": "abc", "title": "Cat", "images": {
+            "original": {"url": "http://media/abc.gif", "width": "480", "height": "270"},
+            "fixed_width_small": {"url": "http://media/abc_s.gif"}
+        }},
+        {"id": "broken", "title": "No images", "images": {}}
+    ], "pagination": {"count": 2}}"#;
+
+    struct FixedProvider;
+
+    #[async_trait::async_trait(?Send)]
+    impl ContentProvider for FixedProvider {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        async fn search(
+            &self,
+            kind: ContentKind,
+            query: &str,
+            limit: usize,
+        ) -> Result<Vec<ContentDescriptor>, ContentError> {
+            Ok((0..limit + 5)
+                .map(|i| ContentDescriptor {
+                    provider: self.name().into(),
+                    kind,
+                    id: format!("{query}{i}"),
+                    title: query.into(),
+                    url: format!("http://media/{i}"),
+                    preview_url: None,
+                    width: None,
+                    height: None,
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_endpoint_parsing() {
+        assert_eq!(
+            HttpEndpoint::parse("http://proxy:8080/giphy/").unwrap(),
+            HttpEndpoint {
+                tls: false,
+                host: "proxy".into(),
+                port: 8080,
+                prefix: "/giphy".into(),
+            }
+        );
+        assert_eq!(HttpEndpoint::parse("http://proxy").unwrap().port, 80);
+        let https = HttpEndpoint::parse("https://api.giphy.com").unwrap();
+        assert!(https.tls);
+        assert_eq!(https.port, 443);
+        assert_eq!(https.url("/v1"), "https://api.giphy.com/v1");
+        assert!(HttpEndpoint::parse("ftp://api.giphy.com").is_err());
+        assert!(HttpEndpoint::parse("http://proxy:port").is_err());
+    }
+
+    #[test]
+    fn test_giphy_response_parsing() {
+        let results = parse_giphy_response("giphy", ContentKind::Gif, GIPHY_RESPONSE).unwrap();
+        assert_eq!(
+            results,
+            vec![ContentDescriptor {
+                provider: "giphy".into(),
+                kind: ContentKind::Gif,
+                id: "abc".into(),
+                title: "Cat".into(),
+                url: "http://media/abc.gif".into(),
+                preview_url: Some("http://media/abc_s.gif".into()),
+                width: Some(480),
+                height: Some(270),
+            }]
+        );
+        assert!(parse_giphy_response("giphy", ContentKind::Gif, "not json").is_err());
+    }
+
+    #[test]
+    fn test_giphy_search_path() {
+        let provider = GiphyProvider::new(
+            HttpEndpoint::parse("http://proxy").unwrap(),
+            "key".into(),
+            "g".into(),
+            Duration::from_secs(1),
+        );
+        assert_eq!(
+            provider.search_path(ContentKind::Sticker, "happy cat&", 5),
+            "/v1/stickers/search?api_key=key&q=happy%20cat%26&limit=5&rating=g"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_limits_results() {
+        let mut providers = ContentProviders::new(3);
+        providers.register(ContentKind::Gif, Arc::new(FixedProvider));
+        let results = providers.search(ContentKind::Gif, "cat", 10).await.unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            providers.search(ContentKind::Sticker, "cat", 10).await,
+            Err(ContentError::NotConfigured(ContentKind::Sticker))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_giphy_provider_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let n = socket.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_string();
+            socket
+                .write_all(format!("HTTP/1.0 200 OK\r\n\r\n{GIPHY_RESPONSE}").as_bytes())
+                .await
+                .unwrap();
+            request
+        });
+        let provider = GiphyProvider::new(
+            HttpEndpoint::parse(&format!("http://127.0.0.1:{port}/proxy")).unwrap(),
+            "key".into(),
+            "g".into(),
+            Duration::from_secs(5),
+        );
+        let results = provider.search(ContentKind::Gif, "cat", 2).await.unwrap();
+        assert_eq!(results.len(), 1);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /proxy/v1/gifs/search?api_key=key&q=cat&limit=2"));
+    }
+
+    /// Отвечает на один запрос заголовком head и телом из length байт
+    async fn fake_api(head: &'static str, length: usize) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket.write_all(head.as_bytes()).await.unwrap();
+            let _ = socket.write_all(&vec![b'x'; length]).await;
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_http_response_is_limited() {
+        let port = fake_api("HTTP/1.1 200 OK\r\n\r\n", MAX_RESPONSE_BYTES + 1).await;
+        let endpoint = HttpEndpoint::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        assert!(endpoint.get("/", Duration::from_secs(5)).await.is_err());
+        // Слишком длинный ответ отклоняется по заголовку, не дожидаясь тела
+        let port = fake_api("HTTP/1.1 200 OK\r\nContent-Length: 99999999\r\n\r\n", 0).await;
+        let endpoint = HttpEndpoint::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        assert!(endpoint.get("/", Duration::from_secs(5)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_request_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Сервер принимает соединение и молчит
+        let server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let endpoint = HttpEndpoint::parse(&format!("http://127.0.0.1:{port}")).unwrap();
+        assert!(endpoint.get("/", Duration::from_millis(200)).await.is_err());
+        server.abort();
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(test)]
+mod tests {
+    use chat::coordination::{spawn_singleton_job, RedisLock};
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[actix::test]
+    #[serial]
+    async fn test_lock_is_exclusive() {
+        let first = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        let second = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        let ttl = Duration::from_secs(10);
+        first.release("test job").await.unwrap();
+        second.release("test job").await.unwrap();
+
+        assert!(first.try_acquire("test job", ttl).await.unwrap());
+        assert!(!second.try_acquire("test job", ttl).await.unwrap());
+        // Владелец может продлить свою блокировку
+        assert!(first.try_acquire("test job", ttl).await.unwrap());
+        // Чужую блокировку отпустить нельзя
+        assert!(!second.release("test job").await.unwrap());
+
+        assert!(first.release("test job").await.unwrap());
+        assert!(second.try_acquire("test job", ttl).await.unwrap());
+        assert!(second.release("test job").await.unwrap());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_lock_expires() {
+        let first = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        let second = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        assert!(first
+            .try_acquire("expiring job", Duration::from_millis(100))
+            .await
+            .unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(second
+            .try_acquire("expiring job", Duration::from_secs(10))
+            .await
+            .unwrap());
+        second.release("expiring job").await.unwrap();
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_long_job_keeps_its_lock() {
+        let first = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        let second = RedisLock::new("127.0.0.1", 6379).await.unwrap();
+        first.release("long job").await.unwrap();
+        second.release("long job").await.unwrap();
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        for lock in [first, second] {
+            let (running, most_running) = (running.clone(), most_running.clone());
+            // Задача идет дольше, чем живет блокировка без продления
+            spawn_singleton_job(lock, "long job", Duration::from_millis(100), move || {
+                let (running, most_running) = (running.clone(), most_running.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(most_running.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,12 +1,25 @@
 #[cfg(test)]
 mod tests {
     use chat::actors::websocket_actor::ChatMessage;
-    use chat::database::data::ChatType;
-    use chat::database::{Database, ScyllaDatabase};
+    use chat::calls::{CallEvent, CallEventKind};
+    use chat::config::{DatabaseConfig, MessageStorage};
+    use chat::database::data::{
+        Attachment, BotWebhook, ChatEventKind, ChatLabels, ChatPermissions, ChatRole, ChatType,
+        Mute, NotificationPriority, NotificationSettings, PostPolicy, QuietHours, ReadPosition,
+        SecretKind, UnpinReason, UnpinnedMessage, UserPreferences,
+    };
+    use chat::database::{
+        BlockedError, ChatCreationError, DBError, Database, MemberLimitError, NameTakenError,
+        ScyllaDatabase, StringError,
+    };
+    use chat::ids::{ChatId, UserId};
     use chat::serializable_duration::SerializableDuration;
+    use chat::services;
     use chrono::Duration;
+    use scylla::frame::value::Timestamp;
     use scylla::{FromRow, Session};
     use serial_test::serial;
+    use std::collections::HashMap;
     use std::error::Error;
     use testcontainers::clients::Cli;
     use testcontainers::core::WaitFor;
@@ -16,7 +29,6 @@ mod tests {
     #[derive(FromRow)]
     struct ChatsRow {
         chat_id: Uuid,
-        creation_date: SerializableDuration,
         name: String,
         users: Option<Vec<i64>>,
         chat_type: String,
@@ -25,7 +37,6 @@ mod tests {
     #[derive(FromRow)]
     struct UsersRow {
         user_id: i64,
-        creation_date: SerializableDuration,
         name: String,
         chats: Option<Vec<Uuid>>,
     }
@@ -113,7 +124,7 @@ mod tests {
     async fn select_data_from_chats(client: &Session) -> Result<Vec<ChatsRow>, Box<dyn Error>> {
         let rows: Result<Vec<_>, _> = client
             .query(
-                r#"SELECT chat_id, creation_date, name, users, chat_type FROM chat.chats"#,
+                r#"SELECT chat_id, name, users, chat_type FROM chat.chats"#,
                 &[],
             )
             .await?
@@ -129,27 +140,36 @@ mod tests {
 
     async fn select_data_from_users(client: &Session) -> Result<Vec<UsersRow>, Box<dyn Error>> {
         let rows: Result<Vec<_>, _> = client
-            .query(
-                r#"SELECT user_id, creation_date, name, chats FROM chat.users"#,
-                &[],
-            )
+            .query(r#"SELECT user_id, name, chats FROM chat.users"#, &[])
             .await?
             .rows_typed_or_empty::<UsersRow>()
             .collect();
         Ok(rows?)
     }
 
+    /// Уплотнение и срок хранения таблицы сообщений
+    async fn messages_table_options(client: &Session) -> (HashMap<String, String>, i32) {
+        client
+            .query(
+                "SELECT compaction, default_time_to_live FROM system_schema.tables \
+                WHERE keyspace_name = 'chat' AND table_name = 'messages'",
+                &[],
+            )
+            .await
+            .unwrap()
+            .single_row_typed()
+            .unwrap()
+    }
+
     async fn select_messages_from_chat(
         client: &Session,
         chat_id: Uuid,
     ) -> Result<Vec<MessageRow>, Box<dyn Error>> {
-        let i = chat_id.to_string().replace("-", "_");
-        let q = format!(
-            "SELECT message_id, user_id, date, message_text FROM chat.chat_{}",
-            i
-        );
         let rows: Result<Vec<_>, _> = client
-            .query(q, &[])
+            .query(
+                "SELECT message_id, user_id, date, message_text FROM chat.messages WHERE chat_id = ?",
+                (chat_id,),
+            )
             .await?
             .rows_typed_or_empty::<MessageRow>()
             .collect();
@@ -172,16 +192,16 @@ mod tests {
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_chats_table_empty,
+        assert!(
+            is_chats_table_empty,
             "Chats table is not empty on db startup"
         );
         let is_users_table_empty = select_data_from_users(&database.client)
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_users_table_empty,
+        assert!(
+            is_users_table_empty,
             "Users table is not empty on db startup"
         );
         insert_data_into_chats(&database.client, "Test chat", vec![1, 2, 3], "Group")
@@ -195,18 +215,12 @@ mod tests {
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            false, is_chats_table_empty,
-            "Chats table is empty on db startup"
-        );
+        assert!(!is_chats_table_empty, "Chats table is empty on db startup");
         let is_users_table_empty = select_data_from_users(&database.client)
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            false, is_users_table_empty,
-            "Users table is empty on db startup"
-        );
+        assert!(!is_users_table_empty, "Users table is empty on db startup");
     }
 
     #[actix::test]
@@ -225,16 +239,16 @@ mod tests {
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_chats_table_empty,
+        assert!(
+            is_chats_table_empty,
             "Chats table is not empty on db startup"
         );
         let is_users_table_empty = select_data_from_users(&database.client)
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_users_table_empty,
+        assert!(
+            is_users_table_empty,
             "Users table is not empty on db startup"
         );
         insert_data_into_chats(&database.client, "Test chat", vec![1, 2, 3], "Group")
@@ -248,16 +262,16 @@ mod tests {
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_chats_table_empty,
+        assert!(
+            is_chats_table_empty,
             "Chats table is not empty on db startup"
         );
         let is_users_table_empty = select_data_from_users(&database.client)
             .await
             .unwrap()
             .is_empty();
-        assert_eq!(
-            true, is_users_table_empty,
+        assert!(
+            is_users_table_empty,
             "Users table is not empty on db startup"
         );
     }
@@ -278,21 +292,26 @@ mod tests {
         database.init_db_clear().await.unwrap();
 
         // Вставляем данные о пользователях
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 3, "Invited Test user 2".into(), vec![])
+        insert_data_into_users(&database.client, 3, "Invited Test user 2", vec![])
             .await
             .unwrap();
 
         // Создаем новый чат
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Private, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Private,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
@@ -306,7 +325,7 @@ mod tests {
 
         // Получаем данные о пользователях из базы
         let mut users = select_data_from_users(&database.client).await.unwrap();
-        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users.sort_by_key(|a| a.user_id);
         let mut users = users.into_iter();
 
         let (user_1, user_2) = (users.next().unwrap(), users.next().unwrap());
@@ -327,7 +346,12 @@ mod tests {
         assert_eq!("private", &chat.chat_type);
 
         let new_chat_info = database
-            .create_new_chat(2, vec![1, 3], ChatType::Group, "Test group chat".into())
+            .create_new_chat(
+                UserId(2),
+                vec![UserId(1), UserId(3)],
+                ChatType::Group,
+                "Test group chat".into(),
+            )
             .await
             .unwrap();
 
@@ -338,7 +362,7 @@ mod tests {
         assert!(messages.is_empty());
 
         let mut users = select_data_from_users(&database.client).await.unwrap();
-        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users.sort_by_key(|a| a.user_id);
         let mut users = users.into_iter();
 
         let (user_1, user_2, user_3) = (
@@ -364,6 +388,118 @@ mod tests {
         assert_eq!("Invited Test user 2", &user_3.name);
         assert_eq!("Test group chat", &chat.name);
         assert_eq!("group", &chat.chat_type);
+        assert_eq!(chat_users.len(), 3);
+
+        // Создатель в списке приглашенных и повторы не дают лишних участников,
+        // а создатель сразу становится владельцем
+        let new_chat_info = database
+            .create_new_chat(
+                UserId(3),
+                vec![UserId(3), UserId(1), UserId(1)],
+                ChatType::Group,
+                "Test duplicates".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(new_chat_info.role, ChatRole::Owner);
+        let chats = select_data_from_chats(&database.client).await.unwrap();
+        let chat = chats
+            .into_iter()
+            .find(|chat| chat.chat_id == new_chat_info.id)
+            .unwrap();
+        let mut chat_users = chat.users.unwrap();
+        chat_users.sort();
+        assert_eq!(chat_users, vec![1, 3]);
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(new_chat_info.id))
+                .await
+                .unwrap()
+                .role,
+            ChatRole::Member
+        );
+
+        // В приватном чате не может быть троих
+        let error = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3)],
+                ChatType::Private,
+                "Test private trio".into(),
+            )
+            .await
+            .unwrap_err();
+        let DBError::LogicError(error) = error else {
+            panic!("Unexpected error {error}");
+        };
+        assert_eq!(
+            error.downcast_ref::<StringError>().unwrap().code,
+            "private_chat_members"
+        );
+        assert!(select_data_from_chats(&database.client)
+            .await
+            .unwrap()
+            .iter()
+            .all(|chat| chat.name != "Test private trio"));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_failed_chat_creation_leaves_no_rows() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db_clear().await.unwrap();
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
+            .await
+            .unwrap();
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
+            .await
+            .unwrap();
+
+        // Пакет с именем больше batch_size_fail_threshold_in_kb Scylla не принимает
+        let error = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "x".repeat(2 * 1024 * 1024),
+            )
+            .await
+            .unwrap_err();
+        let DBError::QueryError(error) = error else {
+            panic!("Unexpected error {error}");
+        };
+        let error = error
+            .downcast_ref::<ChatCreationError>()
+            .expect("Chat creation error");
+        assert!(error.rolled_back);
+        let chat_id = error.chat_id.get();
+
+        assert!(select_data_from_chats(&database.client)
+            .await
+            .unwrap()
+            .iter()
+            .all(|chat| chat.chat_id != chat_id));
+        let members = database
+            .client
+            .query(
+                "SELECT user_id FROM chat.chat_members WHERE chat_id = ?",
+                (chat_id,),
+            )
+            .await
+            .unwrap()
+            .rows_typed_or_empty::<(i64,)>()
+            .count();
+        assert_eq!(members, 0);
+        for user in select_data_from_users(&database.client).await.unwrap() {
+            assert!(!user.chats.unwrap_or_default().contains(&chat_id));
+        }
     }
 
     #[actix::test]
@@ -379,16 +515,21 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Private, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Private,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
         let messages = select_messages_from_chat(&database.client, chat_info.id)
@@ -398,11 +539,20 @@ mod tests {
 
         let new_message = ChatMessage {
             chat_id: chat_info.id,
+            message_id: Uuid::new_v4(),
             sender_id: 1,
             date: SerializableDuration {
                 timestamp: Duration::seconds(10),
             },
             msg_text: "Hello".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
         };
         database.add_new_message_to_chat(new_message).await.unwrap();
         let messages = select_messages_from_chat(&database.client, chat_info.id)
@@ -426,25 +576,30 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 3, "Invited Test user 2".into(), vec![])
+        insert_data_into_users(&database.client, 3, "Invited Test user 2", vec![])
             .await
             .unwrap();
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
         database
-            .add_user_to_chat(1, 3, new_chat_info.id)
+            .add_user_to_chat(UserId(1), UserId(3), ChatId(new_chat_info.id))
             .await
             .unwrap();
 
@@ -460,7 +615,7 @@ mod tests {
         assert!(chat_users.contains(&1));
 
         let mut users = select_data_from_users(&database.client).await.unwrap();
-        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users.sort_by_key(|a| a.user_id);
         let mut users = users.into_iter();
 
         let (user_1, user_2, user_3) = (
@@ -487,23 +642,31 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
-        database.exit_chat(1, new_chat_info.id).await.unwrap();
+        database
+            .exit_chat(UserId(1), ChatId(new_chat_info.id))
+            .await
+            .unwrap();
 
         let mut users = select_data_from_users(&database.client).await.unwrap();
-        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users.sort_by_key(|a| a.user_id);
         let mut users = users.into_iter();
 
         let (user_1, user_2) = (users.next().unwrap(), users.next().unwrap());
@@ -520,7 +683,10 @@ mod tests {
         assert!(user_1.chats.is_none());
         assert!(user_2.chats.unwrap().contains(&new_chat_info.id));
 
-        database.exit_chat(2, new_chat_info.id).await.unwrap();
+        database
+            .exit_chat(UserId(2), ChatId(new_chat_info.id))
+            .await
+            .unwrap();
         let is_chat_present = select_data_from_chats(&database.client)
             .await
             .unwrap()
@@ -528,10 +694,11 @@ mod tests {
             .find(|c| c.chat_id == new_chat_info.id)
             .is_some();
         assert!(!is_chat_present);
-        let is_chat_history_present = select_messages_from_chat(&database.client, new_chat_info.id)
+        let is_chat_history_empty = select_messages_from_chat(&database.client, new_chat_info.id)
             .await
-            .is_ok();
-        assert!(!is_chat_history_present);
+            .unwrap()
+            .is_empty();
+        assert!(is_chat_history_empty);
     }
 
     #[actix::test]
@@ -547,20 +714,28 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
-        database.delete_chat(new_chat_info.id).await.unwrap();
+        database
+            .delete_chat(ChatId(new_chat_info.id))
+            .await
+            .unwrap();
 
         let is_chat_present = select_data_from_chats(&database.client)
             .await
@@ -569,10 +744,11 @@ mod tests {
             .find(|c| c.chat_id == new_chat_info.id)
             .is_some();
         assert!(!is_chat_present);
-        let is_chat_history_present = select_messages_from_chat(&database.client, new_chat_info.id)
+        let is_chat_history_empty = select_messages_from_chat(&database.client, new_chat_info.id)
             .await
-            .is_ok();
-        assert!(!is_chat_history_present);
+            .unwrap()
+            .is_empty();
+        assert!(is_chat_history_empty);
     }
 
     #[actix::test]
@@ -588,20 +764,28 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
-        let chat_info = database.get_chat_info(1, new_chat_info.id).await.unwrap();
+        let chat_info = database
+            .get_chat_info(UserId(1), ChatId(new_chat_info.id))
+            .await
+            .unwrap();
         assert_eq!(new_chat_info.id, chat_info.id);
         assert_eq!(new_chat_info.users, chat_info.users);
         assert_eq!(new_chat_info.name, chat_info.name);
@@ -626,26 +810,31 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
-        let user_info = database.get_user_info(1).await.unwrap();
+        let user_info = database.get_user_info(UserId(1)).await.unwrap();
 
         assert_eq!(user_info.id, 1);
         assert_eq!(user_info.name, "Test user");
         assert_eq!(user_info.chats, vec!());
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
-        let user_info = database.get_user_info(1).await.unwrap();
+        let user_info = database.get_user_info(UserId(1)).await.unwrap();
 
         assert_eq!(user_info.id, 1);
         assert_eq!(user_info.name, "Test user");
@@ -666,7 +855,7 @@ mod tests {
         database.init_db_clear().await.unwrap();
 
         let user_info = database
-            .create_new_user(1, "Test user".into())
+            .create_new_user(UserId(1), "Test user".into())
             .await
             .unwrap();
         assert_eq!(user_info.id, 1);
@@ -674,7 +863,7 @@ mod tests {
         assert_eq!(user_info.chats, vec!());
 
         let user_info = database
-            .create_new_user(1, "Test usar".into())
+            .create_new_user(UserId(1), "Test usar".into())
             .await
             .unwrap();
         assert_eq!(user_info.id, 1);
@@ -695,24 +884,29 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
-        let user_chats = database.get_user_chats(1).await.unwrap();
+        let user_chats = database.get_user_chats(UserId(1)).await.unwrap();
         assert!(user_chats.is_empty());
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
-        let user_chats = database.get_user_chats(1).await.unwrap();
-        assert_eq!(vec!(new_chat_info.id), user_chats);
+        let user_chats = database.get_user_chats(UserId(1)).await.unwrap();
+        assert_eq!(vec!(ChatId(new_chat_info.id)), user_chats);
     }
 
     #[actix::test]
@@ -731,17 +925,17 @@ mod tests {
         let list = database.get_user_list().await.unwrap();
         assert!(list.is_empty());
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let mut list = database.get_user_list().await.unwrap();
         list.sort();
-        assert_eq!(vec!(1, 2), list);
+        assert_eq!(vec!(UserId(1), UserId(2)), list);
     }
 
     #[actix::test]
@@ -757,21 +951,26 @@ mod tests {
         clear_database(&database.client).await.unwrap();
         database.init_db_clear().await.unwrap();
 
-        insert_data_into_users(&database.client, 1, "Test user".into(), vec![])
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
             .await
             .unwrap();
 
-        insert_data_into_users(&database.client, 2, "Invited Test user".into(), vec![])
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
             .await
             .unwrap();
 
         let new_chat_info = database
-            .create_new_chat(1, vec![2], ChatType::Group, "Test chat".into())
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
             .await
             .unwrap();
 
         let (messages, _index) = database
-            .get_chat_history_paged(1, new_chat_info.id, 10, None)
+            .get_chat_history_paged(UserId(1), ChatId(new_chat_info.id), 10, None)
             .await
             .unwrap();
 
@@ -781,18 +980,27 @@ mod tests {
             database
                 .add_new_message_to_chat(ChatMessage {
                     chat_id: new_chat_info.id,
+                    message_id: Uuid::new_v4(),
                     sender_id: 1,
                     date: SerializableDuration {
-                        timestamp: Duration::seconds(10),
+                        timestamp: Duration::seconds(10 + i),
                     },
                     msg_text: format!("{i}"),
+                    edited_at: None,
+                    reply_to: None,
+                    attachments: vec![],
+                    forwarded_from: None,
+                    mentions: vec![],
+                    client_msg_id: None,
+                    delivery_id: None,
+                    call: None,
                 })
                 .await
                 .unwrap();
         }
 
         let (messages, index) = database
-            .get_chat_history_paged(1, new_chat_info.id, 10, None)
+            .get_chat_history_paged(UserId(1), ChatId(new_chat_info.id), 10, None)
             .await
             .unwrap();
 
@@ -801,7 +1009,7 @@ mod tests {
         assert_eq!("10", &messages.last().unwrap().msg_text);
 
         let (messages, index) = database
-            .get_chat_history_paged(1, new_chat_info.id, 10, Some(index))
+            .get_chat_history_paged(UserId(1), ChatId(new_chat_info.id), 10, Some(index))
             .await
             .unwrap();
 
@@ -810,10 +1018,3231 @@ mod tests {
         assert_eq!("0", &messages.last().unwrap().msg_text);
 
         let (messages, _index) = database
-            .get_chat_history_paged(1, new_chat_info.id, 10, Some(index))
+            .get_chat_history_paged(UserId(1), ChatId(new_chat_info.id), 10, Some(index))
             .await
             .unwrap();
 
         assert!(messages.is_empty());
     }
+
+    #[actix::test]
+    #[serial]
+    async fn test_invite_codes() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db_clear().await.unwrap();
+
+        insert_data_into_users(&database.client, 1, "Test user", vec![])
+            .await
+            .unwrap();
+
+        insert_data_into_users(&database.client, 2, "Invited Test user", vec![])
+            .await
+            .unwrap();
+
+        insert_data_into_users(&database.client, 3, "Invited Test user 2", vec![])
+            .await
+            .unwrap();
+
+        let new_chat_info = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+
+        // Выпустить код может только участник чата
+        assert!(database
+            .rotate_chat_secret(UserId(3), ChatId(new_chat_info.id), SecretKind::InviteCode)
+            .await
+            .is_err());
+
+        let old_code = database
+            .rotate_chat_secret(UserId(1), ChatId(new_chat_info.id), SecretKind::InviteCode)
+            .await
+            .unwrap();
+        let code = database
+            .rotate_chat_secret(UserId(1), ChatId(new_chat_info.id), SecretKind::InviteCode)
+            .await
+            .unwrap();
+
+        // Старый код после ротации не работает, код вебхука не подходит для приглашения
+        assert!(database
+            .join_chat_by_invite(UserId(3), ChatId(new_chat_info.id), old_code)
+            .await
+            .is_err());
+        assert!(!database
+            .verify_chat_secret(
+                ChatId(new_chat_info.id),
+                SecretKind::WebhookToken,
+                code.clone()
+            )
+            .await
+            .unwrap());
+
+        let chat_info = database
+            .join_chat_by_invite(UserId(3), ChatId(new_chat_info.id), code.clone())
+            .await
+            .unwrap();
+        assert!(chat_info.users.contains(&3));
+
+        database
+            .revoke_chat_secret(UserId(1), ChatId(new_chat_info.id), SecretKind::InviteCode)
+            .await
+            .unwrap();
+        assert!(!database
+            .verify_chat_secret(ChatId(new_chat_info.id), SecretKind::InviteCode, code)
+            .await
+            .unwrap());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_schema_check() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        assert!(!database.check_schema().await.unwrap().is_empty());
+        database.init_db().await.unwrap();
+        assert_eq!(database.check_schema().await.unwrap(), Vec::<String>::new());
+        database
+            .client
+            .query(
+                "UPDATE chat.schema_version SET version = 1000 WHERE id = 0",
+                &[],
+            )
+            .await
+            .unwrap();
+        database.init_db().await.unwrap();
+        assert_eq!(database.check_schema().await.unwrap().len(), 1);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_chat_tables_migration() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        insert_data_into_chats(&database.client, "Old chat", vec![1, 2], "Group")
+            .await
+            .unwrap();
+        let chat_id = select_data_from_chats(&database.client).await.unwrap()[0].chat_id;
+        // Таблица сообщений чата в том виде, в каком ее создавала схема версии 32
+        let table = format!("chat.chat_{}", chat_id.to_string().replace("-", "_"));
+        database
+            .client
+            .query(
+                format!(
+                    "CREATE TABLE {table} (message_id UUID, user_id BIGINT, date TIMESTAMP, \
+                    message_text TEXT, yes BOOLEAN, edited_at TIMESTAMP, reply_to UUID, \
+                    attachments LIST<UUID>, forwarded_chat_id UUID, forwarded_message_id UUID, \
+                    forwarded_sender_id BIGINT, mentions LIST<BIGINT>, call_id UUID, \
+                    call_event TEXT, call_duration INT, PRIMARY KEY (yes, date, message_id)) \
+                    WITH CLUSTERING ORDER BY (date desc)"
+                ),
+                &[],
+            )
+            .await
+            .unwrap();
+        let (old, disappearing) = (Uuid::new_v4(), Uuid::new_v4());
+        database
+            .client
+            .query(
+                format!(
+                    "INSERT INTO {table} (yes, date, message_id, user_id, message_text) \
+                    VALUES (true, ?, ?, 1, 'old')"
+                ),
+                (Timestamp(Duration::days(3)), old),
+            )
+            .await
+            .unwrap();
+        database
+            .client
+            .query(
+                format!(
+                    "INSERT INTO {table} (yes, date, message_id, user_id, message_text) \
+                    VALUES (true, ?, ?, 2, 'disappearing') USING TTL 3600"
+                ),
+                (Timestamp(Duration::days(5)), disappearing),
+            )
+            .await
+            .unwrap();
+        database
+            .client
+            .query(
+                "UPDATE chat.schema_version SET version = 32 WHERE id = 0",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        database.init_db().await.unwrap();
+        assert_eq!(database.check_schema().await.unwrap(), Vec::<String>::new());
+        let messages = select_messages_from_chat(&database.client, chat_id)
+            .await
+            .unwrap();
+        let ids: Vec<_> = messages.iter().map(|msg| msg.message_id).collect();
+        assert_eq!(ids, vec![disappearing, old]);
+        assert_eq!(messages[1].user_id, 1);
+        assert_eq!(messages[1].message_text, "old");
+        assert_eq!(messages[1].date.timestamp, Duration::days(3));
+        let (ttl,): (Option<i32>,) = database
+            .client
+            .query(
+                "SELECT TTL(message_text) FROM chat.messages WHERE chat_id = ? AND bucket = 5 \
+                AND date = ? AND message_id = ?",
+                (chat_id, Timestamp(Duration::days(5)), disappearing),
+            )
+            .await
+            .unwrap()
+            .single_row_typed()
+            .unwrap();
+        assert!(ttl.is_some_and(|ttl| ttl <= 3600));
+        // Старая таблица удалена
+        assert!(database
+            .client
+            .query(format!("SELECT * FROM {table}"), &[])
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_message_storage() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let config = DatabaseConfig {
+            host: "localhost".into(),
+            port,
+            messages: MessageStorage {
+                compaction_window_days: 7,
+                default_ttl_secs: 86400,
+            },
+            ..Default::default()
+        };
+        let database = ScyllaDatabase::connect(&config).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Chat".into())
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Hi".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        // Сообщение чата без своего срока живет столько, сколько задано в конфигурации
+        let (ttl,): (Option<i32>,) = database
+            .client
+            .query(
+                "SELECT TTL(message_text) FROM chat.messages WHERE chat_id = ? AND bucket = 0 \
+                AND date = ? AND message_id = ?",
+                (
+                    chat.id,
+                    Timestamp(Duration::seconds(10)),
+                    message.message_id,
+                ),
+            )
+            .await
+            .unwrap()
+            .single_row_typed()
+            .unwrap();
+        assert!(ttl.is_some_and(|ttl| ttl > 0 && ttl <= 86400));
+        let (compaction, default_ttl) = messages_table_options(&database.client).await;
+        assert!(compaction["class"].ends_with("TimeWindowCompactionStrategy"));
+        assert_eq!(compaction["compaction_window_size"], "7");
+        assert_eq!(default_ttl, 86400);
+
+        // Новые настройки применяются к уже созданной таблице при следующем запуске
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        database.init_db().await.unwrap();
+        let (compaction, default_ttl) = messages_table_options(&database.client).await;
+        assert_eq!(compaction["compaction_window_size"], "1");
+        assert_eq!(default_ttl, 0);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_user_list_paged() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for id in 1..=5 {
+            database
+                .create_new_user(UserId(id), format!("User {id}"))
+                .await
+                .unwrap();
+        }
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let (users, next) = database.get_user_list_paged(cursor, 2).await.unwrap();
+            assert!(users.len() <= 2);
+            seen.extend(users.into_iter().map(|user| user.id));
+            if next.is_none() {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        assert_eq!(seen, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_chat_members_paged() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for id in 1..=6 {
+            database
+                .create_new_user(UserId(id), format!("User {id}"))
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3), UserId(4), UserId(5)],
+                ChatType::Group,
+                "Big chat".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(chat.member_count, 5);
+        database
+            .exit_chat(UserId(3), ChatId(chat.id))
+            .await
+            .unwrap();
+
+        let (first, cursor) = database
+            .get_chat_members_paged(UserId(1), ChatId(chat.id), None, 2)
+            .await
+            .unwrap();
+        assert_eq!(first, vec![UserId(1), UserId(2)]);
+        let (second, cursor) = database
+            .get_chat_members_paged(UserId(1), ChatId(chat.id), cursor, 2)
+            .await
+            .unwrap();
+        assert_eq!(second, vec![UserId(4), UserId(5)]);
+        let (last, cursor) = database
+            .get_chat_members_paged(UserId(1), ChatId(chat.id), cursor, 2)
+            .await
+            .unwrap();
+        assert!(last.is_empty());
+        assert!(cursor.is_none());
+
+        assert!(database
+            .get_chat_members_paged(UserId(6), ChatId(chat.id), None, 2)
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_users_info() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let mut users = database
+            .get_users_info(vec![UserId(1), UserId(2), UserId(3)])
+            .await
+            .unwrap();
+        users.sort_by_key(|user| user.id);
+        let names: Vec<_> = users.into_iter().map(|user| user.name).collect();
+        assert_eq!(names, vec!["First", "Second"]);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_chat_history_before() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Private,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+        for text in ["one", "two", "three"] {
+            database
+                .add_new_message_to_chat(ChatMessage {
+                    chat_id: chat.id,
+                    message_id: Uuid::new_v4(),
+                    sender_id: 1,
+                    date: (chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH).into(),
+                    msg_text: text.into(),
+                    edited_at: None,
+                    reply_to: None,
+                    attachments: vec![],
+                    forwarded_from: None,
+                    mentions: vec![],
+                    client_msg_id: None,
+                    delivery_id: None,
+                    call: None,
+                })
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let newest = database
+            .get_chat_history_before(UserId(2), ChatId(chat.id), None, 2)
+            .await
+            .unwrap();
+        let texts: Vec<_> = newest.iter().map(|msg| msg.msg_text.as_str()).collect();
+        assert_eq!(texts, vec!["three", "two"]);
+        let older = database
+            .get_chat_history_before(
+                UserId(2),
+                ChatId(chat.id),
+                Some(newest[1].date.timestamp),
+                2,
+            )
+            .await
+            .unwrap();
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].msg_text, "one");
+        assert!(database
+            .get_chat_history_before(UserId(3), ChatId(chat.id), None, 2)
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_edit_message() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Private,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Helo".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+
+        // Чужое сообщение менять нельзя
+        assert!(database
+            .edit_message(
+                UserId(2),
+                ChatId(chat.id),
+                message.message_id,
+                message.date.timestamp,
+                "Hijacked".into()
+            )
+            .await
+            .is_err());
+        let edited = database
+            .edit_message(
+                UserId(1),
+                ChatId(chat.id),
+                message.message_id,
+                message.date.timestamp,
+                "Hello".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(edited.msg_text, "Hello");
+        assert!(edited.edited_at.is_some());
+
+        let (history, _) = database
+            .get_chat_history_paged(UserId(2), ChatId(chat.id), 10, None)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].message_id, message.message_id);
+        assert_eq!(history[0].msg_text, "Hello");
+        assert!(history[0].edited_at.is_some());
+        assert!(database
+            .edit_message(
+                UserId(1),
+                ChatId(chat.id),
+                Uuid::new_v4(),
+                message.date.timestamp,
+                "?".into()
+            )
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_delete_message() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Private,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Oops".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+
+        // Чужое сообщение удалять нельзя
+        assert!(database
+            .delete_message(UserId(2), ChatId(chat.id), message.message_id)
+            .await
+            .is_err());
+        let tombstone = database
+            .delete_message(UserId(1), ChatId(chat.id), message.message_id)
+            .await
+            .unwrap();
+        assert_eq!(tombstone.message_id, message.message_id);
+        assert_eq!(tombstone.date.timestamp, message.date.timestamp);
+
+        let (history, _) = database
+            .get_chat_history_paged(UserId(2), ChatId(chat.id), 10, None)
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+        assert!(database
+            .delete_message(UserId(1), ChatId(chat.id), message.message_id)
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_chat_events_feed() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "First"), (2, "Second"), (3, "Third")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Old".into())
+            .await
+            .unwrap();
+        let chat_id = ChatId(chat.id);
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Hi".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: Some("local-1".into()),
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        database
+            .edit_message(
+                UserId(1),
+                chat_id,
+                message.message_id,
+                message.date.timestamp,
+                "Hello".into(),
+            )
+            .await
+            .unwrap();
+        database
+            .rename_chat(UserId(1), chat_id, "New".into())
+            .await
+            .unwrap();
+        database
+            .add_user_to_chat(UserId(1), UserId(3), chat_id)
+            .await
+            .unwrap();
+        database
+            .delete_message(UserId(1), chat_id, message.message_id)
+            .await
+            .unwrap();
+        database.exit_chat(UserId(3), chat_id).await.unwrap();
+
+        let (events, next) = database.get_chat_events(chat_id, None, 100).await.unwrap();
+        assert_eq!(next, None);
+        assert_eq!(
+            events.iter().map(|event| event.kind).collect::<Vec<_>>(),
+            vec![
+                ChatEventKind::Message,
+                ChatEventKind::MessageEdited,
+                ChatEventKind::ChatRenamed,
+                ChatEventKind::MemberJoined,
+                ChatEventKind::MessageDeleted,
+                ChatEventKind::MemberLeft,
+            ]
+        );
+        assert!(events.windows(2).all(|w| w[0].position < w[1].position));
+        // Служебные поля доставки отправителю в ленту не попадают
+        assert_eq!(events[0].payload["msg_text"], "Hi");
+        assert!(events[0].payload["client_msg_id"].is_null());
+        assert_eq!(events[1].payload["msg_text"], "Hello");
+        assert_eq!(events[2].payload["name"], "New");
+        assert_eq!(events[3].payload["user_id"], 3);
+        assert_eq!(events[5].payload["user_id"], 3);
+
+        // Лента читается страницами, следующая начинается строго после курсора
+        let (first, next) = database.get_chat_events(chat_id, None, 4).await.unwrap();
+        assert_eq!(first, events[..4]);
+        assert_eq!(next, Some(events[3].position));
+        let (rest, next) = database.get_chat_events(chat_id, next, 4).await.unwrap();
+        assert_eq!(rest, events[4..]);
+        assert_eq!(next, None);
+
+        database.delete_chat(chat_id).await.unwrap();
+        let (events, _) = database.get_chat_events(chat_id, None, 100).await.unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_thread_replies() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(3), "Third".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+        let root = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Question".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(root.clone())
+            .await
+            .unwrap();
+        for i in 0..3 {
+            database
+                .add_new_message_to_chat(ChatMessage {
+                    message_id: Uuid::new_v4(),
+                    sender_id: 2,
+                    date: Duration::seconds(20 + i).into(),
+                    msg_text: format!("Answer {i}"),
+                    reply_to: Some(root.message_id),
+                    ..root.clone()
+                })
+                .await
+                .unwrap();
+        }
+
+        let (first_page, index) = database
+            .get_thread_paged(UserId(1), ChatId(chat.id), root.message_id, 2, None)
+            .await
+            .unwrap();
+        assert_eq!(first_page.len(), 2);
+        assert_eq!(first_page[0].msg_text, "Answer 2");
+        assert!(first_page
+            .iter()
+            .all(|message| message.reply_to == Some(root.message_id)));
+        let (second_page, _) = database
+            .get_thread_paged(UserId(1), ChatId(chat.id), root.message_id, 2, Some(index))
+            .await
+            .unwrap();
+        assert_eq!(second_page.len(), 1);
+        assert_eq!(second_page[0].msg_text, "Answer 0");
+
+        // Не участник чата ответы не видит
+        assert!(database
+            .get_thread_paged(UserId(3), ChatId(chat.id), root.message_id, 2, None)
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_notification_settings() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(3), "Third".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+
+        let info = database
+            .get_chat_info(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(info.notifications, NotificationSettings::default());
+
+        let settings = NotificationSettings {
+            priority: NotificationPriority::MentionsOnly,
+            sound: Some("chime".into()),
+            mute: None,
+        };
+        database
+            .set_notification_settings(UserId(1), ChatId(chat.id), settings.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .notifications,
+            settings
+        );
+        // Настройки у каждого участника свои
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .notifications,
+            NotificationSettings::default()
+        );
+        // Не участник чата настройки менять не может
+        assert!(database
+            .set_notification_settings(UserId(3), ChatId(chat.id), settings.clone())
+            .await
+            .is_err());
+
+        // Отключение уведомлений не сбрасывает остальные настройки, а их смена - отключение
+        let until = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH + chrono::Duration::hours(1);
+        database
+            .set_mute(UserId(1), ChatId(chat.id), Some(Mute::Until(until.into())))
+            .await
+            .unwrap();
+        database
+            .set_notification_settings(UserId(1), ChatId(chat.id), settings.clone())
+            .await
+            .unwrap();
+        let muted = database
+            .get_notification_settings(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(muted.sound, settings.sound);
+        assert_eq!(muted.mute, Some(Mute::Until(until.into())));
+        assert!(!muted.should_notify(true));
+        database
+            .set_mute(UserId(2), ChatId(chat.id), Some(Mute::Forever))
+            .await
+            .unwrap();
+        let all = database
+            .get_all_notification_settings(UserId(2))
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[&ChatId(chat.id)].mute, Some(Mute::Forever));
+        assert_eq!(all[&ChatId(chat.id)].priority, NotificationPriority::All);
+        database
+            .set_mute(UserId(2), ChatId(chat.id), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_notification_settings(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap(),
+            NotificationSettings::default()
+        );
+        assert!(database
+            .set_mute(UserId(3), ChatId(chat.id), Some(Mute::Forever))
+            .await
+            .is_err());
+
+        // После выхода из чата настройки забываются
+        database
+            .exit_chat(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_notification_settings(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap(),
+            NotificationSettings::default()
+        );
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_user_preferences() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+
+        assert_eq!(
+            database.get_user_preferences(UserId(1)).await.unwrap(),
+            UserPreferences::default()
+        );
+        let preferences = UserPreferences {
+            sound: false,
+            mentions_only: true,
+            quiet_hours: Some(QuietHours {
+                start_minute: 22 * 60,
+                end_minute: 7 * 60,
+                utc_offset_minutes: -300,
+            }),
+        };
+        database
+            .set_user_preferences(UserId(1), preferences.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_user_preferences(UserId(1)).await.unwrap(),
+            preferences
+        );
+        // Настройки у каждого пользователя свои
+        assert_eq!(
+            database.get_user_preferences(UserId(2)).await.unwrap(),
+            UserPreferences::default()
+        );
+        // Настройки заменяются целиком
+        let without_quiet_hours = UserPreferences {
+            quiet_hours: None,
+            ..preferences
+        };
+        database
+            .set_user_preferences(UserId(1), without_quiet_hours.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_user_preferences(UserId(1)).await.unwrap(),
+            without_quiet_hours
+        );
+        database.delete_user_preferences(UserId(1)).await.unwrap();
+        assert_eq!(
+            database.get_user_preferences(UserId(1)).await.unwrap(),
+            UserPreferences::default()
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_post_policy_and_pins() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Announcements".into(),
+            )
+            .await
+            .unwrap();
+        database
+            .set_post_policy(ChatId(chat.id), PostPolicy::CreatorOnly)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .post_policy,
+            PostPolicy::CreatorOnly
+        );
+
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Rules".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        // Писать может только создатель
+        assert!(database
+            .add_new_message_to_chat(ChatMessage {
+                message_id: Uuid::new_v4(),
+                sender_id: 2,
+                ..message.clone()
+            })
+            .await
+            .is_err());
+
+        let pin = database
+            .pin_message(UserId(1), ChatId(chat.id), message.message_id, None, 10)
+            .await
+            .unwrap()
+            .pin;
+        assert_eq!(pin.pinned_by, 1);
+        assert_eq!(
+            database.get_pins(UserId(2), ChatId(chat.id)).await.unwrap(),
+            vec![pin]
+        );
+        // Несуществующее сообщение не закрепить
+        assert!(database
+            .pin_message(UserId(1), ChatId(chat.id), Uuid::new_v4(), None, 10)
+            .await
+            .is_err());
+
+        // Удаленное сообщение открепляется
+        database
+            .delete_message(UserId(1), ChatId(chat.id), message.message_id)
+            .await
+            .unwrap();
+        assert!(database
+            .get_pins(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_pin_expiry_and_rotation() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Test chat".into(),
+            )
+            .await
+            .unwrap();
+        let mut messages = vec![];
+        for i in 0..3 {
+            let message = ChatMessage {
+                chat_id: chat.id,
+                message_id: Uuid::new_v4(),
+                sender_id: 1,
+                date: Duration::seconds(10 + i).into(),
+                msg_text: format!("Message {i}"),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            database
+                .add_new_message_to_chat(message.clone())
+                .await
+                .unwrap();
+            messages.push(message);
+        }
+
+        // Третье закрепление при лимите 2 вытесняет самое старое
+        for message in &messages[..2] {
+            let outcome = database
+                .pin_message(UserId(1), ChatId(chat.id), message.message_id, None, 2)
+                .await
+                .unwrap();
+            assert!(outcome.rotated.is_empty());
+        }
+        let outcome = database
+            .pin_message(UserId(2), ChatId(chat.id), messages[2].message_id, None, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome.rotated,
+            vec![UnpinnedMessage {
+                chat_id: chat.id,
+                message_id: messages[0].message_id,
+                reason: UnpinReason::Rotated,
+            }]
+        );
+        let pinned: Vec<_> = database
+            .get_pins(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|pin| pin.message_id)
+            .collect();
+        assert_eq!(pinned, vec![messages[2].message_id, messages[1].message_id]);
+
+        // Истекшее закрепление не показывается и снимается планировщиком
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        database
+            .pin_message(
+                UserId(1),
+                ChatId(chat.id),
+                messages[1].message_id,
+                Some(now),
+                2,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_pins(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            database
+                .expire_pins(now + Duration::seconds(1))
+                .await
+                .unwrap(),
+            vec![UnpinnedMessage {
+                chat_id: chat.id,
+                message_id: messages[1].message_id,
+                reason: UnpinReason::Expired,
+            }]
+        );
+
+        let unpinned = database
+            .unpin_message(UserId(1), ChatId(chat.id), messages[2].message_id)
+            .await
+            .unwrap();
+        assert_eq!(unpinned.reason, UnpinReason::Manual);
+        assert!(database
+            .get_pins(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap()
+            .is_empty());
+        // Повторно открепить нельзя
+        assert!(database
+            .unpin_message(UserId(1), ChatId(chat.id), messages[2].message_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_message_attachments() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Files".into())
+            .await
+            .unwrap();
+        let other_chat = database
+            .create_new_chat(UserId(2), vec![], ChatType::Group, "Other".into())
+            .await
+            .unwrap();
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            chat_id: chat.id,
+            uploader_id: 1,
+            name: "photo.png".into(),
+            size: 1024,
+            mime: "image/png".into(),
+            url: "http://storage/attachments/photo".into(),
+            created_at: Duration::seconds(5).into(),
+        };
+        database.add_attachment(attachment.clone()).await.unwrap();
+        assert_eq!(
+            database
+                .get_attachment(UserId(1), attachment.id)
+                .await
+                .unwrap(),
+            attachment
+        );
+        // Посторонний не видит вложение и не может загрузить его в чужой чат
+        assert!(database
+            .get_attachment(UserId(2), attachment.id)
+            .await
+            .is_err());
+        assert!(database
+            .add_attachment(Attachment {
+                id: Uuid::new_v4(),
+                uploader_id: 2,
+                ..attachment.clone()
+            })
+            .await
+            .is_err());
+
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Look".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![attachment.id],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        let (history, _) = database
+            .get_chat_history_paged(UserId(1), ChatId(chat.id), 10, None)
+            .await
+            .unwrap();
+        assert_eq!(history[0].attachments, vec![attachment.id]);
+        // Вложение из другого чата к сообщению не прикрепить
+        database
+            .add_user_to_chat(UserId(2), UserId(1), ChatId(other_chat.id))
+            .await
+            .unwrap();
+        assert!(database
+            .add_new_message_to_chat(ChatMessage {
+                chat_id: other_chat.id,
+                message_id: Uuid::new_v4(),
+                ..message
+            })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_forward_message() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let source = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Source".into())
+            .await
+            .unwrap();
+        let target = database
+            .create_new_chat(UserId(2), vec![], ChatType::Group, "Target".into())
+            .await
+            .unwrap();
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            chat_id: source.id,
+            uploader_id: 1,
+            name: "notes.txt".into(),
+            size: 10,
+            mime: "text/plain".into(),
+            url: "http://storage/attachments/notes".into(),
+            created_at: Duration::seconds(5).into(),
+        };
+        database.add_attachment(attachment.clone()).await.unwrap();
+        let original = ChatMessage {
+            chat_id: source.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "Read this".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![attachment.id],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(original.clone())
+            .await
+            .unwrap();
+
+        // Первый пользователь не состоит в чате назначения
+        assert!(database
+            .forward_message(
+                UserId(1),
+                ChatId(source.id),
+                original.message_id,
+                ChatId(target.id)
+            )
+            .await
+            .is_err());
+        let forwarded = database
+            .forward_message(
+                UserId(2),
+                ChatId(source.id),
+                original.message_id,
+                ChatId(target.id),
+            )
+            .await
+            .unwrap();
+        assert_eq!(forwarded.chat_id, target.id);
+        assert_eq!(forwarded.sender_id, 2);
+        assert_eq!(forwarded.msg_text, "Read this");
+        let from = forwarded.forwarded_from.clone().unwrap();
+        assert_eq!(
+            (from.chat_id, from.message_id, from.sender_id),
+            (source.id, original.message_id, 1)
+        );
+        // Вложение скопировано в чат назначения
+        assert_eq!(forwarded.attachments.len(), 1);
+        let copy = database
+            .get_attachment(UserId(2), forwarded.attachments[0])
+            .await
+            .unwrap();
+        assert_eq!((copy.chat_id, copy.url), (target.id, attachment.url));
+
+        let (history, _) = database
+            .get_chat_history_paged(UserId(2), ChatId(target.id), 10, None)
+            .await
+            .unwrap();
+        assert_eq!(history[0].forwarded_from, Some(from.clone()));
+
+        // Пересланное дальше сообщение сохраняет первоначальный источник
+        let again = database
+            .forward_message(
+                UserId(2),
+                ChatId(target.id),
+                forwarded.message_id,
+                ChatId(source.id),
+            )
+            .await
+            .unwrap();
+        assert_eq!(again.forwarded_from, Some(from));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_message_mentions() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second User".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(3), "Outsider".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Mentions".into(),
+            )
+            .await
+            .unwrap();
+
+        let mentions = database
+            .find_mentions(
+                UserId(1),
+                ChatId(chat.id),
+                "@second_user look, @Outsider and @1",
+            )
+            .await
+            .unwrap();
+        assert_eq!(mentions, vec![UserId(2)]);
+        assert!(database
+            .find_mentions(UserId(1), ChatId(chat.id), "nobody here")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "@2 look".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: mentions.into_iter().map(i64::from).collect(),
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database.add_new_message_to_chat(message).await.unwrap();
+        let (history, _) = database
+            .get_chat_history_paged(UserId(1), ChatId(chat.id), 10, None)
+            .await
+            .unwrap();
+        assert_eq!(history[0].mentions, vec![2]);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_chat_labels() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Labeled".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .labels,
+            ChatLabels::default()
+        );
+        let labels = ChatLabels {
+            language: Some("en".into()),
+            labels: vec!["gaming".into(), "nsfw".into()],
+        };
+        database
+            .set_chat_labels(ChatId(chat.id), labels.clone())
+            .await
+            .unwrap();
+        let info = database
+            .get_chat_info(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(info.labels, labels);
+        assert!(info.labels.is_nsfw());
+        assert!(database
+            .set_chat_labels(ChatId(Uuid::new_v4()), labels)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_message_ttl() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Disappearing".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .message_ttl_secs,
+            None
+        );
+        assert!(database
+            .set_message_ttl(UserId(2), ChatId(chat.id), 1)
+            .await
+            .is_err());
+        database
+            .set_message_ttl(UserId(1), ChatId(chat.id), 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .message_ttl_secs,
+            Some(1)
+        );
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 2,
+            date: chat::clock::CLOCK.now().into(),
+            msg_text: "Soon gone".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database.add_new_message_to_chat(message).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        let (history, _) = database
+            .get_chat_history_paged(UserId(1), ChatId(chat.id), 10, None)
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+        database
+            .set_message_ttl(UserId(1), ChatId(chat.id), 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .message_ttl_secs,
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_edited_message_ttl() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Disappearing".into())
+            .await
+            .unwrap();
+        database
+            .set_message_ttl(UserId(1), ChatId(chat.id), 2)
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: chat::clock::CLOCK.now().into(),
+            msg_text: "Soon gone".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        database
+            .edit_message(
+                UserId(1),
+                ChatId(chat.id),
+                message.message_id,
+                message.date.timestamp,
+                "Still secret".into(),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        // Правка не оставляет строку с текстом после того, как сообщение исчезло
+        let rows = database
+            .client
+            .query(
+                "SELECT message_text FROM chat.messages WHERE chat_id = ?",
+                (chat.id,),
+            )
+            .await
+            .unwrap()
+            .rows_typed_or_empty::<(Option<String>,)>()
+            .count();
+        assert_eq!(rows, 0);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_user_creation_date() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        let before = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        let created = database.get_user_creation_date(UserId(1)).await.unwrap();
+        assert!(created >= before - Duration::seconds(1));
+        assert!(created <= chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH);
+        assert!(database.get_user_creation_date(UserId(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_drafts() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(3), "Third".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Drafts".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_draft(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap(),
+            None
+        );
+        let first = database
+            .save_draft(UserId(1), ChatId(chat.id), "Hel".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let second = database
+            .save_draft(UserId(1), ChatId(chat.id), "Hello".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(second.updated_at.timestamp > first.updated_at.timestamp);
+        assert_eq!(
+            database
+                .get_draft(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap(),
+            Some(second)
+        );
+        // Черновики у каждого участника свои
+        assert_eq!(
+            database
+                .get_draft(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(database
+            .get_draft(UserId(3), ChatId(chat.id))
+            .await
+            .is_err());
+        assert!(database
+            .save_draft(UserId(3), ChatId(chat.id), "Hi".into())
+            .await
+            .is_err());
+
+        assert_eq!(
+            database
+                .save_draft(UserId(1), ChatId(chat.id), String::new())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            database
+                .get_draft(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_average_message_size() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Stats".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_average_message_size(ChatId(chat.id))
+                .await
+                .unwrap(),
+            None
+        );
+        for text in ["short", &"long".repeat(1000)] {
+            let message = ChatMessage {
+                chat_id: chat.id,
+                message_id: Uuid::new_v4(),
+                sender_id: 1,
+                date: chat::clock::CLOCK.now().into(),
+                msg_text: text.to_string(),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            database.add_new_message_to_chat(message).await.unwrap();
+        }
+        let average = database
+            .get_average_message_size(ChatId(chat.id))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(average > 2000 && average < 2500);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_chat_usage() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        assert!(database
+            .get_chat_usage(ChatId(Uuid::new_v4()))
+            .await
+            .is_err());
+        let chat = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Usage".into())
+            .await
+            .unwrap();
+        let usage = database.get_chat_usage(ChatId(chat.id)).await.unwrap();
+        assert_eq!(usage.message_count, 0);
+        assert_eq!(usage.attachment_bytes, 0);
+        assert_eq!(usage.oldest_message, None);
+        for seconds in [10, 30, 20] {
+            let message = ChatMessage {
+                chat_id: chat.id,
+                message_id: Uuid::new_v4(),
+                sender_id: 1,
+                date: Duration::seconds(seconds).into(),
+                msg_text: "hello".into(),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            database.add_new_message_to_chat(message).await.unwrap();
+        }
+        for size in [1024, 976] {
+            database
+                .add_attachment(Attachment {
+                    id: Uuid::new_v4(),
+                    chat_id: chat.id,
+                    uploader_id: 1,
+                    name: "photo.png".into(),
+                    size,
+                    mime: "image/png".into(),
+                    url: "http://storage/attachments/photo".into(),
+                    created_at: Duration::seconds(5).into(),
+                })
+                .await
+                .unwrap();
+        }
+        let usage = database.get_chat_usage(ChatId(chat.id)).await.unwrap();
+        assert_eq!(usage.chat_id, chat.id);
+        assert_eq!(usage.message_count, 3);
+        assert!(usage.message_bytes > 0);
+        assert_eq!(usage.attachment_count, 2);
+        assert_eq!(usage.attachment_bytes, 2000);
+        assert_eq!(usage.oldest_message, Some(Duration::seconds(10).into()));
+        assert_eq!(usage.newest_message, Some(Duration::seconds(30).into()));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_unread_counts() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Unread".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_unread_counts(UserId(2)).await.unwrap()[&ChatId(chat.id)],
+            0
+        );
+        for text in ["One", "Two", "Three"] {
+            let message = ChatMessage {
+                chat_id: chat.id,
+                message_id: Uuid::new_v4(),
+                sender_id: 1,
+                date: chat::clock::CLOCK.now().into(),
+                msg_text: text.into(),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            database.add_new_message_to_chat(message).await.unwrap();
+        }
+        assert_eq!(
+            database.get_unread_counts(UserId(2)).await.unwrap()[&ChatId(chat.id)],
+            3
+        );
+        // Свои сообщения непрочитанными не считаются
+        assert_eq!(
+            database.get_unread_counts(UserId(1)).await.unwrap()[&ChatId(chat.id)],
+            0
+        );
+        database
+            .reset_unread(UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_unread_counts(UserId(2)).await.unwrap()[&ChatId(chat.id)],
+            0
+        );
+        database
+            .reset_unread(UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_unread_counts(UserId(2)).await.unwrap()[&ChatId(chat.id)],
+            0
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_read_positions() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Read".into())
+            .await
+            .unwrap();
+        assert!(database
+            .get_chat_info(UserId(2), ChatId(chat.id))
+            .await
+            .unwrap()
+            .last_read
+            .is_none());
+        let earlier = ReadPosition {
+            message_id: Uuid::new_v4(),
+            date: Duration::milliseconds(1_000).into(),
+        };
+        let later = ReadPosition {
+            message_id: Uuid::new_v4(),
+            date: Duration::milliseconds(2_000).into(),
+        };
+        database
+            .save_read_position(UserId(2), ChatId(chat.id), later.clone())
+            .await
+            .unwrap();
+        // Отметка о более раннем сообщении пришла позже, но не заменяет более позднюю
+        database
+            .save_read_position(UserId(2), ChatId(chat.id), earlier)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .last_read,
+            Some(later.clone())
+        );
+        assert!(database
+            .get_chat_info(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap()
+            .last_read
+            .is_none());
+        let positions = database.get_read_positions(UserId(2)).await.unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[&ChatId(chat.id)], later);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_client_msg_id_dedup() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        let chat_id = Uuid::new_v4();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let date = Duration::milliseconds(1_000);
+        assert_eq!(
+            database
+                .claim_client_msg_id(ChatId(chat_id), UserId(1), "local-1", first, date)
+                .await
+                .unwrap(),
+            None
+        );
+        // Повтор получает id и дату первого сообщения
+        assert_eq!(
+            database
+                .claim_client_msg_id(
+                    ChatId(chat_id),
+                    UserId(1),
+                    "local-1",
+                    second,
+                    Duration::milliseconds(2_000)
+                )
+                .await
+                .unwrap(),
+            Some((first, date))
+        );
+        // Тот же client_msg_id у другого отправителя - другое сообщение
+        assert_eq!(
+            database
+                .claim_client_msg_id(ChatId(chat_id), UserId(2), "local-1", second, date)
+                .await
+                .unwrap(),
+            None
+        );
+        // Освобождает только то сообщение, за которым закреплен id
+        database
+            .release_client_msg_id(ChatId(chat_id), UserId(1), "local-1", second)
+            .await
+            .unwrap();
+        assert!(database
+            .claim_client_msg_id(ChatId(chat_id), UserId(1), "local-1", second, date)
+            .await
+            .unwrap()
+            .is_some());
+        database
+            .release_client_msg_id(ChatId(chat_id), UserId(1), "local-1", first)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .claim_client_msg_id(ChatId(chat_id), UserId(1), "local-1", second, date)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_rename_chat() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "First".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(2), "Second".into())
+            .await
+            .unwrap();
+        database
+            .create_new_user(UserId(3), "Third".into())
+            .await
+            .unwrap();
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Old".into())
+            .await
+            .unwrap();
+        database
+            .rename_chat(UserId(1), ChatId(chat.id), "New".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .name,
+            "New"
+        );
+        // Обычный участник и не участник чата переименовать его не могут
+        assert!(matches!(
+            database
+                .rename_chat(UserId(2), ChatId(chat.id), "Member".into())
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database
+                .rename_chat(UserId(3), ChatId(chat.id), "Stranger".into())
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .name,
+            "New"
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_chat_roles() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "Owner"), (2, "Admin"), (3, "Member"), (4, "Guest")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3)],
+                ChatType::Group,
+                "Roles".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .role,
+            ChatRole::Owner
+        );
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .role,
+            ChatRole::Member
+        );
+
+        // Обычный участник не приглашает и не раздает роли
+        assert!(matches!(
+            database
+                .add_user_to_chat(UserId(3), UserId(4), ChatId(chat.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database
+                .set_role(UserId(3), ChatId(chat.id), UserId(2), ChatRole::Admin)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+
+        database
+            .set_role(UserId(1), ChatId(chat.id), UserId(2), ChatRole::Admin)
+            .await
+            .unwrap();
+        // Администратор приглашает, но роли не раздает
+        database
+            .add_user_to_chat(UserId(2), UserId(4), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(matches!(
+            database
+                .set_role(UserId(2), ChatId(chat.id), UserId(3), ChatRole::Admin)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        // Свою роль владелец не меняет, роль не участнику не назначить
+        assert!(matches!(
+            database
+                .set_role(UserId(1), ChatId(chat.id), UserId(1), ChatRole::Member)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        database
+            .exit_chat(UserId(4), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(matches!(
+            database
+                .set_role(UserId(1), ChatId(chat.id), UserId(4), ChatRole::Admin)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+
+        // Передав чат, бывший владелец становится администратором
+        database
+            .set_role(UserId(1), ChatId(chat.id), UserId(3), ChatRole::Owner)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(3), ChatId(chat.id))
+                .await
+                .unwrap()
+                .role,
+            ChatRole::Owner
+        );
+        assert_eq!(
+            database
+                .get_chat_info(UserId(1), ChatId(chat.id))
+                .await
+                .unwrap()
+                .role,
+            ChatRole::Admin
+        );
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_chat_permissions() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "Owner"), (2, "Member"), (3, "Guest")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Permissions".into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_chat_info(UserId(2), ChatId(chat.id))
+                .await
+                .unwrap()
+                .permissions,
+            ChatPermissions::default()
+        );
+        assert!(matches!(
+            database
+                .rename_chat(UserId(2), ChatId(chat.id), "Renamed".into())
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        // Разрешения задают только владелец и администраторы
+        assert!(matches!(
+            database
+                .set_permissions(UserId(2), ChatId(chat.id), ChatPermissions::ALL)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+
+        database
+            .set_permissions(
+                UserId(1),
+                ChatId(chat.id),
+                ChatPermissions::INVITE | ChatPermissions::CHANGE_INFO,
+            )
+            .await
+            .unwrap();
+        database
+            .rename_chat(UserId(2), ChatId(chat.id), "Renamed".into())
+            .await
+            .unwrap();
+        database
+            .add_user_to_chat(UserId(2), UserId(3), ChatId(chat.id))
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: chat.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 2,
+            date: (chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH).into(),
+            msg_text: "pin me".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        // Закреплять участникам больше нельзя, а владельцу можно всегда
+        assert!(matches!(
+            database
+                .pin_message(UserId(2), ChatId(chat.id), message.message_id, None, 10)
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        database
+            .pin_message(UserId(1), ChatId(chat.id), message.message_id, None, 10)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    #[serial]
+    async fn test_kick_user() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "Owner"), (2, "Admin"), (3, "Member"), (4, "Other")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3), UserId(4)],
+                ChatType::Group,
+                "Kick".into(),
+            )
+            .await
+            .unwrap();
+        database
+            .set_role(UserId(1), ChatId(chat.id), UserId(2), ChatRole::Admin)
+            .await
+            .unwrap();
+
+        // Обычный участник не исключает, администратор не исключает владельца и себя
+        assert!(matches!(
+            database
+                .remove_user_from_chat(UserId(3), UserId(4), ChatId(chat.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database
+                .remove_user_from_chat(UserId(2), UserId(1), ChatId(chat.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database
+                .remove_user_from_chat(UserId(2), UserId(2), ChatId(chat.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+
+        database
+            .remove_user_from_chat(UserId(2), UserId(3), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(!database
+            .get_user_chats(UserId(3))
+            .await
+            .unwrap()
+            .contains(&ChatId(chat.id)));
+        assert!(!database
+            .get_chat_info(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap()
+            .users
+            .contains(&3));
+        // Исключенного второй раз не исключить
+        assert!(matches!(
+            database
+                .remove_user_from_chat(UserId(2), UserId(3), ChatId(chat.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        // Администратора исключает только владелец
+        database
+            .remove_user_from_chat(UserId(1), UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(!database
+            .get_user_chats(UserId(2))
+            .await
+            .unwrap()
+            .contains(&ChatId(chat.id)));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_archive_chat() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "First"), (2, "Second"), (3, "Third")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2)],
+                ChatType::Group,
+                "Archive".into(),
+            )
+            .await
+            .unwrap();
+
+        assert!(database
+            .get_archived_chats(UserId(1))
+            .await
+            .unwrap()
+            .is_empty());
+        database
+            .archive_chat(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(database
+            .get_archived_chats(UserId(1))
+            .await
+            .unwrap()
+            .contains(&ChatId(chat.id)));
+        // Архив у каждого участника свой, а из чата пользователь не выходит
+        assert!(database
+            .get_archived_chats(UserId(2))
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(database
+            .get_user_chats(UserId(1))
+            .await
+            .unwrap()
+            .contains(&ChatId(chat.id)));
+        // Не участник архивировать чат не может
+        assert!(matches!(
+            database.archive_chat(UserId(3), ChatId(chat.id)).await,
+            Err(DBError::LogicError(_))
+        ));
+
+        database
+            .unarchive_chat(UserId(1), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(database
+            .get_archived_chats(UserId(1))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // После выхода из чата он пропадает из архива
+        database
+            .archive_chat(UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        database
+            .exit_chat(UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(database
+            .get_archived_chats(UserId(2))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    fn is_blocked<T>(result: Result<T, DBError>) -> bool {
+        matches!(result, Err(DBError::LogicError(e)) if e.is::<BlockedError>())
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_user_blocks() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "First"), (2, "Second"), (3, "Third")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        // Себя и несуществующих пользователей не блокируют
+        assert!(database.block_user(UserId(1), UserId(1)).await.is_err());
+        assert!(database.block_user(UserId(1), UserId(4)).await.is_err());
+
+        database.block_user(UserId(2), UserId(1)).await.unwrap();
+        assert_eq!(
+            database.get_blocked_users(UserId(2)).await.unwrap(),
+            [UserId(1)].into()
+        );
+        assert_eq!(
+            database
+                .get_blockers(UserId(1), vec![UserId(2), UserId(3)])
+                .await
+                .unwrap(),
+            vec![UserId(2)]
+        );
+
+        // Заблокированный не создает чат с заблокировавшим и не приглашает его
+        assert!(is_blocked(
+            database
+                .create_new_chat(
+                    UserId(1),
+                    vec![UserId(2)],
+                    ChatType::Private,
+                    "Blocked".into()
+                )
+                .await
+        ));
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(3)], ChatType::Group, "Group".into())
+            .await
+            .unwrap();
+        assert!(is_blocked(
+            database
+                .add_user_to_chat(UserId(1), UserId(2), ChatId(chat.id))
+                .await
+        ));
+        // В обратную сторону блокировка не действует
+        database
+            .create_new_chat(UserId(2), vec![UserId(1)], ChatType::Private, "Back".into())
+            .await
+            .unwrap();
+
+        database.unblock_user(UserId(2), UserId(1)).await.unwrap();
+        assert!(database
+            .get_blocked_users(UserId(2))
+            .await
+            .unwrap()
+            .is_empty());
+        database
+            .add_user_to_chat(UserId(1), UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_unique_user_names() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+
+        database
+            .create_new_user(UserId(1), "Alice".into())
+            .await
+            .unwrap();
+        // Повторная авторизация с тем же именем ничего не ломает
+        database
+            .create_new_user(UserId(1), "Alice".into())
+            .await
+            .unwrap();
+        // Имена сравниваются без учета регистра
+        match database.create_new_user(UserId(2), "ALICE".into()).await {
+            Err(DBError::LogicError(e)) => assert!(e.is::<NameTakenError>()),
+            other => panic!("Name should be taken: {other:?}"),
+        }
+        assert!(database.get_user_info(UserId(2)).await.is_err());
+
+        // Существующий пользователь не занимает новое имя
+        database
+            .create_new_user(UserId(1), "Bob".into())
+            .await
+            .unwrap();
+        let taken = database
+            .taken_names(vec!["alice".into(), "Bob".into(), "Carol".into()])
+            .await
+            .unwrap();
+        assert_eq!(taken, ["alice".to_string()].into());
+        database
+            .create_new_user(UserId(2), "Bob".into())
+            .await
+            .unwrap();
+
+        // Переименование закрепляет новое имя и освобождает старое
+        match database.rename_user(UserId(1), "bob".into()).await {
+            Err(DBError::LogicError(e)) => assert!(e.is::<NameTakenError>()),
+            other => panic!("Name should be taken: {other:?}"),
+        }
+        let renamed = database
+            .rename_user(UserId(1), "Carol".into())
+            .await
+            .unwrap();
+        assert_eq!(renamed.name, "Carol");
+        database
+            .rename_user(UserId(1), "CAROL".into())
+            .await
+            .unwrap();
+        let taken = database
+            .taken_names(vec!["Alice".into(), "Carol".into()])
+            .await
+            .unwrap();
+        assert_eq!(taken, ["carol".to_string()].into());
+        database
+            .create_new_user(UserId(3), "Alice".into())
+            .await
+            .unwrap();
+        assert!(database
+            .rename_user(UserId(4), "Dave".into())
+            .await
+            .is_err());
+        assert!(database
+            .taken_names(vec!["Dave".into()])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_user_contacts() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "First"), (2, "Second"), (3, "Third")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        // Себя и несуществующих пользователей в контакты не добавляют
+        assert!(database.add_contact(UserId(1), UserId(1)).await.is_err());
+        assert!(database.add_contact(UserId(1), UserId(4)).await.is_err());
+
+        database.add_contact(UserId(1), UserId(3)).await.unwrap();
+        database.add_contact(UserId(1), UserId(2)).await.unwrap();
+        database.add_contact(UserId(1), UserId(2)).await.unwrap();
+        assert_eq!(
+            database.get_contacts(UserId(1)).await.unwrap(),
+            vec![UserId(2), UserId(3)]
+        );
+        // Контакты односторонние
+        assert!(database.get_contacts(UserId(2)).await.unwrap().is_empty());
+
+        database.remove_contact(UserId(1), UserId(2)).await.unwrap();
+        assert_eq!(
+            database.get_contacts(UserId(1)).await.unwrap(),
+            vec![UserId(3)]
+        );
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_bot_webhooks() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        database
+            .create_new_user(UserId(1), "Bot".into())
+            .await
+            .unwrap();
+        assert!(database
+            .set_bot_webhook(UserId(2), "http://bot/hook".into())
+            .await
+            .is_err());
+
+        let first = database
+            .set_bot_webhook(UserId(1), "http://bot/hook".into())
+            .await
+            .unwrap();
+        // Повторная регистрация меняет адрес и секрет
+        let second = database
+            .set_bot_webhook(UserId(1), "http://bot/v2".into())
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            database.get_bot_webhooks().await.unwrap(),
+            vec![BotWebhook {
+                user_id: 1,
+                callback_url: "http://bot/v2".into(),
+                secret: second,
+            }]
+        );
+
+        database.remove_bot_webhook(UserId(1)).await.unwrap();
+        assert!(database.get_bot_webhooks().await.unwrap().is_empty());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_public_channels() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "First"), (2, "Second")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let channel = database
+            .create_new_chat(UserId(1), vec![], ChatType::Channel, "Rust News".into())
+            .await
+            .unwrap();
+        assert_eq!(channel.chat_type, ChatType::Channel);
+        let group = database
+            .create_new_chat(UserId(1), vec![], ChatType::Group, "Rust Team".into())
+            .await
+            .unwrap();
+
+        // Ищется только канал и без учета регистра
+        let found = database.discover_channels("rUST".into(), 10).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, channel.id);
+        assert_eq!(found[0].member_count, 1);
+        assert!(database
+            .discover_channels("news".into(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // В группу без приглашения не войти, а в канал можно
+        assert!(matches!(
+            database
+                .join_public_channel(UserId(2), ChatId(group.id))
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        let joined = database
+            .join_public_channel(UserId(2), ChatId(channel.id))
+            .await
+            .unwrap();
+        assert_eq!(joined.member_count, 2);
+
+        // Индекс следует за переименованием и удалением
+        database
+            .rename_chat(UserId(1), ChatId(channel.id), "Daily Rust".into())
+            .await
+            .unwrap();
+        assert!(database
+            .discover_channels("rust".into(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        let found = database.discover_channels("".into(), 10).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "Daily Rust");
+        database.delete_chat(ChatId(channel.id)).await.unwrap();
+        assert!(database
+            .discover_channels("".into(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_broadcast_channel() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "Owner"), (2, "Subscriber"), (3, "Stranger")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let channel = database
+            .create_new_chat(UserId(1), vec![], ChatType::Channel, "Announcements".into())
+            .await
+            .unwrap();
+        database
+            .set_post_policy(ChatId(channel.id), PostPolicy::AdminsOnly)
+            .await
+            .unwrap();
+        database
+            .join_public_channel(UserId(2), ChatId(channel.id))
+            .await
+            .unwrap();
+        let message = ChatMessage {
+            chat_id: channel.id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: Duration::seconds(10).into(),
+            msg_text: "News".into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        };
+        database
+            .add_new_message_to_chat(message.clone())
+            .await
+            .unwrap();
+        database
+            .check_can_post(UserId(1), ChatId(channel.id))
+            .await
+            .unwrap();
+        // Подписчик только читает, а не участник и вовсе не может писать
+        assert!(matches!(
+            database
+                .add_new_message_to_chat(ChatMessage {
+                    message_id: Uuid::new_v4(),
+                    sender_id: 2,
+                    ..message.clone()
+                })
+                .await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database.check_can_post(UserId(2), ChatId(channel.id)).await,
+            Err(DBError::LogicError(_))
+        ));
+        assert!(matches!(
+            database.check_can_post(UserId(3), ChatId(channel.id)).await,
+            Err(DBError::LogicError(_))
+        ));
+
+        // Назначенный администратором подписчик может писать
+        database
+            .set_role(UserId(1), ChatId(channel.id), UserId(2), ChatRole::Admin)
+            .await
+            .unwrap();
+        database
+            .add_new_message_to_chat(ChatMessage {
+                message_id: Uuid::new_v4(),
+                sender_id: 2,
+                ..message
+            })
+            .await
+            .unwrap();
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_call_messages() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::new("localhost".into(), port).await.unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for (id, name) in [(1, "Caller"), (2, "Callee")] {
+            database
+                .create_new_user(UserId(id), name.into())
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Private, "Call".into())
+            .await
+            .unwrap();
+        let call_id = Uuid::new_v4();
+        let started = ChatMessage {
+            date: Duration::seconds(10).into(),
+            ..services::call_message(
+                UserId(1),
+                ChatId(chat.id),
+                CallEvent {
+                    call_id,
+                    kind: CallEventKind::Started,
+                    duration_secs: None,
+                },
+            )
+        };
+        let ended = ChatMessage {
+            date: Duration::seconds(70).into(),
+            ..services::call_message(
+                UserId(1),
+                ChatId(chat.id),
+                CallEvent {
+                    call_id,
+                    kind: CallEventKind::Ended,
+                    duration_secs: Some(55),
+                },
+            )
+        };
+        for message in [started.clone(), ended.clone()] {
+            database.add_new_message_to_chat(message).await.unwrap();
+        }
+        let history = database
+            .get_chat_history_before(UserId(2), ChatId(chat.id), None, 10)
+            .await
+            .unwrap();
+        let calls: Vec<_> = history.iter().map(|message| message.call.clone()).collect();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.contains(&started.call));
+        assert!(calls.contains(&ended.call));
+        assert!(history.iter().all(|message| message.msg_text.is_empty()));
+    }
+
+    fn is_member_limit(error: &DBError) -> bool {
+        matches!(error, DBError::LogicError(e) if e.is::<MemberLimitError>())
+    }
+
+    #[test]
+    fn test_member_limit_error() {
+        let error = DBError::LogicError(Box::new(MemberLimitError { limit: 3 }));
+        assert!(is_member_limit(&error));
+        assert_eq!(
+            error.to_string(),
+            "Logic error: Chat member limit of 3 reached"
+        );
+        assert!(!is_member_limit(&DBError::LogicError(Box::new(
+            StringError {
+                code: "member_limit",
+                args: vec![("limit", "3".into())],
+            }
+        ))));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_member_limit() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::connect(&DatabaseConfig {
+            host: "localhost".into(),
+            port,
+            max_chat_members: 3,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for id in 1..=5 {
+            database
+                .create_new_user(UserId(id), format!("User {id}"))
+                .await
+                .unwrap();
+        }
+        // Создатель тоже участник
+        let error = database
+            .create_new_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3), UserId(4)],
+                ChatType::Group,
+                "Crowd".into(),
+            )
+            .await
+            .unwrap_err();
+        assert!(is_member_limit(&error));
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Group, "Small".into())
+            .await
+            .unwrap();
+        database
+            .add_user_to_chat(UserId(1), UserId(3), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(is_member_limit(
+            &database
+                .add_user_to_chat(UserId(1), UserId(4), ChatId(chat.id))
+                .await
+                .unwrap_err()
+        ));
+        // Повторное приглашение участника ничего не меняет и не упирается в ограничение
+        database
+            .add_user_to_chat(UserId(1), UserId(3), ChatId(chat.id))
+            .await
+            .unwrap();
+
+        // Собственное ограничение чата заменяет общее
+        database
+            .set_member_limit(ChatId(chat.id), Some(4))
+            .await
+            .unwrap();
+        database
+            .add_user_to_chat(UserId(1), UserId(4), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert!(is_member_limit(
+            &database
+                .add_user_to_chat(UserId(1), UserId(5), ChatId(chat.id))
+                .await
+                .unwrap_err()
+        ));
+        // Без своего ограничения снова действует общее, вступившие участники остаются
+        database
+            .set_member_limit(ChatId(chat.id), None)
+            .await
+            .unwrap();
+        assert_eq!(database.get_member_count(ChatId(chat.id)).await.unwrap(), 4);
+        assert!(matches!(
+            database.set_member_limit(ChatId::new_v4(), Some(10)).await,
+            Err(DBError::LogicError(_))
+        ));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_private_chat_has_two_members() {
+        let docker = Cli::default();
+        let image = GenericImage::new("scylladb/scylla", "5.1.0")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr("initialization completed."));
+        let node = docker.run(image);
+        let port = node.get_host_port_ipv4(9042);
+        let database = ScyllaDatabase::connect(&DatabaseConfig {
+            host: "localhost".into(),
+            port,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        clear_database(&database.client).await.unwrap();
+        database.init_db().await.unwrap();
+        for id in 1..=3 {
+            database
+                .create_new_user(UserId(id), format!("User {id}"))
+                .await
+                .unwrap();
+        }
+        let chat = database
+            .create_new_chat(UserId(1), vec![UserId(2)], ChatType::Private, "Dm".into())
+            .await
+            .unwrap();
+        let error = database
+            .add_user_to_chat(UserId(1), UserId(3), ChatId(chat.id))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, DBError::LogicError(_)) && !is_member_limit(&error));
+        // Участник личного чата остается в нем и при повторном приглашении
+        database
+            .add_user_to_chat(UserId(1), UserId(2), ChatId(chat.id))
+            .await
+            .unwrap();
+        assert_eq!(database.get_member_count(ChatId(chat.id)).await.unwrap(), 2);
+    }
 }
@@ -0,0 +1,347 @@
+#[cfg(test)]
+mod tests {
+    use chat::actors::redis_actor::DeliveryModeCache;
+    use chat::actors::websocket_actor::{
+        ChatMessage, ClientFrame, ClientRequest, MessageTombstone,
+    };
+    use chat::config::{Config, RedisConfig, RedisTopology};
+    use chat::database::data::DeliveryMode;
+    use chat::ids::{ChatId, UserId};
+    use chat::redis_topology::{key_slot, RedisConnector};
+    use chat::transport::{ControlLog, PubSubTransport, StreamTransport, Transport};
+    use serial_test::serial;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn test_delivery_config() {
+        let config = Config::default();
+        assert_eq!(config.delivery.default_mode, DeliveryMode::AtMostOnce);
+        let config: Config =
+            serde_json::from_str(r#"{"delivery": {"default_mode": "at_least_once"}}"#).unwrap();
+        assert_eq!(config.delivery.default_mode, DeliveryMode::AtLeastOnce);
+        assert_eq!(config.delivery.replay_limit, 100);
+        assert_eq!(config.delivery.stream_ttl_secs, 7 * 24 * 3600);
+        assert!(serde_json::from_str::<DeliveryMode>(r#""exactly_once""#).is_err());
+    }
+
+    #[test]
+    fn test_delivery_mode_cache_is_bounded() {
+        let mut cache = DeliveryModeCache::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let (first, second, third) = (ChatId::new_v4(), ChatId::new_v4(), ChatId::new_v4());
+        cache.insert(first, DeliveryMode::AtLeastOnce, start);
+        cache.insert(
+            second,
+            DeliveryMode::AtMostOnce,
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(cache.get(first, start), Some(DeliveryMode::AtLeastOnce));
+        // Переполнение вытесняет самую старую запись
+        cache.insert(
+            third,
+            DeliveryMode::AtLeastOnce,
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(first, start + Duration::from_secs(2)), None);
+        assert_eq!(
+            cache.get(third, start + Duration::from_secs(2)),
+            Some(DeliveryMode::AtLeastOnce)
+        );
+        // Устаревший режим снова спрашивается у базы
+        assert_eq!(cache.get(second, start + Duration::from_secs(61)), None);
+        // Устаревшие записи выбрасываются, когда места не хватает
+        cache.insert(
+            first,
+            DeliveryMode::AtMostOnce,
+            start + Duration::from_secs(70),
+        );
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache.get(first, start + Duration::from_secs(70)),
+            Some(DeliveryMode::AtMostOnce)
+        );
+    }
+
+    #[test]
+    fn test_ack_frame() {
+        let frame = ClientFrame::parse(
+            r#"{"type": "ack", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "delivery_id": "1-0"}"#,
+        )
+        .unwrap();
+        match frame {
+            ClientFrame::Request(ClientRequest::Ack { delivery_id, .. }) => {
+                assert_eq!(delivery_id, "1-0")
+            }
+            _ => panic!("ack frame parsed as something else"),
+        }
+        assert!(ClientFrame::parse(r#"{"type": "ack", "delivery_id": "1-0"}"#).is_err());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_stream_replays_unacked_messages() {
+        let config = RedisConfig {
+            namespace: format!("test_{}:", Uuid::new_v4()),
+            ..Default::default()
+        };
+        let connection = RedisConnector::new(&config).connect().await.unwrap();
+        let mut keys = connection.clone();
+        let stream =
+            StreamTransport::new(PubSubTransport::new(connection, config.clone()), 100, 3600);
+        let chat_id = Uuid::new_v4();
+        for i in 0..3 {
+            let message = ChatMessage {
+                chat_id,
+                message_id: Uuid::new_v4(),
+                sender_id: 1,
+                date: chrono::Duration::seconds(i).into(),
+                msg_text: format!("Message {i}"),
+                edited_at: None,
+                reply_to: None,
+                attachments: vec![],
+                forwarded_from: None,
+                mentions: vec![],
+                client_msg_id: None,
+                delivery_id: None,
+                call: None,
+            };
+            stream.publish(message).await.unwrap();
+        }
+
+        let pending = stream
+            .pending(ChatId(chat_id), UserId(2), 10)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0].msg_text, "Message 0");
+        let second = pending[1].delivery_id.clone().unwrap();
+        assert!(stream
+            .ack(ChatId(chat_id), UserId(2), &second)
+            .await
+            .unwrap());
+        // Поток и подтверждения истекают, когда в чат долго не пишут
+        for key in [format!("stream:{chat_id}"), format!("acks:{chat_id}")] {
+            let ttl: i64 = redis::cmd("TTL")
+                .arg(config.key(&key))
+                .query_async(&mut keys)
+                .await
+                .unwrap();
+            assert!(ttl > 0 && ttl <= 3600, "{key} expires in {ttl}");
+        }
+
+        let pending_after_ack = stream
+            .pending(ChatId(chat_id), UserId(2), 10)
+            .await
+            .unwrap();
+        assert_eq!(pending_after_ack.len(), 1);
+        assert_eq!(pending_after_ack[0].msg_text, "Message 2");
+
+        // Старое подтверждение не откатывает позицию назад
+        let first = pending[0].delivery_id.clone().unwrap();
+        assert!(!stream
+            .ack(ChatId(chat_id), UserId(2), &first)
+            .await
+            .unwrap());
+        assert!(!stream
+            .ack(ChatId(chat_id), UserId(2), "garbage")
+            .await
+            .unwrap());
+        assert_eq!(
+            stream
+                .pending(ChatId(chat_id), UserId(2), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        // Другой участник ничего не подтверждал
+        assert_eq!(
+            stream
+                .pending(ChatId(chat_id), UserId(3), 10)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // Удаленное сообщение больше не досылается
+        stream
+            .forget(&MessageTombstone {
+                chat_id,
+                message_id: pending[1].message_id,
+                date: pending[1].date.clone(),
+            })
+            .await
+            .unwrap();
+        let texts: Vec<_> = stream
+            .pending(ChatId(chat_id), UserId(3), 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.msg_text)
+            .collect();
+        assert_eq!(texts, vec!["Message 0", "Message 2"]);
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        let config = RedisConfig::default();
+        assert_eq!(config.reconnect_delay(0), Duration::from_millis(200));
+        assert_eq!(config.reconnect_delay(1), Duration::from_millis(400));
+        assert_eq!(config.reconnect_delay(5), Duration::from_millis(6400));
+        assert_eq!(config.reconnect_delay(6), Duration::from_secs(10));
+        assert_eq!(config.reconnect_delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_redis_topology() {
+        let config: RedisConfig = serde_json::from_value(serde_json::json!({
+            "topology": {
+                "mode": "sentinel",
+                "master_name": "mymaster",
+                "sentinels": ["sentinel-1:26379", "sentinel-2:26379"]
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            config.topology,
+            RedisTopology::Sentinel {
+                master_name: "mymaster".into(),
+                sentinels: vec!["sentinel-1:26379".into(), "sentinel-2:26379".into()],
+            }
+        );
+        let config: RedisConfig = serde_json::from_value(serde_json::json!({
+            "topology": {"mode": "cluster", "nodes": ["redis-1:6379"]}
+        }))
+        .unwrap();
+        assert_eq!(
+            config.topology,
+            RedisTopology::Cluster {
+                nodes: vec!["redis-1:6379".into()]
+            }
+        );
+        assert_eq!(RedisConfig::default().topology, RedisTopology::Standalone);
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(key_slot(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        // Слот считается только по хеш-тегу
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(
+            key_slot(b"{user1000}.following"),
+            key_slot(b"{user1000}.followers")
+        );
+        // Пустой тег не считается тегом
+        assert_ne!(key_slot(b"{}foo"), key_slot(b"foo"));
+        assert_ne!(key_slot(b"foo{}{bar}"), key_slot(b"bar"));
+    }
+
+    /// Redis, который рвет подключение на первую команду каждого вида, а повтор GET
+    /// выполняет; возвращает порт и счетчик полученных команд по именам
+    async fn flaky_redis() -> (u16, Arc<Mutex<HashMap<String, usize>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(HashMap::new()));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut buffer = vec![0; 1024];
+                    loop {
+                        let n = socket.read(&mut buffer).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        let request = String::from_utf8_lossy(&buffer[..n]).to_uppercase();
+                        let name = if request.contains("INCR") {
+                            "INCR"
+                        } else {
+                            "GET"
+                        };
+                        let count = {
+                            let mut counter = counter.lock().unwrap();
+                            let count = counter.entry(name.to_string()).or_insert(0);
+                            *count += 1;
+                            *count
+                        };
+                        if count == 1 || name == "INCR" {
+                            return;
+                        }
+                        socket.write_all(b"$2\r\nok\r\n").await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, received)
+    }
+
+    #[actix::test]
+    async fn test_only_reads_are_resent_after_disconnect() {
+        let (port, received) = flaky_redis().await;
+        let config = RedisConfig {
+            host: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        };
+        let mut connection = RedisConnector::new(&config).connect().await.unwrap();
+        // Запись могла выполниться до обрыва, ее повтор выполнил бы ее дважды
+        assert!(redis::cmd("INCR")
+            .arg("counter")
+            .query_async::<_, i64>(&mut connection)
+            .await
+            .is_err());
+        assert_eq!(received.lock().unwrap()["INCR"], 1);
+        // Чтение безопасно повторить на новом подключении
+        let value: String = redis::cmd("GET")
+            .arg("key")
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(value, "ok");
+        assert_eq!(received.lock().unwrap()["GET"], 2);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_control_log_replays_missed_messages() {
+        let config = RedisConfig {
+            namespace: format!("test_{}:", Uuid::new_v4()),
+            ..Default::default()
+        };
+        let connection = RedisConnector::new(&config).connect().await.unwrap();
+        let log = ControlLog::new(PubSubTransport::new(connection, config), 100);
+        assert_eq!(log.last_id().await.unwrap(), None);
+        log.publish("subscribe", &serde_json::json!({"user_id": 1}))
+            .await
+            .unwrap();
+        let cursor = log.last_id().await.unwrap();
+        for user_id in [2, 3] {
+            log.publish("unsubscribe", &serde_json::json!({ "user_id": user_id }))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(log.since(None).await.unwrap().len(), 3);
+        let missed = log.since(cursor.as_deref()).await.unwrap();
+        assert_eq!(missed.len(), 2);
+        assert_eq!(missed[0].1, "unsubscribe");
+        let payload: serde_json::Value = serde_json::from_str(&missed[1].2).unwrap();
+        assert_eq!(payload["user_id"], 3);
+        assert_eq!(log.last_id().await.unwrap().as_ref(), Some(&missed[1].0));
+    }
+}
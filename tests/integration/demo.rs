@@ -0,0 +1,127 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use chat::database::data::{ChatInfo, ChatType, UserInfo};
+    use chat::database::{DBError, MockDatabase, StringError};
+    use chat::demo::{demo_name, seed, SeedConfig, SeedReport, DEMO_FIRST_USER_ID};
+    use uuid::Uuid;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn chat_info(users: Vec<i64>, chat_type: ChatType, name: String) -> ChatInfo {
+        ChatInfo {
+            id: Uuid::new_v4(),
+            name,
+            member_count: users.len(),
+            users,
+            chat_type,
+            delivery_mode: None,
+            notifications: Default::default(),
+            post_policy: Default::default(),
+            labels: Default::default(),
+            message_ttl_secs: None,
+            last_read: None,
+            role: Default::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            SeedConfig::parse(&args("--users 5 --messages 100")).unwrap(),
+            SeedConfig {
+                users: 5,
+                messages: 100,
+            }
+        );
+        assert_eq!(SeedConfig::parse(&[]).unwrap(), SeedConfig::default());
+        assert!(SeedConfig::parse(&args("--users 1")).is_err());
+        assert!(SeedConfig::parse(&args("--messages")).is_err());
+        assert!(SeedConfig::parse(&args("--chats 3")).is_err());
+        assert_eq!(demo_name(0), "Alice");
+        assert_eq!(demo_name(21), "Bob 2");
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let messages = Arc::new(Mutex::new(vec![]));
+        let stored = messages.clone();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_info().returning(|_| {
+            Err(DBError::LogicError(Box::new(StringError::new(
+                "invalid_user",
+            ))))
+        });
+        db.expect_create_new_user().times(5).returning(|id, name| {
+            Ok(UserInfo {
+                id: id.get(),
+                name,
+                chats: vec![],
+                profile: Default::default(),
+            })
+        });
+        db.expect_create_new_chat()
+            .returning(|creator_id, mut users, chat_type, name| {
+                users.push(creator_id);
+                Ok(chat_info(
+                    users.into_iter().map(i64::from).collect(),
+                    chat_type,
+                    name,
+                ))
+            });
+        db.expect_add_new_message_to_chat()
+            .returning(move |message| {
+                stored.lock().unwrap().push(message);
+                Ok(())
+            });
+        let report = seed(
+            &db,
+            &SeedConfig {
+                users: 5,
+                messages: 70,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report,
+            SeedReport {
+                already_seeded: false,
+                users: 5,
+                group_chats: 4,
+                private_chats: 3,
+                messages: 70,
+            }
+        );
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 70);
+        // История идет по порядку и заканчивается сейчас
+        assert!(messages
+            .windows(2)
+            .all(|pair| pair[0].date.timestamp < pair[1].date.timestamp));
+        assert!(messages
+            .iter()
+            .all(|message| message.sender_id >= DEMO_FIRST_USER_ID.0));
+        assert!(messages.iter().any(|message| message.reply_to.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_seed_twice() {
+        let mut db = MockDatabase::new();
+        db.expect_get_user_info().returning(|id| {
+            Ok(UserInfo {
+                id: id.get(),
+                name: "Alice".into(),
+                chats: vec![],
+                profile: Default::default(),
+            })
+        });
+        db.expect_create_new_user().never();
+        let report = seed(&db, &SeedConfig::default()).await.unwrap();
+        assert!(report.already_seeded);
+    }
+}
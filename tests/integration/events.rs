@@ -0,0 +1,89 @@
+#[cfg(test)]
+mod tests {
+    use chat::actors::websocket_actor::ChatMessage;
+    use chat::events::{
+        encode, negotiate_version, to_redis_payload, ServerEvent, MIN_PROTOCOL_VERSION,
+        PROTOCOL_VERSION,
+    };
+    use chat::ids::{ChatId, UserId};
+    use chat::validation::{validate_name, FieldError};
+    use serde_json::Value;
+
+    fn decode(frame: String) -> Value {
+        serde_json::from_str(&frame).unwrap()
+    }
+
+    fn field_error() -> FieldError {
+        validate_name("msg_text", "", &Default::default()).unwrap_err()
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(None), MIN_PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(Some(0)), MIN_PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(Some(PROTOCOL_VERSION)), PROTOCOL_VERSION);
+        assert_eq!(
+            negotiate_version(Some(PROTOCOL_VERSION + 5)),
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn test_events_are_stamped() {
+        let event = ServerEvent::Typing {
+            chat_id: ChatId::new_v4(),
+            user_id: UserId(1),
+        };
+        let current = decode(encode(&event, PROTOCOL_VERSION));
+        assert_eq!(current["v"], PROTOCOL_VERSION);
+        assert_eq!(current["event"], "typing");
+        // Событие есть во всех версиях и не меняется, кроме поля v
+        let old = decode(encode(&event, MIN_PROTOCOL_VERSION));
+        assert_eq!(old["v"], MIN_PROTOCOL_VERSION);
+        assert_eq!(old["user_id"], 1);
+    }
+
+    #[test]
+    fn test_new_events_downgraded_for_old_clients() {
+        let chat_id = ChatId::new_v4();
+        let error = field_error();
+        let failed = ServerEvent::ValidationFailed {
+            chat_id,
+            client_msg_id: Some("a".into()),
+            fields: vec![error.clone()],
+        };
+        let current = decode(encode(&failed, 2));
+        assert_eq!(current["event"], "validation_failed");
+        assert_eq!(current["client_msg_id"], "a");
+
+        let old = decode(encode(&failed, 1));
+        assert_eq!(old["event"], "error");
+        assert_eq!(old["message"], error.message.as_str());
+        assert_eq!(old["v"], 1);
+        assert!(old.get("fields").is_none());
+
+        let read_only = ServerEvent::ReadOnly {
+            chat_id,
+            client_msg_id: None,
+            retry_after_secs: 30,
+        };
+        assert_eq!(decode(encode(&read_only, 2))["event"], "read_only");
+        let old = decode(encode(&read_only, 1));
+        assert_eq!(old["event"], "error");
+        assert_eq!(old["message"], "read_only");
+    }
+
+    #[test]
+    fn test_redis_payload_still_readable() {
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "sender_id": 1, "date": 1000, "msg_text": "hi"}"#,
+        )
+        .unwrap();
+        let payload = to_redis_payload(&message);
+        assert_eq!(decode(payload.clone())["v"], PROTOCOL_VERSION);
+        // Экземпляры, которые не знают о поле v, читают сообщение как раньше
+        let read: ChatMessage = serde_json::from_str(&payload).unwrap();
+        assert_eq!(read.msg_text, "hi");
+        assert_eq!(read.sender_id, 1);
+    }
+}
@@ -0,0 +1,85 @@
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chat::config::NameRules;
+    use chat::database::StringError;
+    use chat::i18n::{translate, DisplayHints, Locale, TIMEZONE_HEADER};
+    use chat::validation::validate_name;
+
+    #[test]
+    fn test_accept_language() {
+        assert_eq!(Locale::from_accept_language("ru-RU,ru;q=0.9"), Locale::Ru);
+        assert_eq!(
+            Locale::from_accept_language("de-DE, en;q=0.5, ru;q=0.8"),
+            Locale::Ru
+        );
+        assert_eq!(Locale::from_accept_language("ru;q=0, fr"), Locale::En);
+        assert_eq!(Locale::from_accept_language(""), Locale::En);
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            translate(Locale::Ru, "too_long", &[("max", "5".into())]),
+            "Должно быть не длиннее 5 символов"
+        );
+        assert_eq!(translate(Locale::Ru, "unknown_code", &[]), "unknown_code");
+    }
+
+    #[test]
+    fn test_database_error_is_translated() {
+        let error = StringError {
+            code: "too_many_attachments",
+            args: vec![("max", "10".into())],
+        };
+        assert_eq!(
+            error.to_string(),
+            "A message can have at most 10 attachments"
+        );
+        assert_eq!(
+            translate(Locale::Ru, error.code, &error.args),
+            "В сообщении может быть не больше 10 вложений"
+        );
+    }
+
+    #[test]
+    fn test_field_error_keeps_code() {
+        let error = validate_name("user_name", "", &NameRules::default()).unwrap_err();
+        assert_eq!(error.message, "Must be at least 1 characters long");
+        let error = error.localize(Locale::Ru);
+        assert_eq!(error.code, "too_short");
+        assert_eq!(error.message, "Должно быть не короче 1 символов");
+    }
+
+    #[test]
+    fn test_display_hints_from_headers() {
+        let req = TestRequest::default()
+            .insert_header(("Accept-Language", "ru"))
+            .insert_header((TIMEZONE_HEADER, "Europe/Moscow"))
+            .to_http_request();
+        let hints = DisplayHints::from_request(&req);
+        assert_eq!(hints.locale, Locale::Ru);
+        assert_eq!(hints.timezone, chrono_tz::Europe::Moscow);
+
+        let req = TestRequest::default()
+            .insert_header((TIMEZONE_HEADER, "Mars/Olympus"))
+            .to_http_request();
+        assert_eq!(DisplayHints::from_request(&req), DisplayHints::default());
+    }
+
+    #[test]
+    fn test_format_time() {
+        // 2024-01-05 11:03:00 UTC
+        let date = chrono::Duration::milliseconds(1_704_452_580_000);
+        let hints = DisplayHints {
+            locale: Locale::Ru,
+            timezone: chrono_tz::Europe::Moscow,
+        };
+        let time = hints.format_time(date);
+        assert_eq!(time.utc, "2024-01-05T11:03:00.000Z");
+        assert_eq!(time.local, "05.01.2024 14:03 MSK");
+
+        let time = DisplayHints::default().format_time(date);
+        assert_eq!(time.local, "Jan 5, 2024, 11:03 UTC");
+    }
+}
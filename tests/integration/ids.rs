@@ -0,0 +1,40 @@
+#[cfg(test)]
+mod tests {
+    use chat::ids::{ChatId, UserId};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_ids_serialize_as_inner_values() {
+        let chat_id = Uuid::new_v4();
+        assert_eq!(
+            serde_json::to_value(UserId(42)).unwrap(),
+            serde_json::json!(42)
+        );
+        assert_eq!(
+            serde_json::to_value(ChatId(chat_id)).unwrap(),
+            serde_json::json!(chat_id.to_string())
+        );
+        assert_eq!(serde_json::from_str::<UserId>("7").unwrap(), UserId(7));
+        assert_eq!(
+            serde_json::from_value::<ChatId>(serde_json::json!(chat_id)).unwrap(),
+            ChatId(chat_id)
+        );
+        // id чата - не число, а id пользователя - не строка
+        assert!(serde_json::from_str::<ChatId>("7").is_err());
+        assert!(serde_json::from_value::<UserId>(serde_json::json!(chat_id)).is_err());
+    }
+
+    #[test]
+    fn test_ids_parse_and_display() {
+        let chat_id = Uuid::new_v4();
+        assert_eq!("15".parse::<UserId>().unwrap(), UserId(15));
+        assert!("abc".parse::<UserId>().is_err());
+        assert_eq!(
+            chat_id.to_string().parse::<ChatId>().unwrap(),
+            ChatId(chat_id)
+        );
+        assert!("15".parse::<ChatId>().is_err());
+        assert_eq!(UserId(15).to_string(), "15");
+        assert_eq!(ChatId(chat_id).to_string(), chat_id.to_string());
+    }
+}
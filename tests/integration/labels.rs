@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use chat::database::data::{ChatLabels, LabelFilter};
+
+    fn labels(language: Option<&str>, labels: &[&str]) -> ChatLabels {
+        ChatLabels {
+            language: language.map(Into::into),
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_filter_by_language_and_label() {
+        let chat = labels(Some("pt-br"), &["gaming"]);
+        assert!(LabelFilter::default().matches(&chat));
+        let filter = LabelFilter {
+            language: Some("PT-BR".into()),
+            label: Some("gaming".into()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&chat));
+        assert!(!filter.matches(&labels(Some("en"), &["gaming"])));
+        assert!(!filter.matches(&labels(None, &[])));
+    }
+
+    #[test]
+    fn test_nsfw_hidden_by_default() {
+        let chat = labels(Some("en"), &["nsfw", "art"]);
+        assert!(chat.is_nsfw());
+        assert!(!LabelFilter::default().matches(&chat));
+        assert!(LabelFilter {
+            include_nsfw: true,
+            ..Default::default()
+        }
+        .matches(&chat));
+        // Кто явно ищет метку nsfw, тот ее и получает
+        assert!(LabelFilter {
+            label: Some("nsfw".into()),
+            ..Default::default()
+        }
+        .matches(&chat));
+    }
+}
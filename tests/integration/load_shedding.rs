@@ -0,0 +1,63 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::LoadShedding;
+    use chat::events::{self, ServerEvent};
+    use chat::load_shedding::{overload_reason, OverloadReason, QueueDepth};
+
+    fn config() -> LoadShedding {
+        LoadShedding {
+            enabled: true,
+            max_broker_queue: 100,
+            shed_when_read_only: true,
+            retry_after_secs: 15,
+        }
+    }
+
+    #[test]
+    fn test_overload_reason() {
+        let config = config();
+        assert_eq!(overload_reason(&config, 100, false), None);
+        assert_eq!(
+            overload_reason(&config, 101, false),
+            Some(OverloadReason::BrokerQueue)
+        );
+        assert_eq!(
+            overload_reason(&config, 0, true),
+            Some(OverloadReason::ReadOnly)
+        );
+        // Режим только для чтения сам по себе не мешает подключаться, если так настроено
+        let config = LoadShedding {
+            shed_when_read_only: false,
+            ..config
+        };
+        assert_eq!(overload_reason(&config, 0, true), None);
+        let config = LoadShedding {
+            enabled: false,
+            ..config
+        };
+        assert_eq!(overload_reason(&config, 1000, true), None);
+    }
+
+    #[test]
+    fn test_queue_depth() {
+        let queue = QueueDepth::new();
+        let first = queue.enter();
+        let second = queue.enter();
+        assert_eq!(queue.depth(), 2);
+        drop(first);
+        assert_eq!(queue.depth(), 1);
+        drop(second);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn test_reconnect_hint_event() {
+        let frame: serde_json::Value = serde_json::from_str(&events::encode(
+            &ServerEvent::ReconnectHint { after_seconds: 15 },
+            events::PROTOCOL_VERSION,
+        ))
+        .unwrap();
+        assert_eq!(frame["event"], "reconnect_hint");
+        assert_eq!(frame["after_seconds"], 15);
+    }
+}
@@ -1,2 +1,39 @@
 pub mod api;
+pub mod bots;
+pub mod calls;
+pub mod client_ip;
+pub mod clock;
+pub mod config;
+pub mod content;
+pub mod coordination;
 pub mod database;
+pub mod delivery;
+pub mod demo;
+pub mod events;
+pub mod i18n;
+pub mod ids;
+pub mod labels;
+pub mod load_shedding;
+pub mod mentions;
+pub mod metrics;
+pub mod migration;
+pub mod moderation;
+pub mod pagination;
+pub mod presence;
+pub mod purge;
+pub mod rate_limit;
+pub mod read_only;
+pub mod repair;
+pub mod schema;
+pub mod secrets;
+pub mod services;
+pub mod session_binding;
+pub mod sharing;
+pub mod soak;
+pub mod storage;
+pub mod templates;
+pub mod text;
+pub mod uploads;
+pub mod usage;
+pub mod validation;
+pub mod websocket;
@@ -0,0 +1,50 @@
+#[cfg(test)]
+mod tests {
+    use chat::database::data::UserInfo;
+    use chat::mentions::{parse_mentions, resolve_mentions, Mention, MAX_MENTIONS_PER_MESSAGE};
+
+    fn member(id: i64, name: &str) -> UserInfo {
+        UserInfo {
+            id,
+            name: name.into(),
+            chats: vec![],
+            profile: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(
+            parse_mentions("@42, ask @Alice_Smith and @alice_smith (@7)"),
+            vec![
+                Mention::Id(42),
+                Mention::Name("alice_smith".into()),
+                Mention::Id(7)
+            ]
+        );
+        // Адрес почты и одинокая '@' упоминаниями не считаются
+        assert!(parse_mentions("write to bob@example.com @ noon").is_empty());
+        assert_eq!(
+            parse_mentions("привет, @Вася!"),
+            vec![Mention::Name("вася".into())]
+        );
+    }
+
+    #[test]
+    fn test_mentions_are_limited() {
+        let text: String = (0..100).map(|id| format!("@{id} ")).collect();
+        assert_eq!(parse_mentions(&text).len(), MAX_MENTIONS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn test_resolve_mentions() {
+        let members = [
+            member(1, "Sender"),
+            member(2, "Alice Smith"),
+            member(3, "Bob"),
+        ];
+        let mentions = parse_mentions("@sender @alice_smith @3 @99 @carol");
+        // Отправитель и те, кого нет в чате, не упоминаются
+        assert_eq!(resolve_mentions(&mentions, &members, 1), vec![2, 3]);
+    }
+}
@@ -0,0 +1,39 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chat::metrics::{
+        chat_size_bucket, gather, observe_scylla_error, watch_scylla_driver, SCYLLA_TIMEOUTS,
+    };
+    use scylla::transport::errors::{BadQuery, QueryError};
+
+    #[test]
+    fn test_chat_size_buckets() {
+        assert_eq!(chat_size_bucket(0), "1-2");
+        assert_eq!(chat_size_bucket(2), "1-2");
+        assert_eq!(chat_size_bucket(3), "3-10");
+        assert_eq!(chat_size_bucket(100), "11-100");
+        assert_eq!(chat_size_bucket(1000), "101-1000");
+        assert_eq!(chat_size_bucket(1001), "1000+");
+    }
+
+    #[test]
+    fn test_scylla_timeouts() {
+        let client = SCYLLA_TIMEOUTS.with_label_values(&["client"]);
+        let before = client.get();
+        observe_scylla_error(&QueryError::TimeoutError);
+        observe_scylla_error(&QueryError::RequestTimeout("too slow".into()));
+        observe_scylla_error(&QueryError::BadQuery(BadQuery::Other("bad".into())));
+        assert_eq!(client.get(), before + 2);
+    }
+
+    #[test]
+    fn test_scylla_driver_metrics() {
+        watch_scylla_driver(Arc::new(scylla::Metrics::new()));
+        let exported = gather();
+        assert!(exported.contains("chat_scylla_queries_total 0"));
+        assert!(exported.contains("chat_scylla_retries_total 0"));
+        // Задержек еще нет, пока драйвер не выполнил ни одного запроса
+        assert!(!exported.contains("chat_scylla_latency_avg_ms"));
+    }
+}
@@ -0,0 +1,112 @@
+#[cfg(test)]
+mod tests {
+    use chat::actors::websocket_actor::ChatMessage;
+    use chat::database::data::{ChatInfo, ChatType, UserInfo};
+    use chat::database::{MockDatabase, PageIndex};
+    use chat::ids::{ChatId, UserId};
+    use chat::migration::{Migration, MigrationProgress};
+    use mockall::predicate::eq;
+    use uuid::Uuid;
+
+    fn page(index: Option<u8>) -> PageIndex {
+        serde_json::from_value(serde_json::json!({ "index": index.map(|i| vec![i]) })).unwrap()
+    }
+
+    fn message(chat_id: Uuid, text: &str) -> ChatMessage {
+        ChatMessage {
+            chat_id,
+            message_id: Uuid::new_v4(),
+            sender_id: 1,
+            date: chrono::Duration::milliseconds(1000).into(),
+            msg_text: text.into(),
+            edited_at: None,
+            reply_to: None,
+            attachments: vec![],
+            forwarded_from: None,
+            mentions: vec![],
+            client_msg_id: None,
+            delivery_id: None,
+            call: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migration_copies_everything_once() {
+        let chat_id = Uuid::new_v4();
+        let mut source = MockDatabase::new();
+        source
+            .expect_get_user_list()
+            .returning(|| Ok(vec![UserId(2), UserId(1)]));
+        source.expect_get_user_info().returning(|id| {
+            Ok(UserInfo {
+                id: id.get(),
+                name: format!("user {id}"),
+                chats: vec![],
+                profile: Default::default(),
+            })
+        });
+        source
+            .expect_get_user_chats()
+            .returning(move |_| Ok(vec![ChatId(chat_id)]));
+        source
+            .expect_get_chat_info()
+            .times(1)
+            .returning(move |_, _| {
+                Ok(ChatInfo {
+                    id: chat_id,
+                    name: "chat".into(),
+                    users: vec![1, 2],
+                    chat_type: ChatType::Group,
+                    member_count: 2,
+                    delivery_mode: None,
+                    notifications: Default::default(),
+                    post_policy: Default::default(),
+                    labels: Default::default(),
+                    message_ttl_secs: None,
+                    last_read: None,
+                    role: Default::default(),
+                    permissions: Default::default(),
+                })
+            });
+        source.expect_get_chat_history_paged().times(2).returning(
+            move |_, _, _, index| match index {
+                None => Ok((vec![message(chat_id, "first")], page(Some(1)))),
+                Some(_) => Ok((vec![message(chat_id, "second")], page(None))),
+            },
+        );
+
+        let mut target = MockDatabase::new();
+        target
+            .expect_create_new_user()
+            .times(2)
+            .returning(|id, name| {
+                Ok(UserInfo {
+                    id: id.get(),
+                    name,
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+            });
+        target.expect_import_chat().times(1).returning(|_| Ok(()));
+        target
+            .expect_import_messages()
+            .with(eq(ChatId(chat_id)), mockall::predicate::always())
+            .times(2)
+            .returning(|_, _| Ok(()));
+
+        let progress = Migration::new(&source, &target, None)
+            .unwrap()
+            .page_size(1)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(
+            progress,
+            MigrationProgress {
+                users: 2,
+                chats: 1,
+                messages: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chat::config::{Config, ConfigHandle};
+    use chat::moderation::{check_wordlist, ModerationFilter, WordlistFilter};
+
+    #[test]
+    fn test_check_wordlist() {
+        let words = vec!["spam".to_string(), "Scam".to_string()];
+        assert!(check_wordlist("msg_text", "Hello there", &words).is_ok());
+        // Слова ищутся целиком и без учета регистра
+        assert!(check_wordlist("msg_text", "Spamming is fine", &words).is_ok());
+        let error = check_wordlist("msg_text", "This is a SCAM!", &words).unwrap_err();
+        assert_eq!(error.field, "msg_text");
+        assert_eq!(error.code, "blocked_word");
+        assert!(check_wordlist("msg_text", "spam", &[]).is_ok());
+    }
+
+    #[test]
+    fn test_wordlist_filter_follows_reloads() {
+        let path = std::env::temp_dir().join(format!(
+            "chat_config_moderation_{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, r#"{"moderation_wordlist": ["spam"]}"#).unwrap();
+        let handle = ConfigHandle::load_from(path.clone()).unwrap();
+        let filter = WordlistFilter::new(handle.clone());
+        assert!(filter.check("msg_text", "no spam here").is_err());
+
+        std::fs::write(&path, r#"{"moderation_wordlist": []}"#).unwrap();
+        handle.reload().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(filter.check("msg_text", "no spam here").is_ok());
+
+        let filter = WordlistFilter::new(ConfigHandle::new(
+            PathBuf::from("config.json"),
+            Config::default(),
+        ));
+        assert!(filter.check("msg_text", "anything").is_ok());
+    }
+}
@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, Uri},
+        HttpResponse,
+    };
+    use chat::database::PageIndex;
+    use chat::pagination::{PageMeta, HAS_MORE_HEADER, NEXT_CURSOR_HEADER, TOTAL_COUNT_HEADER};
+
+    #[test]
+    fn test_next_link() {
+        let uri: Uri = "/api/chat/members?chat_id=42&cursor=7&page_size=10"
+            .parse()
+            .unwrap();
+        let meta = PageMeta::new(Some("17".into()));
+        assert!(meta.has_more);
+        assert_eq!(
+            meta.next_link(&uri, "cursor").unwrap(),
+            "</api/chat/members?chat_id=42&page_size=10&cursor=17>; rel=\"next\""
+        );
+        // У последней страницы ссылки нет
+        assert!(PageMeta::new(None).next_link(&uri, "cursor").is_none());
+
+        let uri: Uri = "/api/admin/users".parse().unwrap();
+        assert_eq!(
+            PageMeta::new(Some("a b".into()))
+                .next_link(&uri, "cursor")
+                .unwrap(),
+            "</api/admin/users?cursor=a%20b>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        let uri: Uri = "/api/chat/history?chat_id=1&page_size=20".parse().unwrap();
+        let mut response = HttpResponse::Ok();
+        PageMeta::new(Some("0a0b".into()))
+            .with_total(Some(120))
+            .insert_headers(&mut response, &uri, "cursor");
+        let response = response.finish();
+        let headers = response.headers();
+        assert_eq!(headers.get(NEXT_CURSOR_HEADER).unwrap(), "0a0b");
+        assert_eq!(headers.get(HAS_MORE_HEADER).unwrap(), "true");
+        assert_eq!(headers.get(TOTAL_COUNT_HEADER).unwrap(), "120");
+        assert_eq!(
+            headers.get(header::LINK).unwrap(),
+            "</api/chat/history?chat_id=1&page_size=20&cursor=0a0b>; rel=\"next\""
+        );
+
+        let mut response = HttpResponse::Ok();
+        PageMeta::new(None).insert_headers(&mut response, &uri, "cursor");
+        let response = response.finish();
+        let headers = response.headers();
+        assert_eq!(headers.get(HAS_MORE_HEADER).unwrap(), "false");
+        assert!(headers.get(NEXT_CURSOR_HEADER).is_none());
+        assert!(headers.get(TOTAL_COUNT_HEADER).is_none());
+        assert!(headers.get(header::LINK).is_none());
+    }
+
+    #[test]
+    fn test_page_index_cursor() {
+        let index: PageIndex =
+            serde_json::from_value(serde_json::json!({ "index": [1, 171, 255] })).unwrap();
+        let cursor = index.to_cursor().unwrap();
+        assert_eq!(cursor, "01abff");
+        let parsed = PageIndex::from_cursor(&cursor).unwrap();
+        assert!(parsed.has_next_page());
+        assert_eq!(parsed.to_cursor().unwrap(), cursor);
+        assert!(PageIndex::from_cursor("not hex").is_none());
+
+        let last: PageIndex = serde_json::from_value(serde_json::json!({ "index": null })).unwrap();
+        assert!(last.to_cursor().is_none());
+    }
+}
@@ -0,0 +1,91 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::RedisConfig;
+    use chat::events::{self, ServerEvent};
+    use chat::ids::{ChatId, UserId};
+    use chat::presence::PresenceTracker;
+    use serial_test::serial;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    async fn tracker(ttl: Duration) -> PresenceTracker {
+        PresenceTracker::connect(&RedisConfig::default(), ttl)
+            .await
+            .unwrap()
+    }
+
+    /// Случайный пользователь, чтобы не зависеть от отметок прошлых запусков
+    fn user_id() -> UserId {
+        UserId((Uuid::new_v4().as_u128() >> 65) as i64)
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_presence_across_instances() {
+        let user_id = user_id();
+        let first = tracker(Duration::from_secs(60)).await;
+        let second = tracker(Duration::from_secs(60)).await;
+        assert!(first.online_users(&[user_id]).await.unwrap().is_empty());
+
+        assert!(first.set_online(user_id).await.unwrap());
+        // Второй экземпляр не порождает еще одного появления в сети
+        assert!(!second.set_online(user_id).await.unwrap());
+        assert_eq!(
+            first
+                .online_users(&[user_id, UserId(user_id.get() + 1)])
+                .await
+                .unwrap(),
+            vec![user_id]
+        );
+
+        assert!(!first.set_offline(user_id).await.unwrap());
+        assert_eq!(
+            second.online_users(&[user_id]).await.unwrap(),
+            vec![user_id]
+        );
+        assert!(second.set_offline(user_id).await.unwrap());
+        assert!(first.online_users(&[user_id]).await.unwrap().is_empty());
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_presence_of_dead_instance_expires() {
+        let user_id = user_id();
+        let dead = tracker(Duration::from_millis(200)).await;
+        let alive = tracker(Duration::from_secs(60)).await;
+        assert!(dead.set_online(user_id).await.unwrap());
+        actix::clock::sleep(Duration::from_millis(300)).await;
+        assert!(alive.online_users(&[user_id]).await.unwrap().is_empty());
+        // Отметка упавшего экземпляра не мешает снова появиться в сети
+        assert!(alive.set_online(user_id).await.unwrap());
+        alive.refresh(&[user_id]).await.unwrap();
+        assert!(alive.set_offline(user_id).await.unwrap());
+    }
+
+    #[test]
+    fn test_presence_events() {
+        let chat_id = ChatId::new_v4();
+        for (event, name) in [
+            (
+                ServerEvent::MemberOnline {
+                    chat_id,
+                    user_id: UserId(3),
+                },
+                "member_online",
+            ),
+            (
+                ServerEvent::MemberOffline {
+                    chat_id,
+                    user_id: UserId(3),
+                },
+                "member_offline",
+            ),
+        ] {
+            let frame: serde_json::Value =
+                serde_json::from_str(&events::encode(&event, events::PROTOCOL_VERSION)).unwrap();
+            assert_eq!(frame["event"], name);
+            assert_eq!(frame["chat_id"], chat_id.to_string());
+            assert_eq!(frame["user_id"], 3);
+        }
+    }
+}
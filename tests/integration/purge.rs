@@ -0,0 +1,74 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::PurgeConfig;
+    use chat::database::data::ChatRecord;
+    use chat::database::MockDatabase;
+    use chat::ids::{ChatId, UserId};
+    use chat::purge::{purge_abandoned_chats, PurgeReport};
+    use mockall::predicate::eq;
+    use uuid::Uuid;
+
+    fn chat(id: Uuid, age_secs: i64, users: Vec<i64>) -> ChatRecord {
+        let now = chrono::Utc::now() - chrono::DateTime::UNIX_EPOCH;
+        ChatRecord {
+            id,
+            creation_date: Some(now - chrono::Duration::seconds(age_secs)),
+            users,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_deletes_only_abandoned_chats() {
+        let empty = Uuid::new_v4();
+        let orphaned = Uuid::new_v4();
+        let alive = Uuid::new_v4();
+        let fresh = Uuid::new_v4();
+        let undated = Uuid::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_list()
+            .returning(|| Ok(vec![UserId(1), UserId(2)]));
+        db.expect_get_chat_list().returning(move || {
+            Ok(vec![
+                chat(empty, 7200, vec![]),
+                chat(orphaned, 7200, vec![5, 6]),
+                chat(alive, 7200, vec![2, 5]),
+                chat(fresh, 10, vec![]),
+                ChatRecord {
+                    id: undated,
+                    creation_date: None,
+                    users: vec![],
+                },
+            ])
+        });
+        db.expect_delete_chat()
+            .with(eq(ChatId(empty)))
+            .times(1)
+            .returning(|_| Ok(()));
+        db.expect_delete_chat()
+            .with(eq(ChatId(orphaned)))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        // Чат без даты создания не удаляется: он может оказаться новым
+        let report = purge_abandoned_chats(&db, &PurgeConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            PurgeReport {
+                scanned: 5,
+                empty: 1,
+                orphaned: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_purge_config_defaults() {
+        let config = PurgeConfig::default();
+        // Чистка удаляет историю, поэтому включается только явно
+        assert!(!config.enabled);
+        assert_eq!(config.interval_secs, 3600);
+        assert_eq!(config.min_age_secs, 3600);
+    }
+}
@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::{AuthLockout, RedisConfig};
+    use chat::rate_limit::{RateLimit, RateLimiter};
+    use serial_test::serial;
+    use uuid::Uuid;
+
+    #[actix::test]
+    #[serial]
+    async fn test_hit_counts_within_window() {
+        let limiter = RateLimiter::new("127.0.0.1", 6379).await.unwrap();
+        let key = format!("test:{}", Uuid::new_v4());
+        assert_eq!(limiter.hit(&key, 60).await.unwrap(), 1);
+        assert_eq!(limiter.hit(&key, 60).await.unwrap(), 2);
+        assert_eq!(limiter.hit(&key, 60).await.unwrap(), 3);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_auth_failures_lock_out() {
+        let limiter = RateLimiter::new("127.0.0.1", 6379).await.unwrap();
+        let subject = format!("ip:{}", Uuid::new_v4());
+        let limits = AuthLockout {
+            max_failures: 3,
+            window_secs: 60,
+            lockout_secs: 30,
+        };
+        assert!(!limiter
+            .register_auth_failure(&subject, &limits)
+            .await
+            .unwrap());
+        assert!(!limiter
+            .register_auth_failure(&subject, &limits)
+            .await
+            .unwrap());
+        assert!(limiter.lockout_remaining(&subject).await.unwrap().is_none());
+        assert!(limiter
+            .register_auth_failure(&subject, &limits)
+            .await
+            .unwrap());
+        let remaining = limiter.lockout_remaining(&subject).await.unwrap().unwrap();
+        assert!(remaining <= 30);
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_namespaces_do_not_share_counters() {
+        let namespaced = |namespace: &str| RedisConfig {
+            host: "127.0.0.1".into(),
+            port: 6379,
+            namespace: namespace.into(),
+            ..Default::default()
+        };
+        let staging = RateLimiter::connect(&namespaced("chat:staging:"))
+            .await
+            .unwrap();
+        let production = RateLimiter::connect(&namespaced("chat:prod:"))
+            .await
+            .unwrap();
+        let key = format!("test:{}", Uuid::new_v4());
+        assert_eq!(staging.hit(&key, 60).await.unwrap(), 1);
+        assert_eq!(staging.hit(&key, 60).await.unwrap(), 2);
+        assert_eq!(production.hit(&key, 60).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,64 @@
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use actix_web::http::Method;
+    use chat::config::ReadOnlyConfig;
+    use chat::middlewares::read_only_middleware::is_write;
+    use chat::read_only::WriteHealth;
+
+    fn config() -> ReadOnlyConfig {
+        ReadOnlyConfig {
+            enabled: false,
+            failure_threshold: 3,
+            cooldown_secs: 30,
+        }
+    }
+
+    #[test]
+    fn test_trips_after_failed_writes() {
+        let config = config();
+        let health = WriteHealth::default();
+        let start = Instant::now();
+        health.record_at(false, &config, start);
+        health.record_at(false, &config, start);
+        assert!(!health.is_read_only_at(&config, start));
+        // Удачная запись сбрасывает счетчик
+        health.record_at(true, &config, start);
+        health.record_at(false, &config, start);
+        health.record_at(false, &config, start);
+        assert!(!health.is_read_only_at(&config, start));
+        health.record_at(false, &config, start);
+        assert!(health.is_read_only_at(&config, start));
+        assert!(health.is_read_only_at(&config, start + Duration::from_secs(29)));
+        // После паузы сервис снова пробует писать, и первая же неудача возвращает режим
+        let retry = start + Duration::from_secs(30);
+        assert!(!health.is_read_only_at(&config, retry));
+        health.record_at(false, &config, retry);
+        assert!(health.is_read_only_at(&config, retry));
+        health.record_at(true, &config, retry);
+        assert!(!health.is_read_only_at(&config, retry));
+    }
+
+    #[test]
+    fn test_enabled_in_config() {
+        let config = ReadOnlyConfig {
+            enabled: true,
+            ..config()
+        };
+        let health = WriteHealth::default();
+        health.record_at(true, &config, Instant::now());
+        assert!(health.is_read_only_at(&config, Instant::now()));
+    }
+
+    #[test]
+    fn test_writes_are_detected() {
+        assert!(is_write(&Method::POST, "/api/chat/new-group"));
+        assert!(is_write(&Method::PUT, "/api/chat/draft"));
+        assert!(is_write(&Method::DELETE, "/api/chat/message"));
+        assert!(!is_write(&Method::GET, "/api/chat/history"));
+        assert!(!is_write(&Method::GET, "/ws"));
+        assert!(!is_write(&Method::POST, "/api/user/bulk-info"));
+        assert!(!is_write(&Method::POST, "/api/admin/reload-config"));
+    }
+}
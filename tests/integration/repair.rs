@@ -0,0 +1,99 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::RepairConfig;
+    use chat::database::data::{ChatRecord, UserInfo};
+    use chat::database::MockDatabase;
+    use chat::ids::{ChatId, UserId};
+    use chat::repair::{find_inconsistencies, repair, Inconsistency};
+    use mockall::predicate::eq;
+    use uuid::Uuid;
+
+    fn user(id: i64, chats: Vec<Uuid>) -> UserInfo {
+        UserInfo {
+            id,
+            name: format!("user{id}"),
+            chats,
+            profile: Default::default(),
+        }
+    }
+
+    fn chat(id: Uuid, users: Vec<i64>) -> ChatRecord {
+        ChatRecord {
+            id,
+            creation_date: None,
+            users,
+        }
+    }
+
+    fn diverged_db(first: Uuid, second: Uuid) -> MockDatabase {
+        let mut db = MockDatabase::new();
+        // Пользователь 2 не знает о чате first, пользователь 1 помнит чат second,
+        // из которого вышел, а в чате second числится несуществующий пользователь 3
+        db.expect_get_users_with_chats()
+            .returning(move || Ok(vec![user(1, vec![first, second]), user(2, vec![])]));
+        db.expect_get_chat_list()
+            .returning(move || Ok(vec![chat(first, vec![1, 2]), chat(second, vec![3])]));
+        db
+    }
+
+    #[tokio::test]
+    async fn test_find_inconsistencies() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let db = diverged_db(first, second);
+        assert_eq!(
+            find_inconsistencies(&db).await.unwrap(),
+            vec![
+                Inconsistency::MissingUserChat {
+                    user_id: 2,
+                    chat_id: first
+                },
+                Inconsistency::UnknownMember {
+                    chat_id: second,
+                    user_id: 3
+                },
+                Inconsistency::StaleUserChat {
+                    user_id: 1,
+                    chat_id: second
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repair_only_reports_without_fix() {
+        let db = diverged_db(Uuid::new_v4(), Uuid::new_v4());
+        let report = repair(&db, false).await.unwrap();
+        assert_eq!(report.inconsistencies.len(), 3);
+        assert_eq!(report.fixed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_repair_fixes_user_side() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let mut db = diverged_db(first, second);
+        db.expect_add_chat_to_user()
+            .with(eq(UserId(2)), eq(ChatId(first)))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        db.expect_drop_chat_member()
+            .with(eq(ChatId(second)), eq(UserId(3)))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        db.expect_remove_chat_from_user()
+            .with(eq(UserId(1)), eq(ChatId(second)))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let report = repair(&db, true).await.unwrap();
+        assert_eq!(report.fixed, 3);
+    }
+
+    #[test]
+    fn test_repair_config_defaults() {
+        let config = RepairConfig::default();
+        assert!(config.enabled);
+        assert!(!config.fix);
+        assert_eq!(config.interval_secs, 86400);
+    }
+}
@@ -0,0 +1,80 @@
+#[cfg(test)]
+mod tests {
+    use chat::database::data::ChatEventKind;
+    use chat::database::schema::{find_problems, EXPECTED_TABLES, SCHEMA_VERSION};
+    use chat::database::{event_position, message_bucket};
+    use uuid::Uuid;
+
+    fn expected_columns() -> Vec<(String, String, String)> {
+        EXPECTED_TABLES
+            .iter()
+            .flat_map(|(table, columns)| {
+                columns
+                    .iter()
+                    .map(|(column, kind)| (table.to_string(), column.to_string(), kind.to_string()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matching_schema_has_no_problems() {
+        assert!(find_problems(&expected_columns(), Some(SCHEMA_VERSION)).is_empty());
+    }
+
+    #[test]
+    fn test_schema_problems_are_reported() {
+        assert_eq!(find_problems(&[], None).len(), EXPECTED_TABLES.len() + 1);
+
+        let mut columns = expected_columns();
+        columns.retain(|(table, column, _)| !(table == "users" && column == "name"));
+        columns
+            .iter_mut()
+            .filter(|(table, column, _)| table == "chats" && column == "name")
+            .for_each(|(_, _, kind)| *kind = "int".into());
+        let problems = find_problems(&columns, Some(SCHEMA_VERSION + 1));
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("users.name"));
+        assert!(problems[1].contains("chats.name"));
+        assert!(problems[2].contains("newer"));
+    }
+
+    #[test]
+    fn test_message_bucket() {
+        let day = chrono::Duration::days(1);
+        assert_eq!(message_bucket(chrono::Duration::zero()), 0);
+        assert_eq!(message_bucket(day - chrono::Duration::milliseconds(1)), 0);
+        assert_eq!(message_bucket(day * 19_000), 19_000);
+        // Сообщения до начала эпохи попадают в предыдущие сутки, а не в нулевые
+        assert_eq!(message_bucket(-chrono::Duration::milliseconds(1)), -1);
+    }
+
+    #[test]
+    fn test_event_position() {
+        let id = Uuid::from_u128(1_234_567);
+        let date = chrono::Duration::milliseconds(1_700_000_000_000);
+        assert_eq!(event_position(date, id), 1_700_000_000_000_567);
+        // Позиции событий соседних миллисекунд не пересекаются, какими бы ни были id
+        let last = event_position(date, Uuid::from_u128(999));
+        let next = event_position(date + chrono::Duration::milliseconds(1), Uuid::nil());
+        assert!(last < next);
+    }
+
+    #[test]
+    fn test_chat_event_kind_names() {
+        for kind in [
+            ChatEventKind::Message,
+            ChatEventKind::MessageEdited,
+            ChatEventKind::MessageDeleted,
+            ChatEventKind::MemberJoined,
+            ChatEventKind::MemberLeft,
+            ChatEventKind::ChatRenamed,
+        ] {
+            assert_eq!(ChatEventKind::parse(kind.as_str()), Some(kind));
+            assert_eq!(
+                serde_json::to_value(kind).unwrap(),
+                serde_json::Value::from(kind.as_str())
+            );
+        }
+        assert_eq!(ChatEventKind::parse("reaction_added"), None);
+    }
+}
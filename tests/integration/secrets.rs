@@ -0,0 +1,22 @@
+#[cfg(test)]
+mod tests {
+    use chat::secrets::{generate_secret, hash_secret, verify_secret};
+
+    #[test]
+    fn test_generated_secrets_are_unique() {
+        let first = generate_secret();
+        let second = generate_secret();
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_secret_verification() {
+        let secret = generate_secret();
+        let hash = hash_secret(&secret);
+        assert_ne!(hash, secret.as_bytes());
+        assert!(verify_secret(&secret, &hash));
+        assert!(!verify_secret(&generate_secret(), &hash));
+        assert!(!verify_secret(&secret, &[]));
+    }
+}
@@ -0,0 +1,619 @@
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chat::actors::websocket_actor::{ChatMessage, NewChatMessage};
+    use chat::config::{Config, ConfigHandle, MessageRules, NameRules};
+    use chat::database::data::{
+        ChatInfo, ChatType, DeliveryMode, Mute, NotificationSettings, UserInfo,
+    };
+    use chat::database::{ContactLimitError, DBError, MockDatabase, NameTakenError, StringError};
+    use chat::ids::{ChatId, UserId};
+    use chat::moderation::{NoModeration, WordlistFilter};
+    use chat::services::{
+        chat_for_client, compose_message, ChatService, ServiceError, SkipReason, UserService,
+    };
+    use mockall::predicate::eq;
+    use uuid::Uuid;
+
+    fn not_found() -> DBError {
+        DBError::LogicError(Box::new(StringError::new("invalid_user")))
+    }
+
+    fn message(chat_id: Uuid, client_msg_id: Option<&str>) -> ChatMessage {
+        compose_message(
+            UserId(1),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: "Hello @2".into(),
+                reply_to: None,
+                attachments: vec![],
+                client_msg_id: client_msg_id.map(String::from),
+            },
+            &MessageRules::default(),
+            &NoModeration,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ensure_member() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .with(eq(UserId(1)))
+            .returning(move |_| Ok(vec![chat_id]));
+        let service = ChatService::new(&db);
+        assert!(service.ensure_member(UserId(1), chat_id).await.is_ok());
+        assert!(matches!(
+            service.ensure_member(UserId(1), ChatId::new_v4()).await,
+            Err(DBError::LogicError(_))
+        ));
+    }
+
+    #[test]
+    fn test_compose_message() {
+        let chat_id = Uuid::new_v4();
+        let composed = message(chat_id, Some("local-1"));
+        assert_eq!(composed.chat_id, chat_id);
+        assert_eq!(composed.sender_id, 1);
+        assert_eq!(composed.client_msg_id.as_deref(), Some("local-1"));
+        // Все ошибки сообщаются разом
+        let invalid = compose_message(
+            UserId(1),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: "  ".into(),
+                reply_to: None,
+                attachments: vec![],
+                client_msg_id: Some(String::new()),
+            },
+            &MessageRules::default(),
+            &NoModeration,
+        );
+        let Err(ServiceError::Invalid(fields)) = invalid else {
+            panic!("Blank message must be rejected");
+        };
+        let fields: Vec<_> = fields.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["msg_text", "client_msg_id"]);
+        // Сообщению с вложениями текст не нужен
+        let attachment_only = compose_message(
+            UserId(1),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: " ".into(),
+                reply_to: None,
+                attachments: vec![Uuid::new_v4()],
+                client_msg_id: None,
+            },
+            &MessageRules::default(),
+            &NoModeration,
+        )
+        .unwrap();
+        assert_eq!(attachment_only.msg_text, "");
+        let too_many = compose_message(
+            UserId(1),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: "Photos".into(),
+                reply_to: None,
+                attachments: vec![Uuid::new_v4(), Uuid::new_v4()],
+                client_msg_id: None,
+            },
+            &MessageRules {
+                max_attachments: 1,
+                ..Default::default()
+            },
+            &NoModeration,
+        );
+        let Err(ServiceError::Invalid(fields)) = too_many else {
+            panic!("Too many attachments must be rejected");
+        };
+        assert_eq!(fields[0].field, "attachments");
+        // Текст проходит фильтр модерации после обычной проверки
+        let mut config = Config::default();
+        config.dynamic.moderation_wordlist = vec!["spam".into()];
+        let moderation =
+            WordlistFilter::new(ConfigHandle::new(PathBuf::from("config.json"), config));
+        let blocked = compose_message(
+            UserId(1),
+            NewChatMessage {
+                chat_id: ChatId(chat_id),
+                msg_text: "Buy SPAM now".into(),
+                reply_to: None,
+                attachments: vec![],
+                client_msg_id: None,
+            },
+            &MessageRules::default(),
+            &moderation,
+        );
+        let Err(ServiceError::Invalid(fields)) = blocked else {
+            panic!("Message with a forbidden word must be rejected");
+        };
+        assert_eq!(fields[0].code, "blocked_word");
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let chat_id = Uuid::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_claim_client_msg_id()
+            .times(1)
+            .returning(|_, _, _, _, _| Ok(None));
+        db.expect_find_mentions()
+            .times(1)
+            .returning(|_, _, _| Ok(vec![UserId(2)]));
+        db.expect_add_new_message_to_chat()
+            .withf(|message| message.mentions == vec![2])
+            .times(1)
+            .returning(|_| Ok(()));
+        let inserted = ChatService::new(&db)
+            .send_message(message(chat_id, Some("local-1")))
+            .await
+            .unwrap();
+        assert!(!inserted.duplicate);
+        assert_eq!(inserted.message.mentions, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_duplicate() {
+        let chat_id = Uuid::new_v4();
+        let original = Uuid::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_claim_client_msg_id()
+            .returning(move |_, _, _, _, _| Ok(Some((original, chrono::Duration::seconds(1)))));
+        // Повтор не сохраняется
+        db.expect_add_new_message_to_chat().never();
+        let inserted = ChatService::new(&db)
+            .send_message(message(chat_id, Some("local-1")))
+            .await
+            .unwrap();
+        assert!(inserted.duplicate);
+        assert_eq!(inserted.message.message_id, original);
+        assert_eq!(
+            inserted.message.date.timestamp,
+            chrono::Duration::seconds(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message_releases_client_msg_id() {
+        let chat_id = Uuid::new_v4();
+        let composed = message(chat_id, Some("local-1"));
+        let message_id = composed.message_id;
+        let mut db = MockDatabase::new();
+        db.expect_claim_client_msg_id()
+            .returning(|_, _, _, _, _| Ok(None));
+        db.expect_find_mentions().returning(|_, _, _| Ok(vec![]));
+        db.expect_add_new_message_to_chat()
+            .returning(|_| Err(not_found()));
+        db.expect_release_client_msg_id()
+            .withf(move |_, sender_id, client_msg_id, id| {
+                *sender_id == UserId(1) && client_msg_id == "local-1" && *id == message_id
+            })
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        assert!(ChatService::new(&db).send_message(composed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let rules = NameRules {
+            max_length: 5,
+            ..Default::default()
+        };
+        let mut db = MockDatabase::new();
+        db.expect_get_user_info().returning(|id| {
+            if id == UserId(1) {
+                Ok(UserInfo {
+                    id: id.get(),
+                    name: "Existing user".into(),
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+            } else {
+                Err(not_found())
+            }
+        });
+        db.expect_create_new_user()
+            .with(eq(UserId(2)), eq("New".to_string()))
+            .times(1)
+            .returning(|id, name| {
+                Ok(UserInfo {
+                    id: id.get(),
+                    name,
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+            });
+        let service = UserService::new(&db);
+        // Имя существующего пользователя не проверяется
+        let existing = service
+            .authorize(UserId(1), "Too long name", &rules, &[])
+            .await
+            .unwrap();
+        assert_eq!(existing.user.name, "Existing user");
+        assert!(matches!(
+            service
+                .authorize(UserId(2), "Too long name", &rules, &[])
+                .await,
+            Err(ServiceError::Invalid(_))
+        ));
+        let created = service
+            .authorize(UserId(2), " New ", &rules, &[])
+            .await
+            .unwrap();
+        assert_eq!(created.user.name, "New");
+        assert!(created.joined.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authorize_suggests_free_names() {
+        let rules = NameRules {
+            max_length: 6,
+            ..Default::default()
+        };
+        let mut db = MockDatabase::new();
+        db.expect_get_user_info().returning(|_| Err(not_found()));
+        db.expect_create_new_user().times(1).returning(|_, name| {
+            Err(DBError::LogicError(Box::new(NameTakenError {
+                name,
+                suggestions: vec![],
+            })))
+        });
+        db.expect_taken_names().times(1).returning(|names| {
+            // Имя укорачивается под число
+            assert_eq!(names[0], "Alice2");
+            assert_eq!(names[8], "Alic10");
+            Ok(["alice2".to_string(), "alice4".to_string()].into())
+        });
+        let result = UserService::new(&db)
+            .authorize(UserId(2), "Alice", &rules, &[])
+            .await;
+        let Err(ServiceError::Database(DBError::LogicError(e))) = result else {
+            panic!("Name should be taken");
+        };
+        let e = e.downcast_ref::<NameTakenError>().unwrap();
+        assert_eq!(e.name, "Alice");
+        assert_eq!(e.suggestions, vec!["Alice3", "Alice5", "Alice6"]);
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let rules = NameRules::default();
+        let mut db = MockDatabase::new();
+        db.expect_rename_user().returning(|id, name| {
+            if name == "Taken" {
+                Err(DBError::LogicError(Box::new(NameTakenError {
+                    name,
+                    suggestions: vec![],
+                })))
+            } else {
+                Ok(UserInfo {
+                    id: id.get(),
+                    name,
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+            }
+        });
+        db.expect_taken_names()
+            .returning(|_| Ok(["taken2".to_string()].into()));
+        let service = UserService::new(&db);
+        let renamed = service.rename(UserId(1), " Free ", &rules).await.unwrap();
+        assert_eq!(renamed.name, "Free");
+        assert!(matches!(
+            service.rename(UserId(1), " ", &rules).await,
+            Err(ServiceError::Invalid(_))
+        ));
+        let Err(ServiceError::Database(DBError::LogicError(e))) =
+            service.rename(UserId(1), "Taken", &rules).await
+        else {
+            panic!("Name should be taken");
+        };
+        let e = e.downcast_ref::<NameTakenError>().unwrap();
+        assert_eq!(e.suggestions, vec!["Taken3", "Taken4", "Taken5"]);
+    }
+
+    #[actix_web::test]
+    async fn test_contacts() {
+        let mut db = MockDatabase::new();
+        db.expect_get_contacts()
+            .returning(|_| Ok(vec![UserId(2), UserId(3), UserId(4)]));
+        // Удаленный пользователь 4 в список не попадает
+        db.expect_get_users_info().returning(|ids| {
+            Ok(ids
+                .into_iter()
+                .filter(|id| *id != UserId(4))
+                .map(|id| UserInfo {
+                    id: id.get(),
+                    name: if id == UserId(2) { "Zoe" } else { "Adam" }.into(),
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+                .collect())
+        });
+        db.expect_add_contact().times(2).returning(|_, _| Ok(()));
+        let service = UserService::new(&db);
+        let contacts = service.contacts(UserId(1)).await.unwrap();
+        assert_eq!(
+            contacts.iter().map(|user| user.id).collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        // Список заполнен: новый контакт не добавляется, а уже записанный - можно
+        assert!(matches!(
+            service.add_contact(UserId(1), UserId(5), 3).await,
+            Err(DBError::LogicError(e)) if e.is::<ContactLimitError>()
+        ));
+        service.add_contact(UserId(1), UserId(2), 3).await.unwrap();
+        service.add_contact(UserId(1), UserId(5), 4).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_joins_default_chats() {
+        let announcements = ChatId::new_v4();
+        let deleted = ChatId::new_v4();
+        let support = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_info().returning(|id| {
+            if id == UserId(1) {
+                Ok(UserInfo {
+                    id: id.get(),
+                    name: "Existing user".into(),
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+            } else {
+                Err(not_found())
+            }
+        });
+        db.expect_create_new_user().returning(|id, name| {
+            Ok(UserInfo {
+                id: id.get(),
+                name,
+                chats: vec![],
+                profile: Default::default(),
+            })
+        });
+        // Существующего пользователя в чаты по умолчанию не добавляем
+        db.expect_join_chat()
+            .withf(|user_id, _| *user_id == UserId(2))
+            .times(3)
+            .returning(move |_, chat_id| {
+                if chat_id == deleted {
+                    Err(not_found())
+                } else {
+                    Ok(())
+                }
+            });
+        let service = UserService::new(&db);
+        let default_chats = [announcements, deleted, support];
+        let existing = service
+            .authorize(
+                UserId(1),
+                "Existing user",
+                &NameRules::default(),
+                &default_chats,
+            )
+            .await
+            .unwrap();
+        assert!(existing.joined.is_empty());
+        // Удаленный чат пропускается, вход не ломается
+        let created = service
+            .authorize(UserId(2), "New user", &NameRules::default(), &default_chats)
+            .await
+            .unwrap();
+        assert_eq!(created.joined, vec![announcements, support]);
+        assert_eq!(created.user.chats, vec![announcements.get(), support.get()]);
+    }
+
+    #[test]
+    fn test_chat_for_client() {
+        let mut config = Config::default();
+        config.dynamic.max_inline_members = 2;
+        let config = ConfigHandle::new(PathBuf::from("config.json"), config);
+        let chat = ChatInfo {
+            id: Uuid::new_v4(),
+            name: "Big".into(),
+            users: vec![1, 2, 3],
+            chat_type: ChatType::Group,
+            member_count: 3,
+            delivery_mode: None,
+            notifications: Default::default(),
+            post_policy: Default::default(),
+            labels: Default::default(),
+            message_ttl_secs: None,
+            last_read: None,
+            role: Default::default(),
+            permissions: Default::default(),
+        };
+        let chat = chat_for_client(chat, &config);
+        assert!(chat.users.is_empty());
+        assert_eq!(chat.member_count, 3);
+        assert_eq!(chat.delivery_mode, Some(DeliveryMode::AtMostOnce));
+    }
+
+    #[tokio::test]
+    async fn test_create_group_chat_skips_unregistered() {
+        let mut db = MockDatabase::new();
+        db.expect_get_users_info().returning(|user_ids| {
+            Ok(user_ids
+                .into_iter()
+                .filter(|user_id| user_id.get() != 3)
+                .map(|user_id| UserInfo {
+                    id: user_id.get(),
+                    name: format!("User {}", user_id.get()),
+                    chats: vec![],
+                    profile: Default::default(),
+                })
+                .collect())
+        });
+        db.expect_create_new_chat()
+            .withf(|creator, invited, chat_type, _| {
+                *creator == UserId(1)
+                    && invited == &vec![UserId(2), UserId(4)]
+                    && *chat_type == ChatType::Group
+            })
+            .times(1)
+            .returning(|_, invited, chat_type, name| {
+                Ok(ChatInfo {
+                    id: Uuid::new_v4(),
+                    name,
+                    users: invited
+                        .iter()
+                        .map(|user_id| user_id.get())
+                        .chain([1])
+                        .collect(),
+                    chat_type,
+                    member_count: invited.len() + 1,
+                    delivery_mode: None,
+                    notifications: Default::default(),
+                    post_policy: Default::default(),
+                    labels: Default::default(),
+                    message_ttl_secs: None,
+                    last_read: None,
+                    role: Default::default(),
+                    permissions: Default::default(),
+                })
+            });
+        let creation = ChatService::new(&db)
+            .create_group_chat(
+                UserId(1),
+                vec![UserId(2), UserId(3), UserId(4), UserId(3)],
+                "Roster".into(),
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(creation.chat.member_count, 3);
+        // Повтор одного и того же id в отчете не дублируется
+        assert_eq!(creation.skipped.len(), 1);
+        assert_eq!(creation.skipped[0].user_id, 3);
+        assert_eq!(creation.skipped[0].reason, SkipReason::NotRegistered);
+    }
+
+    #[tokio::test]
+    async fn test_chat_summaries() {
+        let (quiet, busy, left) = (ChatId::new_v4(), ChatId::new_v4(), ChatId::new_v4());
+        let mut db = MockDatabase::new();
+        db.expect_get_unread_counts()
+            .returning(move |_| Ok([(busy, 3)].into_iter().collect()));
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![quiet, busy, left]));
+        db.expect_get_chat_info().returning(move |_, chat_id| {
+            if chat_id == left {
+                return Err(not_found());
+            }
+            Ok(ChatInfo {
+                id: chat_id.get(),
+                name: if chat_id == busy { "Busy" } else { "Quiet" }.into(),
+                users: vec![1, 2],
+                chat_type: ChatType::Group,
+                member_count: 2,
+                delivery_mode: None,
+                // Тихий чат заглушен насовсем
+                notifications: NotificationSettings {
+                    mute: (chat_id == quiet).then_some(Mute::Forever),
+                    ..Default::default()
+                },
+                post_policy: Default::default(),
+                labels: Default::default(),
+                message_ttl_secs: None,
+                last_read: None,
+                role: Default::default(),
+                permissions: Default::default(),
+            })
+        });
+        db.expect_get_chat_history_before()
+            .withf(|_, _, before, limit| before.is_none() && *limit == 1)
+            .returning(move |_, chat_id, _, _| {
+                if chat_id == quiet {
+                    return Ok(vec![]);
+                }
+                let mut last = message(busy.get(), None);
+                last.msg_text = "Hello\n\nworld".into();
+                last.date = chrono::Duration::seconds(10).into();
+                Ok(vec![last])
+            });
+        let summaries = ChatService::new(&db)
+            .chat_summaries(UserId(1))
+            .await
+            .unwrap();
+        // Чат, из которого пользователь вышел, пропускается, а чат без сообщений идет последним
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].chat_id, busy);
+        assert_eq!(summaries[0].name, "Busy");
+        assert_eq!(summaries[0].member_count, 2);
+        assert_eq!(
+            summaries[0].last_message_preview.as_deref(),
+            Some("Hello world")
+        );
+        assert_eq!(
+            summaries[0].last_activity,
+            Some(chrono::Duration::seconds(10).into())
+        );
+        assert_eq!(summaries[0].unread_count, 3);
+        assert_eq!(summaries[1].chat_id, quiet);
+        assert_eq!(summaries[1].last_message_preview, None);
+        assert_eq!(summaries[1].unread_count, 0);
+        assert!(!summaries[0].muted);
+        assert!(summaries[1].muted);
+    }
+
+    #[actix::test]
+    async fn test_shared_history() {
+        let chat_id = Uuid::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_chat_info()
+            .with(eq(UserId(1)), eq(ChatId(chat_id)))
+            .returning(move |_, _| {
+                Ok(ChatInfo {
+                    id: chat_id,
+                    name: "Shared".into(),
+                    users: vec![1, 2],
+                    chat_type: ChatType::Group,
+                    member_count: 2,
+                    delivery_mode: None,
+                    notifications: Default::default(),
+                    post_policy: Default::default(),
+                    labels: Default::default(),
+                    message_ttl_secs: None,
+                    last_read: None,
+                    role: Default::default(),
+                    permissions: Default::default(),
+                })
+            });
+        // Конец отрезка входит в него, а база отдает сообщения от новых к старым
+        db.expect_get_chat_history_before()
+            .withf(|_, _, before, limit| {
+                *before == Some(chrono::Duration::milliseconds(3001)) && *limit == 10
+            })
+            .returning(move |_, _, _, _| {
+                Ok([3, 2, 1]
+                    .into_iter()
+                    .map(|secs| {
+                        let mut message = message(chat_id, None);
+                        message.date = chrono::Duration::seconds(secs).into();
+                        message
+                    })
+                    .collect())
+            });
+        let history = ChatService::new(&db)
+            .shared_history(
+                UserId(1),
+                ChatId(chat_id),
+                chrono::Duration::seconds(2),
+                chrono::Duration::seconds(3),
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(history.name, "Shared");
+        let dates: Vec<_> = history
+            .messages
+            .iter()
+            .map(|message| message.date.timestamp.num_seconds())
+            .collect();
+        assert_eq!(dates, vec![2, 3]);
+    }
+}
@@ -0,0 +1,61 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::{RedisConfig, SessionBinding};
+    use chat::session_binding::{fingerprint, session_id, BindingCheck, SessionBinder};
+    use serial_test::serial;
+    use std::net::IpAddr;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_fingerprint_respects_rules() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut rules = SessionBinding::default();
+        assert!(!rules.enabled);
+        assert_eq!(
+            fingerprint(Some(ip), Some("Firefox"), &rules),
+            "10.0.0.1|Firefox"
+        );
+        rules.bind_ip = false;
+        assert_eq!(fingerprint(Some(ip), Some("Firefox"), &rules), "|Firefox");
+        rules.bind_user_agent = false;
+        assert_eq!(
+            fingerprint(Some(ip), Some("Firefox"), &rules),
+            fingerprint(None, None, &rules)
+        );
+    }
+
+    #[test]
+    fn test_session_id_hides_token() {
+        let id = session_id("secret-token");
+        assert_eq!(id.len(), 64);
+        assert!(!id.contains("secret"));
+        assert_eq!(id, session_id("secret-token"));
+        assert_ne!(id, session_id("other-token"));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_mismatch_revokes_session() {
+        let binder = SessionBinder::connect(&RedisConfig::default())
+            .await
+            .unwrap();
+        let session = session_id(&Uuid::new_v4().to_string());
+        assert_eq!(
+            binder.check(&session, "1.1.1.1|A", 60).await.unwrap(),
+            BindingCheck::New
+        );
+        assert_eq!(
+            binder.check(&session, "1.1.1.1|A", 60).await.unwrap(),
+            BindingCheck::Matches
+        );
+        assert_eq!(
+            binder.check(&session, "2.2.2.2|B", 60).await.unwrap(),
+            BindingCheck::Mismatch
+        );
+        // После отзыва не пускаем и настоящего владельца
+        assert_eq!(
+            binder.check(&session, "1.1.1.1|A", 60).await.unwrap(),
+            BindingCheck::Mismatch
+        );
+    }
+}
@@ -0,0 +1,75 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::ShareConfig;
+    use chat::sharing::{ShareError, ShareLinks, ShareToken};
+    use uuid::Uuid;
+
+    fn links(key: &str) -> ShareLinks {
+        ShareLinks::new(Some(key.as_bytes().to_vec()), ShareConfig::default())
+    }
+
+    fn token() -> ShareToken {
+        ShareToken {
+            chat_id: Uuid::new_v4(),
+            created_by: 1,
+            from: 1000,
+            to: 5000,
+            expires_at: 100,
+        }
+    }
+
+    #[test]
+    fn test_share_link_roundtrip() {
+        let links = links("secret");
+        let token = token();
+        let signed = links.sign(&token).unwrap();
+        assert_eq!(links.verify(&signed, 99), Ok(token));
+        assert_eq!(links.verify(&signed, 100), Err(ShareError::Expired));
+    }
+
+    #[test]
+    fn test_forged_share_links() {
+        let signed = links("secret").sign(&token()).unwrap();
+        // Чужой ключ
+        assert_eq!(links("other").verify(&signed, 0), Err(ShareError::Invalid));
+        // Подмененный отрезок со старой подписью
+        let (_, signature) = signed.split_once('.').unwrap();
+        let mut widened = token();
+        widened.from = 0;
+        let forged = format!(
+            "{}.{signature}",
+            hex::encode(serde_json::to_vec(&widened).unwrap())
+        );
+        assert_eq!(links("secret").verify(&forged, 0), Err(ShareError::Invalid));
+        assert_eq!(
+            links("secret").verify("garbage", 0),
+            Err(ShareError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_share_links_without_key() {
+        let links = ShareLinks::new(None, ShareConfig::default());
+        assert_eq!(links.sign(&token()), Err(ShareError::NotConfigured));
+        assert_eq!(links.verify("a.b", 0), Err(ShareError::NotConfigured));
+    }
+
+    #[test]
+    fn test_share_ttl() {
+        let links = links("secret");
+        assert_eq!(links.ttl_secs(None), 24 * 60 * 60);
+        assert_eq!(links.ttl_secs(Some(60)), 60);
+        assert_eq!(links.ttl_secs(Some(u64::MAX)), 7 * 24 * 60 * 60);
+        assert_eq!(links.ttl_secs(Some(0)), 1);
+        // Нулевой предел в конфигурации не роняет обработчик
+        let links = ShareLinks::new(
+            Some(b"secret".to_vec()),
+            ShareConfig {
+                max_ttl_secs: 0,
+                ..Default::default()
+            },
+        );
+        assert_eq!(links.ttl_secs(None), 1);
+        assert_eq!(links.ttl_secs(Some(60)), 1);
+    }
+}
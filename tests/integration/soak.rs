@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chat::soak::{run, SoakConfig};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            SoakConfig::parse(&args("--users 50 --chats 5 --rate 200 --duration 2")).unwrap(),
+            SoakConfig {
+                users: 50,
+                chats: 5,
+                rate: 200,
+                duration: Duration::from_secs(2),
+            }
+        );
+        assert_eq!(SoakConfig::parse(&[]).unwrap(), SoakConfig::default());
+        assert!(SoakConfig::parse(&args("--users")).is_err());
+        assert!(SoakConfig::parse(&args("--users many")).is_err());
+        assert!(SoakConfig::parse(&args("--users 0")).is_err());
+        assert!(SoakConfig::parse(&args("--threads 4")).is_err());
+    }
+
+    #[actix::test]
+    async fn test_short_soak() {
+        let config = SoakConfig {
+            users: 20,
+            chats: 4,
+            rate: 500,
+            duration: Duration::from_secs(1),
+        };
+        let report = run(&config).await;
+        assert_eq!(report.problems(&config), Vec::<String>::new());
+        assert!(report.sent > 0);
+        assert_eq!(report.stored, report.sent);
+        assert_eq!(report.delivered, report.expected_deliveries);
+        // Гости отключились, брокер их больше не помнит
+        assert!(report.guest_connections > 0);
+        assert_eq!(report.broker.users, config.users);
+        assert_eq!(report.broker.subscriptions, config.users);
+    }
+}
@@ -0,0 +1,155 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chat::config::ChatTemplate;
+    use chat::database::data::{ChatInfo, ChatType, PinOutcome, PinnedMessage, PostPolicy};
+    use chat::database::MockDatabase;
+    use chat::ids::{ChatId, UserId};
+    use chat::templates::{create_from_template, render_name};
+    use chrono::{TimeZone, Utc};
+    use mockall::predicate::{always, eq};
+    use uuid::Uuid;
+
+    fn chat_info(id: Uuid, users: Vec<i64>) -> ChatInfo {
+        ChatInfo {
+            id,
+            name: "Incident".into(),
+            member_count: users.len(),
+            users,
+            chat_type: ChatType::Group,
+            delivery_mode: None,
+            notifications: Default::default(),
+            post_policy: PostPolicy::CreatorOnly,
+            labels: Default::default(),
+            message_ttl_secs: None,
+            last_read: None,
+            role: Default::default(),
+            permissions: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_render_name() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 5, 7, 9, 0).unwrap();
+        let params = HashMap::from([("service".to_string(), "billing".to_string())]);
+        assert_eq!(
+            render_name("Incident {service} {date} {time}", &params, now).unwrap(),
+            "Incident billing 2024-03-05 07:09"
+        );
+        assert_eq!(render_name("Plain", &params, now).unwrap(), "Plain");
+        // Незакрытая скобка остается как есть
+        assert_eq!(
+            render_name("Odd {service", &params, now).unwrap(),
+            "Odd {service"
+        );
+        assert_eq!(
+            render_name("Incident {region}", &params, now).unwrap_err(),
+            "region"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_from_template() {
+        let chat_id = Uuid::new_v4();
+        let template = ChatTemplate {
+            name_pattern: "Incident {date}".into(),
+            members: vec![2, 3, 1],
+            pinned_message: Some("Runbook: http://wiki/incidents".into()),
+            post_policy: PostPolicy::CreatorOnly,
+        };
+        let mut db = MockDatabase::new();
+        // Создатель не приглашает сам себя, повторы убираются
+        db.expect_create_new_chat()
+            .with(
+                eq(UserId(1)),
+                eq(vec![UserId(2), UserId(3), UserId(4)]),
+                eq(ChatType::Group),
+                eq("Incident".to_string()),
+            )
+            .times(1)
+            .returning(move |_, users, _, _| {
+                Ok(chat_info(
+                    chat_id,
+                    users.into_iter().map(i64::from).collect(),
+                ))
+            });
+        db.expect_set_post_policy()
+            .with(eq(ChatId(chat_id)), eq(PostPolicy::CreatorOnly))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        db.expect_add_new_message_to_chat()
+            .withf(move |message| {
+                message.chat_id == chat_id
+                    && message.sender_id == 1
+                    && message.msg_text == "Runbook: http://wiki/incidents"
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        db.expect_pin_message()
+            .with(
+                eq(UserId(1)),
+                eq(ChatId(chat_id)),
+                always(),
+                eq(None),
+                always(),
+            )
+            .times(1)
+            .returning(|user_id, _, message_id, _, _| {
+                Ok(PinOutcome {
+                    pin: PinnedMessage {
+                        message_id,
+                        date: chrono::Duration::zero().into(),
+                        pinned_by: user_id.get(),
+                        pinned_at: chrono::Duration::zero().into(),
+                        expires_at: None,
+                    },
+                    rotated: vec![],
+                })
+            });
+        db.expect_get_chat_info()
+            .with(eq(UserId(1)), eq(ChatId(chat_id)))
+            .returning(|_, chat_id| Ok(chat_info(chat_id.get(), vec![1, 2, 3, 4])));
+
+        let created = create_from_template(
+            &db,
+            UserId(1),
+            &template,
+            "Incident".into(),
+            vec![UserId(4), UserId(2)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(created.chat.id, chat_id);
+        assert_eq!(created.chat.post_policy, PostPolicy::CreatorOnly);
+        assert_eq!(
+            created.pinned_message.unwrap().msg_text,
+            "Runbook: http://wiki/incidents"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_from_plain_template() {
+        let chat_id = Uuid::new_v4();
+        let template = ChatTemplate {
+            name_pattern: "Standup".into(),
+            ..Default::default()
+        };
+        let mut db = MockDatabase::new();
+        db.expect_create_new_chat()
+            .times(1)
+            .returning(move |_, users, _, _| {
+                Ok(chat_info(
+                    chat_id,
+                    users.into_iter().map(i64::from).collect(),
+                ))
+            });
+        db.expect_get_chat_info()
+            .returning(|_, chat_id| Ok(chat_info(chat_id.get(), vec![1])));
+        // Без закрепа и с политикой по умолчанию лишних запросов нет
+        let created = create_from_template(&db, UserId(1), &template, "Standup".into(), vec![])
+            .await
+            .unwrap();
+        assert!(created.pinned_message.is_none());
+    }
+}
@@ -0,0 +1,38 @@
+#[cfg(test)]
+mod tests {
+    use chat::text::{grapheme_len, normalize, preview, strip_control, truncate};
+
+    #[test]
+    fn test_truncate_keeps_graphemes_whole() {
+        // Семья из четырех человек - одна графема из семи кодовых точек
+        let family = "👨‍👩‍👧‍👦";
+        let flag = "🇷🇺";
+        let text = format!("ab{family}{flag}é");
+        assert_eq!(grapheme_len(&text), 5);
+        assert_eq!(truncate(&text, 3), format!("ab{family}"));
+        assert_eq!(truncate(&text, 4), format!("ab{family}{flag}"));
+        assert_eq!(truncate(&text, 10), text);
+        assert_eq!(truncate(&text, 0), "");
+        // Буква с отдельным диакритическим знаком тоже не разрезается
+        assert_eq!(truncate("e\u{301}x", 1), "e\u{301}");
+    }
+
+    #[test]
+    fn test_normalize_and_strip_control() {
+        assert_eq!(normalize("e\u{301}"), "\u{e9}");
+        assert_eq!(grapheme_len(&normalize("e\u{301}")), 1);
+        assert_eq!(strip_control("a\u{7}b\nc\td", &['\n']), "ab\ncd");
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("  Hello\n\n  world \u{7}", 100), "Hello world");
+        assert_eq!(preview("Hello world", 11), "Hello world");
+        assert_eq!(preview("Hello world", 7), "Hello…");
+        let hearts = "❤️".repeat(5);
+        let cut = preview(&hearts, 3);
+        assert_eq!(cut, format!("{}…", "❤️".repeat(2)));
+        assert_eq!(grapheme_len(&cut), 3);
+        assert_eq!(preview("", 10), "");
+    }
+}
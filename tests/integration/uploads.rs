@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod tests {
+    use chat::actors::websocket_actor::SERVER_CAPABILITIES;
+    use chat::uploads::{
+        encode_chunk, parse_chunk, ChunkHeader, UploadError, Uploads, DEFAULT_MIME,
+        MAX_PENDING_UPLOADS,
+    };
+    use uuid::Uuid;
+
+    fn header(upload_id: Uuid, seq: u32, last: bool) -> ChunkHeader {
+        ChunkHeader {
+            upload_id,
+            seq,
+            last,
+            chat_id: None,
+            name: None,
+            mime: None,
+        }
+    }
+
+    fn first(upload_id: Uuid, chat_id: Uuid) -> ChunkHeader {
+        ChunkHeader {
+            chat_id: Some(chat_id),
+            name: Some("photo.png".into()),
+            ..header(upload_id, 0, false)
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        assert!(SERVER_CAPABILITIES.contains(&"attachment_upload"));
+        let header = first(Uuid::new_v4(), Uuid::new_v4());
+        let frame = encode_chunk(&header, b"\x00\x01binary");
+        let (parsed, chunk) = parse_chunk(&frame).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(chunk, b"\x00\x01binary");
+
+        assert!(matches!(
+            parse_chunk(&[0]),
+            Err(UploadError::InvalidEnvelope(_))
+        ));
+        // Длина заголовка больше самого кадра
+        assert!(matches!(
+            parse_chunk(&[0, 200, b'{']),
+            Err(UploadError::InvalidEnvelope(_))
+        ));
+        assert!(matches!(
+            parse_chunk(&[0, 2, b'{', b'}', 1]),
+            Err(UploadError::InvalidEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn test_uploads_are_assembled_in_order() {
+        let (upload_id, chat_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut uploads = Uploads::new();
+        assert!(uploads
+            .push(first(upload_id, chat_id), b"abc", 10)
+            .unwrap()
+            .is_none());
+        assert!(uploads
+            .push(header(upload_id, 1, false), b"def", 10)
+            .unwrap()
+            .is_none());
+        let upload = uploads
+            .push(header(upload_id, 2, true), b"g", 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(upload.upload_id, upload_id);
+        assert_eq!(upload.chat_id, chat_id);
+        assert_eq!(upload.name, "photo.png");
+        assert_eq!(upload.mime, DEFAULT_MIME);
+        assert_eq!(upload.body, b"abcdefg");
+        // Законченная загрузка забыта
+        assert_eq!(
+            uploads
+                .push(header(upload_id, 3, true), b"", 10)
+                .unwrap_err(),
+            UploadError::UnexpectedChunk {
+                expected: 0,
+                got: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_broken_uploads_are_dropped() {
+        let chat_id = Uuid::new_v4();
+        let mut uploads = Uploads::new();
+        assert_eq!(
+            uploads
+                .push(header(Uuid::new_v4(), 0, true), b"", 10)
+                .unwrap_err(),
+            UploadError::MissingMetadata
+        );
+
+        let skipped = Uuid::new_v4();
+        uploads.push(first(skipped, chat_id), b"a", 10).unwrap();
+        assert_eq!(
+            uploads.push(header(skipped, 2, true), b"", 10).unwrap_err(),
+            UploadError::UnexpectedChunk {
+                expected: 1,
+                got: 2
+            }
+        );
+        assert!(uploads.push(header(skipped, 1, true), b"", 10).is_err());
+
+        let large = Uuid::new_v4();
+        uploads.push(first(large, chat_id), b"12345", 10).unwrap();
+        assert_eq!(
+            uploads
+                .push(header(large, 1, true), b"123456", 10)
+                .unwrap_err(),
+            UploadError::TooLarge { max_bytes: 10 }
+        );
+        // После ошибки загрузку можно начать заново
+        uploads.push(first(large, chat_id), b"12345", 10).unwrap();
+    }
+
+    #[test]
+    fn test_pending_uploads_are_limited() {
+        let chat_id = Uuid::new_v4();
+        let mut uploads = Uploads::new();
+        for _ in 0..MAX_PENDING_UPLOADS {
+            uploads
+                .push(first(Uuid::new_v4(), chat_id), b"", 10)
+                .unwrap();
+        }
+        assert_eq!(
+            uploads
+                .push(first(Uuid::new_v4(), chat_id), b"", 10)
+                .unwrap_err(),
+            UploadError::TooManyUploads
+        );
+    }
+}
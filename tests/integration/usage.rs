@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::{RedisConfig, UsageConfig};
+    use chat::ids::UserId;
+    use chat::usage::{
+        usage_days, usage_key, NoUsageTracking, RedisUsageTracker, UsageKind, UsageTracker,
+    };
+    use chrono::NaiveDate;
+    use serial_test::serial;
+
+    #[test]
+    fn test_usage_days() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        // Сутки идут от новых к старым и переходят через границу месяца
+        assert_eq!(
+            usage_days(today, 3),
+            vec![
+                today,
+                NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 2, 28).unwrap(),
+            ]
+        );
+        assert!(usage_days(today, 0).is_empty());
+        assert_eq!(usage_key(UserId(42), today), "usage:42:20240301");
+    }
+
+    #[actix::test]
+    async fn test_no_usage_tracking() {
+        let usage = NoUsageTracking;
+        usage
+            .record(UserId(1), UsageKind::ApiRequest)
+            .await
+            .unwrap();
+        let days = usage.usage(UserId(1), 2).await.unwrap();
+        assert_eq!(days.len(), 2);
+        assert!(days
+            .iter()
+            .all(|day| day.api_requests == 0 && day.ws_messages == 0));
+    }
+
+    #[actix::test]
+    #[serial]
+    async fn test_redis_usage_counts_by_kind() {
+        let usage = RedisUsageTracker::connect(
+            &RedisConfig {
+                host: "127.0.0.1".into(),
+                namespace: format!("test:{}:", uuid::Uuid::new_v4()),
+                ..Default::default()
+            },
+            &UsageConfig::default(),
+        )
+        .await
+        .unwrap();
+        usage
+            .record(UserId(7), UsageKind::ApiRequest)
+            .await
+            .unwrap();
+        usage
+            .record(UserId(7), UsageKind::ApiRequest)
+            .await
+            .unwrap();
+        usage
+            .record(UserId(7), UsageKind::WebsocketMessage)
+            .await
+            .unwrap();
+        let days = usage.usage(UserId(7), 2).await.unwrap();
+        assert_eq!(days[0].api_requests, 2);
+        assert_eq!(days[0].ws_messages, 1);
+        assert_eq!(days[1].api_requests, 0);
+    }
+}
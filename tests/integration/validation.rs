@@ -0,0 +1,360 @@
+#[cfg(test)]
+mod tests {
+    use chat::config::{MessageRules, NameRules};
+    use chat::database::data::{
+        ChatPermissions, Mute, NotificationPriority, NotificationSettings, QuietHours,
+        UserPreferences, UserProfile,
+    };
+    use chat::validation::{
+        validate_attachments, validate_client_msg_id, validate_draft, validate_file_name,
+        validate_labels, validate_language, validate_message_text, validate_name,
+        validate_permissions, validate_preferences, validate_profile, validate_sound,
+    };
+
+    #[test]
+    fn test_name_is_trimmed() {
+        let rules = NameRules::default();
+        assert_eq!(
+            validate_name("user_name", "  Alice  ", &rules).unwrap(),
+            "Alice"
+        );
+    }
+
+    #[test]
+    fn test_name_errors_point_to_field() {
+        let rules = NameRules {
+            max_length: 5,
+            allowed_symbols: Some("-".into()),
+            ..Default::default()
+        };
+        let error = validate_name("new_chat_name", "   ", &rules).unwrap_err();
+        assert_eq!(error.field, "new_chat_name");
+        assert_eq!(error.code, "too_short");
+        assert_eq!(
+            validate_name("user_name", "Alexander", &rules)
+                .unwrap_err()
+                .code,
+            "too_long"
+        );
+        assert_eq!(
+            validate_name("user_name", "a\u{7}b", &rules)
+                .unwrap_err()
+                .code,
+            "control_characters"
+        );
+        assert_eq!(
+            validate_name("user_name", "a*b", &rules).unwrap_err().code,
+            "invalid_characters"
+        );
+        assert_eq!(
+            validate_name("user_name", "Ан-на", &rules).unwrap(),
+            "Ан-на"
+        );
+    }
+
+    #[test]
+    fn test_sound_identifier() {
+        assert_eq!(validate_sound("sound", "bell-2.ogg").unwrap(), "bell-2.ogg");
+        assert_eq!(
+            validate_sound("sound", "bell ring").unwrap_err().code,
+            "invalid_characters"
+        );
+        assert_eq!(
+            validate_sound("sound", &"a".repeat(65)).unwrap_err().code,
+            "too_long"
+        );
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(
+            validate_file_name("name", " report 2024.pdf ").unwrap(),
+            "report 2024.pdf"
+        );
+        assert_eq!(
+            validate_file_name("name", "../etc/passwd")
+                .unwrap_err()
+                .code,
+            "invalid_characters"
+        );
+        assert_eq!(
+            validate_file_name("name", "  ").unwrap_err().code,
+            "too_short"
+        );
+        assert_eq!(
+            validate_file_name("name", &"a".repeat(256))
+                .unwrap_err()
+                .code,
+            "too_long"
+        );
+    }
+
+    #[test]
+    fn test_client_msg_id() {
+        assert_eq!(
+            validate_client_msg_id("client_msg_id", "tmp-42").unwrap(),
+            "tmp-42"
+        );
+        assert_eq!(
+            validate_client_msg_id("client_msg_id", "")
+                .unwrap_err()
+                .code,
+            "too_short"
+        );
+        assert_eq!(
+            validate_client_msg_id("client_msg_id", &"a".repeat(65))
+                .unwrap_err()
+                .code,
+            "too_long"
+        );
+        assert_eq!(
+            validate_client_msg_id("client_msg_id", "a\nb")
+                .unwrap_err()
+                .code,
+            "control_characters"
+        );
+    }
+
+    #[test]
+    fn test_draft() {
+        assert_eq!(validate_draft("text", "").unwrap(), "");
+        assert_eq!(
+            validate_draft("text", "Привет,\nмир").unwrap(),
+            "Привет,\nмир"
+        );
+        assert!(validate_draft("text", &"я".repeat(10_000)).is_ok());
+        let error = validate_draft("text", &"я".repeat(10_001)).unwrap_err();
+        assert_eq!(error.field, "text");
+        assert_eq!(error.code, "too_long");
+    }
+
+    #[test]
+    fn test_profile() {
+        let profile = validate_profile(&UserProfile {
+            avatar_url: Some(" https://cdn.example.com/a.png ".into()),
+            bio: Some("Строка 1\nстрока 2".into()),
+            status: Some("   ".into()),
+        })
+        .unwrap();
+        assert_eq!(
+            profile.avatar_url.as_deref(),
+            Some("https://cdn.example.com/a.png")
+        );
+        assert_eq!(profile.bio.as_deref(), Some("Строка 1\nстрока 2"));
+        // Поле из одних пробелов очищается
+        assert_eq!(profile.status, None);
+        // Все ошибки сообщаются разом
+        let errors = validate_profile(&UserProfile {
+            avatar_url: Some("javascript:alert(1)".into()),
+            bio: Some("я".repeat(501)),
+            status: Some("занят\nочень".into()),
+        })
+        .unwrap_err();
+        let codes: Vec<_> = errors
+            .iter()
+            .map(|e| (e.field.as_str(), e.code.as_str()))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                ("bio", "too_long"),
+                ("status", "control_characters"),
+                ("avatar_url", "invalid_url"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_message_text() {
+        let rules = MessageRules {
+            max_length: 10,
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_message_text("msg_text", "Привет,\r\n\tмир", &MessageRules::default())
+                .unwrap(),
+            "Привет,\r\n\tмир"
+        );
+        for blank in ["", "   ", "\n\t"] {
+            assert_eq!(
+                validate_message_text("msg_text", blank, &rules)
+                    .unwrap_err()
+                    .code,
+                "blank"
+            );
+        }
+        assert!(validate_message_text("msg_text", &"я".repeat(10), &rules).is_ok());
+        assert_eq!(
+            validate_message_text("msg_text", &"я".repeat(11), &rules)
+                .unwrap_err()
+                .code,
+            "too_long"
+        );
+        let error = validate_message_text("msg_text", "bell\u{7}", &rules).unwrap_err();
+        assert_eq!(error.field, "msg_text");
+        assert_eq!(error.code, "control_characters");
+        // Эмодзи из нескольких кодовых точек - один символ, текст приводится к NFC
+        assert!(validate_message_text("msg_text", &"👍🏽".repeat(10), &rules).is_ok());
+        assert_eq!(
+            validate_message_text("msg_text", "cafe\u{301}", &rules).unwrap(),
+            "caf\u{e9}"
+        );
+    }
+
+    #[test]
+    fn test_attachments() {
+        let rules = MessageRules {
+            max_attachments: 2,
+            ..Default::default()
+        };
+        let attachments: Vec<_> = (0..3).map(|_| uuid::Uuid::new_v4()).collect();
+        assert!(validate_attachments("attachments", &attachments[..2], &rules).is_ok());
+        let error = validate_attachments("attachments", &attachments, &rules).unwrap_err();
+        assert_eq!(error.field, "attachments");
+        assert_eq!(error.code, "too_many");
+        // Больше, чем принимает база, не разрешить и настройками
+        let rules = MessageRules {
+            max_attachments: 1000,
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.attachment_limit(),
+            chat::database::MAX_ATTACHMENTS_PER_MESSAGE
+        );
+    }
+
+    #[test]
+    fn test_chat_labels() {
+        assert_eq!(validate_language("language", " pt-BR ").unwrap(), "pt-br");
+        assert_eq!(
+            validate_language("language", "en_US").unwrap_err().code,
+            "invalid_characters"
+        );
+        assert_eq!(
+            validate_labels("labels", &["Gaming".into(), "gaming".into(), "NSFW".into()]).unwrap(),
+            vec!["gaming", "nsfw"]
+        );
+        assert_eq!(
+            validate_labels("labels", &["".into()]).unwrap_err().code,
+            "too_short"
+        );
+        let too_many: Vec<String> = (0..11).map(|i| format!("label{i}")).collect();
+        assert_eq!(
+            validate_labels("labels", &too_many).unwrap_err().code,
+            "too_many"
+        );
+    }
+
+    #[test]
+    fn test_notification_priority() {
+        let settings: NotificationSettings =
+            serde_json::from_str(r#"{"priority": "mentions_only"}"#).unwrap();
+        assert_eq!(settings.priority, NotificationPriority::MentionsOnly);
+        assert!(settings.sound.is_none());
+        assert!(settings.should_notify(true));
+        assert!(!settings.should_notify(false));
+        assert!(NotificationSettings::default().should_notify(false));
+        let muted = NotificationSettings {
+            priority: NotificationPriority::None,
+            sound: None,
+            mute: None,
+        };
+        assert!(!muted.should_notify(true));
+    }
+
+    #[test]
+    fn test_mute() {
+        let now = chrono::Duration::milliseconds(1_700_000_000_000);
+        let settings: NotificationSettings =
+            serde_json::from_str(r#"{"mute": {"until": 1700000060000}}"#).unwrap();
+        assert!(settings.is_muted_at(now));
+        assert!(!settings.should_notify_at(true, now));
+        // Отключение кончается в указанный момент
+        let later = now + chrono::Duration::minutes(1);
+        assert!(!settings.is_muted_at(later));
+        assert!(settings.should_notify_at(false, later));
+
+        let forever: NotificationSettings =
+            serde_json::from_str(r#"{"priority": "all", "mute": "forever"}"#).unwrap();
+        assert_eq!(forever.mute, Some(Mute::Forever));
+        assert!(!forever.should_notify_at(true, later));
+        assert_eq!(
+            serde_json::to_value(&forever).unwrap()["mute"],
+            serde_json::json!("forever")
+        );
+        assert!(!NotificationSettings::default().is_muted_at(now));
+    }
+
+    #[test]
+    fn test_user_preferences() {
+        let preferences: UserPreferences = serde_json::from_str(
+            r#"{"mentions_only": true, "quiet_hours": {"start_minute": 1320, "end_minute": 420}}"#,
+        )
+        .unwrap();
+        assert!(preferences.sound);
+        assert_eq!(validate_preferences(&preferences).unwrap(), preferences);
+        let chat = NotificationSettings::default();
+        // 12:00 UTC - часы "не беспокоить" не действуют, уведомляем только об упоминаниях
+        let noon = chrono::Duration::days(19_000) + chrono::Duration::hours(12);
+        assert!(preferences.should_notify_at(&chat, true, noon));
+        assert!(!preferences.should_notify_at(&chat, false, noon));
+        // 23:00 и 6:59 UTC - часы переходят через полночь
+        let night = chrono::Duration::days(19_000) + chrono::Duration::hours(23);
+        assert!(!preferences.should_notify_at(&chat, true, night));
+        let morning = chrono::Duration::days(19_000) + chrono::Duration::minutes(419);
+        assert!(!preferences.should_notify_at(&chat, true, morning));
+        assert!(preferences.should_notify_at(&chat, true, morning + chrono::Duration::minutes(1)));
+        // Настройки чата сильнее общих
+        let silenced = NotificationSettings {
+            priority: NotificationPriority::None,
+            ..Default::default()
+        };
+        assert!(!UserPreferences::default().should_notify_at(&silenced, true, noon));
+
+        // Местное время UTC+3: 12:00 UTC - это 15:00
+        let quiet = QuietHours {
+            start_minute: 14 * 60,
+            end_minute: 16 * 60,
+            utc_offset_minutes: 180,
+        };
+        assert!(quiet.is_active_at(noon));
+        assert!(!QuietHours {
+            utc_offset_minutes: 0,
+            ..quiet.clone()
+        }
+        .is_active_at(noon));
+
+        let invalid = UserPreferences {
+            quiet_hours: Some(QuietHours {
+                start_minute: QuietHours::DAY_MINUTES,
+                end_minute: 0,
+                utc_offset_minutes: -15 * 60,
+            }),
+            ..Default::default()
+        };
+        let errors = validate_preferences(&invalid).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.code == "out_of_range"));
+        assert_eq!(errors[0].field, "quiet_hours.start_minute");
+        assert_eq!(errors[1].field, "quiet_hours.utc_offset_minutes");
+    }
+
+    #[test]
+    fn test_chat_permissions() {
+        let permissions = validate_permissions("permissions", 0b0101).unwrap();
+        assert!(permissions.contains(ChatPermissions::INVITE));
+        assert!(permissions.contains(ChatPermissions::CHANGE_INFO));
+        assert!(!permissions.contains(ChatPermissions::PIN | ChatPermissions::INVITE));
+        assert_eq!(serde_json::to_string(&permissions).unwrap(), "5");
+        assert_eq!(
+            validate_permissions("permissions", 0b10001)
+                .unwrap_err()
+                .code,
+            "unknown_permissions"
+        );
+        // Без маски участники могут то же, что и до появления разрешений
+        let default = ChatPermissions::default();
+        assert!(default.contains(ChatPermissions::PIN | ChatPermissions::POST_MEDIA));
+        assert!(!default.contains(ChatPermissions::INVITE));
+    }
+}
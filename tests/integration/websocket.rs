@@ -0,0 +1,1140 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use actix::prelude::*;
+    use chat::actors::broker_actor::{
+        self, BrokerActor, BrokerStats, SocketHandle, TypingThrottle,
+    };
+    use chat::actors::database_actor::DatabaseActor;
+    use chat::actors::redis_actor::{
+        BlockChangedData, CallSignalData, EphemeralData, MemberRemovedData,
+        NotificationsChangedData, PresenceData,
+    };
+    use chat::actors::websocket_actor::messages::BrokerMessage;
+    use chat::actors::websocket_actor::{
+        protocol_error_close, ChatMessage, ClientFrame, ClientRequest, ForwardedFrom, Fragments,
+        FrameBudget, LoginConflict, MessageTombstone, ServerEvent, TokenDeadlines,
+        SERVER_CAPABILITIES,
+    };
+    use chat::calls::CallSignalKind;
+    use chat::config::{Config, ConfigHandle, DuplicateLogin, DuplicateLoginPolicy};
+    use chat::database::data::{
+        NotificationPriority, NotificationSettings, UnpinReason, UnpinnedMessage,
+    };
+    use chat::database::MockDatabase;
+    use chat::ids::{ChatId, UserId};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_legacy_message_frame() {
+        let frame = ClientFrame::parse(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "msg_text": "hi"}"#,
+        )
+        .unwrap();
+        assert!(matches!(frame, ClientFrame::Message(_)));
+    }
+
+    #[test]
+    fn test_capabilities_frame() {
+        let frame =
+            ClientFrame::parse(r#"{"type": "capabilities", "capabilities": ["slow_consumer"]}"#)
+                .unwrap();
+        match frame {
+            ClientFrame::Request(ClientRequest::Capabilities {
+                capabilities,
+                version,
+            }) => {
+                assert_eq!(capabilities, vec!["slow_consumer"]);
+                assert_eq!(version, None);
+            }
+            _ => panic!("Capabilities frame parsed as something else"),
+        }
+        assert!(ClientFrame::parse(r#"{"type": "unknown"}"#).is_err());
+        assert!(ClientFrame::parse("not json").is_err());
+    }
+
+    #[test]
+    fn test_fetch_history_frame() {
+        let frame = ClientFrame::parse(
+            r#"{"type": "fetch_history", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "limit": 20}"#,
+        )
+        .unwrap();
+        match frame {
+            ClientFrame::Request(ClientRequest::FetchHistory { before, limit, .. }) => {
+                assert_eq!(before, None);
+                assert_eq!(limit, Some(20));
+            }
+            _ => panic!("fetch_history frame parsed as something else"),
+        }
+    }
+
+    #[test]
+    fn test_chat_query_frames() {
+        assert!(matches!(
+            ClientFrame::parse(r#"{"type": "get_chats"}"#).unwrap(),
+            ClientFrame::Request(ClientRequest::GetChats)
+        ));
+        assert!(matches!(
+            ClientFrame::parse(
+                r#"{"type": "get_chat_info", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8"}"#
+            )
+            .unwrap(),
+            ClientFrame::Request(ClientRequest::GetChatInfo { .. })
+        ));
+        assert!(ClientFrame::parse(r#"{"type": "get_chat_info"}"#).is_err());
+    }
+
+    #[test]
+    fn test_chat_renamed_event() {
+        let chat_id = ChatId::new_v4();
+        let event = serde_json::to_value(ServerEvent::ChatRenamed {
+            chat_id,
+            name: "New".into(),
+            renamed_by: UserId(2),
+        })
+        .unwrap();
+        assert_eq!(event["event"], "chat_renamed");
+        assert_eq!(event["chat_id"], chat_id.to_string());
+        assert_eq!(event["name"], "New");
+        assert_eq!(event["renamed_by"], 2);
+        assert!(SERVER_CAPABILITIES.contains(&"chat_renamed"));
+    }
+
+    #[test]
+    fn test_token_deadlines() {
+        let now = chrono::Duration::seconds(1_000);
+        let notice = Duration::from_secs(300);
+        let deadlines = TokenDeadlines::new(chrono::Duration::seconds(4_600), now, notice);
+        assert_eq!(deadlines.expires_in, Duration::from_secs(3_600));
+        assert_eq!(deadlines.notice_in, Duration::from_secs(3_300));
+        // До истечения меньше, чем срок предупреждения: предупреждать сразу
+        let deadlines = TokenDeadlines::new(chrono::Duration::seconds(1_060), now, notice);
+        assert_eq!(deadlines.notice_in, Duration::ZERO);
+        assert_eq!(deadlines.expires_in, Duration::from_secs(60));
+        // Токен уже истек
+        let deadlines = TokenDeadlines::new(chrono::Duration::seconds(900), now, notice);
+        assert_eq!(deadlines.notice_in, Duration::ZERO);
+        assert_eq!(deadlines.expires_in, Duration::ZERO);
+
+        let event = serde_json::to_value(ServerEvent::ReauthRequired { expires_in: 60 }).unwrap();
+        assert_eq!(event["event"], "reauth_required");
+        assert_eq!(event["expires_in"], 60);
+        assert!(SERVER_CAPABILITIES.contains(&"reauth_required"));
+    }
+
+    #[test]
+    fn test_server_events_are_tagged() {
+        let hello = serde_json::to_value(ServerEvent::Hello {
+            protocol_version: 1,
+            capabilities: vec!["slow_consumer"],
+        })
+        .unwrap();
+        assert_eq!(hello["event"], "hello");
+        assert_eq!(hello["protocol_version"], 1);
+    }
+
+    #[test]
+    fn test_message_edited_event() {
+        // Сообщения от экземпляров без id сообщений все еще читаются
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "sender_id": 1, "date": 1000, "msg_text": "hi"}"#,
+        )
+        .unwrap();
+        assert!(message.message_id.is_nil());
+        assert!(message.edited_at.is_none());
+
+        let event = serde_json::to_value(ServerEvent::MessageEdited {
+            message: ChatMessage {
+                msg_text: "hello".into(),
+                edited_at: Some(chrono::Duration::milliseconds(2000).into()),
+                ..message
+            },
+        })
+        .unwrap();
+        assert_eq!(event["event"], "message_edited");
+        assert_eq!(event["message"]["msg_text"], "hello");
+        assert_eq!(event["message"]["edited_at"], 2000);
+    }
+
+    #[test]
+    fn test_message_deleted_event() {
+        let event = serde_json::to_value(ServerEvent::MessageDeleted {
+            tombstone: MessageTombstone {
+                chat_id: uuid::Uuid::nil(),
+                message_id: uuid::Uuid::nil(),
+                date: chrono::Duration::milliseconds(1000).into(),
+            },
+        })
+        .unwrap();
+        assert_eq!(event["event"], "message_deleted");
+        assert_eq!(event["message_id"], uuid::Uuid::nil().to_string());
+        assert_eq!(event["date"], 1000);
+    }
+
+    #[test]
+    fn test_message_ack_event() {
+        let message_id = uuid::Uuid::new_v4();
+        let event = serde_json::to_value(ServerEvent::MessageAck {
+            chat_id: ChatId(Uuid::nil()),
+            message_id,
+            date: chrono::Duration::milliseconds(1500).into(),
+            client_msg_id: Some("local-1".into()),
+        })
+        .unwrap();
+        assert_eq!(event["event"], "message_ack");
+        assert_eq!(event["message_id"], message_id.to_string());
+        assert_eq!(event["date"], 1500);
+        assert_eq!(event["client_msg_id"], "local-1");
+    }
+
+    #[test]
+    fn test_message_unpinned_event() {
+        let message_id = uuid::Uuid::new_v4();
+        let event = serde_json::to_value(ServerEvent::MessageUnpinned {
+            unpinned: UnpinnedMessage {
+                chat_id: uuid::Uuid::nil(),
+                message_id,
+                reason: UnpinReason::Expired,
+            },
+        })
+        .unwrap();
+        assert_eq!(event["event"], "message_unpinned");
+        assert_eq!(event["message_id"], message_id.to_string());
+        assert_eq!(event["reason"], "expired");
+    }
+
+    #[test]
+    fn test_client_msg_id_is_echoed() {
+        assert!(matches!(
+            ClientFrame::parse(
+                r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "msg_text": "hi", "client_msg_id": "tmp-1"}"#
+            )
+            .unwrap(),
+            ClientFrame::Message(_)
+        ));
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "sender_id": 1, "date": 1000, "msg_text": "hi", "client_msg_id": "tmp-1"}"#,
+        )
+        .unwrap();
+        assert_eq!(message.client_msg_id.as_deref(), Some("tmp-1"));
+        assert_eq!(
+            serde_json::to_value(&message).unwrap()["client_msg_id"],
+            "tmp-1"
+        );
+        let stripped = serde_json::to_value(ChatMessage {
+            client_msg_id: None,
+            ..message
+        })
+        .unwrap();
+        assert!(stripped.get("client_msg_id").is_none());
+    }
+
+    #[test]
+    fn test_forwarded_from() {
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "sender_id": 1, "date": 1000, "msg_text": "hi"}"#,
+        )
+        .unwrap();
+        assert!(serde_json::to_value(&message)
+            .unwrap()
+            .get("forwarded_from")
+            .is_none());
+        let source = uuid::Uuid::new_v4();
+        let forwarded = serde_json::to_value(ChatMessage {
+            forwarded_from: Some(ForwardedFrom {
+                chat_id: source,
+                message_id: uuid::Uuid::nil(),
+                sender_id: 7,
+            }),
+            ..message
+        })
+        .unwrap();
+        assert_eq!(forwarded["forwarded_from"]["chat_id"], source.to_string());
+        assert_eq!(forwarded["forwarded_from"]["sender_id"], 7);
+    }
+
+    #[test]
+    fn test_reply_to_is_optional() {
+        let message: ChatMessage = serde_json::from_str(
+            r#"{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "sender_id": 1, "date": 1000, "msg_text": "hi"}"#,
+        )
+        .unwrap();
+        assert!(message.reply_to.is_none());
+        assert!(serde_json::to_value(&message)
+            .unwrap()
+            .get("reply_to")
+            .is_none());
+
+        let parent = uuid::Uuid::new_v4();
+        let reply = serde_json::to_value(ChatMessage {
+            reply_to: Some(parent),
+            ..message
+        })
+        .unwrap();
+        assert_eq!(reply["reply_to"], parent.to_string());
+        assert!(matches!(
+            ClientFrame::parse(&format!(
+                r#"{{"chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "msg_text": "hi", "reply_to": "{parent}"}}"#
+            ))
+            .unwrap(),
+            ClientFrame::Message(_)
+        ));
+    }
+
+    #[test]
+    fn test_typing_frame_and_event() {
+        match ClientFrame::parse(
+            r#"{"type": "typing", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8"}"#,
+        )
+        .unwrap()
+        {
+            ClientFrame::Request(ClientRequest::Typing { chat_id }) => {
+                assert_eq!(chat_id.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8")
+            }
+            _ => panic!("typing frame parsed as something else"),
+        }
+        let event = serde_json::to_value(ServerEvent::Typing {
+            chat_id: ChatId(Uuid::nil()),
+            user_id: UserId(7),
+        })
+        .unwrap();
+        assert_eq!(event["event"], "typing");
+        assert_eq!(event["user_id"], 7);
+    }
+
+    #[test]
+    fn test_mark_read_frame_and_event() {
+        let message_id = uuid::Uuid::new_v4();
+        match ClientFrame::parse(&format!(
+            r#"{{"type": "mark_read", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "message_id": "{message_id}", "date": 2500}}"#
+        ))
+        .unwrap()
+        {
+            ClientFrame::Request(ClientRequest::MarkRead {
+                message_id: id,
+                date,
+                ..
+            }) => {
+                assert_eq!(id, message_id);
+                assert_eq!(date.timestamp.num_milliseconds(), 2500);
+            }
+            _ => panic!("mark_read frame parsed as something else"),
+        }
+        let event = serde_json::to_value(ServerEvent::ReadPositionChanged {
+            chat_id: ChatId(Uuid::nil()),
+            message_id,
+            date: chrono::Duration::milliseconds(2500).into(),
+        })
+        .unwrap();
+        assert_eq!(event["event"], "read_position_changed");
+        assert_eq!(event["message_id"], message_id.to_string());
+        assert_eq!(event["date"], 2500);
+    }
+
+    #[test]
+    fn test_mentioned_event() {
+        let message_id = uuid::Uuid::new_v4();
+        let event = serde_json::to_value(ServerEvent::Mentioned {
+            chat_id: ChatId(Uuid::nil()),
+            message_id,
+            sender_id: UserId(3),
+            preview: "Hello @2".into(),
+        })
+        .unwrap();
+        assert_eq!(event["event"], "mentioned");
+        assert_eq!(event["message_id"], message_id.to_string());
+        assert_eq!(event["sender_id"], 3);
+        assert_eq!(event["preview"], "Hello @2");
+    }
+
+    #[test]
+    fn test_broadcast_ephemeral_frame() {
+        let frame = ClientFrame::parse(
+            r#"{"type": "broadcast_ephemeral", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "kind": "cursor", "payload": {"x": 1, "y": 2}}"#,
+        )
+        .unwrap();
+        match frame {
+            ClientFrame::Request(ClientRequest::BroadcastEphemeral { kind, payload, .. }) => {
+                assert_eq!(kind, "cursor");
+                assert_eq!(payload, serde_json::json!({"x": 1, "y": 2}));
+            }
+            _ => panic!("expected broadcast_ephemeral"),
+        }
+        let event: serde_json::Value = serde_json::from_str(&chat::events::encode(
+            &ServerEvent::BroadcastEphemeral {
+                data: EphemeralData {
+                    chat_id: ChatId(Uuid::nil()),
+                    sender_id: UserId(4),
+                    kind: "ring".into(),
+                    payload: serde_json::Value::Null,
+                },
+            },
+            chat::events::PROTOCOL_VERSION,
+        ))
+        .unwrap();
+        assert_eq!(event["event"], "broadcast_ephemeral");
+        assert_eq!(event["sender_id"], 4);
+        assert_eq!(event["kind"], "ring");
+    }
+
+    #[test]
+    fn test_call_frames_and_event() {
+        let frame = ClientFrame::parse(
+            r#"{"type": "call_start", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "call_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            frame,
+            ClientFrame::Request(ClientRequest::CallStart { .. })
+        ));
+        let frame = ClientFrame::parse(
+            r#"{"type": "call_signal", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "call_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8", "to_user": 2, "signal": "ice_candidate", "payload": {"candidate": "candidate:1"}}"#,
+        )
+        .unwrap();
+        match frame {
+            ClientFrame::Request(ClientRequest::CallSignal {
+                to_user,
+                signal,
+                payload,
+                ..
+            }) => {
+                assert_eq!(to_user, UserId(2));
+                assert_eq!(signal, CallSignalKind::IceCandidate);
+                assert_eq!(payload["candidate"], "candidate:1");
+            }
+            _ => panic!("expected call_signal"),
+        }
+        // Отбою payload не нужен
+        assert!(ClientFrame::parse(
+            r#"{"type": "call_signal", "chat_id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "call_id": "6ba7b810-9dad-11d1-80b4-00c04fd430c8", "to_user": 2, "signal": "hangup"}"#,
+        )
+        .is_ok());
+        let event: serde_json::Value = serde_json::from_str(&chat::events::encode(
+            &ServerEvent::CallSignal {
+                data: CallSignalData {
+                    chat_id: ChatId(Uuid::nil()),
+                    call_id: Uuid::nil(),
+                    from_user: UserId(1),
+                    to_user: UserId(2),
+                    signal: CallSignalKind::Offer,
+                    payload: serde_json::json!({"sdp": "v=0"}),
+                },
+            },
+            chat::events::PROTOCOL_VERSION,
+        ))
+        .unwrap();
+        assert_eq!(event["event"], "call_signal");
+        assert_eq!(event["signal"], "offer");
+        assert_eq!(event["from_user"], 1);
+        assert_eq!(event["payload"]["sdp"], "v=0");
+        assert!(SERVER_CAPABILITIES.contains(&"calls"));
+    }
+
+    #[test]
+    fn test_frame_budget() {
+        let start = Instant::now();
+        let mut budget = FrameBudget::new(start);
+        assert!(budget.allow(start, 2));
+        assert!(budget.allow(start + Duration::from_millis(100), 2));
+        assert!(!budget.allow(start + Duration::from_millis(900), 2));
+        // В новой секунде счет начинается заново
+        assert!(budget.allow(start + Duration::from_secs(1), 2));
+    }
+
+    #[test]
+    fn test_protocol_error_close() {
+        use actix_web_actors::ws::{CloseCode, ProtocolError};
+
+        let reason = protocol_error_close(&ProtocolError::Overflow);
+        assert_eq!(reason.code, CloseCode::Size);
+        assert_eq!(reason.description.as_deref(), Some("frame too large"));
+        assert_eq!(
+            protocol_error_close(&ProtocolError::UnmaskedFrame).code,
+            CloseCode::Protocol
+        );
+        assert_eq!(
+            Config::default().dynamic.websocket.max_frame_bytes,
+            64 * 1024
+        );
+    }
+
+    #[test]
+    fn test_fragments() {
+        use actix_http::ws::Item;
+        use actix_web::web::Bytes;
+        use actix_web_actors::ws::{CloseCode, Message, ProtocolError};
+
+        let mut fragments = Fragments::default();
+        assert!(fragments
+            .push(Item::FirstText(Bytes::from_static(b"{\"msg_")), 100)
+            .unwrap()
+            .is_none());
+        assert!(fragments
+            .push(Item::Continue(Bytes::from_static(b"text\":")), 100)
+            .unwrap()
+            .is_none());
+        let Some(Message::Text(text)) = fragments
+            .push(Item::Last(Bytes::from_static(b" \"hi\"}")), 100)
+            .unwrap()
+        else {
+            panic!("Expected reassembled text");
+        };
+        assert_eq!(&*text, "{\"msg_text\": \"hi\"}");
+
+        // Все фрагменты вместе ограничены тем же размером, что и кадр
+        fragments
+            .push(Item::FirstText(Bytes::from_static(b"12345")), 8)
+            .unwrap();
+        assert!(matches!(
+            fragments.push(Item::Last(Bytes::from_static(b"6789")), 8),
+            Err(ProtocolError::Overflow)
+        ));
+        // После ошибки сборка начинается заново
+        assert!(matches!(
+            fragments.push(Item::Continue(Bytes::from_static(b"1")), 8),
+            Err(ProtocolError::ContinuationNotStarted)
+        ));
+
+        // Эмодзи, разрезанный между фрагментами, собирается целиком
+        let emoji = "🙂".as_bytes();
+        fragments
+            .push(Item::FirstText(Bytes::copy_from_slice(&emoji[..2])), 8)
+            .unwrap();
+        assert!(matches!(
+            fragments.push(Item::Last(Bytes::copy_from_slice(&emoji[2..])), 8),
+            Ok(Some(Message::Text(text))) if &*text == "🙂"
+        ));
+
+        fragments
+            .push(Item::FirstText(Bytes::from_static(b"\xff")), 8)
+            .unwrap();
+        let error = fragments.push(Item::Last(Bytes::new()), 8).unwrap_err();
+        assert_eq!(protocol_error_close(&error).code, CloseCode::Invalid);
+    }
+
+    #[test]
+    fn test_typing_throttle() {
+        let mut throttle = TypingThrottle::new(Duration::from_secs(3));
+        let chat = ChatId::new_v4();
+        let start = Instant::now();
+        assert!(throttle.allow(chat, UserId(1), start));
+        assert!(!throttle.allow(chat, UserId(1), start + Duration::from_secs(1)));
+        // Другие пользователи и чаты ограничиваются отдельно
+        assert!(throttle.allow(chat, UserId(2), start + Duration::from_secs(1)));
+        assert!(throttle.allow(ChatId::new_v4(), UserId(1), start + Duration::from_secs(1)));
+        assert!(throttle.allow(chat, UserId(1), start + Duration::from_secs(3)));
+    }
+
+    /// Сокет, который только запоминает, чем его закрыли, из каких чатов исключили, кто
+    /// появился в сети, какие сигналы и упоминания пришли
+    #[derive(Default)]
+    struct ConflictRecorder {
+        conflicts: Arc<Mutex<Vec<(usize, LoginConflict)>>>,
+        removed: Arc<Mutex<Vec<ChatId>>>,
+        presence: Arc<Mutex<Vec<(ChatId, UserId, bool)>>>,
+        ephemeral: Arc<Mutex<Vec<String>>>,
+        call_signals: Arc<Mutex<Vec<CallSignalKind>>>,
+        messages: Arc<Mutex<Vec<String>>>,
+        mentions: Arc<Mutex<Vec<String>>>,
+        index: usize,
+    }
+
+    impl Actor for ConflictRecorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<BrokerMessage> for ConflictRecorder {
+        type Result = ();
+        fn handle(&mut self, msg: BrokerMessage, _ctx: &mut Self::Context) -> Self::Result {
+            match msg {
+                BrokerMessage::LoginConflict(conflict) => {
+                    self.conflicts.lock().unwrap().push((self.index, conflict))
+                }
+                BrokerMessage::RemovedFromChat(data) => {
+                    self.removed.lock().unwrap().push(data.chat_id)
+                }
+                BrokerMessage::Presence {
+                    chat_id,
+                    user_id,
+                    online,
+                } => self
+                    .presence
+                    .lock()
+                    .unwrap()
+                    .push((chat_id, user_id, online)),
+                BrokerMessage::Ephemeral(data) => self.ephemeral.lock().unwrap().push(data.kind),
+                BrokerMessage::CallSignal(data) => {
+                    self.call_signals.lock().unwrap().push(data.signal)
+                }
+                BrokerMessage::NewMessage(message) | BrokerMessage::MessageEdited(message) => {
+                    self.messages.lock().unwrap().push(message.msg_text)
+                }
+                BrokerMessage::Mentioned { preview, .. } => {
+                    self.mentions.lock().unwrap().push(preview)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn connect_sockets(
+        policy: DuplicateLoginPolicy,
+        sockets: usize,
+    ) -> (Vec<(usize, LoginConflict)>, BrokerStats) {
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats().returning(|_| Ok(vec![]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let db = DatabaseActor::from_database(db).start();
+        let mut config =
+            Config::from_file(&std::env::temp_dir().join("chat_missing.json")).unwrap();
+        config.dynamic.duplicate_login = DuplicateLogin {
+            policy,
+            max_sessions: 2,
+        };
+        let config = ConfigHandle::new(std::env::temp_dir().join("chat_missing.json"), config);
+        let broker = BrokerActor::new(db).await.with_config(config).start();
+        let conflicts = Arc::new(Mutex::new(vec![]));
+        for index in 0..sockets {
+            let socket = ConflictRecorder {
+                conflicts: conflicts.clone(),
+                index,
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        UserId(1),
+                    ),
+                )
+                .await
+                .unwrap();
+        }
+        let stats = broker.send(broker_actor::messages::GetStats).await.unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        let conflicts = conflicts.lock().unwrap().clone();
+        (conflicts, stats)
+    }
+
+    #[actix::test]
+    async fn test_duplicate_login_policy() {
+        let (conflicts, stats) = connect_sockets(DuplicateLoginPolicy::AllowAll, 3).await;
+        assert!(conflicts.is_empty());
+        assert_eq!(stats.sockets, 3);
+
+        let (conflicts, stats) = connect_sockets(DuplicateLoginPolicy::KickOldest, 4).await;
+        assert_eq!(
+            conflicts,
+            vec![(0, LoginConflict::Replaced), (1, LoginConflict::Replaced)]
+        );
+        assert_eq!(stats.sockets, 2);
+
+        let (conflicts, stats) = connect_sockets(DuplicateLoginPolicy::DenyNew, 4).await;
+        assert_eq!(
+            conflicts,
+            vec![(2, LoginConflict::Denied), (3, LoginConflict::Denied)]
+        );
+        assert_eq!(stats.sockets, 2);
+        assert_eq!(LoginConflict::Replaced.close_code(), 4001);
+        assert_eq!(LoginConflict::Denied.close_code(), 4002);
+    }
+
+    /// Сокет, который упал сразу после подключения и не сообщил брокеру о закрытии
+    struct DeadSocket;
+
+    impl Actor for DeadSocket {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    impl Handler<BrokerMessage> for DeadSocket {
+        type Result = ();
+        fn handle(&mut self, _msg: BrokerMessage, _ctx: &mut Self::Context) -> Self::Result {}
+    }
+
+    #[actix::test]
+    async fn test_dead_sessions_are_collected() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let alive = ConflictRecorder::default().start().recipient();
+        let dead = DeadSocket.start().recipient();
+        for (socket, user_id) in [(alive, UserId(1)), (dead, UserId(2))] {
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        let cleaned = broker
+            .send(broker_actor::messages::CollectDeadSessions)
+            .await
+            .unwrap();
+        assert_eq!(cleaned, 1);
+        let stats = broker.send(broker_actor::messages::GetStats).await.unwrap();
+        assert_eq!(stats.users, 1);
+        assert_eq!(stats.sockets, 1);
+        assert_eq!(stats.subscriptions, 1);
+        // Живые сокеты не трогаются
+        let cleaned = broker
+            .send(broker_actor::messages::CollectDeadSessions)
+            .await
+            .unwrap();
+        assert_eq!(cleaned, 0);
+    }
+
+    /// Сокет с очередью на одно событие, который ничего не разбирает, пока тест идет
+    struct StuckSocket;
+
+    impl Actor for StuckSocket {
+        type Context = Context<Self>;
+
+        fn started(&mut self, ctx: &mut Self::Context) {
+            ctx.set_mailbox_capacity(1);
+            ctx.wait(actix::clock::sleep(Duration::from_secs(10)).into_actor(self));
+        }
+    }
+
+    impl Handler<BrokerMessage> for StuckSocket {
+        type Result = ();
+        fn handle(&mut self, _msg: BrokerMessage, _ctx: &mut Self::Context) -> Self::Result {}
+    }
+
+    #[actix::test]
+    async fn test_full_socket_gets_overflow_signal() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let overflow = Arc::new(tokio::sync::Notify::new());
+        let socket = SocketHandle {
+            addr: StuckSocket.start().recipient(),
+            overflow: Some(overflow.clone()),
+        };
+        broker
+            .send(broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(socket, UserId(1)))
+            .await
+            .unwrap();
+        for i in 0..5 {
+            let message: ChatMessage = serde_json::from_value(serde_json::json!({
+                "chat_id": chat_id,
+                "sender_id": 2,
+                "date": 1000 + i,
+                "msg_text": "flood",
+            }))
+            .unwrap();
+            broker
+                .send(broker_actor::messages::RedisMessage::NewMessage(message))
+                .await
+                .unwrap();
+        }
+        // Очередь сокета заполнилась первым событием, об остальных сокет узнал мимо очереди,
+        // и повторные сигналы не дублируют непрочитанный
+        let wait = Duration::from_millis(100);
+        assert!(tokio::time::timeout(wait, overflow.notified())
+            .await
+            .is_ok());
+        assert!(tokio::time::timeout(wait, overflow.notified())
+            .await
+            .is_err());
+    }
+
+    #[actix::test]
+    async fn test_removed_member_is_unsubscribed() {
+        let (chat_id, other_chat_id) = (ChatId::new_v4(), ChatId::new_v4());
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id, other_chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let removed = Arc::new(Mutex::new(vec![]));
+        let socket = ConflictRecorder {
+            removed: removed.clone(),
+            ..Default::default()
+        }
+        .start()
+        .recipient();
+        broker
+            .send(
+                broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                    socket.into(),
+                    UserId(1),
+                ),
+            )
+            .await
+            .unwrap();
+        broker
+            .send(broker_actor::messages::RedisMessage::MemberRemoved(
+                MemberRemovedData {
+                    chat_id,
+                    user_id: UserId(1),
+                    removed_by: UserId(2),
+                },
+            ))
+            .await
+            .unwrap();
+        let stats = broker.send(broker_actor::messages::GetStats).await.unwrap();
+        assert_eq!(stats.chats, 1);
+        assert_eq!(stats.subscriptions, 1);
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        assert_eq!(*removed.lock().unwrap(), vec![chat_id]);
+    }
+
+    #[actix::test]
+    async fn test_presence_skips_the_user_and_unknown_chats() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let mut recorded = vec![];
+        for user_id in [UserId(1), UserId(2)] {
+            let presence = Arc::new(Mutex::new(vec![]));
+            let socket = ConflictRecorder {
+                presence: presence.clone(),
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+            recorded.push(presence);
+        }
+        broker
+            .send(broker_actor::messages::RedisMessage::Presence(
+                PresenceData {
+                    user_id: UserId(1),
+                    online: true,
+                    chats: vec![chat_id, ChatId::new_v4()],
+                },
+            ))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        // Сам пользователь о себе не узнает
+        assert!(recorded[0].lock().unwrap().is_empty());
+        assert_eq!(
+            *recorded[1].lock().unwrap(),
+            vec![(chat_id, UserId(1), true)]
+        );
+    }
+
+    #[actix::test]
+    async fn test_ephemeral_is_not_echoed_to_sender() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let mut recorded = vec![];
+        for user_id in [UserId(1), UserId(2)] {
+            let ephemeral = Arc::new(Mutex::new(vec![]));
+            let socket = ConflictRecorder {
+                ephemeral: ephemeral.clone(),
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+            recorded.push(ephemeral);
+        }
+        broker
+            .send(broker_actor::messages::RedisMessage::Ephemeral(
+                EphemeralData {
+                    chat_id,
+                    sender_id: UserId(1),
+                    kind: "cursor".into(),
+                    payload: serde_json::json!({"x": 10}),
+                },
+            ))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        assert!(recorded[0].lock().unwrap().is_empty());
+        assert_eq!(*recorded[1].lock().unwrap(), vec!["cursor".to_string()]);
+    }
+
+    #[actix::test]
+    async fn test_call_signal_reaches_only_the_addressee() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        // Третий пользователь подключен, но в чате не состоит
+        db.expect_get_user_chats().returning(move |user_id| {
+            Ok(if user_id == UserId(3) {
+                vec![]
+            } else {
+                vec![chat_id]
+            })
+        });
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let mut recorded = vec![];
+        for user_id in [UserId(1), UserId(2), UserId(3)] {
+            let call_signals = Arc::new(Mutex::new(vec![]));
+            let socket = ConflictRecorder {
+                call_signals: call_signals.clone(),
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+            recorded.push(call_signals);
+        }
+        let call_id = Uuid::new_v4();
+        for (to_user, signal) in [
+            (UserId(2), CallSignalKind::Offer),
+            (UserId(3), CallSignalKind::Offer),
+        ] {
+            broker
+                .send(broker_actor::messages::RedisMessage::CallSignal(
+                    CallSignalData {
+                        chat_id,
+                        call_id,
+                        from_user: UserId(1),
+                        to_user,
+                        signal,
+                        payload: serde_json::json!({"sdp": "v=0"}),
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        assert!(recorded[0].lock().unwrap().is_empty());
+        assert_eq!(*recorded[1].lock().unwrap(), vec![CallSignalKind::Offer]);
+        assert!(recorded[2].lock().unwrap().is_empty());
+    }
+
+    #[actix::test]
+    async fn test_blocked_sender_is_not_delivered() {
+        let chat_id = ChatId::new_v4();
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        // Второй пользователь заблокировал первого
+        db.expect_get_blocked_users().returning(|user_id| {
+            Ok(if user_id == UserId(2) {
+                HashSet::from([UserId(1)])
+            } else {
+                HashSet::new()
+            })
+        });
+        db.expect_get_all_notification_settings()
+            .returning(|_| Ok(HashMap::new()));
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let mut recorded = vec![];
+        for user_id in [UserId(1), UserId(2), UserId(3)] {
+            let messages = Arc::new(Mutex::new(vec![]));
+            let socket = ConflictRecorder {
+                messages: messages.clone(),
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+            recorded.push(messages);
+        }
+        let message = |text: &str| -> ChatMessage {
+            serde_json::from_value(serde_json::json!({
+                "chat_id": chat_id,
+                "sender_id": 1,
+                "date": 1000,
+                "msg_text": text,
+            }))
+            .unwrap()
+        };
+        let send = |text: &str| {
+            broker.send(broker_actor::messages::RedisMessage::NewMessage(message(
+                text,
+            )))
+        };
+        send("blocked").await.unwrap();
+        // Правка от заблокированного отправителя тоже не доходит
+        broker
+            .send(broker_actor::messages::RedisMessage::MessageEdited(
+                message("blocked edit"),
+            ))
+            .await
+            .unwrap();
+        broker
+            .send(broker_actor::messages::RedisMessage::BlockChanged(
+                BlockChangedData {
+                    user_id: UserId(2),
+                    blocked_id: UserId(1),
+                    blocked: false,
+                },
+            ))
+            .await
+            .unwrap();
+        send("unblocked").await.unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        let delivered = vec!["blocked", "blocked edit", "unblocked"];
+        assert_eq!(*recorded[0].lock().unwrap(), delivered);
+        assert_eq!(*recorded[1].lock().unwrap(), vec!["unblocked"]);
+        assert_eq!(*recorded[2].lock().unwrap(), delivered);
+    }
+
+    #[actix::test]
+    async fn test_mentions_follow_notification_settings() {
+        let chat_id = ChatId::new_v4();
+        let changed = Arc::new(AtomicBool::new(false));
+        let mut db = MockDatabase::new();
+        db.expect_get_user_chats()
+            .returning(move |_| Ok(vec![chat_id]));
+        db.expect_get_blocked_users()
+            .returning(|_| Ok(HashSet::new()));
+        // Второй отключил уведомления, пока не передумает, третий ждет только упоминаний
+        let settings_changed = changed.clone();
+        db.expect_get_all_notification_settings()
+            .returning(move |user_id| {
+                let priority = match user_id.get() {
+                    2 if !settings_changed.load(Ordering::SeqCst) => NotificationPriority::None,
+                    3 => NotificationPriority::MentionsOnly,
+                    _ => NotificationPriority::All,
+                };
+                Ok(HashMap::from([(
+                    chat_id,
+                    NotificationSettings {
+                        priority,
+                        ..Default::default()
+                    },
+                )]))
+            });
+        let broker = BrokerActor::new(DatabaseActor::from_database(db).start())
+            .await
+            .start();
+        let mut recorded = vec![];
+        for user_id in [UserId(2), UserId(3)] {
+            let mentions = Arc::new(Mutex::new(vec![]));
+            let socket = ConflictRecorder {
+                mentions: mentions.clone(),
+                ..Default::default()
+            }
+            .start()
+            .recipient();
+            broker
+                .send(
+                    broker_actor::messages::WebsocketMessage::BrokerNotifyStarted(
+                        socket.into(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap();
+            recorded.push(mentions);
+        }
+        let send = |text: &str| {
+            let message: ChatMessage = serde_json::from_value(serde_json::json!({
+                "chat_id": chat_id,
+                "sender_id": 1,
+                "date": 1000,
+                "msg_text": text,
+                "mentions": [2, 3],
+            }))
+            .unwrap();
+            broker.send(broker_actor::messages::RedisMessage::NewMessage(message))
+        };
+        send("silenced").await.unwrap();
+        changed.store(true, Ordering::SeqCst);
+        broker
+            .send(broker_actor::messages::RedisMessage::NotificationsChanged(
+                NotificationsChangedData {
+                    user_id: UserId(2),
+                    chat_id,
+                },
+            ))
+            .await
+            .unwrap();
+        send("enabled").await.unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+        assert_eq!(*recorded[0].lock().unwrap(), vec!["enabled"]);
+        assert_eq!(*recorded[1].lock().unwrap(), vec!["silenced", "enabled"]);
+    }
+}